@@ -0,0 +1,670 @@
+// Management server for a running Speaker, so external tooling (scripts, dashboards, a CLI) can do what
+// a router's show and clear commands do. Requests and responses are HTTP with JSON bodies. Only built
+// with the "admin" feature. The server is deliberately small: one request per connection, no TLS and
+// no authentication, so it should only listen on a loopback or management address.
+//
+//   GET    /bgp/summary                      every peer's state and counters (show bgp summary)
+//   GET    /bgp/neighbors/<addr>             one peer in detail (show bgp neighbor), recent errors included
+//   GET    /bgp/neighbors/<addr>/advertised-routes   what the peer has been sent
+//   GET    /bgp/routes?prefix=<addr>/<len>   every path to the prefix (show ip bgp <prefix>)
+//   POST   /bgp/neighbors/<addr>/clear       hard reset, ?soft=in or ?soft=out for a soft one (clear bgp neighbor)
+//   POST   /bgp/routes                       originates {"prefixes": [..], "med": .., "communities": ["asn:value"]}
+//   DELETE /bgp/routes                       withdraws {"prefixes": [..]}
+//   POST   /bgp/exabgp                       applies ExaBGP JSON updates, one per line (see exabgp.rs)
+//
+// Errors come back with a 4xx or 5xx status and a body of {"error": "<reason>"}.
+
+use std::{
+    fmt,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tracing::debug;
+
+use crate::{
+    config::{parse_communities, parse_prefix},
+    exabgp::parse_update,
+    fsm_ds::{ErrorRecord, ErrorSource, MessageCounts, PeerStats},
+    looking_glass::LgQuery,
+    message_types::{MessageType, Route},
+    path_attrs::PathAttr,
+    peer::PeerHandle,
+    session_events::SessionError,
+    speaker::{SpeakerError, SpeakerHandle},
+    table::LocalRoutes,
+};
+
+// Requests bigger than this are refused, injecting a few thousand prefixes fits easily
+const MAX_REQUEST: u64 = 1 << 20;
+const MAX_HEADERS: usize = 64;
+
+// Reason given to a peer reset from the API (RFC 9003)
+const CLEAR_REASON: &str = "cleared by management API";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ApiError {
+    status: u16,
+    reason: String
+}
+
+impl ApiError {
+    fn new(status: u16, reason: impl Into<String>) -> Self {
+        Self { status, reason: reason.into() }
+    }
+    fn unknown_peer(peer: IpAddr) -> Self {
+        Self::new(404, format!("unknown BGP peer {}", peer))
+    }
+    fn closed() -> Self {
+        Self::new(503, "the speaker is shutting down")
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.reason)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// Message counters by type
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct Counts {
+    open: u64,
+    update: u64,
+    keepalive: u64,
+    notification: u64,
+    route_refresh: u64,
+    total: u64
+}
+
+impl Counts {
+    fn new(counts: &MessageCounts) -> Self {
+        Self {
+            open: counts.get(MessageType::Open),
+            update: counts.get(MessageType::Update),
+            keepalive: counts.get(MessageType::KeepAlive),
+            notification: counts.get(MessageType::Notification),
+            route_refresh: counts.get(MessageType::RouteRefresh),
+            total: counts.total()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct Notification {
+    code: u8,
+    subcode: u8,
+    communication: Option<String>,
+    sent: bool
+}
+
+impl Notification {
+    fn new(error: &SessionError) -> Self {
+        Self {
+            code: error.code,
+            subcode: error.subcode,
+            communication: error.communication.clone(),
+            sent: error.sent
+        }
+    }
+}
+
+// A NOTIFICATION or decode error from the peer's history. The time is seconds since the Unix epoch and the
+// Data is hex, cut short as it was recorded
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct PeerError {
+    source: &'static str,
+    action: Option<String>,
+    code: u8,
+    subcode: u8,
+    data: String,
+    at: u64
+}
+
+impl PeerError {
+    fn new(error: &ErrorRecord) -> Self {
+        let (source, action) = match error.source {
+            ErrorSource::Sent => ("sent", None),
+            ErrorSource::Received => ("received", None),
+            ErrorSource::Decode(action) => ("decode", Some(format!("{:?}", action)))
+        };
+        Self {
+            source,
+            action,
+            code: error.code,
+            subcode: error.subcode,
+            data: error.data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            at: error.at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+        }
+    }
+}
+
+// A line of show bgp summary
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct PeerSummary {
+    address: IpAddr,
+    remote_as: u16,
+    state: String,
+    uptime_secs: Option<u64>,
+    messages_received: u64,
+    messages_sent: u64,
+    prefixes_received: u64,
+    prefixes_accepted: u64
+}
+
+impl PeerSummary {
+    fn new(address: IpAddr, remote_as: u16, stats: &PeerStats) -> Self {
+        Self {
+            address,
+            remote_as,
+            state: format!("{:?}", stats.state()),
+            uptime_secs: stats.uptime().map(|uptime| uptime.as_secs()),
+            messages_received: stats.received().total(),
+            messages_sent: stats.sent().total(),
+            prefixes_received: stats.prefixes_received(),
+            prefixes_accepted: stats.prefixes_accepted()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct PeerDetail {
+    #[serde(flatten)]
+    summary: PeerSummary,
+    group: Option<String>,
+    transitions: u64,
+    received: Counts,
+    sent: Counts,
+    prefixes_rejected: u64,
+    prefixes_withdrawn: u64,
+    last_notification: Option<Notification>,
+    // Oldest first
+    errors: Vec<PeerError>
+}
+
+// A route in a peer's Adj-RIB-Out
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct AdvertisedRoute {
+    prefix: String,
+    next_hop: Option<IpAddr>,
+    as_path: String,
+    med: Option<u32>
+}
+
+impl AdvertisedRoute {
+    fn new(route: &Route, pas: &[PathAttr]) -> Self {
+        Self {
+            prefix: route.to_string(),
+            next_hop: pas.iter().find_map(|pa| pa.next_hop()),
+            as_path: pas
+                .iter()
+                .find_map(|pa| pa.as_path())
+                .unwrap_or_default()
+                .iter()
+                .map(|seg| seg.to_string())
+                .collect::<Vec<String>>()
+                .join(" "),
+            med: pas.iter().find_map(|pa| pa.med())
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OriginateRequest {
+    prefixes: Vec<String>,
+    #[serde(default)]
+    med: Option<u32>,
+    #[serde(default)]
+    communities: Vec<String>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WithdrawRequest {
+    prefixes: Vec<String>
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Serves the API for a speaker through a handle of it (see Speaker::handle), the application keeps the
+// Speaker itself. The accept loop runs in its own task until the server is dropped.
+pub struct AdminServer {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>
+}
+
+impl AdminServer {
+    pub async fn bind(addr: SocketAddr, speaker: SpeakerHandle) -> io::Result<Self> {
+        // Must be called within a tokio runtime
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let task = tokio::spawn(accept_loop(listener, speaker));
+        Ok(Self { local_addr, task })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept_loop(listener: TcpListener, speaker: SpeakerHandle) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue
+        };
+        // A slow client only holds up itself
+        tokio::spawn(serve(stream, speaker.clone()));
+    }
+}
+
+async fn serve(mut stream: TcpStream, speaker: SpeakerHandle) {
+    let (read, mut write) = stream.split();
+    let (status, body) = match read_request(BufReader::new(read.take(MAX_REQUEST))).await {
+        Ok(request) => {
+            debug!(method = %request.method, path = %request.path, "admin request");
+            match handle(&request, &speaker).await {
+                Ok(body) => (200, body),
+                Err(err) => (err.status, json!({ "error": err.reason }))
+            }
+        },
+        Err(err) => (400, json!({ "error": err.to_string() }))
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason_phrase(status),
+        body.len(),
+        body
+    );
+    // Nothing to be done if the client went away
+    _ = write.write_all(response.as_bytes()).await;
+    _ = write.shutdown().await;
+}
+
+async fn read_request<R: tokio::io::AsyncRead + Unpin>(mut reader: BufReader<R>) -> io::Result<Request> {
+    // "<method> <target> HTTP/1.x", headers, then a body of Content-Length bytes
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target.to_string())
+        },
+        _ => return Err(invalid("malformed request line"))
+    };
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(invalid("truncated request"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).await?;
+            let (path, query) = target.split_once('?').unwrap_or((target.as_str(), ""));
+            return Ok(Request {
+                method,
+                path: path.to_string(),
+                query: query
+                    .split('&')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        (percent_decode(key), percent_decode(value))
+                    })
+                    .collect(),
+                body
+            });
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| invalid("invalid Content-Length"))?;
+            }
+        }
+    }
+    Err(invalid("too many headers"))
+}
+
+fn percent_decode(value: &str) -> String {
+    // Clients may escape the '/' and ':' of a prefix. Anything that isn't a valid escape is kept as is.
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error"
+    }
+}
+
+async fn handle(request: &Request, speaker: &SpeakerHandle) -> Result<Value, ApiError> {
+    let path: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), path.as_slice()) {
+        ("GET", ["bgp", "summary"]) => summary(speaker).await,
+        ("GET", ["bgp", "neighbors", peer]) => neighbor(speaker, parse_peer(peer)?).await,
+        ("GET", ["bgp", "neighbors", peer, "advertised-routes"]) => advertised(speaker, parse_peer(peer)?).await,
+        ("POST", ["bgp", "neighbors", peer, "clear"]) => clear(speaker, parse_peer(peer)?, request.param("soft")),
+        ("GET", ["bgp", "routes"]) => routes(speaker, request.param("prefix")).await,
+        ("POST", ["bgp", "routes"]) => originate(speaker, &request.body),
+        ("DELETE", ["bgp", "routes"]) => withdraw(speaker, &request.body),
+        ("POST", ["bgp", "exabgp"]) => exabgp(speaker, &request.body),
+        _ => Err(ApiError::new(404, format!("no endpoint for {} {}", request.method, request.path)))
+    }
+}
+
+fn parse_peer(peer: &str) -> Result<IpAddr, ApiError> {
+    percent_decode(peer)
+        .parse()
+        .map_err(|_| ApiError::new(400, format!("invalid peer address '{}'", peer)))
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Value, ApiError> {
+    serde_json::to_value(value).map_err(|err| ApiError::new(500, err.to_string()))
+}
+
+async fn summary(speaker: &SpeakerHandle) -> Result<Value, ApiError> {
+    let mut peers = Vec::new();
+    for handle in speaker.peers() {
+        let peer = handle.peer_addr();
+        // A peer whose task has exited is on its way out
+        if let Ok(stats) = handle.stats().await {
+            peers.push(PeerSummary::new(peer, speaker.remote_as(peer).unwrap_or_default(), &stats));
+        }
+    }
+    peers.sort_by_key(|peer| peer.address);
+    Ok(json!({
+        "router_id": speaker.router_id(),
+        "local_as": speaker.local_as(),
+        "peers": to_json(&peers)?
+    }))
+}
+
+async fn neighbor(speaker: &SpeakerHandle, peer: IpAddr) -> Result<Value, ApiError> {
+    let handle = speaker.peer(peer).ok_or(ApiError::unknown_peer(peer))?;
+    let stats = handle.stats().await.map_err(|_| ApiError::unknown_peer(peer))?;
+    to_json(&PeerDetail {
+        summary: PeerSummary::new(peer, speaker.remote_as(peer).unwrap_or_default(), &stats),
+        group: speaker.group(peer),
+        transitions: stats.transitions(),
+        received: Counts::new(stats.received()),
+        sent: Counts::new(stats.sent()),
+        prefixes_rejected: stats.prefixes_rejected(),
+        prefixes_withdrawn: stats.prefixes_withdrawn(),
+        last_notification: stats.last_notification().map(Notification::new),
+        errors: stats.errors().map(PeerError::new).collect()
+    })
+}
+
+async fn advertised(speaker: &SpeakerHandle, peer: IpAddr) -> Result<Value, ApiError> {
+    let nlri = speaker.advertised_routes(peer).await.map_err(|err| match err {
+        SpeakerError::UnknownPeer(_) => ApiError::unknown_peer(peer),
+        _ => ApiError::closed()
+    })?;
+    let mut routes: Vec<(&Route, AdvertisedRoute)> = nlri
+        .iter()
+        .flat_map(|nlri| nlri.routes().iter().map(|route| (route, AdvertisedRoute::new(route, nlri.path_attrs()))))
+        .collect();
+    routes.sort_by_key(|(route, _)| *route);
+    let routes: Vec<AdvertisedRoute> = routes.into_iter().map(|(_, route)| route).collect();
+    Ok(json!({ "peer": peer, "routes": to_json(&routes)? }))
+}
+
+fn clear(speaker: &SpeakerHandle, peer: IpAddr, soft: Option<&str>) -> Result<Value, ApiError> {
+    let handle: PeerHandle = speaker.peer(peer).ok_or(ApiError::unknown_peer(peer))?;
+    let (reset, sent) = match soft {
+        None => ("hard", handle.hard_reset_with_reason(CLEAR_REASON)),
+        Some("in") => ("soft-in", handle.soft_reset_in()),
+        Some("out") => ("soft-out", handle.soft_reset_out()),
+        Some(other) => return Err(ApiError::new(400, format!("soft must be 'in' or 'out', not '{}'", other)))
+    };
+    sent.map_err(|_| ApiError::unknown_peer(peer))?;
+    Ok(json!({ "peer": peer, "reset": reset }))
+}
+
+async fn routes(speaker: &SpeakerHandle, prefix: Option<&str>) -> Result<Value, ApiError> {
+    let prefix = prefix.ok_or(ApiError::new(400, "missing prefix parameter"))?;
+    let route = parse_prefix(prefix).map_err(|err| ApiError::new(400, err.to_string()))?;
+    let found = speaker.looking_glass(LgQuery::Prefix(route), 1).await.map_err(|_| ApiError::closed())?;
+    match found.first() {
+        Some(found) => to_json(found),
+        None => Err(ApiError::new(404, format!("{} is not in the table", prefix)))
+    }
+}
+
+fn originate(speaker: &SpeakerHandle, body: &[u8]) -> Result<Value, ApiError> {
+    let request: OriginateRequest = serde_json::from_slice(body).map_err(|err| ApiError::new(400, err.to_string()))?;
+    let routes = request
+        .prefixes
+        .iter()
+        .map(|prefix| parse_prefix(prefix))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ApiError::new(400, err.to_string()))?;
+    let communities = parse_communities(&request.communities).map_err(|err| ApiError::new(400, err.to_string()))?;
+    let count = routes.len();
+    let mut local = LocalRoutes::new(routes).communities(communities);
+    if let Some(med) = request.med {
+        local = local.med(med);
+    }
+    speaker.originate(local).map_err(|_| ApiError::closed())?;
+    Ok(json!({ "originated": count }))
+}
+
+fn withdraw(speaker: &SpeakerHandle, body: &[u8]) -> Result<Value, ApiError> {
+    let request: WithdrawRequest = serde_json::from_slice(body).map_err(|err| ApiError::new(400, err.to_string()))?;
+    let routes = request
+        .prefixes
+        .iter()
+        .map(|prefix| parse_prefix(prefix))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ApiError::new(400, err.to_string()))?;
+    let count = routes.len();
+    speaker.withdraw_originated(routes).map_err(|_| ApiError::closed())?;
+    Ok(json!({ "withdrawn": count }))
+}
+
+fn exabgp(speaker: &SpeakerHandle, body: &[u8]) -> Result<Value, ApiError> {
+    // Nothing is applied unless every update parses
+    let body = std::str::from_utf8(body).map_err(|err| ApiError::new(400, err.to_string()))?;
+    let updates = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_update)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ApiError::new(400, err.to_string()))?;
+    let count = updates.len();
+    for change in updates.into_iter().flatten() {
+        speaker.redistribute(change).map_err(|_| ApiError::closed())?;
+    }
+    Ok(json!({ "applied": count }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{future::{self, Future}, net::Ipv4Addr, pin::Pin, time::Duration};
+    use crate::{
+        errors::ErrorAction,
+        fsm_ds::{BgpPeer, PeerSessionBuilder},
+        peer::{Connection, Connector, Inbound, Outbound},
+        speaker::Speaker,
+    };
+
+    // The peer never gets a connection, its session stays down
+    struct NoConn;
+
+    impl Connection for NoConn {
+        fn send(&mut self, _msg: Outbound) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+            Box::pin(future::pending())
+        }
+        fn recv(&mut self) -> Pin<Box<dyn Future<Output = Option<Inbound>> + Send + '_>> {
+            Box::pin(future::pending())
+        }
+    }
+
+    struct SilentConnector;
+
+    impl Connector for SilentConnector {
+        type Conn = NoConn;
+        fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<NoConn>> + Send>> {
+            Box::pin(future::pending())
+        }
+    }
+
+    async fn call(addr: SocketAddr, method: &str, target: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn admin_percent_decode() {
+        assert_eq!(percent_decode("10.0.0.0%2F8"), "10.0.0.0/8");
+        assert_eq!(percent_decode("2001%3adb8::%2f32"), "2001:db8::/32");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn admin_peer_error() {
+        let record = ErrorRecord {
+            source: ErrorSource::Decode(ErrorAction::TreatAsWithdraw),
+            code: 3,
+            subcode: 1,
+            data: vec![0x40, 0x01, 0x01, 0x05],
+            at: UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        };
+        let error = to_json(&PeerError::new(&record)).unwrap();
+        assert_eq!((error["source"].as_str(), error["action"].as_str()), (Some("decode"), Some("TreatAsWithdraw")));
+        assert_eq!((error["data"].as_str(), error["at"].as_u64()), (Some("40010105"), Some(1_700_000_000)));
+        let error = to_json(&PeerError::new(&ErrorRecord { source: ErrorSource::Sent, ..record })).unwrap();
+        assert!(error["action"].is_null());
+    }
+
+    #[tokio::test]
+    async fn admin_server() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(192, 0, 2, 254), 65000).unwrap();
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        let bgp_peer = BgpPeer::new(peer, 65001, "192.0.2.254".parse().unwrap(), PeerSessionBuilder::new().build());
+        speaker.add_peer(bgp_peer, SilentConnector, None).unwrap();
+        let server = AdminServer::bind("127.0.0.1:0".parse().unwrap(), speaker.handle()).await.unwrap();
+        // The server sees what's done to the speaker afterwards
+        speaker.set_group(peer, Some(String::from("ixp"))).unwrap();
+        let addr = server.local_addr();
+
+        let (status, summary) = call(addr, "GET", "/bgp/summary", "").await;
+        assert_eq!(status, 200);
+        assert_eq!((summary["router_id"].as_str(), summary["local_as"].as_u64()), (Some("192.0.2.254"), Some(65000)));
+        assert_eq!(summary["peers"][0]["address"], "192.0.2.1");
+        assert_eq!(summary["peers"][0]["remote_as"], 65001);
+        assert_eq!(summary["peers"][0]["state"], "Connect");
+        assert!(summary["peers"][0]["uptime_secs"].is_null());
+
+        let (status, detail) = call(addr, "GET", "/bgp/neighbors/192.0.2.1", "").await;
+        assert_eq!(status, 200);
+        assert_eq!((detail["group"].as_str(), detail["state"].as_str()), (Some("ixp"), Some("Connect")));
+        assert_eq!(detail["received"]["total"], 0);
+        assert_eq!(detail["errors"].as_array().map(Vec::len), Some(0));
+        assert_eq!(call(addr, "GET", "/bgp/neighbors/192.0.2.9", "").await.0, 404);
+        assert_eq!(call(addr, "GET", "/bgp/neighbors/nonsense", "").await.0, 400);
+        // Nothing goes out until the session is up
+        let (status, advertised) = call(addr, "GET", "/bgp/neighbors/192.0.2.1/advertised-routes", "").await;
+        assert_eq!((status, advertised["routes"].as_array().map(Vec::len)), (200, Some(0)));
+        assert_eq!(call(addr, "GET", "/bgp/neighbors/192.0.2.9/advertised-routes", "").await.0, 404);
+
+        let (status, cleared) = call(addr, "POST", "/bgp/neighbors/192.0.2.1/clear?soft=in", "").await;
+        assert_eq!((status, cleared["reset"].as_str()), (200, Some("soft-in")));
+        assert_eq!(call(addr, "POST", "/bgp/neighbors/192.0.2.1/clear?soft=sideways", "").await.0, 400);
+
+        // Injected routes show up in the table
+        let inject = r#"{"prefixes": ["198.51.100.0/24", "2001:db8::/32"], "med": 10, "communities": ["65000:100"]}"#;
+        let (status, originated) = call(addr, "POST", "/bgp/routes", inject).await;
+        assert_eq!((status, originated["originated"].as_u64()), (200, Some(2)));
+        let (status, route) = call(addr, "GET", "/bgp/routes?prefix=198.51.100.0%2F24", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(route["prefix"], "198.51.100.0/24");
+        assert_eq!(route["paths"][0]["source"], "local");
+        assert_eq!(route["paths"][0]["med"], 10);
+        assert_eq!(route["paths"][0]["communities"][0], "65000:100");
+        assert_eq!(call(addr, "GET", "/bgp/routes?prefix=2001:db8::/32", "").await.0, 200);
+
+        let (status, withdrawn) = call(addr, "DELETE", "/bgp/routes", r#"{"prefixes": ["198.51.100.0/24"]}"#).await;
+        assert_eq!((status, withdrawn["withdrawn"].as_u64()), (200, Some(1)));
+        assert_eq!(call(addr, "GET", "/bgp/routes?prefix=198.51.100.0/24", "").await.0, 404);
+
+        let exabgp = r#"{"announce": {"ipv4 unicast": {"192.0.2.1": [{"nlri": "203.0.113.0/24"}]}}}"#;
+        let (status, applied) = call(addr, "POST", "/bgp/exabgp", exabgp).await;
+        assert_eq!((status, applied["applied"].as_u64()), (200, Some(1)));
+        assert_eq!(call(addr, "GET", "/bgp/routes?prefix=203.0.113.0/24", "").await.0, 200);
+        assert_eq!(call(addr, "POST", "/bgp/exabgp", "announce route 203.0.113.0/24").await.0, 400);
+
+        let (status, err) = call(addr, "POST", "/bgp/routes", r#"{"prefixes": ["198.51.100.0/33"]}"#).await;
+        assert_eq!(status, 400);
+        assert!(err["error"].as_str().unwrap().contains("198.51.100.0/33"));
+        assert_eq!(call(addr, "POST", "/bgp/routes", r#"{"prefix": []}"#).await.0, 400);
+        assert_eq!(call(addr, "GET", "/bgp/routes", "").await.0, 400);
+        assert_eq!(call(addr, "GET", "/bgp/nothing", "").await.0, 404);
+    }
+}
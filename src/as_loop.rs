@@ -0,0 +1,99 @@
+// AS_PATH loop detection on ingest. Paths with our own AS in the AS_PATH are dropped (RFC 4271, Pg. 76)
+// unless the peer is configured with allowas-in, in which case our AS may appear up to that many times.
+// allowas-in is needed when sites of one customer share an AS across a provider backbone (e.g. MPLS-VPN
+// CE peering) and the provider doesn't do as-override.
+// A looped path is treated as a withdrawal rather than ignored, so a path the peer sent earlier for the
+// same routes doesn't outlive the announcement that replaced it.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+};
+
+use crate::{
+    comms::ReceivedRoutes,
+    path_attrs::{as_path_count, PathAttr},
+};
+
+pub(crate) struct AsLoopCheck {
+    local_as: u16,
+    // Occurrences of our AS allowed per peer, peers not in here get 0
+    allowas_in: HashMap<IpAddr, u8>
+}
+
+impl AsLoopCheck {
+    pub fn new(local_as: u16) -> Self {
+        Self {
+            local_as,
+            allowas_in: HashMap::new()
+        }
+    }
+    pub fn allowas_in(mut self, peer: IpAddr, count: u8) -> Self {
+        self.set_allowas_in(peer, count);
+        self
+    }
+    pub fn set_allowas_in(&mut self, peer: IpAddr, count: u8) {
+        match count {
+            0 => self.allowas_in.remove(&peer),
+            count => self.allowas_in.insert(peer, count)
+        };
+    }
+    pub fn is_looped(&self, peer: IpAddr, pas: &[PathAttr]) -> bool {
+        let allowed = self.allowas_in.get(&peer).copied().unwrap_or(0) as usize;
+        pas
+        .iter()
+        .find_map(|pa| pa.as_path())
+        .is_some_and(|segments| as_path_count(&segments, self.local_as) > allowed)
+    }
+    pub fn check(&self, payload: ReceivedRoutes) -> ReceivedRoutes {
+        // Returns the payload as is, or with its announcements turned into withdrawals if the path is looped
+        match self.is_looped(payload.peer_addr(), payload.path_attrs_ref()) {
+            true => treat_as_withdraw(&payload),
+            false => payload
+        }
+    }
+}
+
+// Copy of a payload where every announced route is withdrawn instead
+pub(crate) fn treat_as_withdraw(payload: &ReceivedRoutes) -> ReceivedRoutes {
+    let mut withdrawn = payload.withdrawn_routes().unwrap_or_default();
+    withdrawn.extend(payload.routes().unwrap_or_default());
+    payload.with_routes(payload.path_attrs(), None, Some(withdrawn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::{comms::MockReceivedRoutesBuilder, message_types::Route, path_attrs::*};
+
+    fn payload(ases: Vec<u16>, routes: Vec<Route>) -> ReceivedRoutes {
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(ases)])
+            .build()
+            .unwrap();
+        MockReceivedRoutesBuilder::new(Some(routes), None, vec![origin, aspath]).build()
+    }
+
+    #[test]
+    fn as_loop_allowas_in() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let looped = payload(vec![65001, 64500, 65002, 64500], vec![route.clone()]);
+        let peer = looped.peer_addr();
+
+        let mut check = AsLoopCheck::new(64500);
+        let out = check.check(looped.with_routes(looped.path_attrs(), looped.routes(), None));
+        assert_eq!(out.routes(), None);
+        assert_eq!(out.withdrawn_routes(), Some(vec![route.clone()]));
+        assert!(!check.is_looped(peer, payload(vec![65001], Vec::new()).path_attrs_ref()));
+
+        // Our AS shows up twice, so allowing it once isn't enough
+        check.set_allowas_in(peer, 1);
+        assert!(check.is_looped(peer, looped.path_attrs_ref()));
+        // Only the configured peer gets the exception
+        assert!(check.is_looped(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 99)), looped.path_attrs_ref()));
+        check.set_allowas_in(peer, 2);
+        assert_eq!(check.check(looped).routes(), Some(vec![route]));
+    }
+}
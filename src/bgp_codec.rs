@@ -0,0 +1,289 @@
+// The Codec for sessions over TCP, encoding what the peer's task sends with msg_encoder and decoding
+// what it receives with msg_decoder. Updates can only be turned into payloads for the tables once the
+// session has negotiated (see Codec::negotiated), until then they're handed over empty, which the FSM
+// treats as the error an UPDATE is outside Established.
+// AS_PATH and AGGREGATOR are encoded and decoded with the AS width the session negotiated, 2 octets until
+// it has. RFC 6793, Pg. 3
+// End-of-RIB markers become their own event: an empty UPDATE for IPv4 unicast, one carrying nothing but
+// an empty MP_UNREACH_NLRI for any other family. RFC 4724, Pg. 2
+
+use std::net::IpAddr;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    comms::ReceivedRoutes,
+    errors::{DecodeError, ErrorAction, MsgHeaderErrSubcode, NotifErrorCode, OpenMsgErrSubcode},
+    fsm_ds::{Event, SessionParams},
+    message_types::{Afi, MessageType, Notification, Safi, Update},
+    msg_decoder::{decode_keepalive, decode_notification, decode_open, decode_route_refresh, DecodedUpdate},
+    msg_encoder::{MessageEncoder, NotificationSerializer, OpenSerializer, RouteRefreshSerializer},
+    path_attrs::{AsWidth, MP_UNREACH_NLRI},
+    peer::{Inbound, Outbound},
+    transport::Codec,
+};
+
+#[derive(Clone)]
+pub(crate) struct BgpCodec {
+    // The peer's address and our AS, what received routes are tagged with
+    peer_addr: IpAddr,
+    local_as: u16,
+    // Set once the session is up
+    params: Option<SessionParams>,
+    as_width: AsWidth,
+    encoder: MessageEncoder
+}
+
+impl BgpCodec {
+    pub fn new(peer_addr: IpAddr, local_as: u16) -> Self {
+        Self { peer_addr, local_as, params: None, as_width: AsWidth::default(), encoder: MessageEncoder::new() }
+    }
+    fn update(&self, body: Bytes) -> Inbound {
+        let decoded = match DecodedUpdate::decode_with(body, self.as_width) {
+            Ok(decoded) => decoded,
+            Err(err) => return Inbound::Event(Event::UpdateMsgErr(err))
+        };
+        // Treat-as-withdraw goes on with what's left of the UPDATE, anything else is up to the FSM
+        if let Some(err) = decoded.error().filter(|err| err.action() != ErrorAction::TreatAsWithdraw) {
+            return Inbound::Event(Event::UpdateMsgErr(err.clone()));
+        }
+        let update = decoded.into_update();
+        if let Some((afi, safi)) = end_of_rib(&update) {
+            return Inbound::Event(Event::EndOfRib(afi, safi));
+        }
+        match &self.params {
+            Some(params) => {
+                let payload = ReceivedRoutes::from_update(&update, self.peer_addr, self.local_as, params);
+                Inbound::Update(payload.split_by_afi())
+            },
+            None => Inbound::Update(Vec::new())
+        }
+    }
+}
+
+impl Codec for BgpCodec {
+    fn encode(&mut self, msg: Outbound) -> (u8, BytesMut) {
+        // Updates are written into the encoder's pooled buffers, see recycle
+        match msg {
+            Outbound::Open(open) => (MessageType::Open.into(), OpenSerializer::new(open).serialize()),
+            Outbound::Keepalive => (MessageType::KeepAlive.into(), BytesMut::new()),
+            Outbound::Notification(notification) => {
+                (MessageType::Notification.into(), NotificationSerializer::new(notification).serialize())
+            },
+            Outbound::Update(update) => (MessageType::Update.into(), self.encoder.encode_update_body(&update)),
+            Outbound::RouteRefresh(afi, safi) => {
+                (MessageType::RouteRefresh.into(), RouteRefreshSerializer::new(afi, safi).serialize())
+            }
+        }
+    }
+    fn recycle(&mut self, body: BytesMut) {
+        self.encoder.recycle(body);
+    }
+    fn decode(&mut self, msg_type: u8, body: Bytes) -> Option<Inbound> {
+        let event = match MessageType::try_from(msg_type) {
+            Ok(MessageType::Open) => match decode_open(&body) {
+                Ok(open) => Event::BGPOpen(open),
+                Err(err) => open_err(err)
+            },
+            Ok(MessageType::Update) => return Some(self.update(body)),
+            Ok(MessageType::Notification) => match decode_notification(&body) {
+                Ok(notification) if is_version_err(&notification) => Event::NotifMsgVerErr,
+                Ok(notification) => Event::NotifMsg(notification),
+                Err(err) => Event::BGPHeaderErr(err.into())
+            },
+            Ok(MessageType::KeepAlive) => match decode_keepalive(&body) {
+                Ok(_) => Event::KeepAliveMsg,
+                Err(err) => Event::BGPHeaderErr(err.into())
+            },
+            // Families we don't know are ignored. RFC 2918, Pg. 3
+            Ok(MessageType::RouteRefresh) => match decode_route_refresh(&body) {
+                Ok(Some((afi, safi))) => Event::RouteRefreshMsg(afi, safi),
+                Ok(None) => return None,
+                Err(err) => Event::BGPHeaderErr(err.into())
+            },
+            Err(_) => Event::BGPHeaderErr(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgType))
+        };
+        Some(Inbound::Event(event))
+    }
+    fn negotiated(&mut self, params: &SessionParams) {
+        self.as_width = AsWidth::negotiated(params.four_octet_as());
+        self.encoder.set_as_width(self.as_width);
+        self.params = Some(params.clone());
+    }
+}
+
+fn open_err(err: DecodeError) -> Event {
+    // A body that doesn't add up is a header error, the rest are about the OPEN itself. RFC 4271, Pg. 32
+    match NotifErrorCode::from(err) {
+        code @ NotifErrorCode::MessageHeaderError(_) => Event::BGPHeaderErr(code),
+        code => Event::BGPOpenMsgErr(code)
+    }
+}
+
+fn is_version_err(notification: &Notification) -> bool {
+    // The peer doesn't speak our version, which the FSM handles on its own (Event 24). RFC 4271, Pg. 50
+    let version_err = Notification::new(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedVerNum));
+    (notification.err_code(), notification.err_subcode()) == (version_err.err_code(), version_err.err_subcode())
+}
+
+fn end_of_rib(update: &Update) -> Option<(Afi, Safi)> {
+    if update.nlri().is_some() || update.withdrawn_routes().is_some() {
+        return None;
+    }
+    match update.path_attrs() {
+        None => Some((Afi::Ipv4, Safi::Unicast)),
+        Some([pa]) if pa.attr_type_code() == MP_UNREACH_NLRI => match pa.mp_unreach() {
+            Some((afi, safi, routes)) if routes.is_empty() => Some((afi, safi)),
+            _ => None
+        },
+        Some(_) => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv4Addr, slice};
+    use crate::{
+        errors::UpdateMsgErrSubcode,
+        message_types::{Capability, Nlri, OpenBuilder, Route, UpdateBuilder},
+        path_attrs::{AsPath, AsSegment, NextHop, Origin, OriginValue, PaBuilder, PathAttrBuilder, AS4_PATH, AS_PATH},
+    };
+
+    fn codec() -> BgpCodec {
+        BgpCodec::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 65001)
+    }
+
+    // What the other end of the session decodes from what this one encodes
+    fn roundtrip(codec: &mut BgpCodec, msg: Outbound) -> Option<Inbound> {
+        let (msg_type, body) = codec.encode(msg);
+        codec.decode(msg_type, body.freeze())
+    }
+
+    fn event(inbound: Option<Inbound>) -> Event {
+        match inbound {
+            Some(Inbound::Event(event)) => event,
+            _ => panic!("expected an event")
+        }
+    }
+
+    fn header_err(event: Event) -> Option<MsgHeaderErrSubcode> {
+        match event {
+            Event::BGPHeaderErr(NotifErrorCode::MessageHeaderError(subcode)) => Some(subcode),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn bgp_codec_roundtrip() {
+        let mut codec = codec();
+        let open = OpenBuilder::new(4, 65002, 90, 2).capability(Capability::RouteRefresh).build();
+        assert!(matches!(event(roundtrip(&mut codec, Outbound::Open(open.clone()))), Event::BGPOpen(decoded) if decoded == open));
+        assert!(matches!(event(roundtrip(&mut codec, Outbound::Keepalive)), Event::KeepAliveMsg));
+        let notification = Notification::new(NotifErrorCode::Cease);
+        assert!(matches!(
+            event(roundtrip(&mut codec, Outbound::Notification(notification.clone()))),
+            Event::NotifMsg(decoded) if decoded == notification
+        ));
+        let notification = Notification::new(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedVerNum));
+        assert!(matches!(event(roundtrip(&mut codec, Outbound::Notification(notification))), Event::NotifMsgVerErr));
+        assert!(matches!(
+            event(roundtrip(&mut codec, Outbound::RouteRefresh(Afi::Ipv6, Safi::Unicast))),
+            Event::RouteRefreshMsg(Afi::Ipv6, Safi::Unicast)
+        ));
+        assert!(matches!(
+            event(roundtrip(&mut codec, Outbound::Update(UpdateBuilder::new().build_unchecked()))),
+            Event::EndOfRib(Afi::Ipv4, Safi::Unicast)
+        ));
+
+        // Routes only come through once the session has negotiated
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let pas = vec![
+            PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap(),
+            PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(vec![65002])]).build().unwrap(),
+            PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).build().unwrap(),
+        ];
+        let update = || UpdateBuilder::new().nlri(Nlri::new(slice::from_ref(&route), &pas)).build_unchecked();
+        match roundtrip(&mut codec, Outbound::Update(update())) {
+            Some(Inbound::Update(payloads)) => assert!(payloads.is_empty()),
+            _ => panic!("expected an update")
+        }
+        let local = OpenBuilder::new(4, 65001, 90, 1).build();
+        let remote = OpenBuilder::new(4, 65002, 90, 2).build();
+        codec.negotiated(&SessionParams::negotiate(&local, &remote));
+        match roundtrip(&mut codec, Outbound::Update(update())) {
+            Some(Inbound::Update(payloads)) => assert_eq!(payloads[0].routes(), Some(vec![route])),
+            _ => panic!("expected an update")
+        }
+    }
+
+    #[test]
+    fn bgp_codec_decode_errors() {
+        let mut codec = codec();
+        assert_eq!(header_err(event(codec.decode(6, Bytes::new()))), Some(MsgHeaderErrSubcode::BadMsgType));
+        assert_eq!(header_err(event(codec.decode(4, Bytes::from_static(&[0])))), Some(MsgHeaderErrSubcode::BadMsgLen));
+        assert_eq!(header_err(event(codec.decode(1, Bytes::from_static(&[4, 0])))), Some(MsgHeaderErrSubcode::BadMsgLen));
+        // An Optional Parameter other than Capabilities
+        assert!(matches!(
+            event(codec.decode(1, Bytes::from_static(&[4, 0xFD, 0xE9, 0, 90, 192, 0, 2, 1, 2, 1, 0]))),
+            Event::BGPOpenMsgErr(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedOptParam))
+        ));
+        assert!(matches!(
+            event(codec.decode(2, Bytes::from_static(&[0]))),
+            Event::UpdateMsgErr(err) if *err.code() == NotifErrorCode::from(UpdateMsgErrSubcode::MalformedAttrList)
+        ));
+        // ROUTE-REFRESH for a family we don't know is dropped
+        assert!(codec.decode(5, Bytes::from_static(&[0, 25, 0, 65])).is_none());
+    }
+
+    #[test]
+    fn bgp_codec_as_width() {
+        // AS_PATH [4200000000] as a peer with the 4-octet AS capability sends it
+        let body: &[u8] = &[
+            0, 0, 0, 20,
+            0x40, 1, 1, 0,
+            0x40, 2, 6, 2, 1, 0xFA, 0x56, 0xEA, 0x00,
+            0x40, 3, 4, 192, 0, 2, 1,
+            24, 203, 0, 113,
+        ];
+        let mut four = codec();
+        let four_octet = |asn, id| OpenBuilder::new(4, 23456, 90, id).capability(Capability::FourOctetAs(asn)).build();
+        four.negotiated(&SessionParams::negotiate(&four_octet(65001, 1), &four_octet(4200000000, 2)));
+
+        // Kept with AS_TRANS and AS4_PATH, and sent back as it came
+        let payload = match four.decode(MessageType::Update.into(), Bytes::from_static(body)) {
+            Some(Inbound::Update(mut payloads)) => payloads.remove(0),
+            _ => panic!("expected an update")
+        };
+        let as_path = payload.path_attrs_ref().iter().find(|pa| pa.attr_type_code() == AS_PATH).unwrap();
+        assert_eq!(as_path.attr_value(), &[2, 1, 0x5B, 0xA0]);
+        assert!(payload.path_attrs_ref().iter().any(|pa| pa.attr_type_code() == AS4_PATH));
+        let update = UpdateBuilder::new().nlri(Nlri::new(&payload.routes().unwrap(), payload.path_attrs_ref())).build_unchecked();
+        assert_eq!(four.encode(Outbound::Update(update)).1, body);
+
+        // A peer without the capability gets AS_TRANS, with the real path in AS4_PATH
+        let update = UpdateBuilder::new().nlri(Nlri::new(&payload.routes().unwrap(), payload.path_attrs_ref())).build_unchecked();
+        let sent: &[u8] = &[
+            0, 0, 0, 27,
+            0x40, 1, 1, 0,
+            0x40, 2, 4, 2, 1, 0x5B, 0xA0,
+            0x40, 3, 4, 192, 0, 2, 1,
+            0xC0, 17, 6, 2, 1, 0xFA, 0x56, 0xEA, 0x00,
+            24, 203, 0, 113,
+        ];
+        assert_eq!(codec().encode(Outbound::Update(update)).1, sent);
+    }
+
+    #[test]
+    fn bgp_codec_recycle() {
+        // An Update's buffer is handed out again once it's been written, a small one isn't kept
+        let mut codec = codec();
+        let (_, body) = codec.encode(Outbound::Update(UpdateBuilder::new().build_unchecked()));
+        let ptr = body.as_ptr();
+        codec.recycle(body);
+        let (_, open) = codec.encode(Outbound::Open(OpenBuilder::new(4, 65001, 90, 1).build()));
+        codec.recycle(open);
+        let (_, body) = codec.encode(Outbound::Update(UpdateBuilder::new().build_unchecked()));
+        assert_eq!((body.as_ptr(), &body[..]), (ptr, &[0, 0, 0, 0][..]));
+    }
+}
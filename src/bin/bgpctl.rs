@@ -0,0 +1,479 @@
+// bgpctl: command line client for the management API (see admin.rs), for poking at a running speaker
+// during lab testing. Commands follow the usual router CLI:
+//
+//   show bgp summary
+//   show bgp neighbors [<addr> [advertised-routes]]
+//   show ip bgp <prefix>, or show bgp <prefix> for either family
+//   clear bgp neighbor <addr> [soft [in | out]]
+//   network <prefix> [med <n>] [community <asn:value>]..
+//   no network <prefix>
+//
+// With a command on the command line only that command is run, otherwise commands are read from stdin
+// until EOF or exit. The API is expected on 127.0.0.1:8179 unless --server <addr:port> says otherwise.
+
+use std::{
+    env,
+    io::{self, BufRead, Read, Write},
+    net::TcpStream,
+    process::ExitCode,
+};
+
+use serde_json::{json, Value};
+
+const DEFAULT_SERVER: &str = "127.0.0.1:8179";
+
+const HELP: &str = "\
+show bgp summary
+show bgp neighbors [<addr> [advertised-routes]]
+show ip bgp <prefix>
+show bgp <prefix>
+clear bgp neighbor <addr> [soft [in | out]]
+network <prefix> [med <n>] [community <asn:value>]..
+no network <prefix>
+exit";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    Summary,
+    // Every peer in detail if no address is given
+    Neighbors(Option<String>),
+    Advertised(String),
+    Route(String),
+    // Soft reset directions, a hard reset if there are none
+    Clear(String, Vec<&'static str>),
+    Network {
+        prefix: String,
+        med: Option<u32>,
+        communities: Vec<String>
+    },
+    NoNetwork(String),
+    Help,
+    Exit
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = match words.as_slice() {
+        ["show", "bgp", "summary"] | ["show", "ip", "bgp", "summary"] => Command::Summary,
+        ["show", "bgp", "neighbor" | "neighbors"] => Command::Neighbors(None),
+        ["show", "bgp", "neighbor" | "neighbors", addr] => Command::Neighbors(Some(addr.to_string())),
+        ["show", "bgp", "neighbor" | "neighbors", addr, "advertised-routes"] => Command::Advertised(addr.to_string()),
+        ["show", "ip", "bgp", prefix] | ["show", "bgp", prefix] => Command::Route(prefix.to_string()),
+        ["clear", "bgp", "neighbor", addr] => Command::Clear(addr.to_string(), Vec::new()),
+        ["clear", "bgp", "neighbor", addr, "soft"] => Command::Clear(addr.to_string(), vec!["in", "out"]),
+        ["clear", "bgp", "neighbor", addr, "soft", "in"] => Command::Clear(addr.to_string(), vec!["in"]),
+        ["clear", "bgp", "neighbor", addr, "soft", "out"] => Command::Clear(addr.to_string(), vec!["out"]),
+        ["network", prefix, options @ ..] => {
+            let mut med = None;
+            let mut communities = Vec::new();
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match (*option, options.next()) {
+                    ("med", Some(value)) => {
+                        med = Some(value.parse().map_err(|_| format!("invalid med '{}'", value))?);
+                    },
+                    ("community", Some(value)) => communities.push(value.to_string()),
+                    _ => return Err(format!("unexpected '{}'", option))
+                }
+            }
+            Command::Network { prefix: prefix.to_string(), med, communities }
+        },
+        ["no", "network", prefix] => Command::NoNetwork(prefix.to_string()),
+        ["help" | "?"] => Command::Help,
+        ["exit" | "quit"] => Command::Exit,
+        _ => return Err(format!("unknown command '{}', try help", line.trim()))
+    };
+    Ok(command)
+}
+
+fn call(server: &str, method: &str, target: &str, body: Option<Value>) -> Result<Value, String> {
+    // One request per connection, the server closes it after responding
+    let mut stream = TcpStream::connect(server).map_err(|err| format!("can't reach {}: {}", server, err))?;
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        target,
+        server,
+        body.len(),
+        body
+    )
+    .map_err(|err| err.to_string())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|err| err.to_string())?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or("malformed response")?;
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or("malformed response")?;
+    let body: Value = serde_json::from_str(body).map_err(|err| err.to_string())?;
+    match status {
+        200 => Ok(body),
+        _ => Err(body["error"].as_str().unwrap_or("request failed").to_string())
+    }
+}
+
+fn run(server: &str, command: &Command) -> Result<String, String> {
+    match command {
+        Command::Summary => Ok(render_summary(&call(server, "GET", "/bgp/summary", None)?)),
+        Command::Neighbors(Some(addr)) => {
+            Ok(render_neighbor(&call(server, "GET", &format!("/bgp/neighbors/{}", addr), None)?))
+        },
+        Command::Neighbors(None) => {
+            let summary = call(server, "GET", "/bgp/summary", None)?;
+            let mut out = Vec::new();
+            for peer in summary["peers"].as_array().into_iter().flatten() {
+                let addr = peer["address"].as_str().unwrap_or_default();
+                out.push(render_neighbor(&call(server, "GET", &format!("/bgp/neighbors/{}", addr), None)?));
+            }
+            Ok(out.join("\n"))
+        },
+        Command::Advertised(addr) => {
+            let target = format!("/bgp/neighbors/{}/advertised-routes", addr);
+            Ok(render_advertised(&call(server, "GET", &target, None)?))
+        },
+        Command::Route(prefix) => {
+            let target = format!("/bgp/routes?prefix={}", prefix.replace('/', "%2F"));
+            Ok(render_route(&call(server, "GET", &target, None)?))
+        },
+        Command::Clear(addr, soft) => {
+            let target = format!("/bgp/neighbors/{}/clear", addr);
+            match soft.is_empty() {
+                true => _ = call(server, "POST", &target, None)?,
+                false => {
+                    for direction in soft {
+                        call(server, "POST", &format!("{}?soft={}", target, direction), None)?;
+                    }
+                }
+            }
+            Ok(String::new())
+        },
+        Command::Network { prefix, med, communities } => {
+            let body = json!({ "prefixes": [prefix], "med": med, "communities": communities });
+            call(server, "POST", "/bgp/routes", Some(body))?;
+            Ok(String::new())
+        },
+        Command::NoNetwork(prefix) => {
+            call(server, "DELETE", "/bgp/routes", Some(json!({ "prefixes": [prefix] })))?;
+            Ok(String::new())
+        },
+        Command::Help => Ok(HELP.to_string()),
+        Command::Exit => Ok(String::new())
+    }
+}
+
+fn duration(secs: &Value) -> String {
+    // hh:mm:ss, or days and hours once up for a day
+    match secs.as_u64() {
+        Some(secs) if secs >= 86400 => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+        Some(secs) => format!("{:02}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60),
+        None => String::from("never")
+    }
+}
+
+fn text(value: &Value) -> String {
+    // Strings without their quotes, nulls as nothing
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Null => String::new(),
+        value => value.to_string()
+    }
+}
+
+fn render_summary(summary: &Value) -> String {
+    let mut out = format!(
+        "BGP router identifier {}, local AS number {}\n\n{:<40} {:<7} {:>8} {:>8} {:<10} {}\n",
+        text(&summary["router_id"]),
+        text(&summary["local_as"]),
+        "Neighbor",
+        "AS",
+        "MsgRcvd",
+        "MsgSent",
+        "Up/Down",
+        "State/PfxRcd"
+    );
+    for peer in summary["peers"].as_array().into_iter().flatten() {
+        // Like most routers, the prefix count stands in for the state of Established sessions
+        let state = match peer["state"].as_str() {
+            Some("Established") => text(&peer["prefixes_received"]),
+            _ => text(&peer["state"])
+        };
+        out.push_str(&format!(
+            "{:<40} {:<7} {:>8} {:>8} {:<10} {}\n",
+            text(&peer["address"]),
+            text(&peer["remote_as"]),
+            text(&peer["messages_received"]),
+            text(&peer["messages_sent"]),
+            duration(&peer["uptime_secs"]),
+            state
+        ));
+    }
+    out.trim_end().to_string()
+}
+
+fn render_neighbor(peer: &Value) -> String {
+    let mut out = format!("BGP neighbor is {}, remote AS {}", text(&peer["address"]), text(&peer["remote_as"]));
+    if let Some(group) = peer["group"].as_str() {
+        out.push_str(&format!(", peer group {}", group));
+    }
+    out.push_str(&format!("\n  BGP state = {}", text(&peer["state"])));
+    if !peer["uptime_secs"].is_null() {
+        out.push_str(&format!(", up for {}", duration(&peer["uptime_secs"])));
+    }
+    out.push_str(&format!("\n  State transitions: {}\n  Message statistics:\n", text(&peer["transitions"])));
+    out.push_str(&format!("    {:<16} {:>10} {:>10}\n", "", "Sent", "Rcvd"));
+    for (label, key) in [
+        ("Opens:", "open"),
+        ("Updates:", "update"),
+        ("Keepalives:", "keepalive"),
+        ("Notifications:", "notification"),
+        ("Route Refresh:", "route_refresh"),
+        ("Total:", "total")
+    ] {
+        out.push_str(&format!(
+            "    {:<16} {:>10} {:>10}\n",
+            label,
+            text(&peer["sent"][key]),
+            text(&peer["received"][key])
+        ));
+    }
+    out.push_str(&format!(
+        "  Prefixes: {} received, {} accepted, {} rejected, {} withdrawn",
+        text(&peer["prefixes_received"]),
+        text(&peer["prefixes_accepted"]),
+        text(&peer["prefixes_rejected"]),
+        text(&peer["prefixes_withdrawn"])
+    ));
+    let notification = &peer["last_notification"];
+    if !notification.is_null() {
+        out.push_str(&format!(
+            "\n  Last notification {} code {} subcode {}",
+            match notification["sent"].as_bool() {
+                Some(true) => "sent",
+                _ => "received"
+            },
+            text(&notification["code"]),
+            text(&notification["subcode"])
+        ));
+        if let Some(communication) = notification["communication"].as_str() {
+            out.push_str(&format!(" \"{}\"", communication));
+        }
+    }
+    out
+}
+
+fn render_advertised(advertised: &Value) -> String {
+    let routes = advertised["routes"].as_array().cloned().unwrap_or_default();
+    let mut out = format!("   {:<43} {:<39} {:>10} {}\n", "Network", "Next Hop", "Metric", "Path");
+    for route in routes.iter() {
+        out.push_str(&format!(
+            "   {:<43} {:<39} {:>10} {}\n",
+            text(&route["prefix"]),
+            text(&route["next_hop"]),
+            text(&route["med"]),
+            text(&route["as_path"])
+        ));
+    }
+    out.push_str(&format!("\nTotal number of prefixes {}", routes.len()));
+    out
+}
+
+fn render_route(route: &Value) -> String {
+    let paths = route["paths"].as_array().cloned().unwrap_or_default();
+    let mut out = format!("BGP routing table entry for {}\nPaths: ({} available)", text(&route["prefix"]), paths.len());
+    for path in paths.iter() {
+        let as_path = match path["as_path"].as_str() {
+            Some("") | None => String::from("Local"),
+            Some(as_path) => as_path.to_string()
+        };
+        let mut flags = vec![text(&path["source"])];
+        if path["best"].as_bool() == Some(true) {
+            flags.push(String::from("best"));
+        }
+        out.push_str(&format!(
+            "\n  {}\n    {} from {} ({})\n      Origin {}, metric {}, localpref {}, weight {}, {}",
+            as_path,
+            match path["next_hop"].is_null() {
+                true => String::from("0.0.0.0"),
+                false => text(&path["next_hop"])
+            },
+            text(&path["peer"]),
+            text(&path["peer_id"]),
+            text(&path["origin"]),
+            text(&path["med"]),
+            match path["local_pref"].is_null() {
+                true => String::from("none"),
+                false => text(&path["local_pref"])
+            },
+            text(&path["weight"]),
+            flags.join(", ")
+        ));
+        let communities: Vec<String> = path["communities"].as_array().into_iter().flatten().map(text).collect();
+        if !communities.is_empty() {
+            out.push_str(&format!("\n      Community: {}", communities.join(" ")));
+        }
+        out.push_str(&format!("\n      RPKI validation state: {}", text(&path["rpki"])));
+    }
+    out
+}
+
+fn execute(server: &str, line: &str) -> bool {
+    // Prints the command's output or what went wrong, false once it's time to exit
+    match parse(line).and_then(|command| run(server, &command).map(|out| (command, out))) {
+        Ok((Command::Exit, _)) => return false,
+        Ok((_, out)) if out.is_empty() => (),
+        Ok((_, out)) => println!("{}", out),
+        Err(err) => eprintln!("% {}", err)
+    }
+    true
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let server = match args.first().map(String::as_str) {
+        Some("--server") if args.len() > 1 => {
+            let server = args[1].clone();
+            args.drain(..2);
+            server
+        },
+        Some("--server") => {
+            eprintln!("usage: bgpctl [--server <addr:port>] [command]");
+            return ExitCode::FAILURE;
+        },
+        _ => DEFAULT_SERVER.to_string()
+    };
+    if !args.is_empty() {
+        let line = args.join(" ");
+        return match parse(&line).and_then(|command| run(&server, &command)) {
+            Ok(out) => {
+                if !out.is_empty() {
+                    println!("{}", out);
+                }
+                ExitCode::SUCCESS
+            },
+            Err(err) => {
+                eprintln!("% {}", err);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("bgpctl> ");
+        _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break
+        };
+        if !line.trim().is_empty() && !execute(&server, &line) {
+            break;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bgp4::{AdminServer, PeerGroup, PeerSessionBuilder, Speaker};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn bgpctl_commands() {
+        assert_eq!(parse("show bgp summary"), Ok(Command::Summary));
+        assert_eq!(parse("  show ip bgp   summary "), Ok(Command::Summary));
+        assert_eq!(parse("show bgp neighbors"), Ok(Command::Neighbors(None)));
+        assert_eq!(parse("show bgp neighbor 192.0.2.1"), Ok(Command::Neighbors(Some(String::from("192.0.2.1")))));
+        assert_eq!(
+            parse("show bgp neighbors 192.0.2.1 advertised-routes"),
+            Ok(Command::Advertised(String::from("192.0.2.1")))
+        );
+        assert_eq!(parse("show ip bgp 10.0.0.0/8"), Ok(Command::Route(String::from("10.0.0.0/8"))));
+        assert_eq!(parse("show bgp 2001:db8::/32"), Ok(Command::Route(String::from("2001:db8::/32"))));
+        assert_eq!(parse("clear bgp neighbor 192.0.2.1"), Ok(Command::Clear(String::from("192.0.2.1"), Vec::new())));
+        assert_eq!(
+            parse("clear bgp neighbor 192.0.2.1 soft"),
+            Ok(Command::Clear(String::from("192.0.2.1"), vec!["in", "out"]))
+        );
+        assert_eq!(
+            parse("network 198.51.100.0/24 med 10 community 65000:1 community 65000:2"),
+            Ok(Command::Network {
+                prefix: String::from("198.51.100.0/24"),
+                med: Some(10),
+                communities: vec![String::from("65000:1"), String::from("65000:2")]
+            })
+        );
+        assert_eq!(parse("no network 198.51.100.0/24"), Ok(Command::NoNetwork(String::from("198.51.100.0/24"))));
+        assert!(parse("network 198.51.100.0/24 med lots").is_err());
+        assert!(parse("network 198.51.100.0/24 community").is_err());
+        assert!(parse("show running-config").is_err());
+    }
+
+    #[test]
+    fn bgpctl_output() {
+        let summary = json!({
+            "router_id": "192.0.2.254",
+            "local_as": 65000,
+            "peers": [
+                { "address": "192.0.2.1", "remote_as": 65001, "state": "Established", "uptime_secs": 3725,
+                  "messages_received": 12, "messages_sent": 10, "prefixes_received": 5, "prefixes_accepted": 4 },
+                { "address": "192.0.2.2", "remote_as": 65002, "state": "Active", "uptime_secs": null,
+                  "messages_received": 0, "messages_sent": 0, "prefixes_received": 0, "prefixes_accepted": 0 }
+            ]
+        });
+        let out = render_summary(&summary);
+        assert!(out.starts_with("BGP router identifier 192.0.2.254, local AS number 65000"));
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[3].contains("01:02:05") && lines[3].ends_with(" 5"));
+        assert!(lines[4].contains("never") && lines[4].ends_with("Active"));
+        assert_eq!(duration(&json!(90061)), "1d01h");
+
+        let route = json!({
+            "prefix": "198.51.100.0/24",
+            "paths": [{ "best": true, "peer": "192.0.2.1", "peer_id": "10.0.0.1", "source": "ebgp",
+                        "next_hop": "192.0.2.1", "as_path": "65001 65010", "origin": "IGP", "local_pref": null,
+                        "med": 0, "weight": 0, "communities": ["65001:100"], "rpki": "valid" }]
+        });
+        let out = render_route(&route);
+        assert!(out.contains("Paths: (1 available)"));
+        assert!(out.contains("  65001 65010\n    192.0.2.1 from 192.0.2.1 (10.0.0.1)"));
+        assert!(out.contains("ebgp, best") && out.contains("Community: 65001:100"));
+    }
+
+    #[tokio::test]
+    async fn bgpctl_admin_server() {
+        // The commands against a live management API, run off the runtime since the client blocks
+        let mut speaker = Speaker::new(Ipv4Addr::new(192, 0, 2, 254), 65000).unwrap();
+        let group = PeerGroup::new("ixp").session(PeerSessionBuilder::new().passive());
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        speaker.add_group_peer(&group, peer, Some(65001), IpAddr::V4(Ipv4Addr::UNSPECIFIED)).unwrap();
+        let admin = AdminServer::bind("127.0.0.1:0".parse().unwrap(), speaker.handle()).await.unwrap();
+        let server = admin.local_addr().to_string();
+        let bgpctl = |line: &str| {
+            let (server, line) = (server.clone(), line.to_string());
+            tokio::task::spawn_blocking(move || parse(&line).and_then(|command| run(&server, &command)))
+        };
+
+        let out = bgpctl("show bgp summary").await.unwrap().unwrap();
+        assert!(out.starts_with("BGP router identifier 192.0.2.254, local AS number 65000"));
+        let line = out.lines().nth(3).unwrap();
+        assert!(line.starts_with("192.0.2.1 ") && line.contains(" 65001 "));
+        let out = bgpctl("show bgp neighbors 192.0.2.1").await.unwrap().unwrap();
+        assert!(out.starts_with("BGP neighbor is 192.0.2.1, remote AS 65001, peer group ixp"));
+        assert!(bgpctl("show bgp neighbors 192.0.2.9").await.unwrap().is_err());
+        let out = bgpctl("show bgp neighbors 192.0.2.1 advertised-routes").await.unwrap().unwrap();
+        assert!(out.ends_with("Total number of prefixes 0"));
+
+        let out = bgpctl("network 198.51.100.0/24 med 10 community 65000:100").await.unwrap().unwrap();
+        assert!(out.is_empty());
+        let out = bgpctl("show ip bgp 198.51.100.0/24").await.unwrap().unwrap();
+        assert!(out.starts_with("BGP routing table entry for 198.51.100.0/24\nPaths: (1 available)"));
+        assert!(out.contains("  Local\n") && out.contains("metric 10") && out.contains("Community: 65000:100"));
+        bgpctl("no network 198.51.100.0/24").await.unwrap().unwrap();
+        assert!(bgpctl("show ip bgp 198.51.100.0/24").await.unwrap().is_err());
+        assert!(bgpctl("network 198.51.100.0/33").await.unwrap().unwrap_err().contains("198.51.100.0/33"));
+    }
+}
@@ -1,18 +1,60 @@
 // Definitions for inter-service messaging types
+// Every peer's session runs in its own task (see peer) and the tables in the RIB task (see speaker). The
+// two only talk through the messages here: a peer's task sends the RIB TableCommands through the ingest
+// queue, and the RIB sends each peer PeerRequests through the channel behind its PeerHandle. The
+// speaker's own requests to the RIB (peers added and removed, policy, originated routes) are the
+// speaker's business, see RibRequest.
 
-// This structure contains information for the BGP table to run the decision process
-// and install paths. This message is queued up after decoding a valid Update message.
 use crate::{
-    message_types::Route,
-    path_attrs::{self, OriginValue, PathAttr},
+    errors::{NotifErrorCode, UpdateMsgErrSubcode},
+    fsm::PeerCommand,
+    fsm_ds::{PeerSession, PeerStats, SessionParams},
+    message_types::{Afi, Route, Safi, Update},
+    path_attrs::{self, validate_next_hop, NextHop, OriginValue, PaBuilder, PathAttr, PathAttrBuilder, AS_TRANS},
+    rpki::RpkiState,
+    session_events::SessionError,
     table::RouteSource,
 };
 use std::net::{
     Ipv4Addr,
     IpAddr
 };
+use tokio::sync::oneshot;
+
+// From a peer's task to the RIB, everything the table has to hear about the peer
+pub(crate) enum TableCommand {
+    // The session is Established
+    Up(IpAddr, SessionParams),
+    Routes(ReceivedRoutes),
+    // The session went down, the peer's routes are to be released. Comes with the NOTIFICATION that
+    // ended it, if there was one.
+    Down(IpAddr, Option<SessionError>),
+    // Graceful restart, the peer's routes for the families are kept until flushed. RFC 4724
+    MarkStale(IpAddr, Vec<(Afi, Safi)>),
+    FlushStale(IpAddr, Afi, Safi),
+    // Inbound soft reset without ROUTE-REFRESH, import policy is applied again to what the peer sent
+    ReplayAdjRibIn(IpAddr),
+    // The peer asked for a refresh (or outbound soft reset), it's sent everything again
+    ResendAdjRibOut(IpAddr)
+}
 
+// From the RIB (or anything else holding a PeerHandle) to a peer's task
+#[derive(Debug)]
+pub(crate) enum PeerRequest {
+    Command(PeerCommand),
+    // Updates for the peer from the table, dropped unless the session is Established
+    Advertise(Vec<Update>),
+    // How many of the peer's prefixes the table accepted and rejected
+    Imported(usize, usize),
+    Stats(oneshot::Sender<PeerStats>),
+    // Timer settings that take effect without resetting the session
+    Reconfigure(Box<PeerSession>),
+    // Take the session down and end the task, even with other handles still around
+    Exit
+}
 
+// This structure contains information for the BGP table to run the decision process
+// and install paths. This message is queued up after decoding a valid Update message.
 pub struct ReceivedRoutes {
     peer_id: Ipv4Addr,
     peer_addr: IpAddr,
@@ -25,7 +67,13 @@ pub struct ReceivedRoutes {
     igp_cost: u64,
     path_attrs: Vec<PathAttr>,
     routes: Option<Vec<Route>>,
-    withdrawn_routes: Option<Vec<Route>>
+    withdrawn_routes: Option<Vec<Route>>,
+    afi: Afi,
+    safi: Safi,
+    // Locally significant, set by inbound policy or a per-peer default. Not carried in any PA.
+    weight: u32,
+    // Origin validation state of the routes, set by the table when ROAs are loaded
+    rpki_state: RpkiState
 }
 // Associated Functions
 impl ReceivedRoutes {
@@ -40,7 +88,9 @@ impl ReceivedRoutes {
                igp_cost: u64,
                path_attrs: Vec<PathAttr>,
                routes: Option<Vec<Route>>,
-               withdrawn_routes: Option<Vec<Route>> ) -> Self {
+               withdrawn_routes: Option<Vec<Route>>,
+               afi: Afi,
+               safi: Safi) -> Self {
         Self {
             peer_id,
             peer_addr,
@@ -53,8 +103,71 @@ impl ReceivedRoutes {
             igp_cost,
             path_attrs,
             routes,
-            withdrawn_routes
+            withdrawn_routes,
+            afi,
+            safi,
+            weight: 0,
+            rpki_state: RpkiState::NotFound
+        }
+    }
+    pub fn from_update(update: &Update, peer_addr: IpAddr, local_as: u16, params: &SessionParams) -> Self {
+        // The payload for an Update received on the session, decision data taken from its PAs. Routes
+        // from a peer in our own AS are iBGP. Where the PAs say nothing the defaults stand, e.g. an
+        // empty AS_PATH (iBGP routes the peer originated) leaves the peer's AS as the neighboring AS.
+        let route_source = match params.remote_as() == local_as as u32 {
+            true => RouteSource::Ibgp,
+            false => RouteSource::Ebgp
+        };
+        // Routes in MP_REACH_NLRI and MP_UNREACH_NLRI join the ones in the NLRI and Withdrawn Routes
+        // fields, the MP_REACH_NLRI next hop stands in as the NEXT_HOP. RFC 4760, Pg. 1
+        let mp_reach = update.mp_reach();
+        let mp_unreach = update.mp_unreach();
+        let mut path_attrs: Vec<PathAttr> = update
+            .path_attrs()
+            .unwrap_or_default()
+            .iter()
+            .filter(|pa| ![path_attrs::MP_REACH_NLRI, path_attrs::MP_UNREACH_NLRI].contains(&pa.attr_type_code()))
+            .cloned()
+            .collect();
+        if let Some((_, _, next_hop, _)) = &mp_reach {
+            if !path_attrs.iter().any(|pa| pa.next_hop().is_some()) {
+                path_attrs.extend(PathAttrBuilder::<NextHop>::new().next_hop(*next_hop).build());
+            }
         }
+        let joined = |routes: Option<&[Route]>, mp_routes: Option<&[Route]>| -> Option<Vec<Route>> {
+            let joined: Vec<Route> = routes.into_iter().chain(mp_routes).flatten().cloned().collect();
+            match joined.is_empty() {
+                true => None,
+                false => Some(joined)
+            }
+        };
+        let routes = joined(update.nlri(), mp_reach.as_ref().map(|(_, _, _, routes)| routes.as_slice()));
+        let withdrawn = joined(update.withdrawn_routes(), mp_unreach.as_ref().map(|(_, _, routes)| routes.as_slice()));
+        // An Update carrying more than one family is tagged with the first, see split_by_afi. Without
+        // MP_REACH/MP_UNREACH an Update only carries unicast routes.
+        let afi = routes.iter().chain(withdrawn.iter()).flatten().next().map_or(Afi::Ipv4, Route::afi);
+        let safi = mp_reach
+            .map(|(_, safi, _, _)| safi)
+            .or(mp_unreach.map(|(_, safi, _)| safi))
+            .unwrap_or(Safi::Unicast);
+        let mut payload = Self::new(
+            params.remote_id(),
+            peer_addr,
+            u16::try_from(params.remote_as()).unwrap_or(AS_TRANS),
+            None,
+            0,
+            OriginValue::Igp,
+            0,
+            route_source,
+            0,
+            path_attrs,
+            routes,
+            withdrawn,
+            afi,
+            safi
+        );
+        payload.sync_decision_data();
+        payload
     }
 }
 // Methods
@@ -89,12 +202,343 @@ impl ReceivedRoutes {
     pub fn path_attrs(&self) -> Vec<PathAttr>{
         self.path_attrs.clone()
     }
+    pub fn path_attrs_ref(&self) -> &[PathAttr] {
+        self.path_attrs.as_slice()
+    }
     pub fn routes(&self) -> Option<Vec<Route>> {
         self.routes.clone()
     }
     pub fn withdrawn_routes(&self) -> Option<Vec<Route>> {
         self.withdrawn_routes.clone()
     }
+    pub fn withdrawn(&self) -> Option<WithdrawnRoutes> {
+        // The payload's withdrawals on their own
+        self.withdrawn_routes
+        .as_ref()
+        .filter(|routes| !routes.is_empty())
+        .map(|routes| WithdrawnRoutes::new(self.peer_id, self.peer_addr, routes.clone(), self.afi, self.safi))
+    }
+    pub fn announces(&self) -> bool {
+        // Whether any routes are advertised, as opposed to only withdrawn
+        self.routes.as_ref().is_some_and(|routes| !routes.is_empty())
+    }
+    pub fn afi(&self) -> Afi {
+        self.afi
+    }
+    pub fn safi(&self) -> Safi {
+        self.safi
+    }
+    pub fn family(&self) -> (Afi, Safi) {
+        // Which RIB the payload belongs to
+        (self.afi, self.safi)
+    }
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+    pub fn rpki_state(&self) -> RpkiState {
+        self.rpki_state
+    }
+    pub fn set_rpki_state(&mut self, rpki_state: RpkiState) {
+        self.rpki_state = rpki_state;
+    }
+    pub fn next_hop(&self) -> Option<IpAddr> {
+        self.path_attrs.iter().find_map(|pa| pa.next_hop())
+    }
+    pub fn validate_next_hop(&self, local_addrs: &[IpAddr], shared_subnet: Option<&Route>) -> Result<(), NotifErrorCode> {
+        // Checks the NEXT_HOP of a received Update before it is handed to the table. The shared subnet check
+        // only applies to eBGP peers; the caller should only supply the subnet for single-hop sessions.
+        // Pure withdrawals don't carry a NEXT_HOP, so there is nothing to check.
+        if self.routes.is_none() {
+            return Ok(());
+        }
+        let next_hop = self
+            .next_hop()
+            .ok_or(NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MissingWkAttr))?;
+        let subnet = match self.route_source {
+            RouteSource::Ebgp => shared_subnet,
+            RouteSource::Ibgp | RouteSource::Local => None
+        };
+        validate_next_hop(next_hop, local_addrs, subnet)
+    }
+    pub fn with_routes(&self, path_attrs: Vec<PathAttr>, routes: Option<Vec<Route>>, withdrawn_routes: Option<Vec<Route>>) -> ReceivedRoutes {
+        // Builds a payload from the same peer with the same decision data, but carrying
+        // different PAs and routes.
+        ReceivedRoutes::new(
+            self.peer_id,
+            self.peer_addr,
+            self.last_as,
+            self.local_pref,
+            self.as_path_len,
+            self.origin.clone(),
+            self.med,
+            self.route_source.clone(),
+            self.igp_cost,
+            path_attrs,
+            routes,
+            withdrawn_routes,
+            self.afi,
+            self.safi
+        )
+        .with_weight(self.weight)
+        .with_rpki_state(self.rpki_state)
+    }
+    fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+    fn with_rpki_state(mut self, rpki_state: RpkiState) -> Self {
+        self.rpki_state = rpki_state;
+        self
+    }
+    pub fn sync_decision_data(&mut self) {
+        // Re-derives the decision data from the PAs, e.g. after policy has changed them. Values
+        // whose PA isn't present are left as they are.
+        for pa in self.path_attrs.iter() {
+            if let Some(lp) = pa.local_pref() {
+                self.local_pref = Some(lp);
+            }
+            if let Some(med) = pa.med() {
+                self.med = med;
+            }
+            if let Some(origin) = pa.origin().and_then(|origin| OriginValue::try_from(origin).ok()) {
+                self.origin = origin;
+            }
+            if let Some(segments) = pa.as_path() {
+                self.as_path_len = path_attrs::as_path_len(&segments).min(u8::MAX as usize) as u8;
+                // The neighboring AS is the first AS of the leading AS_SEQUENCE
+                if let Some(path_attrs::AsSegment::AsSequence(ases)) = segments.first() {
+                    self.last_as = ases.first().copied().unwrap_or(self.last_as);
+                }
+            }
+        }
+    }
+    pub fn split_by_afi(self) -> Vec<ReceivedRoutes> {
+        // A single Update can carry routes from more than one address family (v4 in the
+        // NLRI field, v6 in MP_REACH/MP_UNREACH). Splits the payload into one ReceivedRoutes per
+        // AFI so that each can be handed to the appropriate table. The decision data and
+        // path attributes are shared by all the resulting payloads.
+        let mut afis: Vec<Afi> = self.routes
+            .iter()
+            .chain(self.withdrawn_routes.iter())
+            .flatten()
+            .map(|r| r.afi())
+            .collect();
+        afis.sort();
+        afis.dedup();
+
+        // Helper to pull the routes matching an AFI out of an optional route list.
+        let by_afi = |routes: &Option<Vec<Route>>, afi: Afi| -> Option<Vec<Route>> {
+            let filtered: Vec<Route> = routes
+                .iter()
+                .flatten()
+                .filter(|r| r.afi() == afi)
+                .cloned()
+                .collect();
+            match filtered.is_empty() {
+                true => None,
+                false => Some(filtered)
+            }
+        };
+
+        afis
+        .into_iter()
+        .map(|afi| {
+            let mut split = self.with_routes(
+                self.path_attrs.clone(),
+                by_afi(&self.routes, afi),
+                by_afi(&self.withdrawn_routes, afi)
+            );
+            split.afi = afi;
+            split
+        })
+        .collect()
+    }
+}
+
+// Withdrawals on their own. Which peer's paths to remove is all the table needs, so unlike ReceivedRoutes
+// there are no PAs or decision data, and nothing goes through the PA table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WithdrawnRoutes {
+    peer_id: Ipv4Addr,
+    peer_addr: IpAddr,
+    routes: Vec<Route>,
+    afi: Afi,
+    safi: Safi
+}
+
+impl WithdrawnRoutes {
+    pub fn new(peer_id: Ipv4Addr, peer_addr: IpAddr, routes: Vec<Route>, afi: Afi, safi: Safi) -> Self {
+        Self { peer_id, peer_addr, routes, afi, safi }
+    }
+    pub fn peer_id(&self) -> Ipv4Addr {
+        self.peer_id
+    }
+    pub fn peer_addr(&self) -> IpAddr {
+        self.peer_addr
+    }
+    pub fn routes(&self) -> &[Route] {
+        self.routes.as_slice()
+    }
+    pub fn afi(&self) -> Afi {
+        self.afi
+    }
+    pub fn safi(&self) -> Safi {
+        self.safi
+    }
+    pub fn family(&self) -> (Afi, Safi) {
+        (self.afi, self.safi)
+    }
+}
+
+// Routes fed into the table by something other than a session, e.g. a test harness, route server
+// provisioning or a controller. They're imported as if received from a peer at the source address:
+// import policy, maximum prefixes and the decision process all apply, and a later injection from the
+// same source withdraws or replaces them. The decision data comes from the PAs, as it would for an
+// Update, so unlike MockReceivedRoutesBuilder there's nothing to set that the PAs could contradict.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteInjection {
+    source: IpAddr,
+    router_id: Ipv4Addr,
+    source_as: u16,
+    path_attrs: Vec<PathAttr>,
+    routes: Vec<Route>,
+    withdrawn_routes: Vec<Route>,
+    safi: Safi,
+    weight: u32
+}
+
+impl RouteInjection {
+    pub fn path_attrs(&self) -> &[PathAttr] {
+        self.path_attrs.as_slice()
+    }
+    pub fn routes(&self) -> &[Route] {
+        self.routes.as_slice()
+    }
+    pub fn withdrawn_routes(&self) -> &[Route] {
+        self.withdrawn_routes.as_slice()
+    }
+    pub fn source(&self) -> IpAddr {
+        self.source
+    }
+    pub(crate) fn payloads(self, local_as: u16) -> Vec<ReceivedRoutes> {
+        // One payload per AFI, iBGP when the source is in our AS
+        let route_source = match self.source_as == local_as {
+            true => RouteSource::Ibgp,
+            false => RouteSource::Ebgp
+        };
+        let afi = self.routes.iter().chain(self.withdrawn_routes.iter()).next().map_or(Afi::Ipv4, Route::afi);
+        let non_empty = |routes: Vec<Route>| Some(routes).filter(|routes| !routes.is_empty());
+        let mut payload = ReceivedRoutes::new(
+            self.router_id,
+            self.source,
+            self.source_as,
+            None,
+            0,
+            OriginValue::Igp,
+            0,
+            route_source,
+            0,
+            self.path_attrs,
+            non_empty(self.routes),
+            non_empty(self.withdrawn_routes),
+            afi,
+            self.safi
+        );
+        payload.sync_decision_data();
+        payload.set_weight(self.weight);
+        payload.split_by_afi()
+    }
+}
+
+// Why a RouteInjection was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InjectionError {
+    // Neither routes nor withdrawals
+    Empty,
+    // Announced routes without one of the well-known mandatory PAs, carries its type code
+    MissingWkAttr(u8)
+}
+
+impl std::fmt::Display for InjectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InjectionError::Empty => write!(f, "nothing to inject"),
+            InjectionError::MissingWkAttr(code) => write!(f, "injected routes are missing well-known attribute {}", code)
+        }
+    }
+}
+
+impl std::error::Error for InjectionError {}
+
+pub struct RouteInjectionBuilder {
+    source: IpAddr,
+    router_id: Ipv4Addr,
+    source_as: u16,
+    path_attrs: Vec<PathAttr>,
+    routes: Vec<Route>,
+    withdrawn_routes: Vec<Route>,
+    safi: Safi,
+    weight: u32
+}
+
+impl RouteInjectionBuilder {
+    pub fn new(source: IpAddr, router_id: Ipv4Addr, source_as: u16) -> Self {
+        Self {
+            source,
+            router_id,
+            source_as,
+            path_attrs: Vec::new(),
+            routes: Vec::new(),
+            withdrawn_routes: Vec::new(),
+            safi: Safi::Unicast,
+            weight: 0
+        }
+    }
+    pub fn announce(mut self, routes: Vec<Route>, path_attrs: Vec<PathAttr>) -> Self {
+        // The routes all share the PAs, as in an Update
+        self.routes = routes;
+        self.path_attrs = path_attrs;
+        self
+    }
+    pub fn withdraw(mut self, routes: Vec<Route>) -> Self {
+        self.withdrawn_routes = routes;
+        self
+    }
+    pub fn safi(mut self, safi: Safi) -> Self {
+        self.safi = safi;
+        self
+    }
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+    pub fn build(self) -> Result<RouteInjection, InjectionError> {
+        if self.routes.is_empty() && self.withdrawn_routes.is_empty() {
+            return Err(InjectionError::Empty);
+        }
+        // Announcements need the PAs every Update announcing routes carries. RFC 4271, Pg. 25
+        if !self.routes.is_empty() {
+            let missing = [path_attrs::ORIGIN, path_attrs::AS_PATH, path_attrs::NEXT_HOP]
+                .into_iter()
+                .find(|code| !self.path_attrs.iter().any(|pa| pa.attr_type_code() == *code));
+            if let Some(code) = missing {
+                return Err(InjectionError::MissingWkAttr(code));
+            }
+        }
+        Ok(RouteInjection {
+            source: self.source,
+            router_id: self.router_id,
+            source_as: self.source_as,
+            path_attrs: self.path_attrs,
+            routes: self.routes,
+            withdrawn_routes: self.withdrawn_routes,
+            safi: self.safi,
+            weight: self.weight
+        })
+    }
 }
 
 // Used for creating RR messages for testing
@@ -110,7 +554,10 @@ pub (crate) struct MockReceivedRoutesBuilder {
     igp_cost: u64,
     path_attrs: Vec<PathAttr>,
     routes: Option<Vec<Route>>,
-    withdrawn_routes: Option<Vec<Route>>
+    withdrawn_routes: Option<Vec<Route>>,
+    afi: Afi,
+    safi: Safi,
+    weight: u32
 }
  impl MockReceivedRoutesBuilder {
     pub fn new(routes: Option<Vec<Route>>, withdrawn_routes: Option<Vec<Route>>, pa: Vec<PathAttr>) -> Self {
@@ -126,7 +573,10 @@ pub (crate) struct MockReceivedRoutesBuilder {
                 igp_cost: 1000,
                 path_attrs: pa,
                 withdrawn_routes,
-                routes
+                routes,
+                afi: Afi::Ipv4,
+                safi: Safi::Unicast,
+                weight: 0
         }
     }
     pub fn peer_id(mut self, peer_id: Ipv4Addr) -> Self {
@@ -165,6 +615,18 @@ pub (crate) struct MockReceivedRoutesBuilder {
         self.igp_cost = cost;
         self
     }
+    pub fn afi(mut self, afi: Afi) -> Self {
+        self.afi = afi;
+        self
+    }
+    pub fn safi(mut self, safi: Safi) -> Self {
+        self.safi = safi;
+        self
+    }
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
     pub fn build(self) -> ReceivedRoutes {
         ReceivedRoutes::new(
             self.peer_id,
@@ -179,6 +641,164 @@ pub (crate) struct MockReceivedRoutesBuilder {
             self.path_attrs,
             self.routes,
             self.withdrawn_routes,
+            self.afi,
+            self.safi
         )
+        .with_weight(self.weight)
+    }
+ }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_types::{Nlri, OpenBuilder, UpdateBuilder},
+        path_attrs::*,
+    };
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn validate_received_next_hop() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        let nh = PathAttrBuilder::<NextHop>::new()
+            .next_hop(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))
+            .build()
+            .unwrap();
+        let local = [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))];
+        let subnet = Route::new(24, IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)));
+        let err = Err(NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::InvalidNextHopAttr));
+
+        // eBGP peers have the subnet check applied, iBGP peers don't
+        let ebgp = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![nh.clone()]).build();
+        assert_eq!(ebgp.validate_next_hop(&local, Some(&subnet)), err);
+        assert_eq!(ebgp.validate_next_hop(&local, None), Ok(()));
+        let ibgp = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![nh])
+            .route_source(RouteSource::Ibgp)
+            .build();
+        assert_eq!(ibgp.validate_next_hop(&local, Some(&subnet)), Ok(()));
+
+        // Announcements must have a NEXT_HOP, withdrawals don't need one
+        let missing = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, Vec::new()).build();
+        assert_eq!(
+            missing.validate_next_hop(&local, None),
+            Err(NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MissingWkAttr))
+        );
+        let withdrawal = MockReceivedRoutesBuilder::new(None, Some(vec![route]), Vec::new()).build();
+        assert_eq!(withdrawal.validate_next_hop(&local, None), Ok(()));
     }
- }
\ No newline at end of file
+
+    #[test]
+    fn route_injection() {
+        let source = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100));
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let v6 = Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        let pas = vec![
+            PathAttrBuilder::<Origin>::new().origin(OriginValue::Egp).build().unwrap(),
+            PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(vec![65100, 65200])]).build().unwrap(),
+            PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100))).build().unwrap()
+        ];
+        let injection = RouteInjectionBuilder::new(source, Ipv4Addr::new(10, 0, 0, 100), 65000)
+            .announce(vec![v4.clone()], pas.clone())
+            .withdraw(vec![v6.clone()])
+            .weight(100)
+            .build()
+            .unwrap();
+
+        // Decision data from the PAs, one payload per AFI
+        let payloads = injection.payloads(65000);
+        assert_eq!(payloads.len(), 2);
+        let v4_payload = payloads.iter().find(|p| p.afi() == Afi::Ipv4).unwrap();
+        assert_eq!(v4_payload.routes(), Some(vec![v4.clone()]));
+        assert_eq!((v4_payload.origin(), v4_payload.as_path_len(), v4_payload.last_as()), (1, 2, 65100));
+        assert_eq!((v4_payload.route_source(), v4_payload.weight()), (RouteSource::Ibgp, 100));
+        let v6_payload = payloads.iter().find(|p| p.afi() == Afi::Ipv6).unwrap();
+        assert_eq!(v6_payload.withdrawn_routes(), Some(vec![v6]));
+        assert!(!v6_payload.announces());
+
+        let builder = || RouteInjectionBuilder::new(source, Ipv4Addr::new(10, 0, 0, 100), 65100);
+        assert_eq!(builder().build(), Err(InjectionError::Empty));
+        assert_eq!(builder().announce(vec![v4.clone()], pas[..2].to_vec()).build(), Err(InjectionError::MissingWkAttr(NEXT_HOP)));
+        assert_eq!(builder().announce(vec![v4], pas[1..].to_vec()).build(), Err(InjectionError::MissingWkAttr(ORIGIN)));
+    }
+
+    #[test]
+    fn split_mixed_family() {
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        let v6 = Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        let v6_w = Route::new(48, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0)));
+        let rxr = MockReceivedRoutesBuilder::new(
+            Some(vec![v4.clone(), v6.clone()]),
+            Some(vec![v6_w.clone()]),
+            Vec::new()).build();
+
+        let payloads = rxr.split_by_afi();
+        assert_eq!(payloads.len(), 2);
+
+        let v4_payload = payloads.iter().find(|p| p.afi() == Afi::Ipv4).unwrap();
+        assert_eq!(v4_payload.routes(), Some(vec![v4]));
+        assert_eq!(v4_payload.withdrawn_routes(), None);
+
+        let v6_payload = payloads.iter().find(|p| p.afi() == Afi::Ipv6).unwrap();
+        assert_eq!(v6_payload.routes(), Some(vec![v6]));
+        assert_eq!(v6_payload.withdrawn_routes(), Some(vec![v6_w]));
+        assert_eq!(v6_payload.safi(), Safi::Unicast);
+    }
+
+    #[test]
+    fn received_routes_from_update() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        let withdrawn = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 2, 0)));
+        let pas = vec![
+            PathAttrBuilder::<Origin>::new().origin(OriginValue::Egp).build().unwrap(),
+            PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(vec![65001, 65010, 65020])]).build().unwrap(),
+            PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))).build().unwrap(),
+            PathAttrBuilder::<Med>::new().metric(50).build().unwrap()
+        ];
+        let update = UpdateBuilder::new()
+            .withdrawn_routes(vec![withdrawn.clone()])
+            .nlri(Nlri::new(std::slice::from_ref(&route), &pas))
+            .build()
+            .unwrap();
+        let local = OpenBuilder::new(4, 65000, 90, 0x0A000001).build();
+        let remote = OpenBuilder::new(4, 65001, 90, 0x0A000002).build();
+        let params = SessionParams::negotiate(&local, &remote);
+        let peer_addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let payload = ReceivedRoutes::from_update(&update, peer_addr, 65000, &params);
+        assert_eq!(payload.peer_id(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(payload.peer_addr(), peer_addr);
+        assert_eq!(payload.route_source(), RouteSource::Ebgp);
+        assert_eq!(payload.last_as(), 65001);
+        assert_eq!(payload.as_path_len(), 3);
+        assert_eq!(payload.origin(), 1);
+        assert_eq!(payload.med(), 50);
+        assert_eq!(payload.local_pref(), None);
+        assert_eq!(payload.routes(), Some(vec![route]));
+        assert_eq!(payload.withdrawn_routes(), Some(vec![withdrawn.clone()]));
+        assert_eq!(payload.afi(), Afi::Ipv4);
+
+        // A withdrawal from an iBGP peer keeps the defaults, the neighboring AS is the peer's
+        let ibgp = SessionParams::negotiate(&local, &OpenBuilder::new(4, 65000, 90, 0x0A000002).build());
+        let update = UpdateBuilder::new().withdrawn_routes(vec![withdrawn]).build().unwrap();
+        let payload = ReceivedRoutes::from_update(&update, peer_addr, 65000, &ibgp);
+        assert_eq!(payload.route_source(), RouteSource::Ibgp);
+        assert_eq!(payload.last_as(), 65000);
+        assert_eq!(payload.as_path_len(), 0);
+        assert!(payload.path_attrs_ref().is_empty());
+        assert_eq!(payload.routes(), None);
+
+        // IPv6 routes come out of MP_REACH_NLRI with its next hop
+        let v6 = Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        let next_hop = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let pas = vec![
+            pas[0].clone(),
+            pas[1].clone(),
+            PathAttrBuilder::<NextHop>::new().next_hop(next_hop).build().unwrap()
+        ];
+        let update = UpdateBuilder::new().nlri(Nlri::new(std::slice::from_ref(&v6), &pas)).build().unwrap();
+        let payload = ReceivedRoutes::from_update(&update, peer_addr, 65000, &params);
+        assert_eq!((payload.afi(), payload.routes()), (Afi::Ipv6, Some(vec![v6])));
+        assert_eq!(payload.path_attrs_ref().iter().find_map(|pa| pa.next_hop()), Some(next_hop));
+        assert!(payload.path_attrs_ref().iter().all(|pa| pa.attr_type_code() != MP_REACH_NLRI));
+    }
+}
@@ -0,0 +1,1102 @@
+// Router configuration: what a routing daemon reads from its config file, deserialized with serde and turned
+// into the runtime structures the Speaker takes (BgpPeer, RouteMap, LocalRoutes). TOML is the native format,
+// anything serde can read (e.g. JSON) works through RouterConfig's Deserialize impl.
+// Peers can name a peer group, whose settings they inherit unless they set their own. Route maps refer to
+// prefix lists by name, peers and groups refer to route maps by name, so an unknown name is an error at load
+// time rather than a missing policy at runtime.
+// Aggregates are originated while the table holds a contributing route, one more specific than the aggregate
+// (see Speaker::set_aggregates). The more specifics aren't suppressed.
+// A ConfiguredSpeaker runs a Speaker from a config and moves it to a new one (e.g. on SIGHUP) with as
+// few changes as it can, so a reload doesn't take down sessions whose config didn't change.
+// Without a router_id the highest loopback address given to the ConfiguredSpeaker is used, or else the highest
+// IPv4 local address of the peers (see router_id::RouterIdSelector). A reload without one keeps the running ID.
+// Started with start_tcp, the peers' sessions run over TCP and their connections are accepted on the listen
+// address. Without one they're only made from our side.
+// iBGP peers set as route_reflector_client make the speaker a route reflector for them (RFC 4456), with the
+// router ID as the CLUSTER_ID. Changing which peers are clients takes a restart.
+// A peer with marker_check = "lenient" has the header marker skipped on its connections, for implementations
+// that don't send all ones there. One with an md5_password has its connections signed (RFC 2385), where the
+// platform can't do that the peer fails to start over TCP rather than coming up unsigned.
+// Paths with our AS in the AS_PATH are dropped unless the peer sets allowas_in to how many times it may be
+// there, e.g. for CE sites that share an AS and peer with us without as_override on the other end.
+//
+// router_id = "192.0.2.254"
+// local_as = 65000
+// listen = "0.0.0.0:179"
+// networks = ["198.51.100.0/24"]
+//
+// [prefix_lists]
+// bogons = ["permit 10.0.0.0/8 le 32", "permit 192.168.0.0/16 le 32"]
+//
+// [[route_maps.from-ixp]]
+// seq = 10
+// action = "deny"
+// match = { prefix_list = "bogons" }
+//
+// [[route_maps.from-ixp]]
+// seq = 20
+// action = "permit"
+// set = { local_pref = 50 }
+//
+// [peer_groups.ixp]
+// timers = { hold = 30 }
+// afi_safis = ["ipv4-unicast"]
+// import_policy = "from-ixp"
+// route_server_client = true
+//
+// [[peers]]
+// address = "192.0.2.1"
+// remote_as = 65001
+// local_address = "192.0.2.254"
+// group = "ixp"
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use serde::{de::IgnoredAny, Deserialize};
+
+use crate::{
+    export::{ExportOptions, LocalAs},
+    fsm_ds::{BgpPeer, PeerSessionBuilder},
+    message_types::{Afi, HostBits, Route, Safi},
+    path_attrs::OriginValue,
+    peer::{Connector, PeerHandle},
+    peer_group::MissingRemoteAs,
+    policy::{
+        CommunityPattern, Direction, MatchClause, PolicyAction, PolicyError, PrefixList, PrefixListEntry,
+        RouteMap, RouteMapEntry, SetAction,
+    },
+    router_id::{check_router_id, RouterIdError, RouterIdSelector},
+    speaker::{Speaker, SpeakerError},
+    table::LocalRoutes,
+    transport::MarkerCheck,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    // The file isn't valid TOML or doesn't have the expected shape
+    Syntax(String),
+    // A value that doesn't parse, e.g. a prefix or community
+    Invalid(String),
+    UnknownField(String),
+    UnknownPrefixList(String),
+    UnknownRouteMap(String),
+    UnknownGroup(String),
+    MissingRemoteAs(IpAddr),
+    DuplicatePeer(IpAddr),
+    RouterId(RouterIdError),
+    // The router ID, local AS and listen address can't change while the speaker runs
+    RestartRequired,
+    Speaker(SpeakerError),
+    // The listen address couldn't be bound, e.g. it's in use
+    Listen(SocketAddr, io::ErrorKind)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Syntax(msg) => write!(f, "invalid configuration: {}", msg),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+            ConfigError::UnknownField(field) => write!(f, "unknown field '{}'", field),
+            ConfigError::UnknownPrefixList(name) => write!(f, "no prefix list named '{}'", name),
+            ConfigError::UnknownRouteMap(name) => write!(f, "no route map named '{}'", name),
+            ConfigError::UnknownGroup(name) => write!(f, "no peer group named '{}'", name),
+            ConfigError::MissingRemoteAs(peer) => write!(f, "{}", MissingRemoteAs(*peer)),
+            ConfigError::DuplicatePeer(peer) => write!(f, "BGP peer {} is configured more than once", peer),
+            ConfigError::RouterId(err) => write!(f, "{}", err),
+            ConfigError::RestartRequired => write!(
+                f, "changing the router ID, local AS, listen address or route reflector clients requires a restart"
+            ),
+            ConfigError::Speaker(err) => write!(f, "{}", err),
+            ConfigError::Listen(addr, kind) => write!(f, "can't listen on {}: {}", addr, io::Error::from(*kind))
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<PolicyError> for ConfigError {
+    fn from(value: PolicyError) -> Self {
+        ConfigError::Invalid(value.to_string())
+    }
+}
+
+impl From<MissingRemoteAs> for ConfigError {
+    fn from(value: MissingRemoteAs) -> Self {
+        ConfigError::MissingRemoteAs(value.0)
+    }
+}
+
+impl From<RouterIdError> for ConfigError {
+    fn from(value: RouterIdError) -> Self {
+        ConfigError::RouterId(value)
+    }
+}
+
+impl From<SpeakerError> for ConfigError {
+    fn from(value: SpeakerError) -> Self {
+        ConfigError::Speaker(value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouterConfig {
+    // Picked from our addresses if not set
+    pub router_id: Option<Ipv4Addr>,
+    pub local_as: u16,
+    // Where TCP peers' connections are accepted, see ConfiguredSpeaker::start_tcp
+    pub listen: Option<SocketAddr>,
+    // Prefixes originated as they are
+    #[serde(default)]
+    pub networks: Vec<String>,
+    // Originated while there is a more specific route
+    #[serde(default)]
+    pub aggregates: Vec<String>,
+    // Entries are "<permit|deny> <prefix>/<len> [ge <len>] [le <len>]", numbered in steps of 10
+    #[serde(default)]
+    pub prefix_lists: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub route_maps: BTreeMap<String, Vec<RouteMapEntryConfig>>,
+    #[serde(default)]
+    pub peer_groups: BTreeMap<String, PeerGroupConfig>,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>
+}
+
+// Timers in seconds, unset ones keep the defaults (or the group's value)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimersConfig {
+    pub connect_retry: Option<usize>,
+    pub hold: Option<usize>,
+    pub keepalive: Option<usize>,
+    // Enables DelayOpen
+    pub delay_open: Option<usize>,
+    // Enables DampPeerOscillations with this initial IdleHoldTime
+    pub idle_hold: Option<usize>,
+    // How long stale routes are kept after a graceful restart
+    pub stale: Option<usize>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AfiSafiConfig {
+    Ipv4Unicast,
+    Ipv4Multicast,
+    Ipv6Unicast,
+    Ipv6Multicast
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkerCheckConfig {
+    Strict,
+    Lenient
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MigrationAsConfig {
+    pub asn: u16,
+    #[serde(default)]
+    pub no_prepend: bool,
+    #[serde(default)]
+    pub replace_as: bool
+}
+
+// Settings a peer group and its peers share, set on the peer they win over the group's
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct PeerSettings {
+    #[serde(default)]
+    pub timers: TimersConfig,
+    pub passive: Option<bool>,
+    pub afi_safis: Option<Vec<AfiSafiConfig>>,
+    // Route map names
+    pub import_policy: Option<String>,
+    pub export_policy: Option<String>,
+    pub route_server_client: Option<bool>,
+    pub route_reflector_client: Option<bool>,
+    pub as_override: Option<bool>,
+    // Times our AS may be in the AS_PATH of the peer's paths, the ingest side of as_override
+    pub allowas_in: Option<u8>,
+    pub remove_private_as: Option<bool>,
+    pub migration_as: Option<MigrationAsConfig>,
+    pub marker_check: Option<MarkerCheckConfig>,
+    pub md5_password: Option<String>
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct PeerGroupConfig {
+    pub remote_as: Option<u16>,
+    #[serde(flatten)]
+    pub settings: PeerSettings,
+    // Whatever the settings didn't take, deny_unknown_fields doesn't work with flatten
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct PeerConfig {
+    pub address: IpAddr,
+    // Can come from the group instead
+    pub remote_as: Option<u16>,
+    pub local_address: IpAddr,
+    pub group: Option<String>,
+    #[serde(flatten)]
+    pub settings: PeerSettings,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionConfig {
+    Permit,
+    Deny
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OriginConfig {
+    Igp,
+    Egp,
+    Incomplete
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteMapEntryConfig {
+    pub seq: u32,
+    pub action: ActionConfig,
+    #[serde(default, rename = "match")]
+    pub matches: MatchConfig,
+    #[serde(default)]
+    pub set: SetConfig
+}
+
+// All the clauses given have to match
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatchConfig {
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    pub prefix_list: Option<String>,
+    pub as_path_contains: Option<u16>,
+    pub neighbor_as: Option<u16>,
+    pub origin_as: Option<u16>,
+    // "<asn>:<value>" where either half can be "*"
+    pub community: Option<String>,
+    #[serde(default)]
+    pub has_communities: bool,
+    pub origin: Option<OriginConfig>,
+    pub med: Option<u32>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrependConfig {
+    pub asn: u16,
+    pub count: u8
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetConfig {
+    pub local_pref: Option<u32>,
+    pub med: Option<u32>,
+    pub weight: Option<u32>,
+    #[serde(default)]
+    pub add_communities: Vec<String>,
+    // An empty list removes all communities
+    pub set_communities: Option<Vec<String>>,
+    pub delete_communities: Option<String>,
+    pub as_path_prepend: Option<PrependConfig>,
+    pub next_hop: Option<IpAddr>
+}
+
+// What apply_config changed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigChanges {
+    pub added: Vec<IpAddr>,
+    pub removed: Vec<IpAddr>,
+    // Taken down and brought back up with the new settings
+    pub reset: Vec<IpAddr>,
+    // Timers changed on the running session
+    pub reconfigured: Vec<IpAddr>,
+    // The route map was replaced and the peer soft reset in that direction
+    pub policies: Vec<(IpAddr, Direction)>,
+    // Networks, aggregates come and go with their contributing routes
+    pub originated: Vec<Route>,
+    pub withdrawn: Vec<Route>
+}
+
+// A Speaker along with the config it's running, transport adds each peer to it
+pub struct ConfiguredSpeaker<F> {
+    config: RouterConfig,
+    speaker: Speaker,
+    transport: F
+}
+
+// How a ConfiguredSpeaker's peers connect: over TCP with TcpTransport, or with a connector per peer from a closure
+// (e.g. for simulations)
+pub trait PeerTransport {
+    fn add_peer(&mut self, speaker: &mut Speaker, peer: BgpPeer) -> Result<PeerHandle, SpeakerError>;
+}
+
+impl<K, F> PeerTransport for F
+where
+    K: Connector,
+    F: FnMut(&BgpPeer) -> K
+{
+    fn add_peer(&mut self, speaker: &mut Speaker, peer: BgpPeer) -> Result<PeerHandle, SpeakerError> {
+        let connector = self(&peer);
+        speaker.add_peer(peer, connector, None)
+    }
+}
+
+// See Speaker::add_tcp_peer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpTransport;
+
+impl PeerTransport for TcpTransport {
+    fn add_peer(&mut self, speaker: &mut Speaker, peer: BgpPeer) -> Result<PeerHandle, SpeakerError> {
+        speaker.add_tcp_peer(peer)
+    }
+}
+
+impl RouterConfig {
+    pub fn from_toml(config: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(config).map_err(|err| ConfigError::Syntax(err.message().to_string()))?;
+        config.check_unknown()?;
+        Ok(config)
+    }
+    pub fn from_json(config: &str) -> Result<Self, ConfigError> {
+        let config: Self = serde_json::from_str(config).map_err(|err| ConfigError::Syntax(err.to_string()))?;
+        config.check_unknown()?;
+        Ok(config)
+    }
+    fn check_unknown(&self) -> Result<(), ConfigError> {
+        self.peer_groups
+            .values()
+            .map(|group| &group.unknown)
+            .chain(self.peers.iter().map(|peer| &peer.unknown))
+            .find_map(|unknown| unknown.keys().next())
+            .map_or(Ok(()), |field| Err(ConfigError::UnknownField(field.clone())))
+    }
+    pub fn prefix_lists(&self) -> Result<BTreeMap<String, PrefixList>, ConfigError> {
+        self.prefix_lists
+            .iter()
+            .map(|(name, entries)| {
+                let list = entries
+                    .iter()
+                    .enumerate()
+                    .try_fold(PrefixList::new(), |list, (idx, entry)| {
+                        let seq = (idx as u32 + 1) * 10;
+                        let (action, entry) = match entry.trim().split_once(' ') {
+                            Some(("permit", entry)) => (PolicyAction::Permit, entry),
+                            Some(("deny", entry)) => (PolicyAction::Deny, entry),
+                            _ => return Err(ConfigError::Invalid(format!("invalid prefix list entry '{}'", entry)))
+                        };
+                        Ok(list.entry(PrefixListEntry::parse(seq, action, entry)?))
+                    })?;
+                Ok((name.clone(), list))
+            })
+            .collect()
+    }
+    pub fn route_maps(&self) -> Result<BTreeMap<String, RouteMap>, ConfigError> {
+        let prefix_lists = self.prefix_lists()?;
+        self.route_maps
+            .iter()
+            .map(|(name, entries)| {
+                let map = entries
+                    .iter()
+                    .try_fold(RouteMap::new(), |map, entry| Ok::<_, ConfigError>(map.entry(entry.build(&prefix_lists)?)))?;
+                Ok((name.clone(), map))
+            })
+            .collect()
+    }
+    pub fn originated(&self) -> Result<Option<LocalRoutes>, ConfigError> {
+        // The networks, None if there are none
+        let routes = self.originated_routes()?;
+        match routes.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(LocalRoutes::new(routes)))
+        }
+    }
+    fn originated_routes(&self) -> Result<Vec<Route>, ConfigError> {
+        self.networks.iter().map(|prefix| parse_prefix(prefix)).collect()
+    }
+    pub fn aggregates(&self) -> Result<Vec<Route>, ConfigError> {
+        self.aggregates.iter().map(|prefix| parse_prefix(prefix)).collect()
+    }
+    pub fn select_router_id(&self, loopbacks: Vec<Ipv4Addr>) -> Result<Ipv4Addr, ConfigError> {
+        // The configured router ID, or the highest of the loopbacks and then of the peers' local addresses
+        let addresses = self.peers
+            .iter()
+            .filter_map(|peer| match peer.local_address {
+                IpAddr::V4(addr) => Some(addr),
+                IpAddr::V6(_) => None
+            })
+            .collect();
+        let selector = RouterIdSelector::new()
+            .configured(self.router_id)
+            .loopbacks(loopbacks)
+            .addresses(addresses);
+        Ok(selector.select()?)
+    }
+    fn route_reflector_clients(&self) -> Result<Vec<IpAddr>, ConfigError> {
+        // Only iBGP peers are reflected between, the setting means nothing for the others
+        let mut clients = Vec::new();
+        for peer in self.peers.iter() {
+            let (remote_as, settings) = self.settings(peer)?;
+            if remote_as == self.local_as && settings.route_reflector_client == Some(true) {
+                clients.push(peer.address);
+            }
+        }
+        Ok(clients)
+    }
+    fn startup(&self, loopbacks: Vec<Ipv4Addr>) -> Result<(Ipv4Addr, Vec<BgpPeer>, Option<LocalRoutes>), ConfigError> {
+        // Everything a speaker starts with, checked before anything starts
+        self.aggregates()?;
+        Ok((self.select_router_id(loopbacks)?, self.peers()?, self.originated()?))
+    }
+    pub fn peers(&self) -> Result<Vec<BgpPeer>, ConfigError> {
+        let maps = self.route_maps()?;
+        let mut peers: Vec<BgpPeer> = Vec::new();
+        for peer in self.peers.iter() {
+            if peers.iter().any(|existing| existing.peer_address == peer.address) {
+                return Err(ConfigError::DuplicatePeer(peer.address));
+            }
+            peers.push(self.peer(peer, &maps)?);
+        }
+        Ok(peers)
+    }
+    fn settings(&self, peer: &PeerConfig) -> Result<(u16, PeerSettings), ConfigError> {
+        // The peer's remote AS and its settings with the group's merged in
+        let group = match &peer.group {
+            Some(name) => Some(self.peer_groups.get(name).ok_or_else(|| ConfigError::UnknownGroup(name.clone()))?),
+            None => None
+        };
+        let remote_as = peer.remote_as
+            .or(group.and_then(|group| group.remote_as))
+            .ok_or(MissingRemoteAs(peer.address))?;
+        let settings = match group {
+            Some(group) => group.settings.merge(&peer.settings),
+            None => peer.settings.clone()
+        };
+        Ok((remote_as, settings))
+    }
+    fn policy(&self, name: &Option<String>) -> Option<(&[RouteMapEntryConfig], Vec<&[String]>)> {
+        // Everything a route map is made of: its entries and the prefix lists they use
+        let entries = self.route_maps.get(name.as_ref()?)?;
+        let prefix_lists = entries
+            .iter()
+            .filter_map(|entry| entry.matches.prefix_list.as_ref())
+            .filter_map(|list| self.prefix_lists.get(list).map(|entries| entries.as_slice()))
+            .collect();
+        Some((entries, prefix_lists))
+    }
+    fn peer(&self, peer: &PeerConfig, maps: &BTreeMap<String, RouteMap>) -> Result<BgpPeer, ConfigError> {
+        let (remote_as, settings) = self.settings(peer)?;
+        let route_map = |name: &Option<String>| -> Result<Option<RouteMap>, ConfigError> {
+            match name {
+                Some(name) => maps.get(name).cloned().map(Some).ok_or_else(|| ConfigError::UnknownRouteMap(name.clone())),
+                None => Ok(None)
+            }
+        };
+        let mut bgp_peer = BgpPeer::new(peer.address, remote_as, peer.local_address, settings.session().build())
+            .import_policy(route_map(&settings.import_policy)?)
+            .export_policy(route_map(&settings.export_policy)?)
+            .export_options(settings.export_options())
+            .marker_check(settings.marker_mode())
+            .md5_password(settings.md5_password.clone())
+            .allowas_in(settings.allowas_in);
+        if let Some(afi_safis) = &settings.afi_safis {
+            bgp_peer = bgp_peer.afi_safis(afi_safis.iter().map(|afi_safi| afi_safi.afi_safi()).collect());
+        }
+        bgp_peer.group = peer.group.clone();
+        Ok(bgp_peer)
+    }
+}
+
+impl<F: PeerTransport> ConfiguredSpeaker<F> {
+    pub fn start(config: RouterConfig, transport: F) -> Result<Self, ConfigError> {
+        Self::start_with_loopbacks(config, Vec::new(), transport)
+    }
+    pub fn start_with_loopbacks(config: RouterConfig, loopbacks: Vec<Ipv4Addr>, transport: F) -> Result<Self, ConfigError> {
+        // Starts a Speaker with every configured peer. Everything is checked before anything starts.
+        // loopbacks are the host's loopback addresses, the router ID is picked from them if not configured.
+        let (router_id, peers, originated) = config.startup(loopbacks)?;
+        let speaker = Speaker::new(router_id, config.local_as)?;
+        Self::run(config, speaker, peers, originated, transport)
+    }
+    fn run(
+        config: RouterConfig,
+        mut speaker: Speaker,
+        peers: Vec<BgpPeer>,
+        originated: Option<LocalRoutes>,
+        mut transport: F
+    ) -> Result<Self, ConfigError> {
+        let clients = config.route_reflector_clients()?;
+        if !clients.is_empty() {
+            let reflector = clients
+                .into_iter()
+                .fold(speaker.route_reflector(), |reflector, client| reflector.client(client));
+            speaker.set_route_reflector(Some(reflector))?;
+        }
+        for peer in peers {
+            transport.add_peer(&mut speaker, peer)?;
+        }
+        if let Some(originated) = originated {
+            speaker.originate(originated)?;
+        }
+        let aggregates = config.aggregates()?;
+        if !aggregates.is_empty() {
+            speaker.set_aggregates(aggregates)?;
+        }
+        Ok(Self { config, speaker, transport })
+    }
+    pub fn config(&self) -> &RouterConfig {
+        &self.config
+    }
+    pub fn speaker(&self) -> &Speaker {
+        &self.speaker
+    }
+    pub async fn apply_config(&mut self, new: RouterConfig) -> Result<ConfigChanges, ConfigError> {
+        // Moves the speaker to the new config. Peers whose policy changed get the new route map and a soft
+        // reset in that direction, timers that don't go into the Open change on the running session, any
+        // other change resets the peer. Nothing changes if the new config is invalid.
+        let router_id = new.router_id.map(check_router_id).transpose()?;
+        if router_id.is_some_and(|id| id != self.speaker.router_id())
+            || new.local_as != self.config.local_as
+            || new.listen != self.config.listen
+            || new.route_reflector_clients()? != self.config.route_reflector_clients()?
+        {
+            return Err(ConfigError::RestartRequired);
+        }
+        let new_peers = new.peers()?;
+        let new_routes = new.originated_routes()?;
+        let aggregates = new.aggregates()?;
+        let old_routes = self.config.originated_routes()?;
+        let mut changes = ConfigChanges::default();
+
+        for old in self.config.peers.iter().filter(|old| new.peers.iter().all(|peer| peer.address != old.address)) {
+            self.speaker.remove_peer(old.address).await?;
+            changes.removed.push(old.address);
+        }
+        for (peer_config, peer) in new.peers.iter().zip(new_peers) {
+            let addr = peer.peer_address;
+            let old = match self.config.peers.iter().find(|old| old.address == addr) {
+                Some(old) => old,
+                None => {
+                    self.transport.add_peer(&mut self.speaker, peer)?;
+                    changes.added.push(addr);
+                    continue;
+                }
+            };
+            let (old_as, old_settings) = self.config.settings(old)?;
+            let (new_as, new_settings) = new.settings(peer_config)?;
+            if old_as != new_as || old.local_address != peer_config.local_address || old_settings.needs_reset(&new_settings) {
+                self.speaker.remove_peer(addr).await?;
+                self.transport.add_peer(&mut self.speaker, peer)?;
+                changes.reset.push(addr);
+                continue;
+            }
+            if old.group != peer_config.group {
+                self.speaker.set_group(addr, peer_config.group.clone())?;
+            }
+            let handle = self.speaker.peer(addr).ok_or(SpeakerError::UnknownPeer(addr))?;
+            if old_settings.timers != new_settings.timers || old_settings.passive != new_settings.passive {
+                // The task may be on its way out, the session doesn't matter then
+                _ = handle.reconfigure(new_settings.session().build());
+                changes.reconfigured.push(addr);
+            }
+            let (import, export) = peer.policies();
+            if self.config.policy(&old_settings.import_policy) != new.policy(&new_settings.import_policy) {
+                self.speaker.set_policy(addr, Direction::Import, import.cloned())?;
+                _ = handle.soft_reset_in();
+                changes.policies.push((addr, Direction::Import));
+            }
+            if self.config.policy(&old_settings.export_policy) != new.policy(&new_settings.export_policy) {
+                self.speaker.set_policy(addr, Direction::Export, export.cloned())?;
+                _ = handle.soft_reset_out();
+                changes.policies.push((addr, Direction::Export));
+            }
+        }
+
+        changes.withdrawn = old_routes.iter().filter(|route| !new_routes.contains(route)).cloned().collect();
+        changes.originated = new_routes.iter().filter(|route| !old_routes.contains(route)).cloned().collect();
+        if !changes.withdrawn.is_empty() {
+            self.speaker.withdraw_originated(changes.withdrawn.clone())?;
+        }
+        if !changes.originated.is_empty() {
+            self.speaker.originate(LocalRoutes::new(changes.originated.clone()))?;
+        }
+        if aggregates != self.config.aggregates()? {
+            self.speaker.set_aggregates(aggregates)?;
+        }
+        self.config = new;
+        Ok(changes)
+    }
+    pub async fn shutdown(self) {
+        self.speaker.shutdown().await
+    }
+}
+
+impl ConfiguredSpeaker<TcpTransport> {
+    pub async fn start_tcp(config: RouterConfig, loopbacks: Vec<Ipv4Addr>) -> Result<Self, ConfigError> {
+        // start_with_loopbacks with the peers' sessions over TCP. The listener is up before any peer is added,
+        // so none of their connections are turned away.
+        let (router_id, peers, originated) = config.startup(loopbacks)?;
+        let mut speaker = Speaker::new(router_id, config.local_as)?;
+        if let Some(listen) = config.listen {
+            speaker.listen(listen).await.map_err(|err| ConfigError::Listen(listen, err.kind()))?;
+        }
+        Self::run(config, speaker, peers, originated, TcpTransport)
+    }
+}
+
+impl TimersConfig {
+    pub fn merge(&self, over: &TimersConfig) -> TimersConfig {
+        TimersConfig {
+            connect_retry: over.connect_retry.or(self.connect_retry),
+            hold: over.hold.or(self.hold),
+            keepalive: over.keepalive.or(self.keepalive),
+            delay_open: over.delay_open.or(self.delay_open),
+            idle_hold: over.idle_hold.or(self.idle_hold),
+            stale: over.stale.or(self.stale)
+        }
+    }
+}
+
+impl AfiSafiConfig {
+    pub fn afi_safi(&self) -> (Afi, Safi) {
+        match self {
+            AfiSafiConfig::Ipv4Unicast => (Afi::Ipv4, Safi::Unicast),
+            AfiSafiConfig::Ipv4Multicast => (Afi::Ipv4, Safi::Multicast),
+            AfiSafiConfig::Ipv6Unicast => (Afi::Ipv6, Safi::Unicast),
+            AfiSafiConfig::Ipv6Multicast => (Afi::Ipv6, Safi::Multicast)
+        }
+    }
+}
+
+impl PeerSettings {
+    pub fn merge(&self, over: &PeerSettings) -> PeerSettings {
+        // The settings with the ones set in over replacing them
+        PeerSettings {
+            timers: self.timers.merge(&over.timers),
+            passive: over.passive.or(self.passive),
+            afi_safis: over.afi_safis.clone().or_else(|| self.afi_safis.clone()),
+            import_policy: over.import_policy.clone().or_else(|| self.import_policy.clone()),
+            export_policy: over.export_policy.clone().or_else(|| self.export_policy.clone()),
+            route_server_client: over.route_server_client.or(self.route_server_client),
+            route_reflector_client: over.route_reflector_client.or(self.route_reflector_client),
+            as_override: over.as_override.or(self.as_override),
+            allowas_in: over.allowas_in.or(self.allowas_in),
+            remove_private_as: over.remove_private_as.or(self.remove_private_as),
+            migration_as: over.migration_as.or(self.migration_as),
+            marker_check: over.marker_check.or(self.marker_check),
+            md5_password: over.md5_password.clone().or_else(|| self.md5_password.clone())
+        }
+    }
+    pub fn needs_reset(&self, new: &PeerSettings) -> bool {
+        // HoldTime, KeepaliveTime and the address families go into the Open. The export options
+        // are part of the peer's ExportPeer, the marker check and MD5 password of its connections and
+        // its allowas-in count are set when the peer is added as well.
+        self.timers.hold != new.timers.hold
+            || self.timers.keepalive != new.timers.keepalive
+            || self.afi_safis != new.afi_safis
+            || self.export_options() != new.export_options()
+            || self.marker_mode() != new.marker_mode()
+            || self.md5_password != new.md5_password
+            || self.allowas_in != new.allowas_in
+    }
+    pub fn session(&self) -> PeerSessionBuilder {
+        let timers = &self.timers;
+        let mut session = PeerSessionBuilder::new();
+        if let Some(time) = timers.connect_retry {
+            session = session.conn_retry_time(time);
+        }
+        if let Some(time) = timers.hold {
+            session = session.hold_time(time);
+        }
+        if let Some(time) = timers.keepalive {
+            session = session.keep_time(time);
+        }
+        if let Some(time) = timers.delay_open {
+            session = session.delay_open(time);
+        }
+        if let Some(time) = timers.idle_hold {
+            session = session.damp_peer_oscillations(time);
+        }
+        if let Some(time) = timers.stale {
+            session = session.stale_time(time);
+        }
+        match self.passive {
+            Some(true) => session.passive(),
+            _ => session
+        }
+    }
+    pub fn marker_mode(&self) -> MarkerCheck {
+        match self.marker_check {
+            Some(MarkerCheckConfig::Lenient) => MarkerCheck::Lenient,
+            Some(MarkerCheckConfig::Strict) | None => MarkerCheck::Strict
+        }
+    }
+    pub fn export_options(&self) -> ExportOptions {
+        ExportOptions {
+            route_server_client: self.route_server_client.unwrap_or_default(),
+            as_override: self.as_override.unwrap_or_default(),
+            remove_private_as: self.remove_private_as.unwrap_or_default(),
+            migration_as: self.migration_as.map(|migration| {
+                let local_as = LocalAs::new(migration.asn);
+                let local_as = match migration.no_prepend {
+                    true => local_as.no_prepend(),
+                    false => local_as
+                };
+                match migration.replace_as {
+                    true => local_as.replace_as(),
+                    false => local_as
+                }
+            })
+        }
+    }
+}
+
+impl RouteMapEntryConfig {
+    fn build(&self, prefix_lists: &BTreeMap<String, PrefixList>) -> Result<RouteMapEntry, ConfigError> {
+        let action = match self.action {
+            ActionConfig::Permit => PolicyAction::Permit,
+            ActionConfig::Deny => PolicyAction::Deny
+        };
+        let matches = &self.matches;
+        let mut clauses: Vec<MatchClause> = Vec::new();
+        if !matches.prefixes.is_empty() {
+            let prefixes = matches.prefixes
+                .iter()
+                .map(|prefix| parse_prefix(prefix))
+                .collect::<Result<Vec<Route>, ConfigError>>()?;
+            clauses.push(MatchClause::Prefix(prefixes));
+        }
+        if let Some(name) = &matches.prefix_list {
+            let list = prefix_lists.get(name).ok_or_else(|| ConfigError::UnknownPrefixList(name.clone()))?;
+            clauses.push(MatchClause::PrefixList(list.clone()));
+        }
+        clauses.extend(matches.as_path_contains.map(MatchClause::AsPathContains));
+        clauses.extend(matches.neighbor_as.map(MatchClause::NeighborAs));
+        clauses.extend(matches.origin_as.map(MatchClause::OriginAs));
+        if let Some(pattern) = &matches.community {
+            clauses.push(MatchClause::CommunityPattern(CommunityPattern::parse(pattern)?));
+        }
+        if matches.has_communities {
+            clauses.push(MatchClause::HasCommunities);
+        }
+        clauses.extend(matches.origin.map(|origin| MatchClause::Origin(match origin {
+            OriginConfig::Igp => OriginValue::Igp,
+            OriginConfig::Egp => OriginValue::Egp,
+            OriginConfig::Incomplete => OriginValue::Incomplete
+        })));
+        clauses.extend(matches.med.map(MatchClause::Med));
+
+        let set = &self.set;
+        let mut sets: Vec<SetAction> = Vec::new();
+        sets.extend(set.local_pref.map(SetAction::LocalPref));
+        sets.extend(set.med.map(SetAction::Med));
+        sets.extend(set.weight.map(SetAction::Weight));
+        if let Some(communities) = &set.set_communities {
+            sets.push(SetAction::SetCommunities(parse_communities(communities)?));
+        }
+        if !set.add_communities.is_empty() {
+            sets.push(SetAction::AddCommunities(parse_communities(&set.add_communities)?));
+        }
+        if let Some(pattern) = &set.delete_communities {
+            sets.push(SetAction::DeleteCommunities(CommunityPattern::parse(pattern)?));
+        }
+        sets.extend(set.as_path_prepend.map(|prepend| SetAction::AsPathPrepend(prepend.asn, prepend.count)));
+        sets.extend(set.next_hop.map(SetAction::NextHop));
+
+        let entry = clauses
+            .into_iter()
+            .fold(RouteMapEntry::new(self.seq, action), |entry, clause| entry.match_clause(clause));
+        Ok(sets.into_iter().fold(entry, |entry, set| entry.set(set)))
+    }
+}
+
+pub(crate) fn parse_prefix(prefix: &str) -> Result<Route, ConfigError> {
+    // "<addr>/<len>", host bits are cleared
+    Route::parse(prefix, HostBits::Normalize).map_err(|err| ConfigError::Invalid(format!("invalid prefix '{}': {}", prefix, err)))
+}
+
+pub(crate) fn parse_communities(communities: &[String]) -> Result<Vec<u32>, ConfigError> {
+    // "<asn>:<value>", both halves given
+    communities
+        .iter()
+        .map(|community| {
+            let err = || ConfigError::Invalid(format!("invalid community '{}'", community));
+            let (asn, value) = community.split_once(':').ok_or_else(err)?;
+            let asn: u16 = asn.parse().map_err(|_| err())?;
+            let value: u16 = value.parse().map_err(|_| err())?;
+            Ok(((asn as u32) << 16) | value as u32)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{future::{self, Future}, io, pin::Pin};
+    use crate::{
+        bgp_codec::BgpCodec,
+        fsm_ds::Event,
+        path_attrs::{Origin, PaBuilder, PathAttrBuilder},
+        peer::{Connection, Inbound, Outbound},
+        transport::TcpConnector,
+    };
+
+    // Peers never get a connection, the sessions stay down
+    struct NoConn;
+
+    impl Connection for NoConn {
+        fn send(&mut self, _msg: Outbound) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+            Box::pin(future::pending())
+        }
+        fn recv(&mut self) -> Pin<Box<dyn Future<Output = Option<Inbound>> + Send + '_>> {
+            Box::pin(future::pending())
+        }
+    }
+
+    struct SilentConnector;
+
+    impl Connector for SilentConnector {
+        type Conn = NoConn;
+        fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<NoConn>> + Send>> {
+            Box::pin(future::pending())
+        }
+    }
+
+    const CONFIG: &str = r#"
+        router_id = "192.0.2.254"
+        local_as = 65000
+        networks = ["198.51.100.0/24", "2001:db8::/32"]
+        aggregates = ["203.0.112.0/22"]
+
+        [prefix_lists]
+        bogons = ["permit 10.0.0.0/8 le 32", "deny 0.0.0.0/0 le 32"]
+
+        [[route_maps.from-ixp]]
+        seq = 10
+        action = "deny"
+        match = { prefix_list = "bogons" }
+
+        [[route_maps.from-ixp]]
+        seq = 20
+        action = "permit"
+        set = { local_pref = 50, add_communities = ["65000:100"] }
+
+        [peer_groups.ixp]
+        timers = { hold = 30, keepalive = 10 }
+        afi_safis = ["ipv4-unicast"]
+        import_policy = "from-ixp"
+        route_server_client = true
+        md5_password = "secret"
+
+        [[peers]]
+        address = "192.0.2.1"
+        remote_as = 65001
+        local_address = "192.0.2.254"
+        group = "ixp"
+        timers = { hold = 90 }
+
+        [[peers]]
+        address = "2001:db8::1"
+        remote_as = 65002
+        local_address = "2001:db8::fe"
+        passive = true
+        marker_check = "lenient"
+        allowas_in = 2
+    "#;
+
+    #[test]
+    fn config_from_toml() {
+        let config = RouterConfig::from_toml(CONFIG).unwrap();
+        assert_eq!((config.router_id, config.local_as), (Some(Ipv4Addr::new(192, 0, 2, 254)), 65000));
+
+        let peers = config.peers().unwrap();
+        let ixp = &peers[0];
+        // The hold time is the peer's, the rest comes from the group
+        assert_eq!((ixp.remote_as, ixp.group.as_deref()), (65001, Some("ixp")));
+        assert_eq!((ixp.session().hold_time(), ixp.session().keep_time()), (90, 10));
+        assert_eq!(ixp.families(), &[(Afi::Ipv4, Safi::Unicast)]);
+        assert!(ixp.export_settings().route_server_client);
+        let (import, export) = ixp.policies();
+        assert!(export.is_none());
+        let import = import.unwrap();
+        let pas = vec![PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap()];
+        assert!(import.apply(&Route::new(16, "10.1.0.0".parse().unwrap()), &pas).is_none());
+        let pas = import.apply(&Route::new(24, "198.51.100.0".parse().unwrap()), &pas).unwrap();
+        assert!(pas.iter().any(|pa| pa.local_pref() == Some(50)));
+
+        let other = &peers[1];
+        assert_eq!(other.group, None);
+        assert!(other.session().passive());
+        assert_eq!(other.families().len(), 2);
+        assert!(matches!(other.policies(), (None, None)));
+        assert_eq!((ixp.marker_mode(), other.marker_mode()), (MarkerCheck::Strict, MarkerCheck::Lenient));
+        assert_eq!((ixp.password(), other.password()), (Some("secret"), None));
+        assert_eq!((ixp.own_as_allowed(), other.own_as_allowed()), (0, 2));
+
+        let originated = config.originated().unwrap().unwrap();
+        assert!(originated.for_afi(Afi::Ipv4).is_some());
+        assert!(originated.for_afi(Afi::Ipv6).is_some());
+    }
+
+    #[test]
+    fn config_errors() {
+        let load = |extra: &str| RouterConfig::from_toml(&format!("{}\n{}", CONFIG, extra)).and_then(|config| config.peers()).map(|_| ());
+        let peer = |fields: &str| format!(
+            "[[peers]]\naddress = \"192.0.2.9\"\nlocal_address = \"192.0.2.254\"\n{}", fields
+        );
+        assert_eq!(load(&peer("remote_as = 1\nhold = 3")), Err(ConfigError::UnknownField("hold".to_string())));
+        assert_eq!(load(&peer("group = \"ixp\"")), Err(ConfigError::MissingRemoteAs("192.0.2.9".parse().unwrap())));
+        assert_eq!(load(&peer("remote_as = 1\ngroup = \"rr\"")), Err(ConfigError::UnknownGroup("rr".to_string())));
+        assert_eq!(
+            load(&peer("remote_as = 1\nexport_policy = \"to-ixp\"")),
+            Err(ConfigError::UnknownRouteMap("to-ixp".to_string()))
+        );
+        assert_eq!(
+            load(&peer("remote_as = 1\naddress = \"192.0.2.1\"").replace("address = \"192.0.2.9\"\n", "")),
+            Err(ConfigError::DuplicatePeer("192.0.2.1".parse().unwrap()))
+        );
+        assert!(matches!(load(&peer("remote_as = 1\nafi_safis = [\"l2vpn\"]")), Err(ConfigError::Syntax(_))));
+
+        let config = RouterConfig::from_toml("router_id = \"192.0.2.254\"\nlocal_as = 1\nnetworks = [\"10.0.0.0/33\"]").unwrap();
+        assert!(matches!(config.originated(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn config_router_id() {
+        let config = RouterConfig::from_toml(&CONFIG.replace("router_id = \"192.0.2.254\"", "")).unwrap();
+        assert_eq!(config.router_id, None);
+        // The peers' IPv4 local address, unless there's a loopback
+        assert_eq!(config.select_router_id(Vec::new()), Ok(Ipv4Addr::new(192, 0, 2, 254)));
+        assert_eq!(config.select_router_id(vec![Ipv4Addr::new(10, 0, 0, 1)]), Ok(Ipv4Addr::new(10, 0, 0, 1)));
+
+        let config = RouterConfig::from_toml("local_as = 1").unwrap();
+        assert_eq!(config.select_router_id(Vec::new()), Err(ConfigError::RouterId(RouterIdError::NoCandidates)));
+        let multicast = Ipv4Addr::new(224, 0, 0, 1);
+        let config = RouterConfig::from_toml("router_id = \"224.0.0.1\"\nlocal_as = 1").unwrap();
+        assert_eq!(
+            ConfiguredSpeaker::start(config, |_: &BgpPeer| SilentConnector).err(),
+            Some(ConfigError::RouterId(RouterIdError::Invalid(multicast)))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn config_route_reflector() {
+        // Only the iBGP peer is a client, the CLUSTER_ID is the router ID
+        let clients = CONFIG.replace("passive = true", "passive = true\nroute_reflector_client = true")
+            + "[[peers]]\naddress = \"192.0.2.2\"\nremote_as = 65000\nlocal_address = \"192.0.2.254\"\nroute_reflector_client = true";
+        let mut speaker = ConfiguredSpeaker::start(RouterConfig::from_toml(&clients).unwrap(), |_: &BgpPeer| SilentConnector).unwrap();
+        let reflector = speaker.speaker().reflector().unwrap();
+        assert_eq!(reflector.cluster_id(), Ipv4Addr::new(192, 0, 2, 254));
+        assert!(reflector.is_client("192.0.2.2".parse().unwrap()));
+        assert!(!reflector.is_client("2001:db8::1".parse().unwrap()));
+
+        let mut config = speaker.config().clone();
+        config.peers[2].settings.route_reflector_client = None;
+        assert_eq!(speaker.apply_config(config).await, Err(ConfigError::RestartRequired));
+        speaker.shutdown().await;
+
+        let speaker = ConfiguredSpeaker::start(RouterConfig::from_toml(CONFIG).unwrap(), |_: &BgpPeer| SilentConnector).unwrap();
+        assert!(speaker.speaker().reflector().is_none());
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn config_reload() {
+        let mut speaker = ConfiguredSpeaker::start(RouterConfig::from_toml(CONFIG).unwrap(), |_: &BgpPeer| SilentConnector).unwrap();
+        assert_eq!(speaker.speaker().peers().count(), 2);
+        let changes = speaker.apply_config(RouterConfig::from_toml(CONFIG).unwrap()).await.unwrap();
+        assert_eq!(changes, ConfigChanges::default());
+
+        let ixp: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+        let added: IpAddr = "192.0.2.3".parse().unwrap();
+        let new = CONFIG
+            .replace("timers = { hold = 90 }", "timers = { hold = 90, connect_retry = 5 }")
+            .replace("local_pref = 50", "local_pref = 60")
+            .replace("remote_as = 65002", "remote_as = 65003")
+            .replace("\"198.51.100.0/24\"", "\"198.51.100.0/25\"")
+            + "[[peers]]\naddress = \"192.0.2.3\"\nremote_as = 65004\nlocal_address = \"192.0.2.254\"\ngroup = \"ixp\"";
+        let changes = speaker.apply_config(RouterConfig::from_toml(&new).unwrap()).await.unwrap();
+        assert_eq!(changes, ConfigChanges {
+            added: vec![added],
+            reset: vec![other],
+            reconfigured: vec![ixp],
+            policies: vec![(ixp, Direction::Import)],
+            originated: vec![Route::new(25, "198.51.100.0".parse().unwrap())],
+            withdrawn: vec![Route::new(24, "198.51.100.0".parse().unwrap())],
+            ..Default::default()
+        });
+        assert_eq!(speaker.speaker().group_peers("ixp").count(), 2);
+
+        let mut removed = speaker.config().clone();
+        removed.peers.retain(|peer| peer.address != other);
+        let changes = speaker.apply_config(removed.clone()).await.unwrap();
+        assert_eq!(changes.removed, vec![other]);
+        assert!(speaker.speaker().peer(other).is_none());
+
+        // Invalid configs change nothing
+        removed.local_as = 65010;
+        assert_eq!(speaker.apply_config(removed.clone()).await, Err(ConfigError::RestartRequired));
+        removed.local_as = 65000;
+        removed.router_id = Some(Ipv4Addr::new(192, 0, 2, 253));
+        assert_eq!(speaker.apply_config(removed.clone()).await, Err(ConfigError::RestartRequired));
+        removed.router_id = Some(Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            speaker.apply_config(removed.clone()).await,
+            Err(ConfigError::RouterId(RouterIdError::Invalid(Ipv4Addr::UNSPECIFIED)))
+        );
+        removed.router_id = None;
+        removed.peers[0].group = Some("rr".to_string());
+        assert_eq!(speaker.apply_config(removed).await, Err(ConfigError::UnknownGroup("rr".to_string())));
+        assert_eq!(speaker.speaker().peers().count(), 2);
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn config_start_tcp() {
+        let config = RouterConfig::from_toml(r#"
+            router_id = "192.0.2.254"
+            local_as = 65000
+            listen = "127.0.0.1:0"
+
+            [[peers]]
+            address = "127.0.0.1"
+            remote_as = 65001
+            local_address = "0.0.0.0"
+            passive = true
+        "#).unwrap();
+        let speaker = ConfiguredSpeaker::start_tcp(config.clone(), Vec::new()).await.unwrap();
+        let addr = speaker.speaker().listen_addr().unwrap();
+
+        // The passive peer's connection is taken and sent our Open
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let connector = TcpConnector::new(localhost, BgpCodec::new(localhost, 65001)).port(addr.port());
+        let mut conn = connector.connect().await.unwrap();
+        assert!(matches!(conn.recv().await, Some(Inbound::Event(Event::BGPOpen(_)))));
+
+        let mut taken = config;
+        taken.listen = Some(addr);
+        assert_eq!(
+            ConfiguredSpeaker::start_tcp(taken, Vec::new()).await.err(),
+            Some(ConfigError::Listen(addr, io::ErrorKind::AddrInUse))
+        );
+        speaker.shutdown().await;
+    }
+}
@@ -1,6 +1,21 @@
 // This module will contain all the error types that can be used in the NOTIFICATION message.
 // Seems like the easiest way to define these is using enums.
-use std::convert::From;
+// BgpError sits on top of them for applications embedding the crate, every failure they can see is one
+// of its kinds: a message that didn't decode, a session the FSM closed, the table being unreachable or
+// a configuration that was refused. The NOTIFICATION codes convert into the first two.
+use std::{
+    convert::From,
+    error::Error,
+    fmt,
+};
+
+use crate::{
+    comms::InjectionError,
+    config::ConfigError,
+    path_attrs::{AGGREGATOR, ATOMIC_AGGREGATE},
+    speaker::SpeakerError,
+    table_handle::TableClosed,
+};
 
 
 // Constants
@@ -48,6 +63,26 @@ const UNSUPPORTED_OPT_PARAM: u8 = 4;
 // Unacceptable Hold Time.
 const UNACCEPTABLE_HOLD_TIME: u8 = 6;
 
+// ** Cease Subcodes **
+// RFC 4486, Pg. 2
+
+// Maximum Number of Prefixes Reached
+const MAX_PREFIXES_REACHED: u8 = 1;
+// Administrative Shutdown
+const ADMIN_SHUTDOWN: u8 = 2;
+// Peer De-configured
+const PEER_DECONFIGURED: u8 = 3;
+// Administrative Reset
+const ADMIN_RESET: u8 = 4;
+// Connection Rejected
+const CONNECTION_REJECTED: u8 = 5;
+// Other Configuration Change
+const OTHER_CONFIG_CHANGE: u8 = 6;
+// Connection Collision Resolution
+const CONNECTION_COLLISION: u8 = 7;
+// Out of Resources
+const OUT_OF_RESOURCES: u8 = 8;
+
 // ** Message Header Error Subcodes **
 
 // Connection Not Synchronized.
@@ -57,25 +92,27 @@ const BAD_MSG_LEN: u8 = 2;
 // Bad Message Type.
 const BAD_MSG_TYPE: u8 = 3;
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum NotifErrorCode {
+#[derive(Clone, Debug, PartialEq)]
+pub enum NotifErrorCode {
     MessageHeaderError(MsgHeaderErrSubcode),
     OpenMessageError(OpenMsgErrSubcode),
     UpdateMessageError(UpdateMsgErrSubcode),
     HoldTimerExpired,
     FiniteStateMachineError,
-    Cease
+    Cease,
+    // Cease with one of the subcodes from RFC 4486
+    CeaseReason(CeaseSubcode)
 }
 
-impl NotifErrorCode {
-    pub fn as_ref(&self) -> &Self {
-        &self
+impl AsRef<NotifErrorCode> for NotifErrorCode {
+    fn as_ref(&self) -> &Self {
+        self
     }
 }
 
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum OpenMsgErrSubcode {
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpenMsgErrSubcode {
     UnsupportedVerNum,
     BadPeerAs,
     BadBgpId,
@@ -83,27 +120,45 @@ pub(crate) enum OpenMsgErrSubcode {
     UnacceptableHoldTime,
 }
 
-impl OpenMsgErrSubcode {
-    pub fn as_ref(&self) -> &Self {
-        &self
+impl AsRef<OpenMsgErrSubcode> for OpenMsgErrSubcode {
+    fn as_ref(&self) -> &Self {
+        self
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum MsgHeaderErrSubcode {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CeaseSubcode {
+    MaxPrefixesReached,
+    AdminShutdown,
+    PeerDeconfigured,
+    AdminReset,
+    ConnectionRejected,
+    OtherConfigChange,
+    ConnectionCollision,
+    OutOfResources,
+}
+
+impl AsRef<CeaseSubcode> for CeaseSubcode {
+    fn as_ref(&self) -> &Self {
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MsgHeaderErrSubcode {
     ConnNotSynced,
     BadMsgLen,
     BadMsgType,
 }
 
-impl MsgHeaderErrSubcode {
-    pub fn as_ref(&self) -> &Self {
-        &self
+impl AsRef<MsgHeaderErrSubcode> for MsgHeaderErrSubcode {
+    fn as_ref(&self) -> &Self {
+        self
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum UpdateMsgErrSubcode {
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateMsgErrSubcode {
     MalformedAttrList,
     UnrecognizedWkAttr,
     MissingWkAttr,
@@ -116,9 +171,9 @@ pub(crate) enum UpdateMsgErrSubcode {
     MalformedAsPath,
 }
 
-impl UpdateMsgErrSubcode {
-    pub fn as_ref(&self) -> &Self {
-        &self
+impl AsRef<UpdateMsgErrSubcode> for UpdateMsgErrSubcode {
+    fn as_ref(&self) -> &Self {
+        self
     }
 }
 
@@ -133,6 +188,7 @@ impl From<&NotifErrorCode> for u8 {
             NotifErrorCode::HoldTimerExpired => HOLD_TIMER_EXP_ERR,
             NotifErrorCode::FiniteStateMachineError => FSM_ERR,
             NotifErrorCode::Cease => CEASE_ERR,
+            NotifErrorCode::CeaseReason(_) => CEASE_ERR,
         }
     }
 }
@@ -149,6 +205,21 @@ impl From<&OpenMsgErrSubcode> for u8 {
     }
 }
 
+impl From<&CeaseSubcode> for u8 {
+    fn from(value: &CeaseSubcode) -> Self {
+        match value {
+            CeaseSubcode::MaxPrefixesReached => MAX_PREFIXES_REACHED,
+            CeaseSubcode::AdminShutdown => ADMIN_SHUTDOWN,
+            CeaseSubcode::PeerDeconfigured => PEER_DECONFIGURED,
+            CeaseSubcode::AdminReset => ADMIN_RESET,
+            CeaseSubcode::ConnectionRejected => CONNECTION_REJECTED,
+            CeaseSubcode::OtherConfigChange => OTHER_CONFIG_CHANGE,
+            CeaseSubcode::ConnectionCollision => CONNECTION_COLLISION,
+            CeaseSubcode::OutOfResources => OUT_OF_RESOURCES,
+        }
+    }
+}
+
 impl From<&MsgHeaderErrSubcode> for u8 {
     fn from(value: &MsgHeaderErrSubcode) -> Self {
         match value {
@@ -176,6 +247,336 @@ impl From<&UpdateMsgErrSubcode> for u8 {
     }
 }
 
+// Error (Sub)code names are those of RFC 4271, Section 4.5 and RFC 4486
+impl fmt::Display for NotifErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifErrorCode::MessageHeaderError(subcode) => write!(f, "Message Header Error: {}", subcode),
+            NotifErrorCode::OpenMessageError(subcode) => write!(f, "OPEN Message Error: {}", subcode),
+            NotifErrorCode::UpdateMessageError(subcode) => write!(f, "UPDATE Message Error: {}", subcode),
+            NotifErrorCode::HoldTimerExpired => write!(f, "Hold Timer Expired"),
+            NotifErrorCode::FiniteStateMachineError => write!(f, "Finite State Machine Error"),
+            NotifErrorCode::Cease => write!(f, "Cease"),
+            NotifErrorCode::CeaseReason(subcode) => write!(f, "Cease: {}", subcode)
+        }
+    }
+}
+impl Error for NotifErrorCode {}
+
+impl fmt::Display for MsgHeaderErrSubcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MsgHeaderErrSubcode::ConnNotSynced => "Connection Not Synchronized",
+            MsgHeaderErrSubcode::BadMsgLen => "Bad Message Length",
+            MsgHeaderErrSubcode::BadMsgType => "Bad Message Type"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for OpenMsgErrSubcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OpenMsgErrSubcode::UnsupportedVerNum => "Unsupported Version Number",
+            OpenMsgErrSubcode::BadPeerAs => "Bad Peer AS",
+            OpenMsgErrSubcode::BadBgpId => "Bad BGP Identifier",
+            OpenMsgErrSubcode::UnsupportedOptParam => "Unsupported Optional Parameter",
+            OpenMsgErrSubcode::UnacceptableHoldTime => "Unacceptable Hold Time"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for UpdateMsgErrSubcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            UpdateMsgErrSubcode::MalformedAttrList => "Malformed Attribute List",
+            UpdateMsgErrSubcode::UnrecognizedWkAttr => "Unrecognized Well-known Attribute",
+            UpdateMsgErrSubcode::MissingWkAttr => "Missing Well-known Attribute",
+            UpdateMsgErrSubcode::AttrFlagsError => "Attribute Flags Error",
+            UpdateMsgErrSubcode::AttrLengthError => "Attribute Length Error",
+            UpdateMsgErrSubcode::InvalidOriginAttr => "Invalid ORIGIN Attribute",
+            UpdateMsgErrSubcode::InvalidNextHopAttr => "Invalid NEXT_HOP Attribute",
+            UpdateMsgErrSubcode::OptionalAttrError => "Optional Attribute Error",
+            UpdateMsgErrSubcode::InvalidNetworkField => "Invalid Network Field",
+            UpdateMsgErrSubcode::MalformedAsPath => "Malformed AS_PATH"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for CeaseSubcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CeaseSubcode::MaxPrefixesReached => "Maximum Number of Prefixes Reached",
+            CeaseSubcode::AdminShutdown => "Administrative Shutdown",
+            CeaseSubcode::PeerDeconfigured => "Peer De-configured",
+            CeaseSubcode::AdminReset => "Administrative Reset",
+            CeaseSubcode::ConnectionRejected => "Connection Rejected",
+            CeaseSubcode::OtherConfigChange => "Other Configuration Change",
+            CeaseSubcode::ConnectionCollision => "Connection Collision Resolution",
+            CeaseSubcode::OutOfResources => "Out of Resources"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl From<MsgHeaderErrSubcode> for NotifErrorCode {
+    fn from(subcode: MsgHeaderErrSubcode) -> Self {
+        NotifErrorCode::MessageHeaderError(subcode)
+    }
+}
+
+impl From<OpenMsgErrSubcode> for NotifErrorCode {
+    fn from(subcode: OpenMsgErrSubcode) -> Self {
+        NotifErrorCode::OpenMessageError(subcode)
+    }
+}
+
+impl From<UpdateMsgErrSubcode> for NotifErrorCode {
+    fn from(subcode: UpdateMsgErrSubcode) -> Self {
+        NotifErrorCode::UpdateMessageError(subcode)
+    }
+}
+
+impl From<CeaseSubcode> for NotifErrorCode {
+    fn from(subcode: CeaseSubcode) -> Self {
+        NotifErrorCode::CeaseReason(subcode)
+    }
+}
+
+// What's done about an error in a received message. Before RFC 7606 every error took the session down,
+// now most errors in an UPDATE only cost the routes or the attribute. RFC 7606, Section 2
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorAction {
+    // The NOTIFICATION is sent and the session goes down
+    SessionReset,
+    // The UPDATE's routes are withdrawn rather than announced
+    TreatAsWithdraw,
+    // The attribute is dropped, the rest of the UPDATE stands
+    AttributeDiscard,
+    // The UPDATE is ignored, e.g. treat-as-withdraw with nothing to withdraw
+    MessageDiscard
+}
+
+impl ErrorAction {
+    pub fn for_attr(type_code: u8) -> Self {
+        // A malformed attribute of the type. Only those that can't affect route selection are discarded,
+        // unknown ones included. RFC 7606, Section 7
+        match type_code {
+            ATOMIC_AGGREGATE | AGGREGATOR => ErrorAction::AttributeDiscard,
+            _ => ErrorAction::TreatAsWithdraw
+        }
+    }
+    pub fn resets_session(&self) -> bool {
+        matches!(self, ErrorAction::SessionReset)
+    }
+}
+
+impl NotifErrorCode {
+    pub fn action(&self) -> ErrorAction {
+        // Only UPDATE errors are survivable. NLRI that can't be parsed can't be withdrawn either, so those
+        // still reset the session. RFC 7606, Section 5.3
+        match self {
+            NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::InvalidNetworkField) => ErrorAction::SessionReset,
+            NotifErrorCode::UpdateMessageError(_) => ErrorAction::TreatAsWithdraw,
+            _ => ErrorAction::SessionReset
+        }
+    }
+}
+
+// A received message is malformed, carries the NOTIFICATION to send for it and what's to be done about it
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError(NotifErrorCode, ErrorAction);
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let DecodeError(code, _) = self;
+        write!(f, "malformed message: {}", code)
+    }
+}
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl DecodeError {
+    pub fn code(&self) -> &NotifErrorCode {
+        &self.0
+    }
+    pub fn action(&self) -> ErrorAction {
+        self.1
+    }
+    pub fn with_action(mut self, action: ErrorAction) -> Self {
+        // Where the decoder knows better than the code, e.g. which attribute was malformed
+        self.1 = action;
+        self
+    }
+}
+
+impl From<NotifErrorCode> for DecodeError {
+    fn from(code: NotifErrorCode) -> Self {
+        let action = code.action();
+        DecodeError(code, action)
+    }
+}
+
+impl From<UpdateMsgErrSubcode> for DecodeError {
+    fn from(subcode: UpdateMsgErrSubcode) -> Self {
+        DecodeError::from(NotifErrorCode::from(subcode))
+    }
+}
+
+impl From<DecodeError> for NotifErrorCode {
+    fn from(err: DecodeError) -> Self {
+        err.0
+    }
+}
+
+// The FSM closed the session, carries the NOTIFICATION it sent
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsmError(NotifErrorCode);
+impl fmt::Display for FsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let FsmError(code) = self;
+        write!(f, "session closed: {}", code)
+    }
+}
+impl Error for FsmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl FsmError {
+    pub fn code(&self) -> &NotifErrorCode {
+        &self.0
+    }
+}
+
+impl From<NotifErrorCode> for FsmError {
+    fn from(code: NotifErrorCode) -> Self {
+        FsmError(code)
+    }
+}
+
+// The table couldn't take the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableError {
+    // The task running the table has exited
+    Closed,
+    Injection(InjectionError)
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableError::Closed => write!(f, "{}", TableClosed),
+            TableError::Injection(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl Error for TableError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TableError::Closed => None,
+            TableError::Injection(err) => Some(err)
+        }
+    }
+}
+
+impl From<TableClosed> for TableError {
+    fn from(_: TableClosed) -> Self {
+        TableError::Closed
+    }
+}
+
+impl From<InjectionError> for TableError {
+    fn from(err: InjectionError) -> Self {
+        TableError::Injection(err)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BgpError {
+    Decode(DecodeError),
+    Fsm(FsmError),
+    Table(TableError),
+    Config(ConfigError)
+}
+
+impl fmt::Display for BgpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BgpError::Decode(err) => write!(f, "{}", err),
+            BgpError::Fsm(err) => write!(f, "{}", err),
+            BgpError::Table(err) => write!(f, "{}", err),
+            BgpError::Config(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl Error for BgpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BgpError::Decode(err) => Some(err),
+            BgpError::Fsm(err) => Some(err),
+            BgpError::Table(err) => Some(err),
+            BgpError::Config(err) => Some(err)
+        }
+    }
+}
+
+impl From<NotifErrorCode> for BgpError {
+    fn from(code: NotifErrorCode) -> Self {
+        // The message errors are the decoder's, the rest only come out of the FSM
+        match code {
+            NotifErrorCode::MessageHeaderError(_)
+            | NotifErrorCode::OpenMessageError(_)
+            | NotifErrorCode::UpdateMessageError(_) => BgpError::Decode(DecodeError::from(code)),
+            NotifErrorCode::HoldTimerExpired
+            | NotifErrorCode::FiniteStateMachineError
+            | NotifErrorCode::Cease
+            | NotifErrorCode::CeaseReason(_) => BgpError::Fsm(FsmError(code))
+        }
+    }
+}
+
+impl From<DecodeError> for BgpError {
+    fn from(err: DecodeError) -> Self {
+        BgpError::Decode(err)
+    }
+}
+
+impl From<FsmError> for BgpError {
+    fn from(err: FsmError) -> Self {
+        BgpError::Fsm(err)
+    }
+}
+
+impl From<TableError> for BgpError {
+    fn from(err: TableError) -> Self {
+        BgpError::Table(err)
+    }
+}
+
+impl From<ConfigError> for BgpError {
+    fn from(err: ConfigError) -> Self {
+        BgpError::Config(err)
+    }
+}
+
+impl From<SpeakerError> for BgpError {
+    fn from(err: SpeakerError) -> Self {
+        // Only a speaker that's gone is the table's problem, the rest is about what was asked of it
+        match err {
+            SpeakerError::Closed => BgpError::Table(TableError::Closed),
+            err => BgpError::Config(ConfigError::Speaker(err))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,6 +603,16 @@ mod tests {
         assert_eq!(val, converted);
     }
     #[test]
+    fn convert_cease_max_prefixes() {
+        let err = &NotifErrorCode::CeaseReason(CeaseSubcode::MaxPrefixesReached);
+        if let NotifErrorCode::CeaseReason(inner_subcode) = err {
+            let inner_converted: u8 = inner_subcode.into();
+            let outer_converted: u8 = err.into();
+            assert_eq!(inner_converted, 1);
+            assert_eq!(outer_converted, 6);
+        }
+    }
+    #[test]
     fn convert_msg_header_err_and_conn_not_synced() {
         let code = 1u8;
         let subcode = 1u8;
@@ -213,4 +624,37 @@ mod tests {
             assert_eq!(outer_converted, code);
         }
     }
+    #[test]
+    fn bgp_error_kinds() {
+        let update = BgpError::from(NotifErrorCode::from(UpdateMsgErrSubcode::MalformedAsPath));
+        assert!(matches!(&update, BgpError::Decode(err) if err.code() == &NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MalformedAsPath)));
+        assert_eq!(update.to_string(), "malformed message: UPDATE Message Error: Malformed AS_PATH");
+        assert_eq!(update.source().and_then(Error::source).unwrap().to_string(), "UPDATE Message Error: Malformed AS_PATH");
+
+        let cease = BgpError::from(NotifErrorCode::from(CeaseSubcode::AdminShutdown));
+        assert!(matches!(cease, BgpError::Fsm(_)));
+        assert_eq!(cease.to_string(), "session closed: Cease: Administrative Shutdown");
+        assert!(matches!(BgpError::from(NotifErrorCode::HoldTimerExpired), BgpError::Fsm(_)));
+
+        assert_eq!(BgpError::from(SpeakerError::Closed), BgpError::Table(TableError::Closed));
+        assert_eq!(BgpError::from(TableError::from(TableClosed)), BgpError::Table(TableError::Closed));
+        assert!(matches!(BgpError::from(TableError::from(InjectionError::Empty)), BgpError::Table(TableError::Injection(_))));
+        let peer = std::net::IpAddr::from([192, 0, 2, 1]);
+        assert!(matches!(BgpError::from(SpeakerError::UnknownPeer(peer)), BgpError::Config(ConfigError::Speaker(_))));
+    }
+    #[test]
+    fn error_actions() {
+        let update = |subcode| NotifErrorCode::UpdateMessageError(subcode).action();
+        assert_eq!(update(UpdateMsgErrSubcode::MalformedAsPath), ErrorAction::TreatAsWithdraw);
+        assert_eq!(update(UpdateMsgErrSubcode::MissingWkAttr), ErrorAction::TreatAsWithdraw);
+        assert_eq!(update(UpdateMsgErrSubcode::InvalidNetworkField), ErrorAction::SessionReset);
+        assert!(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen).action().resets_session());
+        assert!(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::BadPeerAs).action().resets_session());
+        assert_eq!(ErrorAction::for_attr(AGGREGATOR), ErrorAction::AttributeDiscard);
+        assert_eq!(ErrorAction::for_attr(1), ErrorAction::TreatAsWithdraw);
+
+        let err = DecodeError::from(UpdateMsgErrSubcode::OptionalAttrError);
+        assert_eq!(err.action(), ErrorAction::TreatAsWithdraw);
+        assert_eq!(err.with_action(ErrorAction::AttributeDiscard).action(), ErrorAction::AttributeDiscard);
+    }
 }
\ No newline at end of file
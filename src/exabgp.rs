@@ -0,0 +1,210 @@
+// Route injection in the JSON ExaBGP speaks, so automation written against ExaBGP can drive a Speaker
+// instead. Each line of input is one update in ExaBGP's JSON update format, either the whole message as
+// ExaBGP emits it or just the update inside it:
+//
+//   {"attribute": {"origin": "igp", "med": 10, "community": [[65000, 100]]},
+//    "announce": {"ipv4 unicast": {"192.0.2.1": [{"nlri": "198.51.100.0/24"}]}},
+//    "withdraw": {"ipv6 unicast": [{"nlri": "2001:db8::/32"}]}}
+//
+// Announced prefixes are originated locally with the update's ORIGIN, MED and COMMUNITIES, withdrawn ones
+// are withdrawn from the originated routes. The next hop is ignored, originated routes are sent with
+// ours. Lines come from anything that reads as lines through Speaker::feed_exabgp (ExaBGP's stdin pipe, a
+// socket) or from the management API's POST /bgp/exabgp.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tracing::warn;
+
+use crate::{
+    config::{parse_communities, parse_prefix},
+    message_types::Route,
+    path_attrs::OriginValue,
+    redistribute::Redistributed,
+    speaker::{Speaker, SpeakerError},
+    table::LocalRoutes,
+};
+
+const FAMILIES: [&str; 2] = ["ipv4 unicast", "ipv6 unicast"];
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ExaBgpError(String);
+impl Display for ExaBgpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ExaBgpError(msg) = self;
+        write!(f, "{}", msg)
+    }
+}
+impl Error for ExaBgpError {}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Update {
+    attribute: Attributes,
+    // Family, then next hop, then the prefixes
+    announce: HashMap<String, HashMap<String, Vec<Nlri>>>,
+    withdraw: HashMap<String, Vec<Nlri>>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Attributes {
+    origin: Option<String>,
+    med: Option<u32>,
+    community: Vec<Community>
+}
+
+// ExaBGP writes {"nlri": ".."}, a bare prefix is taken too
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Nlri {
+    Tagged { nlri: String },
+    Prefix(String)
+}
+
+// ExaBGP writes [asn, value], "asn:value" is taken too
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Community {
+    Pair(u16, u16),
+    Text(String)
+}
+
+pub(crate) fn parse_update(line: &str) -> Result<Vec<Redistributed>, ExaBgpError> {
+    // The withdrawals and then the originations the update asks for
+    let err = |msg: String| ExaBgpError(msg);
+    let message: Value = serde_json::from_str(line).map_err(|e| err(e.to_string()))?;
+    let update = message
+        .pointer("/neighbor/message/update")
+        .or_else(|| message.get("update"))
+        .unwrap_or(&message)
+        .clone();
+    let update: Update = serde_json::from_value(update).map_err(|e| err(e.to_string()))?;
+    if let Some(family) = update.announce.keys().chain(update.withdraw.keys()).find(|family| !FAMILIES.contains(&family.as_str())) {
+        return Err(err(format!("unsupported family '{}'", family)));
+    }
+
+    let mut changes = Vec::new();
+    let withdrawn = prefixes(update.withdraw.values().flatten())?;
+    if !withdrawn.is_empty() {
+        changes.push(Redistributed::Withdraw(withdrawn));
+    }
+    let announced = prefixes(update.announce.values().flat_map(|next_hops| next_hops.values()).flatten())?;
+    if !announced.is_empty() {
+        changes.push(Redistributed::Originate(local_routes(announced, &update.attribute)?));
+    }
+    Ok(changes)
+}
+
+fn prefixes<'a>(nlri: impl Iterator<Item = &'a Nlri>) -> Result<Vec<Route>, ExaBgpError> {
+    nlri
+    .map(|nlri| match nlri {
+        Nlri::Tagged { nlri } | Nlri::Prefix(nlri) => parse_prefix(nlri).map_err(|e| ExaBgpError(e.to_string()))
+    })
+    .collect()
+}
+
+fn local_routes(routes: Vec<Route>, attrs: &Attributes) -> Result<LocalRoutes, ExaBgpError> {
+    let origin = match attrs.origin.as_deref() {
+        None | Some("igp") => OriginValue::Igp,
+        Some("egp") => OriginValue::Egp,
+        Some("incomplete") => OriginValue::Incomplete,
+        Some(other) => return Err(ExaBgpError(format!("invalid origin '{}'", other)))
+    };
+    let communities: Vec<String> = attrs
+        .community
+        .iter()
+        .map(|community| match community {
+            Community::Pair(asn, value) => format!("{}:{}", asn, value),
+            Community::Text(community) => community.clone()
+        })
+        .collect();
+    let communities = parse_communities(&communities).map_err(|e| ExaBgpError(e.to_string()))?;
+    let local = LocalRoutes::new(routes).origin(origin).communities(communities);
+    match attrs.med {
+        Some(med) => Ok(local.med(med)),
+        None => Ok(local)
+    }
+}
+
+pub(crate) async fn feed<R: AsyncBufRead + Unpin>(reader: R, speaker: &Speaker) -> Result<usize, SpeakerError> {
+    // Applies every update read until the input ends, returning how many were applied. Lines that
+    // don't parse are logged and skipped, as ExaBGP does with commands it doesn't understand.
+    let mut lines = reader.lines();
+    let mut applied = 0;
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_update(&line) {
+            Ok(changes) => {
+                for change in changes {
+                    speaker.redistribute(change)?;
+                }
+                applied += 1;
+            },
+            Err(err) => warn!(error = %err, "ignoring ExaBGP update")
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use crate::table::BgpTable;
+
+    #[test]
+    fn exabgp_parse_update() {
+        let line = r#"{"exabgp": "4.0.1", "type": "update", "neighbor": {"message": {"update": {
+            "attribute": {"origin": "incomplete", "med": 10, "community": [[65000, 100], "65000:200"]},
+            "announce": {"ipv4 unicast": {"192.0.2.1": [{"nlri": "198.51.100.0/24"}, "198.51.101.0/24"]}},
+            "withdraw": {"ipv6 unicast": [{"nlri": "2001:db8::/32"}]}}}}}"#;
+        let changes = parse_update(&line.replace('\n', "")).unwrap();
+        assert!(matches!(&changes[0], Redistributed::Withdraw(routes) if routes.len() == 1));
+        match &changes[1] {
+            Redistributed::Originate(local) => {
+                let pas = local.path_attrs();
+                assert_eq!(pas.iter().find_map(|pa| pa.origin()), Some(2));
+                assert_eq!(pas.iter().find_map(|pa| pa.med()), Some(10));
+                assert_eq!(pas.iter().find_map(|pa| pa.communities()), Some(vec![0xFDE80064, 0xFDE800C8]));
+            },
+            change => panic!("expected an origination, got {:?}", change)
+        }
+
+        // Just the update, withdrawals only
+        let changes = parse_update(r#"{"withdraw": {"ipv4 unicast": ["198.51.100.0/24"]}}"#).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(parse_update(r#"{"announce": {"l2vpn vpls": {}}}"#).unwrap_err().to_string().contains("l2vpn vpls"));
+        assert!(parse_update(r#"{"withdraw": {"ipv4 unicast": ["198.51.100.0/33"]}}"#).is_err());
+        assert!(parse_update(r#"{"attribute": {"origin": "bgp"}, "announce": {"ipv4 unicast": {"192.0.2.1": ["198.51.100.0/24"]}}}"#).is_err());
+        assert!(parse_update("announce route 198.51.100.0/24 next-hop self").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exabgp_feed() {
+        let speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let input = concat!(
+            r#"{"announce": {"ipv4 unicast": {"192.0.2.1": ["198.51.100.0/24", "198.51.101.0/24"]}, "ipv6 unicast": {"2001:db8::1": ["2001:db8::/32"]}}}"#, "\n",
+            "not json\n",
+            "\n",
+            r#"{"withdraw": {"ipv4 unicast": ["198.51.101.0/24"]}}"#, "\n"
+        );
+        assert_eq!(speaker.feed_exabgp(input.as_bytes()).await.unwrap(), 2);
+        let counts = speaker
+            .with_tables(|v4: &mut BgpTable<Ipv4Addr>, v6: &mut BgpTable<Ipv6Addr>| (v4.num_destinations(), v6.num_destinations()))
+            .await
+            .unwrap();
+        assert_eq!(counts, (1, 1));
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        let found = speaker.with_tables(move |v4, _| v4.route_view(&route).is_some()).await.unwrap();
+        assert!(found);
+    }
+}
@@ -0,0 +1,630 @@
+// Logic for deciding what gets advertised to a given peer and with which Path Attributes.
+// Currently this implements the default rules from RFC 4271 (Pg. 19, 29 and 82):
+// - eBGP peers get our AS prepended to the AS_PATH, our address as the NEXT_HOP and no LOCAL_PREF.
+//   A MED learned from another AS is not passed on.
+// - iBGP peers always get a LOCAL_PREF. AS_PATH and NEXT_HOP are left alone for learned routes.
+// - Locally originated routes always use our address as the NEXT_HOP.
+// The well-known communities from RFC 1997 are also honored: NO_ADVERTISE paths are never sent, NO_EXPORT
+// and NO_EXPORT_SUBCONFED paths are never sent to eBGP peers (confederations aren't supported).
+// Paths learned from an iBGP peer are never sent to other iBGP peers (RFC 4271, Pg. 82) unless we are a
+// route reflector (RFC 4456). A reflector sends paths from clients to every iBGP peer and paths from
+// non-clients to clients only, adding the ORIGINATOR_ID and CLUSTER_LIST as it goes.
+// Route server clients (RFC 7947) are eBGP peers that get learned paths untouched: no AS is prepended
+// and the NEXT_HOP and MED are kept. This is all there is to route server mode, clients share the one
+// Loc-RIB so each gets the same bestpath, and per-client filtering is done with its export policy.
+// With as-override, the peer's AS is replaced by ours in the AS_PATH of paths sent to it, so sites of
+// a customer that reuse one AS still accept each other's routes (RFC 4364, Pg. 29).
+// eBGP peers can also have private ASes (RFC 6996) stripped from the AS_PATH, and can be given a
+// different AS than ours to see (local-as) while they are migrated from an old AS.
+// Paths sent to eBGP peers in maintenance carry the GRACEFUL_SHUTDOWN community, see graceful_shutdown.rs.
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
+
+use crate::{
+    comms::ReceivedRoutes,
+    graceful_shutdown,
+    path_attrs::*,
+    table::RouteSource,
+};
+
+const DEFAULT_LOCAL_PREF: u32 = 100;
+
+// Where a path was learned from, as far as export is concerned
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum PathSource {
+    Local,
+    Ebgp(IpAddr),
+    // Along with the peer's BGP Identifier, which becomes the ORIGINATOR_ID if the path is reflected
+    Ibgp(IpAddr, Ipv4Addr)
+}
+
+impl PathSource {
+    pub fn peer_addr(&self) -> Option<IpAddr> {
+        match self {
+            PathSource::Local => None,
+            PathSource::Ebgp(addr) | PathSource::Ibgp(addr, _) => Some(*addr)
+        }
+    }
+}
+
+// Route reflector settings, shared by every iBGP peer of the reflector. RFC 4456
+#[derive(Clone, Debug)]
+pub struct RouteReflector {
+    cluster_id: Ipv4Addr,
+    clients: HashSet<IpAddr>
+}
+
+impl RouteReflector {
+    pub fn new(cluster_id: Ipv4Addr) -> Self {
+        Self {
+            cluster_id,
+            clients: HashSet::new()
+        }
+    }
+    pub fn client(mut self, peer_addr: IpAddr) -> Self {
+        self.clients.insert(peer_addr);
+        self
+    }
+    pub fn cluster_id(&self) -> Ipv4Addr {
+        self.cluster_id
+    }
+    pub fn is_client(&self, peer_addr: IpAddr) -> bool {
+        self.clients.contains(&peer_addr)
+    }
+    pub fn is_looped(&self, pas: &[PathAttr], router_id: Ipv4Addr) -> bool {
+        // Received paths we originated or that already went through our cluster must be dropped. RFC 4456, Pg. 6
+        pas.iter().any(|pa| {
+            pa.originator_id() == Some(router_id)
+            || pa.cluster_list().is_some_and(|ids| ids.contains(&self.cluster_id))
+        })
+    }
+    fn reflect(&self, pas: &mut Vec<PathAttr>, originator: Ipv4Addr) {
+        // Sets the ORIGINATOR_ID if the path doesn't have one yet and prepends our CLUSTER_ID
+        if !pas.iter().any(|pa| pa.attr_type_code() == ORIGINATOR_ID) {
+            pas.push(
+                PathAttrBuilder::<OriginatorId>::new()
+                .originator_id(originator)
+                .build()
+                .expect("ORIGINATOR_ID value was supplied")
+            );
+        }
+        let mut cluster_ids = vec![self.cluster_id];
+        cluster_ids.extend(pas.iter().find_map(|pa| pa.cluster_list()).unwrap_or_default());
+        pas.retain(|pa| pa.attr_type_code() != CLUSTER_LIST);
+        pas.push(
+            PathAttrBuilder::<ClusterList>::new()
+            .cluster_ids(cluster_ids)
+            .build()
+            .expect("CLUSTER_LIST has at least our CLUSTER_ID")
+        );
+    }
+}
+
+// Private AS range for 2 octet ASes. RFC 6996, Pg. 2
+const PRIVATE_AS_START: u16 = 64512;
+const PRIVATE_AS_END: u16 = 65534;
+
+pub(crate) fn is_private_as(asn: u16) -> bool {
+    (PRIVATE_AS_START..=PRIVATE_AS_END).contains(&asn)
+}
+
+// "local-as" settings for an AS migration, the peer keeps peering with the old AS until it is reconfigured.
+// By default the peer sees the old AS prepended in front of our real one, and paths from the peer get the
+// old AS prepended on import so the rest of our network still sees it as the neighbor's path through it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalAs {
+    asn: u16,
+    // Don't prepend the old AS to paths received from the peer
+    no_prepend: bool,
+    // Advertise the old AS only, our real AS is left out of paths sent to the peer
+    replace_as: bool
+}
+
+impl LocalAs {
+    pub fn new(asn: u16) -> Self {
+        Self {
+            asn,
+            no_prepend: false,
+            replace_as: false
+        }
+    }
+    pub fn no_prepend(mut self) -> Self {
+        self.no_prepend = true;
+        self
+    }
+    pub fn replace_as(mut self) -> Self {
+        self.replace_as = true;
+        self
+    }
+    pub fn asn(&self) -> u16 {
+        self.asn
+    }
+    pub(crate) fn import(&self, mut payload: ReceivedRoutes) -> ReceivedRoutes {
+        // Prepends the old AS to the AS_PATH of a payload received from the peer, unless no-prepend is set
+        if self.no_prepend || payload.routes().is_none() {
+            return payload;
+        }
+        let mut pas = payload.path_attrs();
+        prepend_as(&mut pas, self.asn);
+        payload = payload.with_routes(pas, payload.routes(), payload.withdrawn_routes());
+        payload.sync_decision_data();
+        payload
+    }
+}
+
+// Export settings configured on a peer (or its peer group), applied to the peer's ExportPeer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub route_server_client: bool,
+    pub as_override: bool,
+    pub remove_private_as: bool,
+    pub migration_as: Option<LocalAs>
+}
+
+impl ExportOptions {
+    pub(crate) fn apply(&self, mut peer: ExportPeer) -> ExportPeer {
+        peer.transparent |= self.route_server_client;
+        peer.as_override |= self.as_override;
+        peer.remove_private_as |= self.remove_private_as;
+        if let Some(local_as) = self.migration_as {
+            peer.migration_as = Some(local_as);
+        }
+        peer
+    }
+}
+
+// Describes a peer from the point of view of route export.
+#[derive(Clone, Debug)]
+pub(crate) struct ExportPeer {
+    peer_addr: IpAddr,
+    remote_as: u16,
+    local_as: u16,
+    // Our address on the session, used when we are the NEXT_HOP
+    local_addr: IpAddr,
+    // Set if we are a route reflector, iBGP paths are only re-advertised to iBGP peers if so
+    reflector: Option<Arc<RouteReflector>>,
+    // Set if we are a route server and the peer is one of its clients
+    transparent: bool,
+    as_override: bool,
+    remove_private_as: bool,
+    migration_as: Option<LocalAs>,
+    // Set while the peer is in maintenance, only ever for eBGP peers
+    graceful_shutdown: bool
+}
+
+impl ExportPeer {
+    pub fn new(peer_addr: IpAddr, remote_as: u16, local_as: u16, local_addr: IpAddr) -> Self {
+        Self {
+            peer_addr,
+            remote_as,
+            local_as,
+            local_addr,
+            reflector: None,
+            transparent: false,
+            as_override: false,
+            remove_private_as: false,
+            migration_as: None,
+            graceful_shutdown: false
+        }
+    }
+    pub fn route_reflector(mut self, reflector: Arc<RouteReflector>) -> Self {
+        self.reflector = Some(reflector);
+        self
+    }
+    pub fn route_server_client(mut self) -> Self {
+        self.transparent = true;
+        self
+    }
+    pub fn as_override(mut self) -> Self {
+        self.as_override = true;
+        self
+    }
+    pub fn remove_private_as(mut self) -> Self {
+        self.remove_private_as = true;
+        self
+    }
+    pub fn migration_as(mut self, local_as: LocalAs) -> Self {
+        self.migration_as = Some(local_as);
+        self
+    }
+    pub fn set_graceful_shutdown(&mut self, on: bool) -> bool {
+        // Returns whether anything changed, iBGP peers are never put in maintenance
+        let on = on && self.route_source() == RouteSource::Ebgp;
+        let changed = on != self.graceful_shutdown;
+        self.graceful_shutdown = on;
+        changed
+    }
+    pub fn graceful_shutdown(&self) -> bool {
+        self.graceful_shutdown
+    }
+    pub fn peer_addr(&self) -> IpAddr {
+        self.peer_addr
+    }
+    pub fn remote_as(&self) -> u16 {
+        self.remote_as
+    }
+    pub fn local_as(&self) -> u16 {
+        self.local_as
+    }
+    pub fn session_as(&self) -> u16 {
+        // The AS the peer sees us as
+        self.migration_as.map(|local_as| local_as.asn()).unwrap_or(self.local_as)
+    }
+    pub fn local_addr(&self) -> IpAddr {
+        self.local_addr
+    }
+    pub fn route_source(&self) -> RouteSource {
+        match self.remote_as == self.local_as {
+            true => RouteSource::Ibgp,
+            false => RouteSource::Ebgp
+        }
+    }
+    pub fn export(&self, pas: &[PathAttr], source: &PathSource) -> Option<Vec<PathAttr>> {
+        // Returns the PAs the path would be advertised to this peer with, or None if
+        // the path would not be advertised at all. source is where the path was learned from.
+
+        // Never send a path back to the peer it was learned from.
+        let learned_from = source.peer_addr();
+        if learned_from == Some(self.peer_addr) {
+            return None;
+        }
+        // iBGP split horizon, unless the path can be reflected
+        let reflect_from = match (source, self.route_source()) {
+            (PathSource::Ibgp(from, originator), RouteSource::Ibgp) => match self.reflector.as_deref() {
+                Some(rr) if rr.is_client(*from) || rr.is_client(self.peer_addr) => Some((rr, *originator)),
+                _ => return None
+            },
+            _ => None
+        };
+        let communities = pas
+            .iter()
+            .find_map(|pa| pa.communities())
+            .unwrap_or_default();
+        if communities.contains(&NO_ADVERTISE) {
+            return None;
+        }
+        let no_export = communities.contains(&NO_EXPORT) || communities.contains(&NO_EXPORT_SUBCONFED);
+        if no_export && self.route_source() == RouteSource::Ebgp {
+            return None;
+        }
+        let mut out: Vec<PathAttr> = pas.to_vec();
+        match self.route_source() {
+            RouteSource::Ebgp => {
+                // Reflection attributes never leave the AS
+                out.retain(|pa| ![LOCAL_PREF, ORIGINATOR_ID, CLUSTER_LIST].contains(&pa.attr_type_code()));
+                match (self.transparent, learned_from.is_some()) {
+                    (true, true) => (),
+                    (false, true) => {
+                        out.retain(|pa| pa.attr_type_code() != MED);
+                        self.strip_private_as(&mut out);
+                        self.override_remote_as(&mut out);
+                        self.prepend_local_as(&mut out);
+                        self.next_hop_self(&mut out);
+                    },
+                    (_, false) => {
+                        self.prepend_local_as(&mut out);
+                        self.next_hop_self(&mut out);
+                    }
+                }
+                if self.graceful_shutdown {
+                    graceful_shutdown::tag(&mut out);
+                }
+            },
+            RouteSource::Ibgp | RouteSource::Local => {
+                if !out.iter().any(|pa| pa.attr_type_code() == LOCAL_PREF) {
+                    out.push(
+                        PathAttrBuilder::<LocalPref>::new()
+                        .local_pref(DEFAULT_LOCAL_PREF)
+                        .build()
+                        .expect("LOCAL_PREF value was supplied")
+                    );
+                }
+                if learned_from.is_none() {
+                    self.next_hop_self(&mut out);
+                }
+                if let Some((rr, originator)) = reflect_from {
+                    rr.reflect(&mut out, originator);
+                }
+            }
+        }
+        Some(canonicalize_attrs(out))
+    }
+    fn override_remote_as(&self, pas: &mut [PathAttr]) {
+        if !self.as_override {
+            return;
+        }
+        if let Some(aspath) = pas.iter_mut().find(|pa| pa.attr_type_code() == AS_PATH) {
+            let (remote_as, local_as) = (self.remote_as, self.session_as());
+            *aspath = as_path_rewrite(aspath, |asn| match asn == remote_as {
+                true => Some(local_as),
+                false => Some(asn)
+            });
+        }
+    }
+    fn strip_private_as(&self, pas: &mut [PathAttr]) {
+        // Done before our own AS is prepended, which may itself be private
+        if !self.remove_private_as {
+            return;
+        }
+        if let Some(aspath) = pas.iter_mut().find(|pa| pa.attr_type_code() == AS_PATH) {
+            *aspath = as_path_rewrite(aspath, |asn| (!is_private_as(asn)).then_some(asn));
+        }
+    }
+    fn prepend_local_as(&self, pas: &mut Vec<PathAttr>) {
+        // With local-as the old AS ends up in front of our real AS, or replaces it
+        match self.migration_as {
+            Some(local_as) if local_as.replace_as => prepend_as(pas, local_as.asn),
+            Some(local_as) => {
+                prepend_as(pas, self.local_as);
+                prepend_as(pas, local_as.asn);
+            },
+            None => prepend_as(pas, self.local_as)
+        }
+    }
+    fn next_hop_self(&self, pas: &mut Vec<PathAttr>) {
+        pas.retain(|pa| pa.attr_type_code() != NEXT_HOP);
+        pas.push(
+            PathAttrBuilder::<NextHop>::new()
+            .next_hop(self.local_addr)
+            .build()
+            .expect("NEXT_HOP value was supplied")
+        );
+    }
+}
+
+fn prepend_as(pas: &mut Vec<PathAttr>, asn: u16) {
+    // Prepends the AS, creating the AS_PATH if the path doesn't have one.
+    match pas.iter_mut().find(|pa| pa.attr_type_code() == AS_PATH) {
+        Some(aspath) => *aspath = as_path_prepend(aspath, asn),
+        None => {
+            let empty = PathAttrBuilder::<AsPath>::new()
+                .as_segments(Vec::new())
+                .build()
+                .expect("Empty AS_PATH is valid");
+            pas.push(as_path_prepend(&empty, asn));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::{comms::MockReceivedRoutesBuilder, message_types::Route};
+
+    fn learned_pas() -> Vec<PathAttr> {
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001])])
+            .build()
+            .unwrap();
+        let nh = PathAttrBuilder::<NextHop>::new()
+            .next_hop(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))
+            .build()
+            .unwrap();
+        let med = PathAttrBuilder::<Med>::new().metric(50).build().unwrap();
+        let lp = PathAttrBuilder::<LocalPref>::new().local_pref(200).build().unwrap();
+        vec![origin, aspath, nh, med, lp]
+    }
+
+    #[test]
+    fn export_ebgp_learned() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let peer = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 65002, 65000, local_addr);
+        let out = peer.export(&learned_pas(), &PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))).unwrap();
+
+        let codes: Vec<u8> = out.iter().map(|pa| pa.attr_type_code()).collect();
+        assert_eq!(codes, vec![ORIGIN, AS_PATH, NEXT_HOP]);
+        assert_eq!(out[1].attr_value(), &[2, 2, 253, 232, 253, 233]);
+        assert_eq!(out[2].attr_value(), &[10, 1, 1, 1]);
+    }
+
+    #[test]
+    fn export_ibgp_learned() {
+        let peer = ExportPeer::new(
+            IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)),
+            65000,
+            65000,
+            IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1)));
+        let pas = learned_pas();
+        let out = peer.export(&pas, &PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))).unwrap();
+
+        // Nothing changes for a learned path to an iBGP peer
+        assert_eq!(out, canonicalize_attrs(pas));
+    }
+
+    #[test]
+    fn export_keeps_prefix_sid() {
+        // Optional transitive PAs like the Prefix-SID pass through untouched
+        let sid = PathAttrBuilder::<PrefixSid>::new().label_index(100).build().unwrap();
+        let mut pas = learned_pas();
+        pas.push(sid.clone());
+        let peer = ExportPeer::new(
+            IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)),
+            65002,
+            65000,
+            IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1)));
+        let out = peer.export(&pas, &PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))).unwrap();
+        assert_eq!(out.iter().find(|pa| pa.attr_type_code() == PREFIX_SID), Some(&sid));
+    }
+
+    #[test]
+    fn export_not_to_source() {
+        let peer_addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let peer = ExportPeer::new(peer_addr, 65001, 65000, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(peer.export(&learned_pas(), &PathSource::Ebgp(peer_addr)).is_none());
+    }
+
+    #[test]
+    fn export_local_ibgp() {
+        // Locally originated path with no AS_PATH, NEXT_HOP or LOCAL_PREF
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let peer = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 65000, 65000, local_addr);
+        let out = peer.export(&[origin], &PathSource::Local).unwrap();
+
+        let codes: Vec<u8> = out.iter().map(|pa| pa.attr_type_code()).collect();
+        assert_eq!(codes, vec![ORIGIN, NEXT_HOP, LOCAL_PREF]);
+        assert_eq!(out[2].attr_value(), DEFAULT_LOCAL_PREF.to_be_bytes().as_slice());
+    }
+
+    #[test]
+    fn export_well_known_communities() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let ebgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 65002, 65000, local_addr);
+        let ibgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 3)), 65000, 65000, local_addr);
+        let learned_from = &PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let with_community = |community: u32| {
+            let mut pas = learned_pas();
+            pas.push(PathAttrBuilder::<Communities>::new().communities(vec![100, community]).build().unwrap());
+            pas
+        };
+
+        let pas = with_community(NO_EXPORT);
+        assert!(ebgp.export(&pas, learned_from).is_none());
+        assert!(ibgp.export(&pas, learned_from).is_some());
+        let pas = with_community(NO_EXPORT_SUBCONFED);
+        assert!(ebgp.export(&pas, learned_from).is_none());
+        let pas = with_community(NO_ADVERTISE);
+        assert!(ebgp.export(&pas, learned_from).is_none());
+        assert!(ibgp.export(&pas, learned_from).is_none());
+        let pas = with_community(200);
+        assert!(ebgp.export(&pas, learned_from).is_some());
+    }
+
+    #[test]
+    fn export_graceful_shutdown() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let mut ebgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 65002, 65000, local_addr);
+        let mut ibgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 3)), 65000, 65000, local_addr);
+        let learned_from = &PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let communities = |peer: &ExportPeer| peer.export(&learned_pas(), learned_from).unwrap().iter().find_map(|pa| pa.communities());
+
+        assert!(ebgp.set_graceful_shutdown(true));
+        assert!(!ebgp.set_graceful_shutdown(true));
+        assert_eq!(communities(&ebgp), Some(vec![GRACEFUL_SHUTDOWN]));
+        assert!(ebgp.set_graceful_shutdown(false));
+        assert_eq!(communities(&ebgp), None);
+        // Only eBGP sessions are drained
+        assert!(!ibgp.set_graceful_shutdown(true));
+        assert_eq!(communities(&ibgp), None);
+    }
+
+    #[test]
+    fn export_ibgp_split_horizon() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let client = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2));
+        let non_client = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 3));
+        let other_client = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 4));
+        let from_client = PathSource::Ibgp(client, Ipv4Addr::new(2, 2, 2, 2));
+        let from_non_client = PathSource::Ibgp(non_client, Ipv4Addr::new(3, 3, 3, 3));
+
+        // Without reflection, iBGP paths only go to eBGP peers
+        let ibgp = ExportPeer::new(other_client, 65000, 65000, local_addr);
+        let ebgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 2, 2, 2)), 65002, 65000, local_addr);
+        assert!(ibgp.export(&learned_pas(), &from_client).is_none());
+        assert!(ebgp.export(&learned_pas(), &from_client).is_some());
+
+        let rr = Arc::new(
+            RouteReflector::new(Ipv4Addr::new(1, 1, 1, 1))
+            .client(client)
+            .client(other_client)
+        );
+        let to_client = ExportPeer::new(other_client, 65000, 65000, local_addr).route_reflector(Arc::clone(&rr));
+        let to_non_client = ExportPeer::new(non_client, 65000, 65000, local_addr).route_reflector(Arc::clone(&rr));
+
+        // Client paths are reflected to everyone, non-client paths to clients only
+        let out = to_non_client.export(&learned_pas(), &from_client).unwrap();
+        assert_eq!(out.iter().find_map(|pa| pa.originator_id()), Some(Ipv4Addr::new(2, 2, 2, 2)));
+        assert_eq!(out.iter().find_map(|pa| pa.cluster_list()), Some(vec![Ipv4Addr::new(1, 1, 1, 1)]));
+        assert!(to_client.export(&learned_pas(), &from_client).is_some());
+        assert!(to_client.export(&learned_pas(), &from_non_client).is_some());
+        let to_other = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 5)), 65000, 65000, local_addr)
+            .route_reflector(Arc::clone(&rr));
+        assert!(to_other.export(&learned_pas(), &from_non_client).is_none());
+
+        // The reflection attributes are kept within the AS
+        assert!(rr.is_looped(&out, Ipv4Addr::new(9, 9, 9, 9)));
+        assert!(rr.is_looped(&out, Ipv4Addr::new(2, 2, 2, 2)));
+        let out = ebgp.export(&out, &from_client).unwrap();
+        assert!(!out.iter().any(|pa| [ORIGINATOR_ID, CLUSTER_LIST].contains(&pa.attr_type_code())));
+    }
+
+    #[test]
+    fn export_route_server_client() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let peer = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 65002, 65000, local_addr)
+            .route_server_client();
+        let out = peer.export(&learned_pas(), &PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))).unwrap();
+
+        // Only LOCAL_PREF is dropped, the AS_PATH, NEXT_HOP and MED are passed on as is
+        let mut expected = learned_pas();
+        expected.retain(|pa| pa.attr_type_code() != LOCAL_PREF);
+        assert_eq!(out, canonicalize_attrs(expected));
+    }
+
+    #[test]
+    fn export_as_override() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let peer = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 65001, 65000, local_addr).as_override();
+        let out = peer.export(&learned_pas(), &PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))).unwrap();
+        assert_eq!(
+            out.iter().find_map(|pa| pa.as_path()),
+            Some(vec![AsSegment::AsSequence(vec![65000, 65000])])
+        );
+    }
+
+    #[test]
+    fn export_remove_private_as() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let mut pas = learned_pas();
+        pas[1] = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001, 3356, 64512])])
+            .build()
+            .unwrap();
+        let from = PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        // Our own AS is private too, but is still prepended
+        let peer = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 174, 65000, local_addr).remove_private_as();
+        let out = peer.export(&pas, &from).unwrap();
+        assert_eq!(out.iter().find_map(|pa| pa.as_path()), Some(vec![AsSegment::AsSequence(vec![65000, 3356])]));
+    }
+
+    #[test]
+    fn export_local_as_migration() {
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1));
+        let from = PathSource::Ebgp(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let aspath = |peer: ExportPeer| peer.export(&learned_pas(), &from).unwrap().iter().find_map(|pa| pa.as_path());
+        let peer = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 2)), 65002, 65000, local_addr);
+
+        let old_as = LocalAs::new(64999);
+        assert_eq!(peer.clone().migration_as(old_as).session_as(), 64999);
+        assert_eq!(
+            aspath(peer.clone().migration_as(old_as)),
+            Some(vec![AsSegment::AsSequence(vec![64999, 65000, 65001])])
+        );
+        assert_eq!(
+            aspath(peer.migration_as(old_as.replace_as())),
+            Some(vec![AsSegment::AsSequence(vec![64999, 65001])])
+        );
+
+        // Paths from the peer get the old AS prepended unless no-prepend is set
+        let payload = || MockReceivedRoutesBuilder::new(
+            Some(vec![Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)))]),
+            None,
+            learned_pas()
+        )
+        .as_path_len(1)
+        .build();
+        let imported = old_as.import(payload());
+        assert_eq!(imported.as_path_len(), 2);
+        assert_eq!(
+            imported.path_attrs_ref().iter().find_map(|pa| pa.as_path()),
+            Some(vec![AsSegment::AsSequence(vec![64999, 65001])])
+        );
+        assert_eq!(old_as.no_prepend().import(payload()).as_path_len(), 1);
+    }
+}
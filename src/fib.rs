@@ -0,0 +1,207 @@
+// The forwarding table side of the router. A Fib is whatever dataplane the bestpaths are pushed to: the
+// Linux kernel (KernelFib, with the netlink feature), a hardware or userspace dataplane, or MemoryFib for
+// tests and platforms without one. FibDriver subscribes to a BgpTable and turns its bestpath changes into
+// Fib calls, one per changed destination. It remembers what it installed so a destination the Fib never
+// took (locally originated, no usable next hop, or an add that failed) is added rather than replaced on
+// its next change and isn't deleted when withdrawn.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+
+use crate::{
+    export::PathSource,
+    message_types::Route,
+    table::{BestpathEvent, BestpathSubscriber, PathAttributeTableEntry},
+};
+
+pub trait Fib: Send {
+    // Installs a destination that isn't installed yet
+    fn add_route(&mut self, route: &Route, next_hops: &[IpAddr]) -> io::Result<()>;
+    fn del_route(&mut self, route: &Route) -> io::Result<()>;
+    // Moves an installed destination to new next hops
+    fn replace_nexthops(&mut self, route: &Route, next_hops: &[IpAddr]) -> io::Result<()>;
+}
+
+pub(crate) struct FibDriver<F> {
+    fib: F,
+    installed: HashSet<Route>
+}
+
+impl<F: Fib> FibDriver<F> {
+    pub fn new(fib: F) -> Self {
+        Self {
+            fib,
+            installed: HashSet::new()
+        }
+    }
+    pub fn fib(&self) -> &F {
+        &self.fib
+    }
+    fn apply(&mut self, route: &Route, multipath: &[Arc<PathAttributeTableEntry>]) -> io::Result<()> {
+        // Makes the Fib follow the destination's multipath set, empty if the destination is gone
+        let mut next_hops: Vec<IpAddr> = Vec::with_capacity(multipath.len());
+        for next_hop in multipath.iter().filter_map(|path| installable(path)) {
+            if !next_hops.contains(&next_hop) {
+                next_hops.push(next_hop);
+            }
+        }
+        let installed = self.installed.contains(route);
+        match (next_hops.is_empty(), installed) {
+            (false, true) => self.fib.replace_nexthops(route, &next_hops),
+            (false, false) => {
+                self.fib.add_route(route, &next_hops)?;
+                self.installed.insert(route.clone());
+                Ok(())
+            },
+            (true, true) => {
+                self.installed.remove(route);
+                self.fib.del_route(route)
+            },
+            (true, false) => Ok(())
+        }
+    }
+}
+
+impl<F: Fib> BestpathSubscriber for FibDriver<F> {
+    fn notify(&mut self, event: &BestpathEvent) {
+        let result = match event {
+            BestpathEvent::Added { route, multipath, .. } | BestpathEvent::Changed { route, multipath, .. } => {
+                self.apply(route, multipath)
+            },
+            BestpathEvent::Withdrawn { route, .. } => self.apply(route, &[])
+        };
+        // The Fib is brought back in line by the destination's next change
+        if let Err(err) = result {
+            warn!(route = ?event.route(), error = %err, "FIB update failed");
+        }
+    }
+}
+
+fn installable(path: &PathAttributeTableEntry) -> Option<IpAddr> {
+    // The next hop the path is installed with, None for paths that stay out of the Fib
+    match path.source() {
+        PathSource::Local => None,
+        PathSource::Ebgp(_) | PathSource::Ibgp(..) => path.next_hop().filter(|next_hop| !next_hop.is_unspecified())
+    }
+}
+
+// Keeps the routes in memory. Clones share the routes, so a clone kept outside the table can look at what
+// the driver installed.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFib {
+    routes: Arc<Mutex<HashMap<Route, Vec<IpAddr>>>>
+}
+
+impl MemoryFib {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn next_hops(&self, route: &Route) -> Option<Vec<IpAddr>> {
+        self.routes.lock().unwrap().get(route).cloned()
+    }
+    pub fn len(&self) -> usize {
+        self.routes.lock().unwrap().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Fib for MemoryFib {
+    fn add_route(&mut self, route: &Route, next_hops: &[IpAddr]) -> io::Result<()> {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.contains_key(route) {
+            true => Err(io::Error::new(io::ErrorKind::AlreadyExists, "route is already installed")),
+            false => {
+                routes.insert(route.clone(), next_hops.to_vec());
+                Ok(())
+            }
+        }
+    }
+    fn del_route(&mut self, route: &Route) -> io::Result<()> {
+        match self.routes.lock().unwrap().remove(route) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "route isn't installed"))
+        }
+    }
+    fn replace_nexthops(&mut self, route: &Route, next_hops: &[IpAddr]) -> io::Result<()> {
+        match self.routes.lock().unwrap().get_mut(route) {
+            Some(installed) => {
+                *installed = next_hops.to_vec();
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "route isn't installed"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::{
+        comms::MockReceivedRoutesBuilder,
+        path_attrs::*,
+        table::{BgpTable, RouteSource},
+    };
+
+    #[test]
+    fn fib_driver() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let local = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let pas = |next_hop: Ipv4Addr| {
+            let nh = PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(next_hop)).build().unwrap();
+            vec![origin.clone(), nh]
+        };
+        let fib = MemoryFib::new();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        table.subscribe(Box::new(FibDriver::new(fib.clone())));
+
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(Ipv4Addr::new(10, 0, 0, 1))).build());
+        assert_eq!(fib.next_hops(&route), Some(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]));
+        // A better path moves the route
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(Ipv4Addr::new(10, 0, 0, 2)))
+            .local_pref(200)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 2))
+            .build()
+        );
+        assert_eq!(fib.next_hops(&route), Some(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]));
+        // Locally originated routes stay out
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![local.clone()]), None, pas(Ipv4Addr::new(10, 0, 0, 3)))
+            .route_source(RouteSource::Local)
+            .build()
+        );
+        assert_eq!(fib.next_hops(&local), None);
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(None, Some(vec![route.clone()]), vec![origin.clone()])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 2))
+            .build()
+        );
+        assert_eq!(fib.next_hops(&route), Some(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]));
+        _ = table.walk(MockReceivedRoutesBuilder::new(None, Some(vec![route.clone()]), vec![origin.clone()]).build());
+        assert!(fib.is_empty());
+    }
+
+    #[test]
+    fn fib_memory() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let next_hops = [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))];
+        let mut fib = MemoryFib::new();
+        assert_eq!(fib.replace_nexthops(&route, &next_hops).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fib.add_route(&route, &next_hops[..1]).unwrap();
+        assert_eq!(fib.add_route(&route, &next_hops).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        fib.replace_nexthops(&route, &next_hops).unwrap();
+        assert_eq!(fib.next_hops(&route), Some(next_hops.to_vec()));
+        fib.del_route(&route).unwrap();
+        assert_eq!(fib.del_route(&route).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1 +1,1420 @@
-// Defines the BGP FSM
\ No newline at end of file
+// The BGP session FSM as described in RFC 4271, Pg. 52. Besides the mandatory session attributes, DelayOpen
+// SendNOTIFICATIONwithoutOPEN, PassiveTcpEstablishment and DampPeerOscillations are supported.
+// A passive session waits in Active for the peer to connect and never initiates a connection itself.
+// With damping, every failure that counts against the ConnectRetryCounter starts the IdleHoldTimer, which
+// keeps the peer in Idle for a time that doubles with each consecutive failure. Only a manual start
+// can bring the peer up before the timer expires, automatic starts are ignored until then and don't
+// reset the ConnectRetryCounter, so the backoff keeps growing while the peer keeps failing.
+// With Graceful Restart (RFC 4724) negotiated, losing the TCP connection of an established session doesn't
+// release the peer's routes. They are marked stale and kept while the peer restarts, for at most its Restart
+// Time. Once the session is back up, each address family's stale routes are flushed when the peer sends
+// End-of-RIB for it, or when the StaleTimer runs out, whichever comes first. Families the peer didn't keep
+// forwarding state for through the restart are flushed as soon as its new Open arrives. Any other way the
+// session goes down ends Graceful Restart and flushes everything still stale.
+// Connection collisions (RFC 4271, Pg. 28) are handled by PeerConnections, which runs an FSM per
+// connection to the peer and dumps the losing one with OpenCollisionDump.
+// The FSM doesn't do any I/O itself. Each event updates the session's state, counters and timers
+// and returns the actions the caller has to carry out (sending messages, managing the TCP connection
+// and running the timers) in the order they have to happen.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::SystemTime,
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    errors::{CeaseSubcode, ErrorAction, NotifErrorCode},
+    fsm_ds::{check_open, ErrorSource, Event, KeepaliveScheduler, PeerSession, PeerStats, SessionParams, State},
+    message_types::{Afi, MessageType, Notification, Open, Safi},
+    session_events::{SessionError, SessionEvent, SessionObserver},
+};
+
+// HoldTimer value used until the Open messages have been exchanged. RFC 4271, Pg. 57
+const LARGE_HOLD_TIME: usize = 240;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Timer {
+    ConnectRetry,
+    Hold,
+    Keepalive,
+    DelayOpen,
+    IdleHold,
+    Stale
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Action {
+    // Initiate a TCP connection to the peer
+    Connect,
+    DropConnection,
+    SendOpen,
+    SendKeepalive,
+    SendNotification(NotifErrorCode),
+    // Cease for an Administrative Shutdown or Reset, with a Shutdown Communication for the peer's operator
+    SendShutdown(CeaseSubcode, String),
+    // Hand the Update to the Decision Process
+    ProcessUpdate,
+    // Release all resources for the session, including the routes learned from the peer. Stale routes are
+    // left alone, they are only removed by FlushStale.
+    ReleaseResources,
+    // Keep the peer's routes for these address families, marked stale, instead of releasing them
+    MarkStale(Vec<(Afi, Safi)>),
+    // Remove the peer's routes for the address family that are still stale
+    FlushStale(Afi, Safi),
+    // (Re)start a timer with the value in seconds
+    StartTimer(Timer, usize),
+    StopTimer(Timer),
+    // Soft reset inbound: ask the peer to resend its routes (RFC 2918)
+    SendRouteRefresh,
+    // Soft reset inbound for peers without route refresh: re-run import policy over the stored Adj-RIB-In
+    ReplayAdjRibIn,
+    // Soft reset outbound: re-run export for the peer and send the result
+    ResendAdjRibOut
+}
+
+// Operator commands for a peer. A shutdown or reset can carry a reason, which is sent to the peer as
+// the Shutdown Communication (RFC 9003).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PeerCommand {
+    Start,
+    // Administrative shutdown, the peer is sent a Cease and stays down until started again
+    Shutdown(Option<String>),
+    // Tears the session down with a Cease and brings it back up
+    HardReset(Option<String>),
+    // Stops the session like a shutdown, with a Cease of another kind (e.g. Maximum Number of Prefixes Reached)
+    Cease(CeaseSubcode),
+    SoftResetIn,
+    SoftResetOut
+}
+
+pub(crate) struct Fsm {
+    session: PeerSession,
+    // The Open we send, needed to negotiate the session parameters
+    local_open: Open,
+    // The AS the peer is configured with, its Open has to match. None takes any.
+    remote_as: Option<u32>,
+    // Told about state changes and NOTIFICATIONs, along with the peer's address
+    observer: Option<(IpAddr, Box<dyn SessionObserver>)>,
+    // Address families the peer has stale routes in, waiting for End-of-RIB
+    stale: Vec<(Afi, Safi)>,
+    // Running while the session is OpenConfirm or Established
+    keepalives: KeepaliveScheduler,
+    // Reason given with the shutdown command being handled
+    shutdown_communication: Option<String>,
+    // Set while handling a Cease command
+    cease_subcode: Option<CeaseSubcode>
+}
+
+impl Fsm {
+    pub fn new(session: PeerSession, local_open: Open) -> Self {
+        Self {
+            session,
+            local_open,
+            remote_as: None,
+            observer: None,
+            stale: Vec::new(),
+            keepalives: KeepaliveScheduler::default(),
+            shutdown_communication: None,
+            cease_subcode: None
+        }
+    }
+    pub fn remote_as(mut self, remote_as: u32) -> Self {
+        self.remote_as = Some(remote_as);
+        self
+    }
+    pub fn observer(mut self, peer_addr: IpAddr, observer: Box<dyn SessionObserver>) -> Self {
+        self.observer = Some((peer_addr, observer));
+        self
+    }
+    pub fn last_error(&self) -> Option<&SessionError> {
+        // The last NOTIFICATION sent or received, kept across sessions
+        self.session.stats().last_notification()
+    }
+    pub fn stats(&self) -> &PeerStats {
+        self.session.stats()
+    }
+    pub fn stats_mut(&mut self) -> &mut PeerStats {
+        // For the counters kept outside the FSM: Updates sent and prefixes
+        self.session.stats_mut()
+    }
+    pub fn candidate(&self) -> Fsm {
+        // An FSM for a second connection to the peer, with the same settings and our same Open. It has
+        // no observer, a collision's survivor takes over the loser's (see absorb).
+        let mut fsm = Fsm::new(self.session.candidate(), self.local_open.clone());
+        fsm.remote_as = self.remote_as;
+        fsm
+    }
+    fn absorb(&mut self, loser: Fsm) {
+        // The survivor of a collision carries on with the loser's statistics, stale routes and observer
+        self.session.stats_mut().absorb(loser.session.stats());
+        for af in loser.stale {
+            if !self.stale.contains(&af) {
+                self.stale.push(af);
+            }
+        }
+        if self.observer.is_none() {
+            self.observer = loser.observer;
+        }
+    }
+    pub fn reconfigure(&mut self, session: &PeerSession) {
+        // New timer settings from the config, see PeerSession::reconfigure
+        self.session.reconfigure(session);
+    }
+    pub fn state(&self) -> State {
+        self.session.state()
+    }
+    pub fn stale(&self) -> &[(Afi, Safi)] {
+        self.stale.as_slice()
+    }
+    pub fn session(&self) -> &PeerSession {
+        &self.session
+    }
+    pub fn local_open(&self) -> &Open {
+        &self.local_open
+    }
+    pub fn handle(&mut self, event: Event) -> Vec<Action> {
+        // Runs one event through the FSM and returns the actions to take
+        let prev_state = self.session.state();
+        let received = match &event {
+            Event::BGPOpen(_) | Event::BGPOpenMsgErr(_) => Some(MessageType::Open),
+            Event::UpdateMsg | Event::EndOfRib(..) | Event::UpdateMsgErr(_) => Some(MessageType::Update),
+            Event::KeepAliveMsg => Some(MessageType::KeepAlive),
+            Event::NotifMsg(_) | Event::NotifMsgVerErr => Some(MessageType::Notification),
+            Event::RouteRefreshMsg(..) => Some(MessageType::RouteRefresh),
+            _ => None
+        };
+        if let Some(msg_type) = received {
+            self.session.stats_mut().incr_received(msg_type);
+        }
+        match &event {
+            Event::NotifMsg(notification) => self.notification(notification, false),
+            Event::BGPHeaderErr(err) | Event::BGPOpenMsgErr(err) => self.decode_error(err, err.action()),
+            Event::UpdateMsgErr(err) => self.decode_error(err.code(), err.action()),
+            _ => ()
+        }
+        let actions = self.run(event);
+        self.messages_sent(&actions);
+        self.state_changed(prev_state);
+        actions
+    }
+    pub fn updates_sent(&mut self) -> Vec<Action> {
+        // UPDATEs sent put the next Keepalive off just as a KEEPALIVE would. RFC 4271, Section 8.2.2
+        let mut actions = Vec::new();
+        if self.session.state() == State::Established {
+            if let Some(keep_time) = self.keepalives.next() {
+                self.start_timer(Timer::Keepalive, keep_time, &mut actions);
+            }
+        }
+        actions
+    }
+    fn state_changed(&mut self, from: State) {
+        let to = self.session.state();
+        if from != to {
+            info!(?from, ?to, "state change");
+            self.session.stats_mut().transition(to);
+            self.emit(|peer| SessionEvent::StateChange { peer, from, to, at: SystemTime::now() });
+        }
+    }
+    fn messages_sent(&mut self, actions: &[Action]) {
+        // Counts the messages the caller is about to send, Updates are counted as they go out
+        for action in actions.iter() {
+            let msg_type = match action {
+                Action::SendOpen => MessageType::Open,
+                Action::SendKeepalive => MessageType::KeepAlive,
+                Action::SendNotification(err) => {
+                    self.notification(&Notification::new(err.clone()), true);
+                    MessageType::Notification
+                },
+                Action::SendShutdown(subcode, communication) => {
+                    self.notification(&Notification::shutdown(subcode.clone(), communication), true);
+                    MessageType::Notification
+                },
+                Action::SendRouteRefresh => {
+                    // One per negotiated address family
+                    let afi_safis = self
+                        .session
+                        .session_params()
+                        .map_or(0, |params| params.capabilities().afi_safis().len());
+                    self.session.stats_mut().incr_sent(MessageType::RouteRefresh, afi_safis as u64);
+                    continue;
+                },
+                _ => continue
+            };
+            self.session.stats_mut().incr_sent(msg_type, 1);
+        }
+    }
+    fn notification(&mut self, notification: &Notification, sent: bool) {
+        let error = SessionError {
+            code: notification.err_code(),
+            subcode: notification.err_subcode(),
+            communication: notification.shutdown_communication().map(String::from),
+            sent,
+            at: SystemTime::now()
+        };
+        warn!(
+            code = error.code,
+            subcode = error.subcode,
+            communication = error.communication.as_deref(),
+            sent,
+            "NOTIFICATION"
+        );
+        let source = match sent {
+            true => ErrorSource::Sent,
+            false => ErrorSource::Received
+        };
+        self.session.stats_mut().record_error(source, notification);
+        self.session.stats_mut().set_last_notification(error.clone());
+        self.emit(|peer| SessionEvent::Notification { peer, error });
+    }
+    fn decode_error(&mut self, err: &NotifErrorCode, action: ErrorAction) {
+        // Kept whether or not it takes the session down, the NOTIFICATION is recorded as well if it does
+        self.session.stats_mut().record_error(ErrorSource::Decode(action), &Notification::new(err.clone()));
+    }
+    fn emit<F: FnOnce(IpAddr) -> SessionEvent>(&self, event: F) {
+        if let Some((peer, observer)) = self.observer.as_ref() {
+            observer.on_event(&event(*peer));
+        }
+    }
+    fn run(&mut self, event: Event) -> Vec<Action> {
+        let mut actions: Vec<Action> = Vec::new();
+        // A timer that expired isn't running anymore
+        match event {
+            Event::ConnectRetryTimerExpires => self.session.reset_conn_retry_timer(),
+            Event::HoldTimerExpires => self.session.reset_hold_timer(),
+            Event::KeepaliveTimerExpires => self.session.reset_keep_timer(),
+            Event::DelayOpenTimerExpires => self.session.reset_delay_open_timer(),
+            Event::IdleHoldTimerExpires => self.session.reset_idle_hold_timer(),
+            Event::StaleTimerExpires => self.session.reset_stale_timer(),
+            _ => ()
+        }
+        let prev_state = self.session.state();
+        match (prev_state, event) {
+            // The peer didn't come back, or didn't send End-of-RIB, in time. Doesn't affect the session.
+            (_, Event::StaleTimerExpires) => self.flush_stale(&mut actions),
+            (State::Idle, event) => self.idle(event, &mut actions),
+            (State::Connect, event) => self.connect(event, &mut actions),
+            (State::Active, event) => self.active(event, &mut actions),
+            (State::OpenSent, event) => self.open_sent(event, &mut actions),
+            (State::OpenConfirm, event) => self.open_confirm(event, &mut actions),
+            (State::Established, event) => self.established(event, &mut actions)
+        }
+        let failed = prev_state != State::Idle && self.session.state() == State::Idle && self.session.conn_retry_ctr() > 0;
+        if failed && self.session.damp_peer_oscillations() {
+            self.session.start_idle_hold_timer();
+            actions.push(Action::StartTimer(Timer::IdleHold, self.session.idle_hold_timer()));
+        }
+        actions
+    }
+    pub fn command(&mut self, command: PeerCommand) -> Vec<Action> {
+        // Turns an operator command into FSM events, soft resets don't touch the session itself
+        let established = self.state() == State::Established;
+        match command {
+            PeerCommand::Start => self.handle(Event::ManualStart),
+            PeerCommand::Shutdown(communication) => {
+                // Only sent if the stop gets as far as a Cease
+                self.shutdown_communication = communication;
+                let actions = self.handle(Event::ManualStop);
+                self.shutdown_communication = None;
+                actions
+            },
+            PeerCommand::Cease(subcode) => {
+                self.cease_subcode = Some(subcode);
+                let actions = self.handle(Event::ManualStop);
+                self.cease_subcode = None;
+                actions
+            },
+            PeerCommand::HardReset(communication) => {
+                let mut actions: Vec<Action> = Vec::new();
+                let prev_state = self.state();
+                if prev_state != State::Idle {
+                    match communication {
+                        Some(communication) => {
+                            actions.push(Action::SendShutdown(CeaseSubcode::AdminReset, communication));
+                            self.to_idle(None, false, &mut actions);
+                        },
+                        None => self.to_idle(Some(NotifErrorCode::CeaseReason(CeaseSubcode::AdminReset)), false, &mut actions)
+                    }
+                    self.messages_sent(&actions);
+                    self.state_changed(prev_state);
+                }
+                actions.extend(self.handle(Event::ManualStart));
+                actions
+            },
+            PeerCommand::SoftResetIn if established => {
+                let refresh = self
+                    .session
+                    .session_params()
+                    .is_some_and(|params| params.capabilities().route_refresh());
+                let actions = match refresh {
+                    true => vec![Action::SendRouteRefresh],
+                    false => vec![Action::ReplayAdjRibIn]
+                };
+                self.messages_sent(&actions);
+                actions
+            },
+            PeerCommand::SoftResetOut if established => vec![Action::ResendAdjRibOut],
+            PeerCommand::SoftResetIn | PeerCommand::SoftResetOut => Vec::new()
+        }
+    }
+    pub fn idle_hold_running(&self) -> bool {
+        // While set, the peer must not be started automatically
+        self.session.idle_hold_timer() != 0
+    }
+    fn idle(&mut self, event: Event, actions: &mut Vec<Action>) {
+        // Every event other than a start is ignored. RFC 4271, Pg. 53
+        let (passive, automatic) = match event {
+            Event::ManualStart => (self.session.passive(), false),
+            Event::ManualStartPassive => (true, false),
+            Event::AutomaticStart => (self.session.passive(), true),
+            Event::AutomaticStartPassive => (true, true),
+            _ => return
+        };
+        match (automatic, self.session.damp_peer_oscillations()) {
+            (true, true) if self.idle_hold_running() => return,
+            (true, true) => (),
+            _ => {
+                self.session.reset_conn_retry_ctr();
+                self.stop_timer(Timer::IdleHold, actions);
+            }
+        }
+        self.start_conn_retry_timer(actions);
+        match passive {
+            true => self.session.set_state(State::Active),
+            false => {
+                actions.push(Action::Connect);
+                self.session.set_state(State::Connect);
+            }
+        }
+    }
+    fn connect(&mut self, event: Event, actions: &mut Vec<Action>) {
+        // RFC 4271, Pg. 54
+        match event {
+            Event::ManualStart | Event::ManualStartPassive | Event::AutomaticStart | Event::AutomaticStartPassive => (),
+            Event::ManualStop => {
+                actions.push(Action::DropConnection);
+                actions.push(Action::ReleaseResources);
+                self.session.reset_conn_retry_ctr();
+                self.stop_timer(Timer::ConnectRetry, actions);
+                self.session.set_state(State::Idle);
+            },
+            Event::ConnectRetryTimerExpires => {
+                actions.push(Action::DropConnection);
+                self.start_conn_retry_timer(actions);
+                actions.push(Action::Connect);
+            },
+            Event::TcpCrAcked | Event::TcpConnectionConfirmed => {
+                self.stop_timer(Timer::ConnectRetry, actions);
+                self.connection_up(actions);
+            },
+            Event::DelayOpenTimerExpires => self.send_open(actions),
+            Event::TcpConnectionFails => match self.delay_open_running() {
+                true => {
+                    self.start_conn_retry_timer(actions);
+                    self.stop_timer(Timer::DelayOpen, actions);
+                    self.session.set_state(State::Active);
+                },
+                false => {
+                    self.stop_timer(Timer::ConnectRetry, actions);
+                    actions.push(Action::DropConnection);
+                    actions.push(Action::ReleaseResources);
+                    self.session.set_state(State::Idle);
+                }
+            },
+            Event::BGPOpen(open) if self.delay_open_running() => self.delayed_open_received(open, actions),
+            Event::BGPHeaderErr(err) | Event::BGPOpenMsgErr(err) => {
+                let notification = self.session.send_notif_without_open().then_some(err);
+                self.to_idle(notification, true, actions);
+            },
+            Event::NotifMsgVerErr => {
+                let incr_ctr = !self.delay_open_running();
+                self.to_idle(None, incr_ctr, actions);
+            },
+            _ => self.to_idle(None, true, actions)
+        }
+    }
+    fn active(&mut self, event: Event, actions: &mut Vec<Action>) {
+        // RFC 4271, Pg. 59
+        match event {
+            Event::ManualStart | Event::ManualStartPassive | Event::AutomaticStart | Event::AutomaticStartPassive => (),
+            Event::ManualStop => {
+                if self.delay_open_running() && self.session.send_notif_without_open() {
+                    let cease = self.admin_shutdown();
+                    actions.push(cease);
+                }
+                actions.push(Action::ReleaseResources);
+                actions.push(Action::DropConnection);
+                self.session.reset_conn_retry_ctr();
+                self.stop_timer(Timer::ConnectRetry, actions);
+                self.stop_timer(Timer::DelayOpen, actions);
+                self.session.set_state(State::Idle);
+            },
+            Event::ConnectRetryTimerExpires => {
+                self.start_conn_retry_timer(actions);
+                if !self.session.passive() {
+                    actions.push(Action::Connect);
+                    self.session.set_state(State::Connect);
+                }
+            },
+            Event::TcpCrAcked | Event::TcpConnectionConfirmed => {
+                self.stop_timer(Timer::ConnectRetry, actions);
+                self.connection_up(actions);
+            },
+            Event::DelayOpenTimerExpires => self.send_open(actions),
+            Event::TcpConnectionFails => {
+                self.start_conn_retry_timer(actions);
+                self.stop_timer(Timer::DelayOpen, actions);
+                actions.push(Action::ReleaseResources);
+                self.session.incr_conn_retry_ctr();
+                self.session.set_state(State::Idle);
+            },
+            Event::BGPOpen(open) if self.delay_open_running() => self.delayed_open_received(open, actions),
+            Event::BGPHeaderErr(err) | Event::BGPOpenMsgErr(err) => {
+                let notification = self.session.send_notif_without_open().then_some(err);
+                self.to_idle(notification, true, actions);
+            },
+            Event::NotifMsgVerErr => {
+                let incr_ctr = !self.delay_open_running();
+                self.to_idle(None, incr_ctr, actions);
+            },
+            _ => self.to_idle(None, true, actions)
+        }
+    }
+    fn open_sent(&mut self, event: Event, actions: &mut Vec<Action>) {
+        // RFC 4271, Pg. 63
+        match event {
+            Event::ManualStart | Event::ManualStartPassive | Event::AutomaticStart | Event::AutomaticStartPassive => (),
+            Event::ManualStop => self.manual_stop(actions),
+            Event::HoldTimerExpires => self.to_idle(Some(NotifErrorCode::HoldTimerExpired), true, actions),
+            Event::TcpConnectionFails => {
+                actions.push(Action::DropConnection);
+                self.stop_timer(Timer::Hold, actions);
+                self.start_conn_retry_timer(actions);
+                self.session.set_state(State::Active);
+            },
+            Event::BGPOpen(open) => match check_open(&open, &self.local_open, self.remote_as) {
+                Ok(()) => {
+                    self.stop_timer(Timer::ConnectRetry, actions);
+                    self.open_received(open, actions);
+                },
+                Err(err) => self.to_idle(Some(err), true, actions)
+            },
+            Event::BGPHeaderErr(err) | Event::BGPOpenMsgErr(err) => self.to_idle(Some(err), true, actions),
+            Event::NotifMsgVerErr => self.to_idle(None, false, actions),
+            Event::OpenCollisionDump => self.collision_dump(actions),
+            Event::AutomaticStop => self.to_idle(Some(NotifErrorCode::Cease), true, actions),
+            _ => self.to_idle(Some(NotifErrorCode::FiniteStateMachineError), true, actions)
+        }
+    }
+    fn open_confirm(&mut self, event: Event, actions: &mut Vec<Action>) {
+        // RFC 4271, Pg. 67
+        match event {
+            Event::ManualStart | Event::ManualStartPassive | Event::AutomaticStart | Event::AutomaticStartPassive => (),
+            Event::ManualStop => self.manual_stop(actions),
+            Event::HoldTimerExpires => self.to_idle(Some(NotifErrorCode::HoldTimerExpired), true, actions),
+            Event::KeepaliveTimerExpires => self.send_keepalive(actions),
+            Event::TcpConnectionFails | Event::NotifMsg(_) => self.to_idle(None, true, actions),
+            Event::NotifMsgVerErr => self.to_idle(None, false, actions),
+            Event::BGPHeaderErr(err) | Event::BGPOpenMsgErr(err) => self.to_idle(Some(err), true, actions),
+            Event::KeepAliveMsg => {
+                self.restart_hold_timer(actions);
+                // The peer is back, stale routes now wait for its End-of-RIB
+                if !self.stale.is_empty() {
+                    let stale_time = self.session.stale_time();
+                    self.start_timer(Timer::Stale, stale_time, actions);
+                }
+                self.session.set_state(State::Established);
+            },
+            Event::OpenCollisionDump => self.collision_dump(actions),
+            Event::AutomaticStop => self.to_idle(Some(NotifErrorCode::Cease), true, actions),
+            _ => self.to_idle(Some(NotifErrorCode::FiniteStateMachineError), true, actions)
+        }
+    }
+    fn established(&mut self, event: Event, actions: &mut Vec<Action>) {
+        // RFC 4271, Pg. 71
+        match event {
+            Event::ManualStart | Event::ManualStartPassive | Event::AutomaticStart | Event::AutomaticStartPassive => (),
+            Event::ManualStop => self.manual_stop(actions),
+            Event::HoldTimerExpires => self.to_idle(Some(NotifErrorCode::HoldTimerExpired), true, actions),
+            Event::KeepaliveTimerExpires => self.send_keepalive(actions),
+            Event::TcpConnectionFails => self.connection_lost(actions),
+            Event::NotifMsgVerErr | Event::NotifMsg(_) => self.to_idle(None, true, actions),
+            Event::KeepAliveMsg => self.restart_hold_timer(actions),
+            Event::UpdateMsg => {
+                actions.push(Action::ProcessUpdate);
+                self.restart_hold_timer(actions);
+            },
+            Event::EndOfRib(afi, safi) => {
+                self.end_of_rib(afi, safi, actions);
+                self.restart_hold_timer(actions);
+            },
+            Event::UpdateMsgErr(err) => match err.action() {
+                ErrorAction::SessionReset => self.to_idle(Some(err.into()), true, actions),
+                // Nothing for the session to do, the UPDATE was still a sign of life. RFC 7606, Section 2
+                _ => self.restart_hold_timer(actions)
+            },
+            // Only for families the session carries, anything else is ignored. RFC 2918, Pg. 3
+            Event::RouteRefreshMsg(afi, safi) => {
+                let negotiated = self.session
+                    .session_params()
+                    .is_some_and(|params| params.capabilities().afi_safis().contains(&(afi, safi)));
+                if negotiated {
+                    actions.push(Action::ResendAdjRibOut);
+                }
+            },
+            Event::OpenCollisionDump => self.collision_dump(actions),
+            Event::AutomaticStop => self.to_idle(Some(NotifErrorCode::Cease), true, actions),
+            _ => self.to_idle(Some(NotifErrorCode::FiniteStateMachineError), true, actions)
+        }
+    }
+    fn delay_open_running(&self) -> bool {
+        self.session.delay_open_timer() != 0
+    }
+    fn connection_up(&mut self, actions: &mut Vec<Action>) {
+        // With DelayOpen our Open waits for the peer's, or for the DelayOpenTimer to expire. RFC 4271, Pg. 55
+        match self.session.delay_open() {
+            true => {
+                let time = self.session.delay_open_time();
+                self.start_timer(Timer::DelayOpen, time, actions);
+            },
+            false => self.send_open(actions)
+        }
+    }
+    fn delayed_open_received(&mut self, open: Open, actions: &mut Vec<Action>) {
+        // The peer's Open arrived while ours was being delayed, both are exchanged at once. RFC 4271, Pg. 56
+        if let Err(err) = check_open(&open, &self.local_open, self.remote_as) {
+            let notification = self.session.send_notif_without_open().then_some(err);
+            self.to_idle(notification, true, actions);
+            return;
+        }
+        self.stop_timer(Timer::ConnectRetry, actions);
+        self.stop_timer(Timer::DelayOpen, actions);
+        actions.push(Action::SendOpen);
+        self.open_received(open, actions);
+    }
+    fn open_received(&mut self, open: Open, actions: &mut Vec<Action>) {
+        // The Hold Time is the smaller of the two, Keepalives go out at a third of it or at the
+        // configured KeepaliveTime if that's shorter.
+        let mut params = SessionParams::negotiate(&self.local_open, &open);
+        params.limit_keepalive_time(self.session.keep_time());
+        actions.push(Action::SendKeepalive);
+        // A Hold Time of zero means neither timer is run. RFC 4271, Pg. 65
+        match params.hold_time() {
+            0 => {
+                self.keepalives.stop();
+                self.stop_timer(Timer::Keepalive, actions);
+                self.stop_timer(Timer::Hold, actions);
+            },
+            hold_time => {
+                if let Some(keep_time) = self.keepalives.start(params.keepalive_time(), self.session.keepalive_jitter()) {
+                    self.start_timer(Timer::Keepalive, keep_time, actions);
+                }
+                self.start_timer(Timer::Hold, hold_time as usize, actions);
+            }
+        }
+        // Stale routes in families the peer lost forwarding state for can't wait for End-of-RIB. RFC 4724, Pg. 6
+        let preserved = params.capabilities().forwarding_preserved();
+        let lost: Vec<(Afi, Safi)> = self.stale
+            .iter()
+            .filter(|af| !preserved.contains(af))
+            .copied()
+            .collect();
+        lost.into_iter().for_each(|(afi, safi)| self.end_of_rib(afi, safi, actions));
+        self.session.set_session_params(params);
+        self.session.set_state(State::OpenConfirm);
+    }
+    fn send_open(&mut self, actions: &mut Vec<Action>) {
+        // Sends our Open once the TCP connection is up and waits for the peer's
+        self.stop_timer(Timer::ConnectRetry, actions);
+        self.stop_timer(Timer::DelayOpen, actions);
+        actions.push(Action::SendOpen);
+        self.start_timer(Timer::Hold, LARGE_HOLD_TIME, actions);
+        self.session.set_state(State::OpenSent);
+    }
+    fn send_keepalive(&mut self, actions: &mut Vec<Action>) {
+        actions.push(Action::SendKeepalive);
+        if let Some(keep_time) = self.keepalives.next() {
+            self.start_timer(Timer::Keepalive, keep_time, actions);
+        }
+    }
+    fn restart_hold_timer(&mut self, actions: &mut Vec<Action>) {
+        let hold_time = self.session.session_params().map(|params| params.hold_time()).unwrap_or(0);
+        if hold_time != 0 {
+            self.start_timer(Timer::Hold, hold_time as usize, actions);
+        }
+    }
+    fn collision_dump(&mut self, actions: &mut Vec<Action>) {
+        // This connection lost a collision. RFC 4271, Pg. 66
+        let cease = NotifErrorCode::CeaseReason(CeaseSubcode::ConnectionCollision);
+        self.to_idle(Some(cease), true, actions);
+    }
+    fn connection_lost(&mut self, actions: &mut Vec<Action>) {
+        // The established session dropped without a NOTIFICATION. If the peer can restart gracefully, its
+        // routes are kept for the Restart Time it advertised. RFC 4724, Pg. 5
+        let (restart_time, afi_safis) = match self.session.session_params() {
+            Some(params) => {
+                let caps = params.capabilities();
+                let afi_safis: Vec<(Afi, Safi)> = caps
+                    .restart_afi_safis()
+                    .iter()
+                    .map(|(afi, safi, _)| (*afi, *safi))
+                    .collect();
+                (caps.restart_time().unwrap_or(0), afi_safis)
+            },
+            None => (0, Vec::new())
+        };
+        if restart_time == 0 || afi_safis.is_empty() {
+            return self.to_idle(None, true, actions);
+        }
+        afi_safis.iter().for_each(|af| match self.stale.contains(af) {
+            true => (),
+            false => self.stale.push(*af)
+        });
+        self.close(None, true, Action::MarkStale(afi_safis), actions);
+        self.start_timer(Timer::Stale, restart_time as usize, actions);
+    }
+    fn end_of_rib(&mut self, afi: Afi, safi: Safi, actions: &mut Vec<Action>) {
+        // The address family's stale routes can go, the StaleTimer stops once none are left
+        if let Some(idx) = self.stale.iter().position(|af| *af == (afi, safi)) {
+            _ = self.stale.remove(idx);
+            actions.push(Action::FlushStale(afi, safi));
+        }
+        if self.stale.is_empty() {
+            self.stop_timer(Timer::Stale, actions);
+        }
+    }
+    fn flush_stale(&mut self, actions: &mut Vec<Action>) {
+        self.stop_timer(Timer::Stale, actions);
+        self.stale
+        .drain(..)
+        .for_each(|(afi, safi)| actions.push(Action::FlushStale(afi, safi)));
+    }
+    fn manual_stop(&mut self, actions: &mut Vec<Action>) {
+        // Same in OpenSent, OpenConfirm and Established: the peer is told why and the counter is reset
+        let cease = self.admin_shutdown();
+        actions.push(cease);
+        self.stop_timer(Timer::ConnectRetry, actions);
+        self.flush_stale(actions);
+        actions.push(Action::ReleaseResources);
+        actions.push(Action::DropConnection);
+        self.session.reset_conn_retry_ctr();
+        self.stop_all_timers(actions);
+        self.session.clear_session_params();
+        self.session.set_state(State::Idle);
+    }
+    fn admin_shutdown(&mut self) -> Action {
+        // The Cease for a manual stop, with the operator's reason if there is one
+        match (self.cease_subcode.take(), self.shutdown_communication.take()) {
+            (Some(subcode), _) => Action::SendNotification(NotifErrorCode::CeaseReason(subcode)),
+            (None, Some(communication)) => Action::SendShutdown(CeaseSubcode::AdminShutdown, communication),
+            (None, None) => Action::SendNotification(NotifErrorCode::CeaseReason(CeaseSubcode::AdminShutdown))
+        }
+    }
+    fn to_idle(&mut self, notification: Option<NotifErrorCode>, incr_ctr: bool, actions: &mut Vec<Action>) {
+        // The common error handling: optionally send a NOTIFICATION, tear the session down and go back to Idle.
+        // Most errors also count against the ConnectRetryCounter. Stale routes outlive failed attempts to bring
+        // the session back up, but not the restarted session going down again.
+        if self.session.state() == State::Established {
+            self.flush_stale(actions);
+        }
+        self.close(notification, incr_ctr, Action::ReleaseResources, actions);
+    }
+    fn close(&mut self, notification: Option<NotifErrorCode>, incr_ctr: bool, release: Action, actions: &mut Vec<Action>) {
+        if let Some(err) = notification {
+            actions.push(Action::SendNotification(err));
+        }
+        self.stop_timer(Timer::ConnectRetry, actions);
+        actions.push(release);
+        actions.push(Action::DropConnection);
+        if incr_ctr {
+            self.session.incr_conn_retry_ctr();
+        }
+        self.stop_all_timers(actions);
+        self.session.clear_session_params();
+        self.session.set_state(State::Idle);
+    }
+    fn start_conn_retry_timer(&mut self, actions: &mut Vec<Action>) {
+        self.session.start_conn_retry_timer();
+        actions.push(Action::StartTimer(Timer::ConnectRetry, self.session.conn_retry_time()));
+    }
+    fn start_timer(&mut self, timer: Timer, time: usize, actions: &mut Vec<Action>) {
+        match timer {
+            Timer::ConnectRetry => self.session.start_conn_retry_timer(),
+            Timer::Hold => self.session.start_hold_timer(time),
+            Timer::Keepalive => self.session.start_keep_timer(time),
+            Timer::DelayOpen => self.session.start_delay_open_timer(),
+            Timer::IdleHold => self.session.start_idle_hold_timer(),
+            Timer::Stale => self.session.start_stale_timer(time)
+        }
+        actions.push(Action::StartTimer(timer, time));
+    }
+    fn stop_timer(&mut self, timer: Timer, actions: &mut Vec<Action>) {
+        // Only stops timers that are running, so the caller isn't sent redundant actions
+        let running = match timer {
+            Timer::ConnectRetry => self.session.conn_retry_timer(),
+            Timer::Hold => self.session.hold_timer(),
+            Timer::Keepalive => self.session.keep_timer(),
+            Timer::DelayOpen => self.session.delay_open_timer(),
+            Timer::IdleHold => self.session.idle_hold_timer(),
+            Timer::Stale => self.session.stale_timer()
+        } != 0;
+        if running {
+            match timer {
+                Timer::ConnectRetry => self.session.reset_conn_retry_timer(),
+                Timer::Hold => self.session.reset_hold_timer(),
+                Timer::Keepalive => self.session.reset_keep_timer(),
+                Timer::DelayOpen => self.session.reset_delay_open_timer(),
+                Timer::IdleHold => self.session.reset_idle_hold_timer(),
+                Timer::Stale => self.session.reset_stale_timer()
+            }
+            actions.push(Action::StopTimer(timer));
+        }
+    }
+    fn stop_all_timers(&mut self, actions: &mut Vec<Action>) {
+        self.keepalives.stop();
+        self.stop_timer(Timer::Hold, actions);
+        self.stop_timer(Timer::Keepalive, actions);
+        self.stop_timer(Timer::DelayOpen, actions);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Connection {
+    // The connection we initiated
+    Outgoing,
+    // The connection the peer initiated
+    Incoming
+}
+
+impl Connection {
+    pub fn other(self) -> Self {
+        match self {
+            Connection::Outgoing => Connection::Incoming,
+            Connection::Incoming => Connection::Outgoing
+        }
+    }
+}
+
+// Picks the connection to keep when both sides connected at the same time. The connection initiated by
+// the speaker with the higher BGP Identifier survives. RFC 4271, Pg. 29
+pub(crate) fn collision_winner(local_id: Ipv4Addr, remote_id: Ipv4Addr) -> Connection {
+    match local_id < remote_id {
+        true => Connection::Incoming,
+        false => Connection::Outgoing
+    }
+}
+
+// Both candidate connections to a peer, each with its own FSM. Once an Open arrives on one of them
+// it is checked against the other, and the losing connection is closed with a Cease. A connection
+// that comes up closes the other one the same way, an Established session always wins.
+pub(crate) struct PeerConnections {
+    local_id: Ipv4Addr,
+    outgoing: Option<Fsm>,
+    incoming: Option<Fsm>
+}
+
+impl PeerConnections {
+    pub fn new(local_id: Ipv4Addr) -> Self {
+        Self {
+            local_id,
+            outgoing: None,
+            incoming: None
+        }
+    }
+    pub fn add(&mut self, conn: Connection, fsm: Fsm) {
+        // Replaces any FSM already running for the connection
+        *self.slot(conn) = Some(fsm);
+    }
+    pub fn remove(&mut self, conn: Connection) -> Option<Fsm> {
+        self.slot(conn).take()
+    }
+    pub fn get(&self, conn: Connection) -> Option<&Fsm> {
+        match conn {
+            Connection::Outgoing => self.outgoing.as_ref(),
+            Connection::Incoming => self.incoming.as_ref()
+        }
+    }
+    pub fn get_mut(&mut self, conn: Connection) -> Option<&mut Fsm> {
+        self.slot(conn).as_mut()
+    }
+    pub fn established(&self) -> Option<Connection> {
+        [Connection::Outgoing, Connection::Incoming]
+        .into_iter()
+        .find(|conn| self.get(*conn).is_some_and(|fsm| fsm.state() == State::Established))
+    }
+    pub fn handle(&mut self, conn: Connection, event: Event) -> Vec<(Connection, Action)> {
+        // Runs an event through the connection's FSM. The actions are tagged with the connection they apply
+        // to, since resolving a collision may close the other one. The loser's FSM is removed, the other
+        // one takes over its statistics.
+        let mut out: Vec<(Connection, Action)> = Vec::new();
+        if let Event::BGPOpen(open) = &event {
+            if let Some(loser) = self.collision_loser(conn, Ipv4Addr::from(open.bgp_id())) {
+                self.dump(loser, &mut out);
+                if loser == conn {
+                    return out;
+                }
+            }
+        }
+        if let Some(fsm) = self.slot(conn).as_mut() {
+            out.extend(fsm.handle(event).into_iter().map(|action| (conn, action)));
+            if fsm.state() == State::Established {
+                self.dump(conn.other(), &mut out);
+            }
+        }
+        out
+    }
+    fn dump(&mut self, loser: Connection, out: &mut Vec<(Connection, Action)>) {
+        if let Some(mut fsm) = self.remove(loser) {
+            out.extend(fsm.handle(Event::OpenCollisionDump).into_iter().map(|action| (loser, action)));
+            if let Some(winner) = self.slot(loser.other()).as_mut() {
+                winner.absorb(fsm);
+            }
+        }
+    }
+    fn collision_loser(&self, conn: Connection, remote_id: Ipv4Addr) -> Option<Connection> {
+        // Only a connection that has the peer's BGP Identifier (OpenConfirm) can collide. An Established
+        // session always wins over a new connection. RFC 4271, Pg. 28
+        let other = self.get(conn.other())?;
+        match other.state() {
+            State::Established => Some(conn),
+            State::OpenConfirm => match collision_winner(self.local_id, remote_id) == conn {
+                true => Some(conn.other()),
+                false => Some(conn)
+            },
+            _ => None
+        }
+    }
+    fn slot(&mut self, conn: Connection) -> &mut Option<Fsm> {
+        match conn {
+            Connection::Outgoing => &mut self.outgoing,
+            Connection::Incoming => &mut self.incoming
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        errors::{DecodeError, OpenMsgErrSubcode, UpdateMsgErrSubcode},
+        fsm_ds::{ErrorRecord, PeerSessionBuilder, ERROR_DATA_LEN, ERROR_HISTORY_LEN},
+        message_types::{Capability, OpenBuilder},
+    };
+
+    fn new_fsm() -> Fsm {
+        Fsm::new(PeerSessionBuilder::new().build(), OpenBuilder::new(4, 65000, 90, 1).build())
+    }
+
+    fn established() -> Fsm {
+        let mut fsm = new_fsm();
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        _ = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 30, 2).build()));
+        _ = fsm.handle(Event::KeepAliveMsg);
+        fsm
+    }
+
+    #[test]
+    fn fsm_to_established() {
+        let mut fsm = new_fsm();
+        assert_eq!(fsm.handle(Event::ManualStop), Vec::new());
+        assert_eq!(
+            fsm.handle(Event::ManualStart),
+            vec![Action::StartTimer(Timer::ConnectRetry, 120), Action::Connect]
+        );
+        assert_eq!(fsm.state(), State::Connect);
+        assert_eq!(
+            fsm.handle(Event::TcpConnectionConfirmed),
+            vec![Action::StopTimer(Timer::ConnectRetry), Action::SendOpen, Action::StartTimer(Timer::Hold, LARGE_HOLD_TIME)]
+        );
+        assert_eq!(fsm.state(), State::OpenSent);
+        // The smaller Hold Time wins
+        assert_eq!(
+            fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 30, 2).build())),
+            vec![Action::SendKeepalive, Action::StartTimer(Timer::Keepalive, 10), Action::StartTimer(Timer::Hold, 30)]
+        );
+        assert_eq!(fsm.state(), State::OpenConfirm);
+        assert_eq!(fsm.handle(Event::KeepAliveMsg), vec![Action::StartTimer(Timer::Hold, 30)]);
+        assert_eq!(fsm.state(), State::Established);
+        assert_eq!(fsm.session().session_params().unwrap().remote_as(), 65001);
+
+        assert_eq!(fsm.handle(Event::UpdateMsg), vec![Action::ProcessUpdate, Action::StartTimer(Timer::Hold, 30)]);
+        assert_eq!(
+            fsm.handle(Event::KeepaliveTimerExpires),
+            vec![Action::SendKeepalive, Action::StartTimer(Timer::Keepalive, 10)]
+        );
+    }
+
+    #[test]
+    fn fsm_connect_retry() {
+        let mut fsm = new_fsm();
+        _ = fsm.handle(Event::ManualStart);
+        assert_eq!(
+            fsm.handle(Event::ConnectRetryTimerExpires),
+            vec![Action::DropConnection, Action::StartTimer(Timer::ConnectRetry, 120), Action::Connect]
+        );
+        assert_eq!(fsm.state(), State::Connect);
+        // Losing the connection after the Open was sent falls back to Active
+        _ = fsm.handle(Event::TcpCrAcked);
+        _ = fsm.handle(Event::TcpConnectionFails);
+        assert_eq!(fsm.state(), State::Active);
+        _ = fsm.handle(Event::ConnectRetryTimerExpires);
+        assert_eq!(fsm.state(), State::Connect);
+        assert_eq!(fsm.session().conn_retry_ctr(), 0);
+    }
+
+    #[test]
+    fn fsm_errors() {
+        // Hold timer expiry is reported to the peer and counted
+        let mut fsm = established();
+        let actions = fsm.handle(Event::HoldTimerExpires);
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::HoldTimerExpired));
+        assert!(actions.contains(&Action::ReleaseResources));
+        assert!(actions.contains(&Action::DropConnection));
+        assert_eq!(fsm.state(), State::Idle);
+        assert_eq!(fsm.session().conn_retry_ctr(), 1);
+        assert!(fsm.session().session_params().is_none());
+        assert_eq!((fsm.session().hold_timer(), fsm.session().keep_timer()), (0, 0));
+
+        // Unexpected messages are FSM errors
+        let mut fsm = new_fsm();
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let actions = fsm.handle(Event::UpdateMsg);
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::FiniteStateMachineError));
+        assert_eq!(fsm.state(), State::Idle);
+
+        // An administrative stop resets the counter
+        let mut fsm = established();
+        let actions = fsm.handle(Event::ManualStop);
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::CeaseReason(CeaseSubcode::AdminShutdown)));
+        assert_eq!(fsm.state(), State::Idle);
+        assert_eq!(fsm.session().conn_retry_ctr(), 0);
+
+        // UPDATE errors only take the session down if they have to
+        let mut fsm = established();
+        let err = DecodeError::from(UpdateMsgErrSubcode::MalformedAsPath);
+        assert_eq!(fsm.handle(Event::UpdateMsgErr(err.clone())), vec![Action::StartTimer(Timer::Hold, 30)]);
+        assert_eq!(fsm.state(), State::Established);
+        let actions = fsm.handle(Event::UpdateMsgErr(err.with_action(ErrorAction::SessionReset)));
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MalformedAsPath)));
+        assert_eq!(fsm.state(), State::Idle);
+    }
+
+    #[test]
+    fn fsm_delay_open() {
+        let session = PeerSessionBuilder::new().delay_open(5).build();
+        let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+        _ = fsm.handle(Event::ManualStart);
+        assert_eq!(
+            fsm.handle(Event::TcpConnectionConfirmed),
+            vec![Action::StopTimer(Timer::ConnectRetry), Action::StartTimer(Timer::DelayOpen, 5)]
+        );
+        assert_eq!(fsm.state(), State::Connect);
+        // The peer's Open arrives first, ours goes out right away
+        let actions = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 90, 2).build()));
+        assert_eq!(actions[..3], [Action::StopTimer(Timer::DelayOpen), Action::SendOpen, Action::SendKeepalive]);
+        assert_eq!(fsm.state(), State::OpenConfirm);
+
+        // The timer expires before the peer sends anything
+        let session = PeerSessionBuilder::new().delay_open(5).build();
+        let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let actions = fsm.handle(Event::DelayOpenTimerExpires);
+        assert_eq!(actions[0], Action::SendOpen);
+        assert_eq!(fsm.state(), State::OpenSent);
+    }
+
+    #[test]
+    fn fsm_open_checked() {
+        let mut fsm = Fsm::new(PeerSessionBuilder::new().build(), OpenBuilder::new(4, 65000, 90, 1).build()).remote_as(65001);
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        assert_eq!(fsm.state(), State::OpenSent);
+        let actions = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65002, 90, 2).build()));
+        assert!(actions.contains(&Action::SendNotification(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::BadPeerAs))));
+        assert_eq!(fsm.state(), State::Idle);
+
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        _ = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 90, 2).build()));
+        assert_eq!(fsm.state(), State::OpenConfirm);
+    }
+
+    #[test]
+    fn fsm_notification_without_open() {
+        let err = || NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::BadPeerAs);
+        for (session, notified) in [
+            (PeerSessionBuilder::new().delay_open(5).build(), false),
+            (PeerSessionBuilder::new().delay_open(5).send_notif_without_open().build(), true)
+        ] {
+            let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+            _ = fsm.handle(Event::ManualStart);
+            _ = fsm.handle(Event::TcpConnectionConfirmed);
+            let actions = fsm.handle(Event::BGPOpenMsgErr(err()));
+            assert_eq!(actions.contains(&Action::SendNotification(err())), notified);
+            assert_eq!(fsm.state(), State::Idle);
+            assert_eq!(fsm.session().delay_open_timer(), 0);
+        }
+    }
+
+    #[test]
+    fn fsm_connection_collision() {
+        // Brings a connection up to OpenSent
+        let open_sent = || {
+            let mut fsm = new_fsm();
+            _ = fsm.handle(Event::ManualStart);
+            _ = fsm.handle(Event::TcpConnectionConfirmed);
+            fsm
+        };
+        let remote_open = |id: [u8; 4]| Event::BGPOpen(OpenBuilder::new(4, 65001, 90, u32::from(Ipv4Addr::from(id))).build());
+        let cease = || Action::SendNotification(NotifErrorCode::CeaseReason(CeaseSubcode::ConnectionCollision));
+
+        // The peer has the higher Identifier, so the connection it initiated is kept
+        let mut conns = PeerConnections::new(Ipv4Addr::new(1, 1, 1, 1));
+        conns.add(Connection::Outgoing, open_sent());
+        conns.add(Connection::Incoming, open_sent());
+        _ = conns.handle(Connection::Outgoing, remote_open([2, 2, 2, 2]));
+        let actions = conns.handle(Connection::Incoming, remote_open([2, 2, 2, 2]));
+        assert!(actions.contains(&(Connection::Outgoing, cease())));
+        assert!(actions.iter().any(|(conn, action)| *conn == Connection::Incoming && *action == Action::SendKeepalive));
+        assert!(conns.get(Connection::Outgoing).is_none());
+        assert_eq!(conns.get(Connection::Incoming).unwrap().state(), State::OpenConfirm);
+        // The survivor carries the loser's counters on
+        assert_eq!(conns.get(Connection::Incoming).unwrap().stats().sent().get(MessageType::Open), 2);
+
+        // We have the higher Identifier, the new connection loses and the existing one continues
+        let mut conns = PeerConnections::new(Ipv4Addr::new(3, 3, 3, 3));
+        conns.add(Connection::Outgoing, open_sent());
+        conns.add(Connection::Incoming, open_sent());
+        _ = conns.handle(Connection::Outgoing, remote_open([2, 2, 2, 2]));
+        let actions = conns.handle(Connection::Incoming, remote_open([2, 2, 2, 2]));
+        assert!(actions.iter().all(|(conn, _)| *conn == Connection::Incoming));
+        assert_eq!(actions[0].1, cease());
+        assert!(conns.get(Connection::Incoming).is_none());
+
+        // An Established session is never replaced
+        _ = conns.handle(Connection::Outgoing, Event::KeepAliveMsg);
+        assert_eq!(conns.established(), Some(Connection::Outgoing));
+        conns.add(Connection::Incoming, open_sent());
+        let actions = conns.handle(Connection::Incoming, remote_open([2, 2, 2, 2]));
+        assert!(actions.iter().all(|(conn, _)| *conn == Connection::Incoming));
+        assert_eq!(conns.established(), Some(Connection::Outgoing));
+
+        assert_eq!(collision_winner(Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(2, 2, 2, 2)), Connection::Incoming);
+    }
+
+    #[test]
+    fn fsm_passive() {
+        let session = PeerSessionBuilder::new().passive().build();
+        let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+        assert_eq!(fsm.handle(Event::ManualStart), vec![Action::StartTimer(Timer::ConnectRetry, 120)]);
+        assert_eq!(fsm.state(), State::Active);
+        assert!(!fsm.handle(Event::ConnectRetryTimerExpires).contains(&Action::Connect));
+        assert_eq!(fsm.state(), State::Active);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        assert_eq!(fsm.state(), State::OpenSent);
+
+        // A passive start of an active session only waits for the first connect retry
+        let mut fsm = new_fsm();
+        _ = fsm.handle(Event::ManualStartPassive);
+        assert_eq!(fsm.state(), State::Active);
+        assert!(fsm.handle(Event::ConnectRetryTimerExpires).contains(&Action::Connect));
+        assert_eq!(fsm.state(), State::Connect);
+    }
+
+    #[test]
+    fn fsm_damp_peer_oscillations() {
+        let session = PeerSessionBuilder::new().damp_peer_oscillations(10).build();
+        let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let actions = fsm.handle(Event::HoldTimerExpires);
+        assert_eq!(actions.last(), Some(&Action::StartTimer(Timer::IdleHold, 10)));
+        assert!(fsm.idle_hold_running());
+
+        // The expiry only lifts the hold, the peer stays in Idle
+        assert!(fsm.handle(Event::IdleHoldTimerExpires).is_empty());
+        assert!(!fsm.idle_hold_running());
+        assert_eq!(fsm.state(), State::Idle);
+        assert_eq!(fsm.session().damped_ctr(), 1);
+
+        // A manual stop isn't a failure, so the peer isn't held
+        let session = PeerSessionBuilder::new().damp_peer_oscillations(10).build();
+        let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::ManualStop);
+        assert!(!fsm.idle_hold_running());
+    }
+
+    #[test]
+    fn fsm_automatic_start() {
+        let session = PeerSessionBuilder::new().damp_peer_oscillations(10).build();
+        let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+        _ = fsm.handle(Event::AutomaticStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let actions = fsm.handle(Event::AutomaticStop);
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::Cease));
+        assert!(fsm.idle_hold_running());
+
+        // Held down, automatic starts are ignored until the IdleHoldTimer expires
+        assert!(fsm.handle(Event::AutomaticStart).is_empty());
+        assert_eq!(fsm.state(), State::Idle);
+        _ = fsm.handle(Event::IdleHoldTimerExpires);
+        _ = fsm.handle(Event::AutomaticStart);
+        assert_eq!(fsm.state(), State::Connect);
+        // The failure is still counted, so the next hold is longer
+        assert_eq!(fsm.session().conn_retry_ctr(), 1);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let actions = fsm.handle(Event::HoldTimerExpires);
+        assert_eq!(actions.last(), Some(&Action::StartTimer(Timer::IdleHold, 20)));
+    }
+
+    #[test]
+    fn fsm_peer_commands() {
+        let mut fsm = established();
+        assert_eq!(fsm.command(PeerCommand::SoftResetIn), vec![Action::ReplayAdjRibIn]);
+        assert_eq!(fsm.command(PeerCommand::SoftResetOut), vec![Action::ResendAdjRibOut]);
+        // A ROUTE-REFRESH from the peer is the same as a soft reset out, for families the session carries
+        assert_eq!(fsm.handle(Event::RouteRefreshMsg(Afi::Ipv4, Safi::Unicast)), vec![Action::ResendAdjRibOut]);
+        assert!(fsm.handle(Event::RouteRefreshMsg(Afi::Ipv6, Safi::Unicast)).is_empty());
+        assert_eq!(fsm.stats().received().get(MessageType::RouteRefresh), 2);
+        let actions = fsm.command(PeerCommand::HardReset(None));
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::CeaseReason(CeaseSubcode::AdminReset)));
+        assert_eq!(actions.last(), Some(&Action::Connect));
+        assert_eq!(fsm.state(), State::Connect);
+        // Soft resets need an Established session
+        assert!(fsm.command(PeerCommand::SoftResetIn).is_empty());
+        _ = fsm.command(PeerCommand::Shutdown(None));
+        assert_eq!(fsm.state(), State::Idle);
+
+        let local = OpenBuilder::new(4, 65000, 90, 1).capability(Capability::RouteRefresh).build();
+        let mut fsm = Fsm::new(PeerSessionBuilder::new().build(), local);
+        _ = fsm.command(PeerCommand::Start);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let remote = OpenBuilder::new(4, 65001, 90, 2).capability(Capability::RouteRefresh).build();
+        _ = fsm.handle(Event::BGPOpen(remote));
+        _ = fsm.handle(Event::KeepAliveMsg);
+        assert_eq!(fsm.command(PeerCommand::SoftResetIn), vec![Action::SendRouteRefresh]);
+        let actions = fsm.command(PeerCommand::Cease(CeaseSubcode::MaxPrefixesReached));
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::CeaseReason(CeaseSubcode::MaxPrefixesReached)));
+        assert_eq!(fsm.state(), State::Idle);
+    }
+
+    #[test]
+    fn fsm_shutdown_communication() {
+        let mut fsm = established();
+        let actions = fsm.command(PeerCommand::Shutdown(Some("moving to new router".to_string())));
+        assert_eq!(actions[0], Action::SendShutdown(CeaseSubcode::AdminShutdown, "moving to new router".to_string()));
+        assert_eq!(fsm.last_error().unwrap().communication.as_deref(), Some("moving to new router"));
+        // The reason doesn't stick around for the next stop
+        _ = fsm.command(PeerCommand::Start);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let actions = fsm.command(PeerCommand::Shutdown(None));
+        assert_eq!(actions[0], Action::SendNotification(NotifErrorCode::CeaseReason(CeaseSubcode::AdminShutdown)));
+
+        let mut fsm = established();
+        let actions = fsm.command(PeerCommand::HardReset(Some("clearing flap".to_string())));
+        assert_eq!(actions[0], Action::SendShutdown(CeaseSubcode::AdminReset, "clearing flap".to_string()));
+        assert_eq!(fsm.last_error().unwrap().subcode, 4);
+
+        // What the peer's operator had to say
+        let mut fsm = established();
+        _ = fsm.handle(Event::NotifMsg(Notification::shutdown(CeaseSubcode::AdminShutdown, "upgrade")));
+        let last = fsm.last_error().unwrap();
+        assert_eq!((last.communication.as_deref(), last.sent), (Some("upgrade"), false));
+    }
+
+    #[test]
+    fn fsm_peer_stats() {
+        let mut fsm = established();
+        let stats = fsm.stats();
+        // Idle -> Connect -> OpenSent -> OpenConfirm -> Established
+        assert_eq!(stats.transitions(), 4);
+        assert!(stats.uptime().is_some());
+        assert_eq!((stats.received().get(MessageType::Open), stats.received().get(MessageType::KeepAlive)), (1, 1));
+        assert_eq!((stats.sent().get(MessageType::Open), stats.sent().get(MessageType::KeepAlive)), (1, 1));
+
+        _ = fsm.handle(Event::UpdateMsg);
+        _ = fsm.handle(Event::KeepaliveTimerExpires);
+        _ = fsm.command(PeerCommand::HardReset(None));
+        let stats = fsm.stats();
+        assert_eq!(stats.updates_received(), 1);
+        assert_eq!(stats.sent().get(MessageType::KeepAlive), 2);
+        assert_eq!(stats.sent().get(MessageType::Notification), 1);
+        assert_eq!(stats.last_notification().map(|err| err.sent), Some(true));
+        // Established -> Idle -> Connect
+        assert_eq!(stats.transitions(), 6);
+        assert_eq!(stats.uptime(), None);
+        assert_eq!(stats.sent().total(), 4);
+    }
+
+    #[test]
+    fn fsm_keepalives() {
+        // Due a third of the Hold Time after the last KEEPALIVE or UPDATE sent, only while Established
+        let mut fsm = established();
+        assert_eq!(fsm.handle(Event::KeepaliveTimerExpires), vec![Action::SendKeepalive, Action::StartTimer(Timer::Keepalive, 10)]);
+        assert_eq!(fsm.updates_sent(), vec![Action::StartTimer(Timer::Keepalive, 10)]);
+        _ = fsm.handle(Event::ManualStop);
+        assert_eq!(fsm.session().keep_timer(), 0);
+        assert_eq!(fsm.updates_sent(), Vec::new());
+
+        // A Hold Time of zero never starts them
+        let mut fsm = new_fsm();
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        _ = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 0, 2).build()));
+        _ = fsm.handle(Event::KeepAliveMsg);
+        assert_eq!(fsm.state(), State::Established);
+        assert_eq!(fsm.updates_sent(), Vec::new());
+    }
+
+    #[test]
+    fn fsm_error_history() {
+        let mut fsm = established();
+        let err = DecodeError::from(UpdateMsgErrSubcode::MalformedAsPath);
+        _ = fsm.handle(Event::UpdateMsgErr(err));
+        _ = fsm.handle(Event::NotifMsg(Notification::shutdown(CeaseSubcode::AdminReset, "maintenance window until 0400 UTC")));
+        let errors: Vec<&ErrorRecord> = fsm.stats().errors().collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].source, errors[0].code, errors[0].subcode), (ErrorSource::Decode(ErrorAction::TreatAsWithdraw), 3, 11));
+        assert_eq!((errors[1].source, errors[1].code, errors[1].subcode), (ErrorSource::Received, 6, 4));
+        // Only the start of the Data is kept
+        assert_eq!(errors[1].data.len(), ERROR_DATA_LEN);
+        assert_eq!(&errors[1].data[1..], b"maintenance win");
+
+        // Kept across sessions, the oldest go first
+        for _ in 0..ERROR_HISTORY_LEN {
+            _ = fsm.handle(Event::ManualStart);
+            _ = fsm.handle(Event::TcpConnectionConfirmed);
+            _ = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 30, 2).build()));
+            _ = fsm.handle(Event::KeepAliveMsg);
+            _ = fsm.handle(Event::ManualStop);
+        }
+        let errors: Vec<&ErrorRecord> = fsm.stats().errors().collect();
+        assert_eq!(errors.len(), ERROR_HISTORY_LEN);
+        assert!(errors.iter().all(|err| err.source == ErrorSource::Sent && err.code == 6));
+    }
+
+    #[test]
+    fn fsm_hold_time_negotiation() {
+        // 1 and 2 second Hold Times are rejected
+        let mut fsm = new_fsm();
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        let actions = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 2, 2).build()));
+        assert_eq!(
+            actions[0],
+            Action::SendNotification(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnacceptableHoldTime))
+        );
+        assert_eq!(fsm.state(), State::Idle);
+
+        // The configured KeepaliveTime applies when it is shorter than a third of the Hold Time
+        let session = PeerSessionBuilder::new().keep_time(5).keepalive_jitter().build();
+        let mut fsm = Fsm::new(session, OpenBuilder::new(4, 65000, 90, 1).build());
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        _ = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 60, 2).build()));
+        let params = fsm.session().session_params().unwrap();
+        assert_eq!((params.hold_time(), params.keepalive_time()), (60, 5));
+        assert!((3..=5).contains(&fsm.session().keep_timer()));
+        assert_eq!(fsm.session().hold_timer(), 60);
+    }
+
+    #[test]
+    fn fsm_session_events() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut fsm = new_fsm().observer(peer, Box::new(tx));
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        _ = fsm.handle(Event::BGPOpen(OpenBuilder::new(4, 65001, 90, 2).build()));
+        _ = fsm.handle(Event::KeepAliveMsg);
+        let mut events: Vec<SessionEvent> = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event.peer() == peer));
+        assert!(events[3].is_peer_up());
+
+        // The peer tears the session down
+        let notification = Notification::new(NotifErrorCode::CeaseReason(CeaseSubcode::AdminReset));
+        _ = fsm.handle(Event::NotifMsg(notification));
+        let error = match rx.try_recv().unwrap() {
+            SessionEvent::Notification { error, .. } => error,
+            event => panic!("unexpected event {:?}", event)
+        };
+        assert_eq!((error.code, error.subcode, error.sent), (6, 4, false));
+        assert!(rx.try_recv().unwrap().is_peer_down());
+        assert_eq!(fsm.last_error(), Some(&error));
+
+        // NOTIFICATIONs we send are recorded too
+        _ = fsm.handle(Event::ManualStart);
+        _ = fsm.handle(Event::TcpConnectionConfirmed);
+        _ = fsm.handle(Event::HoldTimerExpires);
+        let last = fsm.last_error().unwrap();
+        assert_eq!((last.code, last.sent), (4, true));
+    }
+
+    #[test]
+    fn fsm_graceful_restart() {
+        let v4 = (Afi::Ipv4, Safi::Unicast);
+        let gr = |forwarding: bool| Capability::GracefulRestart {
+            restart_state: false,
+            restart_time: 120,
+            afi_safis: vec![(Afi::Ipv4, Safi::Unicast, forwarding)]
+        };
+        let peer_open = |forwarding: bool| OpenBuilder::new(4, 65001, 30, 2).capability(gr(forwarding)).build();
+        let restart = |fsm: &mut Fsm, open: Open| -> Vec<Action> {
+            let mut actions = fsm.handle(Event::ManualStart);
+            actions.extend(fsm.handle(Event::TcpConnectionConfirmed));
+            actions.extend(fsm.handle(Event::BGPOpen(open)));
+            actions.extend(fsm.handle(Event::KeepAliveMsg));
+            actions
+        };
+        let mut fsm = Fsm::new(
+            PeerSessionBuilder::new().stale_time(60).build(),
+            OpenBuilder::new(4, 65000, 90, 1).capability(gr(false)).build()
+        );
+        _ = restart(&mut fsm, peer_open(true));
+        assert_eq!(fsm.state(), State::Established);
+
+        // The connection drops, the routes are kept for the peer's Restart Time
+        let actions = fsm.handle(Event::TcpConnectionFails);
+        assert!(actions.contains(&Action::MarkStale(vec![v4])));
+        assert!(!actions.contains(&Action::ReleaseResources));
+        assert!(actions.contains(&Action::StartTimer(Timer::Stale, 120)));
+        assert_eq!(fsm.state(), State::Idle);
+        assert_eq!(fsm.stale(), &[v4]);
+
+        // Back up, End-of-RIB flushes what wasn't refreshed
+        assert!(restart(&mut fsm, peer_open(true)).contains(&Action::StartTimer(Timer::Stale, 60)));
+        assert_eq!(
+            fsm.handle(Event::EndOfRib(Afi::Ipv4, Safi::Unicast)),
+            vec![Action::FlushStale(Afi::Ipv4, Safi::Unicast), Action::StopTimer(Timer::Stale), Action::StartTimer(Timer::Hold, 30)]
+        );
+        assert!(fsm.stale().is_empty());
+
+        // The peer doesn't come back in time
+        _ = fsm.handle(Event::TcpConnectionFails);
+        assert_eq!(fsm.handle(Event::StaleTimerExpires), vec![Action::FlushStale(Afi::Ipv4, Safi::Unicast)]);
+        assert_eq!(fsm.state(), State::Idle);
+
+        // The peer comes back without its forwarding state, the stale routes can't wait for End-of-RIB
+        _ = restart(&mut fsm, peer_open(true));
+        _ = fsm.handle(Event::TcpConnectionFails);
+        let actions = restart(&mut fsm, peer_open(false));
+        assert!(actions.contains(&Action::FlushStale(Afi::Ipv4, Safi::Unicast)));
+        assert!(!actions.contains(&Action::StartTimer(Timer::Stale, 60)));
+
+        // Any other way down flushes the stale routes along with the rest
+        _ = fsm.handle(Event::TcpConnectionFails);
+        _ = restart(&mut fsm, peer_open(true));
+        let actions = fsm.handle(Event::HoldTimerExpires);
+        assert!(actions.contains(&Action::FlushStale(Afi::Ipv4, Safi::Unicast)));
+        assert!(actions.contains(&Action::ReleaseResources));
+    }
+}
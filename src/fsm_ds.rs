@@ -3,18 +3,115 @@
 // state of the connection (E.g. which the BGP FSM is in an associated timers)
 // (See RFC4271; Pg. 37)
 
-use std::net::IpAddr;
+use std::{
+    cmp,
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::Rng;
+
+use crate::{
+    errors::{DecodeError, ErrorAction, NotifErrorCode, OpenMsgErrSubcode},
+    export::ExportOptions,
+    message_types::{AddPathMode, Afi, Capability, MessageType, Notification, Open, Route, Safi, BGP_VERSION},
+    policy::RouteMap,
+    router_id,
+    session_events::SessionError,
+    transport::MarkerCheck,
+};
 
 const DEFAULT_HOLD_TIME: usize = 90;
 const DEFAULT_KEEPALIVE_TIME: usize = 30;
 const DEFAULT_CONNECT_RETRY_TIME: usize = 120;
+const DEFAULT_DELAY_OPEN_TIME: usize = 5;
+const DEFAULT_IDLE_HOLD_TIME: usize = 30;
+// How long to wait for End-of-RIB from a peer that restarted gracefully before its stale routes are flushed
+const DEFAULT_STALE_TIME: usize = 360;
+// Upper bound for the IdleHoldTime as it backs off
+const MAX_IDLE_HOLD_TIME: usize = 600;
 
-// Marker trait for FsmEvents such that we can be generic
-pub(crate) trait FsmEvent {}
+// A Hold Time must be zero or at least three seconds. RFC 4271, Pg. 15
+pub(crate) fn check_hold_time(hold_time: u16) -> Result<(), NotifErrorCode> {
+    match hold_time {
+        1 | 2 => Err(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnacceptableHoldTime)),
+        _ => Ok(())
+    }
+}
+
+// Checks a received Open in the order RFC 4271 lists them (Pg. 31), returning the error to send if it
+// fails. remote_as is the AS the peer is configured with, None takes any. An unsupported version gets
+// ours in the NOTIFICATION's Data (see Notification::new).
+pub(crate) fn check_open(open: &Open, local: &Open, remote_as: Option<u32>) -> Result<(), NotifErrorCode> {
+    let err = NotifErrorCode::OpenMessageError;
+    if open.version() != BGP_VERSION {
+        return Err(err(OpenMsgErrSubcode::UnsupportedVerNum));
+    }
+    if remote_as.is_some_and(|remote_as| remote_as != open.peer_as()) {
+        return Err(err(OpenMsgErrSubcode::BadPeerAs));
+    }
+    check_hold_time(open.hold_time())?;
+    // A unicast address other than ours, which an internal peer can't share. RFC 4271, Pg. 31 and
+    // RFC 6286, Pg. 2
+    let bgp_id = Ipv4Addr::from(open.bgp_id());
+    let bad_id = !router_id::is_valid_id(bgp_id)
+        || (open.peer_as() == local.peer_as() && open.bgp_id() == local.bgp_id());
+    match bad_id {
+        true => Err(err(OpenMsgErrSubcode::BadBgpId)),
+        false => Ok(())
+    }
+}
+
+// Applies the jitter from RFC 4271, Pg. 89 to a timer value: a random factor between 0.75 and 1.0.
+// Non-zero values never jitter down to zero.
+pub(crate) fn jitter(time: usize) -> usize {
+    if time == 0 {
+        return 0;
+    }
+    let factor: f64 = rand::thread_rng().gen_range(0.75..=1.0);
+    ((time as f64 * factor) as usize).max(1)
+}
+
+// When a session's Keepalives are due: every negotiated KeepaliveTime (jittered if configured) after
+// the last KEEPALIVE or UPDATE sent. Started once the Open messages have been exchanged and stopped
+// with the session. A KeepaliveTime of zero means no Keepalives at all. RFC 4271, Section 8.2.2
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct KeepaliveScheduler {
+    // None while stopped
+    interval: Option<usize>,
+    jitter: bool
+}
+
+impl KeepaliveScheduler {
+    pub fn start(&mut self, keepalive_time: u16, jitter: bool) -> Option<usize> {
+        // Returns when the first Keepalive is due, in seconds
+        self.interval = match keepalive_time {
+            0 => None,
+            time => Some(time as usize)
+        };
+        self.jitter = jitter;
+        self.next()
+    }
+    pub fn stop(&mut self) {
+        self.interval = None;
+    }
+    pub fn is_running(&self) -> bool {
+        self.interval.is_some()
+    }
+    pub fn next(&self) -> Option<usize> {
+        // Seconds until the next Keepalive is due from now, None while stopped
+        self.interval.map(|time| match self.jitter {
+            true => jitter(time),
+            false => time
+        })
+    }
+}
 
 // Seems like an enum is a good representatin of the State for a peer. Assuming this will need to be behind 
 // some sort of lock in the multi-threaded case.
-pub(crate) enum State{
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
     Idle,
     Connect,
     Active,
@@ -27,12 +124,128 @@ pub(crate) enum State{
 pub struct BgpPeer {
     pub peer_address: IpAddr,
     pub remote_as: u16,
+    // Our address on the session, the NEXT_HOP of routes we advertise to eBGP peers. Connections to the
+    // peer are made from it and connections from the peer have to be made to it (the update source),
+    // unless it's the unspecified address.
+    pub local_address: IpAddr,
+    // The peer group the settings came from, if any
+    pub group: Option<String>,
     session: PeerSession,
+    // Activated address families, IPv4 and IPv6 unicast unless set. Each is sent as a Multiprotocol
+    // capability and the peer's routes are only taken into, and sent from, the RIBs of the ones the
+    // peer advertised as well.
+    afi_safis: Vec<(Afi, Safi)>,
+    import_policy: Option<RouteMap>,
+    export_policy: Option<RouteMap>,
+    export_options: ExportOptions,
+    // How headers from the peer are checked, on connections either side makes
+    marker_check: MarkerCheck,
+    // Signs the TCP segments of the peer's connections, both ways. RFC 2385
+    md5_password: Option<String>,
+    // How many times our AS may be in the AS_PATH of paths from the peer before they're dropped as looped,
+    // none unless set (allowas-in)
+    allowas_in: Option<u8>,
+    // The subnet a single-hop eBGP peer shares with us, the NEXT_HOP of its routes has to be in it. Unset for
+    // iBGP and multihop peers. RFC 4271, Pg. 33
+    shared_subnet: Option<Route>
 }
-// This struct currently only supports the mandatory session attributes 
-// given in RFC 4271, Pg. 37
+
+impl BgpPeer {
+    pub fn new(peer_address: IpAddr, remote_as: u16, local_address: IpAddr, session: PeerSession) -> Self {
+        Self {
+            peer_address,
+            remote_as,
+            local_address,
+            group: None,
+            session,
+            afi_safis: vec![(Afi::Ipv4, Safi::Unicast), (Afi::Ipv6, Safi::Unicast)],
+            import_policy: None,
+            export_policy: None,
+            export_options: ExportOptions::default(),
+            marker_check: MarkerCheck::default(),
+            md5_password: None,
+            allowas_in: None,
+            shared_subnet: None
+        }
+    }
+    pub fn afi_safis(mut self, afi_safis: Vec<(Afi, Safi)>) -> Self {
+        self.afi_safis = afi_safis;
+        self
+    }
+    pub fn activate(mut self, afi: Afi, safi: Safi) -> Self {
+        // Adds an address family to the ones negotiated with the peer
+        if !self.afi_safis.contains(&(afi, safi)) {
+            self.afi_safis.push((afi, safi));
+        }
+        self
+    }
+    pub fn deactivate(mut self, afi: Afi, safi: Safi) -> Self {
+        // No Multiprotocol capability is sent for the family, and no routes are exchanged for it
+        self.afi_safis.retain(|family| *family != (afi, safi));
+        self
+    }
+    pub fn import_policy(mut self, map: Option<RouteMap>) -> Self {
+        self.import_policy = map;
+        self
+    }
+    pub fn export_policy(mut self, map: Option<RouteMap>) -> Self {
+        self.export_policy = map;
+        self
+    }
+    pub fn export_options(mut self, options: ExportOptions) -> Self {
+        self.export_options = options;
+        self
+    }
+    pub fn marker_check(mut self, marker_check: MarkerCheck) -> Self {
+        self.marker_check = marker_check;
+        self
+    }
+    pub fn md5_password(mut self, password: Option<String>) -> Self {
+        self.md5_password = password;
+        self
+    }
+    pub fn allowas_in(mut self, count: Option<u8>) -> Self {
+        self.allowas_in = count;
+        self
+    }
+    pub fn shared_subnet(mut self, subnet: Option<Route>) -> Self {
+        self.shared_subnet = subnet;
+        self
+    }
+    pub(crate) fn session(&self) -> &PeerSession {
+        &self.session
+    }
+    pub(crate) fn password(&self) -> Option<&str> {
+        self.md5_password.as_deref()
+    }
+    pub(crate) fn own_as_allowed(&self) -> u8 {
+        self.allowas_in.unwrap_or(0)
+    }
+    pub(crate) fn marker_mode(&self) -> MarkerCheck {
+        self.marker_check
+    }
+    pub(crate) fn families(&self) -> &[(Afi, Safi)] {
+        &self.afi_safis
+    }
+    pub(crate) fn policies(&self) -> (Option<&RouteMap>, Option<&RouteMap>) {
+        // Import, then export
+        (self.import_policy.as_ref(), self.export_policy.as_ref())
+    }
+    pub(crate) fn export_settings(&self) -> ExportOptions {
+        self.export_options
+    }
+    pub(crate) fn subnet(&self) -> Option<&Route> {
+        self.shared_subnet.as_ref()
+    }
+    pub(crate) fn into_session(self) -> PeerSession {
+        self.session
+    }
+}
+// This struct supports the mandatory session attributes given in RFC 4271, Pg. 37
+// and the DelayOpen, PassiveTcpEstablishment and DampPeerOscillations optional attributes from Pg. 39.
 // Contains all the values related to the BGP FSM for a given peer
-pub(crate) struct PeerSession {
+#[derive(Debug)]
+pub struct PeerSession {
     state: State,
     connect_retry_ctr: usize,
     connect_retry_timer: usize,
@@ -41,9 +254,510 @@ pub(crate) struct PeerSession {
     hold_time: usize,
     keepalive_timer: usize,
     keepalive_time: usize,
+    // Wait for the peer's Open (or DelayOpenTime) before sending ours once TCP is up
+    delay_open: bool,
+    delay_open_timer: usize,
+    delay_open_time: usize,
+    // Allows a NOTIFICATION to be sent before our Open has been, e.g. while the Open is delayed
+    send_notif_without_open: bool,
+    // Never initiate the TCP connection, only accept the peer's
+    passive: bool,
+    // Keep the peer in Idle for a while after the session fails, longer for each consecutive failure
+    damp_peer_oscillations: bool,
+    idle_hold_timer: usize,
+    idle_hold_time: usize,
+    // Number of times the peer has been held in Idle, for monitoring
+    damped_ctr: usize,
+    // Jitter the KeepaliveTimer each time it is started
+    keepalive_jitter: bool,
+    // Runs while routes from a gracefully restarting peer are stale: first for the peer's Restart Time,
+    // then for StaleTime once the session is back up
+    stale_timer: usize,
+    stale_time: usize,
+    // Only present once the Open messages have been exchanged
+    session_params: Option<SessionParams>,
+    // Counters for show commands and metrics, kept across sessions
+    stats: PeerStats,
+}
+
+// Messages of each type, sent or received
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    open: u64,
+    update: u64,
+    keepalive: u64,
+    notification: u64,
+    route_refresh: u64,
+}
+
+impl MessageCounts {
+    pub fn get(&self, msg_type: MessageType) -> u64 {
+        match msg_type {
+            MessageType::Open => self.open,
+            MessageType::Update => self.update,
+            MessageType::KeepAlive => self.keepalive,
+            MessageType::Notification => self.notification,
+            MessageType::RouteRefresh => self.route_refresh
+        }
+    }
+    pub fn total(&self) -> u64 {
+        self.open + self.update + self.keepalive + self.notification + self.route_refresh
+    }
+    pub(crate) fn incr(&mut self, msg_type: MessageType, count: u64) {
+        let ctr = match msg_type {
+            MessageType::Open => &mut self.open,
+            MessageType::Update => &mut self.update,
+            MessageType::KeepAlive => &mut self.keepalive,
+            MessageType::Notification => &mut self.notification,
+            MessageType::RouteRefresh => &mut self.route_refresh
+        };
+        *ctr += count;
+    }
+    pub(crate) fn add(&mut self, other: &MessageCounts) {
+        self.open += other.open;
+        self.update += other.update;
+        self.keepalive += other.keepalive;
+        self.notification += other.notification;
+        self.route_refresh += other.route_refresh;
+    }
+}
+
+// Errors kept per peer, oldest dropped first, and how much of each one's Data is kept
+pub(crate) const ERROR_HISTORY_LEN: usize = 16;
+pub(crate) const ERROR_DATA_LEN: usize = 16;
+
+// Where a recorded error came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorSource {
+    Sent,
+    Received,
+    // A message from the peer that didn't decode, and what was done about it
+    Decode(ErrorAction)
+}
+
+// A NOTIFICATION sent or received, or a decode error, for diagnosing session flaps after the fact
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorRecord {
+    pub source: ErrorSource,
+    pub code: u8,
+    pub subcode: u8,
+    // The start of the Data field, at most ERROR_DATA_LEN bytes
+    pub data: Vec<u8>,
+    pub at: SystemTime
+}
+
+// Per peer statistics. The FSM counts the messages it sees and decides to send, state transitions and
+// NOTIFICATIONs. Updates sent and prefix counts come from the peer's task and the table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    received: MessageCounts,
+    sent: MessageCounts,
+    // Prefixes announced by the peer, and what the table made of them
+    prefixes_received: u64,
+    prefixes_accepted: u64,
+    prefixes_rejected: u64,
+    prefixes_withdrawn: u64,
+    last_notification: Option<SessionError>,
+    // The last ERROR_HISTORY_LEN errors, oldest first
+    errors: VecDeque<ErrorRecord>,
+    established_since: Option<Instant>,
+    transitions: u64,
+    // None until the first transition
+    state: Option<State>,
+}
+
+impl PeerStats {
+    pub fn received(&self) -> &MessageCounts {
+        &self.received
+    }
+    pub fn sent(&self) -> &MessageCounts {
+        &self.sent
+    }
+    pub fn updates_received(&self) -> u64 {
+        self.received.get(MessageType::Update)
+    }
+    pub fn prefixes_received(&self) -> u64 {
+        self.prefixes_received
+    }
+    pub fn prefixes_accepted(&self) -> u64 {
+        self.prefixes_accepted
+    }
+    pub fn prefixes_rejected(&self) -> u64 {
+        self.prefixes_rejected
+    }
+    pub fn prefixes_withdrawn(&self) -> u64 {
+        self.prefixes_withdrawn
+    }
+    pub fn last_notification(&self) -> Option<&SessionError> {
+        // The last NOTIFICATION sent or received
+        self.last_notification.as_ref()
+    }
+    pub fn errors(&self) -> impl Iterator<Item = &ErrorRecord> {
+        // Oldest first
+        self.errors.iter()
+    }
+    pub fn uptime(&self) -> Option<Duration> {
+        // None unless the session is Established
+        self.established_since.map(|since| since.elapsed())
+    }
+    pub fn transitions(&self) -> u64 {
+        self.transitions
+    }
+    pub fn state(&self) -> State {
+        // The state the FSM was in when the stats were taken
+        self.state.unwrap_or(State::Idle)
+    }
+    pub(crate) fn incr_received(&mut self, msg_type: MessageType) {
+        self.received.incr(msg_type, 1);
+    }
+    pub(crate) fn incr_sent(&mut self, msg_type: MessageType, count: u64) {
+        self.sent.incr(msg_type, count);
+    }
+    pub(crate) fn record_prefixes(&mut self, received: usize, withdrawn: usize) {
+        self.prefixes_received += received as u64;
+        self.prefixes_withdrawn += withdrawn as u64;
+    }
+    pub(crate) fn record_import(&mut self, accepted: usize, rejected: usize) {
+        self.prefixes_accepted += accepted as u64;
+        self.prefixes_rejected += rejected as u64;
+    }
+    pub(crate) fn set_last_notification(&mut self, error: SessionError) {
+        self.last_notification = Some(error);
+    }
+    pub(crate) fn record_error(&mut self, source: ErrorSource, notification: &Notification) {
+        if self.errors.len() == ERROR_HISTORY_LEN {
+            self.errors.pop_front();
+        }
+        let data = notification.data();
+        self.errors.push_back(ErrorRecord {
+            source,
+            code: notification.err_code(),
+            subcode: notification.err_subcode(),
+            data: data[..data.len().min(ERROR_DATA_LEN)].to_vec(),
+            at: SystemTime::now()
+        });
+    }
+    pub(crate) fn absorb(&mut self, other: &PeerStats) {
+        // Adds the counters of another connection to the peer, the loser of a collision. Its errors are
+        // merged in by time, the state and uptime stay this connection's.
+        self.received.add(&other.received);
+        self.sent.add(&other.sent);
+        self.prefixes_received += other.prefixes_received;
+        self.prefixes_accepted += other.prefixes_accepted;
+        self.prefixes_rejected += other.prefixes_rejected;
+        self.prefixes_withdrawn += other.prefixes_withdrawn;
+        self.transitions += other.transitions;
+        if self.last_notification.is_none() {
+            self.last_notification = other.last_notification.clone();
+        }
+        let mut errors: Vec<ErrorRecord> = other.errors.iter().chain(self.errors.iter()).cloned().collect();
+        errors.sort_by_key(|error| error.at);
+        let skip = errors.len().saturating_sub(ERROR_HISTORY_LEN);
+        self.errors = errors.into_iter().skip(skip).collect();
+    }
+    pub(crate) fn transition(&mut self, to: State) {
+        self.transitions += 1;
+        self.state = Some(to);
+        self.established_since = match to {
+            State::Established => Some(Instant::now()),
+            _ => None
+        };
+    }
+}
+
+// The capabilities both sides of the session agreed on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NegotiatedCapabilities {
+    afi_safis: Vec<(Afi, Safi)>,
+    route_refresh: bool,
+    four_octet_as: bool,
+    // The peer's Restart Time, if both sides advertised Graceful Restart
+    restart_time: Option<u16>,
+    // Negotiated address families the peer listed for Graceful Restart, with its Forwarding State bit
+    restart_afi_safis: Vec<(Afi, Safi, bool)>,
+    // Address families we send, and receive, multiple paths for, each with a Path Identifier. RFC 7911
+    add_path_send: Vec<(Afi, Safi)>,
+    add_path_receive: Vec<(Afi, Safi)>,
+}
+
+impl NegotiatedCapabilities {
+    pub fn new(local: &[Capability], remote: &[Capability]) -> Self {
+        // A capability is only usable if both sides advertised it.
+        // If a speaker advertises no Multiprotocol capabilities at all, IPv4 unicast is implied. RFC 4760, Pg. 9
+        let afi_safis = |caps: &[Capability]| -> Vec<(Afi, Safi)> {
+            let found: Vec<(Afi, Safi)> = caps
+                .iter()
+                .filter_map(|c| match c {
+                    Capability::Multiprotocol(afi, safi) => Some((*afi, *safi)),
+                    _ => None
+                })
+                .collect();
+            match found.is_empty() {
+                true => vec![(Afi::Ipv4, Safi::Unicast)],
+                false => found
+            }
+        };
+        let remote_afi_safis = afi_safis(remote);
+        let mut negotiated: Vec<(Afi, Safi)> = afi_safis(local)
+            .into_iter()
+            .filter(|af| remote_afi_safis.contains(af))
+            .collect();
+        negotiated.sort();
+        negotiated.dedup();
+
+        let both = |f: fn(&Capability) -> bool| local.iter().any(f) && remote.iter().any(f);
+        let graceful_restart = both(|c| matches!(c, Capability::GracefulRestart { .. }));
+        let (restart_time, restart_afi_safis) = remote
+            .iter()
+            .find_map(|c| match c {
+                Capability::GracefulRestart { restart_time, afi_safis, .. } if graceful_restart => {
+                    let afi_safis = afi_safis
+                        .iter()
+                        .filter(|(afi, safi, _)| negotiated.contains(&(*afi, *safi)))
+                        .copied()
+                        .collect();
+                    Some((Some(*restart_time), afi_safis))
+                },
+                _ => None
+            })
+            .unwrap_or((None, Vec::new()));
+        // We send paths if we said we could send and the peer said it could receive, and the other way round
+        let add_path = |caps: &[Capability]| -> Vec<(Afi, Safi, AddPathMode)> {
+            caps
+            .iter()
+            .filter_map(|c| match c {
+                Capability::AddPath(afi_safis) => Some(afi_safis.clone()),
+                _ => None
+            })
+            .flatten()
+            .filter(|(afi, safi, _)| negotiated.contains(&(*afi, *safi)))
+            .collect()
+        };
+        let (local_add_path, remote_add_path) = (add_path(local), add_path(remote));
+        let add_path_dir = |we: fn(&AddPathMode) -> bool, they: fn(&AddPathMode) -> bool| -> Vec<(Afi, Safi)> {
+            local_add_path
+            .iter()
+            .filter(|(afi, safi, mode)| {
+                we(mode) && remote_add_path.iter().any(|(r_afi, r_safi, r_mode)| (r_afi, r_safi) == (afi, safi) && they(r_mode))
+            })
+            .map(|(afi, safi, _)| (*afi, *safi))
+            .collect()
+        };
+        Self {
+            afi_safis: negotiated,
+            route_refresh: both(|c| matches!(c, Capability::RouteRefresh)),
+            four_octet_as: both(|c| matches!(c, Capability::FourOctetAs(_))),
+            restart_time,
+            restart_afi_safis,
+            add_path_send: add_path_dir(AddPathMode::sends, AddPathMode::receives),
+            add_path_receive: add_path_dir(AddPathMode::receives, AddPathMode::sends),
+        }
+    }
+    pub fn afi_safis(&self) -> &[(Afi, Safi)] {
+        self.afi_safis.as_slice()
+    }
+    pub fn supports(&self, afi: Afi, safi: Safi) -> bool {
+        self.afi_safis.contains(&(afi, safi))
+    }
+    pub fn route_refresh(&self) -> bool {
+        self.route_refresh
+    }
+    pub fn four_octet_as(&self) -> bool {
+        self.four_octet_as
+    }
+    pub fn restart_time(&self) -> Option<u16> {
+        self.restart_time
+    }
+    pub fn restart_afi_safis(&self) -> &[(Afi, Safi, bool)] {
+        self.restart_afi_safis.as_slice()
+    }
+    pub fn add_path_send(&self, afi: Afi, safi: Safi) -> bool {
+        // Our Updates for the address family carry Path Identifiers
+        self.add_path_send.contains(&(afi, safi))
+    }
+    pub fn add_path_receive(&self, afi: Afi, safi: Safi) -> bool {
+        // The peer's Updates for the address family carry Path Identifiers
+        self.add_path_receive.contains(&(afi, safi))
+    }
+    pub fn forwarding_preserved(&self) -> Vec<(Afi, Safi)> {
+        // Address families the peer kept forwarding through its restart, their stale routes can wait for End-of-RIB
+        self.restart_afi_safis
+        .iter()
+        .filter(|(_, _, forwarding)| *forwarding)
+        .map(|(afi, safi, _)| (*afi, *safi))
+        .collect()
+    }
+}
+
+// Immutable snapshot of everything negotiated during the Open exchange. This is the single
+// source of truth for the session's parameters, it is meant to be cloned wherever they are needed
+// (decoding, events, show output) instead of copying individual fields around.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SessionParams {
+    hold_time: u16,
+    keepalive_time: u16,
+    capabilities: NegotiatedCapabilities,
+    remote_id: Ipv4Addr,
+    remote_as: u32,
+}
+
+impl SessionParams {
+    pub fn negotiate(local: &Open, remote: &Open) -> Self {
+        // Builds the snapshot from the Open we sent and the Open we received.
+        // Hold time is the smaller of the two, keepalive is a third of that. RFC 4271, Pg. 14
+        let hold_time = cmp::min(local.hold_time(), remote.hold_time());
+        let remote_caps = remote.capabilities();
+        Self {
+            hold_time,
+            keepalive_time: hold_time / 3,
+            capabilities: NegotiatedCapabilities::new(&local.capabilities(), &remote_caps),
+            remote_id: Ipv4Addr::from(remote.bgp_id()),
+            remote_as: remote.peer_as(),
+        }
+    }
+    pub fn limit_keepalive_time(&mut self, keepalive_time: usize) {
+        // Sends Keepalives more often than a third of the Hold Time if configured to, but never less often
+        if keepalive_time != 0 && self.hold_time != 0 {
+            self.keepalive_time = cmp::min(self.keepalive_time as usize, keepalive_time).max(1) as u16;
+        }
+    }
+    pub fn hold_time(&self) -> u16 {
+        self.hold_time
+    }
+    pub fn keepalive_time(&self) -> u16 {
+        self.keepalive_time
+    }
+    pub fn capabilities(&self) -> &NegotiatedCapabilities {
+        &self.capabilities
+    }
+    pub fn four_octet_as(&self) -> bool {
+        self.capabilities.four_octet_as()
+    }
+    pub fn remote_id(&self) -> Ipv4Addr {
+        self.remote_id
+    }
+    pub fn remote_as(&self) -> u32 {
+        self.remote_as
+    }
 }
 
 impl PeerSession {
+    pub(crate) fn state(&self) -> State {
+        self.state
+    }
+    pub(crate) fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+    pub(crate) fn conn_retry_ctr(&self) -> usize {
+        self.connect_retry_ctr
+    }
+    pub(crate) fn incr_conn_retry_ctr(&mut self) {
+        self.connect_retry_ctr += 1;
+    }
+    pub(crate) fn conn_retry_time(&self) -> usize {
+        self.connect_retry_time
+    }
+    pub(crate) fn hold_time(&self) -> usize {
+        self.hold_time
+    }
+    pub(crate) fn keep_time(&self) -> usize {
+        self.keepalive_time
+    }
+    // The timer fields hold the value a running timer was started with, 0 means the timer is stopped.
+    pub(crate) fn conn_retry_timer(&self) -> usize {
+        self.connect_retry_timer
+    }
+    pub(crate) fn hold_timer(&self) -> usize {
+        self.hold_timer
+    }
+    pub(crate) fn keep_timer(&self) -> usize {
+        self.keepalive_timer
+    }
+    pub(crate) fn delay_open(&self) -> bool {
+        self.delay_open
+    }
+    pub(crate) fn delay_open_time(&self) -> usize {
+        self.delay_open_time
+    }
+    pub(crate) fn delay_open_timer(&self) -> usize {
+        self.delay_open_timer
+    }
+    pub(crate) fn send_notif_without_open(&self) -> bool {
+        self.send_notif_without_open
+    }
+    pub(crate) fn keepalive_jitter(&self) -> bool {
+        self.keepalive_jitter
+    }
+    pub(crate) fn passive(&self) -> bool {
+        self.passive
+    }
+    pub(crate) fn damp_peer_oscillations(&self) -> bool {
+        self.damp_peer_oscillations
+    }
+    pub(crate) fn idle_hold_timer(&self) -> usize {
+        self.idle_hold_timer
+    }
+    pub(crate) fn damped_ctr(&self) -> usize {
+        self.damped_ctr
+    }
+    pub(crate) fn idle_hold_time(&self) -> usize {
+        // The IdleHoldTime doubles with each consecutive failure counted by the ConnectRetryCounter
+        let backoff = self.connect_retry_ctr.saturating_sub(1).min(usize::BITS as usize - 1) as u32;
+        self.idle_hold_time
+        .saturating_mul(2usize.saturating_pow(backoff))
+        .min(MAX_IDLE_HOLD_TIME.max(self.idle_hold_time))
+    }
+    pub(crate) fn start_idle_hold_timer(&mut self) {
+        self.idle_hold_timer = self.idle_hold_time();
+        self.damped_ctr += 1;
+    }
+    pub(crate) fn reset_idle_hold_timer(&mut self) {
+        self.idle_hold_timer = 0;
+    }
+    pub(crate) fn stale_timer(&self) -> usize {
+        self.stale_timer
+    }
+    pub(crate) fn stale_time(&self) -> usize {
+        self.stale_time
+    }
+    pub(crate) fn start_stale_timer(&mut self, time: usize) {
+        self.stale_timer = time;
+    }
+    pub(crate) fn reset_stale_timer(&mut self) {
+        self.stale_timer = 0;
+    }
+    pub(crate) fn start_delay_open_timer(&mut self) {
+        self.delay_open_timer = self.delay_open_time;
+    }
+    pub(crate) fn reset_delay_open_timer(&mut self) {
+        self.delay_open_timer = 0;
+    }
+    pub(crate) fn start_conn_retry_timer(&mut self) {
+        self.connect_retry_timer = self.connect_retry_time;
+    }
+    pub(crate) fn start_hold_timer(&mut self, time: usize) {
+        self.hold_timer = time;
+    }
+    pub(crate) fn start_keep_timer(&mut self, time: usize) {
+        self.keepalive_timer = time;
+    }
+    pub(crate) fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+    pub(crate) fn stats_mut(&mut self) -> &mut PeerStats {
+        &mut self.stats
+    }
+    pub(crate) fn session_params(&self) -> Option<&SessionParams> {
+        self.session_params.as_ref()
+    }
+    pub(crate) fn set_session_params(&mut self, params: SessionParams) {
+        // Called once the Open exchange completes
+        self.session_params = Some(params);
+    }
+    pub(crate) fn clear_session_params(&mut self) {
+        // Called when the session goes down
+        self.session_params = None;
+    }
     pub(crate) fn reset_conn_retry_ctr(&mut self) {
         self.connect_retry_ctr = 0;
     }
@@ -56,8 +770,49 @@ impl PeerSession {
     pub(crate) fn reset_keep_timer(&mut self) {
         self.keepalive_timer = 0;
     }
+    pub(crate) fn reconfigure(&mut self, new: &PeerSession) {
+        // Takes the settings of new that can change without a new session. Running timers keep
+        // the value they were started with, the new ones are used the next time they start.
+        // HoldTime and KeepaliveTime go into the Open, changing them takes a reset.
+        self.connect_retry_time = new.connect_retry_time;
+        self.delay_open = new.delay_open;
+        self.delay_open_time = new.delay_open_time;
+        self.passive = new.passive;
+        self.damp_peer_oscillations = new.damp_peer_oscillations;
+        self.idle_hold_time = new.idle_hold_time;
+        self.stale_time = new.stale_time;
+    }
+    pub(crate) fn candidate(&self) -> PeerSession {
+        // The same settings for a second connection to the peer, see fsm::PeerConnections. It starts in
+        // Idle with nothing running and its own statistics.
+        PeerSession {
+            state: State::Idle,
+            connect_retry_ctr: self.connect_retry_ctr,
+            connect_retry_timer: 0,
+            connect_retry_time: self.connect_retry_time,
+            hold_timer: 0,
+            hold_time: self.hold_time,
+            keepalive_timer: 0,
+            keepalive_time: self.keepalive_time,
+            delay_open: self.delay_open,
+            delay_open_timer: 0,
+            delay_open_time: self.delay_open_time,
+            send_notif_without_open: self.send_notif_without_open,
+            passive: self.passive,
+            damp_peer_oscillations: self.damp_peer_oscillations,
+            idle_hold_timer: 0,
+            idle_hold_time: self.idle_hold_time,
+            damped_ctr: self.damped_ctr,
+            keepalive_jitter: self.keepalive_jitter,
+            stale_timer: 0,
+            stale_time: self.stale_time,
+            session_params: None,
+            stats: PeerStats::default()
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct PeerSessionBuilder {
     state: State,
     connect_retry_ctr: usize,
@@ -67,6 +822,14 @@ pub struct PeerSessionBuilder {
     hold_time: usize,
     keepalive_timer: usize,
     keepalive_time: usize,
+    delay_open: bool,
+    delay_open_time: usize,
+    send_notif_without_open: bool,
+    passive: bool,
+    damp_peer_oscillations: bool,
+    idle_hold_time: usize,
+    keepalive_jitter: bool,
+    stale_time: usize,
 }
 
 // See RFC 4721, Pg. 90 for suggested default timer thresholds.
@@ -81,6 +844,14 @@ impl PeerSessionBuilder {
             hold_time: DEFAULT_HOLD_TIME,
             keepalive_timer: 0,
             keepalive_time: DEFAULT_KEEPALIVE_TIME,
+            delay_open: false,
+            delay_open_time: DEFAULT_DELAY_OPEN_TIME,
+            send_notif_without_open: false,
+            passive: false,
+            damp_peer_oscillations: false,
+            idle_hold_time: DEFAULT_IDLE_HOLD_TIME,
+            keepalive_jitter: false,
+            stale_time: DEFAULT_STALE_TIME,
         }
     }
     pub fn conn_retry_time(mut self, time: usize) -> Self {
@@ -98,6 +869,37 @@ impl PeerSessionBuilder {
         self.keepalive_time = time;
         self
     }
+    pub fn delay_open(mut self, time: usize) -> Self {
+        // Enables DelayOpen with the given DelayOpenTime
+        self.delay_open = true;
+        self.delay_open_time = time;
+        self
+    }
+    pub fn send_notif_without_open(mut self) -> Self {
+        // Build value for SendNOTIFICATIONwithoutOPEN
+        self.send_notif_without_open = true;
+        self
+    }
+    pub fn keepalive_jitter(mut self) -> Self {
+        self.keepalive_jitter = true;
+        self
+    }
+    pub fn passive(mut self) -> Self {
+        // Build value for PassiveTcpEstablishment
+        self.passive = true;
+        self
+    }
+    pub fn damp_peer_oscillations(mut self, time: usize) -> Self {
+        // Enables DampPeerOscillations with the given initial IdleHoldTime
+        self.damp_peer_oscillations = true;
+        self.idle_hold_time = time;
+        self
+    }
+    pub fn stale_time(mut self, time: usize) -> Self {
+        // Build value for how long stale routes wait for End-of-RIB after a graceful restart
+        self.stale_time = time;
+        self
+    }
     pub fn build(mut self) -> PeerSession {
         PeerSession {
             state: self.state,
@@ -108,31 +910,87 @@ impl PeerSessionBuilder {
             hold_time: self.hold_time,
             keepalive_timer: self.keepalive_timer,
             keepalive_time: self.keepalive_time,
+            delay_open: self.delay_open,
+            delay_open_timer: 0,
+            delay_open_time: self.delay_open_time,
+            send_notif_without_open: self.send_notif_without_open,
+            passive: self.passive,
+            damp_peer_oscillations: self.damp_peer_oscillations,
+            idle_hold_timer: 0,
+            idle_hold_time: self.idle_hold_time,
+            damped_ctr: 0,
+            keepalive_jitter: self.keepalive_jitter,
+            stale_timer: 0,
+            stale_time: self.stale_time,
+            session_params: None,
+            stats: PeerStats::default(),
         }
     }
 }
 
-// Now we'll define the mandatory FSM input events given in RFC 4271, Pg. 43
-struct ManualStart;
-struct ManualStop;
-struct ConnectRetryTimerExpires;
-struct HoldTimerExpires;
-struct KeepaliveTimerExpires;
-struct TcpCrAcked;
-struct TcpConnectionConfirmed;
-struct TcpConnectionFails;
-struct BGPOpen;
-struct BGPHeaderErr;
-struct BGPOpenMsgErr;
-struct NotifMsgVerErr;
-struct NotifMsg;
-struct KeepAliveMsg;
-struct UpdateMsg;
-struct UpdateMsgErr;
+impl Default for PeerSessionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Now we'll define the mandatory FSM input events given in RFC 4271, Pg. 43, along with
+// the optional AutomaticStart, ManualStart_with_PassiveTcpEstablishment, AutomaticStart_with_PassiveTcpEstablishment,
+// AutomaticStop (Pg. 44), DelayOpenTimer_Expires, IdleHoldTimer_Expires (Pg. 45) and OpenCollisionDump (Pg. 50).
+// StaleTimerExpires and EndOfRib drive Graceful Restart (RFC 4724).
+// Events for messages that failed to decode carry the error to send in the NOTIFICATION.
+#[derive(Debug)]
+pub(crate) enum Event {
+    ManualStart,
+    ManualStartPassive,
+    ManualStop,
+    AutomaticStart,
+    AutomaticStartPassive,
+    AutomaticStop,
+    ConnectRetryTimerExpires,
+    HoldTimerExpires,
+    KeepaliveTimerExpires,
+    DelayOpenTimerExpires,
+    IdleHoldTimerExpires,
+    StaleTimerExpires,
+    TcpCrAcked,
+    TcpConnectionConfirmed,
+    TcpConnectionFails,
+    BGPOpen(Open),
+    BGPHeaderErr(NotifErrorCode),
+    BGPOpenMsgErr(NotifErrorCode),
+    NotifMsgVerErr,
+    NotifMsg(Notification),
+    KeepAliveMsg,
+    UpdateMsg,
+    // An Update that is the End-of-RIB marker for the address family
+    EndOfRib(Afi, Safi),
+    // Usually only errors that reset the session (see ErrorAction), the rest are dealt with as the UPDATE
+    // is decoded and what's left of it arrives as UpdateMsg
+    UpdateMsgErr(DecodeError),
+    // A ROUTE-REFRESH for an address family we know. RFC 2918
+    RouteRefreshMsg(Afi, Safi),
+    OpenCollisionDump
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::message_types::OpenBuilder;
+
+    #[test]
+    fn keepalive_scheduler() {
+        let mut keepalives = KeepaliveScheduler::default();
+        assert_eq!((keepalives.is_running(), keepalives.next()), (false, None));
+        assert_eq!(keepalives.start(30, false), Some(30));
+        assert_eq!(keepalives.next(), Some(30));
+        assert!(keepalives.start(30, true).is_some_and(|secs| (22..=30).contains(&secs)));
+        keepalives.stop();
+        assert_eq!(keepalives.next(), None);
+        // No Keepalives without a KeepaliveTime
+        assert_eq!(keepalives.start(0, false), None);
+        assert!(!keepalives.is_running());
+    }
 
     #[test]
     fn build_peer_default() {
@@ -140,6 +998,30 @@ mod tests {
         assert_eq!(peer_session.connect_retry_time, DEFAULT_CONNECT_RETRY_TIME);
         assert_eq!(peer_session.hold_time, DEFAULT_HOLD_TIME);
         assert_eq!(peer_session.keepalive_time, DEFAULT_KEEPALIVE_TIME);
+        assert!(!peer_session.delay_open());
+        assert!(!peer_session.send_notif_without_open());
+        assert!(!peer_session.passive());
+    }
+    #[test]
+    fn peer_idle_hold_backoff() {
+        let mut peer_session = PeerSessionBuilder::new().damp_peer_oscillations(10).build();
+        assert!(peer_session.damp_peer_oscillations());
+        assert_eq!(peer_session.idle_hold_time(), 10);
+        peer_session.connect_retry_ctr = 3;
+        assert_eq!(peer_session.idle_hold_time(), 40);
+        peer_session.connect_retry_ctr = 1000;
+        assert_eq!(peer_session.idle_hold_time(), MAX_IDLE_HOLD_TIME);
+        peer_session.start_idle_hold_timer();
+        assert_eq!(peer_session.idle_hold_timer(), MAX_IDLE_HOLD_TIME);
+        assert_eq!(peer_session.damped_ctr(), 1);
+    }
+    #[test]
+    fn build_peer_delay_open() {
+        let peer_session = PeerSessionBuilder::new().delay_open(10).send_notif_without_open().build();
+        assert!(peer_session.delay_open());
+        assert_eq!(peer_session.delay_open_time(), 10);
+        assert_eq!(peer_session.delay_open_timer(), 0);
+        assert!(peer_session.send_notif_without_open());
     }
     #[test]
     fn build_peer_chg_keep() {
@@ -201,6 +1083,145 @@ mod tests {
         peer_session.reset_keep_timer();
         assert_eq!(peer_session.keepalive_timer, 0);
     }
+    #[test]
+    fn session_params_negotiate() {
+        let local = OpenBuilder::new(4, 65000, 180, 1)
+            .capability(Capability::Multiprotocol(Afi::Ipv4, Safi::Unicast))
+            .capability(Capability::Multiprotocol(Afi::Ipv6, Safi::Unicast))
+            .capability(Capability::RouteRefresh)
+            .capability(Capability::FourOctetAs(65000))
+            .build();
+        let remote = OpenBuilder::new(4, 23456, 90, u32::from(Ipv4Addr::new(10, 0, 0, 1)))
+            .capability(Capability::Multiprotocol(Afi::Ipv6, Safi::Unicast))
+            .capability(Capability::FourOctetAs(4200000000))
+            .build();
+        let params = SessionParams::negotiate(&local, &remote);
+
+        assert_eq!(params.hold_time(), 90);
+        assert_eq!(params.keepalive_time(), 30);
+        assert_eq!(params.remote_id(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(params.remote_as(), 4200000000);
+        assert!(params.four_octet_as());
+        assert!(!params.capabilities().route_refresh());
+        assert_eq!(params.capabilities().afi_safis(), &[(Afi::Ipv6, Safi::Unicast)]);
+    }
+    #[test]
+    fn session_params_add_path() {
+        let local = OpenBuilder::new(4, 65000, 180, 1)
+            .capability(Capability::Multiprotocol(Afi::Ipv4, Safi::Unicast))
+            .capability(Capability::Multiprotocol(Afi::Ipv6, Safi::Unicast))
+            .capability(Capability::AddPath(vec![
+                (Afi::Ipv4, Safi::Unicast, AddPathMode::Both),
+                (Afi::Ipv6, Safi::Unicast, AddPathMode::Send)
+            ]))
+            .build();
+        let remote = OpenBuilder::new(4, 65001, 180, 2)
+            .capability(Capability::Multiprotocol(Afi::Ipv4, Safi::Unicast))
+            .capability(Capability::Multiprotocol(Afi::Ipv6, Safi::Unicast))
+            .capability(Capability::AddPath(vec![
+                (Afi::Ipv4, Safi::Unicast, AddPathMode::Send),
+                (Afi::Ipv6, Safi::Unicast, AddPathMode::Send)
+            ]))
+            .build();
+        let caps = SessionParams::negotiate(&local, &remote).capabilities().clone();
+        // The peer only sends, and only receives from nobody for IPv6
+        assert!(!caps.add_path_send(Afi::Ipv4, Safi::Unicast));
+        assert!(caps.add_path_receive(Afi::Ipv4, Safi::Unicast));
+        assert!(!caps.add_path_send(Afi::Ipv6, Safi::Unicast));
+        assert!(!caps.add_path_receive(Afi::Ipv6, Safi::Unicast));
+    }
+    #[test]
+    fn check_open_errors() {
+        let local = OpenBuilder::new(4, 65000, 90, 0x0A000001).build();
+        let err = |subcode| Err(NotifErrorCode::OpenMessageError(subcode));
+        assert_eq!(check_open(&OpenBuilder::new(4, 65001, 90, 0x0A000002).build(), &local, Some(65001)), Ok(()));
+        assert_eq!(check_open(&OpenBuilder::new(3, 65001, 90, 0x0A000002).build(), &local, Some(65001)), err(OpenMsgErrSubcode::UnsupportedVerNum));
+        assert_eq!(check_open(&OpenBuilder::new(4, 65002, 90, 0x0A000002).build(), &local, Some(65001)), err(OpenMsgErrSubcode::BadPeerAs));
+        assert_eq!(check_open(&OpenBuilder::new(4, 65002, 90, 0x0A000002).build(), &local, None), Ok(()));
+        assert_eq!(check_open(&OpenBuilder::new(4, 65001, 2, 0x0A000002).build(), &local, Some(65001)), err(OpenMsgErrSubcode::UnacceptableHoldTime));
+        // The version is checked first
+        assert_eq!(check_open(&OpenBuilder::new(5, 65002, 2, 0).build(), &local, Some(65001)), err(OpenMsgErrSubcode::UnsupportedVerNum));
+
+        // A 4-octet AS is matched against the capability
+        let open = OpenBuilder::new(4, 23456, 90, 0x0A000002).capability(Capability::FourOctetAs(4200000000)).build();
+        assert_eq!(check_open(&open, &local, Some(4200000000)), Ok(()));
+        assert_eq!(check_open(&open, &local, Some(23456)), err(OpenMsgErrSubcode::BadPeerAs));
+
+        for bgp_id in [0, 0xE0000001, 0xFFFFFFFF] {
+            assert_eq!(check_open(&OpenBuilder::new(4, 65001, 90, bgp_id).build(), &local, None), err(OpenMsgErrSubcode::BadBgpId));
+        }
+        // Our own identifier is only taken from an external peer
+        assert_eq!(check_open(&OpenBuilder::new(4, 65000, 90, 0x0A000001).build(), &local, None), err(OpenMsgErrSubcode::BadBgpId));
+        assert_eq!(check_open(&OpenBuilder::new(4, 65001, 90, 0x0A000001).build(), &local, None), Ok(()));
+    }
+    #[test]
+    fn session_params_hold_time() {
+        assert!(check_hold_time(0).is_ok());
+        assert!(check_hold_time(3).is_ok());
+        assert_eq!(
+            check_hold_time(2),
+            Err(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnacceptableHoldTime))
+        );
+        let local = OpenBuilder::new(4, 65000, 90, 1).build();
+        let remote = OpenBuilder::new(4, 65001, 180, 2).build();
+        let mut params = SessionParams::negotiate(&local, &remote);
+        params.limit_keepalive_time(60);
+        assert_eq!(params.keepalive_time(), 30);
+        params.limit_keepalive_time(10);
+        assert_eq!(params.keepalive_time(), 10);
+
+        assert_eq!(jitter(0), 0);
+        assert!((0..100).map(|_| jitter(30)).all(|time| (22..=30).contains(&time)));
+    }
+    #[test]
+    fn session_params_graceful_restart() {
+        let gr = |afi_safis: Vec<(Afi, Safi, bool)>| Capability::GracefulRestart {
+            restart_state: false,
+            restart_time: 120,
+            afi_safis
+        };
+        let local = OpenBuilder::new(4, 65000, 90, 1).capability(gr(Vec::new())).build();
+        // IPv6 wasn't negotiated, so the peer's IPv6 entry is of no use
+        let remote = OpenBuilder::new(4, 65001, 90, 2)
+            .capability(gr(vec![(Afi::Ipv4, Safi::Unicast, true), (Afi::Ipv6, Safi::Unicast, true)]))
+            .build();
+        let params = SessionParams::negotiate(&local, &remote);
+        assert_eq!(params.capabilities().restart_time(), Some(120));
+        assert_eq!(params.capabilities().forwarding_preserved(), vec![(Afi::Ipv4, Safi::Unicast)]);
+
+        // Only the peer advertising it isn't enough
+        let params = SessionParams::negotiate(&OpenBuilder::new(4, 65000, 90, 1).build(), &remote);
+        assert_eq!(params.capabilities().restart_time(), None);
+        assert!(params.capabilities().restart_afi_safis().is_empty());
+    }
+    #[test]
+    fn session_params_implied_ipv4() {
+        // Neither side advertises capabilities; IPv4 unicast and 2 byte ASes only.
+        let local = OpenBuilder::new(4, 65000, 180, 1).build();
+        let remote = OpenBuilder::new(4, 65001, 180, 2).build();
+        let params = SessionParams::negotiate(&local, &remote);
 
+        assert_eq!(params.remote_as(), 65001);
+        assert!(!params.four_octet_as());
+        assert!(params.capabilities().supports(Afi::Ipv4, Safi::Unicast));
 
+        let mut peer_session = PeerSessionBuilder::new().build();
+        assert!(peer_session.session_params().is_none());
+        peer_session.set_session_params(params.clone());
+        assert_eq!(peer_session.session_params(), Some(&params));
+        peer_session.clear_session_params();
+        assert!(peer_session.session_params().is_none());
+    }
+
+    #[test]
+    fn peer_session_reconfigure() {
+        let mut session = PeerSessionBuilder::new().build();
+        session.start_conn_retry_timer();
+        let new = PeerSessionBuilder::new().conn_retry_time(5).hold_time(30).passive().build();
+        session.reconfigure(&new);
+        // The running timer keeps its value, HoldTime needs a new session
+        assert_eq!((session.conn_retry_time(), session.conn_retry_timer()), (5, DEFAULT_CONNECT_RETRY_TIME));
+        assert_eq!(session.hold_time(), DEFAULT_HOLD_TIME);
+        assert!(session.passive());
+    }
 }
\ No newline at end of file
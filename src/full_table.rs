@@ -0,0 +1,341 @@
+// Synthetic full tables for load testing BgpTable and the encoder at Internet scale. FullTableGenerator
+// draws unique prefixes with the length mix of the global table (mostly /24s in v4, /48s and /32s in v6)
+// and spreads them over a pool of attribute sets, skewed so that a few sets carry many prefixes and most
+// carry a handful, as the prefixes of one origin AS share attributes in a real feed. AS_PATH lengths
+// follow a weighted distribution, the sets also vary in ORIGIN, MED, COMMUNITIES and the occasional
+// aggregate. The table is what a single eBGP peer would send, ready to walk into a BgpTable as
+// ReceivedRoutes, to inject into a Speaker or to pack into Updates as Nlri. Setting a seed makes the table reproducible.
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+
+use crate::{
+    comms::{ReceivedRoutes, RouteInjection, RouteInjectionBuilder},
+    message_types::{Afi, Nlri, Route, Safi},
+    path_attrs::*,
+    table::RouteSource,
+};
+
+// Prefix length mixes of the global tables, in prefixes per thousand
+const V4_LENGTHS: [(u8, u32); 14] = [
+    (8, 1), (12, 2), (13, 3), (14, 6), (15, 10), (16, 20), (17, 12),
+    (18, 20), (19, 35), (20, 45), (21, 45), (22, 120), (23, 100), (24, 581)
+];
+const V6_LENGTHS: [(u8, u32); 8] = [(29, 10), (32, 230), (36, 30), (40, 50), (44, 80), (46, 20), (47, 10), (48, 570)];
+// AS_PATH lengths seen from a well connected peer, in paths per hundred
+const AS_PATH_LENGTHS: [(u8, u32); 9] = [(1, 2), (2, 12), (3, 30), (4, 27), (5, 15), (6, 7), (7, 4), (8, 2), (10, 1)];
+// Public 16 bit ASNs
+const MAX_PUBLIC_AS: u16 = 64495;
+
+#[derive(Clone, Debug)]
+pub struct FullTableGenerator {
+    prefixes_v4: usize,
+    prefixes_v6: usize,
+    attribute_sets: usize,
+    as_path_lengths: Vec<(u8, u32)>,
+    max_communities: usize,
+    peer_as: u16,
+    next_hop_v4: Ipv4Addr,
+    next_hop_v6: Ipv6Addr,
+    seed: Option<u64>
+}
+
+impl FullTableGenerator {
+    pub fn new() -> Self {
+        // Roughly today's Internet: ~950k v4 and ~200k v6 prefixes
+        Self {
+            prefixes_v4: 950_000,
+            prefixes_v6: 200_000,
+            attribute_sets: 50_000,
+            as_path_lengths: AS_PATH_LENGTHS.to_vec(),
+            max_communities: 8,
+            peer_as: 65001,
+            next_hop_v4: Ipv4Addr::new(192, 0, 2, 1),
+            next_hop_v6: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+            seed: None
+        }
+    }
+    pub fn prefixes_v4(mut self, count: usize) -> Self {
+        self.prefixes_v4 = count;
+        self
+    }
+    pub fn prefixes_v6(mut self, count: usize) -> Self {
+        self.prefixes_v6 = count;
+        self
+    }
+    pub fn attribute_sets(mut self, count: usize) -> Self {
+        // How many attribute sets each family's prefixes are spread over, at least one
+        self.attribute_sets = count.max(1);
+        self
+    }
+    pub fn path_lengths(mut self, weights: Vec<(u8, u32)>) -> Self {
+        // AS_PATH lengths (the peer's AS included) and their relative weights. Zero lengths are ignored.
+        self.as_path_lengths = weights;
+        self
+    }
+    pub fn max_communities(mut self, count: usize) -> Self {
+        self.max_communities = count;
+        self
+    }
+    pub fn peer_as(mut self, asn: u16) -> Self {
+        self.peer_as = asn;
+        self
+    }
+    pub fn next_hop(mut self, next_hop: IpAddr) -> Self {
+        // The next hop of the family the address belongs to
+        match next_hop {
+            IpAddr::V4(next_hop) => self.next_hop_v4 = next_hop,
+            IpAddr::V6(next_hop) => self.next_hop_v6 = next_hop
+        }
+        self
+    }
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+    pub fn generate(&self) -> FullTable {
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy()
+        };
+        let mut groups = self.family(&mut rng, self.prefixes_v4, IpAddr::V4(self.next_hop_v4));
+        groups.extend(self.family(&mut rng, self.prefixes_v6, IpAddr::V6(self.next_hop_v6)));
+        FullTable { peer_as: self.peer_as, groups }
+    }
+    fn family(&self, rng: &mut StdRng, count: usize, next_hop: IpAddr) -> Vec<(Vec<PathAttr>, Vec<Route>)> {
+        // The family's prefixes grouped by attribute set, sets left without prefixes dropped
+        let sets = self.attribute_sets.min(count);
+        let mut groups: Vec<(Vec<PathAttr>, Vec<Route>)> = (0..sets)
+            .map(|_| (self.path_attrs(rng, next_hop), Vec::new()))
+            .collect();
+        for route in prefixes(rng, count, next_hop.is_ipv4()) {
+            // Squaring skews the draw towards the first sets
+            let pick: f64 = rng.gen();
+            let set = ((pick * pick * sets as f64) as usize).min(sets - 1);
+            groups[set].1.push(route);
+        }
+        groups.retain(|(_, routes)| !routes.is_empty());
+        groups
+    }
+    fn path_attrs(&self, rng: &mut StdRng, next_hop: IpAddr) -> Vec<PathAttr> {
+        let origin = match rng.gen_range(0..100) {
+            0..=84 => OriginValue::Igp,
+            85..=97 => OriginValue::Incomplete,
+            _ => OriginValue::Egp
+        };
+        let len = match WeightedIndex::new(self.as_path_lengths.iter().map(|(len, weight)| (*len > 0) as u32 * weight)) {
+            Ok(lengths) => self.as_path_lengths[lengths.sample(rng)].0,
+            Err(_) => 1
+        };
+        let mut ases = vec![self.peer_as];
+        ases.extend((1..len).map(|_| rng.gen_range(1..=MAX_PUBLIC_AS)));
+        let origin_as = *ases.last().unwrap();
+
+        let mut pas = vec![
+            PathAttrBuilder::<Origin>::new().origin(origin).build().unwrap(),
+            PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(ases)]).build().unwrap(),
+            PathAttrBuilder::<NextHop>::new().next_hop(next_hop).build().unwrap()
+        ];
+        if rng.gen_bool(0.3) {
+            pas.push(PathAttrBuilder::<Med>::new().metric(rng.gen_range(0..1000)).build().unwrap());
+        }
+        let communities = rng.gen_range(0..=self.max_communities);
+        if communities > 0 {
+            let communities = (0..communities)
+                .map(|_| (self.peer_as as u32) << 16 | rng.gen_range(0..1000))
+                .collect();
+            pas.push(PathAttrBuilder::<Communities>::new().communities(communities).build().unwrap());
+        }
+        if rng.gen_bool(0.05) {
+            let aggregator = Ipv4Addr::from(rng.gen::<u32>());
+            pas.push(PathAttrBuilder::<AtomicAggregate>::new().build().unwrap());
+            pas.push(PathAttrBuilder::<Aggregator>::new().aggregator(origin_as, aggregator).build().unwrap());
+        }
+        canonicalize_attrs(pas)
+    }
+}
+
+impl Default for FullTableGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn prefixes(rng: &mut StdRng, count: usize, v4: bool) -> Vec<Route> {
+    // Unique prefixes with the family's length mix, host bits cleared. The count has to fit in the
+    // family's address space, which every realistic count does.
+    let mix: &[(u8, u32)] = match v4 {
+        true => &V4_LENGTHS,
+        false => &V6_LENGTHS
+    };
+    let lengths = WeightedIndex::new(mix.iter().map(|(_, weight)| *weight)).unwrap();
+    let mut seen: HashSet<Route> = HashSet::with_capacity(count);
+    let mut routes = Vec::with_capacity(count);
+    while routes.len() < count {
+        let len = mix[lengths.sample(rng)].0;
+        let prefix = match v4 {
+            // Unicast space, 1.0.0.0 through 223.255.255.255
+            true => {
+                let addr = rng.gen_range(0x0100_0000..0xe000_0000u32);
+                IpAddr::V4(Ipv4Addr::from(addr & (u32::MAX << (32 - len))))
+            },
+            // Global unicast, 2000::/3
+            false => {
+                let addr = (0x2000u128 << 112) | (rng.gen::<u128>() >> 3);
+                IpAddr::V6(Ipv6Addr::from(addr & (u128::MAX << (128 - len))))
+            }
+        };
+        let route = Route::new(len, prefix);
+        if seen.insert(route.clone()) {
+            routes.push(route);
+        }
+    }
+    routes
+}
+
+// A generated table, as (attribute set, prefixes) groups. A group's prefixes are all of one family.
+#[derive(Clone, Debug)]
+pub struct FullTable {
+    peer_as: u16,
+    groups: Vec<(Vec<PathAttr>, Vec<Route>)>
+}
+
+impl FullTable {
+    pub fn groups(&self) -> &[(Vec<PathAttr>, Vec<Route>)] {
+        self.groups.as_slice()
+    }
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.groups.iter().flat_map(|(_, routes)| routes.iter())
+    }
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|(_, routes)| routes.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub(crate) fn received_routes(&self, peer_id: Ipv4Addr, peer_addr: IpAddr) -> Vec<ReceivedRoutes> {
+        // One payload per group, as received from an eBGP peer with the given identity
+        self.groups
+        .iter()
+        .map(|(pas, routes)| {
+            let afi = routes[0].afi();
+            let mut payload = ReceivedRoutes::new(
+                peer_id,
+                peer_addr,
+                self.peer_as,
+                None,
+                1,
+                OriginValue::Igp,
+                0,
+                RouteSource::Ebgp,
+                0,
+                pas.clone(),
+                Some(routes.clone()),
+                None,
+                afi,
+                Safi::Unicast
+            );
+            payload.sync_decision_data();
+            payload
+        })
+        .collect()
+    }
+    pub fn injections(&self, source: IpAddr, router_id: Ipv4Addr) -> Vec<RouteInjection> {
+        // One injection per group, for Speaker::inject as if the peer at source had sent the table
+        self.groups
+        .iter()
+        .map(|(pas, routes)| {
+            RouteInjectionBuilder::new(source, router_id, self.peer_as)
+            .announce(routes.clone(), pas.clone())
+            .build()
+            .expect("generated sets carry ORIGIN, AS_PATH and NEXT_HOP")
+        })
+        .collect()
+    }
+    pub fn nlri(&self, afi: Afi) -> Vec<Nlri> {
+        // The family's groups, each split into as many Nlri as it takes to fit them in Updates
+        self.groups
+        .iter()
+        .filter(|(_, routes)| routes[0].afi() == afi)
+        .flat_map(|(pas, routes)| Nlri::chunked(routes, pas))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_types::MAX_MESSAGE_LEN,
+        table::BgpTable,
+    };
+
+    fn path_len(pas: &[PathAttr]) -> usize {
+        pas.iter().find_map(|pa| pa.as_path()).map(|segments| as_path_len(&segments)).unwrap()
+    }
+
+    #[test]
+    fn full_table_generate() {
+        let generator = FullTableGenerator::new()
+            .prefixes_v4(20_000)
+            .prefixes_v6(5_000)
+            .attribute_sets(500)
+            .path_lengths(vec![(2, 1), (5, 1)])
+            .seed(7);
+        let table = generator.generate();
+        assert_eq!(table.len(), 25_000);
+        assert_eq!(table.routes().collect::<HashSet<_>>().len(), 25_000);
+        // Seeded tables are reproducible
+        assert_eq!(generator.generate().routes().collect::<Vec<_>>(), table.routes().collect::<Vec<_>>());
+
+        let v4: Vec<&Route> = table.routes().filter(|route| route.afi() == Afi::Ipv4).collect();
+        assert_eq!(v4.len(), 20_000);
+        assert!(v4.iter().all(|route| {
+            let addr = u32::from(route.prefix_v4().unwrap());
+            addr.trailing_zeros() >= 32 - route.prefix_len() as u32 && (1..224).contains(&(addr >> 24))
+        }));
+        // /24s dominate, as they do in the global table
+        let slash24 = v4.iter().filter(|route| route.prefix_len() == 24).count();
+        assert!(slash24 > 10_000 && slash24 < 13_000);
+
+        assert!(table.groups().len() <= 1000);
+        for (pas, routes) in table.groups() {
+            assert!([2, 5].contains(&path_len(pas)));
+            let next_hop = pas.iter().find_map(|pa| pa.next_hop()).unwrap();
+            assert!(routes.iter().all(|route| (route.afi() == Afi::Ipv4) == next_hop.is_ipv4()));
+        }
+    }
+
+    #[test]
+    fn full_table_load() {
+        let table = FullTableGenerator::new().prefixes_v4(5_000).prefixes_v6(1_000).attribute_sets(100).seed(1).generate();
+        let mut v4 = BgpTable::<Ipv4Addr>::new();
+        let mut v6 = BgpTable::<Ipv6Addr>::new();
+        let peer_addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        for payload in table.received_routes(Ipv4Addr::new(10, 0, 0, 1), peer_addr) {
+            assert_eq!(payload.last_as(), 65001);
+            assert_eq!(payload.as_path_len() as usize, path_len(payload.path_attrs_ref()));
+            match payload.afi() {
+                Afi::Ipv4 => _ = v4.walk(payload),
+                Afi::Ipv6 => _ = v6.walk(payload)
+            }
+        }
+        assert_eq!((v4.num_destinations(), v6.num_destinations()), (5_000, 1_000));
+
+        let injections = table.injections(peer_addr, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(injections.len(), table.groups().len());
+        assert_eq!(injections.iter().map(|injection| injection.routes().len()).sum::<usize>(), 6_000);
+
+        let nlri = table.nlri(Afi::Ipv4);
+        assert_eq!(nlri.iter().map(|group| group.routes().len()).sum::<usize>(), 5_000);
+        assert!(nlri.iter().all(|group| group.update_len() <= MAX_MESSAGE_LEN));
+    }
+}
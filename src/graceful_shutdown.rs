@@ -0,0 +1,160 @@
+// Graceful shutdown of eBGP sessions (RFC 8326), for taking a peer or the whole router out of service
+// without dropping traffic. While an eBGP peer is in maintenance, every path sent to it is tagged with the
+// GRACEFUL_SHUTDOWN community and the paths received from it get a LOCAL_PREF of 0, so both ends move
+// their traffic to other paths before the session is closed. Paths received with the community get the
+// same LOCAL_PREF from any peer (RFC 8326, Pg. 5), which is what makes the other end's maintenance work.
+// Maintenance doesn't close the session itself, that's left to the operator once traffic has drained.
+
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+};
+
+use crate::{
+    comms::ReceivedRoutes,
+    path_attrs::*,
+};
+
+pub(crate) const GSHUT_LOCAL_PREF: u32 = 0;
+
+// The eBGP peers in maintenance
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Maintenance {
+    // Every eBGP peer, including those added later. Set before taking the router down.
+    all: bool,
+    peers: HashSet<IpAddr>
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn start(&mut self, peer: Option<IpAddr>) {
+        // None puts every peer in maintenance
+        match peer {
+            Some(peer) => _ = self.peers.insert(peer),
+            None => self.all = true
+        }
+    }
+    pub fn stop(&mut self, peer: Option<IpAddr>) {
+        // None takes every peer out of maintenance, including those put in one by one
+        match peer {
+            Some(peer) => _ = self.peers.remove(&peer),
+            None => {
+                self.all = false;
+                self.peers.clear();
+            }
+        }
+    }
+    pub fn forget(&mut self, peer: IpAddr) {
+        // The peer has been removed
+        self.peers.remove(&peer);
+    }
+    pub fn contains(&self, peer: IpAddr) -> bool {
+        self.all || self.peers.contains(&peer)
+    }
+}
+
+pub(crate) fn on_receipt(pas: &[PathAttr], draining: bool) -> Option<Vec<PathAttr>> {
+    // The PAs with the LOCAL_PREF lowered if the path carries the community or came from a peer in
+    // maintenance, None if they stay as they are
+    let tagged = pas
+        .iter()
+        .find_map(|pa| pa.communities())
+        .is_some_and(|communities| communities.contains(&GRACEFUL_SHUTDOWN));
+    if !tagged && !draining {
+        return None;
+    }
+    let mut out: Vec<PathAttr> = pas
+        .iter()
+        .filter(|pa| pa.attr_type_code() != LOCAL_PREF)
+        .cloned()
+        .collect();
+    out.push(
+        PathAttrBuilder::<LocalPref>::new()
+        .local_pref(GSHUT_LOCAL_PREF)
+        .build()
+        .expect("LOCAL_PREF value was supplied")
+    );
+    Some(canonicalize_attrs(out))
+}
+
+pub(crate) fn import(mut payload: ReceivedRoutes, draining: bool) -> ReceivedRoutes {
+    // on_receipt() for a payload, after import policy so the lowered LOCAL_PREF wins
+    if payload.routes().is_none() {
+        return payload;
+    }
+    if let Some(pas) = on_receipt(payload.path_attrs_ref(), draining) {
+        payload = payload.with_routes(pas, payload.routes(), payload.withdrawn_routes());
+        payload.sync_decision_data();
+    }
+    payload
+}
+
+pub(crate) fn tag(pas: &mut Vec<PathAttr>) {
+    // Adds the community to a path being sent to a peer in maintenance
+    let mut communities = pas
+        .iter()
+        .find_map(|pa| pa.communities())
+        .unwrap_or_default();
+    if communities.contains(&GRACEFUL_SHUTDOWN) {
+        return;
+    }
+    communities.push(GRACEFUL_SHUTDOWN);
+    pas.retain(|pa| pa.attr_type_code() != COMMUNITIES);
+    pas.push(
+        PathAttrBuilder::<Communities>::new()
+        .communities(communities)
+        .build()
+        .expect("COMMUNITIES value was supplied")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::{comms::MockReceivedRoutesBuilder, message_types::Route};
+
+    fn pas(communities: Vec<u32>) -> Vec<PathAttr> {
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let comms = PathAttrBuilder::<Communities>::new().communities(communities).build().unwrap();
+        vec![origin, comms]
+    }
+
+    #[test]
+    fn graceful_shutdown_receipt() {
+        let local_pref = |pas: &[PathAttr]| pas.iter().find_map(|pa| pa.local_pref());
+        assert_eq!(on_receipt(&pas(vec![0xFDE80064]), false), None);
+        let lowered = on_receipt(&pas(vec![0xFDE80064, GRACEFUL_SHUTDOWN]), false).unwrap();
+        assert_eq!(local_pref(&lowered), Some(GSHUT_LOCAL_PREF));
+        let lowered = on_receipt(&pas(vec![0xFDE80064]), true).unwrap();
+        assert_eq!(local_pref(&lowered), Some(GSHUT_LOCAL_PREF));
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route]), None, pas(vec![GRACEFUL_SHUTDOWN]))
+            .local_pref(200)
+            .build();
+        assert_eq!(import(payload, false).local_pref(), Some(GSHUT_LOCAL_PREF));
+    }
+
+    #[test]
+    fn graceful_shutdown_tag() {
+        let mut tagged = pas(vec![0xFDE80064]);
+        tag(&mut tagged);
+        tag(&mut tagged);
+        assert_eq!(tagged.iter().find_map(|pa| pa.communities()), Some(vec![0xFDE80064, GRACEFUL_SHUTDOWN]));
+        let mut tagged = vec![PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap()];
+        tag(&mut tagged);
+        assert_eq!(tagged.iter().find_map(|pa| pa.communities()), Some(vec![GRACEFUL_SHUTDOWN]));
+
+        let mut maintenance = Maintenance::new();
+        let (a, b) = (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        maintenance.start(Some(a));
+        assert!(maintenance.contains(a) && !maintenance.contains(b));
+        maintenance.start(None);
+        assert!(maintenance.contains(b));
+        maintenance.stop(None);
+        assert!(!maintenance.contains(a) && !maintenance.contains(b));
+    }
+}
@@ -0,0 +1,235 @@
+// The queue between the peers' tasks and the RIB task. Announcements are bounded: once capacity of them
+// are waiting on the decision process, peer tasks stop reading from their connections until it catches
+// up (see has_room and room), so TCP pushes back on the peers instead of memory filling. The peer tasks
+// themselves never block on the queue, their timers (and so their KEEPALIVEs) carry on regardless.
+// Everything else (withdrawals, sessions coming up and going down, graceful restart) goes ahead of
+// queued announcements, so a burst from one peer doesn't hold up another peer's withdrawals. A peer's
+// own events are never reordered though: while any of its announcements are queued, its other events
+// queue behind them. Each clone of the sender keeps its own order, so every peer task gets its own.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Notify,
+};
+
+use crate::comms::TableCommand;
+
+// Payloads (one per Update and address family) of announcements queued before the peers stop reading
+pub(crate) const INGEST_CAPACITY: usize = 1024;
+
+// The RIB task is gone, so the event was dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct IngestClosed;
+
+impl fmt::Display for IngestClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RIB task is no longer running")
+    }
+}
+
+impl std::error::Error for IngestClosed {}
+
+struct Shared {
+    capacity: usize,
+    queued: AtomicUsize,
+    room: Notify
+}
+
+pub(crate) fn ingest_queue(capacity: usize) -> (IngestSender, IngestReceiver) {
+    let (urgent_tx, urgent_rx) = mpsc::unbounded_channel();
+    let (bulk_tx, bulk_rx) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared { capacity, queued: AtomicUsize::new(0), room: Notify::new() });
+    let tx = IngestSender {
+        urgent: urgent_tx,
+        bulk: bulk_tx,
+        shared: Arc::clone(&shared),
+        pending: Arc::new(AtomicUsize::new(0))
+    };
+    (tx, IngestReceiver { urgent: urgent_rx, bulk: bulk_rx, shared })
+}
+
+pub(crate) struct IngestSender {
+    urgent: UnboundedSender<TableCommand>,
+    // Each event comes with its sender's count of queued announcements, for the receiver to take it off
+    bulk: UnboundedSender<(TableCommand, Arc<AtomicUsize>)>,
+    shared: Arc<Shared>,
+    pending: Arc<AtomicUsize>
+}
+
+impl IngestSender {
+    pub fn send(&self, event: TableCommand) -> Result<(), IngestClosed> {
+        // Never waits, announcements are queued even past capacity. Err once the receiver is gone.
+        let bulk = match &event {
+            TableCommand::Routes(payload) => payload.announces(),
+            _ => false
+        };
+        match bulk || self.pending.load(Ordering::Acquire) > 0 {
+            true => {
+                self.pending.fetch_add(1, Ordering::AcqRel);
+                if bulk {
+                    self.shared.queued.fetch_add(1, Ordering::AcqRel);
+                }
+                self.bulk.send((event, Arc::clone(&self.pending))).map_err(|_| IngestClosed)
+            },
+            false => self.urgent.send(event).map_err(|_| IngestClosed)
+        }
+    }
+    pub fn has_room(&self) -> bool {
+        self.queued() < self.shared.capacity
+    }
+    pub async fn room(&self) {
+        // Returns once there's room for announcements
+        loop {
+            let room = self.shared.room.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+            if self.has_room() {
+                return;
+            }
+            room.await;
+        }
+    }
+    pub fn queued(&self) -> usize {
+        // Announcements waiting on the RIB, from every peer
+        self.shared.queued.load(Ordering::Acquire)
+    }
+}
+
+impl Clone for IngestSender {
+    fn clone(&self) -> Self {
+        // Shares the queue, but not the order of this sender's events
+        Self {
+            urgent: self.urgent.clone(),
+            bulk: self.bulk.clone(),
+            shared: Arc::clone(&self.shared),
+            pending: Arc::new(AtomicUsize::new(0))
+        }
+    }
+}
+
+pub(crate) struct IngestReceiver {
+    urgent: UnboundedReceiver<TableCommand>,
+    bulk: UnboundedReceiver<(TableCommand, Arc<AtomicUsize>)>,
+    shared: Arc<Shared>
+}
+
+impl IngestReceiver {
+    pub async fn recv(&mut self) -> Option<TableCommand> {
+        // Cancel safe, None once every sender is gone and the queue is empty
+        tokio::select! {
+            biased;
+            Some(event) = self.urgent.recv() => Some(event),
+            Some((event, pending)) = self.bulk.recv() => {
+                pending.fetch_sub(1, Ordering::AcqRel);
+                if matches!(&event, TableCommand::Routes(payload) if payload.announces()) {
+                    self.shared.queued.fetch_sub(1, Ordering::AcqRel);
+                    self.shared.room.notify_waiters();
+                }
+                Some(event)
+            },
+            else => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+    use crate::{
+        comms::MockReceivedRoutesBuilder,
+        message_types::Route,
+        path_attrs::*,
+    };
+
+    fn peer(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    fn routes(peer_addr: IpAddr, announced: bool) -> TableCommand {
+        let routes = vec![Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)))];
+        let builder = match announced {
+            true => MockReceivedRoutesBuilder::new(Some(routes), None, vec![PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap()]),
+            false => MockReceivedRoutesBuilder::new(None, Some(routes), Vec::new())
+        };
+        TableCommand::Routes(builder.peer_addr(peer_addr).build())
+    }
+
+    // Which peer the event is from and whether it announces anything
+    fn describe(event: TableCommand) -> (IpAddr, bool) {
+        match event {
+            TableCommand::Routes(payload) => (payload.peer_addr(), payload.announces()),
+            TableCommand::Down(peer, _) => (peer, false),
+            _ => panic!("unexpected event")
+        }
+    }
+
+    #[tokio::test]
+    async fn ingest_priority() {
+        let (tx, mut rx) = ingest_queue(INGEST_CAPACITY);
+        let (a, b) = (tx.clone(), tx.clone());
+        assert!(a.send(routes(peer(1), true)).is_ok());
+        assert!(a.send(routes(peer(1), true)).is_ok());
+        // B's withdrawal goes ahead of A's announcements, A's own withdrawal and Down stay behind them
+        assert!(a.send(routes(peer(1), false)).is_ok());
+        assert!(b.send(routes(peer(2), false)).is_ok());
+        assert!(a.send(TableCommand::Down(peer(1), None)).is_ok());
+        assert_eq!(tx.queued(), 2);
+
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            order.push(describe(rx.recv().await.unwrap()));
+        }
+        assert_eq!(order, vec![
+            (peer(2), false),
+            (peer(1), true),
+            (peer(1), true),
+            (peer(1), false),
+            (peer(1), false)
+        ]);
+        assert_eq!(tx.queued(), 0);
+
+        // With its announcements through, A's events go ahead of B's burst again
+        assert!(b.send(routes(peer(2), true)).is_ok());
+        assert!(b.send(routes(peer(2), true)).is_ok());
+        assert!(a.send(TableCommand::Down(peer(1), None)).is_ok());
+        assert_eq!(describe(rx.recv().await.unwrap()), (peer(1), false));
+        assert_eq!(describe(rx.recv().await.unwrap()), (peer(2), true));
+        assert_eq!(describe(rx.recv().await.unwrap()), (peer(2), true));
+
+        drop((tx, a, b));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn ingest_backpressure() {
+        let (tx, mut rx) = ingest_queue(2);
+        assert!(tx.send(routes(peer(1), true)).is_ok());
+        assert!(tx.has_room());
+        assert!(tx.send(routes(peer(1), true)).is_ok());
+        // Full, but nothing is refused
+        assert!(!tx.has_room());
+        assert!(tx.send(routes(peer(1), true)).is_ok());
+        assert_eq!(tx.queued(), 3);
+        assert!(tokio::time::timeout(Duration::from_millis(20), tx.room()).await.is_err());
+
+        let waiter = tx.clone();
+        let room = tokio::spawn(async move { waiter.room().await });
+        rx.recv().await.unwrap();
+        assert!(!tx.has_room());
+        rx.recv().await.unwrap();
+        room.await.unwrap();
+        assert!(tx.has_room());
+    }
+}
@@ -0,0 +1,546 @@
+// The Linux kernel routing table as a Fib, programmed over rtnetlink so the host forwards along the
+// routes BGP picked. Driven by a FibDriver subscribed to a BgpTable (one per AFI, each with its own
+// socket). Routes are installed in a configurable table with a configurable protocol number (RTPROT_BGP
+// by default), which is what keeps them apart from static and IGP routes and lets them be flushed with
+// `ip route flush proto <protocol> table <table>`.
+// The socket is blocking: the kernel answers a route request at once, so waiting for its ACK in the
+// table walk is cheaper than queueing the change.
+// KernelRouteMonitor goes the other way, it reads the table's routes and then follows its changes for
+// redistribution. Routes with our own protocol number are left out, BGP routes are never fed back in.
+
+use std::{
+    io, mem,
+    net::IpAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use crate::{
+    fib::Fib,
+    message_types::{HostBits, Route},
+    redistribute::{KernelRouteEvent, RouteKind},
+};
+
+// rtm_protocol for routes installed by a BGP daemon (linux/rtnetlink.h)
+pub const RTPROT_BGP: u8 = 186;
+pub const RT_TABLE_MAIN: u32 = 254;
+
+// From linux/netlink.h and linux/rtnetlink.h
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+const RTPROT_KERNEL: u8 = 2;
+const RTPROT_BOOT: u8 = 3;
+const RTPROT_STATIC: u8 = 4;
+const RTA_DST: u16 = 1;
+const RTA_GATEWAY: u16 = 5;
+const RTA_MULTIPATH: u16 = 9;
+const RTA_TABLE: u16 = 15;
+const RT_TABLE_UNSPEC: u8 = 0;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+const NLMSG_HDR_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const RTNH_LEN: usize = 8;
+const RECV_BUF_LEN: usize = 8192;
+
+pub struct KernelFib {
+    socket: OwnedFd,
+    table: u32,
+    protocol: u8,
+    seq: u32
+}
+
+impl KernelFib {
+    pub fn new(table: u32, protocol: u8) -> io::Result<Self> {
+        // Opens the rtnetlink socket. Installing routes needs CAP_NET_ADMIN, which is only checked
+        // on the first request.
+        Ok(Self {
+            socket: open_socket(0)?,
+            table,
+            protocol,
+            seq: 0
+        })
+    }
+    pub fn table(&self) -> u32 {
+        self.table
+    }
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+    fn next_seq(&mut self) -> u32 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+    fn route_request(&mut self, msg_type: u16, flags: u16, route: &Route, next_hops: &[IpAddr]) -> io::Result<()> {
+        let seq = self.next_seq();
+        let msg = route_message(msg_type, flags, seq, route, next_hops, self.table, self.protocol)?;
+        self.request(msg)
+    }
+    fn request(&mut self, msg: Vec<u8>) -> io::Result<()> {
+        // Sends the request to the kernel and waits for its ACK
+        let seq = self.seq;
+        send(&self.socket, &msg)?;
+        let mut buf = vec![0u8; RECV_BUF_LEN];
+        loop {
+            let read = recv(&self.socket, &mut buf)?;
+            // Replies to earlier requests that were given up on are skipped
+            if let Some(result) = parse_ack(&buf[..read], seq) {
+                return result;
+            }
+        }
+    }
+}
+
+impl Fib for KernelFib {
+    fn add_route(&mut self, route: &Route, next_hops: &[IpAddr]) -> io::Result<()> {
+        // A route left in the table from an earlier run is taken over. More than one next hop installs an
+        // ECMP route.
+        self.route_request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE, route, next_hops)
+    }
+    fn del_route(&mut self, route: &Route) -> io::Result<()> {
+        self.route_request(RTM_DELROUTE, 0, route, &[])
+    }
+    fn replace_nexthops(&mut self, route: &Route, next_hops: &[IpAddr]) -> io::Result<()> {
+        self.route_request(RTM_NEWROUTE, NLM_F_REPLACE, route, next_hops)
+    }
+}
+
+// Follows the routes of a kernel table. Blocking, so it runs on a thread of its own (or in
+// spawn_blocking) and hands the events on, e.g. through Redistribution::update() to Speaker::redistribute().
+pub struct KernelRouteMonitor {
+    socket: OwnedFd,
+    table: u32,
+    // Ours, whatever KernelFib installed
+    protocol: u8
+}
+
+impl KernelRouteMonitor {
+    pub fn new(table: u32, protocol: u8) -> io::Result<Self> {
+        // Subscribes to the table's changes before anything is read, so none are missed between
+        // dump() and the first recv()
+        Ok(Self {
+            socket: open_socket(RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE)?,
+            table,
+            protocol
+        })
+    }
+    pub fn dump(&mut self) -> io::Result<Vec<KernelRouteEvent>> {
+        // The routes in the table now, as Added events. Changes that arrive during the dump are
+        // included in the order they came.
+        send(&self.socket, &dump_message())?;
+        let mut buf = vec![0u8; RECV_BUF_LEN];
+        let mut events = Vec::new();
+        loop {
+            let read = recv(&self.socket, &mut buf)?;
+            let (mut received, done) = parse_routes(&buf[..read], self.table, self.protocol)?;
+            events.append(&mut received);
+            if done {
+                return Ok(events);
+            }
+        }
+    }
+    pub fn recv(&mut self) -> io::Result<Vec<KernelRouteEvent>> {
+        // Waits for the next changes to the table, may be empty if they were all to other tables
+        let mut buf = vec![0u8; RECV_BUF_LEN];
+        let read = recv(&self.socket, &mut buf)?;
+        parse_routes(&buf[..read], self.table, self.protocol).map(|(events, _)| events)
+    }
+}
+
+fn open_socket(groups: u32) -> io::Result<OwnedFd> {
+    // An rtnetlink socket, joined to the multicast groups
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe, the descriptor was just opened and nothing else owns it
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if groups != 0 {
+        // Safe, all zeroes is valid for every field. A zero nl_pid lets the kernel pick one.
+        let mut local: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        local.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        local.nl_groups = groups;
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &local as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(socket)
+}
+
+fn send(socket: &OwnedFd, msg: &[u8]) -> io::Result<()> {
+    // Safe, all zeroes is valid for every field. A zero nl_pid addresses the kernel.
+    let mut kernel: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+            0,
+            &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+        )
+    };
+    match sent < 0 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(())
+    }
+}
+
+fn recv(socket: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    let read = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+    match read < 0 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(read as usize)
+    }
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8
+    }
+}
+
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec()
+    }
+}
+
+fn push_attr(buf: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    // struct rtattr followed by the data, padded to 4 bytes
+    buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&attr_type.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn route_message(
+    msg_type: u16,
+    flags: u16,
+    seq: u32,
+    route: &Route,
+    next_hops: &[IpAddr],
+    table: u32,
+    protocol: u8
+) -> io::Result<Vec<u8>> {
+    // An RTM_NEWROUTE or RTM_DELROUTE request for the route in the table
+    let prefix = route.prefix();
+    if next_hops.iter().any(|next_hop| family(next_hop) != family(&prefix)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "next hop and prefix families differ"));
+    }
+    let mut buf = Vec::with_capacity(64);
+    // struct nlmsghdr, the length is filled in last
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    buf.extend_from_slice(&msg_type.to_ne_bytes());
+    buf.extend_from_slice(&(NLM_F_REQUEST | NLM_F_ACK | flags).to_ne_bytes());
+    buf.extend_from_slice(&seq.to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    // struct rtmsg. Tables past 255 only fit in RTA_TABLE.
+    buf.extend_from_slice(&[
+        family(&prefix),
+        route.prefix_len(),
+        0,
+        0,
+        u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC),
+        protocol,
+        RT_SCOPE_UNIVERSE,
+        RTN_UNICAST
+    ]);
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    push_attr(&mut buf, RTA_TABLE, &table.to_ne_bytes());
+    if route.prefix_len() > 0 {
+        push_attr(&mut buf, RTA_DST, &octets(&prefix));
+    }
+    match next_hops {
+        [] => (),
+        [next_hop] => push_attr(&mut buf, RTA_GATEWAY, &octets(next_hop)),
+        next_hops => {
+            // A struct rtnexthop for each next hop, each followed by its gateway
+            let mut nexthops = Vec::new();
+            for next_hop in next_hops {
+                let mut gateway = Vec::new();
+                push_attr(&mut gateway, RTA_GATEWAY, &octets(next_hop));
+                nexthops.extend_from_slice(&((RTNH_LEN + gateway.len()) as u16).to_ne_bytes());
+                // Flags, hops (weight - 1) and ifindex, the kernel finds the interface
+                nexthops.extend_from_slice(&[0, 0]);
+                nexthops.extend_from_slice(&0i32.to_ne_bytes());
+                nexthops.extend_from_slice(&gateway);
+            }
+            push_attr(&mut buf, RTA_MULTIPATH, &nexthops);
+        }
+    }
+    let len = buf.len() as u32;
+    buf[..4].copy_from_slice(&len.to_ne_bytes());
+    Ok(buf)
+}
+
+fn dump_message() -> Vec<u8> {
+    // An RTM_GETROUTE dump of every table and family, the monitor picks its table out of the replies
+    let mut buf = Vec::with_capacity(NLMSG_HDR_LEN + RTMSG_LEN);
+    buf.extend_from_slice(&((NLMSG_HDR_LEN + RTMSG_LEN) as u32).to_ne_bytes());
+    buf.extend_from_slice(&RTM_GETROUTE.to_ne_bytes());
+    buf.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    buf.extend_from_slice(&1u32.to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    // struct rtmsg, AF_UNSPEC
+    buf.extend_from_slice(&[0; RTMSG_LEN]);
+    buf
+}
+
+fn parse_routes(buf: &[u8], table: u32, protocol: u8) -> io::Result<(Vec<KernelRouteEvent>, bool)> {
+    // The changes to unicast routes of the table in buf, and whether it ends a dump
+    let mut events = Vec::new();
+    let mut rest = buf;
+    while rest.len() >= NLMSG_HDR_LEN {
+        let len = u32::from_ne_bytes(rest[..4].try_into().unwrap()) as usize;
+        let msg_type = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+        if len < NLMSG_HDR_LEN || len > rest.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message"));
+        }
+        let body = &rest[NLMSG_HDR_LEN..len];
+        match msg_type {
+            NLMSG_DONE => return Ok((events, true)),
+            NLMSG_ERROR if body.len() >= 4 => {
+                let error = i32::from_ne_bytes(body[..4].try_into().unwrap());
+                if error != 0 {
+                    return Err(io::Error::from_raw_os_error(-error));
+                }
+            },
+            RTM_NEWROUTE | RTM_DELROUTE if body.len() >= RTMSG_LEN => {
+                if let Some((route, kind)) = parse_route(body, table, protocol) {
+                    events.push(match msg_type {
+                        RTM_NEWROUTE => KernelRouteEvent::Added(route, kind),
+                        _ => KernelRouteEvent::Removed(route, kind)
+                    });
+                }
+            },
+            _ => ()
+        }
+        rest = &rest[len.next_multiple_of(4).min(rest.len())..];
+    }
+    Ok((events, false))
+}
+
+fn parse_route(body: &[u8], table: u32, protocol: u8) -> Option<(Route, RouteKind)> {
+    // The route in an rtmsg and its attributes, None if it's for another table, isn't unicast or is ours
+    let (family, dst_len, rtm_table, rtm_protocol, rtm_type) = (body[0], body[1], body[4], body[5], body[7]);
+    if rtm_type != RTN_UNICAST || rtm_protocol == protocol {
+        return None;
+    }
+    let mut route_table = rtm_table as u32;
+    let mut dst: Option<&[u8]> = None;
+    let mut attrs = &body[RTMSG_LEN..];
+    while attrs.len() >= 4 {
+        let attr_len = u16::from_ne_bytes(attrs[..2].try_into().unwrap()) as usize;
+        let attr_type = u16::from_ne_bytes(attrs[2..4].try_into().unwrap());
+        if attr_len < 4 || attr_len > attrs.len() {
+            return None;
+        }
+        let data = &attrs[4..attr_len];
+        match (attr_type, data.len()) {
+            (RTA_TABLE, 4) => route_table = u32::from_ne_bytes(data.try_into().unwrap()),
+            (RTA_DST, _) => dst = Some(data),
+            _ => ()
+        }
+        attrs = &attrs[attr_len.next_multiple_of(4).min(attrs.len())..];
+    }
+    if route_table != table {
+        return None;
+    }
+    let prefix = match (family as i32, dst) {
+        (libc::AF_INET, Some(dst)) => IpAddr::from(<[u8; 4]>::try_from(dst).ok()?),
+        (libc::AF_INET, None) => IpAddr::from([0u8; 4]),
+        (libc::AF_INET6, Some(dst)) => IpAddr::from(<[u8; 16]>::try_from(dst).ok()?),
+        (libc::AF_INET6, None) => IpAddr::from([0u8; 16]),
+        _ => return None
+    };
+    let kind = match rtm_protocol {
+        RTPROT_KERNEL => RouteKind::Connected,
+        RTPROT_BOOT | RTPROT_STATIC => RouteKind::Static,
+        _ => RouteKind::Kernel
+    };
+    // dst_len comes straight off the socket, so it's checked like any other input
+    let route = Route::try_new(dst_len, prefix, HostBits::Normalize).ok()?;
+    Some((route, kind))
+}
+
+fn parse_ack(buf: &[u8], seq: u32) -> Option<io::Result<()>> {
+    // The outcome of request seq if buf holds its ACK (an NLMSG_ERROR, zero on success)
+    let mut rest = buf;
+    while rest.len() >= NLMSG_HDR_LEN {
+        let len = u32::from_ne_bytes(rest[..4].try_into().unwrap()) as usize;
+        let msg_type = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+        let msg_seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap());
+        if len < NLMSG_HDR_LEN || len > rest.len() {
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message")));
+        }
+        if msg_type == NLMSG_ERROR && msg_seq == seq && len >= NLMSG_HDR_LEN + 4 {
+            let error = i32::from_ne_bytes(rest[NLMSG_HDR_LEN..NLMSG_HDR_LEN + 4].try_into().unwrap());
+            return match error {
+                0 => Some(Ok(())),
+                error => Some(Err(io::Error::from_raw_os_error(-error)))
+            };
+        }
+        rest = &rest[len.next_multiple_of(4).min(rest.len())..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use crate::{message_types::Afi, redistribute::{RedistributeAttrs, Redistribution}, speaker::Speaker};
+
+    fn ack(seq: u32, error: i32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&36u32.to_ne_bytes());
+        buf.extend_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        buf.extend_from_slice(&0u16.to_ne_bytes());
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&error.to_ne_bytes());
+        // The header of the request being acknowledged
+        buf.extend_from_slice(&[0; NLMSG_HDR_LEN]);
+        buf
+    }
+
+    #[test]
+    fn kernel_fib_messages() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let next_hop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let msg = route_message(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE, 7, &route, &[next_hop], 1000, RTPROT_BGP).unwrap();
+        // Header, rtmsg, then RTA_TABLE, RTA_DST and RTA_GATEWAY of 8 bytes each
+        assert_eq!(msg.len(), NLMSG_HDR_LEN + RTMSG_LEN + 24);
+        assert_eq!(u32::from_ne_bytes(msg[..4].try_into().unwrap()) as usize, msg.len());
+        assert_eq!(u16::from_ne_bytes(msg[4..6].try_into().unwrap()), RTM_NEWROUTE);
+        assert_eq!(u16::from_ne_bytes(msg[6..8].try_into().unwrap()), 0x505);
+        assert_eq!(u32::from_ne_bytes(msg[8..12].try_into().unwrap()), 7);
+        assert_eq!(&msg[16..24], &[libc::AF_INET as u8, 24, 0, 0, RT_TABLE_UNSPEC, RTPROT_BGP, 0, RTN_UNICAST]);
+        assert_eq!(u32::from_ne_bytes(msg[32..36].try_into().unwrap()), 1000);
+        assert_eq!(&msg[40..44], &[192, 0, 2, 0]);
+        assert_eq!(&msg[48..52], &[10, 0, 0, 1]);
+
+        let msg = route_message(RTM_DELROUTE, 0, 8, &route, &[], RT_TABLE_MAIN, RTPROT_BGP).unwrap();
+        assert_eq!(msg.len(), NLMSG_HDR_LEN + RTMSG_LEN + 16);
+        assert_eq!(msg[20], RT_TABLE_MAIN as u8);
+
+        // ECMP: one rtnexthop of 8 bytes plus an 8 byte gateway per next hop
+        let next_hops = [next_hop, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))];
+        let msg = route_message(RTM_NEWROUTE, 0, 9, &route, &next_hops, RT_TABLE_MAIN, RTPROT_BGP).unwrap();
+        assert_eq!(msg.len(), NLMSG_HDR_LEN + RTMSG_LEN + 16 + 4 + 32);
+        assert_eq!(u16::from_ne_bytes(msg[46..48].try_into().unwrap()), RTA_MULTIPATH);
+        assert_eq!(&msg[60..64], &[10, 0, 0, 1]);
+        assert_eq!(&msg[76..80], &[10, 0, 0, 2]);
+
+        let default = Route::new(0, IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let msg = route_message(RTM_DELROUTE, 0, 10, &default, &[], RT_TABLE_MAIN, RTPROT_BGP).unwrap();
+        assert_eq!(msg.len(), NLMSG_HDR_LEN + RTMSG_LEN + 8);
+        let err = route_message(RTM_NEWROUTE, 0, 11, &default, &[next_hop], RT_TABLE_MAIN, RTPROT_BGP).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn kernel_fib_monitor() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let next_hop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        // The replies look like the requests, without the ACK flag
+        let mut buf = route_message(RTM_NEWROUTE, 0, 1, &route, &[], RT_TABLE_MAIN, RTPROT_KERNEL).unwrap();
+        buf.extend(route_message(RTM_NEWROUTE, 0, 1, &route, &[next_hop], RT_TABLE_MAIN, RTPROT_BGP).unwrap());
+        buf.extend(route_message(RTM_NEWROUTE, 0, 1, &route, &[next_hop], 1000, RTPROT_STATIC).unwrap());
+        buf.extend(route_message(RTM_DELROUTE, 0, 1, &route, &[next_hop], RT_TABLE_MAIN, RTPROT_STATIC).unwrap());
+        let (events, done) = parse_routes(&buf, RT_TABLE_MAIN, RTPROT_BGP).unwrap();
+        assert!(!done);
+        assert_eq!(events, vec![
+            KernelRouteEvent::Added(route.clone(), RouteKind::Connected),
+            KernelRouteEvent::Removed(route.clone(), RouteKind::Static)
+        ]);
+
+        let default = Route::new(0, IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let mut buf = route_message(RTM_NEWROUTE, 0, 1, &default, &[], RT_TABLE_MAIN, 16).unwrap();
+        buf.extend_from_slice(&(NLMSG_HDR_LEN as u32).to_ne_bytes());
+        buf.extend_from_slice(&NLMSG_DONE.to_ne_bytes());
+        buf.extend_from_slice(&[0; 10]);
+        let (events, done) = parse_routes(&buf, RT_TABLE_MAIN, RTPROT_BGP).unwrap();
+        assert!(done);
+        assert_eq!(events, vec![KernelRouteEvent::Added(default, RouteKind::Kernel)]);
+        assert_eq!(dump_message().len(), NLMSG_HDR_LEN + RTMSG_LEN);
+    }
+
+    #[test]
+    fn kernel_fib_acks() {
+        assert!(matches!(parse_ack(&ack(3, 0), 3), Some(Ok(()))));
+        let err = parse_ack(&ack(3, -libc::EPERM), 3).unwrap().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        // An ACK for an earlier request, then ours
+        let mut buf = ack(2, -libc::EEXIST);
+        buf.extend(ack(3, 0));
+        assert!(matches!(parse_ack(&buf, 3), Some(Ok(()))));
+        assert!(parse_ack(&ack(2, 0), 3).is_none());
+        let err = parse_ack(&ack(3, 0)[..20], 3).unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn kernel_fib_speaker() {
+        // Nothing is installed while the table is empty, so no CAP_NET_ADMIN is needed
+        let speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        for afi in [Afi::Ipv4, Afi::Ipv6] {
+            speaker.attach_fib(afi, KernelFib::new(RT_TABLE_MAIN, RTPROT_BGP).unwrap()).unwrap();
+        }
+        assert!(speaker.routes(Afi::Ipv4).await.unwrap().is_empty());
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn kernel_fib_redistribute() {
+        // The host's routes as the speaker should end up with them
+        let redistribution = Redistribution::new()
+            .kind(RouteKind::Connected, RedistributeAttrs::new())
+            .kind(RouteKind::Kernel, RedistributeAttrs::new());
+        let mut expected = redistribution.clone();
+        KernelRouteMonitor::new(RT_TABLE_MAIN, RTPROT_BGP)
+            .unwrap()
+            .dump()
+            .unwrap()
+            .into_iter()
+            .for_each(|event| _ = expected.update(event));
+
+        let speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let monitor = KernelRouteMonitor::new(RT_TABLE_MAIN, RTPROT_BGP).unwrap();
+        _ = speaker.redistribute_kernel(monitor, redistribution);
+        let mut routes = Vec::new();
+        for _ in 0..50 {
+            routes = Vec::new();
+            for afi in [Afi::Ipv4, Afi::Ipv6] {
+                routes.extend(speaker.routes(afi).await.unwrap().iter().map(|view| view.route().clone()));
+            }
+            routes.sort();
+            if routes == expected.originated() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(routes, expected.originated());
+        speaker.shutdown().await;
+    }
+}
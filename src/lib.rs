@@ -8,7 +8,145 @@ mod errors;
 mod path_attrs;
 mod fsm_ds;
 mod fsm;
+mod router_id;
 mod msg_decoder;
-//mod msg_encoder;
+mod msg_encoder;
 mod table;
-mod comms;
\ No newline at end of file
+mod comms;
+mod ingest;
+mod export;
+mod trie;
+mod policy;
+mod rpki;
+mod rtr;
+mod max_prefix;
+mod graceful_shutdown;
+mod fib;
+mod redistribute;
+mod exabgp;
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+mod kernel_fib;
+mod table_handle;
+mod looking_glass;
+mod as_loop;
+mod timers;
+mod peer;
+mod session_events;
+mod router_events;
+mod transport;
+mod bgp_codec;
+mod tcp_md5;
+mod speaker;
+mod simulation;
+mod full_table;
+mod peer_group;
+mod config;
+#[cfg(feature = "admin")]
+mod admin;
+
+#[cfg(feature = "admin")]
+pub use admin::AdminServer;
+pub use comms::{InjectionError, RouteInjection, RouteInjectionBuilder};
+pub use config::{
+    ActionConfig,
+    AfiSafiConfig,
+    ConfigChanges,
+    ConfigError,
+    ConfiguredSpeaker,
+    MarkerCheckConfig,
+    MatchConfig,
+    MigrationAsConfig,
+    OriginConfig,
+    PeerConfig,
+    PeerGroupConfig,
+    PeerSettings,
+    PeerTransport,
+    PrependConfig,
+    RouteMapEntryConfig,
+    RouterConfig,
+    SetConfig,
+    TcpTransport,
+    TimersConfig,
+};
+pub use errors::{
+    BgpError,
+    CeaseSubcode,
+    DecodeError,
+    ErrorAction,
+    FsmError,
+    MsgHeaderErrSubcode,
+    NotifErrorCode,
+    OpenMsgErrSubcode,
+    TableError,
+    UpdateMsgErrSubcode,
+};
+pub use export::{ExportOptions, LocalAs, RouteReflector};
+pub use fib::{Fib, MemoryFib};
+pub use fsm_ds::{
+    BgpPeer,
+    ErrorRecord,
+    ErrorSource,
+    MessageCounts,
+    PeerSession,
+    PeerSessionBuilder,
+    PeerStats,
+    State,
+};
+pub use full_table::{FullTable, FullTableGenerator};
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+pub use kernel_fib::{KernelFib, KernelRouteMonitor, RTPROT_BGP, RT_TABLE_MAIN};
+pub use looking_glass::{LgPath, LgQuery, LgRoute};
+pub use max_prefix::{MaxPrefixAction, MaxPrefixConfig};
+pub use message_types::{Afi, HostBits, Nlri, Route, RouteError, Safi};
+pub use path_attrs::{
+    canonicalize_attrs,
+    Aggregator,
+    AsPath,
+    AsSegment,
+    AtomicAggregate,
+    Communities,
+    LocalPref,
+    Med,
+    NextHop,
+    Origin,
+    OriginValue,
+    PaBuilder,
+    PathAttr,
+    PathAttrBuilder,
+    PathAttrError,
+    PathAttrLen,
+};
+pub use peer::{PeerClosed, PeerHandle};
+pub use peer_group::{MissingRemoteAs, PeerGroup};
+pub use policy::{
+    CommunityPattern,
+    Direction,
+    MatchClause,
+    PolicyAction,
+    PolicyError,
+    PrefixList,
+    PrefixListEntry,
+    RouteMap,
+    RouteMapEntry,
+    SetAction,
+};
+pub use redistribute::{KernelRouteEvent, RedistributeAttrs, Redistributed, Redistribution, RouteKind};
+pub use router_events::RouterEvent;
+pub use router_id::{RouterIdError, RouterIdSelector};
+pub use rpki::{Roa, RoaTable, RpkiPolicy, RpkiState};
+pub use session_events::SessionError;
+pub use simulation::Simulation;
+pub use speaker::{Speaker, SpeakerError, SpeakerHandle};
+pub use table::{
+    DecisionConfig,
+    LocalRoutes,
+    MemoryStats,
+    PathView,
+    RouteSource,
+    RouteView,
+    SnapshotFormat,
+    SnapshotRow,
+    TableDelta,
+};
+pub use table_handle::{TableClosed, TableHandle};
+pub use transport::MarkerCheck;
\ No newline at end of file
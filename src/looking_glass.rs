@@ -0,0 +1,206 @@
+// Queries for serving a looking glass frontend. Results only hold plain, serializable values (strings,
+// numbers and addresses) so the frontend never needs the table's internal types and the output can be
+// handed straight to a JSON encoder. Speaker::looking_glass runs them against a running speaker's tables.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use serde::Serialize;
+
+use crate::{
+    message_types::Route,
+    rpki::{origin_as, RpkiState},
+    table::{BgpTable, PathView, RouteSource, RouteView, TableAfi},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LgQuery {
+    // Every path to the prefix. Falls back to the most specific covering prefix if there is no exact match.
+    Prefix(Route),
+    // Bestpaths of the prefixes originated by the AS
+    OriginAs(u16),
+    // Bestpaths carrying the community
+    Community(u32),
+    // Bestpaths learned from the peer
+    Peer(IpAddr)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LgPath {
+    best: bool,
+    peer: IpAddr,
+    peer_id: Ipv4Addr,
+    source: &'static str,
+    next_hop: Option<IpAddr>,
+    as_path: String,
+    origin: &'static str,
+    local_pref: Option<u32>,
+    med: u32,
+    weight: u32,
+    // Communities in "asn:value" form
+    communities: Vec<String>,
+    rpki: &'static str
+}
+
+impl LgPath {
+    fn new(path: &PathView) -> Self {
+        let as_path = path
+            .pas()
+            .iter()
+            .find_map(|pa| pa.as_path())
+            .unwrap_or_default()
+            .iter()
+            .map(|seg| seg.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        Self {
+            best: path.is_best(),
+            peer: path.peer_addr(),
+            peer_id: path.peer_id(),
+            source: match path.route_source() {
+                RouteSource::Local => "local",
+                RouteSource::Ebgp => "ebgp",
+                RouteSource::Ibgp => "ibgp"
+            },
+            next_hop: path.next_hop(),
+            as_path,
+            origin: match path.origin() {
+                0 => "IGP",
+                1 => "EGP",
+                _ => "INCOMPLETE"
+            },
+            local_pref: path.local_pref(),
+            med: path.med(),
+            weight: path.weight(),
+            communities: path
+                .communities()
+                .iter()
+                .map(|c| format!("{}:{}", c >> 16, c & 0xFFFF))
+                .collect(),
+            rpki: match path.rpki_state() {
+                RpkiState::Valid => "valid",
+                RpkiState::NotFound => "not-found",
+                RpkiState::Invalid => "invalid"
+            }
+        }
+    }
+    pub fn is_best(&self) -> bool {
+        self.best
+    }
+    pub fn peer(&self) -> IpAddr {
+        self.peer
+    }
+    pub fn as_path(&self) -> &str {
+        &self.as_path
+    }
+    pub fn communities(&self) -> &[String] {
+        self.communities.as_slice()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LgRoute {
+    prefix: String,
+    paths: Vec<LgPath>
+}
+
+impl LgRoute {
+    fn new(view: &RouteView) -> Self {
+        Self {
+            prefix: view.route().to_string(),
+            paths: view.paths().iter().map(LgPath::new).collect()
+        }
+    }
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+    pub fn paths(&self) -> &[LgPath] {
+        self.paths.as_slice()
+    }
+}
+
+// Runs a query against the table. At most limit routes are returned (sorted by prefix), so a
+// broad query can't dump the whole table. The result is a snapshot, later walks don't change it.
+pub(crate) fn query<A: TableAfi>(table: &BgpTable<A>, query: &LgQuery, limit: usize) -> Vec<LgRoute> {
+    let views: Vec<RouteView> = match query {
+        LgQuery::Prefix(route) => table
+            .route_view(route)
+            .or_else(|| {
+                table
+                .covering_routes(route)
+                .last()
+                .and_then(|covering| table.route_view(covering))
+            })
+            .into_iter()
+            .collect(),
+        // Locally originated paths come out with AS 0, which is never a valid origin (RFC 7607)
+        LgQuery::OriginAs(asn) => table.filter_routes(|path| {
+            path.is_best() && *asn != 0 && origin_as(path.pas(), 0) == Some(*asn as u32)
+        }),
+        LgQuery::Community(community) => table.filter_routes(|path| {
+            path.is_best() && path.communities().contains(community)
+        }),
+        LgQuery::Peer(peer) => table.filter_routes(|path| path.is_best() && path.peer_addr() == *peer)
+    };
+    views
+    .iter()
+    .take(limit)
+    .map(LgRoute::new)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comms::MockReceivedRoutesBuilder, path_attrs::*};
+
+    fn walk(table: &mut BgpTable<Ipv4Addr>, from: u8, ases: Vec<u16>, communities: Vec<u32>, routes: Vec<Route>) {
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(ases)])
+            .build()
+            .unwrap();
+        let mut pas = vec![origin, aspath];
+        if !communities.is_empty() {
+            pas.push(PathAttrBuilder::<Communities>::new().communities(communities).build().unwrap());
+        }
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(routes), None, pas)
+            .peer_id(Ipv4Addr::new(1, 1, 1, from))
+            .peer_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, from)))
+            .build()
+        );
+    }
+
+    #[test]
+    fn looking_glass_queries() {
+        let r1 = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let r2 = Route::new(16, IpAddr::V4(Ipv4Addr::new(198, 51, 0, 0)));
+        let mut table: BgpTable<Ipv4Addr> = BgpTable::new();
+        walk(&mut table, 1, vec![65001, 65010], vec![0xFDE80064], vec![r1.clone(), r2.clone()]);
+        walk(&mut table, 2, vec![65002, 65020], Vec::new(), vec![r1.clone()]);
+
+        let out = query(&table, &LgQuery::Prefix(r1.clone()), 10);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].prefix(), "192.0.2.0/24");
+        assert_eq!(out[0].paths().len(), 2);
+        assert!(out[0].paths()[0].is_best());
+        assert_eq!(out[0].paths()[0].as_path(), "65001 65010");
+        assert_eq!(out[0].paths()[0].communities(), &[String::from("65000:100")]);
+
+        // Falls back to the covering prefix
+        let more_specific = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        assert_eq!(query(&table, &LgQuery::Prefix(more_specific), 10)[0].prefix(), "198.51.0.0/16");
+
+        assert_eq!(query(&table, &LgQuery::OriginAs(65010), 10).len(), 2);
+        assert_eq!(query(&table, &LgQuery::OriginAs(65010), 1).len(), 1);
+        assert!(query(&table, &LgQuery::OriginAs(65020), 10).is_empty());
+        assert_eq!(query(&table, &LgQuery::Community(0xFDE80064), 10).len(), 2);
+        let from_peer = query(&table, &LgQuery::Peer(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))), 10);
+        assert_eq!(from_peer.len(), 2);
+        assert!(from_peer.iter().all(|route| route.paths().iter().all(|path| path.is_best())));
+
+        let json = serde_json::to_value(&out).unwrap();
+        assert_eq!(json[0]["paths"][0]["origin"], "IGP");
+        assert_eq!(json[0]["paths"][0]["rpki"], "not-found");
+    }
+}
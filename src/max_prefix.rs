@@ -0,0 +1,228 @@
+// Per-peer maximum-prefix limits. Counts the prefixes accepted from each peer (after import policy)
+// and decides what to do when a peer crosses its configured limit: log a warning only, or tear the
+// session down with a Cease (Maximum Number of Prefixes Reached, RFC 4486) and optionally allow it to
+// restart after a delay. Peers torn down without a restart timer stay down until cleared manually.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    comms::ReceivedRoutes,
+    errors::{CeaseSubcode, NotifErrorCode},
+    message_types::Route,
+};
+
+// Percentage of the limit at which a warning is raised, same as IOS
+const DEFAULT_WARNING_PCT: u8 = 75;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaxPrefixAction {
+    WarnOnly,
+    // Restart is how long to wait before the session may be brought back up
+    Teardown { restart: Option<Duration> }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaxPrefixConfig {
+    limit: usize,
+    warning_pct: u8,
+    action: MaxPrefixAction
+}
+
+impl MaxPrefixConfig {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            warning_pct: DEFAULT_WARNING_PCT,
+            action: MaxPrefixAction::Teardown { restart: None }
+        }
+    }
+    pub fn warning_threshold(mut self, pct: u8) -> Self {
+        self.warning_pct = pct.min(100);
+        self
+    }
+    pub fn warn_only(mut self) -> Self {
+        self.action = MaxPrefixAction::WarnOnly;
+        self
+    }
+    pub fn restart_after(mut self, restart: Duration) -> Self {
+        self.action = MaxPrefixAction::Teardown { restart: Some(restart) };
+        self
+    }
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    pub fn action(&self) -> MaxPrefixAction {
+        self.action
+    }
+    fn warning_at(&self) -> usize {
+        self.limit * self.warning_pct as usize / 100
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum MaxPrefixEvent {
+    // Crossed the warning threshold, only raised once until the count drops back below it
+    Warning { count: usize, limit: usize },
+    // Over the limit but the peer is configured to only warn
+    Exceeded { count: usize, limit: usize },
+    // The session must be torn down with this error. restart_at is None if it must be cleared manually.
+    Teardown { error: NotifErrorCode, restart_at: Option<Instant> }
+}
+
+pub(crate) struct PrefixLimiter {
+    configs: HashMap<IpAddr, MaxPrefixConfig>,
+    accepted: HashMap<IpAddr, HashSet<Route>>,
+    // Peers that have already been warned (or are over the limit), to avoid repeating the event
+    warned: HashSet<IpAddr>,
+    exceeded: HashSet<IpAddr>,
+    // Peers torn down for exceeding their limit, along with when they may restart
+    held_down: HashMap<IpAddr, Option<Instant>>
+}
+
+impl PrefixLimiter {
+    pub fn new() -> Self {
+        Self {
+            configs: HashMap::new(),
+            accepted: HashMap::new(),
+            warned: HashSet::new(),
+            exceeded: HashSet::new(),
+            held_down: HashMap::new()
+        }
+    }
+    pub fn configure(&mut self, peer: IpAddr, config: MaxPrefixConfig) {
+        self.configs.insert(peer, config);
+    }
+    pub fn unconfigure(&mut self, peer: IpAddr) {
+        self.configs.remove(&peer);
+        self.warned.remove(&peer);
+        self.exceeded.remove(&peer);
+    }
+    pub fn config(&self, peer: IpAddr) -> Option<MaxPrefixConfig> {
+        self.configs.get(&peer).copied()
+    }
+    pub fn count(&self, peer: IpAddr) -> usize {
+        self.accepted.get(&peer).map_or(0, |routes| routes.len())
+    }
+    pub fn update(&mut self, payload: &ReceivedRoutes, now: Instant) -> Option<MaxPrefixEvent> {
+        // Tracks the routes accepted from the payload's peer and checks the peer's limit.
+        // Withdrawals are applied first so a replaced route is never double counted.
+        let peer = payload.peer_addr();
+        let accepted = self.accepted.entry(peer).or_default();
+        payload.withdrawn_routes().unwrap_or_default().iter().for_each(|route| _ = accepted.remove(route));
+        payload.routes().unwrap_or_default().into_iter().for_each(|route| _ = accepted.insert(route));
+        let count = accepted.len();
+
+        let config = self.configs.get(&peer)?;
+        if count < config.warning_at() {
+            self.warned.remove(&peer);
+        }
+        if count <= config.limit {
+            self.exceeded.remove(&peer);
+            return match count >= config.warning_at() && self.warned.insert(peer) {
+                true => Some(MaxPrefixEvent::Warning { count, limit: config.limit }),
+                false => None
+            };
+        }
+        match config.action {
+            MaxPrefixAction::WarnOnly => match self.exceeded.insert(peer) {
+                true => Some(MaxPrefixEvent::Exceeded { count, limit: config.limit }),
+                false => None
+            },
+            MaxPrefixAction::Teardown { restart } => {
+                let restart_at = restart.map(|restart| now + restart);
+                self.held_down.insert(peer, restart_at);
+                Some(MaxPrefixEvent::Teardown {
+                    error: NotifErrorCode::CeaseReason(CeaseSubcode::MaxPrefixesReached),
+                    restart_at
+                })
+            }
+        }
+    }
+    pub fn remove_peer(&mut self, peer: IpAddr) {
+        // Forgets the accepted routes, e.g. when the session goes down. Hold downs are kept.
+        self.accepted.remove(&peer);
+        self.warned.remove(&peer);
+        self.exceeded.remove(&peer);
+    }
+    pub fn can_start(&mut self, peer: IpAddr, now: Instant) -> bool {
+        // Whether the session may be (re)started. Expired restart timers are cleared.
+        match self.held_down.get(&peer) {
+            None => true,
+            Some(Some(restart_at)) if now >= *restart_at => {
+                self.held_down.remove(&peer);
+                true
+            },
+            Some(_) => false
+        }
+    }
+    pub fn clear(&mut self, peer: IpAddr) {
+        // Manually lifts a hold down
+        self.held_down.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::{comms::MockReceivedRoutesBuilder, path_attrs::*};
+
+    fn routes(start: u8, num: u8) -> Vec<Route> {
+        (start..start + num)
+        .map(|octet| Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 0, octet, 0))))
+        .collect()
+    }
+
+    fn payload(routes: Option<Vec<Route>>, withdrawn: Option<Vec<Route>>) -> ReceivedRoutes {
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        MockReceivedRoutesBuilder::new(routes, withdrawn, vec![pa]).build()
+    }
+
+    #[test]
+    fn max_prefix_warn_only() {
+        let now = Instant::now();
+        let peer = payload(None, None).peer_addr();
+        let mut limiter = PrefixLimiter::new();
+        limiter.configure(peer, MaxPrefixConfig::new(10).warn_only());
+
+        assert_eq!(limiter.update(&payload(Some(routes(0, 6)), None), now), None);
+        assert_eq!(limiter.update(&payload(Some(routes(6, 1)), None), now), Some(MaxPrefixEvent::Warning { count: 7, limit: 10 }));
+        // Duplicates aren't counted and the warning isn't repeated
+        assert_eq!(limiter.update(&payload(Some(routes(0, 9)), None), now), None);
+        assert_eq!(limiter.update(&payload(Some(routes(9, 2)), None), now), Some(MaxPrefixEvent::Exceeded { count: 11, limit: 10 }));
+        assert_eq!(limiter.update(&payload(Some(routes(11, 1)), None), now), None);
+        assert_eq!(limiter.update(&payload(None, Some(routes(0, 6))), now), None);
+        assert_eq!(limiter.count(peer), 6);
+        assert!(limiter.can_start(peer, now));
+    }
+
+    #[test]
+    fn max_prefix_teardown() {
+        let now = Instant::now();
+        let peer = payload(None, None).peer_addr();
+        let mut limiter = PrefixLimiter::new();
+        limiter.configure(peer, MaxPrefixConfig::new(5).restart_after(Duration::from_secs(60)));
+
+        let event = limiter.update(&payload(Some(routes(0, 6)), None), now);
+        assert_eq!(event, Some(MaxPrefixEvent::Teardown {
+            error: NotifErrorCode::CeaseReason(CeaseSubcode::MaxPrefixesReached),
+            restart_at: Some(now + Duration::from_secs(60))
+        }));
+        limiter.remove_peer(peer);
+        assert_eq!(limiter.count(peer), 0);
+        assert!(!limiter.can_start(peer, now + Duration::from_secs(30)));
+        assert!(limiter.can_start(peer, now + Duration::from_secs(60)));
+
+        // Without a restart timer the peer stays down until cleared
+        limiter.configure(peer, MaxPrefixConfig::new(5));
+        let event = limiter.update(&payload(Some(routes(0, 6)), None), now);
+        assert!(matches!(event, Some(MaxPrefixEvent::Teardown { restart_at: None, .. })));
+        assert!(!limiter.can_start(peer, now + Duration::from_secs(3600)));
+        limiter.clear(peer);
+        assert!(limiter.can_start(peer, now));
+    }
+}
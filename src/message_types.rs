@@ -1,34 +1,82 @@
 use std::{
     cell::RefCell,
+    collections::HashSet,
     convert::From,
+    error::Error,
+    fmt,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     ops::{Deref, DerefMut},
+    str::FromStr,
 };
 use bytes::Buf;
 
 use crate::{
     errors::{
+        CeaseSubcode,
         MsgHeaderErrSubcode,
         NotifErrorCode,
         OpenMsgErrSubcode,
         UpdateMsgErrSubcode
     },
     path_attrs::{
+        canonicalize_attrs,
         PathAttr,
         PathAttrBuilder,
-        Med}
+        PathAttrLen,
+        PaBuilder,
+        Med,
+        MpReachNlri,
+        MpUnreachNlri,
+        EXT_LEN_FLAG,
+        AS_PATH,
+        NEXT_HOP,
+        ORIGIN}
 };
 
 use serde::{Serialize, Deserialize};
 use bgp4_serde::to_bytes;
 
-// Definitions for the basic message types in BGP.
+// Definitions for the basic message types in BGP. RFC 4271, Pg. 13 and RFC 2918, Pg. 2
 static OPEN_VALUE: u8 = 1;
 static UPDATE_VALUE: u8 = 2;
-static KEEP_VALUE: u8 = 3;
-static NOT_VALUE: u8 = 4;
+static NOT_VALUE: u8 = 3;
+static KEEP_VALUE: u8 = 4;
+static ROUTE_REFRESH_VALUE: u8 = 5;
+
+// Address Family Identifiers and Subsequent Address Family Identifiers. RFC 4760, Pg. 2
+const AFI_IPV4: u16 = 1;
+const AFI_IPV6: u16 = 2;
+const SAFI_UNICAST: u8 = 1;
+const SAFI_MULTICAST: u8 = 2;
+
+// The only BGP version spoken
+pub(crate) const BGP_VERSION: u8 = 4;
+
+// Longest Shutdown Communication, in octets of UTF-8. RFC 9003, Pg. 3
+pub(crate) const MAX_SHUTDOWN_COMMUNICATION_LEN: usize = 255;
+
+// Optional Parameter type used to carry Capabilities. RFC 5492, Pg. 3
+const OPT_PARAM_CAPABILITIES: u8 = 2;
+// ** MESSAGE SIZES ** RFC 4271, Pg. 11 and 15
+pub(crate) const MAX_MESSAGE_LEN: usize = 4096;
+pub(crate) const HEADER_LEN: usize = 19;
+// Every header starts with it, all ones. RFC 4271, Pg. 12
+pub(crate) const MARKER: [u8; 16] = [0xFF; 16];
+// Withdrawn Routes Length plus Total Path Attribute Length
+const UPDATE_FIXED_LEN: usize = 4;
+// What carrying routes in MP_REACH_NLRI and MP_UNREACH_NLRI adds to an Update: the PA header with an
+// extended length, AFI and SAFI, and for MP_REACH_NLRI a global and link-local next hop with its length
+// and the Reserved octet. RFC 4760, Pg. 3
+const MP_REACH_LEN: usize = 4 + 3 + 1 + 32 + 1;
+const MP_UNREACH_LEN: usize = 4 + 3;
+
+// Capability Codes
+const CAP_MULTIPROTOCOL: u8 = 1;
+const CAP_ROUTE_REFRESH: u8 = 2;
+const CAP_GRACEFUL_RESTART: u8 = 64;
+const CAP_FOUR_OCTET_AS: u8 = 65;
+const CAP_ADD_PATH: u8 = 69;
 
-type KeepAlive = Header;
 
 #[derive(Debug, Serialize)]
 pub struct Header {
@@ -41,17 +89,10 @@ pub struct Header {
 
 impl Header {
     pub fn new(length: u16, message_type: MessageType) -> Self {
-        // First need to actually get the bit values from the MessageType
-        let mtype = match message_type {
-            MessageType::Open => OPEN_VALUE,
-            MessageType::Update => UPDATE_VALUE,
-            MessageType::KeepAlive => KEEP_VALUE,
-            MessageType::Notification => NOT_VALUE
-        };
         Self {
-            marker: [1; 16],
+            marker: MARKER,
             length,
-            message_type: mtype
+            message_type: message_type.into()
         }
     }
     pub fn marker(&self) -> &[u8] {
@@ -64,12 +105,63 @@ impl Header {
         self.message_type
     }
 }
+
+// A KEEPALIVE is a header alone: 19 octets, type 4. RFC 4271, Pg. 21
+#[derive(Debug)]
+pub(crate) struct KeepAlive(Header);
+
+impl KeepAlive {
+    pub fn new() -> Self {
+        Self(Header::new(HEADER_LEN as u16, MessageType::KeepAlive))
+    }
+    pub fn header(&self) -> &Header {
+        &self.0
+    }
+    pub fn into_header(self) -> Header {
+        self.0
+    }
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MessageType {
     Open,
     Update,
     KeepAlive,
-    Notification
+    Notification,
+    RouteRefresh
 }
+
+impl From<MessageType> for u8 {
+    fn from(value: MessageType) -> Self {
+        // The Type field of the header
+        match value {
+            MessageType::Open => OPEN_VALUE,
+            MessageType::Update => UPDATE_VALUE,
+            MessageType::KeepAlive => KEEP_VALUE,
+            MessageType::Notification => NOT_VALUE,
+            MessageType::RouteRefresh => ROUTE_REFRESH_VALUE
+        }
+    }
+}
+
+// The Error holds the unknown Type
+impl TryFrom<u8> for MessageType {
+    type Error = u8;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        [MessageType::Open, MessageType::Update, MessageType::KeepAlive, MessageType::Notification, MessageType::RouteRefresh]
+            .into_iter()
+            .find(|msg_type| u8::from(*msg_type) == value)
+            .ok_or(value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub (crate) struct Open {
     version: u8,
     // "My Autonomous System"
@@ -107,7 +199,34 @@ impl Open {
     pub fn opt_params_len(&self) -> u8 {
         self.opt_params_len
     }
-
+    pub fn capabilities(&self) -> Vec<Capability> {
+        // Collects the Capabilities from all the Capabilities Optional Parameters. A
+        // truncated capability ends parsing of its parameter. RFC 5492, Pg. 3
+        let mut caps: Vec<Capability> = Vec::new();
+        for tlv in self.opt_params.iter().filter(|tlv| tlv.param_type() == OPT_PARAM_CAPABILITIES) {
+            let mut value = tlv.param_value();
+            while value.len() >= 2 {
+                let code = value[0];
+                let len = value[1] as usize;
+                if value.len() < 2 + len {
+                    break;
+                }
+                caps.push(Capability::from_wire(code, &value[2..2 + len]));
+                value = &value[2 + len..];
+            }
+        }
+        caps
+    }
+    pub fn peer_as(&self) -> u32 {
+        // The real AS of a 4-octet speaker is in its capability, my_as will be AS_TRANS. RFC 6793, Pg. 4
+        self.capabilities()
+            .iter()
+            .find_map(|c| match c {
+                Capability::FourOctetAs(asn) => Some(*asn),
+                _ => None
+            })
+            .unwrap_or(self.my_as as u32)
+    }
 }
 
 pub(crate) struct OpenBuilder {
@@ -135,6 +254,11 @@ impl OpenBuilder {
         self.opt_params.push(tlv);
         self
     }
+    pub fn capability(mut self, cap: Capability) -> Self {
+        // Each Capability is carried in its own Optional Parameter.
+        self.opt_params.push(Tlv::new(OPT_PARAM_CAPABILITIES, cap.to_wire()));
+        self
+    }
     pub fn build(mut self) -> Open {
         let opt_len = match self.opt_params.len() {
             0 => 0, // If no optional params added, length is 0
@@ -158,6 +282,7 @@ impl OpenBuilder {
 }
 
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct Notification {
     // Notification Error Code
     err_code: u8,
@@ -169,21 +294,95 @@ pub(crate) struct Notification {
     data: Vec<u8>
 }
 
+// What a NOTIFICATION's Data field says, going by its Error Code and Subcode. RFC 4271, Section 6
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum NotifData<'a> {
+    Empty,
+    // Bad Message Length, the erroneous Length field
+    MessageLength(u16),
+    // Bad Message Type, the erroneous Type field
+    MessageType(u8),
+    // Unsupported Version Number, the largest version the sender supports
+    SupportedVersion(u16),
+    // Missing Well-known Attribute, its type code
+    MissingAttr(u8),
+    // The erroneous attribute of an UPDATE Message Error
+    Attribute { flags: u8, type_code: u8, value: &'a [u8] },
+    // Administrative Shutdown or Reset. RFC 9003
+    ShutdownCommunication(&'a str),
+    // Data the codes don't define, or that doesn't have the shape they say it should
+    Other(&'a [u8])
+}
+
 impl Notification {
-    pub fn new(error: NotifErrorCode, data: usize) -> Self {
+    pub fn new(error: NotifErrorCode) -> Self {
+        // Errors whose Data field is empty, or can be filled in from the code alone. The ones carrying what
+        // was wrong with a message have constructors of their own.
+        let data = match &error {
+            NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedVerNum) => (BGP_VERSION as u16).to_be_bytes().to_vec(),
+            _ => Vec::new()
+        };
+        Self::with_data(error, data)
+    }
+    pub fn from_wire(err_code: u8, err_subcode: u8, data: Vec<u8>) -> Self {
+        // As received, codes we don't know included
+        Self { err_code, err_subcode, data }
+    }
+    fn with_data(error: NotifErrorCode, data: Vec<u8>) -> Self {
         // Extract the error code and subcode from the NotifErrorCode instance
         let err_code: u8 = error.as_ref().into();
         let err_subcode: u8 = match error.as_ref() {
             NotifErrorCode::MessageHeaderError(inner) => inner.into(),
             NotifErrorCode::OpenMessageError(inner) => inner.into(),
             NotifErrorCode::UpdateMessageError(inner) => inner.into(),
+            NotifErrorCode::CeaseReason(inner) => inner.into(),
             // RFC 4271, Pg.21; Error codes without defined subcodes should use 0 as subcode
             _ => 0
         };
         Self {
             err_code,
             err_subcode,
-            data: Vec::from(data.to_be_bytes())
+            data
+        }
+    }
+    pub fn bad_message_length(len: u16) -> Self {
+        // RFC 4271, Pg. 32
+        Self::with_data(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen), len.to_be_bytes().to_vec())
+    }
+    pub fn bad_message_type(msg_type: u8) -> Self {
+        Self::with_data(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgType), vec![msg_type])
+    }
+    pub fn unsupported_version(supported: u16) -> Self {
+        // The largest version we support below the one the peer bid. RFC 4271, Pg. 33
+        Self::with_data(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedVerNum), supported.to_be_bytes().to_vec())
+    }
+    pub fn missing_attr(type_code: u8) -> Self {
+        // RFC 4271, Pg. 34
+        Self::with_data(NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MissingWkAttr), vec![type_code])
+    }
+    pub fn attr_error(subcode: UpdateMsgErrSubcode, pa: &PathAttr) -> Self {
+        // The erroneous attribute (type, length and value) as it was received. RFC 4271, Pg. 34
+        let mut data = vec![pa.attr_flags(), pa.attr_type_code()];
+        match pa.attr_len() {
+            PathAttrLen::Std(len) => data.push(*len),
+            PathAttrLen::Ext(len) => data.extend_from_slice(&len.to_be_bytes())
+        }
+        data.extend_from_slice(pa.attr_value());
+        Self::with_data(NotifErrorCode::UpdateMessageError(subcode), data)
+    }
+    pub fn shutdown(subcode: CeaseSubcode, communication: &str) -> Self {
+        // Cease with a Shutdown Communication for the peer's operator, only meant for Administrative Shutdown
+        // and Administrative Reset. Longer messages are cut at a character boundary. RFC 9003, Pg. 3
+        let mut len = communication.len().min(MAX_SHUTDOWN_COMMUNICATION_LEN);
+        while !communication.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut data = vec![len as u8];
+        data.extend_from_slice(&communication.as_bytes()[..len]);
+        Self {
+            err_code: (&NotifErrorCode::Cease).into(),
+            err_subcode: (&subcode).into(),
+            data
         }
     }
     pub fn err_code(&self) -> u8 {
@@ -195,7 +394,214 @@ impl Notification {
     pub fn data(&self) -> &[u8] {
         self.data.as_slice()
     }
+    pub fn payload(&self) -> NotifData<'_> {
+        // The Data field as the Error Code and Subcode define it
+        let header = u8::from(&NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen));
+        let open = u8::from(&NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::BadPeerAs));
+        let update = u8::from(&NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MalformedAttrList));
+        let attr_errors = [
+            UpdateMsgErrSubcode::UnrecognizedWkAttr,
+            UpdateMsgErrSubcode::AttrFlagsError,
+            UpdateMsgErrSubcode::AttrLengthError,
+            UpdateMsgErrSubcode::InvalidOriginAttr,
+            UpdateMsgErrSubcode::InvalidNextHopAttr,
+            UpdateMsgErrSubcode::OptionalAttrError
+        ].map(|subcode| u8::from(&subcode));
+        let data = self.data.as_slice();
+        if data.is_empty() {
+            return NotifData::Empty;
+        }
+        if let Some(communication) = self.shutdown_communication() {
+            return NotifData::ShutdownCommunication(communication);
+        }
+        match (self.err_code, self.err_subcode, data) {
+            (code, subcode, [high, low]) if code == header && subcode == u8::from(&MsgHeaderErrSubcode::BadMsgLen) => {
+                NotifData::MessageLength(u16::from_be_bytes([*high, *low]))
+            },
+            (code, subcode, [msg_type]) if code == header && subcode == u8::from(&MsgHeaderErrSubcode::BadMsgType) => {
+                NotifData::MessageType(*msg_type)
+            },
+            (code, subcode, [high, low]) if code == open && subcode == u8::from(&OpenMsgErrSubcode::UnsupportedVerNum) => {
+                NotifData::SupportedVersion(u16::from_be_bytes([*high, *low]))
+            },
+            (code, subcode, [type_code]) if code == update && subcode == u8::from(&UpdateMsgErrSubcode::MissingWkAttr) => {
+                NotifData::MissingAttr(*type_code)
+            },
+            (code, subcode, [flags, type_code, rest @ ..]) if code == update && attr_errors.contains(&subcode) => {
+                let (len, value) = match *flags & EXT_LEN_FLAG != 0 {
+                    true => match rest {
+                        [high, low, value @ ..] => (u16::from_be_bytes([*high, *low]) as usize, value),
+                        _ => return NotifData::Other(data)
+                    },
+                    false => match rest {
+                        [len, value @ ..] => (*len as usize, value),
+                        _ => return NotifData::Other(data)
+                    }
+                };
+                match value.len() == len {
+                    true => NotifData::Attribute { flags: *flags, type_code: *type_code, value },
+                    false => NotifData::Other(data)
+                }
+            },
+            _ => NotifData::Other(data)
+        }
+    }
+    pub fn shutdown_communication(&self) -> Option<&str> {
+        // The Shutdown Communication the peer sent along with an Administrative Shutdown or Reset, if any.
+        // A bad length or invalid UTF-8 is only worth logging, so it's treated as no message. RFC 9003, Pg. 4
+        let shutdown: [u8; 2] = [(&CeaseSubcode::AdminShutdown).into(), (&CeaseSubcode::AdminReset).into()];
+        if self.err_code != u8::from(&NotifErrorCode::Cease) || !shutdown.contains(&self.err_subcode) {
+            return None;
+        }
+        let len = *self.data.first()? as usize;
+        match self.data.get(1..len + 1) {
+            Some(bytes) if len > 0 => std::str::from_utf8(bytes).ok(),
+            _ => None
+        }
+    }
+}
+// Capabilities advertised in the Open message. Anything not understood is kept
+// as raw data so it can still be reported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Capability {
+    // RFC 4760
+    Multiprotocol(Afi, Safi),
+    // RFC 2918
+    RouteRefresh,
+    // RFC 4724. Restart State bit, Restart Time in seconds and the address families whose forwarding
+    // state the speaker keeps across a restart, with the Forwarding State bit.
+    GracefulRestart {
+        restart_state: bool,
+        restart_time: u16,
+        afi_safis: Vec<(Afi, Safi, bool)>
+    },
+    // RFC 6793
+    FourOctetAs(u32),
+    // RFC 7911. Whether the speaker can send and/or receive multiple paths, per address family.
+    AddPath(Vec<(Afi, Safi, AddPathMode)>),
+    Unknown(u8, Vec<u8>)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AddPathMode {
+    Receive,
+    Send,
+    Both
+}
+
+impl AddPathMode {
+    pub fn sends(&self) -> bool {
+        matches!(self, AddPathMode::Send | AddPathMode::Both)
+    }
+    pub fn receives(&self) -> bool {
+        matches!(self, AddPathMode::Receive | AddPathMode::Both)
+    }
 }
+
+impl Capability {
+    fn from_wire(code: u8, value: &[u8]) -> Self {
+        // Builds a Capability from its code and value
+        match (code, value.len()) {
+            (CAP_MULTIPROTOCOL, 4) => {
+                let afi = Afi::try_from(u16::from_be_bytes([value[0], value[1]]));
+                let safi = Safi::try_from(value[3]);
+                match (afi, safi) {
+                    (Ok(afi), Ok(safi)) => Capability::Multiprotocol(afi, safi),
+                    _ => Capability::Unknown(code, value.to_vec())
+                }
+            },
+            (CAP_ROUTE_REFRESH, 0) => Capability::RouteRefresh,
+            (CAP_GRACEFUL_RESTART, len) if len >= 2 && (len - 2) % 4 == 0 => {
+                let flags_time = u16::from_be_bytes([value[0], value[1]]);
+                // Address families we don't know are skipped, the rest of the capability is still usable
+                let afi_safis = value[2..]
+                    .chunks(4)
+                    .filter_map(|af| {
+                        let afi = Afi::try_from(u16::from_be_bytes([af[0], af[1]])).ok()?;
+                        let safi = Safi::try_from(af[2]).ok()?;
+                        Some((afi, safi, af[3] & 0x80 != 0))
+                    })
+                    .collect();
+                Capability::GracefulRestart {
+                    restart_state: flags_time & 0x8000 != 0,
+                    restart_time: flags_time & 0x0FFF,
+                    afi_safis
+                }
+            },
+            (CAP_FOUR_OCTET_AS, 4) => {
+                Capability::FourOctetAs(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+            },
+            (CAP_ADD_PATH, len) if len > 0 && len % 4 == 0 => {
+                // Address families we don't know, and invalid Send/Receive values, are skipped
+                let afi_safis = value
+                    .chunks(4)
+                    .filter_map(|af| {
+                        let afi = Afi::try_from(u16::from_be_bytes([af[0], af[1]])).ok()?;
+                        let safi = Safi::try_from(af[2]).ok()?;
+                        let mode = match af[3] {
+                            1 => AddPathMode::Receive,
+                            2 => AddPathMode::Send,
+                            3 => AddPathMode::Both,
+                            _ => return None
+                        };
+                        Some((afi, safi, mode))
+                    })
+                    .collect();
+                Capability::AddPath(afi_safis)
+            },
+            _ => Capability::Unknown(code, value.to_vec())
+        }
+    }
+    pub fn to_wire(&self) -> Vec<u8> {
+        // Encodes the Capability as its Code, Length and Value
+        let (code, value) = match self {
+            Capability::Multiprotocol(afi, safi) => {
+                let mut value = Vec::from(u16::from(afi).to_be_bytes());
+                value.push(0); // Reserved
+                value.push(u8::from(safi));
+                (CAP_MULTIPROTOCOL, value)
+            },
+            Capability::RouteRefresh => (CAP_ROUTE_REFRESH, Vec::new()),
+            Capability::GracefulRestart { restart_state, restart_time, afi_safis } => {
+                // The Restart Time only has 12 bits
+                let flags_time = (*restart_time).min(0x0FFF) | match restart_state {
+                    true => 0x8000,
+                    false => 0
+                };
+                let mut value = Vec::from(flags_time.to_be_bytes());
+                for (afi, safi, forwarding) in afi_safis {
+                    value.extend(u16::from(afi).to_be_bytes());
+                    value.push(u8::from(safi));
+                    value.push(match forwarding {
+                        true => 0x80,
+                        false => 0
+                    });
+                }
+                (CAP_GRACEFUL_RESTART, value)
+            },
+            Capability::FourOctetAs(asn) => (CAP_FOUR_OCTET_AS, Vec::from(asn.to_be_bytes())),
+            Capability::AddPath(afi_safis) => {
+                let mut value: Vec<u8> = Vec::new();
+                for (afi, safi, mode) in afi_safis {
+                    value.extend(u16::from(afi).to_be_bytes());
+                    value.push(u8::from(safi));
+                    value.push(match mode {
+                        AddPathMode::Receive => 1,
+                        AddPathMode::Send => 2,
+                        AddPathMode::Both => 3
+                    });
+                }
+                (CAP_ADD_PATH, value)
+            },
+            Capability::Unknown(code, value) => (*code, value.clone())
+        };
+        let mut buf = vec![code, value.len() as u8];
+        buf.extend(value);
+        buf
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct Tlv { // These will be constructed on the fly
     param_type: u8,
     param_length: u8,
@@ -222,8 +628,8 @@ impl Tlv {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub(crate) struct Route {
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Route {
     // RFC 4271 explicitly states that the prefixes are IP addresses.
     // Will use the std::net package for this
     length: u8,
@@ -232,6 +638,12 @@ pub(crate) struct Route {
 
 impl Route {
     pub fn new(length: u8, prefix: IpAddr) -> Self {
+        // For lengths we know are in range. Anything read off the wire or from a user goes through try_new.
+        let max = match prefix {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        assert!(length <= max, "prefix length {} doesn't fit {}", length, prefix);
         Self {
             length,
             prefix,
@@ -240,6 +652,9 @@ impl Route {
     pub fn prefix_len(&self) -> u8 {
         self.length
     }
+    pub fn prefix(&self) -> IpAddr {
+        self.prefix
+    }
     pub fn prefix_v4(&self) -> Option<Ipv4Addr> {
         match self.prefix {
             IpAddr::V4(addr) => Some(addr),
@@ -253,17 +668,183 @@ impl Route {
             
         }
     }
-    pub fn len(&self) -> usize {
-        // Size of the route in octets
+    pub(crate) fn len(&self) -> usize {
+        // Size of the route in octets on the wire, the length octet and the prefix
+        1 + self.prefix_octets()
+    }
+    pub fn prefix_octets(&self) -> usize {
+        // Only as many octets of the prefix as its length needs, e.g. 1 for a /8. RFC 4271, Pg. 20
+        let max = match self.prefix {
+            IpAddr::V4(_) => 4,
+            IpAddr::V6(_) => 16,
+        };
+        (self.length as usize).div_ceil(8).min(max)
+    }
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Checks whether the address falls within this prefix
+        match (self.prefix, addr) {
+            (IpAddr::V4(prefix), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.length.min(32) as u32).unwrap_or(0);
+                u32::from(prefix) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(prefix), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.length.min(128) as u32).unwrap_or(0);
+                u128::from(prefix) & mask == u128::from(addr) & mask
+            },
+            _ => false
+        }
+    }
+    pub fn afi(&self) -> Afi {
+        // Address family of the prefix
         match self.prefix {
-            IpAddr::V4(_) => 1 + 4,
-            IpAddr::V6(_) => 1 + 16,
+            IpAddr::V4(_) => Afi::Ipv4,
+            IpAddr::V6(_) => Afi::Ipv6,
+        }
+    }
+    pub fn try_new(length: u8, prefix: IpAddr, host_bits: HostBits) -> Result<Self, RouteError> {
+        // new() panics on a length that doesn't fit the address family, this returns an error instead
+        let max_len = match prefix {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        if length > max_len {
+            return Err(RouteError::PrefixLength(length));
+        }
+        let route = Self::new(length, prefix);
+        match (route.has_host_bits(), host_bits) {
+            (true, HostBits::Reject) => Err(RouteError::HostBits(route.to_string())),
+            (true, HostBits::Normalize) => Ok(route.normalized()),
+            (false, _) => Ok(route)
         }
     }
-} 
+    pub fn parse(s: &str, host_bits: HostBits) -> Result<Self, RouteError> {
+        // "<addr>/<len>"
+        let syntax = || RouteError::Syntax(s.to_string());
+        let (addr, len) = s.trim().split_once('/').ok_or_else(syntax)?;
+        let addr: IpAddr = addr.parse().map_err(|_| syntax())?;
+        let len: u8 = len.parse().map_err(|_| syntax())?;
+        Self::try_new(len, addr, host_bits)
+    }
+    pub fn has_host_bits(&self) -> bool {
+        // Whether any bit past the prefix length is set, e.g. 10.0.0.1/24
+        self.normalized().prefix != self.prefix
+    }
+    pub fn normalized(&self) -> Self {
+        // The prefix with the bits past its length cleared
+        let prefix = match self.prefix {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX.checked_shl(32 - self.length.min(32) as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            },
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX.checked_shl(128 - self.length.min(128) as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        };
+        Self::new(self.length, prefix)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.length)
+    }
+}
+
+// Host bits are an error
+impl FromStr for Route {
+    type Err = RouteError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, HostBits::Reject)
+    }
+}
+
+// What to do with a prefix that has bits set past its length
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostBits {
+    Reject,
+    // Clear them, 10.0.0.1/24 is taken as 10.0.0.0/24
+    Normalize
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteError {
+    // Not "<addr>/<len>"
+    Syntax(String),
+    // Longer than the address family allows
+    PrefixLength(u8),
+    HostBits(String)
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::Syntax(s) => write!(f, "'{}' is not a prefix", s),
+            RouteError::PrefixLength(len) => write!(f, "prefix length {} is too long for the address family", len),
+            RouteError::HostBits(route) => write!(f, "{} has host bits set", route)
+        }
+    }
+}
+
+impl Error for RouteError {}
+
+// Used to tag routes with their address family as they move between the decoder,
+// the BGP tables and Update generation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Afi {
+    Ipv4,
+    Ipv6
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Safi {
+    Unicast,
+    Multicast
+}
+
+impl From<&Afi> for u16 {
+    fn from(value: &Afi) -> Self {
+        match value {
+            Afi::Ipv4 => AFI_IPV4,
+            Afi::Ipv6 => AFI_IPV6,
+        }
+    }
+}
+
+impl From<&Safi> for u8 {
+    fn from(value: &Safi) -> Self {
+        match value {
+            Safi::Unicast => SAFI_UNICAST,
+            Safi::Multicast => SAFI_MULTICAST,
+        }
+    }
+}
+
+// The Error holds the unsupported value
+impl TryFrom<u16> for Afi {
+    type Error = u16;
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            AFI_IPV4 => Ok(Afi::Ipv4),
+            AFI_IPV6 => Ok(Afi::Ipv6),
+            _ => Err(value)
+        }
+    }
+}
+
+impl TryFrom<u8> for Safi {
+    type Error = u8;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            SAFI_UNICAST => Ok(Safi::Unicast),
+            SAFI_MULTICAST => Ok(Safi::Multicast),
+            _ => Err(value)
+        }
+    }
+}
 
 // Struct to couple Routes with PAs. Will be used in the Builder for Update messages.
-pub(crate) struct Nlri {
+pub struct Nlri {
     routes: Vec<Route>,
     path_attrs: Vec<PathAttr>
 }
@@ -276,12 +857,65 @@ impl Nlri {
         this_pas.extend_from_slice(pas);
         Self {
             routes: this_routes,
-            path_attrs: this_pas
+            path_attrs: canonicalize_attrs(this_pas)
+        }
+    }
+    pub fn chunked(routes: &[Route], pas: &[PathAttr]) -> Vec<Self> {
+        // Splits the routes (in order) into as many Nlri as needed for each to fit in a single Update
+        // along with the PAs. A route is never dropped, even if the PAs alone leave no room for it.
+        let path_attrs = canonicalize_attrs(pas.to_vec());
+        let room = MAX_MESSAGE_LEN
+            .saturating_sub(HEADER_LEN + UPDATE_FIXED_LEN + mp_reach_len(routes))
+            .saturating_sub(path_attrs.iter().map(|pa| pa.attr_len_octets()).sum::<usize>());
+        let mut out: Vec<Self> = Vec::new();
+        let mut chunk: Vec<Route> = Vec::new();
+        let mut used: usize = 0;
+        for route in routes {
+            if used + route.len() > room && !chunk.is_empty() {
+                out.push(Self { routes: std::mem::take(&mut chunk), path_attrs: path_attrs.clone() });
+                used = 0;
+            }
+            used += route.len();
+            chunk.push(route.clone());
         }
+        if !chunk.is_empty() {
+            out.push(Self { routes: chunk, path_attrs });
+        }
+        out
+    }
+    pub fn routes(&self) -> &[Route] {
+        self.routes.as_slice()
+    }
+    pub fn path_attrs(&self) -> &[PathAttr] {
+        self.path_attrs.as_slice()
+    }
+    pub fn update_len(&self) -> usize {
+        // Size of the Update message carrying this Nlri, header included
+        HEADER_LEN
+        + UPDATE_FIXED_LEN
+        + mp_reach_len(&self.routes)
+        + self.path_attrs.iter().map(|pa| pa.attr_len_octets()).sum::<usize>()
+        + self.routes.iter().map(|route| route.len()).sum::<usize>()
+    }
+}
+
+fn mp_reach_len(routes: &[Route]) -> usize {
+    // Room taken by MP_REACH_NLRI if the routes need it, see UpdateBuilder::nlri
+    match routes.iter().any(|route| route.afi() != Afi::Ipv4) {
+        true => MP_REACH_LEN,
+        false => 0
     }
 }
 
+fn withdrawal_len(route: &Route, mp_unreach: bool) -> usize {
+    // Room the withdrawal takes in an Update, MP_UNREACH_NLRI included unless the Update already has it
+    match route.afi() == Afi::Ipv4 || mp_unreach {
+        true => route.len(),
+        false => route.len() + MP_UNREACH_LEN
+    }
+}
 
+#[derive(Debug, PartialEq)]
 pub (crate) struct Update {
     // Length in octets
     withdrawn_routes_len: u16,
@@ -331,14 +965,25 @@ impl Update {
         self.nlri.as_mut()
 
     }
+    pub fn mp_reach(&self) -> Option<(Afi, Safi, IpAddr, Vec<Route>)> {
+        // What MP_REACH_NLRI announces, see PathAttr::mp_reach
+        self.path_attrs()?.iter().find_map(PathAttr::mp_reach)
+    }
+    pub fn mp_unreach(&self) -> Option<(Afi, Safi, Vec<Route>)> {
+        self.path_attrs()?.iter().find_map(PathAttr::mp_unreach)
+    }
 }
 
+// IPv4 routes go in the Withdrawn Routes and NLRI fields, routes of any other family go in MP_UNREACH_NLRI
+// and MP_REACH_NLRI instead. RFC 4760, Pg. 1
 pub(crate) struct UpdateBuilder {
     withdrawn_routes_len: u16,
     withdrawn_routes: Option<Vec<Route>>,
-    total_path_attr_len: u16,
     path_attrs: Option<Vec<PathAttr>>,
     nlri: Option<Vec<Route>>,
+    mp_reach: Option<PathAttr>,
+    mp_unreach: Option<PathAttr>,
+    announces: bool
 }
 
 impl UpdateBuilder {
@@ -346,65 +991,168 @@ impl UpdateBuilder {
         Self {
             withdrawn_routes_len: 0,
             withdrawn_routes: None,
-            total_path_attr_len: 0,
             path_attrs: None,
             nlri: None,
+            mp_reach: None,
+            mp_unreach: None,
+            announces: false
         }
     }
     pub fn withdrawn_routes(mut self, routes: Vec<Route>) -> Self {
-        match routes.len() {
-            // If len of routes is 0, erroneous use of the method, just
-            // return self using default value.
-            0 => self,
-            _ => {
-                // Only ever expect either Ipv4 or Ipv6 routes, per RFC4271.
-                self.withdrawn_routes_len = {
-                    routes.iter().map(|r| r.len()).sum::<usize>() as u16
-                };
-                self.withdrawn_routes = Some(routes);
-                self
-            }
+        // If len of routes is 0, erroneous use of the method, the fields
+        // keep their default values.
+        let (routes, mp_routes): (Vec<Route>, Vec<Route>) = routes.into_iter().partition(|r| r.afi() == Afi::Ipv4);
+        self.mp_unreach = PathAttrBuilder::<MpUnreachNlri>::new().unreach(Safi::Unicast, &mp_routes).build().ok();
+        if !routes.is_empty() {
+            self.withdrawn_routes_len = {
+                routes.iter().map(|r| r.len()).sum::<usize>() as u16
+            };
+            self.withdrawn_routes = Some(routes);
         }
+        self
     }
     pub fn nlri(mut self, nlri: Nlri) -> Self {
         // Again, if either data member is empty,
         // this is erroneous. Will return a default update.
-        match (nlri.routes.is_empty(), nlri.path_attrs.is_empty()) {
-            (false, false) => {
-                self.total_path_attr_len = {
-                    nlri.path_attrs.iter().map(|pa| pa.attr_len_octets()).sum::<usize>() as u16
-                };
-                self.path_attrs = Some(nlri.path_attrs);
-                self.nlri = Some(nlri.routes);
-                self
-            },
-            _ => self
+        if nlri.routes.is_empty() || nlri.path_attrs.is_empty() {
+            return self;
+        }
+        self.announces = true;
+        let (routes, mp_routes): (Vec<Route>, Vec<Route>) = nlri.routes.into_iter().partition(|r| r.afi() == Afi::Ipv4);
+        let mut path_attrs = nlri.path_attrs;
+        if !mp_routes.is_empty() {
+            // The next hop moves into MP_REACH_NLRI, NEXT_HOP is only kept for IPv4 routes alongside.
+            // Without a next hop the routes can't be advertised. RFC 4760, Pg. 3
+            let next_hop = path_attrs.iter().find_map(|pa| pa.next_hop());
+            self.mp_reach = next_hop.and_then(|next_hop| {
+                PathAttrBuilder::<MpReachNlri>::new().reach(Safi::Unicast, next_hop, &mp_routes).build().ok()
+            });
+            if routes.is_empty() {
+                path_attrs.retain(|pa| pa.attr_type_code() != NEXT_HOP);
+            }
         }
+        self.path_attrs = Some(path_attrs);
+        self.nlri = match routes.is_empty() {
+            true => None,
+            false => Some(routes)
+        };
+        self
     }
-    pub fn build(self) -> Update {
+    pub fn build(self) -> Result<Update, UpdateError> {
+        // An Update announcing routes has to carry ORIGIN and AS_PATH, and NEXT_HOP unless all of its routes
+        // are in MP_REACH_NLRI. Routes of other families left out of it for want of a next hop count as
+        // missing NEXT_HOP too. RFC 4271, Pg. 25 and RFC 4760, Pg. 3
+        if self.announces {
+            let path_attrs = self.path_attrs.as_deref().unwrap_or_default();
+            let needs_next_hop = self.nlri.is_some() || self.mp_reach.is_none();
+            let missing = [ORIGIN, AS_PATH, NEXT_HOP]
+                .into_iter()
+                .filter(|code| *code != NEXT_HOP || needs_next_hop)
+                .find(|code| !path_attrs.iter().any(|pa| pa.attr_type_code() == *code));
+            if let Some(code) = missing {
+                return Err(UpdateError::MissingWkAttr(code));
+            }
+        }
+        Ok(self.build_unchecked())
+    }
+    pub fn build_unchecked(self) -> Update {
+        // Builds whatever was given, e.g. an Update as it was received or one that's malformed on purpose
+        let mut path_attrs: Vec<PathAttr> = self
+            .path_attrs
+            .into_iter()
+            .flatten()
+            .chain(self.mp_reach)
+            .chain(self.mp_unreach)
+            .collect();
+        path_attrs.sort_by_key(|pa| pa.attr_type_code());
+        let total_path_attr_len = path_attrs.iter().map(|pa| pa.attr_len_octets()).sum::<usize>() as u16;
         Update {
             withdrawn_routes_len: self.withdrawn_routes_len,
             withdrawn_routes: self.withdrawn_routes,
-            total_path_attr_len: self.total_path_attr_len,
-            path_attrs: self.path_attrs,
+            total_path_attr_len,
+            path_attrs: match path_attrs.is_empty() {
+                true => None,
+                false => Some(path_attrs)
+            },
             nlri: self.nlri
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UpdateError {
+    // Type code of the mandatory PA the announcement is missing
+    MissingWkAttr(u8)
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::MissingWkAttr(code) => write!(f, "Update is missing well-known attribute {}", code)
+        }
+    }
+}
+
+impl Error for UpdateError {}
+
+// Packs withdrawals and Nlri into as few Updates as possible. Duplicate withdrawals are dropped, as are
+// withdrawals of routes that are also being advertised (the advertisement implicitly withdraws the old path).
+// Withdrawals first fill the room left in the Updates carrying Nlri, the rest share withdrawal-only Updates.
+// The Nlri's PAs are sent as they are, they're whatever the tables and export policy made of them.
+pub(crate) fn build_updates(withdrawn: Vec<Route>, nlri: Vec<Nlri>) -> Vec<Update> {
+    let advertised: HashSet<Route> = nlri.iter().flat_map(|group| group.routes().iter().cloned()).collect();
+    let mut withdrawn = withdrawn;
+    withdrawn.sort();
+    withdrawn.dedup();
+    withdrawn.retain(|route| !advertised.contains(route));
+    let mut pending = withdrawn.into_iter().peekable();
+
+    let mut updates: Vec<Update> = Vec::new();
+    for group in nlri {
+        let mut room = MAX_MESSAGE_LEN.saturating_sub(group.update_len());
+        let mut riders: Vec<Route> = Vec::new();
+        let mut mp_unreach = false;
+        while let Some(route) = pending.next_if(|route| withdrawal_len(route, mp_unreach) <= room) {
+            room -= withdrawal_len(&route, mp_unreach);
+            mp_unreach |= route.afi() != Afi::Ipv4;
+            riders.push(route);
+        }
+        updates.push(UpdateBuilder::new().withdrawn_routes(riders).nlri(group).build_unchecked());
+    }
+    let room = MAX_MESSAGE_LEN - HEADER_LEN - UPDATE_FIXED_LEN;
+    let mut chunk: Vec<Route> = Vec::new();
+    let mut used: usize = 0;
+    let mut mp_unreach = false;
+    for route in pending {
+        if used + withdrawal_len(&route, mp_unreach) > room {
+            updates.push(UpdateBuilder::new().withdrawn_routes(std::mem::take(&mut chunk)).build_unchecked());
+            used = 0;
+            mp_unreach = false;
+        }
+        used += withdrawal_len(&route, mp_unreach);
+        mp_unreach |= route.afi() != Afi::Ipv4;
+        chunk.push(route);
+    }
+    if !chunk.is_empty() {
+        updates.push(UpdateBuilder::new().withdrawn_routes(chunk).build_unchecked());
+    }
+    updates
+}
+
 #[cfg(test)]
 mod tests {
 
     use crate::path_attrs::{self, PaBuilder};
 
     use super::*;
+    use bytes::Bytes;
 
     #[test]
     fn build_header_open() {
         let header = Header::new(100, MessageType::Open);
         let cell = RefCell::new(header);
         assert_eq!(cell.borrow().length, 100);
-        assert_eq!(cell.borrow().marker, [1u8; 16]);
+        assert_eq!(cell.borrow().marker, MARKER);
         assert_eq!(cell.borrow().message_type, 1u8);
     }
     #[test]
@@ -416,7 +1164,7 @@ mod tests {
 
         // Check marker
         let extracted_marker = buf.get(0..16).unwrap();
-        assert_eq!(&[1u8; 16], extracted_marker);
+        assert_eq!(&MARKER, extracted_marker);
     
         // Check Length
         let extracted_length = buf.get(16..18).unwrap();
@@ -432,7 +1180,7 @@ mod tests {
         let header = Header::new(100, MessageType::Update);
         let cell = RefCell::new(header);
         assert_eq!(cell.borrow().length, 100);
-        assert_eq!(cell.borrow().marker, [1u8; 16]);
+        assert_eq!(cell.borrow().marker, MARKER);
         assert_eq!(cell.borrow().message_type, 2u8);
     }
     #[test]
@@ -440,16 +1188,18 @@ mod tests {
         let header = Header::new(100, MessageType::KeepAlive);
         let cell = RefCell::new(header);
         assert_eq!(cell.borrow().length, 100);
-        assert_eq!(cell.borrow().marker, [1u8; 16]);
-        assert_eq!(cell.borrow().message_type, 3u8);
+        assert_eq!(cell.borrow().marker, MARKER);
+        assert_eq!(cell.borrow().message_type, 4u8);
     }
     #[test]
     fn build_header_not() {
         let header = Header::new(100, MessageType::Notification);
         let cell = RefCell::new(header);
         assert_eq!(cell.borrow().length, 100);
-        assert_eq!(cell.borrow().marker, [1u8; 16]);
-        assert_eq!(cell.borrow().message_type, 4u8);
+        assert_eq!(cell.borrow().marker, MARKER);
+        assert_eq!(cell.borrow().message_type, 3u8);
+        assert_eq!(MessageType::try_from(3), Ok(MessageType::Notification));
+        assert_eq!(MessageType::try_from(6), Err(6));
     }
     #[test]
     fn new_tlv() {
@@ -461,24 +1211,69 @@ mod tests {
     #[test]
     fn build_notification_with_subcode() {
         let err_code = NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::BadBgpId);
-        let msg = Notification::new(err_code, 1);
+        let msg = Notification::new(err_code);
         assert_eq!(msg.err_code(), 2);
         assert_eq!(msg.err_subcode(), 3);
-
-        let mut data: [u8; 8] = [0; 8];
-        data.copy_from_slice(msg.data());
-        assert_eq!(usize::from_be_bytes(data), 1);
+        assert!(msg.data().is_empty());
+        assert_eq!(msg.payload(), NotifData::Empty);
     }
     #[test]
     fn build_notification_no_subcode() {
         let err_code = NotifErrorCode::Cease;
-        let msg = Notification::new(err_code, 1);
+        let msg = Notification::new(err_code);
         assert_eq!(msg.err_code(), 6);
         assert_eq!(msg.err_subcode(), 0);
+        assert!(msg.data().is_empty());
+    }
+    #[test]
+    fn notification_data() {
+        let msg = Notification::bad_message_length(4097);
+        assert_eq!((msg.err_code(), msg.err_subcode(), msg.data()), (1, 2, &[0x10, 0x01][..]));
+        assert_eq!(msg.payload(), NotifData::MessageLength(4097));
+        assert_eq!(Notification::bad_message_type(7).payload(), NotifData::MessageType(7));
+        let msg = Notification::new(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedVerNum));
+        assert_eq!(msg.data(), &[0, 4]);
+        assert_eq!(msg.payload(), NotifData::SupportedVersion(4));
+        assert_eq!(Notification::missing_attr(3).payload(), NotifData::MissingAttr(3));
+
+        let origin = PathAttr::from_wire(0x40, 1, Bytes::from_static(&[7]));
+        let msg = Notification::attr_error(UpdateMsgErrSubcode::InvalidOriginAttr, &origin);
+        assert_eq!((msg.err_code(), msg.err_subcode(), msg.data()), (3, 6, &[0x40, 1, 1, 7][..]));
+        assert_eq!(msg.payload(), NotifData::Attribute { flags: 0x40, type_code: 1, value: &[7] });
+        let ext = PathAttr::from_wire(0x50, 2, Bytes::from_static(&[2, 1, 0xFD, 0xE8]));
+        let msg = Notification::attr_error(UpdateMsgErrSubcode::AttrFlagsError, &ext);
+        assert_eq!(msg.data(), &[0x50, 2, 0, 4, 2, 1, 0xFD, 0xE8]);
+        assert_eq!(msg.payload(), NotifData::Attribute { flags: 0x50, type_code: 2, value: &[2, 1, 0xFD, 0xE8] });
+
+        // What's received as is, whether or not it has the expected shape
+        assert_eq!(Notification::from_wire(3, 6, vec![0x40, 1, 5, 7]).payload(), NotifData::Other(&[0x40, 1, 5, 7]));
+        assert_eq!(Notification::from_wire(1, 2, vec![1]).payload(), NotifData::Other(&[1]));
+        assert_eq!(Notification::from_wire(9, 1, vec![1, 2]).payload(), NotifData::Other(&[1, 2]));
+        assert_eq!(
+            Notification::shutdown(CeaseSubcode::AdminShutdown, "bye").payload(),
+            NotifData::ShutdownCommunication("bye")
+        );
+    }
 
-        let mut data: [u8; 8] = [0; 8];
-        data.copy_from_slice(msg.data());
-        assert_eq!(usize::from_be_bytes(data), 1);
+    #[test]
+    fn notification_shutdown_communication() {
+        let msg = Notification::shutdown(CeaseSubcode::AdminShutdown, "maintenance, back at 02:00 UTC");
+        assert_eq!((msg.err_code(), msg.err_subcode()), (6, 2));
+        assert_eq!(msg.data()[0], 30);
+        assert_eq!(msg.shutdown_communication(), Some("maintenance, back at 02:00 UTC"));
+
+        // Cut to 255 octets without splitting a character
+        let long = "é".repeat(200);
+        let msg = Notification::shutdown(CeaseSubcode::AdminReset, &long);
+        assert_eq!(msg.data()[0], 254);
+        assert_eq!(msg.shutdown_communication(), Some(&long[..254]));
+
+        // No message, a length past the end of the data and other Ceases
+        assert_eq!(Notification::new(NotifErrorCode::CeaseReason(CeaseSubcode::AdminShutdown)).shutdown_communication(), None);
+        let mut msg = Notification::shutdown(CeaseSubcode::AdminShutdown, "bye");
+        msg.data[0] = 10;
+        assert_eq!(msg.shutdown_communication(), None);
+        assert_eq!(Notification::shutdown(CeaseSubcode::PeerDeconfigured, "bye").shutdown_communication(), None);
     }
 
     #[test]
@@ -509,6 +1304,102 @@ mod tests {
         assert_eq!(msg.opt_params_len, 11);
     }
 
+    #[test]
+    fn open_capabilities_roundtrip() {
+        let msg = OpenBuilder::new(4, 23456, 180, 1)
+            .capability(Capability::Multiprotocol(Afi::Ipv6, Safi::Unicast))
+            .capability(Capability::RouteRefresh)
+            .capability(Capability::FourOctetAs(4200000000))
+            .capability(Capability::Unknown(200, vec![1, 2]))
+            .opt_param(Tlv::new(1, vec![1])) // Not a capability
+            .build();
+
+        assert_eq!(msg.capabilities(), vec![
+            Capability::Multiprotocol(Afi::Ipv6, Safi::Unicast),
+            Capability::RouteRefresh,
+            Capability::FourOctetAs(4200000000),
+            Capability::Unknown(200, vec![1, 2]),
+        ]);
+    }
+    #[test]
+    fn open_capabilities_truncated() {
+        // Second capability claims 4 octets but only has 2
+        let msg = OpenBuilder::new(4, 65000, 180, 1)
+            .opt_param(Tlv::new(2, vec![2, 0, 65, 4, 0, 0]))
+            .build();
+        assert_eq!(msg.capabilities(), vec![Capability::RouteRefresh]);
+    }
+    #[test]
+    fn open_capability_graceful_restart() {
+        let gr = Capability::GracefulRestart {
+            restart_state: true,
+            restart_time: 120,
+            afi_safis: vec![(Afi::Ipv4, Safi::Unicast, true), (Afi::Ipv6, Safi::Unicast, false)]
+        };
+        assert_eq!(gr.to_wire(), vec![64, 10, 0x80, 120, 0, 1, 1, 0x80, 0, 2, 1, 0]);
+        let msg = OpenBuilder::new(4, 65000, 180, 1).capability(gr.clone()).build();
+        assert_eq!(msg.capabilities(), vec![gr]);
+    }
+    #[test]
+    fn open_capability_add_path() {
+        let add_path = Capability::AddPath(vec![(Afi::Ipv4, Safi::Unicast, AddPathMode::Both), (Afi::Ipv6, Safi::Unicast, AddPathMode::Receive)]);
+        assert_eq!(add_path.to_wire(), vec![69, 8, 0, 1, 1, 3, 0, 2, 1, 1]);
+        let msg = OpenBuilder::new(4, 65000, 180, 1).capability(add_path.clone()).build();
+        assert_eq!(msg.capabilities(), vec![add_path]);
+        // A Send/Receive value of 0 isn't valid, that address family is left out
+        assert_eq!(Capability::from_wire(69, &[0, 1, 1, 0, 0, 2, 1, 2]), Capability::AddPath(vec![(Afi::Ipv6, Safi::Unicast, AddPathMode::Send)]));
+    }
+    #[test]
+    fn route_contains() {
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        assert!(v4.contains(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 200))));
+        assert!(!v4.contains(IpAddr::V4(Ipv4Addr::new(192, 168, 2, 1))));
+        assert!(!v4.contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        let default = Route::new(0, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert!(default.contains(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
+
+        let v6 = Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        assert!(v6.contains(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1))));
+        assert!(!v6.contains(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb9, 0, 0, 0, 0, 0, 1))));
+    }
+    #[test]
+    fn route_afi() {
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        let v6 = Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        assert_eq!(v4.afi(), Afi::Ipv4);
+        assert_eq!(v6.afi(), Afi::Ipv6);
+        assert_eq!(u16::from(&v4.afi()), 1);
+        assert_eq!(u16::from(&v6.afi()), 2);
+        assert_eq!(u8::from(&Safi::Unicast), 1);
+    }
+    #[test]
+    fn route_parse() {
+        let v4: Route = "192.168.1.0/24".parse().unwrap();
+        assert_eq!(v4, Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0))));
+        assert_eq!(v4.to_string(), "192.168.1.0/24");
+        let v6: Route = "2001:db8::/32".parse().unwrap();
+        assert_eq!(v6.to_string(), "2001:db8::/32");
+        assert_eq!("0.0.0.0/0".parse::<Route>().unwrap().prefix_len(), 0);
+
+        assert_eq!("10.0.0.0/33".parse::<Route>(), Err(RouteError::PrefixLength(33)));
+        assert_eq!("2001:db8::/129".parse::<Route>(), Err(RouteError::PrefixLength(129)));
+        assert!(matches!("10.0.0.0".parse::<Route>(), Err(RouteError::Syntax(_))));
+        assert!(matches!("10.0.0/8".parse::<Route>(), Err(RouteError::Syntax(_))));
+
+        // Host bits are rejected unless asked to clear them
+        assert_eq!("10.1.2.3/8".parse::<Route>(), Err(RouteError::HostBits("10.1.2.3/8".to_string())));
+        let route = Route::parse("10.1.2.3/8", HostBits::Normalize).unwrap();
+        assert_eq!(route.to_string(), "10.0.0.0/8");
+        assert!(!route.has_host_bits());
+        let route = Route::parse("2001:db8::1/64", HostBits::Normalize).unwrap();
+        assert_eq!(route.to_string(), "2001:db8::/64");
+        assert_eq!(Route::new(128, IpAddr::V6(Ipv6Addr::LOCALHOST)).to_string(), "::1/128");
+    }
+    #[test]
+    #[should_panic(expected = "prefix length 40")]
+    fn route_new_length() {
+        Route::new(40, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)));
+    }
     #[test]
     fn build_update_withdrawn_only() {
         // build the withdrawn routes vec
@@ -519,10 +1410,10 @@ mod tests {
         routes.push(route);
 
         // no PAs, can build the Update msg
-        let update = UpdateBuilder::new().withdrawn_routes(routes).build();
+        let update = UpdateBuilder::new().withdrawn_routes(routes).build().unwrap();
 
         // Checking values
-        assert_eq!(update.withdrawn_routes_len(), 1 + 4);
+        assert_eq!(update.withdrawn_routes_len(), 1 + 3);
         match update.path_attrs() {
             Some(_) => panic!("Expected no PAs!"),
             None => ()
@@ -543,7 +1434,7 @@ mod tests {
         routes.push(route);
 
         // build the pa vec
-        let pa = PathAttrBuilder::<Med>::new().metric(1000).build();
+        let pa = PathAttrBuilder::<Med>::new().metric(1000).build().unwrap();
         let pas = vec![pa];
         let pa_len = pas.iter().map(|pa| pa.attr_len_octets()).sum::<usize>() as u16;
 
@@ -551,7 +1442,7 @@ mod tests {
         let nlri = Nlri::new(routes.as_slice(), pas.as_slice());
 
         // build the Update msg
-        let update = UpdateBuilder::new().nlri(nlri).build();
+        let update = UpdateBuilder::new().nlri(nlri).build_unchecked();
 
         // Checking values
         assert_eq!(update.withdrawn_routes_len(), 0);
@@ -583,7 +1474,7 @@ mod tests {
         n_routes.push(n_route);
 
         // build the pa vec
-        let pa = PathAttrBuilder::<Med>::new().metric(1000).build();
+        let pa = PathAttrBuilder::<Med>::new().metric(1000).build().unwrap();
         let pas = vec![pa];
         let pa_len = pas.iter().map(|pa| pa.attr_len_octets()).sum::<usize>() as u16;
 
@@ -591,10 +1482,10 @@ mod tests {
         let nlri = Nlri::new(n_routes.as_slice(), pas.as_slice());
 
         // build the Update msg
-        let update = UpdateBuilder::new().withdrawn_routes(w_routes).nlri(nlri).build();
+        let update = UpdateBuilder::new().withdrawn_routes(w_routes).nlri(nlri).build_unchecked();
 
         // Checking values
-        assert_eq!(update.withdrawn_routes_len(), 1 + 4);
+        assert_eq!(update.withdrawn_routes_len(), 1 + 3);
         match update.withdrawn_routes() {
             Some(_) => (),
             None => panic!("Expected to see Withdrawn routes!")
@@ -609,4 +1500,110 @@ mod tests {
             None => panic!("Expected to see NLRI!")
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn nlri_chunked() {
+        let routes: Vec<Route> = (0..2000u32)
+            .map(|i| Route::new(32, IpAddr::V4(Ipv4Addr::from(0x0A000000 + i))))
+            .collect();
+        let pas = vec![path_attrs::PathAttrBuilder::<Med>::new().metric(10).build().unwrap()];
+        let chunks = Nlri::chunked(&routes, &pas);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|nlri| nlri.update_len() <= MAX_MESSAGE_LEN));
+        let rejoined: Vec<Route> = chunks.iter().flat_map(|nlri| nlri.routes().to_vec()).collect();
+        assert_eq!(rejoined, routes);
+        assert!(Nlri::chunked(&[], &pas).is_empty());
+    }
+
+    #[test]
+    fn build_updates_coalesce_withdrawals() {
+        let route = |i: u32| Route::new(32, IpAddr::V4(Ipv4Addr::from(0x0A000000 + i)));
+        let pas = vec![path_attrs::PathAttrBuilder::<Med>::new().metric(10).build().unwrap()];
+
+        // Withdrawals ride along with the Nlri when there is room
+        let nlri = Nlri::chunked(&[route(0), route(1)], &pas);
+        let updates = build_updates(vec![route(1), route(2), route(2), route(3)], nlri);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].withdrawn_routes(), Some([route(2), route(3)].as_slice()));
+
+        // Withdrawals alone are packed as tightly as the message size allows
+        let withdrawn: Vec<Route> = (0..1000).map(route).collect();
+        let updates = build_updates(withdrawn, Vec::new());
+        assert_eq!(updates.len(), 2);
+        assert!(updates
+            .iter()
+            .all(|update| HEADER_LEN + UPDATE_FIXED_LEN + update.withdrawn_routes_len() as usize <= MAX_MESSAGE_LEN));
+        assert_eq!(updates.iter().map(|update| update.withdrawn_routes().unwrap().len()).sum::<usize>(), 1000);
+    }
+
+    #[test]
+    fn build_update_mandatory_attrs() {
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let v6 = Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        let origin = PathAttrBuilder::<path_attrs::Origin>::new().origin(path_attrs::OriginValue::Igp).build().unwrap();
+        let as_path = PathAttrBuilder::<path_attrs::AsPath>::new().as_segments(Vec::new()).build().unwrap();
+        let next_hop = PathAttrBuilder::<path_attrs::NextHop>::new()
+            .next_hop(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)))
+            .build()
+            .unwrap();
+        let announce = |routes: &[Route], pas: &[PathAttr]| UpdateBuilder::new().nlri(Nlri::new(routes, pas)).build();
+
+        assert!(announce(std::slice::from_ref(&v4), &[origin.clone(), as_path.clone(), next_hop.clone()]).is_ok());
+        assert_eq!(announce(std::slice::from_ref(&v4), &[as_path.clone(), next_hop.clone()]).unwrap_err(), UpdateError::MissingWkAttr(ORIGIN));
+        assert_eq!(announce(std::slice::from_ref(&v4), &[origin.clone(), next_hop.clone()]).unwrap_err(), UpdateError::MissingWkAttr(AS_PATH));
+        assert_eq!(announce(std::slice::from_ref(&v4), &[origin.clone(), as_path.clone()]).unwrap_err(), UpdateError::MissingWkAttr(NEXT_HOP));
+        // IPv6 routes only need a next hop for MP_REACH_NLRI, which doesn't leave NEXT_HOP behind
+        let update = announce(std::slice::from_ref(&v6), &[origin.clone(), as_path.clone(), next_hop]).unwrap();
+        assert!(update.mp_reach().is_some());
+        assert_eq!(announce(std::slice::from_ref(&v6), &[origin.clone(), as_path.clone()]).unwrap_err(), UpdateError::MissingWkAttr(NEXT_HOP));
+
+        // Withdrawals don't need any, and build_unchecked takes what it's given
+        assert!(UpdateBuilder::new().withdrawn_routes(vec![v4.clone(), v6]).build().is_ok());
+        let update = UpdateBuilder::new().nlri(Nlri::new(std::slice::from_ref(&v4), std::slice::from_ref(&origin))).build_unchecked();
+        assert_eq!(update.nlri(), Some([v4].as_slice()));
+    }
+    #[test]
+    fn build_update_mp_reach() {
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let v6 = Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        let v6_w = Route::new(48, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0)));
+        let next_hop = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let pas = vec![
+            PathAttrBuilder::<Med>::new().metric(10).build().unwrap(),
+            PathAttrBuilder::<path_attrs::NextHop>::new().next_hop(next_hop).build().unwrap()
+        ];
+
+        // IPv6 routes only go in MP_REACH_NLRI and MP_UNREACH_NLRI, NEXT_HOP goes with them
+        let update = UpdateBuilder::new().withdrawn_routes(vec![v6_w.clone()]).nlri(Nlri::new(std::slice::from_ref(&v6), &pas)).build_unchecked();
+        assert_eq!((update.nlri(), update.withdrawn_routes()), (None, None));
+        assert_eq!(update.mp_reach(), Some((Afi::Ipv6, Safi::Unicast, next_hop, vec![v6.clone()])));
+        assert_eq!(update.mp_unreach(), Some((Afi::Ipv6, Safi::Unicast, vec![v6_w.clone()])));
+        let type_codes: Vec<u8> = update.path_attrs().unwrap().iter().map(|pa| pa.attr_type_code()).collect();
+        assert_eq!(type_codes, vec![path_attrs::MED, path_attrs::MP_REACH_NLRI, path_attrs::MP_UNREACH_NLRI]);
+        let pa_len: usize = update.path_attrs().unwrap().iter().map(|pa| pa.attr_len_octets()).sum();
+        assert_eq!(update.total_path_attr_len() as usize, pa_len);
+
+        // Alongside IPv4 routes NEXT_HOP stays for them, an IPv4 next hop is mapped for the IPv6 ones
+        let v4_hop = Ipv4Addr::new(192, 0, 2, 1);
+        let pas = vec![PathAttrBuilder::<path_attrs::NextHop>::new().next_hop(IpAddr::V4(v4_hop)).build().unwrap()];
+        let update = UpdateBuilder::new().withdrawn_routes(vec![v4.clone(), v6_w]).nlri(Nlri::new(&[v4.clone(), v6.clone()], &pas)).build_unchecked();
+        assert_eq!((update.nlri(), update.withdrawn_routes()), (Some([v4.clone()].as_slice()), Some([v4].as_slice())));
+        assert_eq!(update.mp_reach().map(|(_, _, next_hop, _)| next_hop), Some(IpAddr::V6(v4_hop.to_ipv6_mapped())));
+        assert!(update.path_attrs().unwrap().iter().any(|pa| pa.next_hop() == Some(IpAddr::V4(v4_hop))));
+
+        // Without a next hop there's nothing to advertise them with
+        let pas = vec![PathAttrBuilder::<Med>::new().metric(10).build().unwrap()];
+        assert_eq!(UpdateBuilder::new().nlri(Nlri::new(&[v6], &pas)).build_unchecked().mp_reach(), None);
+    }
+
+    #[test]
+    fn build_updates_mp_withdrawals() {
+        // MP_UNREACH_NLRI is accounted for when packing IPv6 withdrawals
+        let route = |i: u16| Route::new(128, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        let withdrawn: Vec<Route> = (0..1000).map(route).collect();
+        let updates = build_updates(withdrawn, Vec::new());
+        assert_eq!(updates.len(), 5);
+        assert!(updates.iter().all(|update| HEADER_LEN + UPDATE_FIXED_LEN + update.total_path_attr_len() as usize <= MAX_MESSAGE_LEN));
+        assert_eq!(updates.iter().map(|update| update.mp_unreach().unwrap().2.len()).sum::<usize>(), 1000);
+    }
+}
@@ -0,0 +1,392 @@
+// Decoding of received messages, the counterpart of msg_encoder. Bodies come off the connection as
+// Bytes split from its receive buffer (see transport::Codec), and what's decoded keeps pointing into
+// them: PA values are slices of the body, and an Update's withdrawn routes and NLRI stay in wire form
+// until they're iterated. Ingesting a full table then costs a reference count per PA rather than an
+// allocation and a copy.
+// Only the framing inside the body is checked here, i.e. that the lengths add up and every prefix is
+// well formed. What the PAs themselves say is checked where they're used (see path_attrs), except for
+// the AS_PATH of a session with 4-octet ASes, which is narrowed to the 2-octet form it's kept in.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    errors::{DecodeError, ErrorAction, MsgHeaderErrSubcode, NotifErrorCode, OpenMsgErrSubcode, UpdateMsgErrSubcode},
+    message_types::{Afi, KeepAlive, Nlri, Notification, Open, OpenBuilder, Route, Safi, Tlv, Update, UpdateBuilder},
+    path_attrs::{from_session_width, AsWidth, PathAttr, EXT_LEN_FLAG},
+};
+
+// Withdrawn Routes Length plus Total Path Attribute Length
+const UPDATE_FIXED_LEN: usize = 4;
+// Version, My Autonomous System, Hold Time, BGP Identifier and Optional Parameters Length. RFC 4271, Pg. 14
+const OPEN_FIXED_LEN: usize = 10;
+// The only Optional Parameter there is, Capabilities. RFC 5492, Pg. 3
+const OPT_PARAM_CAPABILITIES: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct DecodedUpdate {
+    withdrawn: Bytes,
+    path_attrs: Vec<PathAttr>,
+    nlri: Bytes,
+    error: Option<DecodeError>
+}
+
+impl DecodedUpdate {
+    pub fn decode(body: Bytes) -> Result<Self, DecodeError> {
+        // From a session with 2-octet ASes
+        Self::decode_with(body, AsWidth::Two)
+    }
+    pub fn decode_with(body: Bytes, as_width: AsWidth) -> Result<Self, DecodeError> {
+        // Splits the body into its three parts, the lengths have to account for all of it. RFC 4271, Pg. 15
+        // Only errors that reset the session are returned as such. Malformed PAs leave an UPDATE that
+        // withdraws everything it mentions, with the error alongside (see error()). RFC 7606, Section 2
+        let malformed = || DecodeError::from(UpdateMsgErrSubcode::MalformedAttrList).with_action(ErrorAction::SessionReset);
+        if body.len() < UPDATE_FIXED_LEN {
+            return Err(malformed());
+        }
+        let attrs_at = 2 + u16::from_be_bytes([body[0], body[1]]) as usize;
+        if attrs_at + 2 > body.len() {
+            return Err(malformed());
+        }
+        let nlri_at = attrs_at + 2 + u16::from_be_bytes([body[attrs_at], body[attrs_at + 1]]) as usize;
+        if nlri_at > body.len() {
+            return Err(malformed());
+        }
+        let withdrawn = body.slice(2..attrs_at);
+        let nlri = body.slice(nlri_at..);
+        // The prefixes are only decoded when iterated, so they're checked up front
+        validate_prefixes(&withdrawn, Afi::Ipv4)?;
+        validate_prefixes(&nlri, Afi::Ipv4)?;
+        match decode_path_attrs(&body.slice(attrs_at + 2..nlri_at)).and_then(|pas| from_session_width(pas, as_width)) {
+            Ok(path_attrs) => Ok(Self { withdrawn, path_attrs, nlri, error: None }),
+            Err(err) => Ok(Self::treat_as_withdraw(withdrawn, nlri, err))
+        }
+    }
+    fn treat_as_withdraw(withdrawn: Bytes, nlri: Bytes, err: DecodeError) -> Self {
+        // Without its PAs the UPDATE can't announce anything, what it announced is withdrawn along with what
+        // it withdrew. With neither there's nothing left of it. RFC 7606, Section 2
+        let action = match withdrawn.is_empty() && nlri.is_empty() {
+            true => ErrorAction::MessageDiscard,
+            false => err.action()
+        };
+        let mut routes = BytesMut::with_capacity(withdrawn.len() + nlri.len());
+        routes.extend_from_slice(&withdrawn);
+        routes.extend_from_slice(&nlri);
+        Self {
+            withdrawn: routes.freeze(),
+            path_attrs: Vec::new(),
+            nlri: Bytes::new(),
+            error: Some(err.with_action(action))
+        }
+    }
+    pub fn error(&self) -> Option<&DecodeError> {
+        // What was wrong with the UPDATE, if the session survives it. See decode.
+        self.error.as_ref()
+    }
+    pub fn path_attrs(&self) -> &[PathAttr] {
+        self.path_attrs.as_slice()
+    }
+    pub fn withdrawn_routes(&self) -> Prefixes<'_> {
+        Prefixes::new(&self.withdrawn, Afi::Ipv4)
+    }
+    pub fn nlri(&self) -> Prefixes<'_> {
+        Prefixes::new(&self.nlri, Afi::Ipv4)
+    }
+    pub fn into_update(self) -> Update {
+        // The routes are copied out, the PAs still share the body. Built as received, missing PAs are
+        // dealt with once the routes are taken in.
+        let nlri: Vec<Route> = self.nlri().collect();
+        UpdateBuilder::new()
+            .withdrawn_routes(self.withdrawn_routes().collect())
+            .nlri(Nlri::new(&nlri, &self.path_attrs))
+            .build_unchecked()
+    }
+}
+
+// The routes in a run of prefixes in wire form, e.g. an Update's NLRI. Checked before they're iterated.
+pub(crate) struct Prefixes<'a> {
+    buf: &'a [u8],
+    afi: Afi
+}
+
+impl<'a> Prefixes<'a> {
+    fn new(buf: &'a [u8], afi: Afi) -> Self {
+        Self { buf, afi }
+    }
+}
+
+impl Iterator for Prefixes<'_> {
+    type Item = Route;
+    fn next(&mut self) -> Option<Route> {
+        let (route, used) = split_prefix(self.buf, self.afi).ok()?;
+        self.buf = &self.buf[used..];
+        Some(route)
+    }
+}
+
+pub(crate) fn decode_notification(body: &[u8]) -> Result<Notification, DecodeError> {
+    // Error Code, Error Subcode and whatever Data follows, see Notification::payload for what it says.
+    // Anything shorter than the two codes is a message too short for its type. RFC 4271, Pg. 22
+    match body {
+        [err_code, err_subcode, data @ ..] => Ok(Notification::from_wire(*err_code, *err_subcode, data.to_vec())),
+        _ => Err(DecodeError::from(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen)))
+    }
+}
+
+pub(crate) fn decode_open(body: &[u8]) -> Result<Open, DecodeError> {
+    // The fixed fields, then the Optional Parameters, which have to fill exactly what's left of the body.
+    // What the fields say (version, AS, hold time, BGP Identifier) is checked by the FSM, see check_open.
+    let bad_len = || DecodeError::from(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen));
+    if body.len() < OPEN_FIXED_LEN || body.len() != OPEN_FIXED_LEN + body[9] as usize {
+        return Err(bad_len());
+    }
+    let open = OpenBuilder::new(
+        body[0],
+        u16::from_be_bytes([body[1], body[2]]),
+        u16::from_be_bytes([body[3], body[4]]),
+        u32::from_be_bytes([body[5], body[6], body[7], body[8]])
+    );
+    let mut rest = &body[OPEN_FIXED_LEN..];
+    let mut params: Vec<Tlv> = Vec::new();
+    while let [param_type, len, value @ ..] = rest {
+        let len = *len as usize;
+        if value.len() < len {
+            return Err(bad_len());
+        }
+        // Parameters we don't recognize are an error. RFC 4271, Pg. 32
+        if *param_type != OPT_PARAM_CAPABILITIES {
+            return Err(DecodeError::from(NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedOptParam)));
+        }
+        params.push(Tlv::new(*param_type, value[..len].to_vec()));
+        rest = &value[len..];
+    }
+    match rest.is_empty() {
+        true => Ok(params.into_iter().fold(open, |open, tlv| open.opt_param(tlv)).build()),
+        false => Err(bad_len())
+    }
+}
+
+pub(crate) fn decode_route_refresh(body: &[u8]) -> Result<Option<(Afi, Safi)>, DecodeError> {
+    // AFI, Reserved and SAFI: the address family whose routes the peer wants sent again. None if it's one
+    // we don't know, those are ignored rather than an error. RFC 2918, Pg. 2-3
+    let [afi_high, afi_low, _, safi] = body else {
+        return Err(DecodeError::from(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen)));
+    };
+    let afi = Afi::try_from(u16::from_be_bytes([*afi_high, *afi_low]));
+    Ok(afi.ok().zip(Safi::try_from(*safi).ok()))
+}
+
+pub(crate) fn decode_keepalive(body: &[u8]) -> Result<KeepAlive, DecodeError> {
+    // A KEEPALIVE is the header alone, anything after it makes the length wrong. RFC 4271, Pg. 21
+    match body.is_empty() {
+        true => Ok(KeepAlive::new()),
+        false => Err(DecodeError::from(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen)))
+    }
+}
+
+pub(crate) fn decode_path_attrs(buf: &Bytes) -> Result<Vec<PathAttr>, DecodeError> {
+    // Each PA's value is a slice of the buffer. RFC 4271, Pg. 16
+    let mut attrs: Vec<PathAttr> = Vec::new();
+    let mut at = 0;
+    while at < buf.len() {
+        let rest = &buf[at..];
+        let (len, header) = match rest {
+            [flags, _, high, low, ..] if flags & EXT_LEN_FLAG != 0 => (u16::from_be_bytes([*high, *low]) as usize, 4),
+            [flags, _, len, ..] if flags & EXT_LEN_FLAG == 0 => (*len as usize, 3),
+            _ => return Err(DecodeError::from(UpdateMsgErrSubcode::MalformedAttrList))
+        };
+        if header + len > rest.len() {
+            return Err(DecodeError::from(UpdateMsgErrSubcode::AttrLengthError));
+        }
+        attrs.push(PathAttr::from_wire(rest[0], rest[1], buf.slice(at + header..at + header + len)));
+        at += header + len;
+    }
+    Ok(attrs)
+}
+
+pub(crate) fn decode_prefixes(buf: &[u8], afi: Afi) -> Result<Vec<Route>, DecodeError> {
+    // A whole run of prefixes, e.g. the routes in MP_REACH_NLRI
+    validate_prefixes(buf, afi)?;
+    Ok(Prefixes::new(buf, afi).collect())
+}
+
+fn validate_prefixes(buf: &[u8], afi: Afi) -> Result<(), DecodeError> {
+    let mut rest = buf;
+    while !rest.is_empty() {
+        let (_, used) = split_prefix(rest, afi)?;
+        rest = &rest[used..];
+    }
+    Ok(())
+}
+
+fn split_prefix(buf: &[u8], afi: Afi) -> Result<(Route, usize), DecodeError> {
+    // The first prefix and the octets it took: its length, then only as many octets as the length
+    // needs. RFC 4271, Pg. 20
+    let invalid = || DecodeError::from(UpdateMsgErrSubcode::InvalidNetworkField);
+    let (len, rest) = match buf.split_first() {
+        Some((len, rest)) => (*len, rest),
+        None => return Err(invalid())
+    };
+    let octets = (len as usize).div_ceil(8);
+    let max_len = match afi {
+        Afi::Ipv4 => 32,
+        Afi::Ipv6 => 128
+    };
+    if len > max_len || rest.len() < octets {
+        return Err(invalid());
+    }
+    let prefix = match afi {
+        Afi::Ipv4 => {
+            let mut addr = [0u8; 4];
+            addr[..octets].copy_from_slice(&rest[..octets]);
+            IpAddr::V4(Ipv4Addr::from(addr))
+        },
+        Afi::Ipv6 => {
+            let mut addr = [0u8; 16];
+            addr[..octets].copy_from_slice(&rest[..octets]);
+            IpAddr::V6(Ipv6Addr::from(addr))
+        }
+    };
+    Ok((Route::new(len, prefix), 1 + octets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_types::{Capability, NotifData},
+        path_attrs::{AS_PATH, NEXT_HOP, ORIGIN},
+    };
+
+    fn route(len: u8, a: u8, b: u8, c: u8, d: u8) -> Route {
+        Route::new(len, IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+    }
+
+    fn update_body(withdrawn: &[u8], attrs: &[u8], nlri: &[u8]) -> Bytes {
+        let mut body: Vec<u8> = Vec::new();
+        body.extend_from_slice(&(withdrawn.len() as u16).to_be_bytes());
+        body.extend_from_slice(withdrawn);
+        body.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        body.extend_from_slice(attrs);
+        body.extend_from_slice(nlri);
+        Bytes::from(body)
+    }
+
+    #[test]
+    fn decode_update_slices() {
+        let attrs = [
+            0x40, ORIGIN, 1, 0,
+            // Extended length AS_PATH
+            0x50, AS_PATH, 0, 4, 2, 1, 0xFD, 0xE8,
+            0x40, NEXT_HOP, 4, 192, 0, 2, 1
+        ];
+        let body = update_body(&[8, 10, 24, 192, 168, 1], &attrs, &[24, 198, 51, 100, 0, 25, 203, 0, 113, 128]);
+        let update = DecodedUpdate::decode(body.clone()).unwrap();
+        assert!(update.error().is_none());
+
+        assert_eq!(update.withdrawn_routes().collect::<Vec<_>>(), vec![route(8, 10, 0, 0, 0), route(24, 192, 168, 1, 0)]);
+        assert_eq!(
+            update.nlri().collect::<Vec<_>>(),
+            vec![route(24, 198, 51, 100, 0), route(0, 0, 0, 0, 0), route(25, 203, 0, 113, 128)]
+        );
+        let pas = update.path_attrs();
+        assert_eq!(pas.iter().map(|pa| pa.attr_type_code()).collect::<Vec<_>>(), vec![ORIGIN, AS_PATH, NEXT_HOP]);
+        assert_eq!(pas[1].attr_flags(), 0x50);
+        assert_eq!(pas[1].attr_len_octets(), 8);
+        assert_eq!(pas[2].next_hop(), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+        // The values are the body's own octets, not copies
+        let range = body.as_ptr_range();
+        assert!(pas.iter().all(|pa| range.contains(&pa.attr_value().as_ptr())));
+
+        let update = update.into_update();
+        assert_eq!(update.nlri().unwrap().len(), 3);
+        assert_eq!(update.withdrawn_routes().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn decode_notification_data() {
+        let msg = decode_notification(&[3, 3, 2]).unwrap();
+        assert_eq!((msg.err_code(), msg.err_subcode()), (3, 3));
+        assert_eq!(msg.payload(), NotifData::MissingAttr(2));
+        assert_eq!(decode_notification(&[4, 0]).unwrap().payload(), NotifData::Empty);
+        assert_eq!(
+            decode_notification(&[6]).unwrap_err().code(),
+            &NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen)
+        );
+    }
+
+    #[test]
+    fn decode_open_params() {
+        // AS 65000, hold time 90, 10.0.0.1 and a Capabilities parameter with a 4-octet AS
+        let body = [4, 0xFD, 0xE8, 0, 90, 10, 0, 0, 1, 8, 2, 6, 65, 4, 0, 0, 0xFD, 0xE9];
+        let open = decode_open(&body).unwrap();
+        assert_eq!(open, OpenBuilder::new(4, 65000, 90, 0x0A000001).capability(Capability::FourOctetAs(65001)).build());
+        assert_eq!(open.peer_as(), 65001);
+
+        let err = |body: &[u8]| decode_open(body).unwrap_err().code().clone();
+        let bad_len = NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen);
+        assert_eq!(err(&body[..9]), bad_len);
+        // The Optional Parameters Length doesn't match what follows, or a parameter runs past it
+        assert_eq!(err(&body[..10]), bad_len);
+        assert_eq!(err(&body[..17]), bad_len);
+        assert_eq!(err(&[4, 0xFD, 0xE8, 0, 90, 10, 0, 0, 1, 3, 2, 6, 65]), bad_len);
+        assert_eq!(
+            err(&[4, 0xFD, 0xE8, 0, 90, 10, 0, 0, 1, 2, 1, 0]),
+            NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::UnsupportedOptParam)
+        );
+    }
+
+    #[test]
+    fn decode_route_refresh_body() {
+        assert_eq!(decode_route_refresh(&[0, 1, 0, 1]), Ok(Some((Afi::Ipv4, Safi::Unicast))));
+        assert_eq!(decode_route_refresh(&[0, 2, 0, 2]), Ok(Some((Afi::Ipv6, Safi::Multicast))));
+        assert_eq!(decode_route_refresh(&[0, 1, 0, 128]), Ok(None));
+        assert_eq!(
+            decode_route_refresh(&[0, 1, 0]).unwrap_err().code(),
+            &NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen)
+        );
+    }
+
+    #[test]
+    fn decode_keepalive_body() {
+        assert_eq!(decode_keepalive(&[]).unwrap().header().message_type(), 4);
+        assert_eq!(
+            decode_keepalive(&[0]).unwrap_err().code(),
+            &NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::BadMsgLen)
+        );
+    }
+
+    #[test]
+    fn decode_update_errors() {
+        // The error, whether it came back as one or alongside what's left of the UPDATE
+        let err = |body: Bytes| {
+            let err = match DecodedUpdate::decode(body) {
+                Ok(update) => update.error().cloned().unwrap(),
+                Err(err) => err
+            };
+            match err.code() {
+                NotifErrorCode::UpdateMessageError(subcode) => (subcode.clone(), err.action()),
+                code => panic!("expected an UPDATE Message Error, got {}", code)
+            }
+        };
+        let reset = ErrorAction::SessionReset;
+        assert_eq!(err(Bytes::from_static(&[0, 0, 0])), (UpdateMsgErrSubcode::MalformedAttrList, reset));
+        // Withdrawn Routes Length runs past the body
+        assert_eq!(err(Bytes::from_static(&[0, 9, 8, 10, 0, 0])), (UpdateMsgErrSubcode::MalformedAttrList, reset));
+        assert_eq!(err(update_body(&[], &[0x40, ORIGIN, 2, 0], &[])), (UpdateMsgErrSubcode::AttrLengthError, ErrorAction::MessageDiscard));
+        assert_eq!(err(update_body(&[], &[0x40, ORIGIN], &[24, 10, 0, 0])), (UpdateMsgErrSubcode::MalformedAttrList, ErrorAction::TreatAsWithdraw));
+        assert_eq!(err(update_body(&[33, 10, 0, 0, 0, 0], &[], &[])), (UpdateMsgErrSubcode::InvalidNetworkField, reset));
+        assert_eq!(err(update_body(&[], &[], &[24, 198, 51])), (UpdateMsgErrSubcode::InvalidNetworkField, reset));
+
+        // Treat-as-withdraw: both withdrawn and announced routes come out withdrawn, without PAs
+        let update = DecodedUpdate::decode(update_body(&[8, 10], &[0x40, ORIGIN, 2, 0], &[24, 198, 51, 100])).unwrap();
+        assert_eq!(update.withdrawn_routes().collect::<Vec<_>>(), vec![route(8, 10, 0, 0, 0), route(24, 198, 51, 100, 0)]);
+        assert_eq!(update.nlri().count(), 0);
+        assert!(update.path_attrs().is_empty());
+        assert!(matches!(
+            NotifErrorCode::from(DecodedUpdate::decode(Bytes::new()).unwrap_err()),
+            NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MalformedAttrList)
+        ));
+    }
+}
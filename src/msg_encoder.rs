@@ -1,26 +1,23 @@
 // This module contains all the BGP Control Message serialization and transfer logic.
 // Since this is an RFC based protocol, the serialization will be home-rolled for accuracy as opposed
 // to using Serde.
+// The serializers own their message and buffer, which suits one-off messages. Updates go out by the
+// thousand when a peer is sent a full table, so they're encoded by reference with MessageEncoder, which
+// writes their bodies into buffers it pools and takes back once they've been sent (see Codec::recycle).
 
 
 // GLOBAL TO-DOs:
 // 1. Make sure that all arbitrary "puts" into the BytesMut types are Big Endian!
 // 2. Add tests for OpenSerializer
-use std::{
-    net::{IpAddr}
-};
 use crate::{message_types::{
-    ByteLen, Header, MessageType, Notification, Open, Route, Tlv, Update
-}, path_attrs::{PathAttr, PathAttrLen}};
-
-use crate::errors::{
-    NotifErrorCode,
-    OpenMsgErrSubcode,
-    MsgHeaderErrSubcode,
-    UpdateMsgErrSubcode,
-};
+    Afi, Header, KeepAlive, Notification, Open, Route, Safi, Update, MAX_MESSAGE_LEN
+}, path_attrs::{to_session_width, AsWidth, PathAttr, PathAttrLen}};
 
 use bytes::{BytesMut, BufMut};
+use std::{net::IpAddr, slice};
+
+// Buffers kept for reuse by default, enough for the Updates in flight to a handful of peers
+const DEFAULT_POOLED: usize = 64;
 // Each Control Message will have a custom Serializer type which will be combined into a MessageBuilder
 struct HeaderSerializer {
     msg: Header,
@@ -42,7 +39,21 @@ impl HeaderSerializer {
     }
 }
 
-struct NotificationSerializer {
+// The whole message, since a KEEPALIVE has no body
+struct KeepAliveSerializer {
+    msg: KeepAlive
+}
+
+impl KeepAliveSerializer {
+    pub fn new(msg: KeepAlive) -> Self {
+        Self { msg }
+    }
+    pub fn serialize(self) -> BytesMut {
+        HeaderSerializer::new(self.msg.into_header()).serialize()
+    }
+}
+
+pub(crate) struct NotificationSerializer {
     msg: Notification,
     buf: BytesMut,
 }
@@ -64,7 +75,7 @@ impl NotificationSerializer {
         self.buf
     }
 }
-struct OpenSerializer {
+pub(crate) struct OpenSerializer {
     msg: Open,
     buf: BytesMut,
 }
@@ -100,6 +111,25 @@ impl OpenSerializer {
     }
 }
 
+// AFI, a reserved octet and SAFI. RFC 2918, Pg. 2
+pub(crate) struct RouteRefreshSerializer {
+    afi: Afi,
+    safi: Safi
+}
+
+impl RouteRefreshSerializer {
+    pub fn new(afi: Afi, safi: Safi) -> Self {
+        Self { afi, safi }
+    }
+    pub fn serialize(self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(4);
+        buf.put_u16(u16::from(&self.afi));
+        buf.put_u8(0);
+        buf.put_u8(u8::from(&self.safi));
+        buf
+    }
+}
+
 struct RouteSerializer {
     msg: Route,
     buf: BytesMut
@@ -107,168 +137,289 @@ struct RouteSerializer {
 
 impl RouteSerializer {
     pub fn new(msg: Route) -> Self {
-        let byte_len = msg.byte_len();
+        let byte_len = msg.len();
         Self {
             msg,
             buf: BytesMut::with_capacity(byte_len)
         }
     }
     pub fn serialize(mut self) -> BytesMut {
-        self.buf.put_u8(self.msg.length());
-        match self.msg.prefix_v4() {
-            Some(addr) => self.buf.put(addr.octets().as_slice()),
-            None => self.buf.put(self.msg.prefix_v6().unwrap().octets().as_slice()),
-        }
+        put_route(&mut self.buf, &self.msg);
         self.buf
     }
 }
 
+// AS_PATH and AGGREGATOR go out with 2-octet ASes unless the session negotiated 4-octet ones (see
+// session_width), and the AS4_* PAs only go with 2-octet ones.
 struct PathAttrSerializer {
     msg: PathAttr,
-    buf: BytesMut
+    buf: BytesMut,
+    as_width: AsWidth
 }
 
 impl PathAttrSerializer {
     pub fn new(msg: PathAttr) -> Self {
-        let byte_len = msg.byte_len();
+        let byte_len = msg.attr_len_octets();
         Self {
             msg,
-            buf: BytesMut::with_capacity(byte_len)
+            buf: BytesMut::with_capacity(byte_len),
+            as_width: AsWidth::default()
         }
     }
+    pub fn session_width(mut self, as_width: AsWidth) -> Self {
+        self.as_width = as_width;
+        self
+    }
     pub fn serialize(mut self) -> BytesMut {
-        self.buf.put_u8(self.msg.attr_flags());
-        self.buf.put_u8(self.msg.attr_type_code());
-        // Serialize based on standard or extended length size
-        match self.msg.attr_len() {
-            &PathAttrLen::Std(x) => self.buf.put_u8(x),
-            &PathAttrLen::Ext(x) => self.buf.put_u16(x),
-        }
-        self.buf.put(self.msg.attr_value());
+        let pas = to_session_width(slice::from_ref(&self.msg), self.as_width);
+        pas.iter().for_each(|pa| put_path_attr(&mut self.buf, pa));
         self.buf
     }
 }
-struct UpdateSerializer {
-    msg: Update,
-    buf: BytesMut,
+
+// Writers shared by the serializers and MessageEncoder. They append to the buffer and only borrow
+// what they write.
+pub(crate) fn put_route<B: BufMut>(buf: &mut B, route: &Route) {
+    // The prefix length, then the prefix truncated to the octets it needs. Any host bits past the
+    // length in the last octet go out as they are. RFC 4271, Pg. 20
+    let octets = route.prefix_octets();
+    buf.put_u8(route.prefix_len());
+    match route.prefix() {
+        IpAddr::V4(addr) => buf.put_slice(&addr.octets()[..octets]),
+        IpAddr::V6(addr) => buf.put_slice(&addr.octets()[..octets]),
+    }
 }
 
-impl UpdateSerializer {
-    pub fn new(msg: Update) -> Self {
-        let w_routes_len = msg.withdrawn_routes_len();
-        let pa_len = msg.total_path_attr_len();
-        Self {
-            msg,
-            // This will not capture the entire Update message length, but will lower number of resizes
-            buf: BytesMut::with_capacity(2 + w_routes_len as usize + 2 + pa_len as usize)
-        }
+fn put_path_attr(buf: &mut BytesMut, pa: &PathAttr) {
+    buf.put_u8(pa.attr_flags());
+    buf.put_u8(pa.attr_type_code());
+    // Serialize based on standard or extended length size
+    match pa.attr_len() {
+        PathAttrLen::Std(x) => buf.put_u8(*x),
+        PathAttrLen::Ext(x) => buf.put_u16(*x),
     }
-    pub fn serialize(mut self) -> BytesMut {
-        self.buf.put_u16(self.msg.withdrawn_routes_len());
-
-        // Check to see if there any withdrawn routes to serialize
-        // and serialize if so
-        match self.msg.withdrawn_routes_mut() {
-            Some(serial_vec) => {
-                for route in serial_vec {
-                    // Create RouteSerializer and serialize the route
-                    let rs = RouteSerializer::new(route);
-                    self.buf.put(rs.serialize())
-                }
-            },
-            None => ()
-        }
+    buf.put(pa.attr_value());
+}
 
-        self.buf.put_u16(self.msg.total_path_attr_len());
-        
-        // Check to see if there are any PAs to serialize
-        // and serialize if so.
-        match self.msg.path_attrs_mut() {
-            Some(vec) => {
-                for path_attr in vec.to_owned() {
-                    // Create serializer and serialize the PathAttr.
-                    let ps = PathAttrSerializer::new(path_attr);
-                    self.buf.put(ps.serialize())
-                }
-            },
-            None => ()
+fn put_update_body(buf: &mut BytesMut, msg: &Update, pas: &[PathAttr]) {
+    // With the PAs as they go out on the session, see to_session_width
+    buf.put_u16(msg.withdrawn_routes_len());
+    msg.withdrawn_routes().unwrap_or_default().iter().for_each(|route| put_route(buf, route));
+    buf.put_u16(path_attrs_len(pas) as u16);
+    pas.iter().for_each(|pa| put_path_attr(buf, pa));
+    msg.nlri().unwrap_or_default().iter().for_each(|route| put_route(buf, route));
+}
+
+fn path_attrs_len(pas: &[PathAttr]) -> usize {
+    pas.iter().map(|pa| pa.attr_len_octets()).sum()
+}
+
+#[derive(Clone)]
+pub(crate) struct MessageEncoder {
+    pool: Vec<BytesMut>,
+    // How many returned buffers are kept, the rest are dropped
+    max_pooled: usize,
+    as_width: AsWidth
+}
+
+impl MessageEncoder {
+    pub fn new() -> Self {
+        Self {
+            pool: Vec::new(),
+            max_pooled: DEFAULT_POOLED,
+            as_width: AsWidth::default()
         }
-        // Finally, check to see if any NLRI need to be serialized
-        match self.msg.nlri_mut() {
-            Some(serial_vec) => {
-                for route in serial_vec {
-                    // Create RouteSerializer and serialize the Route
-                    let rs = RouteSerializer::new(route);
-                    self.buf.put(rs.serialize())
-                }
-            },
-            None => ()
+    }
+    pub fn set_as_width(&mut self, as_width: AsWidth) {
+        // Once the session has negotiated, see AsWidth::negotiated
+        self.as_width = as_width;
+    }
+    pub fn encode_update_body(&mut self, msg: &Update) -> BytesMut {
+        // Without the header, the connection frames messages itself (see transport::Codec)
+        let mut buf = self.buffer();
+        let pas = to_session_width(msg.path_attrs().unwrap_or_default(), self.as_width);
+        put_update_body(&mut buf, msg, &pas);
+        buf
+    }
+    pub fn recycle(&mut self, mut buf: BytesMut) {
+        // Takes back a buffer that has been written out. Its capacity is kept for the next message, one
+        // too small for a whole message (e.g. an Open's) isn't worth keeping.
+        if self.pool.len() < self.max_pooled && buf.capacity() >= MAX_MESSAGE_LEN {
+            buf.clear();
+            self.pool.push(buf);
         }
-        self.buf
+    }
+    fn buffer(&mut self) -> BytesMut {
+        self.pool.pop().unwrap_or_else(|| BytesMut::with_capacity(MAX_MESSAGE_LEN))
+    }
+}
 
+impl Default for MessageEncoder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-//#[cfg(test)]
-//mod tests {
-//    use crate::message_types::OpenBuilder;
-//
-//    use super::*;
-//
-//    #[test]
-//    fn test_serialize_header() {
-//        let msg = Header::new(1, MessageType::Open);
-//        let serializer = HeaderSerializer::new(msg);
-//        let correct = vec![1u8,1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 1, 1];
-//        let serialized: Vec<_> = serializer.serialize().into();
-//        assert_eq!(correct, serialized);
-//    }
-//    #[test]
-//    fn test_serialize_notification() {
-//        let code = NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::BadPeerAs);
-//        let msg = Notification::new(code, 1);
-//        let serializer = NotificationSerializer::new(msg);
-//        let correct = vec![2u8, 2, 0, 0, 0, 0, 0, 0, 0, 1];
-//        let serialized: Vec<_> = serializer.serialize().into();
-//        assert_eq!(correct, serialized);
-//    }
-//    #[test]
-//    fn test_serialize_open_no_params() {
-//        let msg = OpenBuilder::new(4, 65000, 180, 1).build();
-//        let serializer = OpenSerializer::new(msg);
-//
-//        // Build the correct byte array
-//        let mut correct: Vec<u8> = Vec::new();
-//        correct.push(4u8);
-//        correct.extend_from_slice(65000u16.to_be_bytes().as_slice());
-//        correct.extend_from_slice(180u16.to_be_bytes().as_slice());
-//        correct.extend_from_slice(1u32.to_be_bytes().as_slice());
-//        correct.push(0u8);
-//
-//        let serialized: Vec<_> = serializer.serialize().into();
-//        assert_eq!(correct, serialized);
-//    }
-//    #[test]
-//    fn test_serialize_open_with_params() {
-//        let param1 = Tlv::new(1, vec![1, 1, 1, 1, 1, 1]);
-//        let param2 = Tlv::new(1, vec![1]);
-//        let msg = OpenBuilder::new(4, 65000, 180, 1)
-//            .opt_param(param1)
-//            .opt_param(param2)
-//            .build();
-//        let serializer = OpenSerializer::new(msg);
-//        // Build the correct byte array
-//        let mut correct: Vec<u8> = Vec::new();
-//        correct.push(4u8);
-//        correct.extend_from_slice(65000u16.to_be_bytes().as_slice());
-//        correct.extend_from_slice(180u16.to_be_bytes().as_slice());
-//        correct.extend_from_slice(1u32.to_be_bytes().as_slice());
-//        correct.push(11u8);
-//        correct.push(1u8);
-//        correct.push(6u8);
-//        correct.extend_from_slice(vec![1u8,1,1,1,1,1].as_slice());
-//        correct.push(1u8);
-//        correct.push(1u8);
-//        correct.push(1u8);
-//    }
-//}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::{NotifErrorCode, OpenMsgErrSubcode},
+        message_types::{build_updates, Afi, MessageType, Nlri, OpenBuilder, Tlv, UpdateBuilder, HEADER_LEN},
+        msg_decoder::{decode_prefixes, DecodedUpdate},
+        path_attrs::*,
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        slice,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_serialize_header() {
+        let msg = Header::new(1, MessageType::Open);
+        let serializer = HeaderSerializer::new(msg);
+        let mut correct = vec![0xFFu8; 16];
+        correct.extend_from_slice(&[0, 1, 1]);
+        let serialized: Vec<_> = serializer.serialize().into();
+        assert_eq!(correct, serialized);
+    }
+    #[test]
+    fn serialize_keepalive() {
+        let serialized: Vec<_> = KeepAliveSerializer::new(KeepAlive::new()).serialize().into();
+        assert_eq!(serialized.len(), HEADER_LEN);
+        assert_eq!(&serialized[16..], &[0, 19, 4]);
+    }
+    #[test]
+    fn test_serialize_notification() {
+        let code = NotifErrorCode::OpenMessageError(OpenMsgErrSubcode::BadPeerAs);
+        let msg = Notification::new(code);
+        let serializer = NotificationSerializer::new(msg);
+        let correct = vec![2u8, 2];
+        let serialized: Vec<_> = serializer.serialize().into();
+        assert_eq!(correct, serialized);
+    }
+    #[test]
+    fn test_serialize_open_no_params() {
+        let msg = OpenBuilder::new(4, 65000, 180, 1).build();
+        let serializer = OpenSerializer::new(msg);
+
+        // Build the correct byte array
+        let mut correct: Vec<u8> = Vec::new();
+        correct.push(4u8);
+        correct.extend_from_slice(65000u16.to_be_bytes().as_slice());
+        correct.extend_from_slice(180u16.to_be_bytes().as_slice());
+        correct.extend_from_slice(1u32.to_be_bytes().as_slice());
+        correct.push(0u8);
+
+        let serialized: Vec<_> = serializer.serialize().into();
+        assert_eq!(correct, serialized);
+    }
+    #[test]
+    fn test_serialize_open_with_params() {
+        let param1 = Tlv::new(1, vec![1, 1, 1, 1, 1, 1]);
+        let param2 = Tlv::new(1, vec![1]);
+        let msg = OpenBuilder::new(4, 65000, 180, 1)
+            .opt_param(param1)
+            .opt_param(param2)
+            .build();
+        let serializer = OpenSerializer::new(msg);
+        // Build the correct byte array
+        let mut correct: Vec<u8> = Vec::new();
+        correct.push(4u8);
+        correct.extend_from_slice(65000u16.to_be_bytes().as_slice());
+        correct.extend_from_slice(180u16.to_be_bytes().as_slice());
+        correct.extend_from_slice(1u32.to_be_bytes().as_slice());
+        correct.push(11u8);
+        correct.push(1u8);
+        correct.push(6u8);
+        correct.extend_from_slice(vec![1u8,1,1,1,1,1].as_slice());
+        correct.push(1u8);
+        correct.push(1u8);
+        correct.push(1u8);
+
+        let serialized: Vec<_> = serializer.serialize().into();
+        assert_eq!(correct, serialized);
+    }
+    #[test]
+    fn serialize_route() {
+        // Only the octets the prefix length needs
+        let serialize = |route: &Route| -> Vec<u8> { RouteSerializer::new(route.clone()).serialize().into() };
+        let v4 = |len, a, b, c, d| Route::new(len, IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+        assert_eq!(serialize(&v4(0, 0, 0, 0, 0)), vec![0]);
+        assert_eq!(serialize(&v4(8, 10, 0, 0, 0)), vec![8, 10]);
+        assert_eq!(serialize(&v4(25, 192, 0, 2, 128)), vec![25, 192, 0, 2, 128]);
+        assert_eq!(serialize(&v4(32, 192, 0, 2, 1)), vec![32, 192, 0, 2, 1]);
+        let v6 = Route::new(33, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0x8000, 0, 0, 0, 0, 0)));
+        assert_eq!(serialize(&v6), vec![33, 0x20, 0x01, 0x0d, 0xb8, 0x80]);
+        assert_eq!(v6.len(), 6);
+
+        // And back
+        let routes = vec![v4(0, 0, 0, 0, 0), v4(12, 172, 16, 0, 0), v4(32, 192, 0, 2, 1)];
+        let mut buf = BytesMut::new();
+        routes.iter().for_each(|route| put_route(&mut buf, route));
+        assert_eq!(buf.len(), routes.iter().map(Route::len).sum::<usize>());
+        assert_eq!(decode_prefixes(&buf, Afi::Ipv4).unwrap(), routes);
+        let buf = serialize(&v6);
+        assert_eq!(decode_prefixes(&buf, Afi::Ipv6).unwrap(), vec![v6]);
+    }
+    #[test]
+    fn serialize_update() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let withdrawn = Route::new(16, IpAddr::V4(Ipv4Addr::new(198, 51, 0, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let msg = build_updates(vec![withdrawn], vec![Nlri::new(&[route], &[origin])]).pop().unwrap();
+        let correct = vec![
+            0u8, 3, 16, 198, 51,
+            0, 4, 0x40, 1, 1, 0,
+            24, 192, 0, 2
+        ];
+        let serialized: Vec<_> = MessageEncoder::new().encode_update_body(&msg).into();
+        assert_eq!(correct, serialized);
+    }
+    #[test]
+    fn serialize_update_as_width() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let pas = vec![
+            PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap(),
+            PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(vec![65001, 65002])]).build().unwrap(),
+            PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).build().unwrap()
+        ];
+        let update = || UpdateBuilder::new().nlri(Nlri::new(slice::from_ref(&route), &pas)).build().unwrap();
+
+        // Each AS takes 2 more octets, and comes back as it was
+        let mut encoder = MessageEncoder::new();
+        let two = encoder.encode_update_body(&update());
+        encoder.set_as_width(AsWidth::Four);
+        let four = encoder.encode_update_body(&update());
+        assert_eq!(four.len(), two.len() + 4);
+        assert_eq!(u16::from_be_bytes([four[2], four[3]]), update().total_path_attr_len() + 4);
+        let decoded = DecodedUpdate::decode_with(four.freeze(), AsWidth::Four).unwrap();
+        assert_eq!(decoded.path_attrs(), pas.as_slice());
+
+        let serialized = PathAttrSerializer::new(pas[1].clone()).session_width(AsWidth::Four).serialize();
+        assert_eq!(&serialized[..], &[0x40, AS_PATH, 10, 2, 2, 0, 0, 0xFD, 0xE9, 0, 0, 0xFD, 0xEA]);
+    }
+    #[test]
+    fn message_encoder_pool() {
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let routes: Vec<Route> = (0..=255).map(|third| Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 0, third, 0)))).collect();
+        let origin = slice::from_ref(&origin);
+        let updates = build_updates(vec![], vec![Nlri::new(&routes[..10], origin), Nlri::new(&routes[10..], origin)]);
+        let mut encoder = MessageEncoder { max_pooled: 1, ..MessageEncoder::new() };
+
+        // The body only, the lengths and ORIGIN ahead of 4 octets for each route
+        let buf = encoder.encode_update_body(&updates[0]);
+        assert_eq!(buf.len(), 2 + 2 + 4 + 10 * 4);
+
+        // A recycled buffer is handed out again, the pool keeps at most max_pooled and nothing too small
+        let ptr = buf.as_ptr();
+        encoder.recycle(buf);
+        encoder.recycle(BytesMut::with_capacity(MAX_MESSAGE_LEN));
+        encoder.recycle(BytesMut::new());
+        assert_eq!(encoder.pool.len(), 1);
+        let again = encoder.encode_update_body(&updates[1]);
+        assert_eq!(again.as_ptr(), ptr);
+        assert_eq!(encoder.pool.len(), 0);
+    }
+}
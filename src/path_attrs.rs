@@ -4,7 +4,17 @@
 // This way, we can get rid of dynamic dispatch (all will be the same size). Will be able to
 // selectively serialize based off the State.
 
+use bytes::Bytes;
+
+use crate::{
+    errors::{DecodeError, NotifErrorCode, UpdateMsgErrSubcode},
+    message_types::{Afi, Route, Safi},
+    msg_decoder::decode_prefixes,
+    msg_encoder::put_route,
+};
+
 use std::{
+    borrow::Cow,
     error::Error,
     fmt::Display,
     marker::PhantomData,
@@ -21,10 +31,32 @@ pub (crate) const MED: u8 = 4;
 pub (crate) const LOCAL_PREF: u8 = 5;
 pub (crate) const ATOMIC_AGGREGATE: u8 = 6;
 pub (crate) const AGGREGATOR: u8 = 7;
+pub (crate) const COMMUNITIES: u8 = 8;
+pub (crate) const ORIGINATOR_ID: u8 = 9;
+pub (crate) const CLUSTER_LIST: u8 = 10;
+pub (crate) const MP_REACH_NLRI: u8 = 14;
+pub (crate) const MP_UNREACH_NLRI: u8 = 15;
+pub (crate) const AS4_PATH: u8 = 17;
+pub (crate) const AS4_AGGREGATOR: u8 = 18;
+pub (crate) const PREFIX_SID: u8 = 40;
+
+// AS_PATH segment types
+const AS_SET: u8 = 1;
+const AS_SEQUENCE: u8 = 2;
+
+// ** WELL-KNOWN COMMUNITIES ** RFC 1997, Pg. 3
+pub (crate) const NO_EXPORT: u32 = 0xFFFFFF01;
+pub (crate) const NO_ADVERTISE: u32 = 0xFFFFFF02;
+pub (crate) const NO_EXPORT_SUBCONFED: u32 = 0xFFFFFF03;
+// RFC 8326
+pub (crate) const GRACEFUL_SHUTDOWN: u32 = 0xFFFF0000;
+
+// Extended Length flag. RFC 4271, Pg. 17
+pub (crate) const EXT_LEN_FLAG: u8 = 1 << 4;
 
 // Implement a basic PA error
 #[derive(Debug, PartialEq)]
-struct PathAttrError(String);
+pub struct PathAttrError(String);
 impl Display for PathAttrError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let PathAttrError(msg) = self;
@@ -35,34 +67,43 @@ impl Error for PathAttrError {}
 
 // Enum to flag whether a PA is Standard or Extended
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub(crate) enum PathAttrLen {
+pub enum PathAttrLen {
     Std(u8),
     Ext(u16),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub(crate) struct PathAttr {
+pub struct PathAttr {
     // Attribute Flags
     attr_flags: u8,
     // Attribute Type Code
     attr_type_code: u8,
     // Attribute Length; All PAs will have a u16 for the length.
     attr_len: PathAttrLen,
-    attr_value: Vec<u8>,
+    // Received values are slices of the message they came in, cloning a PA never copies it
+    attr_value: Bytes,
 }
 
 impl PathAttr {
     pub fn new(
         attr_type_code: u8,
         attr_len: PathAttrLen,
-        attr_value: Vec<u8>) -> Self {
+        attr_value: impl Into<Bytes>) -> Self {
             Self {
                 attr_flags: 0,
                 attr_type_code,
                 attr_len,
-                attr_value
+                attr_value: attr_value.into()
             }
     }
+    pub fn from_wire(attr_flags: u8, attr_type_code: u8, attr_value: Bytes) -> Self {
+        // A PA as received, the length encoding is the one the flags ask for
+        let attr_len = match attr_flags & EXT_LEN_FLAG != 0 {
+            true => PathAttrLen::Ext(attr_value.len() as u16),
+            false => PathAttrLen::Std(attr_value.len() as u8)
+        };
+        Self { attr_flags, attr_type_code, attr_len, attr_value }
+    }
     pub fn attr_type_code(&self) -> u8 {
         self.attr_type_code
     }
@@ -83,7 +124,126 @@ impl PathAttr {
         2 + attr_len + self.attr_value.len()
     }
     pub fn attr_value(&self) -> &[u8] {
-        self.attr_value.as_slice()
+        &self.attr_value[..]
+    }
+    pub fn next_hop(&self) -> Option<IpAddr> {
+        // Decodes the address if this is a NEXT_HOP PA
+        if self.attr_type_code != NEXT_HOP {
+            return None;
+        }
+        match self.attr_value.len() {
+            4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&self.attr_value);
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            },
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.attr_value);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            },
+            _ => None
+        }
+    }
+    pub fn origin(&self) -> Option<u8> {
+        match (self.attr_type_code, &self.attr_value[..]) {
+            (ORIGIN, [origin]) => Some(*origin),
+            _ => None
+        }
+    }
+    pub fn med(&self) -> Option<u32> {
+        match self.attr_type_code {
+            MED => self.u32_value(),
+            _ => None
+        }
+    }
+    pub fn local_pref(&self) -> Option<u32> {
+        match self.attr_type_code {
+            LOCAL_PREF => self.u32_value(),
+            _ => None
+        }
+    }
+    pub fn as_path(&self) -> Option<Vec<AsSegment>> {
+        // Decodes the segments if this is a well formed AS_PATH PA
+        if self.attr_type_code != AS_PATH || validate_as_path(&self.attr_value).is_err() {
+            return None;
+        }
+        let mut segments: Vec<AsSegment> = Vec::new();
+        let mut rest = &self.attr_value[..];
+        while !rest.is_empty() {
+            let seg_len = rest[1] as usize;
+            let ases: Vec<u16> = rest[2..2 + 2 * seg_len]
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            segments.push(match rest[0] {
+                AS_SET => AsSegment::AsSet(ases),
+                _ => AsSegment::AsSequence(ases)
+            });
+            rest = &rest[2 + 2 * seg_len..];
+        }
+        Some(segments)
+    }
+    pub fn communities(&self) -> Option<Vec<u32>> {
+        if self.attr_type_code != COMMUNITIES || self.attr_value.len() % 4 != 0 {
+            return None;
+        }
+        Some(
+            self.attr_value
+            .chunks(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+        )
+    }
+    pub fn originator_id(&self) -> Option<Ipv4Addr> {
+        match self.attr_type_code {
+            ORIGINATOR_ID => self.u32_value().map(Ipv4Addr::from),
+            _ => None
+        }
+    }
+    pub fn cluster_list(&self) -> Option<Vec<Ipv4Addr>> {
+        if self.attr_type_code != CLUSTER_LIST || self.attr_value.len() % 4 != 0 {
+            return None;
+        }
+        Some(
+            self.attr_value
+            .chunks(4)
+            .map(|c| Ipv4Addr::new(c[0], c[1], c[2], c[3]))
+            .collect()
+        )
+    }
+    pub fn mp_reach(&self) -> Option<(Afi, Safi, IpAddr, Vec<Route>)> {
+        // Decodes a well formed MP_REACH_NLRI PA: its address family, next hop and routes. Of a global and
+        // link-local next hop, the global one. RFC 4760, Section 3
+        if self.attr_type_code != MP_REACH_NLRI {
+            return None;
+        }
+        let (afi, safi) = mp_family(&self.attr_value)?;
+        let nh_len = *self.attr_value.get(3)? as usize;
+        let next_hop = self.attr_value.get(4..4 + nh_len)?;
+        let next_hop = match (afi, nh_len) {
+            (Afi::Ipv4, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(next_hop).ok()?)),
+            (Afi::Ipv6, 16 | 32) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&next_hop[..16]).ok()?)),
+            _ => return None
+        };
+        // Past the Reserved octet
+        let routes = decode_prefixes(self.attr_value.get(5 + nh_len..)?, afi).ok()?;
+        Some((afi, safi, next_hop, routes))
+    }
+    pub fn mp_unreach(&self) -> Option<(Afi, Safi, Vec<Route>)> {
+        // Decodes a well formed MP_UNREACH_NLRI PA. RFC 4760, Section 4
+        if self.attr_type_code != MP_UNREACH_NLRI {
+            return None;
+        }
+        let (afi, safi) = mp_family(&self.attr_value)?;
+        let routes = decode_prefixes(self.attr_value.get(3..)?, afi).ok()?;
+        Some((afi, safi, routes))
+    }
+    fn u32_value(&self) -> Option<u32> {
+        match &self.attr_value[..] {
+            [a, b, c, d] => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+            _ => None
+        }
     }
     fn set_opt_bit(&mut self) {
         // Set MSB (network byte order) to 1
@@ -97,22 +257,193 @@ impl PathAttr {
          // Set third MSB (network byte order) to 1
         self.attr_flags = self.attr_flags | 1 << 5;       
     }
+    fn set_ext_len_bit(&mut self) {
+        // Set fourth MSB (network byte order) to 1
+        self.attr_flags = self.attr_flags | 1 << 4;
+    }
+    fn clear_ext_len_bit(&mut self) {
+        self.attr_flags = self.attr_flags & !(1 << 4);
+    }
+    fn normalize_len(&mut self) {
+        // Picks the shortest length encoding that fits the value and makes the
+        // Extended Length flag agree with it.
+        match self.attr_value.len() {
+            len if len > u8::MAX as usize => {
+                self.attr_len = PathAttrLen::Ext(len as u16);
+                self.set_ext_len_bit();
+            },
+            len => {
+                self.attr_len = PathAttrLen::Std(len as u8);
+                self.clear_ext_len_bit();
+            }
+        }
+    }
+}
+
+// Puts a set of Path Attributes into their canonical form: sorted by type code, with only the
+// first occurrence of each type code kept and every length field using the shortest encoding.
+// Anything that compares or hashes sets of PAs (the PA table, AdvertisedRoutes keys, Update packing)
+// must go through this so they all agree on attribute identity.
+pub fn canonicalize_attrs(mut attrs: Vec<PathAttr>) -> Vec<PathAttr> {
+    // Stable sort, so dedup keeps the first PA received for a given type code.
+    attrs.sort_by_key(|pa| pa.attr_type_code());
+    attrs.dedup_by_key(|pa| pa.attr_type_code());
+    attrs.iter_mut().for_each(|pa| pa.normalize_len());
+    attrs
+}
+
+// Returns a copy of an AS_PATH PA with the given AS prepended to the path. The AS is added to the
+// leading AS_SEQUENCE if there is one with room, otherwise a new AS_SEQUENCE is started. RFC 4271, Pg. 29
+pub(crate) fn as_path_prepend(pa: &PathAttr, asn: u16) -> PathAttr {
+    let value = pa.attr_value();
+    let mut new_value: Vec<u8> = Vec::with_capacity(value.len() + 4);
+    match (value.first(), value.get(1)) {
+        (Some(&AS_SEQUENCE), Some(&count)) if count < u8::MAX => {
+            new_value.push(AS_SEQUENCE);
+            new_value.push(count + 1);
+            new_value.extend_from_slice(asn.to_be_bytes().as_slice());
+            new_value.extend_from_slice(&value[2..]);
+        },
+        _ => {
+            new_value.push(AS_SEQUENCE);
+            new_value.push(1);
+            new_value.extend_from_slice(asn.to_be_bytes().as_slice());
+            new_value.extend_from_slice(value);
+        }
+    }
+    let mut new_pa = PathAttr::new(AS_PATH, PathAttrLen::Std(0), new_value);
+    new_pa.attr_flags = pa.attr_flags;
+    new_pa.normalize_len();
+    new_pa
+}
+
+// Number of ASes in a path for the Decision Process, an AS_SET counts as 1. RFC 4271, Pg. 77
+pub(crate) fn as_path_len(segments: &[AsSegment]) -> usize {
+    segments
+    .iter()
+    .map(|seg| match seg {
+        AsSegment::AsSequence(ases) => ases.len(),
+        AsSegment::AsSet(_) => 1
+    })
+    .sum()
+}
+
+// Number of times an AS appears anywhere in a path, AS_SETs included
+pub(crate) fn as_path_count(segments: &[AsSegment], asn: u16) -> usize {
+    segments
+    .iter()
+    .map(|seg| match seg {
+        AsSegment::AsSequence(ases) | AsSegment::AsSet(ases) => ases.iter().filter(|a| **a == asn).count()
+    })
+    .sum()
+}
+
+// Returns a copy of an AS_PATH PA with every AS passed through f. ASes f returns None for are removed,
+// along with any segment left empty. A PA that isn't a well formed AS_PATH is returned unchanged.
+pub(crate) fn as_path_rewrite<F: Fn(u16) -> Option<u16>>(pa: &PathAttr, f: F) -> PathAttr {
+    let segments = match pa.as_path() {
+        Some(segments) => segments,
+        None => return pa.clone()
+    };
+    let rewrite = |ases: Vec<u16>| ases.into_iter().filter_map(&f).collect::<Vec<u16>>();
+    let segments: Vec<AsSegment> = segments
+        .into_iter()
+        .map(|seg| match seg {
+            AsSegment::AsSequence(ases) => AsSegment::AsSequence(rewrite(ases)),
+            AsSegment::AsSet(ases) => AsSegment::AsSet(rewrite(ases))
+        })
+        .filter(|seg| match seg {
+            AsSegment::AsSequence(ases) | AsSegment::AsSet(ases) => !ases.is_empty()
+        })
+        .collect();
+    let mut new_pa = PathAttrBuilder::<AsPath>::new()
+        .as_segments(segments)
+        .build()
+        .expect("Rewriting never lengthens a valid AS_PATH");
+    new_pa.attr_flags = pa.attr_flags;
+    new_pa.normalize_len();
+    new_pa
+}
+
+// Semantic checks for a received NEXT_HOP. RFC 4271, Pg. 33
+// The address must be unicast and must not be one of our own addresses. shared_subnet should only be
+// supplied for single-hop eBGP peers, in which case the NEXT_HOP must fall within it.
+// Returns the error to report if the NEXT_HOP is invalid. Usable by both ingest and policy code.
+pub(crate) fn validate_next_hop(
+    next_hop: IpAddr,
+    local_addrs: &[IpAddr],
+    shared_subnet: Option<&Route>) -> Result<(), NotifErrorCode> {
+        let err = NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::InvalidNextHopAttr);
+        let unicast = match next_hop {
+            IpAddr::V4(addr) => {
+                !(addr.is_unspecified() || addr.is_broadcast() || addr.is_multicast() || addr.is_loopback())
+            },
+            IpAddr::V6(addr) => {
+                !(addr.is_unspecified() || addr.is_multicast() || addr.is_loopback())
+            }
+        };
+        if !unicast || local_addrs.contains(&next_hop) {
+            return Err(err);
+        }
+        match shared_subnet {
+            Some(subnet) if !subnet.contains(next_hop) => Err(err),
+            _ => Ok(())
+        }
+}
+
+// Validates that the value of a PA has the exact length required by its type.
+fn check_len(name: &str, value: &[u8], expected: usize) -> Result<(), PathAttrError> {
+    match value.len() == expected {
+        true => Ok(()),
+        false => Err(PathAttrError(format!(
+            "{} must be {} octets, got {}", name, expected, value.len()
+        )))
+    }
+}
+
+// Walks the encoded AS_PATH segments to make sure they are structurally valid. Each segment
+// must have a known type, at least one AS, and the number of ASes must match the data that
+// follows. RFC 4271, Pg. 18
+pub(crate) fn validate_as_path(value: &[u8]) -> Result<(), PathAttrError> {
+    let mut idx = 0;
+    while idx < value.len() {
+        if idx + 2 > value.len() {
+            return Err(PathAttrError(String::from("AS_PATH segment header truncated")));
+        }
+        let seg_type = value[idx];
+        let seg_len = value[idx + 1] as usize;
+        if seg_type != AS_SET && seg_type != AS_SEQUENCE {
+            return Err(PathAttrError(format!("unknown AS_PATH segment type {}", seg_type)));
+        }
+        if seg_len == 0 {
+            return Err(PathAttrError(String::from("AS_PATH segment has no ASes")));
+        }
+        idx += 2 + 2 * seg_len;
+    }
+    match idx == value.len() {
+        true => Ok(()),
+        false => Err(PathAttrError(String::from("AS_PATH segment length does not match its data")))
+    }
 }
 
 // This trait will enforce that all impls for custom Path Attributes
 // have a build method that returns a structurally valid PA type. This
-// should greatly simplify the API.
-pub(crate) trait PaBuilder {
-    fn build(self) -> PathAttr;
+// should greatly simplify the API. Building fails if mandatory values
+// were never supplied or the value is malformed.
+pub trait PaBuilder {
+    fn build(self) -> Result<PathAttr, PathAttrError>;
 }
 // This is a generic builder that can be used over any custom Path Attribute type.
 // May add a trait bound later that requires that requires each impl to have a build()
 // method.
-pub(crate) struct PathAttrBuilder<T> {
+pub struct PathAttrBuilder<T> {
     _marker: PhantomData<T>,
     attr_type_code: u8,
     attr_len: PathAttrLen,
     attr_value: Vec<u8>,
+    // Setters can't fail, so the first invalid value they see is kept here
+    // and reported by build().
+    err: Option<PathAttrError>,
 }
 
 impl<T> PathAttrBuilder<T> {
@@ -121,18 +452,30 @@ impl<T> PathAttrBuilder<T> {
             _marker: PhantomData,
             attr_type_code: 0,
             attr_len: PathAttrLen::Std(0),
-            attr_value: Vec::new()
+            attr_value: Vec::new(),
+            err: None
+        }
+    }
+    fn set_err(&mut self, err: PathAttrError) {
+        if self.err.is_none() {
+            self.err = Some(err);
         }
     }
 }
 
+impl<T> Default for PathAttrBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // ** Individual Path Attribute Definitions for those defined in RFC4271 **
 
 // ** ORIGIN **
-pub(crate) struct Origin;
+pub struct Origin;
 
-#[derive(Debug, Clone)]
-pub(crate) enum OriginValue {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OriginValue {
     Igp,
     Egp,
     Incomplete
@@ -148,6 +491,18 @@ impl From<OriginValue> for u8 {
     }
 }
 
+impl TryFrom<u8> for OriginValue {
+    type Error = u8;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(OriginValue::Igp),
+            1 => Ok(OriginValue::Egp),
+            2 => Ok(OriginValue::Incomplete),
+            _ => Err(value)
+        }
+    }
+}
+
 impl PathAttrBuilder<Origin> {
     pub fn origin(mut self, val: OriginValue) -> Self {
         self.attr_value.push(val.into());
@@ -156,27 +511,43 @@ impl PathAttrBuilder<Origin> {
 }
 
 impl PaBuilder for PathAttrBuilder<Origin> {
-    fn build(self) -> PathAttr {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        check_len("ORIGIN", &self.attr_value, 1)?;
+        if self.attr_value[0] > 2 {
+            return Err(PathAttrError(format!("invalid ORIGIN value {}", self.attr_value[0])));
+        }
         let mut pa = PathAttr::new(
             1,
             PathAttrLen::Std(1),
             self.attr_value
         );
         pa.set_trans_bit();
-        pa
+        Ok(pa)
     }
 }
 
 // ** AS_PATH **
 
-pub(crate) struct AsPath;
-enum AsSegment {
+pub struct AsPath;
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsSegment {
     // Used when building the AS_PATH PA. RFC 4721, Pg. 18
     // The vec holds ASes.
     AsSequence(Vec<u16>),
     AsSet(Vec<u16>)
 }
 
+impl Display for AsSegment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Sequences are space separated, sets are wrapped in braces. e.g. "65001 65002" or "{1 2}"
+        let join = |ases: &[u16]| ases.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(" ");
+        match self {
+            AsSegment::AsSequence(ases) => write!(f, "{}", join(ases)),
+            AsSegment::AsSet(ases) => write!(f, "{{{}}}", join(ases))
+        }
+    }
+}
+
 impl PathAttrBuilder<AsPath> {
     pub fn as_segments(mut self, val: Vec<AsSegment>) -> Self {
         // Need to decompose the Vec<AsSegments> into a Vec<u8> to conform
@@ -187,7 +558,7 @@ impl PathAttrBuilder<AsPath> {
             match seg {
                 AsSegment::AsSequence(ases) => {
                     // AS_SEQUENCE segment type is 2
-                    self.attr_value.push(2);
+                    self.attr_value.push(AS_SEQUENCE);
                     self.attr_value.push(ases.len() as u8);
                     for a in ases {
                         // Decompose the u16 to two u8s and add to vec
@@ -196,7 +567,7 @@ impl PathAttrBuilder<AsPath> {
                 },
                 AsSegment::AsSet(ases) => {
                     // AS_SET segment type is 1
-                    self.attr_value.push(1);
+                    self.attr_value.push(AS_SET);
                     self.attr_value.push(ases.len() as u8);
                     for a in ases {
                         // Decompose the u16 to two u8s and add to vec
@@ -210,20 +581,36 @@ impl PathAttrBuilder<AsPath> {
 }
 
 impl PaBuilder for PathAttrBuilder<AsPath> {
-    fn build(self) -> PathAttr {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        // An empty AS_PATH is valid (e.g. locally originated routes), but any segments
+        // present must be well formed. Segments with more than 255 ASes are caught here
+        // since the encoded count won't match the data.
+        validate_as_path(&self.attr_value)?;
+        if self.attr_value.len() > u16::MAX as usize {
+            return Err(PathAttrError(String::from("AS_PATH is too long to encode")));
+        }
+        // Long paths need the extended length field.
+        let attr_len = match self.attr_value.len() {
+            len if len > u8::MAX as usize => PathAttrLen::Ext(len as u16),
+            len => PathAttrLen::Std(len as u8)
+        };
+        let ext = matches!(attr_len, PathAttrLen::Ext(_));
         let mut pa = PathAttr::new(
             2,
-            PathAttrLen::Std(self.attr_value.len() as u8),
+            attr_len,
             self.attr_value
         );
         pa.set_trans_bit();
-        pa
+        if ext {
+            pa.set_ext_len_bit();
+        }
+        Ok(pa)
     }
 }
 
 // ** NEXT_HOP **
 
-pub(crate) struct NextHop;
+pub struct NextHop;
 
 impl PathAttrBuilder<NextHop> {
     pub fn next_hop(mut self, val: IpAddr) -> Self {
@@ -242,19 +629,25 @@ impl PathAttrBuilder<NextHop> {
 }
 
 impl PaBuilder for PathAttrBuilder<NextHop> {
-    fn build(self) -> PathAttr {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        // Either a v4 or v6 address must have been supplied (exactly once).
+        if self.attr_value.len() != 4 && self.attr_value.len() != 16 {
+            return Err(PathAttrError(format!(
+                "NEXT_HOP must be 4 or 16 octets, got {}", self.attr_value.len()
+            )));
+        }
         let mut pa = PathAttr::new(
             3,
             self.attr_len,
             self.attr_value);
         pa.set_trans_bit();
-        pa
+        Ok(pa)
     }
 }
 
 // ** MED **
 
-pub(crate) struct Med;
+pub struct Med;
 impl PathAttrBuilder<Med> {
     pub fn metric(mut self, val: u32) -> Self {
         // Builds the optional, non-transitory PA MULTI_EXIT_DISC (MED)
@@ -266,20 +659,21 @@ impl PathAttrBuilder<Med> {
     }
 }
 impl PaBuilder for PathAttrBuilder<Med> {
-    fn build(self) -> PathAttr {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        check_len("MULTI_EXIT_DISC", &self.attr_value, 4)?;
         let mut pa = PathAttr::new(
             4,
             PathAttrLen::Std(4),
             self.attr_value);
         pa.set_opt_bit();
-        pa
+        Ok(pa)
     }
     
 }
 
 // ** LOCAL_PREF **
 
-pub(crate) struct LocalPref;
+pub struct LocalPref;
 impl PathAttrBuilder<LocalPref> {
     pub fn local_pref(mut self, val: u32) -> Self {
         self.attr_value.extend_from_slice(val.to_be_bytes().as_slice());
@@ -288,22 +682,23 @@ impl PathAttrBuilder<LocalPref> {
 }
 
 impl PaBuilder for PathAttrBuilder<LocalPref> {
-    fn build(self) -> PathAttr {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        check_len("LOCAL_PREF", &self.attr_value, 4)?;
         let mut pa = PathAttr::new(
             5,
             PathAttrLen::Std(4),
             self.attr_value);
         pa.set_trans_bit();
-        pa
+        Ok(pa)
     }
     
 }
 
 // ** ATOMIC_AGGREGATE **
 
-pub(crate) struct AtomicAggregate;
+pub struct AtomicAggregate;
 impl PaBuilder for PathAttrBuilder<AtomicAggregate> {
-    fn build(self) -> PathAttr {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
         // Builds the well-known, discretionary ATOMIC_AGGREGATE PA
         // RFC 4271, Pg. 19. This is essentially a marker PA.
         let mut pa = PathAttr::new(
@@ -311,13 +706,13 @@ impl PaBuilder for PathAttrBuilder<AtomicAggregate> {
             PathAttrLen::Std(0),
             self.attr_value);
         pa.set_trans_bit();
-        pa
+        Ok(pa)
     }
 }
 
 
 // ** AGGREGATOR **
-pub(crate) struct Aggregator;
+pub struct Aggregator;
 impl PathAttrBuilder<Aggregator> {
     pub fn aggregator(mut self, last_as: u16, speaker: Ipv4Addr) -> Self {
         // Append Last AS
@@ -328,14 +723,509 @@ impl PathAttrBuilder<Aggregator> {
     }
 }
 impl PaBuilder for PathAttrBuilder<Aggregator> {
-    fn build(self) -> PathAttr {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        check_len("AGGREGATOR", &self.attr_value, 6)?;
         let mut pa = PathAttr::new(
             7,
             PathAttrLen::Std(6),
             self.attr_value);
         pa.set_trans_bit();
         pa.set_opt_bit();
-        pa
+        Ok(pa)
+    }
+}
+
+// ** COMMUNITIES ** RFC 1997
+// Optional, transitive. The value is a list of 4 octet communities.
+pub struct Communities;
+impl PathAttrBuilder<Communities> {
+    pub fn communities(mut self, val: Vec<u32>) -> Self {
+        val
+        .iter()
+        .for_each(|community| self.attr_value.extend_from_slice(community.to_be_bytes().as_slice()));
+        self
+    }
+}
+impl PaBuilder for PathAttrBuilder<Communities> {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        if self.attr_value.is_empty() {
+            return Err(PathAttrError(String::from("COMMUNITIES requires at least one community")));
+        }
+        if self.attr_value.len() > u16::MAX as usize {
+            return Err(PathAttrError(String::from("COMMUNITIES is too long to encode")));
+        }
+        let mut pa = PathAttr::new(COMMUNITIES, PathAttrLen::Std(0), self.attr_value);
+        pa.set_opt_bit();
+        pa.set_trans_bit();
+        pa.normalize_len();
+        Ok(pa)
+    }
+}
+
+// ** ORIGINATOR_ID ** RFC 4456
+// Optional, non-transitive. BGP Identifier of the route's originator within the AS.
+pub(crate) struct OriginatorId;
+impl PathAttrBuilder<OriginatorId> {
+    pub fn originator_id(mut self, val: Ipv4Addr) -> Self {
+        self.attr_value.extend_from_slice(val.octets().as_slice());
+        self
+    }
+}
+impl PaBuilder for PathAttrBuilder<OriginatorId> {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        check_len("ORIGINATOR_ID", &self.attr_value, 4)?;
+        let mut pa = PathAttr::new(ORIGINATOR_ID, PathAttrLen::Std(4), self.attr_value);
+        pa.set_opt_bit();
+        Ok(pa)
+    }
+}
+
+// ** CLUSTER_LIST ** RFC 4456
+// Optional, non-transitive. The CLUSTER_IDs of the reflection path, most recent first.
+pub(crate) struct ClusterList;
+impl PathAttrBuilder<ClusterList> {
+    pub fn cluster_ids(mut self, val: Vec<Ipv4Addr>) -> Self {
+        val
+        .iter()
+        .for_each(|id| self.attr_value.extend_from_slice(id.octets().as_slice()));
+        self
+    }
+}
+impl PaBuilder for PathAttrBuilder<ClusterList> {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        if self.attr_value.is_empty() {
+            return Err(PathAttrError(String::from("CLUSTER_LIST requires at least one CLUSTER_ID")));
+        }
+        if self.attr_value.len() > u16::MAX as usize {
+            return Err(PathAttrError(String::from("CLUSTER_LIST is too long to encode")));
+        }
+        let mut pa = PathAttr::new(CLUSTER_LIST, PathAttrLen::Std(0), self.attr_value);
+        pa.set_opt_bit();
+        pa.normalize_len();
+        Ok(pa)
+    }
+}
+
+// ** MP_REACH_NLRI ** RFC 4760
+// Optional, non-transitive. Routes the NLRI field can't carry (IPv6 ones in particular) with a next hop of
+// their family: AFI, SAFI, the next hop and its length, a Reserved octet and then the prefixes.
+pub(crate) struct MpReachNlri;
+impl PathAttrBuilder<MpReachNlri> {
+    pub fn reach(mut self, safi: Safi, next_hop: IpAddr, routes: &[Route]) -> Self {
+        let afi = match mp_afi(routes) {
+            Ok(afi) => afi,
+            Err(err) => {
+                self.set_err(err);
+                return self;
+            }
+        };
+        let next_hop = match (afi, next_hop) {
+            (Afi::Ipv4, IpAddr::V4(addr)) => addr.octets().to_vec(),
+            (Afi::Ipv6, IpAddr::V6(addr)) => addr.octets().to_vec(),
+            // An IPv4 next hop for IPv6 routes goes as an IPv4-mapped IPv6 address. RFC 4291, Section 2.5.5.2
+            (Afi::Ipv6, IpAddr::V4(addr)) => addr.to_ipv6_mapped().octets().to_vec(),
+            (Afi::Ipv4, IpAddr::V6(addr)) => {
+                self.set_err(PathAttrError(format!("IPv6 next hop {} for IPv4 routes", addr)));
+                return self;
+            }
+        };
+        put_mp_family(&mut self.attr_value, afi, safi);
+        self.attr_value.push(next_hop.len() as u8);
+        self.attr_value.extend_from_slice(&next_hop);
+        self.attr_value.push(0);
+        routes.iter().for_each(|route| put_route(&mut self.attr_value, route));
+        self
+    }
+}
+impl PaBuilder for PathAttrBuilder<MpReachNlri> {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        if let Some(err) = self.err {
+            return Err(err);
+        }
+        if self.attr_value.is_empty() {
+            return Err(PathAttrError(String::from("MP_REACH_NLRI requires at least one route")));
+        }
+        if self.attr_value.len() > u16::MAX as usize {
+            return Err(PathAttrError(String::from("MP_REACH_NLRI is too long to encode")));
+        }
+        let mut pa = PathAttr::new(MP_REACH_NLRI, PathAttrLen::Std(0), self.attr_value);
+        pa.set_opt_bit();
+        pa.normalize_len();
+        Ok(pa)
+    }
+}
+
+// ** MP_UNREACH_NLRI ** RFC 4760
+// Optional, non-transitive. Withdrawals of routes the Withdrawn Routes field can't carry: AFI, SAFI and
+// then the prefixes.
+pub(crate) struct MpUnreachNlri;
+impl PathAttrBuilder<MpUnreachNlri> {
+    pub fn unreach(mut self, safi: Safi, routes: &[Route]) -> Self {
+        match mp_afi(routes) {
+            Ok(afi) => {
+                put_mp_family(&mut self.attr_value, afi, safi);
+                routes.iter().for_each(|route| put_route(&mut self.attr_value, route));
+            },
+            Err(err) => self.set_err(err)
+        }
+        self
+    }
+}
+impl PaBuilder for PathAttrBuilder<MpUnreachNlri> {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        if let Some(err) = self.err {
+            return Err(err);
+        }
+        if self.attr_value.is_empty() {
+            return Err(PathAttrError(String::from("MP_UNREACH_NLRI requires at least one route")));
+        }
+        if self.attr_value.len() > u16::MAX as usize {
+            return Err(PathAttrError(String::from("MP_UNREACH_NLRI is too long to encode")));
+        }
+        let mut pa = PathAttr::new(MP_UNREACH_NLRI, PathAttrLen::Std(0), self.attr_value);
+        pa.set_opt_bit();
+        pa.normalize_len();
+        Ok(pa)
+    }
+}
+
+fn mp_afi(routes: &[Route]) -> Result<Afi, PathAttrError> {
+    // The one address family of the routes
+    match routes.first().map(Route::afi) {
+        Some(afi) if routes.iter().all(|route| route.afi() == afi) => Ok(afi),
+        Some(_) => Err(PathAttrError(String::from("routes of more than one address family"))),
+        None => Err(PathAttrError(String::from("no routes")))
+    }
+}
+
+fn mp_family(value: &[u8]) -> Option<(Afi, Safi)> {
+    match value {
+        [high, low, safi, ..] => Some((Afi::try_from(u16::from_be_bytes([*high, *low])).ok()?, Safi::try_from(*safi).ok()?)),
+        _ => None
+    }
+}
+
+fn put_mp_family(value: &mut Vec<u8>, afi: Afi, safi: Safi) {
+    value.extend_from_slice(&u16::from(&afi).to_be_bytes());
+    value.push(u8::from(&safi));
+}
+
+// ** AS4_PATH and AS4_AGGREGATOR ** RFC 6793
+// Optional, transitive. ASes are kept 2 octets wide (see AsSegment), one that doesn't fit is AS_TRANS in
+// AS_PATH and AGGREGATOR, with the 4-octet path and aggregator alongside in these. That's how they go to
+// a speaker without the 4-octet AS capability. A session with it takes AS_PATH and AGGREGATOR with
+// 4-octet ASes instead and no AS4_PATH or AS4_AGGREGATOR, see to_session_width and from_session_width.
+
+// Stands in for a 4-octet AS where only 2 octets fit. RFC 6793, Pg. 3
+pub (crate) const AS_TRANS: u16 = 23456;
+
+// How wide the ASes in AS_PATH and AGGREGATOR are on the wire
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum AsWidth {
+    #[default]
+    Two,
+    // Both sides have the 4-octet AS capability. RFC 6793, Pg. 3
+    Four
+}
+
+impl AsWidth {
+    pub fn negotiated(four_octet_as: bool) -> Self {
+        match four_octet_as {
+            true => AsWidth::Four,
+            false => AsWidth::Two
+        }
+    }
+    fn octets(&self) -> usize {
+        match self {
+            AsWidth::Two => 2,
+            AsWidth::Four => 4
+        }
+    }
+}
+
+// Segment types and their ASes, whatever their width on the wire
+type Segments = Vec<(u8, Vec<u32>)>;
+
+pub(crate) fn to_session_width(pas: &[PathAttr], width: AsWidth) -> Cow<'_, [PathAttr]> {
+    // The PAs as they go out on a session. With 4-octet ASes the real path is merged back into AS_PATH
+    // from AS4_PATH, and the real aggregator into AGGREGATOR. RFC 6793, Pg. 6
+    if width == AsWidth::Two {
+        return Cow::Borrowed(pas);
+    }
+    let find = |code: u8| pas.iter().find(|pa| pa.attr_type_code() == code);
+    let aggregator_as = find(AGGREGATOR).and_then(|pa| match pa.attr_value() {
+        [high, low, _, _, _, _] => Some(u16::from_be_bytes([*high, *low])),
+        _ => None
+    });
+    // Unless an old speaker aggregated the path, then they're out of date
+    let (as4_path, as4_aggregator) = match aggregator_as.is_some_and(|asn| asn != AS_TRANS) {
+        true => (None, None),
+        false => (
+            find(AS4_PATH).and_then(|pa| read_segments(pa.attr_value(), AsWidth::Four)),
+            find(AS4_AGGREGATOR).map(|pa| pa.attr_value()).filter(|value| value.len() == 8)
+        )
+    };
+    let pas = pas
+        .iter()
+        .filter(|pa| pa.attr_type_code() != AS4_PATH && pa.attr_type_code() != AS4_AGGREGATOR)
+        .map(|pa| match (pa.attr_type_code(), pa.attr_value()) {
+            (AS_PATH, value) => match read_segments(value, AsWidth::Two) {
+                Some(segments) => {
+                    let segments = match &as4_path {
+                        Some(as4_path) => merge_as4_path(segments, as4_path.clone()),
+                        None => segments
+                    };
+                    width_pa(pa, AS_PATH, write_segments(&segments, AsWidth::Four))
+                },
+                None => pa.clone()
+            },
+            (AGGREGATOR, [high, low, id @ ..]) if id.len() == 4 => {
+                let value = match (u16::from_be_bytes([*high, *low]), as4_aggregator) {
+                    (AS_TRANS, Some(as4_aggregator)) => as4_aggregator.to_vec(),
+                    (asn, _) => [(asn as u32).to_be_bytes().as_slice(), id].concat()
+                };
+                width_pa(pa, AGGREGATOR, value)
+            },
+            _ => pa.clone()
+        })
+        .collect();
+    Cow::Owned(pas)
+}
+
+pub(crate) fn from_session_width(pas: Vec<PathAttr>, width: AsWidth) -> Result<Vec<PathAttr>, DecodeError> {
+    // The PAs as received on a session, in the form they're kept in. 4-octet ASes that don't fit become
+    // AS_TRANS, with AS4_PATH and AS4_AGGREGATOR added for them. Any AS4_PATH or AS4_AGGREGATOR that came
+    // with them is discarded, a speaker with the capability doesn't send them. RFC 6793, Pg. 5
+    if width == AsWidth::Two {
+        return Ok(pas);
+    }
+    let mut kept: Vec<PathAttr> = Vec::with_capacity(pas.len() + 1);
+    for pa in pas {
+        match (pa.attr_type_code(), pa.attr_value()) {
+            (AS4_PATH | AS4_AGGREGATOR, _) => (),
+            (AS_PATH, value) => {
+                let segments = read_segments(value, AsWidth::Four)
+                    .ok_or(DecodeError::from(UpdateMsgErrSubcode::MalformedAsPath))?;
+                kept.push(width_pa(&pa, AS_PATH, write_segments(&segments, AsWidth::Two)));
+                if segments.iter().flat_map(|(_, ases)| ases).any(|asn| *asn > u16::MAX as u32) {
+                    kept.push(as4_pa(AS4_PATH, write_segments(&segments, AsWidth::Four)));
+                }
+            },
+            (AGGREGATOR, [a, b, c, d, id @ ..]) if id.len() == 4 => {
+                let asn = u32::from_be_bytes([*a, *b, *c, *d]);
+                let narrow = u16::try_from(asn).unwrap_or(AS_TRANS);
+                kept.push(width_pa(&pa, AGGREGATOR, [narrow.to_be_bytes().as_slice(), id].concat()));
+                if asn > u16::MAX as u32 {
+                    kept.push(as4_pa(AS4_AGGREGATOR, pa.attr_value().to_vec()));
+                }
+            },
+            _ => kept.push(pa)
+        }
+    }
+    Ok(kept)
+}
+
+fn read_segments(value: &[u8], width: AsWidth) -> Option<Segments> {
+    // None unless every segment is well formed, as validate_as_path checks
+    let octets = width.octets();
+    let mut segments: Segments = Vec::new();
+    let mut rest = value;
+    while let [seg_type, count, tail @ ..] = rest {
+        let len = *count as usize * octets;
+        if (*seg_type != AS_SET && *seg_type != AS_SEQUENCE) || *count == 0 || tail.len() < len {
+            return None;
+        }
+        let ases = tail[..len]
+            .chunks(octets)
+            .map(|asn| asn.iter().fold(0u32, |acc, octet| acc << 8 | *octet as u32))
+            .collect();
+        segments.push((*seg_type, ases));
+        rest = &tail[len..];
+    }
+    match rest.is_empty() {
+        true => Some(segments),
+        false => None
+    }
+}
+
+fn write_segments(segments: &[(u8, Vec<u32>)], width: AsWidth) -> Vec<u8> {
+    let mut value: Vec<u8> = Vec::new();
+    for (seg_type, ases) in segments {
+        value.push(*seg_type);
+        value.push(ases.len() as u8);
+        for asn in ases {
+            match width {
+                AsWidth::Two => value.extend_from_slice(&u16::try_from(*asn).unwrap_or(AS_TRANS).to_be_bytes()),
+                AsWidth::Four => value.extend_from_slice(&asn.to_be_bytes())
+            }
+        }
+    }
+    value
+}
+
+fn merge_as4_path(as_path: Segments, as4_path: Segments) -> Segments {
+    // AS4_PATH, behind the leading ASes of AS_PATH it doesn't have (added by speakers without the
+    // capability). One longer than AS_PATH is ignored. RFC 6793, Pg. 7
+    let len = |segments: &Segments| -> usize {
+        segments.iter().map(|(seg_type, ases)| match *seg_type {
+            AS_SET => 1,
+            _ => ases.len()
+        }).sum()
+    };
+    let (path_len, as4_len) = (len(&as_path), len(&as4_path));
+    if as4_len > path_len {
+        return as_path;
+    }
+    let mut leading = path_len - as4_len;
+    let mut merged: Segments = Vec::new();
+    for (seg_type, mut ases) in as_path {
+        if leading == 0 {
+            break;
+        }
+        match seg_type {
+            AS_SET => leading -= 1,
+            _ => {
+                ases.truncate(leading);
+                leading -= ases.len();
+            }
+        }
+        merged.push((seg_type, ases));
+    }
+    // Joined into one AS_SEQUENCE where they meet, if it fits
+    for (seg_type, ases) in as4_path {
+        match merged.last_mut() {
+            Some((AS_SEQUENCE, last)) if seg_type == AS_SEQUENCE && last.len() + ases.len() <= u8::MAX as usize => last.extend(ases),
+            _ => merged.push((seg_type, ases))
+        }
+    }
+    merged
+}
+
+fn width_pa(pa: &PathAttr, type_code: u8, value: Vec<u8>) -> PathAttr {
+    // The PA with its value in the other width, flags kept
+    let mut new_pa = PathAttr::new(type_code, PathAttrLen::Std(0), value);
+    new_pa.attr_flags = pa.attr_flags;
+    new_pa.normalize_len();
+    new_pa
+}
+
+fn as4_pa(type_code: u8, value: Vec<u8>) -> PathAttr {
+    let mut pa = PathAttr::new(type_code, PathAttrLen::Std(0), value);
+    pa.set_opt_bit();
+    pa.set_trans_bit();
+    pa.normalize_len();
+    pa
+}
+
+// ** BGP Prefix-SID ** RFC 8669
+// Optional, transitive. The value is a sequence of TLVs with a 1 octet type and 2 octet length.
+const PREFIX_SID_LABEL_INDEX: u8 = 1;
+const PREFIX_SID_ORIGINATOR_SRGB: u8 = 3;
+
+pub(crate) struct PrefixSid;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PrefixSidTlv {
+    LabelIndex { flags: u16, label_index: u32 },
+    // Each range is an SRGB base and its size, both 3 octets on the wire
+    OriginatorSrgb { flags: u16, ranges: Vec<(u32, u32)> },
+    // Unrecognized TLVs are kept so they can be passed along unchanged
+    Unknown(u8, Vec<u8>)
+}
+
+impl PathAttrBuilder<PrefixSid> {
+    pub fn label_index(mut self, label_index: u32) -> Self {
+        // Label-Index TLV; Reserved (1), Flags (2), Label Index (4)
+        self.attr_value.push(PREFIX_SID_LABEL_INDEX);
+        self.attr_value.extend_from_slice(7u16.to_be_bytes().as_slice());
+        self.attr_value.push(0);
+        self.attr_value.extend_from_slice(0u16.to_be_bytes().as_slice());
+        self.attr_value.extend_from_slice(label_index.to_be_bytes().as_slice());
+        self
+    }
+    pub fn originator_srgb(mut self, ranges: Vec<(u32, u32)>) -> Self {
+        // Originator SRGB TLV; Flags (2) followed by 6 octets per range
+        self.attr_value.push(PREFIX_SID_ORIGINATOR_SRGB);
+        self.attr_value.extend_from_slice(((2 + 6 * ranges.len()) as u16).to_be_bytes().as_slice());
+        self.attr_value.extend_from_slice(0u16.to_be_bytes().as_slice());
+        for (base, range) in ranges {
+            if base > 0xFFFFFF || range > 0xFFFFFF {
+                self.set_err(PathAttrError(format!("SRGB ({}, {}) doesn't fit in 3 octets", base, range)));
+            }
+            // Only the low 3 octets are encoded
+            self.attr_value.extend_from_slice(&base.to_be_bytes()[1..]);
+            self.attr_value.extend_from_slice(&range.to_be_bytes()[1..]);
+        }
+        self
+    }
+}
+
+impl PaBuilder for PathAttrBuilder<PrefixSid> {
+    fn build(self) -> Result<PathAttr, PathAttrError> {
+        // Must contain at least one TLV and every TLV must be well formed.
+        // The Label-Index TLV is mandatory. RFC 8669, Pg. 6
+        if let Some(err) = self.err {
+            return Err(err);
+        }
+        let tlvs = decode_prefix_sid(&self.attr_value)?;
+        if !tlvs.iter().any(|tlv| matches!(tlv, PrefixSidTlv::LabelIndex { .. })) {
+            return Err(PathAttrError(String::from("Prefix-SID requires a Label-Index TLV")));
+        }
+        let mut pa = PathAttr::new(PREFIX_SID, PathAttrLen::Std(0), self.attr_value);
+        pa.set_opt_bit();
+        pa.set_trans_bit();
+        pa.normalize_len();
+        Ok(pa)
+    }
+}
+
+pub(crate) fn decode_prefix_sid(value: &[u8]) -> Result<Vec<PrefixSidTlv>, PathAttrError> {
+    // Decodes the TLVs in a Prefix-SID value.
+    let mut tlvs: Vec<PrefixSidTlv> = Vec::new();
+    let mut rest = value;
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            return Err(PathAttrError(String::from("Prefix-SID TLV header truncated")));
+        }
+        let tlv_type = rest[0];
+        let tlv_len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        if rest.len() < 3 + tlv_len {
+            return Err(PathAttrError(String::from("Prefix-SID TLV value truncated")));
+        }
+        let tlv_value = &rest[3..3 + tlv_len];
+        let tlv = match tlv_type {
+            PREFIX_SID_LABEL_INDEX => {
+                if tlv_len != 7 {
+                    return Err(PathAttrError(format!("Label-Index TLV must be 7 octets, got {}", tlv_len)));
+                }
+                PrefixSidTlv::LabelIndex {
+                    flags: u16::from_be_bytes([tlv_value[1], tlv_value[2]]),
+                    label_index: u32::from_be_bytes([tlv_value[3], tlv_value[4], tlv_value[5], tlv_value[6]])
+                }
+            },
+            PREFIX_SID_ORIGINATOR_SRGB => {
+                if tlv_len < 2 + 6 || (tlv_len - 2) % 6 != 0 {
+                    return Err(PathAttrError(format!("invalid Originator SRGB TLV length {}", tlv_len)));
+                }
+                let ranges = tlv_value[2..]
+                    .chunks(6)
+                    .map(|c| {
+                        (u32::from_be_bytes([0, c[0], c[1], c[2]]), u32::from_be_bytes([0, c[3], c[4], c[5]]))
+                    })
+                    .collect();
+                PrefixSidTlv::OriginatorSrgb {
+                    flags: u16::from_be_bytes([tlv_value[0], tlv_value[1]]),
+                    ranges
+                }
+            },
+            _ => PrefixSidTlv::Unknown(tlv_type, tlv_value.to_vec())
+        };
+        tlvs.push(tlv);
+        rest = &rest[3 + tlv_len..];
+    }
+    match tlvs.is_empty() {
+        true => Err(PathAttrError(String::from("Prefix-SID has no TLVs"))),
+        false => Ok(tlvs)
     }
 }
 
@@ -347,7 +1237,7 @@ mod tests {
     fn build_origin() {
         let variants = vec![OriginValue::Igp, OriginValue::Egp, OriginValue::Incomplete];
         for (idx, v) in variants.into_iter().enumerate() {
-            let origin = PathAttrBuilder::<Origin>::new().origin(v).build();
+            let origin = PathAttrBuilder::<Origin>::new().origin(v).build().unwrap();
             assert_eq!(64, origin.attr_flags);
             assert_eq!(1, origin.attr_type_code);
             assert_eq!(PathAttrLen::Std(1), origin.attr_len);
@@ -359,7 +1249,7 @@ mod tests {
     fn build_as_path() {
         // Create a sequence of AS Segments. One AS_SET and one AS_SEQUENCE
         let as_segs = vec![AsSegment::AsSet(vec![65000u16, 65001]), AsSegment::AsSequence(vec![131u16, 30437])];
-        let aspath = PathAttrBuilder::<AsPath>::new().as_segments(as_segs).build();
+        let aspath = PathAttrBuilder::<AsPath>::new().as_segments(as_segs).build().unwrap();
 
         // Verify the path attr values are correctly encoded.
         // Path Attr checks
@@ -383,14 +1273,14 @@ mod tests {
     #[test]
     fn build_next_hop_v4() {
         let ip = IpAddr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap());
-        let n_hop = PathAttrBuilder::<NextHop>::new().next_hop(ip).build();
+        let n_hop = PathAttrBuilder::<NextHop>::new().next_hop(ip).build().unwrap();
 
         // Path Attr checks
         assert_eq!(n_hop.attr_flags, 64u8);
         assert_eq!(n_hop.attr_type_code, 3u8);
         assert_eq!(n_hop.attr_len, PathAttrLen::Std(4));
         let mut bytes = [0u8; 4];
-        bytes.copy_from_slice(n_hop.attr_value.as_slice());
+        bytes.copy_from_slice(&n_hop.attr_value[..]);
         assert_eq!(Ipv4Addr::from(bytes), Ipv4Addr::from_str("192.168.0.0").unwrap());
     }
 
@@ -398,7 +1288,7 @@ mod tests {
     fn build_next_hop_v6() {
         // Using Ipv6 Neighbor Solicitation dest address (multicast) because why not?
         let ip = IpAddr::V6(Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0x0001, 0xFFCC, 0xCCCC));
-        let n_hop = PathAttrBuilder::<NextHop>::new().next_hop(ip).build();
+        let n_hop = PathAttrBuilder::<NextHop>::new().next_hop(ip).build().unwrap();
 
         // Path n_hop.attr checks
         assert_eq!(n_hop.attr_flags, 64u8);
@@ -407,15 +1297,41 @@ mod tests {
 
         // Cumbersome to build an Ipv6Addr, so will just compare the octets.
         if let IpAddr::V6(inner) = ip {
-            assert_eq!(n_hop.attr_value, inner.octets());
+            assert_eq!(n_hop.attr_value, inner.octets()[..]);
         } else {
             panic!()
         }
     }
 
+    #[test]
+    fn build_mp_reach() {
+        let routes = vec![
+            Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0))),
+            Route::new(0, IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        ];
+        let next_hop = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let reach = PathAttrBuilder::<MpReachNlri>::new().reach(Safi::Unicast, next_hop, &routes).build().unwrap();
+        assert_eq!((reach.attr_flags, reach.attr_type_code), (128, MP_REACH_NLRI));
+        // AFI 2, SAFI 1, the next hop, Reserved and then only the octets each prefix needs
+        assert_eq!(&reach.attr_value[..4], &[0, 2, 1, 16]);
+        assert_eq!(&reach.attr_value[20..], &[0, 32, 0x20, 0x01, 0x0d, 0xb8, 0]);
+        assert_eq!(reach.mp_reach(), Some((Afi::Ipv6, Safi::Unicast, next_hop, routes.clone())));
+
+        let unreach = PathAttrBuilder::<MpUnreachNlri>::new().unreach(Safi::Multicast, &routes).build().unwrap();
+        assert_eq!((unreach.attr_flags, unreach.attr_type_code), (128, MP_UNREACH_NLRI));
+        assert_eq!(unreach.mp_unreach(), Some((Afi::Ipv6, Safi::Multicast, routes.clone())));
+        assert_eq!(unreach.mp_reach(), None);
+
+        // The next hop has to suit the routes, which have to be of one family
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        assert!(PathAttrBuilder::<MpReachNlri>::new().reach(Safi::Unicast, next_hop, std::slice::from_ref(&v4)).build().is_err());
+        assert!(PathAttrBuilder::<MpUnreachNlri>::new().unreach(Safi::Unicast, &[v4, routes[0].clone()]).build().is_err());
+        assert!(PathAttrBuilder::<MpUnreachNlri>::new().unreach(Safi::Unicast, &[]).build().is_err());
+    }
+
     #[test]
     fn build_med() {
-        let med = PathAttrBuilder::<Med>::new().metric(1000u32).build();
+        let med = PathAttrBuilder::<Med>::new().metric(1000u32).build().unwrap();
 
         // Path Attr checks
         assert_eq!(med.attr_flags, 128);
@@ -427,7 +1343,7 @@ mod tests {
 
     #[test]
     fn build_local_pref() {
-        let lp = PathAttrBuilder::<LocalPref>::new().local_pref(1000).build();
+        let lp = PathAttrBuilder::<LocalPref>::new().local_pref(1000).build().unwrap();
 
         // Path Attr checks
         assert_eq!(lp.attr_flags, 64);
@@ -439,7 +1355,7 @@ mod tests {
 
     #[test]
     fn build_atomic_agg() {
-        let aa = PathAttrBuilder::<AtomicAggregate>::new().build();
+        let aa = PathAttrBuilder::<AtomicAggregate>::new().build().unwrap();
 
         // Path Attr checks
         assert_eq!(aa.attr_flags, 64);
@@ -453,7 +1369,8 @@ mod tests {
     fn build_aggregator_v4() {
         let ag = PathAttrBuilder::<Aggregator>::new()
             .aggregator(65000, Ipv4Addr::new(1, 1, 1, 1))
-            .build();
+            .build()
+            .unwrap();
 
         // Path Attr checks
         assert_eq!(ag.attr_flags, 192);
@@ -470,4 +1387,296 @@ mod tests {
         assert_eq!(u16::from_be_bytes(last_as_bytes), 65000u16);
         assert_eq!(Ipv4Addr::from(ip_bytes), Ipv4Addr::new(1, 1, 1, 1));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn build_missing_values() {
+        // None of these builders had their value supplied
+        assert!(PathAttrBuilder::<Origin>::new().build().is_err());
+        assert!(PathAttrBuilder::<NextHop>::new().build().is_err());
+        assert!(PathAttrBuilder::<Med>::new().build().is_err());
+        assert!(PathAttrBuilder::<LocalPref>::new().build().is_err());
+        assert!(PathAttrBuilder::<Aggregator>::new().build().is_err());
+    }
+
+    #[test]
+    fn build_duplicate_values() {
+        // Supplying a value twice produces a value with an invalid length
+        let med = PathAttrBuilder::<Med>::new().metric(1).metric(2).build();
+        assert!(med.is_err());
+        let origin = PathAttrBuilder::<Origin>::new()
+            .origin(OriginValue::Igp)
+            .origin(OriginValue::Egp)
+            .build();
+        assert!(origin.is_err());
+    }
+
+    #[test]
+    fn build_as_path_invalid_segments() {
+        // Empty segments are invalid, but an empty AS_PATH is fine.
+        let empty_seg = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![])])
+            .build();
+        assert!(empty_seg.is_err());
+        let empty_path = PathAttrBuilder::<AsPath>::new().as_segments(vec![]).build();
+        assert!(empty_path.is_ok());
+
+        // More than 255 ASes can't be encoded in a single segment
+        let too_long = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65000; 256])])
+            .build();
+        assert!(too_long.is_err());
+    }
+
+    #[test]
+    fn build_as_path_ext_len() {
+        // 2 segments of 100 ASes is 404 octets, needs the extended length
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65000; 100]), AsSegment::AsSequence(vec![65001; 100])])
+            .build()
+            .unwrap();
+        assert_eq!(aspath.attr_len, PathAttrLen::Ext(404));
+        assert_eq!(aspath.attr_flags, 64 + 16);
+    }
+
+    #[test]
+    fn canonicalize_sort_dedup() {
+        let med = PathAttrBuilder::<Med>::new().metric(10).build().unwrap();
+        let med2 = PathAttrBuilder::<Med>::new().metric(20).build().unwrap();
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Egp).build().unwrap();
+        let lp = PathAttrBuilder::<LocalPref>::new().local_pref(100).build().unwrap();
+
+        let canon = canonicalize_attrs(vec![med.clone(), lp.clone(), med2, origin.clone()]);
+        assert_eq!(canon, vec![origin, med, lp]);
+    }
+
+    #[test]
+    fn canonicalize_normalize_len() {
+        // Short value encoded with an extended length should be switched back to standard.
+        let mut pa = PathAttr::new(MED, PathAttrLen::Ext(4), vec![0, 0, 0, 1]);
+        pa.set_opt_bit();
+        pa.set_ext_len_bit();
+        let canon = canonicalize_attrs(vec![pa]);
+        assert_eq!(canon[0].attr_len, PathAttrLen::Std(4));
+        assert_eq!(canon[0].attr_flags, 128);
+
+        // Standard length that disagrees with the value is fixed up.
+        let pa = PathAttr::new(AS_PATH, PathAttrLen::Std(1), vec![0; 300]);
+        let canon = canonicalize_attrs(vec![pa]);
+        assert_eq!(canon[0].attr_len, PathAttrLen::Ext(300));
+        assert_eq!(canon[0].attr_flags, 16);
+    }
+
+    #[test]
+    fn prepend_as_path() {
+        // Prepending to a leading AS_SEQUENCE extends it
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001])])
+            .build()
+            .unwrap();
+        let prepended = as_path_prepend(&aspath, 65000);
+        assert_eq!(prepended.attr_value, vec![2, 2, 253, 232, 253, 233]);
+        assert_eq!(prepended.attr_len, PathAttrLen::Std(6));
+
+        // Prepending to a leading AS_SET or empty path creates a new AS_SEQUENCE
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSet(vec![65001])])
+            .build()
+            .unwrap();
+        let prepended = as_path_prepend(&aspath, 65000);
+        assert_eq!(prepended.attr_value, vec![2, 1, 253, 232, 1, 1, 253, 233]);
+        let empty = PathAttrBuilder::<AsPath>::new().as_segments(vec![]).build().unwrap();
+        let prepended = as_path_prepend(&empty, 65000);
+        assert_eq!(prepended.attr_value, vec![2, 1, 253, 232]);
+        assert!(validate_as_path(prepended.attr_value()).is_ok());
+    }
+
+    #[test]
+    fn next_hop_decode() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let n_hop = PathAttrBuilder::<NextHop>::new().next_hop(ip).build().unwrap();
+        assert_eq!(n_hop.next_hop(), Some(ip));
+        let med = PathAttrBuilder::<Med>::new().metric(1).build().unwrap();
+        assert_eq!(med.next_hop(), None);
+    }
+
+    #[test]
+    fn next_hop_validation() {
+        let local = [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))];
+        let subnet = Route::new(30, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)));
+        let err = Err(NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::InvalidNextHopAttr));
+
+        // Valid, on the shared subnet
+        assert_eq!(validate_next_hop(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), &local, Some(&subnet)), Ok(()));
+        // Our own address
+        assert_eq!(validate_next_hop(local[0], &local, None), err);
+        // Not unicast
+        assert_eq!(validate_next_hop(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 5)), &local, None), err);
+        assert_eq!(validate_next_hop(IpAddr::V4(Ipv4Addr::UNSPECIFIED), &local, None), err);
+        assert_eq!(validate_next_hop(IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1)), &local, None), err);
+        // Off the shared subnet is only an error when a subnet is given (single-hop eBGP)
+        let off_subnet = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(validate_next_hop(off_subnet, &local, Some(&subnet)), err);
+        assert_eq!(validate_next_hop(off_subnet, &local, None), Ok(()));
+    }
+
+    #[test]
+    fn build_prefix_sid() {
+        let sid = PathAttrBuilder::<PrefixSid>::new()
+            .label_index(100)
+            .originator_srgb(vec![(16000, 8000)])
+            .build()
+            .unwrap();
+
+        // Optional, transitive
+        assert_eq!(sid.attr_flags, 192);
+        assert_eq!(sid.attr_type_code, PREFIX_SID);
+        assert_eq!(sid.attr_len, PathAttrLen::Std(10 + 11));
+        assert_eq!(decode_prefix_sid(sid.attr_value()).unwrap(), vec![
+            PrefixSidTlv::LabelIndex { flags: 0, label_index: 100 },
+            PrefixSidTlv::OriginatorSrgb { flags: 0, ranges: vec![(16000, 8000)] },
+        ]);
+    }
+
+    #[test]
+    fn build_prefix_sid_invalid() {
+        // Label-Index is mandatory
+        let no_index = PathAttrBuilder::<PrefixSid>::new().originator_srgb(vec![(16000, 8000)]).build();
+        assert!(no_index.is_err());
+        assert!(PathAttrBuilder::<PrefixSid>::new().build().is_err());
+        // SRGB values must fit in 3 octets
+        let too_big = PathAttrBuilder::<PrefixSid>::new()
+            .label_index(1)
+            .originator_srgb(vec![(0x1000000, 10)])
+            .build();
+        assert!(too_big.is_err());
+    }
+
+    #[test]
+    fn decode_prefix_sid_unknown_tlv() {
+        // Unknown TLVs are preserved
+        let value = vec![1, 0, 7, 0, 0, 0, 0, 0, 0, 5, 99, 0, 2, 0xAB, 0xCD];
+        assert_eq!(decode_prefix_sid(&value).unwrap(), vec![
+            PrefixSidTlv::LabelIndex { flags: 0, label_index: 5 },
+            PrefixSidTlv::Unknown(99, vec![0xAB, 0xCD]),
+        ]);
+        // Truncated TLV
+        assert!(decode_prefix_sid(&value[..value.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn build_communities() {
+        let pa = PathAttrBuilder::<Communities>::new().communities(vec![0xFDE80064, 0xFFFFFF01]).build().unwrap();
+        assert_eq!(pa.attr_flags, 192);
+        assert_eq!(pa.attr_type_code, COMMUNITIES);
+        assert_eq!(pa.attr_len, PathAttrLen::Std(8));
+        assert_eq!(pa.communities(), Some(vec![0xFDE80064, 0xFFFFFF01]));
+        assert!(PathAttrBuilder::<Communities>::new().communities(Vec::new()).build().is_err());
+
+        // Enough communities need the extended length
+        let pa = PathAttrBuilder::<Communities>::new().communities((0..100).collect()).build().unwrap();
+        assert_eq!(pa.attr_len, PathAttrLen::Ext(400));
+    }
+
+    #[test]
+    fn decode_pa_values() {
+        let segs = vec![AsSegment::AsSequence(vec![65001, 65002]), AsSegment::AsSet(vec![1, 2, 3])];
+        let aspath = PathAttrBuilder::<AsPath>::new().as_segments(segs.clone()).build().unwrap();
+        assert_eq!(aspath.as_path(), Some(segs.clone()));
+        assert_eq!(as_path_len(&segs), 3);
+        assert_eq!(aspath.med(), None);
+
+        let med = PathAttrBuilder::<Med>::new().metric(50).build().unwrap();
+        assert_eq!(med.med(), Some(50));
+        assert_eq!(med.local_pref(), None);
+        let lp = PathAttrBuilder::<LocalPref>::new().local_pref(200).build().unwrap();
+        assert_eq!(lp.local_pref(), Some(200));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Egp).build().unwrap();
+        assert_eq!(origin.origin().map(OriginValue::try_from), Some(Ok(OriginValue::Egp)));
+    }
+
+    #[test]
+    fn build_reflection_attrs() {
+        let id = Ipv4Addr::new(10, 0, 0, 1);
+        let pa = PathAttrBuilder::<OriginatorId>::new().originator_id(id).build().unwrap();
+        assert_eq!(pa.attr_flags, 128);
+        assert_eq!(pa.attr_type_code, ORIGINATOR_ID);
+        assert_eq!(pa.originator_id(), Some(id));
+
+        let ids = vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(2, 2, 2, 2)];
+        let pa = PathAttrBuilder::<ClusterList>::new().cluster_ids(ids.clone()).build().unwrap();
+        assert_eq!(pa.attr_flags, 128);
+        assert_eq!(pa.attr_len, PathAttrLen::Std(8));
+        assert_eq!(pa.cluster_list(), Some(ids));
+        assert!(PathAttrBuilder::<ClusterList>::new().cluster_ids(Vec::new()).build().is_err());
+    }
+
+    #[test]
+    fn as_path_rewrite_count() {
+        let pa = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001, 65002, 65001]), AsSegment::AsSet(vec![65001])])
+            .build()
+            .unwrap();
+        assert_eq!(as_path_count(&pa.as_path().unwrap(), 65001), 3);
+
+        let replaced = as_path_rewrite(&pa, |asn| match asn {
+            65001 => Some(64500),
+            asn => Some(asn)
+        });
+        assert_eq!(replaced.attr_flags, pa.attr_flags);
+        assert_eq!(
+            replaced.as_path(),
+            Some(vec![AsSegment::AsSequence(vec![64500, 65002, 64500]), AsSegment::AsSet(vec![64500])])
+        );
+        // Segments left empty are dropped
+        let removed = as_path_rewrite(&pa, |asn| (asn != 65001).then_some(asn));
+        assert_eq!(removed.as_path(), Some(vec![AsSegment::AsSequence(vec![65002])]));
+    }
+    #[test]
+    fn as_width_conversion() {
+        let value = |pas: &[PathAttr], code: u8| pas.iter().find(|pa| pa.attr_type_code() == code).map(|pa| pa.attr_value().to_vec());
+        // AS_SEQUENCE of 65010 and 4200000001 with 4-octet ASes, aggregated by 4200000001
+        let as_path = vec![AS_SEQUENCE, 2, 0, 0, 0xFD, 0xF2, 0xFA, 0x56, 0xEA, 0x01];
+        let aggregator = vec![0xFA, 0x56, 0xEA, 0x01, 192, 0, 2, 1];
+        let received = vec![
+            PathAttr::from_wire(0x40, AS_PATH, Bytes::from(as_path.clone())),
+            PathAttr::from_wire(0xC0, AGGREGATOR, Bytes::from(aggregator.clone())),
+            // Not sent between speakers that both have the capability
+            PathAttr::from_wire(0xC0, AS4_PATH, Bytes::from_static(&[AS_SEQUENCE, 1, 0, 0, 0, 1]))
+        ];
+
+        // Kept with 2-octet ASes, the real ones alongside
+        let kept = from_session_width(received, AsWidth::Four).unwrap();
+        assert_eq!(kept.iter().find_map(|pa| pa.as_path()), Some(vec![AsSegment::AsSequence(vec![65010, AS_TRANS])]));
+        assert_eq!(value(&kept, AS4_PATH), Some(as_path.clone()));
+        assert_eq!(value(&kept, AGGREGATOR), Some(vec![0x5B, 0xA0, 192, 0, 2, 1]));
+        assert_eq!(value(&kept, AS4_AGGREGATOR), Some(aggregator.clone()));
+        assert!(kept.iter().filter(|pa| pa.attr_type_code() == AS4_PATH).all(|pa| pa.attr_flags() == 0xC0));
+
+        // Sent back out the same, an old speaker's 2-octet one as is
+        let sent = to_session_width(&kept, AsWidth::Four);
+        assert_eq!(value(&sent, AS_PATH), Some(as_path));
+        assert_eq!(value(&sent, AGGREGATOR), Some(aggregator));
+        assert_eq!((value(&sent, AS4_PATH), value(&sent, AS4_AGGREGATOR)), (None, None));
+        assert!(matches!(to_session_width(&kept, AsWidth::Two), Cow::Borrowed(pas) if pas == kept.as_slice()));
+
+        // ASes an old speaker added ahead of AS4_PATH stay in front of it
+        let mut kept = kept;
+        kept[0] = as_path_prepend(&kept[0], 65000);
+        let sent = to_session_width(&kept, AsWidth::Four);
+        assert_eq!(value(&sent, AS_PATH), Some(vec![AS_SEQUENCE, 3, 0, 0, 0xFD, 0xE8, 0, 0, 0xFD, 0xF2, 0xFA, 0x56, 0xEA, 0x01]));
+
+        // An AS4_PATH longer than AS_PATH is ignored
+        let as_path = PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(vec![65010])]).build().unwrap();
+        let as4_path = PathAttr::from_wire(0xC0, AS4_PATH, Bytes::from_static(&[AS_SEQUENCE, 2, 0, 0, 0, 1, 0, 0, 0, 2]));
+        let pas = [as_path, as4_path];
+        let sent = to_session_width(&pas, AsWidth::Four);
+        assert_eq!(value(&sent, AS_PATH), Some(vec![AS_SEQUENCE, 1, 0, 0, 0xFD, 0xF2]));
+
+        // A 4-octet AS_PATH that doesn't add up
+        let malformed = PathAttr::from_wire(0x40, AS_PATH, Bytes::from_static(&[AS_SEQUENCE, 1, 0xFD, 0xF2]));
+        assert_eq!(
+            NotifErrorCode::from(from_session_width(vec![malformed], AsWidth::Four).unwrap_err()),
+            NotifErrorCode::UpdateMessageError(UpdateMsgErrSubcode::MalformedAsPath)
+        );
+    }
+}
@@ -0,0 +1,965 @@
+// Control side of a peer. The peer's session runs in its own task which owns the FSM, anything else
+// (the admin API, config reloads) controls it through a cloneable PeerHandle. Commands are queued and
+// handed to the FSM in order, see Fsm::command for what each of them does.
+// The task (PeerTask) owns the FSM, its timers and the connection to the peer. It feeds the FSM with
+// commands, timer expiries and whatever the connection delivers, carries out the actions that come back
+// and reports what the table has to know about (routes received, the session coming up or going down)
+// as TableCommands, the table answers with PeerRequests (see comms). Encoding and decoding messages is
+// the Connection's job, so the task never sees bytes.
+// Connections the peer opens to us arrive through the incoming channel (see transport::PeerListener).
+// One that arrives while our own connection is still exchanging Opens is a collision: it gets an FSM of its
+// own and both go on through fsm::PeerConnections until one of them is closed. The table only hears about
+// the connection that survives.
+// The peer's statistics live with its FSM, PeerHandle::stats asks the task for a snapshot.
+// The task only reads from the connection while the RIB's ingest queue has room for announcements, so
+// a slow decision process pushes back on the peer through TCP (see ingest).
+// The task runs in a bgp_peer tracing span carrying the peer's address. Messages are logged at debug
+// level as one line summaries as they're received and sent.
+
+use std::{
+    fmt,
+    future::{self, Future},
+    io,
+    net::IpAddr,
+    pin::Pin,
+};
+
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tracing::{debug, info_span, Instrument};
+
+use crate::{
+    comms::{PeerRequest, ReceivedRoutes, TableCommand},
+    fsm::{self, Action, Fsm, PeerCommand, PeerConnections},
+    errors::{CeaseSubcode, DecodeError, ErrorAction},
+    fsm_ds::{Event, PeerSession, PeerStats, SessionParams, State},
+    ingest::IngestSender,
+    message_types::{Afi, MessageType, Notification, Open, Route, Safi, Update},
+    session_events::SessionError,
+    timers::{Clock, SessionTimers, TimerExpired},
+};
+
+pub(crate) type ConnectFuture<T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send>>;
+
+// The peer's task has exited, so the command couldn't be delivered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerClosed;
+
+impl fmt::Display for PeerClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BGP peer task is no longer running")
+    }
+}
+
+impl std::error::Error for PeerClosed {}
+
+#[derive(Clone, Debug)]
+pub struct PeerHandle {
+    peer_addr: IpAddr,
+    tx: UnboundedSender<PeerRequest>
+}
+
+impl PeerHandle {
+    pub(crate) fn new(peer_addr: IpAddr) -> (Self, UnboundedReceiver<PeerRequest>) {
+        // The receiver goes to the peer's task
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { peer_addr, tx }, rx)
+    }
+    pub fn peer_addr(&self) -> IpAddr {
+        self.peer_addr
+    }
+    pub fn start(&self) -> Result<(), PeerClosed> {
+        self.send(PeerCommand::Start)
+    }
+    pub fn shutdown(&self) -> Result<(), PeerClosed> {
+        self.send(PeerCommand::Shutdown(None))
+    }
+    pub fn shutdown_with_reason(&self, reason: &str) -> Result<(), PeerClosed> {
+        // The reason is shown to the peer's operator, e.g. "maintenance, ticket 1234"
+        self.send(PeerCommand::Shutdown(Some(reason.to_string())))
+    }
+    pub fn cease(&self, subcode: CeaseSubcode) -> Result<(), PeerClosed> {
+        // Like a shutdown, the session stays down until started again
+        self.send(PeerCommand::Cease(subcode))
+    }
+    pub fn hard_reset(&self) -> Result<(), PeerClosed> {
+        self.send(PeerCommand::HardReset(None))
+    }
+    pub fn hard_reset_with_reason(&self, reason: &str) -> Result<(), PeerClosed> {
+        self.send(PeerCommand::HardReset(Some(reason.to_string())))
+    }
+    pub fn soft_reset_in(&self) -> Result<(), PeerClosed> {
+        self.send(PeerCommand::SoftResetIn)
+    }
+    pub fn soft_reset_out(&self) -> Result<(), PeerClosed> {
+        self.send(PeerCommand::SoftResetOut)
+    }
+    pub(crate) fn advertise(&self, updates: Vec<Update>) -> Result<(), PeerClosed> {
+        self.tx.send(PeerRequest::Advertise(updates)).map_err(|_| PeerClosed)
+    }
+    pub(crate) fn imported(&self, accepted: usize, rejected: usize) -> Result<(), PeerClosed> {
+        self.tx.send(PeerRequest::Imported(accepted, rejected)).map_err(|_| PeerClosed)
+    }
+    pub fn reconfigure(&self, session: PeerSession) -> Result<(), PeerClosed> {
+        self.tx.send(PeerRequest::Reconfigure(Box::new(session))).map_err(|_| PeerClosed)
+    }
+    pub fn exit(&self) -> Result<(), PeerClosed> {
+        self.tx.send(PeerRequest::Exit).map_err(|_| PeerClosed)
+    }
+    pub async fn stats(&self) -> Result<PeerStats, PeerClosed> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(PeerRequest::Stats(tx)).map_err(|_| PeerClosed)?;
+        rx.await.map_err(|_| PeerClosed)
+    }
+    fn send(&self, command: PeerCommand) -> Result<(), PeerClosed> {
+        self.tx.send(PeerRequest::Command(command)).map_err(|_| PeerClosed)
+    }
+}
+
+// Messages read from the peer, as decoded by the Connection
+pub(crate) enum Inbound {
+    // Everything that maps straight to an FSM event: Open, Keepalive, NOTIFICATION, End-of-RIB and decode errors
+    Event(Event),
+    // A valid Update, as one payload per set of routes sharing their path attributes
+    Update(Vec<ReceivedRoutes>)
+}
+
+impl fmt::Display for Inbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // One line summaries for the logs, Updates aren't printed in full
+        match self {
+            Inbound::Event(event) => write!(f, "{:?}", event),
+            Inbound::Update(payloads) => {
+                let count = |routes: Option<Vec<Route>>| routes.map_or(0, |routes| routes.len());
+                let announced: usize = payloads.iter().map(|payload| count(payload.routes())).sum();
+                let withdrawn: usize = payloads.iter().map(|payload| count(payload.withdrawn_routes())).sum();
+                write!(f, "UPDATE announcing {} and withdrawing {} routes", announced, withdrawn)
+            }
+        }
+    }
+}
+
+// Messages to send to the peer, for the Connection to encode
+#[derive(Debug, PartialEq)]
+pub(crate) enum Outbound {
+    Open(Open),
+    Keepalive,
+    Notification(Notification),
+    Update(Update),
+    RouteRefresh(Afi, Safi)
+}
+
+impl fmt::Display for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outbound::Open(open) => write!(f, "OPEN {:?}", open),
+            Outbound::Keepalive => write!(f, "KEEPALIVE"),
+            Outbound::Notification(notification) => write!(
+                f, "NOTIFICATION code {} subcode {}", notification.err_code(), notification.err_subcode()
+            ),
+            Outbound::Update(update) => write!(
+                f,
+                "UPDATE announcing {} and withdrawing {} routes",
+                update.nlri().map_or(0, |routes| routes.len()) + update.mp_reach().map_or(0, |(.., routes)| routes.len()),
+                update.withdrawn_routes().map_or(0, |routes| routes.len()) + update.mp_unreach().map_or(0, |(.., routes)| routes.len())
+            ),
+            Outbound::RouteRefresh(afi, safi) => write!(f, "ROUTE-REFRESH {:?} {:?}", afi, safi)
+        }
+    }
+}
+
+// An established transport connection to the peer, along with the codec for it
+pub(crate) trait Connection: Send + 'static {
+    fn send(&mut self, msg: Outbound) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>>;
+    // Sends the messages in order, e.g. a whole Adj-RIB-Out flush. Connections that can write several
+    // messages at once override it, by default they go one at a time.
+    fn send_batch(&mut self, msgs: Vec<Outbound>) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            for msg in msgs {
+                self.send(msg).await?;
+            }
+            Ok(())
+        })
+    }
+    // None once the peer closed the connection or it failed
+    fn recv(&mut self) -> Pin<Box<dyn Future<Output = Option<Inbound>> + Send + '_>>;
+    // Called once the session is Established, before any Update goes either way. Encoding and decoding
+    // from then on follows what was negotiated, e.g. 2 or 4 octet ASes in AS_PATH, Path Identifiers.
+    fn negotiated(&mut self, _params: &SessionParams) {}
+}
+
+// Opens connections to the peer. The future must not borrow the connector, so it can be polled
+// alongside everything else the task waits on.
+pub(crate) trait Connector: Send + 'static {
+    type Conn: Connection;
+    fn connect(&self) -> ConnectFuture<Self::Conn>;
+}
+
+// Whatever woke the task up
+enum Input<T> {
+    Request(Option<PeerRequest>),
+    Expired(TimerExpired),
+    Connected(io::Result<T>),
+    Incoming(T),
+    Received(Option<Inbound>),
+    // The same for the colliding connection
+    CandidateExpired(TimerExpired),
+    CandidateReceived(Option<Inbound>),
+    // The ingest queue has room again
+    Room
+}
+
+// A connection from the peer that collided with ours, along with the timers of its FSM
+struct Candidate<T, C> {
+    conn: T,
+    timers: SessionTimers<C>,
+    expiries: UnboundedReceiver<TimerExpired>
+}
+
+pub(crate) struct PeerTask<K: Connector, C> {
+    peer_addr: IpAddr,
+    // The session's FSM, and the colliding connection's while there is one
+    fsms: PeerConnections,
+    // Which of them is the session's
+    side: fsm::Connection,
+    connector: K,
+    connecting: Option<ConnectFuture<K::Conn>>,
+    connection: Option<K::Conn>,
+    // Connections accepted on the peer's behalf
+    incoming: Option<UnboundedReceiver<K::Conn>>,
+    candidate: Option<Candidate<K::Conn, C>>,
+    timers: SessionTimers<C>,
+    expiries: UnboundedReceiver<TimerExpired>,
+    events: IngestSender,
+    // Payloads of the Update being handed to the FSM, sent on once it says to process them
+    received: Vec<ReceivedRoutes>,
+    // What the NEXT_HOP of received routes is checked against, see validate_next_hop
+    local_addrs: Vec<IpAddr>,
+    shared_subnet: Option<Route>,
+    // Whether the session was Established as of the last batch of actions
+    up: bool,
+    // The last NOTIFICATION from before the session came up, so one ending the session can be told apart
+    notified: Option<SessionError>
+}
+
+impl<K: Connector, C: Clock + 'static> PeerTask<K, C> {
+    pub fn new(peer_addr: IpAddr, fsms: PeerConnections, connector: K, clock: C, events: IngestSender) -> Self {
+        // fsms holds the session's FSM, e.g. Speaker::connections() with it added as the outgoing connection.
+        // Must be called within a tokio runtime, the timers need it.
+        let (timers, expiries) = SessionTimers::new(clock);
+        let side = match fsms.get(fsm::Connection::Outgoing).is_some() {
+            true => fsm::Connection::Outgoing,
+            false => fsm::Connection::Incoming
+        };
+        Self {
+            peer_addr,
+            fsms,
+            side,
+            connector,
+            connecting: None,
+            connection: None,
+            incoming: None,
+            candidate: None,
+            timers,
+            expiries,
+            events,
+            received: Vec::new(),
+            local_addrs: Vec::new(),
+            shared_subnet: None,
+            up: false,
+            notified: None
+        }
+    }
+    pub fn incoming(mut self, incoming: UnboundedReceiver<K::Conn>) -> Self {
+        self.incoming = Some(incoming);
+        self
+    }
+    pub fn check_next_hop(mut self, local_addr: IpAddr, shared_subnet: Option<Route>) -> Self {
+        // Received routes with our address as the NEXT_HOP, or one outside the subnet shared with a
+        // single-hop eBGP peer, are an error. An unspecified local address is never a NEXT_HOP anyway.
+        self.local_addrs = match local_addr.is_unspecified() {
+            true => Vec::new(),
+            false => vec![local_addr]
+        };
+        self.shared_subnet = shared_subnet;
+        self
+    }
+    pub fn spawn(self, requests: UnboundedReceiver<PeerRequest>) -> JoinHandle<Fsm> {
+        // The task runs until told to exit or every PeerHandle is gone, the FSM is handed back once it's done
+        let span = info_span!("bgp_peer", peer = %self.peer_addr);
+        tokio::spawn(self.run(requests).instrument(span))
+    }
+    pub async fn run(mut self, mut requests: UnboundedReceiver<PeerRequest>) -> Fsm {
+        loop {
+            let input = tokio::select! {
+                request = requests.recv() => Input::Request(request),
+                Some(expired) = self.expiries.recv() => Input::Expired(expired),
+                result = connected(&mut self.connecting) => Input::Connected(result),
+                Some(conn) = accepted(&mut self.incoming) => Input::Incoming(conn),
+                msg = received(&mut self.connection), if self.events.has_room() => Input::Received(msg),
+                input = candidate(&mut self.candidate, self.events.has_room()) => input,
+                _ = self.events.room(), if !self.events.has_room() => Input::Room
+            };
+            if let Input::Received(Some(msg)) | Input::CandidateReceived(Some(msg)) = &input {
+                debug!(%msg, "received");
+            }
+            match input {
+                Input::Request(Some(PeerRequest::Command(command))) => {
+                    let actions = self.fsm_mut().command(command);
+                    self.execute(actions).await;
+                },
+                Input::Request(Some(PeerRequest::Advertise(updates))) => self.advertise(updates).await,
+                Input::Request(Some(PeerRequest::Imported(accepted, rejected))) => {
+                    self.fsm_mut().stats_mut().record_import(accepted, rejected);
+                },
+                Input::Request(Some(PeerRequest::Stats(reply))) => _ = reply.send(self.fsm().stats().clone()),
+                Input::Request(Some(PeerRequest::Reconfigure(session))) => self.fsm_mut().reconfigure(&session),
+                Input::Request(Some(PeerRequest::Exit)) | Input::Request(None) => break,
+                Input::Expired(expired) => {
+                    if let Some(event) = self.timers.accept(expired) {
+                        self.handle(event).await;
+                    }
+                },
+                Input::Connected(result) => {
+                    self.connecting = None;
+                    match result {
+                        Ok(conn) => {
+                            self.follow(fsm::Connection::Outgoing);
+                            self.connection = Some(conn);
+                            self.handle(Event::TcpCrAcked).await;
+                        },
+                        Err(err) => {
+                            debug!(%err, "connect failed");
+                            self.handle(Event::TcpConnectionFails).await
+                        }
+                    }
+                },
+                Input::Incoming(conn) => self.accept(conn).await,
+                Input::Received(Some(Inbound::Event(event))) => self.handle(event).await,
+                Input::Received(Some(Inbound::Update(payloads))) => match self.next_hop_error(&payloads) {
+                    Some(err) => self.handle(Event::UpdateMsgErr(err)).await,
+                    None => {
+                        self.received = payloads;
+                        self.handle(Event::UpdateMsg).await;
+                        // Anything the FSM didn't want processed is dropped
+                        self.received.clear();
+                    }
+                },
+                Input::Received(None) => {
+                    self.connection = None;
+                    self.handle(Event::TcpConnectionFails).await;
+                },
+                Input::CandidateExpired(expired) => {
+                    let event = self.candidate.as_mut().and_then(|candidate| candidate.timers.accept(expired));
+                    if let Some(event) = event {
+                        self.handle_on(self.side.other(), event).await;
+                    }
+                },
+                // Updates aren't expected before the Open exchange is done, the FSM closes the connection
+                Input::CandidateReceived(Some(Inbound::Event(event))) => self.handle_on(self.side.other(), event).await,
+                Input::CandidateReceived(Some(Inbound::Update(_))) => self.handle_on(self.side.other(), Event::UpdateMsg).await,
+                Input::CandidateReceived(None) => self.drop_candidate(),
+                Input::Room => ()
+            }
+        }
+        // Nobody can control the peer anymore, so take the session down
+        let actions = self.fsm_mut().command(PeerCommand::Shutdown(None));
+        self.execute(actions).await;
+        self.drop_candidate();
+        self.fsms.remove(self.side).expect("the session's FSM is always there")
+    }
+    fn next_hop_error(&self, payloads: &[ReceivedRoutes]) -> Option<DecodeError> {
+        // An Update whose NEXT_HOP fails the checks is reported to the peer and the session is reset.
+        // RFC 4271, Pg. 33
+        payloads
+            .iter()
+            .find_map(|payload| payload.validate_next_hop(&self.local_addrs, self.shared_subnet.as_ref()).err())
+            .map(|code| DecodeError::from(code).with_action(ErrorAction::SessionReset))
+    }
+    fn fsm(&self) -> &Fsm {
+        // The session's FSM is only ever replaced by the candidate's, so it is always there
+        self.fsms.get(self.side).expect("the session's FSM is always there")
+    }
+    fn fsm_mut(&mut self) -> &mut Fsm {
+        self.fsms.get_mut(self.side).expect("the session's FSM is always there")
+    }
+    fn follow(&mut self, side: fsm::Connection) {
+        // The session's FSM moves with its connection, which side it's on decides who wins a collision.
+        // There's no candidate while the session waits for a connection.
+        if side != self.side {
+            if let Some(fsm) = self.fsms.remove(self.side) {
+                self.fsms.add(side, fsm);
+            }
+            self.side = side;
+        }
+    }
+    async fn handle(&mut self, event: Event) {
+        self.handle_on(self.side, event).await;
+    }
+    async fn handle_on(&mut self, side: fsm::Connection, event: Event) {
+        let actions = self.fsms.handle(side, event);
+        self.dispatch(actions).await;
+    }
+    async fn dispatch(&mut self, actions: Vec<(fsm::Connection, Action)>) {
+        // The actions are tagged with the connection they're for. If the session's connection lost a
+        // collision its FSM is gone, the candidate takes over before its own actions are carried out.
+        let (ours, theirs): (Vec<_>, Vec<_>) = actions.into_iter().partition(|(side, _)| *side == self.side);
+        let untag = |actions: Vec<(fsm::Connection, Action)>| -> Vec<Action> {
+            actions.into_iter().map(|(_, action)| action).collect()
+        };
+        let lost = self.fsms.get(self.side).is_none();
+        match lost {
+            false => {
+                self.execute(untag(ours)).await;
+                self.carry_out_candidate(untag(theirs)).await;
+            },
+            true => {
+                self.hand_over(untag(ours)).await;
+                self.execute(untag(theirs)).await;
+            }
+        }
+    }
+    async fn execute(&mut self, mut actions: Vec<Action>) {
+        // A failed send is a failed connection, the FSM hears about it once the remaining actions are done
+        while self.carry_out(actions).await {
+            actions = self.fsm_mut().handle(Event::TcpConnectionFails);
+        }
+    }
+    async fn carry_out(&mut self, actions: Vec<Action>) -> bool {
+        // Carries out the actions in order, returns whether the connection failed along the way
+        self.timers.apply(&actions);
+        let mut failed = false;
+        for action in actions {
+            let msg = match action {
+                Action::Connect => {
+                    self.connecting = Some(self.connector.connect());
+                    None
+                },
+                Action::DropConnection => {
+                    self.connecting = None;
+                    self.connection = None;
+                    None
+                },
+                Action::SendOpen => Some(Outbound::Open(self.fsm().local_open().clone())),
+                Action::SendKeepalive => Some(Outbound::Keepalive),
+                Action::SendNotification(err) => Some(Outbound::Notification(Notification::new(err))),
+                Action::SendShutdown(subcode, communication) => {
+                    Some(Outbound::Notification(Notification::shutdown(subcode, &communication)))
+                },
+                Action::SendRouteRefresh => {
+                    let afi_safis = self.fsm()
+                        .session()
+                        .session_params()
+                        .map(|params| params.capabilities().afi_safis().to_vec())
+                        .unwrap_or_default();
+                    for (afi, safi) in afi_safis {
+                        failed |= !self.send(Outbound::RouteRefresh(afi, safi)).await;
+                    }
+                    None
+                },
+                Action::ProcessUpdate => {
+                    let payloads: Vec<ReceivedRoutes> = self.received.drain(..).collect();
+                    for payload in payloads {
+                        let received = payload.routes().map_or(0, |routes| routes.len());
+                        let withdrawn = payload.withdrawn_routes().map_or(0, |routes| routes.len());
+                        self.fsm_mut().stats_mut().record_prefixes(received, withdrawn);
+                        _ = self.events.send(TableCommand::Routes(payload));
+                    }
+                    None
+                },
+                Action::ReleaseResources => {
+                    let notification = self.fsm().last_error().filter(|error| Some(*error) != self.notified.as_ref()).cloned();
+                    self.emit(TableCommand::Down(self.peer_addr, notification))
+                },
+                Action::MarkStale(afi_safis) => self.emit(TableCommand::MarkStale(self.peer_addr, afi_safis)),
+                Action::FlushStale(afi, safi) => self.emit(TableCommand::FlushStale(self.peer_addr, afi, safi)),
+                Action::ReplayAdjRibIn => self.emit(TableCommand::ReplayAdjRibIn(self.peer_addr)),
+                Action::ResendAdjRibOut => self.emit(TableCommand::ResendAdjRibOut(self.peer_addr)),
+                Action::StartTimer(..) | Action::StopTimer(_) => None
+            };
+            if let Some(msg) = msg {
+                failed |= !self.send(msg).await;
+            }
+        }
+        let up = self.fsm().state() == State::Established;
+        if up && !self.up {
+            self.notified = self.fsm().last_error().cloned();
+            if let Some(params) = self.fsm().session().session_params().cloned() {
+                if let Some(conn) = self.connection.as_mut() {
+                    conn.negotiated(&params);
+                }
+                _ = self.events.send(TableCommand::Up(self.peer_addr, params));
+            }
+        }
+        self.up = up;
+        // A candidate only lives as long as the session's connection is being set up
+        if self.fsm().state() == State::Idle {
+            self.drop_candidate();
+        }
+        // A connection that's already gone doesn't fail again
+        match failed {
+            true => self.connection.take().is_some(),
+            false => false
+        }
+    }
+    async fn accept(&mut self, conn: K::Conn) {
+        // Taken while the session is waiting for a connection, it replaces our own attempt. While ours is
+        // still exchanging Opens it's a collision, see collide. Otherwise the connection already there wins
+        // and the new one is closed.
+        match (self.fsm().state(), self.connection.is_some()) {
+            (State::Connect | State::Active, false) => {
+                self.follow(fsm::Connection::Incoming);
+                self.connecting = None;
+                self.connection = Some(conn);
+                self.handle(Event::TcpConnectionConfirmed).await;
+            },
+            (State::OpenSent | State::OpenConfirm, true) if self.side == fsm::Connection::Outgoing && self.candidate.is_none() => {
+                self.collide(conn).await;
+            },
+            _ => drop(conn)
+        }
+    }
+    async fn collide(&mut self, conn: K::Conn) {
+        // Both connections go on with an FSM of their own, until an Open shows which one has to go or one
+        // of them comes up. RFC 4271, Pg. 28
+        let side = self.side.other();
+        let fsm = self.fsm().candidate();
+        self.fsms.add(side, fsm);
+        let (timers, expiries) = self.timers.share();
+        self.candidate = Some(Candidate { conn, timers, expiries });
+        self.handle_on(side, Event::ManualStartPassive).await;
+        self.handle_on(side, Event::TcpConnectionConfirmed).await;
+    }
+    async fn carry_out_candidate(&mut self, actions: Vec<Action>) {
+        // Until it takes over, only the messages of the Open exchange go out on the candidate's connection.
+        // The table doesn't hear about it.
+        let side = self.side.other();
+        let candidate = match self.candidate.as_mut() {
+            Some(candidate) => candidate,
+            None => return
+        };
+        candidate.timers.apply(&actions);
+        let mut close = false;
+        for action in actions {
+            let msg = match action {
+                Action::SendOpen => self.fsms.get(side).map(|fsm| Outbound::Open(fsm.local_open().clone())),
+                Action::SendKeepalive => Some(Outbound::Keepalive),
+                Action::SendNotification(err) => Some(Outbound::Notification(Notification::new(err))),
+                Action::SendShutdown(subcode, communication) => {
+                    Some(Outbound::Notification(Notification::shutdown(subcode, &communication)))
+                },
+                Action::DropConnection => {
+                    close = true;
+                    None
+                },
+                _ => None
+            };
+            if let Some(msg) = msg {
+                debug!(%msg, "sending on the colliding connection");
+                close |= candidate.conn.send(msg).await.is_err();
+            }
+        }
+        // Also gone if it lost the collision
+        if close || self.fsms.get(side).is_none() {
+            self.drop_candidate();
+        }
+    }
+    async fn hand_over(&mut self, actions: Vec<Action>) {
+        // The session's connection lost the collision: it's closed with its Cease, and the candidate's
+        // connection and timers become the session's
+        for action in actions {
+            if let Action::SendNotification(err) = action {
+                _ = self.send(Outbound::Notification(Notification::new(err))).await;
+            }
+        }
+        self.timers.stop_all();
+        self.connection = None;
+        self.side = self.side.other();
+        if let Some(candidate) = self.candidate.take() {
+            self.connection = Some(candidate.conn);
+            self.timers = candidate.timers;
+            self.expiries = candidate.expiries;
+        }
+    }
+    fn drop_candidate(&mut self) {
+        if let Some(mut candidate) = self.candidate.take() {
+            candidate.timers.stop_all();
+        }
+        _ = self.fsms.remove(self.side.other());
+    }
+    async fn advertise(&mut self, updates: Vec<Update>) {
+        // Sent as one batch, the connection writes as much of it at a time as the peer takes
+        if self.fsm().state() != State::Established {
+            return;
+        }
+        let count = updates.len() as u64;
+        let msgs: Vec<Outbound> = updates
+            .into_iter()
+            .map(Outbound::Update)
+            .inspect(|msg| debug!(%msg, "sending"))
+            .collect();
+        let sent = match self.connection.as_mut() {
+            Some(conn) => conn.send_batch(msgs).await.is_ok(),
+            None => true
+        };
+        match sent {
+            true => {
+                self.fsm_mut().stats_mut().incr_sent(MessageType::Update, count);
+                let actions = self.fsm_mut().updates_sent();
+                self.timers.apply(&actions);
+            },
+            false => {
+                self.connection = None;
+                self.handle(Event::TcpConnectionFails).await;
+            }
+        }
+    }
+    async fn send(&mut self, msg: Outbound) -> bool {
+        // Messages for a connection that's already gone are dropped, the FSM knows or is about to
+        match self.connection.as_mut() {
+            Some(conn) => {
+                debug!(%msg, "sending");
+                conn.send(msg).await.is_ok()
+            },
+            None => true
+        }
+    }
+    fn emit(&self, event: TableCommand) -> Option<Outbound> {
+        // The table may be shutting down, the session carries on regardless
+        _ = self.events.send(event);
+        None
+    }
+}
+
+async fn connected<T>(connecting: &mut Option<ConnectFuture<T>>) -> io::Result<T> {
+    match connecting.as_mut() {
+        Some(connecting) => connecting.await,
+        None => future::pending().await
+    }
+}
+
+async fn accepted<T>(incoming: &mut Option<UnboundedReceiver<T>>) -> Option<T> {
+    match incoming.as_mut() {
+        Some(incoming) => incoming.recv().await,
+        None => future::pending().await
+    }
+}
+
+async fn received<T: Connection>(connection: &mut Option<T>) -> Option<Inbound> {
+    match connection.as_mut() {
+        Some(conn) => conn.recv().await,
+        None => future::pending().await
+    }
+}
+
+async fn candidate<T: Connection, C>(candidate: &mut Option<Candidate<T, C>>, read: bool) -> Input<T> {
+    // Like received, the connection is only read while the ingest queue has room
+    match candidate.as_mut() {
+        Some(candidate) => tokio::select! {
+            Some(expired) = candidate.expiries.recv() => Input::CandidateExpired(expired),
+            msg = candidate.conn.recv(), if read => Input::CandidateReceived(msg),
+            else => future::pending().await
+        },
+        None => future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::{Ipv4Addr, SocketAddr}, sync::Mutex};
+    use bytes::Bytes;
+    use crate::{
+        bgp_codec::BgpCodec,
+        comms::MockReceivedRoutesBuilder,
+        errors::{CeaseSubcode, NotifErrorCode},
+        fsm_ds::PeerSessionBuilder,
+        ingest::{ingest_queue, INGEST_CAPACITY},
+        message_types::{Capability, Nlri, OpenBuilder, Route, UpdateBuilder},
+        msg_decoder::DecodedUpdate,
+        path_attrs::{AsWidth, NextHop, PaBuilder, PathAttrBuilder, AS4_PATH, AS_PATH},
+        timers::TokioClock,
+        transport::{PeerListener, TcpConnector},
+    };
+
+    #[test]
+    fn peer_handle_commands() {
+        let (handle, mut rx) = PeerHandle::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        handle.shutdown().unwrap();
+        handle.clone().soft_reset_in().unwrap();
+        handle.shutdown_with_reason("planned maintenance").unwrap();
+        assert!(matches!(rx.try_recv(), Ok(PeerRequest::Command(PeerCommand::Shutdown(None)))));
+        assert!(matches!(rx.try_recv(), Ok(PeerRequest::Command(PeerCommand::SoftResetIn))));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(PeerRequest::Command(PeerCommand::Shutdown(Some(reason)))) if reason == "planned maintenance"
+        ));
+        drop(rx);
+        assert_eq!(handle.hard_reset(), Err(PeerClosed));
+    }
+
+    #[test]
+    fn message_summaries() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone(), route]), None, Vec::new()).build();
+        assert_eq!(Inbound::Update(vec![payload]).to_string(), "UPDATE announcing 2 and withdrawing 0 routes");
+        assert_eq!(Inbound::Event(Event::KeepAliveMsg).to_string(), "KeepAliveMsg");
+        assert_eq!(Outbound::RouteRefresh(Afi::Ipv6, Safi::Unicast).to_string(), "ROUTE-REFRESH Ipv6 Unicast");
+    }
+
+    fn session(fsm: Fsm) -> PeerConnections {
+        // Collisions are resolved against the BGP Identifier in our Open, as with Speaker::connections
+        let mut fsms = PeerConnections::new(Ipv4Addr::from(fsm.local_open().bgp_id()));
+        fsms.add(fsm::Connection::Outgoing, fsm);
+        fsms
+    }
+
+    // The test plays the peer on the other end of the channels
+    struct MockConn {
+        tx: UnboundedSender<Outbound>,
+        rx: UnboundedReceiver<Inbound>
+    }
+
+    impl Connection for MockConn {
+        fn send(&mut self, msg: Outbound) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+            let result = self.tx.send(msg).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+            Box::pin(future::ready(result))
+        }
+        fn recv(&mut self) -> Pin<Box<dyn Future<Output = Option<Inbound>> + Send + '_>> {
+            Box::pin(self.rx.recv())
+        }
+    }
+
+    struct MockConnector(Mutex<Option<MockConn>>);
+
+    impl Connector for MockConnector {
+        type Conn = MockConn;
+        fn connect(&self) -> ConnectFuture<MockConn> {
+            let conn = self.0.lock().unwrap().take().ok_or(io::Error::from(io::ErrorKind::ConnectionRefused));
+            Box::pin(future::ready(conn))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_task_session() {
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+        let (in_tx, in_rx) = mpsc::unbounded_channel();
+        let connector = MockConnector(Mutex::new(Some(MockConn { tx: out_tx, rx: in_rx })));
+        let (events_tx, mut events) = ingest_queue(INGEST_CAPACITY);
+        let fsm = Fsm::new(PeerSessionBuilder::new().build(), OpenBuilder::new(4, 65000, 90, 1).build());
+        let (handle, requests) = PeerHandle::new(peer);
+        let task = PeerTask::new(peer, session(fsm), connector, TokioClock, events_tx).spawn(requests);
+
+        handle.start().unwrap();
+        assert!(matches!(out_rx.recv().await, Some(Outbound::Open(_))));
+        in_tx.send(Inbound::Event(Event::BGPOpen(OpenBuilder::new(4, 65001, 90, 2).build()))).unwrap();
+        assert_eq!(out_rx.recv().await, Some(Outbound::Keepalive));
+        in_tx.send(Inbound::Event(Event::KeepAliveMsg)).unwrap();
+        match events.recv().await {
+            Some(TableCommand::Up(addr, params)) => assert_eq!((addr, params.remote_as()), (peer, 65001)),
+            _ => panic!("expected the session to come up")
+        }
+
+        // Routes from the peer go to the table, the table's go to the peer
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let nh = PathAttrBuilder::<NextHop>::new().next_hop(peer).build().unwrap();
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![nh]).build();
+        in_tx.send(Inbound::Update(vec![payload])).unwrap();
+        match events.recv().await {
+            Some(TableCommand::Routes(payload)) => assert_eq!(payload.routes(), Some(vec![route.clone()])),
+            _ => panic!("expected the peer's routes")
+        }
+        handle.advertise(vec![UpdateBuilder::new().build().unwrap()]).unwrap();
+        assert!(matches!(out_rx.recv().await, Some(Outbound::Update(_))));
+        handle.imported(1, 0).unwrap();
+        let stats = handle.stats().await.unwrap();
+        assert_eq!((stats.updates_received(), stats.prefixes_received(), stats.prefixes_accepted()), (1, 1, 1));
+        assert_eq!(stats.sent().get(MessageType::Update), 1);
+        assert_eq!(stats.transitions(), 4);
+        assert!(stats.uptime().is_some());
+
+        // Keepalives go out on their own
+        assert_eq!(out_rx.recv().await, Some(Outbound::Keepalive));
+
+        handle.shutdown_with_reason("maintenance").unwrap();
+        assert_eq!(
+            out_rx.recv().await,
+            Some(Outbound::Notification(Notification::shutdown(CeaseSubcode::AdminShutdown, "maintenance")))
+        );
+        match events.recv().await {
+            Some(TableCommand::Down(addr, Some(error))) => assert_eq!((addr, error.subcode, error.sent), (peer, 2, true)),
+            _ => panic!("expected the session to go down with our NOTIFICATION")
+        }
+        drop(handle);
+        let fsm = task.await.unwrap();
+        assert_eq!(fsm.state(), State::Idle);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_task_connect_fails() {
+        // Nobody answers, the session gives up and goes back to Idle
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        let (events_tx, mut events) = ingest_queue(INGEST_CAPACITY);
+        let fsm = Fsm::new(PeerSessionBuilder::new().build(), OpenBuilder::new(4, 65000, 90, 1).build());
+        let (handle, requests) = PeerHandle::new(peer);
+        let task = PeerTask::new(peer, session(fsm), MockConnector(Mutex::new(None)), TokioClock, events_tx).spawn(requests);
+        handle.start().unwrap();
+        assert!(matches!(events.recv().await, Some(TableCommand::Down(addr, None)) if addr == peer));
+        drop(handle);
+        let fsm = task.await.unwrap();
+        assert_eq!(fsm.state(), State::Idle);
+    }
+
+    // Connection attempts that never complete
+    struct SilentConnector;
+
+    impl Connector for SilentConnector {
+        type Conn = MockConn;
+        fn connect(&self) -> ConnectFuture<MockConn> {
+            Box::pin(future::pending())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_task_incoming() {
+        // Our own attempt is still pending, the peer's connection is taken instead
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4));
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+        let (_in_tx, in_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (events_tx, _events) = ingest_queue(INGEST_CAPACITY);
+        let fsm = Fsm::new(PeerSessionBuilder::new().build(), OpenBuilder::new(4, 65000, 90, 1).build());
+        let (handle, requests) = PeerHandle::new(peer);
+        let task = PeerTask::new(peer, session(fsm), SilentConnector, TokioClock, events_tx)
+            .incoming(incoming)
+            .spawn(requests);
+        handle.start().unwrap();
+        // Let the task get to Connect first
+        tokio::task::yield_now().await;
+        incoming_tx.send(MockConn { tx: out_tx, rx: in_rx }).unwrap();
+        assert!(matches!(out_rx.recv().await, Some(Outbound::Open(_))));
+        drop(handle);
+        assert_eq!(task.await.unwrap().state(), State::Idle);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_task_collision() {
+        // The peer connects while our connection is in OpenSent, and both get the peer's Open. The connection
+        // initiated by the side with the higher BGP Identifier (2 against our 1, then our 3 against 2) is kept.
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+        let open = |id| OpenBuilder::new(4, 65001, 90, id).build();
+        let cease = || Outbound::Notification(Notification::new(NotifErrorCode::CeaseReason(CeaseSubcode::ConnectionCollision)));
+        for local_id in [1, 3] {
+            let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+            let (in_tx, in_rx) = mpsc::unbounded_channel();
+            let connector = MockConnector(Mutex::new(Some(MockConn { tx: out_tx, rx: in_rx })));
+            let (incoming_tx, incoming) = mpsc::unbounded_channel();
+            let (events_tx, mut events) = ingest_queue(INGEST_CAPACITY);
+            let fsm = Fsm::new(PeerSessionBuilder::new().build(), OpenBuilder::new(4, 65000, 90, local_id).build());
+            let (handle, requests) = PeerHandle::new(peer);
+            let task = PeerTask::new(peer, session(fsm), connector, TokioClock, events_tx)
+                .incoming(incoming)
+                .spawn(requests);
+            handle.start().unwrap();
+            assert!(matches!(out_rx.recv().await, Some(Outbound::Open(_))));
+
+            let (peer_out_tx, mut peer_out_rx) = mpsc::unbounded_channel();
+            let (peer_in_tx, peer_in_rx) = mpsc::unbounded_channel();
+            incoming_tx.send(MockConn { tx: peer_out_tx, rx: peer_in_rx }).unwrap();
+            assert!(matches!(peer_out_rx.recv().await, Some(Outbound::Open(_))));
+            in_tx.send(Inbound::Event(Event::BGPOpen(open(2)))).unwrap();
+            assert_eq!(out_rx.recv().await, Some(Outbound::Keepalive));
+            peer_in_tx.send(Inbound::Event(Event::BGPOpen(open(2)))).unwrap();
+
+            // The loser gets a Cease and is closed, the session goes on over the winner
+            let (mut winner, winner_tx, mut loser) = match local_id {
+                1 => (peer_out_rx, peer_in_tx, out_rx),
+                _ => (out_rx, in_tx, peer_out_rx)
+            };
+            assert_eq!(loser.recv().await, Some(cease()));
+            assert_eq!(loser.recv().await, None);
+            if local_id == 1 {
+                assert_eq!(winner.recv().await, Some(Outbound::Keepalive));
+            }
+            winner_tx.send(Inbound::Event(Event::KeepAliveMsg)).unwrap();
+            assert!(matches!(events.recv().await, Some(TableCommand::Up(addr, _)) if addr == peer));
+            assert_eq!(winner.recv().await, Some(Outbound::Keepalive));
+
+            // Both connections count for the peer
+            let stats = handle.stats().await.unwrap();
+            assert_eq!(stats.sent().get(MessageType::Open), 2);
+            assert_eq!(stats.state(), State::Established);
+            drop(handle);
+            assert_eq!(task.await.unwrap().state(), State::Idle);
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_task_negotiated_codec() {
+        // Over TCP, with the test dialing in as a peer with 4-octet AS 4200000000. Once Established the
+        // session's codec reads and writes AS_PATH with 4-octet ASes.
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let listener = PeerListener::bind(SocketAddr::new(localhost, 0)).await.unwrap();
+        let incoming = listener.add_peer(localhost, None, BgpCodec::new(localhost, 65000));
+        let (events_tx, mut events) = ingest_queue(INGEST_CAPACITY);
+        let four_octet = |my_as, asn, id| OpenBuilder::new(4, my_as, 90, id).capability(Capability::FourOctetAs(asn)).build();
+        let fsm = Fsm::new(PeerSessionBuilder::new().passive().build(), four_octet(65000, 65000, 1));
+        let connector = TcpConnector::new(localhost, BgpCodec::new(localhost, 65000));
+        let (handle, requests) = PeerHandle::new(localhost);
+        let task = PeerTask::new(localhost, session(fsm), connector, TokioClock, events_tx).incoming(incoming).spawn(requests);
+        handle.start().unwrap();
+
+        let remote = TcpConnector::new(localhost, BgpCodec::new(localhost, 23456)).port(listener.local_addr().port());
+        let mut conn = remote.connect().await.unwrap();
+        let open = four_octet(23456, 4200000000, 2);
+        conn.send(Outbound::Open(open.clone())).await.unwrap();
+        let sent = match conn.recv().await {
+            Some(Inbound::Event(Event::BGPOpen(sent))) => sent,
+            _ => panic!("expected an Open")
+        };
+        assert!(matches!(conn.recv().await, Some(Inbound::Event(Event::KeepAliveMsg))));
+        conn.send(Outbound::Keepalive).await.unwrap();
+        conn.negotiated(&SessionParams::negotiate(&open, &sent));
+        assert!(matches!(events.recv().await, Some(TableCommand::Up(..))));
+
+        // AS_PATH [4200000000] is kept as AS_TRANS and AS4_PATH
+        let body = Bytes::from_static(&[
+            0, 0, 0, 20,
+            0x40, 1, 1, 0,
+            0x40, 2, 6, 2, 1, 0xFA, 0x56, 0xEA, 0x00,
+            0x40, 3, 4, 192, 0, 2, 1,
+            24, 203, 0, 113,
+        ]);
+        let update = DecodedUpdate::decode_with(body, AsWidth::Four).unwrap().into_update();
+        conn.send(Outbound::Update(update)).await.unwrap();
+        let payload = match events.recv().await {
+            Some(TableCommand::Routes(payload)) => payload,
+            _ => panic!("expected the peer's routes")
+        };
+        let as_path = payload.path_attrs_ref().iter().find(|pa| pa.attr_type_code() == AS_PATH).unwrap();
+        assert_eq!(as_path.attr_value(), &[2, 1, 0x5B, 0xA0]);
+        assert!(payload.path_attrs_ref().iter().any(|pa| pa.attr_type_code() == AS4_PATH));
+
+        // And goes back out with the 4-octet AS in AS_PATH, which the peer reads the same way
+        let routes = payload.routes().unwrap();
+        let update = UpdateBuilder::new().nlri(Nlri::new(&routes, payload.path_attrs_ref())).build_unchecked();
+        handle.advertise(vec![update]).unwrap();
+        let received = loop {
+            match conn.recv().await {
+                Some(Inbound::Update(mut payloads)) => break payloads.remove(0),
+                Some(_) => continue,
+                None => panic!("expected an Update")
+            }
+        };
+        assert_eq!(received.routes(), Some(routes));
+        assert_eq!(received.path_attrs_ref(), payload.path_attrs_ref());
+        drop(handle);
+        assert_eq!(task.await.unwrap().state(), State::Idle);
+    }
+}
@@ -0,0 +1,136 @@
+// Peer groups: settings shared by a set of similar peers (e.g. every participant at an IXP) so each
+// peer only needs its address. A peer built from a group gets the group's session timers, address
+// families, import and export policies and export options. Anything set on the BgpPeer afterwards
+// overrides what came from the group, and the peer remembers the group's name for show commands.
+
+use std::{
+    fmt,
+    net::IpAddr,
+};
+
+use crate::{
+    export::ExportOptions,
+    fsm_ds::{BgpPeer, PeerSessionBuilder},
+    message_types::{Afi, Safi},
+    policy::RouteMap,
+};
+
+// The group has no remote AS, so peers have to be given theirs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MissingRemoteAs(pub IpAddr);
+
+impl fmt::Display for MissingRemoteAs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no remote AS for BGP peer {}", self.0)
+    }
+}
+
+impl std::error::Error for MissingRemoteAs {}
+
+#[derive(Clone)]
+pub struct PeerGroup {
+    name: String,
+    // Set for groups whose peers are all in the same AS, e.g. iBGP or route reflector clients
+    remote_as: Option<u16>,
+    session: PeerSessionBuilder,
+    afi_safis: Vec<(Afi, Safi)>,
+    import_policy: Option<RouteMap>,
+    export_policy: Option<RouteMap>,
+    export_options: ExportOptions
+}
+
+impl PeerGroup {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            remote_as: None,
+            session: PeerSessionBuilder::new(),
+            afi_safis: vec![(Afi::Ipv4, Safi::Unicast), (Afi::Ipv6, Safi::Unicast)],
+            import_policy: None,
+            export_policy: None,
+            export_options: ExportOptions::default()
+        }
+    }
+    pub fn remote_as(mut self, remote_as: u16) -> Self {
+        self.remote_as = Some(remote_as);
+        self
+    }
+    pub fn session(mut self, session: PeerSessionBuilder) -> Self {
+        // Timers and session options, each peer gets a session built from it
+        self.session = session;
+        self
+    }
+    pub fn afi_safis(mut self, afi_safis: Vec<(Afi, Safi)>) -> Self {
+        self.afi_safis = afi_safis;
+        self
+    }
+    pub fn import_policy(mut self, map: RouteMap) -> Self {
+        self.import_policy = Some(map);
+        self
+    }
+    pub fn export_policy(mut self, map: RouteMap) -> Self {
+        self.export_policy = Some(map);
+        self
+    }
+    pub fn export_options(mut self, options: ExportOptions) -> Self {
+        self.export_options = options;
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn peer(&self, peer_address: IpAddr, local_address: IpAddr) -> Result<BgpPeer, MissingRemoteAs> {
+        // For groups with a remote AS
+        match self.remote_as {
+            Some(remote_as) => Ok(self.peer_with_as(peer_address, remote_as, local_address)),
+            None => Err(MissingRemoteAs(peer_address))
+        }
+    }
+    pub fn peer_with_as(&self, peer_address: IpAddr, remote_as: u16, local_address: IpAddr) -> BgpPeer {
+        let mut peer = BgpPeer::new(peer_address, remote_as, local_address, self.session.clone().build())
+            .afi_safis(self.afi_safis.clone())
+            .import_policy(self.import_policy.clone())
+            .export_policy(self.export_policy.clone())
+            .export_options(self.export_options);
+        peer.group = Some(self.name.clone());
+        peer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::policy::{PolicyAction, RouteMapEntry};
+
+    #[test]
+    fn peer_group_members() {
+        let group = PeerGroup::new("ixp")
+            .session(PeerSessionBuilder::new().hold_time(30).passive())
+            .afi_safis(vec![(Afi::Ipv4, Safi::Unicast)])
+            .import_policy(RouteMap::new().entry(RouteMapEntry::new(10, PolicyAction::Permit)))
+            .export_options(ExportOptions { route_server_client: true, ..Default::default() });
+        let local = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254));
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(group.peer(addr, local).err(), Some(MissingRemoteAs(addr)));
+
+        let peer = group.peer_with_as(addr, 65001, local);
+        assert_eq!((peer.remote_as, peer.group.as_deref()), (65001, Some("ixp")));
+        assert_eq!((peer.session().hold_time(), peer.session().passive()), (30, true));
+        assert_eq!(peer.families(), &[(Afi::Ipv4, Safi::Unicast)]);
+        assert!(peer.export_settings().route_server_client);
+        assert!(matches!(peer.policies(), (Some(_), None)));
+
+        // Set on the peer wins over the group
+        let peer = group
+            .clone()
+            .remote_as(65002)
+            .peer(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), local)
+            .unwrap()
+            .import_policy(None)
+            .afi_safis(vec![(Afi::Ipv6, Safi::Unicast)]);
+        assert_eq!(peer.remote_as, 65002);
+        assert_eq!(peer.families(), &[(Afi::Ipv6, Safi::Unicast)]);
+        assert!(matches!(peer.policies(), (None, None)));
+    }
+}
@@ -0,0 +1,743 @@
+// Route maps that can be attached per peer and per direction. Import policy runs between the decoder
+// and BgpTable::walk, export policy runs between the walk (after the default export rules) and
+// Update generation.
+// A route map is an ordered list of entries. The first entry whose match clauses all match decides
+// whether the route is permitted (with the entry's set actions applied) or denied. A route that
+// matches no entry is denied.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    net::IpAddr,
+};
+
+use crate::{
+    comms::ReceivedRoutes,
+    message_types::{HostBits, Nlri, Route},
+    path_attrs::*,
+    trie::{ip_bits, PrefixTrie},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct PolicyError(String);
+impl Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let PolicyError(msg) = self;
+        write!(f, "{}", msg)
+    }
+}
+impl Error for PolicyError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Import,
+    Export
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    Permit,
+    Deny
+}
+
+// Matches communities by their two halves, e.g. "65000:*". None matches any value. RFC 1997
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommunityPattern {
+    asn: Option<u16>,
+    value: Option<u16>
+}
+
+impl CommunityPattern {
+    pub fn new(asn: Option<u16>, value: Option<u16>) -> Self {
+        Self {
+            asn,
+            value
+        }
+    }
+    pub fn parse(pattern: &str) -> Result<Self, PolicyError> {
+        // Parses "<asn>:<value>" where either half can be "*"
+        let err = || PolicyError(format!("invalid community pattern '{}'", pattern));
+        let half = |part: &str| -> Result<Option<u16>, PolicyError> {
+            match part {
+                "*" => Ok(None),
+                part => part.parse().map(Some).map_err(|_| err())
+            }
+        };
+        let (asn, value) = pattern.split_once(':').ok_or_else(err)?;
+        Ok(Self::new(half(asn)?, half(value)?))
+    }
+    pub fn matches(&self, community: u32) -> bool {
+        let asn = (community >> 16) as u16;
+        let value = community as u16;
+        self.asn.map_or(true, |a| a == asn) && self.value.map_or(true, |v| v == value)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum MatchClause {
+    // Route is exactly one of the prefixes
+    Prefix(Vec<Route>),
+    // Route is permitted by the prefix list
+    PrefixList(PrefixList),
+    // AS appears anywhere in the AS_PATH
+    AsPathContains(u16),
+    // AS the route was learned from (first AS in the path)
+    NeighborAs(u16),
+    // AS that originated the route (last AS in the path)
+    OriginAs(u16),
+    Community(u32),
+    // Any community matches the pattern
+    CommunityPattern(CommunityPattern),
+    // Route carries at least one community
+    HasCommunities,
+    Origin(OriginValue),
+    Med(u32)
+}
+
+impl MatchClause {
+    pub fn matches(&self, route: &Route, pas: &[PathAttr]) -> bool {
+        let ases = || -> Vec<u16> {
+            pas
+            .iter()
+            .find_map(|pa| pa.as_path())
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|seg| match seg {
+                AsSegment::AsSequence(ases) | AsSegment::AsSet(ases) => ases
+            })
+            .collect()
+        };
+        match self {
+            MatchClause::Prefix(prefixes) => prefixes.contains(route),
+            MatchClause::PrefixList(list) => list.permits(route),
+            MatchClause::AsPathContains(asn) => ases().contains(asn),
+            MatchClause::NeighborAs(asn) => ases().first() == Some(asn),
+            MatchClause::OriginAs(asn) => ases().last() == Some(asn),
+            MatchClause::Community(community) => pas
+                .iter()
+                .find_map(|pa| pa.communities())
+                .is_some_and(|communities| communities.contains(community)),
+            MatchClause::CommunityPattern(pattern) => pas
+                .iter()
+                .find_map(|pa| pa.communities())
+                .is_some_and(|communities| communities.iter().any(|c| pattern.matches(*c))),
+            MatchClause::HasCommunities => pas.iter().any(|pa| pa.attr_type_code() == COMMUNITIES),
+            MatchClause::Origin(origin) => pas
+                .iter()
+                .find_map(|pa| pa.origin())
+                .is_some_and(|value| value == u8::from(origin.clone())),
+            MatchClause::Med(med) => pas.iter().find_map(|pa| pa.med()) == Some(*med)
+        }
+    }
+}
+
+// A single prefix list entry, e.g. "10.0.0.0/8 ge 16 le 24". Without ge/le only the exact prefix matches.
+// With ge and/or le, any route covered by the prefix whose length falls within [ge, le] matches. ge
+// defaults to the prefix length and le to the maximum length for the address family.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixListEntry {
+    seq: u32,
+    action: PolicyAction,
+    prefix: Route,
+    ge: u8,
+    le: u8
+}
+
+impl PrefixListEntry {
+    pub fn new(seq: u32, action: PolicyAction, prefix: Route, ge: Option<u8>, le: Option<u8>) -> Result<Self, PolicyError> {
+        let max_len: u8 = match prefix.prefix_v4() {
+            Some(_) => 32,
+            None => 128
+        };
+        let len = prefix.prefix_len();
+        if len > max_len {
+            return Err(PolicyError(format!("prefix length {} is longer than {}", len, max_len)));
+        }
+        let (ge, le) = match (ge, le) {
+            (None, None) => (len, len),
+            (ge, le) => (ge.unwrap_or(len), le.unwrap_or(max_len))
+        };
+        if ge < len || ge > le || le > max_len {
+            return Err(PolicyError(format!(
+                "invalid range ge {} le {} for a /{} prefix", ge, le, len
+            )));
+        }
+        Ok(Self {
+            seq,
+            action,
+            prefix,
+            ge,
+            le
+        })
+    }
+    pub fn parse(seq: u32, action: PolicyAction, entry: &str) -> Result<Self, PolicyError> {
+        // Parses entries of the form "<prefix>/<len> [ge <len>] [le <len>]"
+        let err = || PolicyError(format!("invalid prefix list entry '{}'", entry));
+        let mut tokens = entry.split_whitespace();
+        let prefix = tokens
+            .next()
+            .and_then(|cidr| Route::parse(cidr, HostBits::Normalize).ok())
+            .ok_or_else(err)?;
+
+        let mut ge: Option<u8> = None;
+        let mut le: Option<u8> = None;
+        while let Some(keyword) = tokens.next() {
+            let value: u8 = tokens
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(err)?;
+            match keyword {
+                "ge" if ge.is_none() && le.is_none() => ge = Some(value),
+                "le" if le.is_none() => le = Some(value),
+                _ => return Err(err())
+            }
+        }
+        Self::new(seq, action, prefix, ge, le)
+    }
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+    pub fn matches(&self, route: &Route) -> bool {
+        self.prefix.contains(route_addr(route))
+            && route.afi() == self.prefix.afi()
+            && (self.ge..=self.le).contains(&route.prefix_len())
+    }
+}
+
+fn route_addr(route: &Route) -> IpAddr {
+    route
+    .prefix_v4()
+    .map(IpAddr::V4)
+    .or_else(|| route.prefix_v6().map(IpAddr::V6))
+    .expect("Route is either v4 or v6")
+}
+
+// Ordered list of prefix list entries. The first matching entry (by sequence number) decides,
+// no match is a deny. Entries are indexed in a trie by prefix so only entries covering the route
+// are looked at.
+#[derive(Clone, Debug)]
+pub struct PrefixList {
+    entries: Vec<PrefixListEntry>,
+    // Indexes into entries
+    index: PrefixTrie<Vec<usize>>
+}
+
+impl PrefixList {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            index: PrefixTrie::new()
+        }
+    }
+    pub fn entry(mut self, entry: PrefixListEntry) -> Self {
+        // An entry with an existing sequence number replaces it.
+        self.entries.retain(|existing| existing.seq != entry.seq);
+        self.entries.push(entry);
+        self.entries.sort_by_key(|entry| entry.seq);
+
+        // v4 and v6 prefixes share the trie, the AFI is checked on match
+        let mut index: PrefixTrie<Vec<usize>> = PrefixTrie::new();
+        self.entries
+        .iter()
+        .enumerate()
+        .for_each(|(idx, entry)| {
+            let bits = ip_bits(route_addr(&entry.prefix));
+            match index.get_mut(bits, entry.prefix.prefix_len()) {
+                Some(idxs) => idxs.push(idx),
+                None => _ = index.insert(bits, entry.prefix.prefix_len(), vec![idx])
+            }
+        });
+        self.index = index;
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn evaluate(&self, route: &Route) -> Option<PolicyAction> {
+        // Returns the action of the first matching entry, None if no entry matches.
+        self.index
+        .covering(ip_bits(route_addr(route)), route.prefix_len())
+        .into_iter()
+        .flat_map(|(_, idxs)| idxs.iter())
+        .filter(|idx| self.entries[**idx].matches(route))
+        .min()
+        .map(|idx| self.entries[*idx].action)
+    }
+    pub fn permits(&self, route: &Route) -> bool {
+        self.evaluate(route) == Some(PolicyAction::Permit)
+    }
+}
+
+impl Default for PrefixList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SetAction {
+    LocalPref(u32),
+    Med(u32),
+    // Added to any communities already on the route
+    AddCommunities(Vec<u32>),
+    // Replaces any communities already on the route
+    SetCommunities(Vec<u32>),
+    // Removes the communities matching the pattern
+    DeleteCommunities(CommunityPattern),
+    // Prepends the AS the given number of times
+    AsPathPrepend(u16, u8),
+    NextHop(IpAddr),
+    // Not a PA, only has an effect on import
+    Weight(u32)
+}
+
+impl SetAction {
+    pub fn apply(&self, pas: &mut Vec<PathAttr>) {
+        // Replaces (or adds) the PA the action applies to.
+        let (type_code, pa) = match self {
+            SetAction::Weight(_) => return,
+            SetAction::SetCommunities(communities) if communities.is_empty() => {
+                pas.retain(|pa| pa.attr_type_code() != COMMUNITIES);
+                return;
+            },
+            SetAction::SetCommunities(communities) => (
+                COMMUNITIES,
+                PathAttrBuilder::<Communities>::new().communities(communities.clone()).build()
+            ),
+            SetAction::DeleteCommunities(pattern) => {
+                let remaining: Vec<u32> = pas
+                    .iter()
+                    .find_map(|pa| pa.communities())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|community| !pattern.matches(*community))
+                    .collect();
+                SetAction::SetCommunities(remaining).apply(pas);
+                return;
+            },
+            SetAction::LocalPref(lp) => (
+                LOCAL_PREF,
+                PathAttrBuilder::<LocalPref>::new().local_pref(*lp).build()
+            ),
+            SetAction::Med(med) => (
+                MED,
+                PathAttrBuilder::<Med>::new().metric(*med).build()
+            ),
+            SetAction::AddCommunities(add) => {
+                let mut communities = pas
+                    .iter()
+                    .find_map(|pa| pa.communities())
+                    .unwrap_or_default();
+                for community in add {
+                    if !communities.contains(community) {
+                        communities.push(*community);
+                    }
+                }
+                (COMMUNITIES, PathAttrBuilder::<Communities>::new().communities(communities).build())
+            },
+            SetAction::AsPathPrepend(asn, count) => {
+                let mut aspath = match pas.iter().find(|pa| pa.attr_type_code() == AS_PATH) {
+                    Some(aspath) => aspath.clone(),
+                    None => PathAttrBuilder::<AsPath>::new()
+                        .as_segments(Vec::new())
+                        .build()
+                        .expect("Empty AS_PATH is valid")
+                };
+                for _ in 0..*count {
+                    aspath = as_path_prepend(&aspath, *asn);
+                }
+                (AS_PATH, Ok(aspath))
+            },
+            SetAction::NextHop(addr) => (
+                NEXT_HOP,
+                PathAttrBuilder::<NextHop>::new().next_hop(*addr).build()
+            )
+        };
+        pas.retain(|pa| pa.attr_type_code() != type_code);
+        pas.push(pa.expect("Set action values are always valid"));
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteMapEntry {
+    seq: u32,
+    action: PolicyAction,
+    matches: Vec<MatchClause>,
+    sets: Vec<SetAction>
+}
+
+impl RouteMapEntry {
+    pub fn new(seq: u32, action: PolicyAction) -> Self {
+        Self {
+            seq,
+            action,
+            matches: Vec::new(),
+            sets: Vec::new()
+        }
+    }
+    pub fn match_clause(mut self, clause: MatchClause) -> Self {
+        self.matches.push(clause);
+        self
+    }
+    pub fn set(mut self, action: SetAction) -> Self {
+        self.sets.push(action);
+        self
+    }
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+    pub fn matches(&self, route: &Route, pas: &[PathAttr]) -> bool {
+        // An entry without match clauses matches everything
+        self.matches.iter().all(|clause| clause.matches(route, pas))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RouteMap {
+    entries: Vec<RouteMapEntry>
+}
+
+impl RouteMap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new()
+        }
+    }
+    pub fn entry(mut self, entry: RouteMapEntry) -> Self {
+        // Entries are evaluated in sequence number order. An entry with an existing
+        // sequence number replaces it.
+        self.entries.retain(|existing| existing.seq != entry.seq);
+        self.entries.push(entry);
+        self.entries.sort_by_key(|entry| entry.seq);
+        self
+    }
+    pub fn apply(&self, route: &Route, pas: &[PathAttr]) -> Option<Vec<PathAttr>> {
+        // Returns the PAs to use for the route, or None if the route is denied.
+        self.evaluate(route, pas).map(|(pas, _)| pas)
+    }
+    pub fn evaluate(&self, route: &Route, pas: &[PathAttr]) -> Option<(Vec<PathAttr>, Option<u32>)> {
+        // Same as apply(), but also returns the weight if the matching entry sets one.
+        let entry = self.entries.iter().find(|entry| entry.matches(route, pas))?;
+        match entry.action {
+            PolicyAction::Permit => {
+                let mut out = pas.to_vec();
+                entry.sets.iter().for_each(|set| set.apply(&mut out));
+                let weight = entry.sets.iter().rev().find_map(|set| match set {
+                    SetAction::Weight(weight) => Some(*weight),
+                    _ => None
+                });
+                Some((canonicalize_attrs(out), weight))
+            },
+            PolicyAction::Deny => None
+        }
+    }
+    pub(crate) fn filter_payload(&self, payload: &ReceivedRoutes, default_weight: Option<u32>) -> Vec<ReceivedRoutes> {
+        // Runs the announced routes of a payload through the map. Routes that end up with the same PAs
+        // share a payload. Denied routes are treated as withdrawn, in case an earlier announcement was
+        // permitted. Withdrawn routes are passed through untouched.
+        let mut withdrawn: Vec<Route> = payload.withdrawn_routes().unwrap_or_default();
+        let mut grouped: HashMap<(Vec<PathAttr>, Option<u32>), Vec<Route>> = HashMap::new();
+        payload
+        .routes()
+        .unwrap_or_default()
+        .into_iter()
+        .for_each(|route| {
+            match self.evaluate(&route, payload.path_attrs_ref()) {
+                Some(result) => grouped.entry(result).or_default().push(route),
+                None => withdrawn.push(route)
+            }
+        });
+
+        let mut out: Vec<ReceivedRoutes> = grouped
+            .into_iter()
+            .map(|((pas, weight), routes)| {
+                let mut permitted = payload.with_routes(pas, Some(routes), None);
+                permitted.sync_decision_data();
+                if let Some(weight) = weight.or(default_weight) {
+                    permitted.set_weight(weight);
+                }
+                permitted
+            })
+            .collect();
+        if !withdrawn.is_empty() {
+            out.push(payload.with_routes(payload.path_attrs(), None, Some(withdrawn)));
+        }
+        out
+    }
+    pub fn filter_nlri(&self, withdrawn: Vec<Route>, nlri: Vec<Nlri>) -> (Vec<Route>, Vec<Nlri>) {
+        // Runs the contents of an Update through the map. Denied routes are withdrawn instead,
+        // in case the peer has an earlier version of them.
+        let mut withdrawn = withdrawn;
+        let mut grouped: HashMap<Vec<PathAttr>, Vec<Route>> = HashMap::new();
+        nlri
+        .iter()
+        .for_each(|group| {
+            group
+            .routes()
+            .iter()
+            .for_each(|route| {
+                match self.apply(route, group.path_attrs()) {
+                    Some(pas) => grouped.entry(pas).or_default().push(route.clone()),
+                    None => withdrawn.push(route.clone())
+                }
+            })
+        });
+        let nlri = grouped
+            .iter()
+            .flat_map(|(pas, routes)| Nlri::chunked(routes.as_slice(), pas.as_slice()))
+            .collect();
+        (withdrawn, nlri)
+    }
+}
+
+// Holds the route maps attached to each peer.
+pub(crate) struct PolicyEngine {
+    maps: HashMap<(IpAddr, Direction), RouteMap>,
+    // Weight given to paths from the peer unless import policy sets one
+    default_weights: HashMap<IpAddr, u32>
+}
+
+impl PolicyEngine {
+    pub fn new() -> Self {
+        Self {
+            maps: HashMap::new(),
+            default_weights: HashMap::new()
+        }
+    }
+    pub fn set_default_weight(&mut self, peer: IpAddr, weight: u32) {
+        self.default_weights.insert(peer, weight);
+    }
+    pub fn attach(&mut self, peer: IpAddr, direction: Direction, map: RouteMap) {
+        self.maps.insert((peer, direction), map);
+    }
+    pub fn detach(&mut self, peer: IpAddr, direction: Direction) -> Option<RouteMap> {
+        self.maps.remove(&(peer, direction))
+    }
+    pub fn apply(&self, peer: IpAddr, direction: Direction, route: &Route, pas: &[PathAttr]) -> Option<Vec<PathAttr>> {
+        // Peers without a route map for the direction permit everything unchanged.
+        match self.maps.get(&(peer, direction)) {
+            Some(map) => map.apply(route, pas),
+            None => Some(pas.to_vec())
+        }
+    }
+    pub fn apply_import(&self, payload: ReceivedRoutes) -> Vec<ReceivedRoutes> {
+        // Runs the announced routes of a received payload through the peer's import policy,
+        // see RouteMap::filter_payload().
+        let peer = payload.peer_addr();
+        let default_weight = self.default_weights.get(&peer).copied();
+        match self.maps.get(&(peer, Direction::Import)) {
+            Some(map) => map.filter_payload(&payload, default_weight),
+            None => {
+                let mut payload = payload;
+                if let Some(weight) = default_weight {
+                    payload.set_weight(weight);
+                }
+                vec![payload]
+            }
+        }
+    }
+    pub fn apply_export(&self, peer: IpAddr, withdrawn: Vec<Route>, nlri: Vec<Nlri>) -> (Vec<Route>, Vec<Nlri>) {
+        // Runs the Update contents for a peer through its export policy, see RouteMap::filter_nlri().
+        match self.maps.get(&(peer, Direction::Export)) {
+            Some(map) => map.filter_nlri(withdrawn, nlri),
+            None => (withdrawn, nlri)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::comms::MockReceivedRoutesBuilder;
+
+    fn pas() -> Vec<PathAttr> {
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001, 65010])])
+            .build()
+            .unwrap();
+        let comms = PathAttrBuilder::<Communities>::new().communities(vec![0xFDE90001]).build().unwrap();
+        canonicalize_attrs(vec![origin, aspath, comms])
+    }
+
+    fn route(octet: u8) -> Route {
+        Route::new(24, IpAddr::V4(Ipv4Addr::new(10, octet, 0, 0)))
+    }
+
+    #[test]
+    fn route_map_match_and_set() {
+        let map = RouteMap::new()
+            .entry(RouteMapEntry::new(10, PolicyAction::Deny).match_clause(MatchClause::Prefix(vec![route(1)])))
+            .entry(
+                RouteMapEntry::new(20, PolicyAction::Permit)
+                .match_clause(MatchClause::OriginAs(65010))
+                .match_clause(MatchClause::Community(0xFDE90001))
+                .set(SetAction::LocalPref(300))
+                .set(SetAction::AsPathPrepend(65000, 2))
+                .set(SetAction::AddCommunities(vec![0xFDE90002]))
+            );
+
+        assert!(map.apply(&route(1), &pas()).is_none());
+        let out = map.apply(&route(2), &pas()).unwrap();
+        assert_eq!(out.iter().find_map(|pa| pa.local_pref()), Some(300));
+        assert_eq!(
+            out.iter().find_map(|pa| pa.as_path()),
+            Some(vec![AsSegment::AsSequence(vec![65000, 65000, 65001, 65010])])
+        );
+        assert_eq!(out.iter().find_map(|pa| pa.communities()), Some(vec![0xFDE90001, 0xFDE90002]));
+
+        // Implicit deny
+        let map = RouteMap::new().entry(RouteMapEntry::new(10, PolicyAction::Permit).match_clause(MatchClause::Med(5)));
+        assert!(map.apply(&route(2), &pas()).is_none());
+    }
+
+    #[test]
+    fn policy_import() {
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route(1), route(2)]), None, pas()).build();
+        let peer = payload.peer_addr();
+        let mut engine = PolicyEngine::new();
+        engine.attach(peer, Direction::Import, RouteMap::new()
+            .entry(RouteMapEntry::new(10, PolicyAction::Deny).match_clause(MatchClause::Prefix(vec![route(1)])))
+            .entry(RouteMapEntry::new(20, PolicyAction::Permit).set(SetAction::LocalPref(250)).set(SetAction::Med(7)))
+        );
+
+        let out = engine.apply_import(payload);
+        assert_eq!(out.len(), 2);
+        let permitted = out.iter().find(|rr| rr.routes().is_some()).unwrap();
+        assert_eq!(permitted.routes(), Some(vec![route(2)]));
+        // Decision data follows the PAs set by policy
+        assert_eq!(permitted.local_pref(), Some(250));
+        assert_eq!(permitted.med(), 7);
+        assert_eq!(permitted.as_path_len(), 2);
+        let denied = out.iter().find(|rr| rr.withdrawn_routes().is_some()).unwrap();
+        assert_eq!(denied.withdrawn_routes(), Some(vec![route(1)]));
+    }
+
+    #[test]
+    fn policy_export() {
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut engine = PolicyEngine::new();
+        let nlri = vec![Nlri::new(&[route(1), route(2)], &pas())];
+
+        // No policy attached, nothing changes
+        let (withdrawn, out) = engine.apply_export(peer, Vec::new(), nlri);
+        assert!(withdrawn.is_empty());
+        assert_eq!(out[0].routes().len(), 2);
+
+        engine.attach(peer, Direction::Export, RouteMap::new()
+            .entry(RouteMapEntry::new(10, PolicyAction::Permit).match_clause(MatchClause::Prefix(vec![route(2)])))
+        );
+        let (withdrawn, out) = engine.apply_export(peer, Vec::new(), out);
+        assert_eq!(withdrawn, vec![route(1)]);
+        assert_eq!(out[0].routes(), &[route(2)]);
+        assert!(engine.detach(peer, Direction::Export).is_some());
+    }
+
+    #[test]
+    fn prefix_list_parse() {
+        let entry = PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8 ge 16 le 24").unwrap();
+        assert_eq!((entry.ge, entry.le), (16, 24));
+        let entry = PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8 le 24").unwrap();
+        assert_eq!((entry.ge, entry.le), (8, 24));
+        let entry = PrefixListEntry::parse(10, PolicyAction::Permit, "2001:db8::/32 ge 48").unwrap();
+        assert_eq!((entry.ge, entry.le), (48, 128));
+        let entry = PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8").unwrap();
+        assert_eq!((entry.ge, entry.le), (8, 8));
+
+        assert!(PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8 ge 4").is_err());
+        assert!(PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8 ge 24 le 16").is_err());
+        assert!(PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8 le 33").is_err());
+        assert!(PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8 le 24 ge 16").is_err());
+        assert!(PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0 ge 16").is_err());
+    }
+
+    #[test]
+    fn prefix_list_match() {
+        let list = PrefixList::new()
+            .entry(PrefixListEntry::parse(5, PolicyAction::Deny, "10.1.0.0/16 le 32").unwrap())
+            .entry(PrefixListEntry::parse(10, PolicyAction::Permit, "10.0.0.0/8 ge 16 le 24").unwrap())
+            .entry(PrefixListEntry::parse(20, PolicyAction::Permit, "0.0.0.0/0").unwrap());
+
+        let v4 = |a, b, c, len| Route::new(len, IpAddr::V4(Ipv4Addr::new(10, a, b, c)));
+        assert!(list.permits(&v4(2, 0, 0, 16)));
+        assert!(list.permits(&v4(2, 3, 0, 24)));
+        assert!(!list.permits(&v4(2, 3, 4, 32)));
+        assert!(!list.permits(&v4(0, 0, 0, 8)));
+        // Earlier deny wins
+        assert_eq!(list.evaluate(&v4(1, 1, 0, 24)), Some(PolicyAction::Deny));
+        assert!(list.permits(&Route::new(0, IpAddr::V4(Ipv4Addr::UNSPECIFIED))));
+        // v4 entries never match v6 routes
+        assert_eq!(list.evaluate(&Route::new(16, "a00::".parse().unwrap())), None);
+
+        let map = RouteMap::new()
+            .entry(RouteMapEntry::new(10, PolicyAction::Permit).match_clause(MatchClause::PrefixList(list)));
+        assert!(map.apply(&v4(2, 0, 0, 16), &pas()).is_some());
+        assert!(map.apply(&v4(1, 0, 0, 16), &pas()).is_none());
+    }
+
+    #[test]
+    fn community_pattern() {
+        let pattern = CommunityPattern::parse("65001:*").unwrap();
+        assert!(pattern.matches(0xFDE90001));
+        assert!(!pattern.matches(0xFDE80001));
+        let pattern = CommunityPattern::parse("*:1").unwrap();
+        assert!(pattern.matches(0xFDE80001));
+        assert!(!pattern.matches(0xFDE80002));
+        assert!(CommunityPattern::parse("65001").is_err());
+        assert!(CommunityPattern::parse("65001:x").is_err());
+    }
+
+    #[test]
+    fn community_match_and_set() {
+        let map = RouteMap::new()
+            .entry(
+                RouteMapEntry::new(10, PolicyAction::Permit)
+                .match_clause(MatchClause::CommunityPattern(CommunityPattern::parse("65001:*").unwrap()))
+                .set(SetAction::DeleteCommunities(CommunityPattern::parse("65001:*").unwrap()))
+            )
+            .entry(
+                RouteMapEntry::new(20, PolicyAction::Permit)
+                .set(SetAction::AddCommunities(vec![NO_EXPORT]))
+            );
+
+        // Deleting the only community removes the PA
+        let out = map.apply(&route(1), &pas()).unwrap();
+        assert!(!MatchClause::HasCommunities.matches(&route(1), &out));
+
+        let out = map.apply(&route(1), &out).unwrap();
+        assert_eq!(out.iter().find_map(|pa| pa.communities()), Some(vec![NO_EXPORT]));
+
+        let mut pas = pas();
+        SetAction::SetCommunities(vec![1, 2]).apply(&mut pas);
+        assert_eq!(pas.iter().find_map(|pa| pa.communities()), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn policy_weight() {
+        let payload = || MockReceivedRoutesBuilder::new(Some(vec![route(1), route(2)]), None, pas()).build();
+        let peer = payload().peer_addr();
+        let mut engine = PolicyEngine::new();
+        engine.set_default_weight(peer, 50);
+        assert_eq!(engine.apply_import(payload())[0].weight(), 50);
+
+        engine.attach(peer, Direction::Import, RouteMap::new()
+            .entry(
+                RouteMapEntry::new(10, PolicyAction::Permit)
+                .match_clause(MatchClause::Prefix(vec![route(1)]))
+                .set(SetAction::Weight(200))
+            )
+            .entry(RouteMapEntry::new(20, PolicyAction::Permit))
+        );
+        let out = engine.apply_import(payload());
+        assert_eq!(out.len(), 2);
+        let weighted = out.iter().find(|rr| rr.routes() == Some(vec![route(1)])).unwrap();
+        assert_eq!(weighted.weight(), 200);
+        let default = out.iter().find(|rr| rr.routes() == Some(vec![route(2)])).unwrap();
+        assert_eq!(default.weight(), 50);
+    }
+}
@@ -0,0 +1,213 @@
+// Redistribution of the routes the host already has (connected, static and other kernel routes) into BGP.
+// Changes to the host's routing table come in as KernelRouteEvents, from KernelRouteMonitor with the
+// netlink feature or from anything else watching a routing table, and Redistribution turns the ones of the
+// redistributed kinds into originations and withdrawals for Speaker::redistribute (or a BgpTable).
+// Speaker::redistribute_kernel does both for a KernelRouteMonitor. Each kind is originated with its own
+// attributes, then run through the redistribution filter: routes the route map denies stay out, set
+// actions on ORIGIN, MED and COMMUNITIES carry over (the rest have no meaning for a locally originated
+// path).
+// Redistributed routes share the originated routes with the configured networks, withdrawing one that's
+// also a network withdraws the network.
+
+use std::collections::HashMap;
+
+use crate::{
+    message_types::Route,
+    path_attrs::*,
+    policy::RouteMap,
+    table::LocalRoutes,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteKind {
+    // Subnets of the host's interfaces
+    Connected,
+    Static,
+    // Installed by anything else, e.g. another routing daemon or DHCP
+    Kernel
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KernelRouteEvent {
+    Added(Route, RouteKind),
+    Removed(Route, RouteKind)
+}
+
+#[derive(Clone, Debug)]
+pub enum Redistributed {
+    Originate(LocalRoutes),
+    Withdraw(Vec<Route>)
+}
+
+// The attributes a kind of route is originated with, INCOMPLETE origin unless set (RFC 4271, Pg. 18)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RedistributeAttrs {
+    origin: OriginValue,
+    med: Option<u32>,
+    communities: Vec<u32>
+}
+
+impl RedistributeAttrs {
+    pub fn new() -> Self {
+        Self {
+            origin: OriginValue::Incomplete,
+            med: None,
+            communities: Vec::new()
+        }
+    }
+    pub fn origin(mut self, origin: OriginValue) -> Self {
+        self.origin = origin;
+        self
+    }
+    pub fn med(mut self, med: u32) -> Self {
+        self.med = Some(med);
+        self
+    }
+    pub fn communities(mut self, communities: Vec<u32>) -> Self {
+        self.communities = communities;
+        self
+    }
+    fn local_routes(&self, route: Route) -> LocalRoutes {
+        let local = LocalRoutes::new(vec![route])
+            .origin(self.origin.clone())
+            .communities(self.communities.clone());
+        match self.med {
+            Some(med) => local.med(med),
+            None => local
+        }
+    }
+}
+
+impl Default for RedistributeAttrs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Redistribution {
+    kinds: HashMap<RouteKind, RedistributeAttrs>,
+    filter: Option<RouteMap>,
+    // What's been originated, along with the kind of route it came from
+    originated: HashMap<Route, RouteKind>
+}
+
+impl Redistribution {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn kind(mut self, kind: RouteKind, attrs: RedistributeAttrs) -> Self {
+        // Redistributes routes of the kind
+        self.kinds.insert(kind, attrs);
+        self
+    }
+    pub fn filter(mut self, map: RouteMap) -> Self {
+        self.filter = Some(map);
+        self
+    }
+    pub fn originated(&self) -> Vec<Route> {
+        let mut routes: Vec<Route> = self.originated.keys().cloned().collect();
+        routes.sort();
+        routes
+    }
+    pub fn update(&mut self, event: KernelRouteEvent) -> Option<Redistributed> {
+        // What the change to the host's table does to the originated routes, None if nothing
+        match event {
+            KernelRouteEvent::Added(route, kind) => {
+                let local = self.kinds.get(&kind)?.local_routes(route.clone());
+                match self.permit(&route, local) {
+                    Some(local) => {
+                        self.originated.insert(route, kind);
+                        Some(Redistributed::Originate(local))
+                    },
+                    // Denied now, e.g. after a change of metric, so it's no longer redistributed
+                    None => self.originated.remove(&route).map(|_| Redistributed::Withdraw(vec![route]))
+                }
+            },
+            KernelRouteEvent::Removed(route, kind) => match self.originated.get(&route) == Some(&kind) {
+                true => {
+                    self.originated.remove(&route);
+                    Some(Redistributed::Withdraw(vec![route]))
+                },
+                false => None
+            }
+        }
+    }
+    fn permit(&self, route: &Route, local: LocalRoutes) -> Option<LocalRoutes> {
+        // The routes as the filter leaves them, None if it denies them
+        let map = match &self.filter {
+            Some(map) => map,
+            None => return Some(local)
+        };
+        let pas = map.apply(route, &local.path_attrs())?;
+        let origin = pas
+            .iter()
+            .find_map(|pa| pa.origin())
+            .and_then(|origin| OriginValue::try_from(origin).ok())
+            .unwrap_or(OriginValue::Incomplete);
+        let local = LocalRoutes::new(vec![route.clone()])
+            .origin(origin)
+            .communities(pas.iter().find_map(|pa| pa.communities()).unwrap_or_default());
+        match pas.iter().find_map(|pa| pa.med()) {
+            Some(med) => Some(local.med(med)),
+            None => Some(local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use crate::policy::{MatchClause, PolicyAction, RouteMapEntry, SetAction};
+
+    fn route(third: u8) -> Route {
+        Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, third, 0)))
+    }
+
+    fn originated(change: Option<Redistributed>) -> Vec<PathAttr> {
+        match change {
+            Some(Redistributed::Originate(local)) => local.path_attrs(),
+            change => panic!("expected an origination, got {:?}", change)
+        }
+    }
+
+    #[test]
+    fn redistribute_kinds() {
+        let mut redist = Redistribution::new()
+            .kind(RouteKind::Connected, RedistributeAttrs::new())
+            .kind(RouteKind::Static, RedistributeAttrs::new().med(10).communities(vec![0xFDE80001]));
+
+        let pas = originated(redist.update(KernelRouteEvent::Added(route(1), RouteKind::Connected)));
+        assert_eq!(pas.iter().find_map(|pa| pa.origin()), Some(2));
+        assert_eq!(pas.iter().find_map(|pa| pa.med()), None);
+        let pas = originated(redist.update(KernelRouteEvent::Added(route(2), RouteKind::Static)));
+        assert_eq!(pas.iter().find_map(|pa| pa.med()), Some(10));
+        assert_eq!(pas.iter().find_map(|pa| pa.communities()), Some(vec![0xFDE80001]));
+        // Kinds that aren't redistributed are ignored
+        assert!(redist.update(KernelRouteEvent::Added(route(3), RouteKind::Kernel)).is_none());
+        assert_eq!(redist.originated(), vec![route(1), route(2)]);
+
+        assert!(redist.update(KernelRouteEvent::Removed(route(2), RouteKind::Kernel)).is_none());
+        assert!(matches!(
+            redist.update(KernelRouteEvent::Removed(route(2), RouteKind::Static)),
+            Some(Redistributed::Withdraw(routes)) if routes == vec![route(2)]
+        ));
+        assert_eq!(redist.originated(), vec![route(1)]);
+    }
+
+    #[test]
+    fn redistribute_filter() {
+        let map = RouteMap::new()
+            .entry(RouteMapEntry::new(10, PolicyAction::Deny).match_clause(MatchClause::Prefix(vec![route(1)])))
+            .entry(RouteMapEntry::new(20, PolicyAction::Permit).set(SetAction::Med(50)));
+        let mut redist = Redistribution::new()
+            .kind(RouteKind::Connected, RedistributeAttrs::new())
+            .filter(map);
+
+        assert!(redist.update(KernelRouteEvent::Added(route(1), RouteKind::Connected)).is_none());
+        let pas = originated(redist.update(KernelRouteEvent::Added(route(2), RouteKind::Connected)));
+        assert_eq!(pas.iter().find_map(|pa| pa.med()), Some(50));
+        assert_eq!(redist.originated(), vec![route(2)]);
+    }
+}
@@ -0,0 +1,57 @@
+// Events about the router as a whole for applications embedding the crate (controllers, exporters), so
+// they can react to sessions and table changes without polling. The Speaker's RIB task publishes them on a
+// broadcast channel, every subscriber gets every event from when it subscribed. Subscribers that fall
+// more than ROUTER_EVENT_CAPACITY events behind miss the oldest ones and are told how many by
+// RecvError::Lagged, after a burst of table changes they should resync from the table if that matters.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::{
+    message_types::{Afi, Route},
+    session_events::SessionError,
+};
+
+pub(crate) const ROUTER_EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouterEvent {
+    PeerUp {
+        peer: IpAddr,
+        remote_as: u32,
+        router_id: Ipv4Addr
+    },
+    // Along with the NOTIFICATION that ended the session, None if the connection just went away
+    PeerDown {
+        peer: IpAddr,
+        notification: Option<SessionError>
+    },
+    // Routes whose bestpath changed in one go (e.g. a single Update), withdrawn ones no longer have one
+    BestPathChanged {
+        afi: Afi,
+        changed: Vec<Route>,
+        withdrawn: Vec<Route>
+    },
+    // Raised once each time the peer goes over its maximum-prefix limit
+    PrefixLimitExceeded {
+        peer: IpAddr,
+        count: usize,
+        limit: usize
+    },
+    // Follows the BestPathChanged events of a walk, for consumers tracking changes by table version
+    TableVersionBumped {
+        afi: Afi,
+        version: usize
+    }
+}
+
+impl RouterEvent {
+    pub fn peer(&self) -> Option<IpAddr> {
+        // The peer the event is about, if it's about one
+        match self {
+            RouterEvent::PeerUp { peer, .. }
+            | RouterEvent::PeerDown { peer, .. }
+            | RouterEvent::PrefixLimitExceeded { peer, .. } => Some(*peer),
+            RouterEvent::BestPathChanged { .. } | RouterEvent::TableVersionBumped { .. } => None
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// The local BGP Identifier. It goes into every Open we send, it's the CLUSTER_ID of a route reflector
+// that isn't given one and it decides which connection survives a collision, so the speaker only takes one
+// that is valid: not zero and a unicast address. RFC 4271, Pg. 31 and RFC 6286, Pg. 2
+// Unless it's configured, the highest loopback address is picked, as most implementations do, since it
+// doesn't go away with an interface. Without one the highest other configured address is used. Loopback
+// addresses come from a hook (e.g. reading the host's interfaces), the crate doesn't look them up itself.
+
+use std::{fmt, net::Ipv4Addr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouterIdError {
+    // Zero, multicast or broadcast
+    Invalid(Ipv4Addr),
+    // Not configured and no address to pick one from
+    NoCandidates
+}
+
+impl fmt::Display for RouterIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouterIdError::Invalid(id) => write!(f, "{} is not a valid BGP Identifier", id),
+            RouterIdError::NoCandidates => write!(f, "no router ID configured and no IPv4 address to pick one from")
+        }
+    }
+}
+
+impl std::error::Error for RouterIdError {}
+
+// Whether the address can be a BGP Identifier, ours or a peer's
+pub(crate) fn is_valid_id(id: Ipv4Addr) -> bool {
+    !(id.is_unspecified() || id.is_multicast() || id.is_broadcast())
+}
+
+pub(crate) fn check_router_id(id: Ipv4Addr) -> Result<Ipv4Addr, RouterIdError> {
+    match is_valid_id(id) {
+        true => Ok(id),
+        false => Err(RouterIdError::Invalid(id))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RouterIdSelector {
+    configured: Option<Ipv4Addr>,
+    loopbacks: Vec<Ipv4Addr>,
+    // Any other addresses of ours, e.g. the peers' local addresses
+    addresses: Vec<Ipv4Addr>
+}
+
+impl RouterIdSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn configured(mut self, id: Option<Ipv4Addr>) -> Self {
+        self.configured = id;
+        self
+    }
+    pub fn loopbacks(mut self, loopbacks: Vec<Ipv4Addr>) -> Self {
+        self.loopbacks = loopbacks;
+        self
+    }
+    pub fn addresses(mut self, addresses: Vec<Ipv4Addr>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+    pub fn select(&self) -> Result<Ipv4Addr, RouterIdError> {
+        // A configured ID is taken as is, as long as it's valid. 127/8 is on every host, so it's never picked.
+        if let Some(id) = self.configured {
+            return check_router_id(id);
+        }
+        let highest = |addrs: &[Ipv4Addr]| addrs
+            .iter()
+            .copied()
+            .filter(|addr| is_valid_id(*addr) && !addr.is_loopback())
+            .max();
+        highest(&self.loopbacks)
+            .or_else(|| highest(&self.addresses))
+            .ok_or(RouterIdError::NoCandidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn router_id_selection() {
+        let (low, high) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let selector = RouterIdSelector::new().addresses(vec![Ipv4Addr::new(192, 0, 2, 254)]);
+        assert_eq!(selector.select(), Ok(Ipv4Addr::new(192, 0, 2, 254)));
+
+        // Loopbacks win over higher addresses, 127/8 and invalid ones aren't candidates
+        let selector = selector.loopbacks(vec![low, Ipv4Addr::LOCALHOST, high, Ipv4Addr::BROADCAST]);
+        assert_eq!(selector.select(), Ok(high));
+        assert_eq!(selector.clone().configured(Some(low)).select(), Ok(low));
+
+        // A configured ID has to be valid, there's no falling back
+        for id in [Ipv4Addr::UNSPECIFIED, Ipv4Addr::new(224, 0, 0, 5), Ipv4Addr::BROADCAST] {
+            assert_eq!(selector.clone().configured(Some(id)).select(), Err(RouterIdError::Invalid(id)));
+        }
+        assert_eq!(RouterIdSelector::new().loopbacks(vec![Ipv4Addr::LOCALHOST]).select(), Err(RouterIdError::NoCandidates));
+        assert_eq!(check_router_id(Ipv4Addr::new(127, 0, 0, 1)), Ok(Ipv4Addr::LOCALHOST));
+    }
+}
@@ -0,0 +1,248 @@
+// RPKI route origin validation (RFC 6811). Holds the set of Validated ROA Payloads (VRPs) and
+// decides whether a route's origin AS is authorized to announce it.
+
+use crate::{
+    message_types::Route,
+    path_attrs::{AsSegment, PathAttr},
+    trie::{ip_bits, PrefixTrie},
+};
+
+// Validation state of a path. RFC 6811, Pg. 5
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum RpkiState {
+    Valid,
+    // No ROA covers the route, also used when no ROAs are loaded
+    #[default]
+    NotFound,
+    Invalid
+}
+
+impl From<&RpkiState> for u8 {
+    fn from(value: &RpkiState) -> Self {
+        // Lower is preferred
+        match value {
+            RpkiState::Valid => 0,
+            RpkiState::NotFound => 1,
+            RpkiState::Invalid => 2
+        }
+    }
+}
+
+// How validation states affect the Decision Process
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RpkiPolicy {
+    // States are recorded but not used
+    #[default]
+    Ignore,
+    // Valid is preferred over NotFound, which is preferred over Invalid. Compared right after weight.
+    PreferValid,
+    // Same as PreferValid, but Invalid routes are never installed
+    DropInvalid
+}
+
+// Communities to tag exported paths with, based on their validation state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RpkiTags {
+    valid: u32,
+    not_found: u32,
+    invalid: u32
+}
+
+impl RpkiTags {
+    pub fn new(valid: u32, not_found: u32, invalid: u32) -> Self {
+        Self {
+            valid,
+            not_found,
+            invalid
+        }
+    }
+    pub fn community(&self, state: RpkiState) -> u32 {
+        match state {
+            RpkiState::Valid => self.valid,
+            RpkiState::NotFound => self.not_found,
+            RpkiState::Invalid => self.invalid
+        }
+    }
+}
+
+// A single VRP: the AS authorized to originate the prefix and anything more specific up to max_len.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Roa {
+    prefix: Route,
+    max_len: u8,
+    asn: u32
+}
+
+impl Roa {
+    pub fn new(prefix: Route, max_len: u8, asn: u32) -> Self {
+        Self {
+            prefix,
+            max_len,
+            asn
+        }
+    }
+    pub fn prefix(&self) -> &Route {
+        &self.prefix
+    }
+    pub fn max_len(&self) -> u8 {
+        self.max_len
+    }
+    pub fn asn(&self) -> u32 {
+        self.asn
+    }
+    fn matches(&self, route: &Route, origin: Option<u32>) -> bool {
+        // AS 0 ROAs never match anything. RFC 6483, Pg. 6
+        route.prefix_len() <= self.max_len && self.asn != 0 && origin == Some(self.asn)
+    }
+}
+
+// The set of loaded ROAs, indexed by prefix so only covering ROAs are looked at.
+#[derive(Clone, Debug)]
+pub struct RoaTable {
+    index: PrefixTrie<Vec<Roa>>,
+    len: usize
+}
+
+impl RoaTable {
+    pub fn new() -> Self {
+        Self {
+            index: PrefixTrie::new(),
+            len: 0
+        }
+    }
+    pub fn load(roas: Vec<Roa>) -> Self {
+        // Builds a table from a full set of ROAs, e.g. from a validator export
+        let mut table = Self::new();
+        roas.into_iter().for_each(|roa| table.insert(roa));
+        table
+    }
+    pub fn insert(&mut self, roa: Roa) {
+        let bits = ip_bits(roa.prefix.prefix());
+        let len = roa.prefix.prefix_len();
+        match self.index.get_mut(bits, len) {
+            Some(roas) if roas.contains(&roa) => return,
+            Some(roas) => roas.push(roa),
+            None => _ = self.index.insert(bits, len, vec![roa])
+        }
+        self.len += 1;
+    }
+    pub fn remove(&mut self, roa: &Roa) -> bool {
+        let bits = ip_bits(roa.prefix.prefix());
+        let len = roa.prefix.prefix_len();
+        let roas = match self.index.get_mut(bits, len) {
+            Some(roas) => roas,
+            None => return false
+        };
+        let before = roas.len();
+        roas.retain(|existing| existing != roa);
+        let removed = roas.len() != before;
+        if roas.is_empty() {
+            _ = self.index.remove(bits, len);
+        }
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn validate(&self, route: &Route, origin: Option<u32>) -> RpkiState {
+        // Valid if any covering ROA matches, Invalid if there are covering ROAs but none match,
+        // NotFound if nothing covers the route. RFC 6811, Pg. 6
+        let covering: Vec<&Roa> = self.index
+            .covering(ip_bits(route.prefix()), route.prefix_len())
+            .into_iter()
+            .flat_map(|(_, roas)| roas.iter())
+            .filter(|roa| roa.prefix.afi() == route.afi())
+            .collect();
+        match covering.is_empty() {
+            true => RpkiState::NotFound,
+            false if covering.iter().any(|roa| roa.matches(route, origin)) => RpkiState::Valid,
+            false => RpkiState::Invalid
+        }
+    }
+}
+
+impl Default for RoaTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Origin AS of a path: the last AS of the AS_PATH if it ends in an AS_SEQUENCE. Paths ending in
+// an AS_SET have no origin, and locally originated paths (empty AS_PATH) use our own AS.
+pub(crate) fn origin_as(pas: &[PathAttr], local_as: u16) -> Option<u32> {
+    let segments = pas.iter().find_map(|pa| pa.as_path()).unwrap_or_default();
+    match segments.last() {
+        Some(AsSegment::AsSequence(ases)) => ases.last().map(|asn| *asn as u32),
+        Some(AsSegment::AsSet(_)) => None,
+        None => Some(local_as as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use crate::path_attrs::{AsPath, PaBuilder, PathAttrBuilder};
+
+    fn v4(a: u8, b: u8, len: u8) -> Route {
+        Route::new(len, IpAddr::V4(Ipv4Addr::new(10, a, b, 0)))
+    }
+
+    #[test]
+    fn roa_validate() {
+        let roas = RoaTable::load(vec![
+            Roa::new(v4(0, 0, 8), 16, 65001),
+            Roa::new(v4(1, 0, 16), 24, 65002),
+            Roa::new(v4(9, 0, 16), 24, 0),
+        ]);
+        assert_eq!(roas.len(), 3);
+
+        assert_eq!(roas.validate(&v4(2, 0, 16), Some(65001)), RpkiState::Valid);
+        // Too specific
+        assert_eq!(roas.validate(&v4(2, 0, 24), Some(65001)), RpkiState::Invalid);
+        // Wrong origin
+        assert_eq!(roas.validate(&v4(2, 0, 16), Some(65003)), RpkiState::Invalid);
+        // Either covering ROA can make it valid
+        assert_eq!(roas.validate(&v4(1, 1, 24), Some(65002)), RpkiState::Valid);
+        assert_eq!(roas.validate(&v4(1, 1, 16), Some(65001)), RpkiState::Valid);
+        // AS 0 never matches
+        assert_eq!(roas.validate(&v4(9, 1, 24), Some(0)), RpkiState::Invalid);
+        assert_eq!(roas.validate(&v4(2, 0, 16), None), RpkiState::Invalid);
+        let other = Route::new(16, IpAddr::V4(Ipv4Addr::new(11, 0, 0, 0)));
+        assert_eq!(roas.validate(&other, Some(65001)), RpkiState::NotFound);
+    }
+
+    #[test]
+    fn roa_insert_remove() {
+        let roa = Roa::new(v4(0, 0, 8), 16, 65001);
+        let mut roas = RoaTable::new();
+        roas.insert(roa.clone());
+        roas.insert(roa.clone());
+        assert_eq!(roas.len(), 1);
+        assert!(roas.remove(&roa));
+        assert!(!roas.remove(&roa));
+        assert!(roas.is_empty());
+        assert_eq!(roas.validate(&v4(2, 0, 16), Some(65001)), RpkiState::NotFound);
+    }
+
+    #[test]
+    fn path_origin_as() {
+        let seq = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001, 65002])])
+            .build()
+            .unwrap();
+        assert_eq!(origin_as(&[seq], 65000), Some(65002));
+        let set = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001]), AsSegment::AsSet(vec![1, 2])])
+            .build()
+            .unwrap();
+        assert_eq!(origin_as(&[set], 65000), None);
+        assert_eq!(origin_as(&[], 65000), Some(65000));
+    }
+}
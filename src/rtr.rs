@@ -0,0 +1,638 @@
+// RPKI to Router protocol client (RFC 8210). The client is sans-IO: PDUs are read and written by the
+// caller (read_pdu() works over any blocking reader) and handed to the client, which tracks the
+// session and serial with the cache and accumulates VRP changes. Once the cache signals End of Data the
+// changes are returned as a VrpUpdate, which can be applied to a BgpTable to re-validate affected paths.
+// sync() drives a client over TCP for a Speaker, see Speaker::connect_rtr.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::warn;
+
+use crate::{
+    message_types::Route,
+    rpki::{Roa, RoaTable},
+};
+
+// ** PROTOCOL CONSTANTS **
+const RTR_VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+// Anything larger is almost certainly a framing problem
+const MAX_PDU_LEN: usize = 65535;
+
+// ** PDU TYPES **
+// RFC 8210, Pg. 7
+const SERIAL_NOTIFY: u8 = 0;
+const SERIAL_QUERY: u8 = 1;
+const RESET_QUERY: u8 = 2;
+const CACHE_RESPONSE: u8 = 3;
+const IPV4_PREFIX: u8 = 4;
+const IPV6_PREFIX: u8 = 6;
+const END_OF_DATA: u8 = 7;
+const CACHE_RESET: u8 = 8;
+const ROUTER_KEY: u8 = 9;
+const ERROR_REPORT: u8 = 10;
+
+// Announce flag on prefix PDUs, withdraw otherwise
+const FLAG_ANNOUNCE: u8 = 1;
+
+// ** ERROR CODES **
+// RFC 8210, Pg. 30
+const ERR_CORRUPT_DATA: u16 = 0;
+const ERR_UNSUPPORTED_VERSION: u16 = 4;
+const ERR_UNSUPPORTED_PDU: u16 = 5;
+
+// ** DEFAULT TIMERS **
+// RFC 8210, Pg. 17
+const DEFAULT_REFRESH_SECS: u64 = 3600;
+const DEFAULT_RETRY_SECS: u64 = 600;
+const DEFAULT_EXPIRE_SECS: u64 = 7200;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RtrError {
+    // Not enough bytes for the PDU described by the header
+    Truncated,
+    UnsupportedVersion(u8),
+    UnsupportedPdu(u8),
+    BadLength(u8, usize),
+    // The PDU is valid but not expected in the current state
+    Unexpected(u8),
+    // The cache sent an Error Report
+    Report(u16, String),
+    Io(String)
+}
+
+impl RtrError {
+    pub fn error_code(&self) -> u16 {
+        // Error code to send back to the cache in an Error Report
+        match self {
+            RtrError::UnsupportedVersion(_) => ERR_UNSUPPORTED_VERSION,
+            RtrError::UnsupportedPdu(_) => ERR_UNSUPPORTED_PDU,
+            _ => ERR_CORRUPT_DATA
+        }
+    }
+}
+
+impl fmt::Display for RtrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtrError::Truncated => write!(f, "truncated RTR PDU"),
+            RtrError::UnsupportedVersion(version) => write!(f, "unsupported RTR version {}", version),
+            RtrError::UnsupportedPdu(pdu_type) => write!(f, "unsupported RTR PDU type {}", pdu_type),
+            RtrError::BadLength(pdu_type, len) => write!(f, "bad length {} for RTR PDU type {}", len, pdu_type),
+            RtrError::Unexpected(pdu_type) => write!(f, "unexpected RTR PDU type {}", pdu_type),
+            RtrError::Report(code, text) => write!(f, "cache reported error {}: {}", code, text),
+            RtrError::Io(err) => write!(f, "RTR transport error: {}", err)
+        }
+    }
+}
+
+impl std::error::Error for RtrError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RtrPdu {
+    SerialNotify { session_id: u16, serial: u32 },
+    SerialQuery { session_id: u16, serial: u32 },
+    ResetQuery,
+    CacheResponse { session_id: u16 },
+    Prefix { announce: bool, roa: Roa },
+    EndOfData { session_id: u16, serial: u32, refresh: u32, retry: u32, expire: u32 },
+    CacheReset,
+    // Only used for BGPsec, which isn't supported. Kept so it can be skipped.
+    RouterKey,
+    ErrorReport { code: u16, text: String }
+}
+
+impl RtrPdu {
+    pub fn pdu_type(&self) -> u8 {
+        match self {
+            RtrPdu::SerialNotify { .. } => SERIAL_NOTIFY,
+            RtrPdu::SerialQuery { .. } => SERIAL_QUERY,
+            RtrPdu::ResetQuery => RESET_QUERY,
+            RtrPdu::CacheResponse { .. } => CACHE_RESPONSE,
+            RtrPdu::Prefix { roa, .. } => match roa.prefix().prefix() {
+                IpAddr::V4(_) => IPV4_PREFIX,
+                IpAddr::V6(_) => IPV6_PREFIX
+            },
+            RtrPdu::EndOfData { .. } => END_OF_DATA,
+            RtrPdu::CacheReset => CACHE_RESET,
+            RtrPdu::RouterKey => ROUTER_KEY,
+            RtrPdu::ErrorReport { .. } => ERROR_REPORT
+        }
+    }
+    pub fn encode(&self) -> BytesMut {
+        // Serializes the PDU, header included. All fields are Big Endian.
+        let mut body = BytesMut::new();
+        let session = match self {
+            RtrPdu::SerialNotify { session_id, serial } | RtrPdu::SerialQuery { session_id, serial } => {
+                body.put_u32(*serial);
+                *session_id
+            },
+            RtrPdu::CacheResponse { session_id } => *session_id,
+            RtrPdu::Prefix { announce, roa } => {
+                body.put_u8(match announce { true => FLAG_ANNOUNCE, false => 0 });
+                body.put_u8(roa.prefix().prefix_len());
+                body.put_u8(roa.max_len());
+                body.put_u8(0);
+                match roa.prefix().prefix() {
+                    IpAddr::V4(addr) => body.put_slice(&addr.octets()),
+                    IpAddr::V6(addr) => body.put_slice(&addr.octets())
+                }
+                body.put_u32(roa.asn());
+                0
+            },
+            RtrPdu::EndOfData { session_id, serial, refresh, retry, expire } => {
+                body.put_u32(*serial);
+                body.put_u32(*refresh);
+                body.put_u32(*retry);
+                body.put_u32(*expire);
+                *session_id
+            },
+            RtrPdu::ErrorReport { code, text } => {
+                // No encapsulated PDU
+                body.put_u32(0);
+                body.put_u32(text.len() as u32);
+                body.put_slice(text.as_bytes());
+                *code
+            },
+            RtrPdu::ResetQuery | RtrPdu::CacheReset | RtrPdu::RouterKey => 0
+        };
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + body.len());
+        buf.put_u8(RTR_VERSION);
+        buf.put_u8(self.pdu_type());
+        buf.put_u16(session);
+        buf.put_u32((HEADER_LEN + body.len()) as u32);
+        buf.put_slice(&body);
+        buf
+    }
+    pub fn decode(mut buf: &[u8]) -> Result<(Self, usize), RtrError> {
+        // Parses a single PDU from the front of the buffer, returning it along with the
+        // number of bytes consumed.
+        if buf.len() < HEADER_LEN {
+            return Err(RtrError::Truncated);
+        }
+        let version = buf.get_u8();
+        let pdu_type = buf.get_u8();
+        let session = buf.get_u16();
+        let len = buf.get_u32() as usize;
+        // Version 0 caches are understood, they only differ in the End of Data PDU
+        if version > RTR_VERSION {
+            return Err(RtrError::UnsupportedVersion(version));
+        }
+        if !(HEADER_LEN..=MAX_PDU_LEN).contains(&len) {
+            return Err(RtrError::BadLength(pdu_type, len));
+        }
+        if buf.len() < len - HEADER_LEN {
+            return Err(RtrError::Truncated);
+        }
+        let mut body = &buf[..len - HEADER_LEN];
+        let expect_len = |expected: usize| match len == expected {
+            true => Ok(()),
+            false => Err(RtrError::BadLength(pdu_type, len))
+        };
+        let pdu = match pdu_type {
+            SERIAL_NOTIFY | SERIAL_QUERY => {
+                expect_len(12)?;
+                let serial = body.get_u32();
+                match pdu_type {
+                    SERIAL_NOTIFY => RtrPdu::SerialNotify { session_id: session, serial },
+                    _ => RtrPdu::SerialQuery { session_id: session, serial }
+                }
+            },
+            RESET_QUERY => {
+                expect_len(8)?;
+                RtrPdu::ResetQuery
+            },
+            CACHE_RESPONSE => {
+                expect_len(8)?;
+                RtrPdu::CacheResponse { session_id: session }
+            },
+            IPV4_PREFIX | IPV6_PREFIX => {
+                let addr_len = match pdu_type {
+                    IPV4_PREFIX => 4,
+                    _ => 16
+                };
+                expect_len(HEADER_LEN + 8 + addr_len)?;
+                let flags = body.get_u8();
+                let prefix_len = body.get_u8();
+                let max_len = body.get_u8();
+                body.advance(1);
+                let prefix = match pdu_type {
+                    IPV4_PREFIX => IpAddr::V4(Ipv4Addr::from(body.get_u32())),
+                    _ => IpAddr::V6(Ipv6Addr::from(body.get_u128()))
+                };
+                let asn = body.get_u32();
+                let max_bits = (addr_len * 8) as u8;
+                if prefix_len > max_len || max_len > max_bits {
+                    return Err(RtrError::BadLength(pdu_type, len));
+                }
+                RtrPdu::Prefix {
+                    announce: flags & FLAG_ANNOUNCE == FLAG_ANNOUNCE,
+                    roa: Roa::new(Route::new(prefix_len, prefix), max_len, asn)
+                }
+            },
+            END_OF_DATA => {
+                // Version 0 has no timers. RFC 6810, Pg. 11
+                let serial = match len {
+                    12 | 24 => body.get_u32(),
+                    _ => return Err(RtrError::BadLength(pdu_type, len))
+                };
+                let (refresh, retry, expire) = match len {
+                    24 => (body.get_u32(), body.get_u32(), body.get_u32()),
+                    _ => (DEFAULT_REFRESH_SECS as u32, DEFAULT_RETRY_SECS as u32, DEFAULT_EXPIRE_SECS as u32)
+                };
+                RtrPdu::EndOfData { session_id: session, serial, refresh, retry, expire }
+            },
+            CACHE_RESET => {
+                expect_len(8)?;
+                RtrPdu::CacheReset
+            },
+            ROUTER_KEY => RtrPdu::RouterKey,
+            ERROR_REPORT => {
+                // Skips the encapsulated PDU, only the text is kept
+                if body.remaining() < 4 {
+                    return Err(RtrError::Truncated);
+                }
+                let pdu_len = body.get_u32() as usize;
+                if body.remaining() < pdu_len + 4 {
+                    return Err(RtrError::Truncated);
+                }
+                body.advance(pdu_len);
+                let text_len = body.get_u32() as usize;
+                if body.remaining() < text_len {
+                    return Err(RtrError::Truncated);
+                }
+                let text = String::from_utf8_lossy(&body[..text_len]).into_owned();
+                RtrPdu::ErrorReport { code: session, text }
+            },
+            _ => return Err(RtrError::UnsupportedPdu(pdu_type))
+        };
+        Ok((pdu, len))
+    }
+}
+
+pub(crate) fn read_pdu<R: Read>(reader: &mut R) -> Result<RtrPdu, RtrError> {
+    // Reads exactly one PDU from a blocking transport (e.g. a TcpStream to the cache).
+    let mut buf = vec![0u8; HEADER_LEN];
+    reader.read_exact(&mut buf).map_err(|err| RtrError::Io(err.to_string()))?;
+    let len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+    if !(HEADER_LEN..=MAX_PDU_LEN).contains(&len) {
+        return Err(RtrError::BadLength(buf[1], len));
+    }
+    buf.resize(len, 0);
+    reader.read_exact(&mut buf[HEADER_LEN..]).map_err(|err| RtrError::Io(err.to_string()))?;
+    RtrPdu::decode(&buf).map(|(pdu, _)| pdu)
+}
+
+pub(crate) async fn recv_pdu<R: AsyncRead + Unpin>(reader: &mut R) -> Result<RtrPdu, RtrError> {
+    // Same as read_pdu, for an async transport
+    let mut buf = vec![0u8; HEADER_LEN];
+    reader.read_exact(&mut buf).await.map_err(|err| RtrError::Io(err.to_string()))?;
+    let len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+    if !(HEADER_LEN..=MAX_PDU_LEN).contains(&len) {
+        return Err(RtrError::BadLength(buf[1], len));
+    }
+    buf.resize(len, 0);
+    reader.read_exact(&mut buf[HEADER_LEN..]).await.map_err(|err| RtrError::Io(err.to_string()))?;
+    RtrPdu::decode(&buf).map(|(pdu, _)| pdu)
+}
+
+pub(crate) async fn sync<F: FnMut(VrpUpdate) -> bool>(cache: SocketAddr, mut apply: F) {
+    // Hands the VRP changes from the cache to apply until it returns false (nobody takes them anymore). The
+    // connection is retried after the cache's retry interval, and the VRPs are dropped once they have expired
+    // without the cache coming back. RFC 8210, Pg. 17
+    let mut client = RtrClient::new();
+    loop {
+        match session(cache, &mut client, &mut apply).await {
+            Ok(()) => return,
+            Err(err) => warn!(%cache, %err, "RTR session down")
+        }
+        if client.expired(Instant::now()) && !apply(client.expire_data()) {
+            return;
+        }
+        tokio::time::sleep(client.retry()).await;
+    }
+}
+
+async fn session<F>(cache: SocketAddr, client: &mut RtrClient, apply: &mut F) -> Result<(), RtrError>
+where
+    F: FnMut(VrpUpdate) -> bool
+{
+    // One connection to the cache, queried again whenever it has been quiet for the refresh interval. Only
+    // returns Ok once apply turned an update down.
+    let io_err = |err: io::Error| RtrError::Io(err.to_string());
+    let mut stream = TcpStream::connect(cache).await.map_err(io_err)?;
+    let mut send = Some(client.query());
+    loop {
+        if let Some(pdu) = send.take() {
+            stream.write_all(&pdu.encode()).await.map_err(io_err)?;
+        }
+        let pdu = tokio::select! {
+            pdu = recv_pdu(&mut stream) => pdu,
+            _ = tokio::time::sleep(client.refresh()) => {
+                send = Some(client.query());
+                continue;
+            }
+        };
+        match pdu.and_then(|pdu| client.handle(pdu, Instant::now())) {
+            Ok(RtrAction::Send(pdu)) => send = Some(pdu),
+            Ok(RtrAction::Update(update)) if !update.is_empty() => {
+                if !apply(update) {
+                    return Ok(());
+                }
+            },
+            Ok(_) => (),
+            // The cache is told what was wrong with what it sent, unless it was the one reporting an error
+            Err(err @ (RtrError::Io(_) | RtrError::Report(..))) => return Err(err),
+            Err(err) => {
+                let report = RtrPdu::ErrorReport { code: err.error_code(), text: err.to_string() };
+                _ = stream.write_all(&report.encode()).await;
+                return Err(err);
+            }
+        }
+    }
+}
+
+// Changes to the VRP set since the last update. If reset is set, the receiver should drop
+// every VRP it has before applying the announcements.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct VrpUpdate {
+    reset: bool,
+    announced: Vec<Roa>,
+    withdrawn: Vec<Roa>
+}
+
+impl VrpUpdate {
+    pub fn is_reset(&self) -> bool {
+        self.reset
+    }
+    pub fn announced(&self) -> &[Roa] {
+        self.announced.as_slice()
+    }
+    pub fn withdrawn(&self) -> &[Roa] {
+        self.withdrawn.as_slice()
+    }
+    pub fn is_empty(&self) -> bool {
+        !self.reset && self.announced.is_empty() && self.withdrawn.is_empty()
+    }
+    pub fn apply(&self, roas: &mut RoaTable) {
+        if self.reset {
+            *roas = RoaTable::new();
+        }
+        self.withdrawn.iter().for_each(|roa| _ = roas.remove(roa));
+        self.announced.iter().for_each(|roa| roas.insert(roa.clone()));
+    }
+}
+
+// What the caller should do after handing a PDU to the client
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RtrAction {
+    None,
+    Send(RtrPdu),
+    Update(VrpUpdate)
+}
+
+pub(crate) struct RtrClient {
+    session_id: Option<u16>,
+    serial: Option<u32>,
+    vrps: HashSet<Roa>,
+    // Changes received since the last Cache Response, committed on End of Data
+    pending: Option<VrpUpdate>,
+    // Whether the outstanding query was a Reset Query
+    resetting: bool,
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+    last_update: Option<Instant>
+}
+
+impl RtrClient {
+    pub fn new() -> Self {
+        Self {
+            session_id: None,
+            serial: None,
+            vrps: HashSet::new(),
+            pending: None,
+            resetting: false,
+            refresh: Duration::from_secs(DEFAULT_REFRESH_SECS),
+            retry: Duration::from_secs(DEFAULT_RETRY_SECS),
+            expire: Duration::from_secs(DEFAULT_EXPIRE_SECS),
+            last_update: None
+        }
+    }
+    pub fn session_id(&self) -> Option<u16> {
+        self.session_id
+    }
+    pub fn serial(&self) -> Option<u32> {
+        self.serial
+    }
+    pub fn num_vrps(&self) -> usize {
+        self.vrps.len()
+    }
+    pub fn roa_table(&self) -> RoaTable {
+        // Snapshot of the current VRP set
+        RoaTable::load(self.vrps.iter().cloned().collect())
+    }
+    pub fn refresh(&self) -> Duration {
+        self.refresh
+    }
+    pub fn retry(&self) -> Duration {
+        self.retry
+    }
+    pub fn query(&mut self) -> RtrPdu {
+        // Query to send when (re)connecting or when the refresh timer fires. Only
+        // incremental changes are asked for if we already have data from this cache.
+        match (self.session_id, self.serial) {
+            (Some(session_id), Some(serial)) => {
+                self.resetting = false;
+                RtrPdu::SerialQuery { session_id, serial }
+            },
+            _ => {
+                self.resetting = true;
+                RtrPdu::ResetQuery
+            }
+        }
+    }
+    pub fn refresh_due(&self, now: Instant) -> bool {
+        self.last_update.map_or(true, |last| now.duration_since(last) >= self.refresh)
+    }
+    pub fn expired(&self, now: Instant) -> bool {
+        // Once expired, the VRPs must not be used any more. RFC 8210, Pg. 17
+        self.last_update.is_some_and(|last| now.duration_since(last) >= self.expire)
+    }
+    pub fn expire_data(&mut self) -> VrpUpdate {
+        // Drops everything learned from the cache, returning the update that removes it.
+        self.session_id = None;
+        self.serial = None;
+        self.pending = None;
+        self.last_update = None;
+        self.vrps.clear();
+        VrpUpdate { reset: true, ..Default::default() }
+    }
+    pub fn handle(&mut self, pdu: RtrPdu, now: Instant) -> Result<RtrAction, RtrError> {
+        // Advances the client with a PDU received from the cache.
+        let pdu_type = pdu.pdu_type();
+        match pdu {
+            RtrPdu::SerialNotify { session_id, serial } => {
+                // Only worth querying if we're idle and behind
+                let behind = self.session_id == Some(session_id) && self.serial != Some(serial);
+                match behind && self.pending.is_none() {
+                    true => Ok(RtrAction::Send(self.query())),
+                    false => Ok(RtrAction::None)
+                }
+            },
+            RtrPdu::CacheResponse { session_id } => {
+                // A new session ID on an incremental response means our data is stale. RFC 8210, Pg. 9
+                if !self.resetting && self.session_id != Some(session_id) {
+                    self.session_id = None;
+                    self.serial = None;
+                    return Ok(RtrAction::Send(self.query()));
+                }
+                self.session_id = Some(session_id);
+                self.pending = Some(VrpUpdate { reset: self.resetting, ..Default::default() });
+                Ok(RtrAction::None)
+            },
+            RtrPdu::Prefix { announce, roa } => {
+                let pending = self.pending.as_mut().ok_or(RtrError::Unexpected(pdu_type))?;
+                match announce {
+                    true => pending.announced.push(roa),
+                    false => pending.withdrawn.push(roa)
+                }
+                Ok(RtrAction::None)
+            },
+            RtrPdu::EndOfData { session_id, serial, refresh, retry, expire } => {
+                let update = self.pending.take().ok_or(RtrError::Unexpected(pdu_type))?;
+                if self.session_id != Some(session_id) {
+                    return Err(RtrError::Unexpected(pdu_type));
+                }
+                if update.reset {
+                    self.vrps.clear();
+                }
+                update.withdrawn.iter().for_each(|roa| _ = self.vrps.remove(roa));
+                update.announced.iter().for_each(|roa| _ = self.vrps.insert(roa.clone()));
+                self.serial = Some(serial);
+                self.refresh = Duration::from_secs(refresh as u64);
+                self.retry = Duration::from_secs(retry as u64);
+                self.expire = Duration::from_secs(expire as u64);
+                self.last_update = Some(now);
+                self.resetting = false;
+                Ok(RtrAction::Update(update))
+            },
+            RtrPdu::CacheReset => {
+                // The cache can't give us incremental changes, start over
+                self.serial = None;
+                self.pending = None;
+                Ok(RtrAction::Send(self.query()))
+            },
+            RtrPdu::RouterKey => Ok(RtrAction::None),
+            RtrPdu::ErrorReport { code, text } => {
+                self.pending = None;
+                Err(RtrError::Report(code, text))
+            },
+            // Queries are only ever sent by the router
+            RtrPdu::SerialQuery { .. } | RtrPdu::ResetQuery => Err(RtrError::Unexpected(pdu_type))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roa(b: u8, asn: u32) -> Roa {
+        Roa::new(Route::new(16, IpAddr::V4(Ipv4Addr::new(10, b, 0, 0))), 24, asn)
+    }
+
+    #[test]
+    fn rtr_pdu_round_trip() {
+        let pdus = vec![
+            RtrPdu::SerialNotify { session_id: 7, serial: 10 },
+            RtrPdu::SerialQuery { session_id: 7, serial: 10 },
+            RtrPdu::ResetQuery,
+            RtrPdu::CacheResponse { session_id: 7 },
+            RtrPdu::Prefix { announce: true, roa: roa(1, 65001) },
+            RtrPdu::Prefix {
+                announce: false,
+                roa: Roa::new(Route::new(32, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0))), 48, 65002)
+            },
+            RtrPdu::EndOfData { session_id: 7, serial: 11, refresh: 1, retry: 2, expire: 3 },
+            RtrPdu::CacheReset,
+            RtrPdu::ErrorReport { code: 2, text: String::from("No Data Available") },
+        ];
+        for pdu in pdus {
+            let buf = pdu.encode();
+            assert_eq!(RtrPdu::decode(&buf), Ok((pdu.clone(), buf.len())));
+            assert_eq!(read_pdu(&mut &buf[..]), Ok(pdu));
+        }
+        let v4 = RtrPdu::Prefix { announce: true, roa: roa(1, 65001) }.encode();
+        assert_eq!(v4.len(), 20);
+        assert_eq!(RtrPdu::decode(&v4[..12]), Err(RtrError::Truncated));
+        let mut bad = v4.clone();
+        bad[1] = 42;
+        assert_eq!(RtrPdu::decode(&bad), Err(RtrError::UnsupportedPdu(42)));
+    }
+
+    #[test]
+    fn rtr_client_sync() {
+        let now = Instant::now();
+        let mut client = RtrClient::new();
+        assert_eq!(client.query(), RtrPdu::ResetQuery);
+        assert_eq!(client.handle(RtrPdu::CacheResponse { session_id: 7 }, now), Ok(RtrAction::None));
+        _ = client.handle(RtrPdu::Prefix { announce: true, roa: roa(1, 65001) }, now);
+        _ = client.handle(RtrPdu::Prefix { announce: true, roa: roa(2, 65002) }, now);
+        let eod = RtrPdu::EndOfData { session_id: 7, serial: 1, refresh: 60, retry: 10, expire: 600 };
+        match client.handle(eod, now).unwrap() {
+            RtrAction::Update(update) => {
+                assert!(update.is_reset());
+                assert_eq!(update.announced().len(), 2);
+            },
+            other => panic!("expected an update, got {:?}", other)
+        }
+        assert_eq!(client.num_vrps(), 2);
+        assert!(!client.refresh_due(now));
+
+        // The cache has new data, only the changes are asked for
+        let action = client.handle(RtrPdu::SerialNotify { session_id: 7, serial: 2 }, now).unwrap();
+        assert_eq!(action, RtrAction::Send(RtrPdu::SerialQuery { session_id: 7, serial: 1 }));
+        _ = client.handle(RtrPdu::CacheResponse { session_id: 7 }, now);
+        _ = client.handle(RtrPdu::Prefix { announce: false, roa: roa(2, 65002) }, now);
+        let eod = RtrPdu::EndOfData { session_id: 7, serial: 2, refresh: 60, retry: 10, expire: 600 };
+        match client.handle(eod, now).unwrap() {
+            RtrAction::Update(update) => {
+                assert!(!update.is_reset());
+                assert_eq!(update.withdrawn(), &[roa(2, 65002)]);
+            },
+            other => panic!("expected an update, got {:?}", other)
+        }
+        assert_eq!(client.num_vrps(), 1);
+        assert_eq!(client.serial(), Some(2));
+
+        // Cache can't serve increments any more
+        assert_eq!(client.handle(RtrPdu::CacheReset, now), Ok(RtrAction::Send(RtrPdu::ResetQuery)));
+        assert!(client.expired(now + Duration::from_secs(600)));
+        assert!(client.expire_data().is_reset());
+        assert_eq!(client.num_vrps(), 0);
+    }
+
+    #[test]
+    fn rtr_client_unexpected() {
+        let mut client = RtrClient::new();
+        let now = Instant::now();
+        assert_eq!(
+            client.handle(RtrPdu::Prefix { announce: true, roa: roa(1, 65001) }, now),
+            Err(RtrError::Unexpected(IPV4_PREFIX))
+        );
+        let report = RtrPdu::ErrorReport { code: 2, text: String::from("No Data Available") };
+        assert_eq!(client.handle(report, now), Err(RtrError::Report(2, String::from("No Data Available"))));
+    }
+}
@@ -0,0 +1,70 @@
+// Structured events about a peer's session, for applications embedding the crate to drive logging,
+// dashboards or BMP Peer Up/Down messages from. Observers are called synchronously by the FSM as the
+// events happen, so they should be cheap. A channel sender is an observer too, for consumers that
+// want to handle the events in their own task.
+
+use std::{
+    net::IpAddr,
+    time::SystemTime,
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::fsm_ds::State;
+
+// A NOTIFICATION's Error Code and Subcode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionError {
+    pub code: u8,
+    pub subcode: u8,
+    // Shutdown Communication of an Administrative Shutdown or Reset (RFC 9003)
+    pub communication: Option<String>,
+    // Whether we sent the NOTIFICATION or the peer did
+    pub sent: bool,
+    pub at: SystemTime
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SessionEvent {
+    StateChange {
+        peer: IpAddr,
+        from: State,
+        to: State,
+        at: SystemTime
+    },
+    Notification {
+        peer: IpAddr,
+        error: SessionError
+    }
+}
+
+impl SessionEvent {
+    pub fn peer(&self) -> IpAddr {
+        match self {
+            SessionEvent::StateChange { peer, .. } | SessionEvent::Notification { peer, .. } => *peer
+        }
+    }
+    pub fn is_peer_up(&self) -> bool {
+        matches!(self, SessionEvent::StateChange { to: State::Established, .. })
+    }
+    pub fn is_peer_down(&self) -> bool {
+        matches!(self, SessionEvent::StateChange { from: State::Established, .. })
+    }
+}
+
+pub(crate) trait SessionObserver: Send {
+    fn on_event(&self, event: &SessionEvent);
+}
+
+impl<F: Fn(&SessionEvent) + Send> SessionObserver for F {
+    fn on_event(&self, event: &SessionEvent) {
+        self(event)
+    }
+}
+
+impl SessionObserver for UnboundedSender<SessionEvent> {
+    fn on_event(&self, event: &SessionEvent) {
+        // Nobody listening anymore isn't the session's problem
+        _ = self.send(event.clone());
+    }
+}
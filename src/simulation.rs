@@ -0,0 +1,266 @@
+// Simulated networks of Speakers in one process, for building topologies (eBGP chains, iBGP meshes,
+// route reflectors and their clients) and checking how routes propagate without sockets. Sessions run
+// over channels: what one side sends is handed to the other as if it had been decoded off the wire, so
+// the FSMs, the RIB tasks and the export rules all run as they would with real peers, only the encoding
+// is skipped.
+// Each speaker peers from one address, its router ID, the way iBGP peers use loopbacks. The speaker with
+// the lower router ID of a pair opens the connection, the other one only accepts it.
+// Run simulations under a paused Tokio clock (`start_paused`) and the timers fire as soon as everything
+// else is idle, so a simulation takes as long as its message processing, however long its hold times.
+
+use std::{
+    collections::HashMap,
+    future::{self, Future},
+    io,
+    net::{IpAddr, Ipv4Addr},
+    pin::Pin,
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::{sleep, Instant},
+};
+
+use crate::{
+    comms::ReceivedRoutes,
+    fsm_ds::{BgpPeer, Event, PeerSessionBuilder, SessionParams},
+    message_types::{Afi, Route, Safi, Update},
+    peer::{ConnectFuture, Connection, Connector, Inbound, Outbound, PeerHandle},
+    router_id::RouterIdError,
+    speaker::{Speaker, SpeakerError},
+    table::RouteView,
+};
+
+// How often wait_for() looks at the tables
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// One end of a simulated connection
+pub(crate) struct SimConnection {
+    tx: UnboundedSender<Outbound>,
+    rx: UnboundedReceiver<Outbound>,
+    // The other end's address and AS, and once the session is up its BGP Identifier and AS
+    peer_addr: IpAddr,
+    local_as: u16,
+    remote: Option<SessionParams>
+}
+
+impl SimConnection {
+    pub fn pair(a: (IpAddr, u16), b: (IpAddr, u16)) -> (SimConnection, SimConnection) {
+        // Both ends of a connection between a and b, given as their address and AS. The first end is a's.
+        let (a_tx, b_rx) = mpsc::unbounded_channel();
+        let (b_tx, a_rx) = mpsc::unbounded_channel();
+        let end = |tx, rx, peer_addr, local_as| SimConnection { tx, rx, peer_addr, local_as, remote: None };
+        (end(a_tx, a_rx, b.0, a.1), end(b_tx, b_rx, a.0, b.1))
+    }
+    fn inbound(&self, msg: Outbound) -> Inbound {
+        // What the other end sent, as our decoder would have handed it over
+        match msg {
+            Outbound::Open(open) => Inbound::Event(Event::BGPOpen(open)),
+            Outbound::Keepalive => Inbound::Event(Event::KeepAliveMsg),
+            Outbound::Notification(notification) => Inbound::Event(Event::NotifMsg(notification)),
+            Outbound::Update(update) => self.update(update),
+            Outbound::RouteRefresh(afi, safi) => Inbound::Event(Event::RouteRefreshMsg(afi, safi))
+        }
+    }
+    fn update(&self, update: Update) -> Inbound {
+        // An empty Update is the IPv4 unicast End-of-RIB marker. RFC 4724, Pg. 2
+        if update.nlri().is_none() && update.withdrawn_routes().is_none() && update.path_attrs().is_none() {
+            return Inbound::Event(Event::EndOfRib(Afi::Ipv4, Safi::Unicast));
+        }
+        // Updates only go out once the session is up, so this side has negotiated by now
+        match &self.remote {
+            Some(params) => {
+                let payload = ReceivedRoutes::from_update(&update, self.peer_addr, self.local_as, params);
+                Inbound::Update(payload.split_by_afi())
+            },
+            None => Inbound::Update(Vec::new())
+        }
+    }
+}
+
+impl Connection for SimConnection {
+    fn send(&mut self, msg: Outbound) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        let sent = self.tx.send(msg).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+        Box::pin(future::ready(sent))
+    }
+    fn recv(&mut self) -> Pin<Box<dyn Future<Output = Option<Inbound>> + Send + '_>> {
+        Box::pin(async move {
+            let msg = self.rx.recv().await?;
+            Some(self.inbound(msg))
+        })
+    }
+    fn negotiated(&mut self, params: &SessionParams) {
+        self.remote = Some(params.clone());
+    }
+}
+
+// Opens simulated connections, handing the other end to the other speaker's peer task. None for the
+// side that only accepts.
+pub(crate) struct SimConnector {
+    link: Option<Link>
+}
+
+struct Link {
+    local: (IpAddr, u16),
+    remote: (IpAddr, u16),
+    accept: UnboundedSender<SimConnection>
+}
+
+impl Connector for SimConnector {
+    type Conn = SimConnection;
+    fn connect(&self) -> ConnectFuture<SimConnection> {
+        let link = match &self.link {
+            Some(link) => link,
+            None => return Box::pin(future::pending())
+        };
+        let (ours, theirs) = SimConnection::pair(link.local, link.remote);
+        let result = link
+            .accept
+            .send(theirs)
+            .map(|_| ours)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused));
+        Box::pin(future::ready(result))
+    }
+}
+
+#[derive(Default)]
+pub struct Simulation {
+    speakers: HashMap<Ipv4Addr, Speaker>
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_speaker(&mut self, router_id: Ipv4Addr, local_as: u16) -> Result<&mut Speaker, RouterIdError> {
+        Ok(self.add(Speaker::new(router_id, local_as)?))
+    }
+    pub fn add(&mut self, speaker: Speaker) -> &mut Speaker {
+        // For speakers set up beforehand, e.g. as route reflectors or with preconfigured tables
+        self.speakers.entry(speaker.router_id()).or_insert(speaker)
+    }
+    pub fn speaker(&self, router_id: Ipv4Addr) -> Option<&Speaker> {
+        self.speakers.get(&router_id)
+    }
+    pub fn speaker_mut(&mut self, router_id: Ipv4Addr) -> Option<&mut Speaker> {
+        self.speakers.get_mut(&router_id)
+    }
+    pub fn connect(&mut self, a: Ipv4Addr, b: Ipv4Addr) -> Result<(PeerHandle, PeerHandle), SpeakerError> {
+        // Peers a and b with default sessions, returning a's handle for b and b's handle for a
+        let peer = |speaker: &Simulation, from: Ipv4Addr, to: Ipv4Addr| {
+            let remote_as = speaker.speakers.get(&to).map_or(0, |to| to.local_as());
+            BgpPeer::new(IpAddr::V4(to), remote_as, IpAddr::V4(from), PeerSessionBuilder::new().build())
+        };
+        let (a_peer, b_peer) = (peer(self, a, b), peer(self, b, a));
+        self.link(a, a_peer, b, b_peer)
+    }
+    pub fn link(&mut self, a: Ipv4Addr, a_peer: BgpPeer, b: Ipv4Addr, b_peer: BgpPeer) -> Result<(PeerHandle, PeerHandle), SpeakerError> {
+        // Peers a and b as configured, a_peer being a's configuration for b and b_peer b's for a
+        let local_as = |router_id: Ipv4Addr| {
+            self.speakers
+                .get(&router_id)
+                .map(Speaker::local_as)
+                .ok_or(SpeakerError::UnknownPeer(IpAddr::V4(router_id)))
+        };
+        let (a_end, b_end) = ((IpAddr::V4(a), local_as(a)?), (IpAddr::V4(b), local_as(b)?));
+        let (accept, incoming) = mpsc::unbounded_channel();
+        // The lower router ID connects, the higher one accepts
+        let ((opener, opener_peer, opener_end), (acceptor, acceptor_peer, acceptor_end), a_opens) = match a < b {
+            true => ((a, a_peer, a_end), (b, b_peer, b_end), true),
+            false => ((b, b_peer, b_end), (a, a_peer, a_end), false)
+        };
+        let link = Link { local: opener_end, remote: acceptor_end, accept };
+        let acceptor_handle = self
+            .speakers
+            .get_mut(&acceptor)
+            .expect("both speakers were looked up")
+            .add_peer(acceptor_peer, SimConnector { link: None }, Some(incoming))?;
+        let opener_handle = self
+            .speakers
+            .get_mut(&opener)
+            .expect("both speakers were looked up")
+            .add_peer(opener_peer, SimConnector { link: Some(link) }, None)?;
+        match a_opens {
+            true => Ok((opener_handle, acceptor_handle)),
+            false => Ok((acceptor_handle, opener_handle))
+        }
+    }
+    pub async fn wait_for<F>(&self, router_id: Ipv4Addr, timeout: Duration, route: Route, check: F) -> Result<bool, SpeakerError>
+    where
+        F: Fn(Option<&RouteView>) -> bool
+    {
+        // Whether what the speaker has for the route passes the check within the timeout, e.g. once the
+        // route has propagated to it (Option::is_some) or been withdrawn from it (Option::is_none)
+        let speaker = self.speakers.get(&router_id).ok_or(SpeakerError::UnknownPeer(IpAddr::V4(router_id)))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if check(speaker.route(route.clone()).await?.as_ref()) {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+    pub async fn shutdown(self) {
+        for speaker in self.speakers.into_values() {
+            speaker.shutdown().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{path_attrs::AsSegment, table::LocalRoutes};
+
+    #[tokio::test(start_paused = true)]
+    async fn simulation_ebgp_chain() {
+        // 65001 - 65002 - 65003, a route from one end reaches the other and is withdrawn from it
+        let (a, b, c) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+        let mut sim = Simulation::new();
+        sim.add_speaker(a, 65001).unwrap();
+        sim.add_speaker(b, 65002).unwrap();
+        sim.add_speaker(c, 65003).unwrap();
+        sim.connect(a, b).unwrap();
+        sim.connect(c, b).unwrap();
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        sim.speaker(a).unwrap().originate(LocalRoutes::new(vec![route.clone()])).unwrap();
+        assert!(sim.wait_for(c, Duration::from_secs(60), route.clone(), |view| view.is_some()).await.unwrap());
+        let view = sim.speaker(c).unwrap().route(route.clone()).await.unwrap().unwrap();
+        let as_path = view.paths()[0].pas().iter().find_map(|pa| pa.as_path());
+        assert_eq!(as_path, Some(vec![AsSegment::AsSequence(vec![65002, 65001])]));
+
+        sim.speaker(a).unwrap().withdraw_originated(vec![route.clone()]).unwrap();
+        assert!(sim.wait_for(c, Duration::from_secs(60), route, |view| view.is_none()).await.unwrap());
+        sim.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulation_route_reflector() {
+        // Clients of a reflector only peer with it, without it they wouldn't hear of each other's routes
+        let (rr, c1, c2) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+        let mut sim = Simulation::new();
+        let mut reflector = Speaker::new(rr, 65000).unwrap();
+        let settings = reflector.route_reflector().client(IpAddr::V4(c1)).client(IpAddr::V4(c2));
+        assert_eq!(settings.cluster_id(), rr);
+        reflector.set_route_reflector(Some(settings)).unwrap();
+        sim.add(reflector);
+        sim.add_speaker(c1, 65000).unwrap();
+        sim.add_speaker(c2, 65000).unwrap();
+        sim.connect(rr, c1).unwrap();
+        sim.connect(rr, c2).unwrap();
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        sim.speaker(c1).unwrap().originate(LocalRoutes::new(vec![route.clone()])).unwrap();
+        assert!(sim.wait_for(c2, Duration::from_secs(60), route.clone(), |view| view.is_some()).await.unwrap());
+        let view = sim.speaker(c2).unwrap().route(route).await.unwrap().unwrap();
+        let reflected = view.paths()[0].pas();
+        assert_eq!(reflected.iter().find_map(|pa| pa.originator_id()), Some(c1));
+        assert_eq!(reflected.iter().find_map(|pa| pa.cluster_list()), Some(vec![rr]));
+        sim.shutdown().await;
+    }
+}
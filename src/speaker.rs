@@ -0,0 +1,1971 @@
+// A BGP speaker: the peers, the tables their routes go into and the policy in between, wired together.
+// Each peer runs in its own task (see peer.rs) and reports to the RIB task, which owns a table per address
+// family along with each family's Adj-RIB-In and the policy engine. Routes from a peer are checked for loops
+// (see as_loop.rs and, as a route reflector, RouteReflector::is_looped), then go through its import policy and
+// into the table, and every bestpath change goes back out through the default export rules and each
+// Established peer's export policy. A peer that comes up gets the full table. The RIB task also enforces
+// maximum-prefix limits, drains eBGP peers put in maintenance (RFC 8326) and publishes RouterEvents for
+// anyone subscribed. Aggregates are originated by it while the table holds a more specific route, there is
+// no suppression of the more specifics.
+// Speaker is the owner of all that, it must be created within a tokio runtime. Dropping it without calling
+// shutdown leaves the tasks running until their handles are gone. Other tasks get a SpeakerHandle.
+// Peers added with add_tcp_peer have their sessions run over TCP with BgpCodec. Their connections are
+// opened from the peer's local address, and accepted on the speaker's listener if it has one (see listen).
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncBufRead,
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tracing::{debug_span, warn};
+
+use crate::{
+    as_loop::{treat_as_withdraw, AsLoopCheck},
+    bgp_codec::BgpCodec,
+    comms::{ReceivedRoutes, RouteInjection, TableCommand},
+    errors::CeaseSubcode,
+    exabgp,
+    export::{ExportPeer, RouteReflector},
+    fib::{Fib, FibDriver},
+    fsm::{Connection, Fsm, PeerConnections},
+    fsm_ds::{BgpPeer, PeerStats},
+    graceful_shutdown::{self, Maintenance},
+    ingest::{ingest_queue, IngestReceiver, IngestSender, INGEST_CAPACITY},
+    looking_glass::{LgQuery, LgRoute},
+    max_prefix::{MaxPrefixConfig, MaxPrefixEvent, PrefixLimiter},
+    message_types::{build_updates, Afi, BGP_VERSION, Capability, Nlri, OpenBuilder, Route, Safi},
+    peer::{Connector, PeerHandle, PeerTask},
+    peer_group::{MissingRemoteAs, PeerGroup},
+    policy::{Direction, PolicyEngine, RouteMap},
+    redistribute::Redistributed,
+    router_events::{RouterEvent, ROUTER_EVENT_CAPACITY},
+    router_id::{check_router_id, RouterIdError, RouterIdSelector},
+    rpki::RoaTable,
+    rtr::{self, VrpUpdate},
+    table::{
+        AdjRibIn, AdjRibOut, AdvertisedRoutes, BestpathSubscriber, BgpTable, DecisionConfig, LocalRoutes, MemoryStats,
+        RouteView, SnapshotFormat, SnapshotRow, TableAfi, TableDelta,
+    },
+    table_handle::{TableClosed, TableHandle, TableJob},
+    timers::{Clock, TokioClock},
+    transport::{PeerListener, TcpConnector},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpeakerError {
+    PeerExists(IpAddr),
+    UnknownPeer(IpAddr),
+    // The peer's group has no remote AS and none was given
+    MissingRemoteAs(IpAddr),
+    // The peer's MD5 password couldn't be set on the listener
+    Md5(IpAddr, io::ErrorKind),
+    // The RIB task has exited
+    Closed
+}
+
+impl fmt::Display for SpeakerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpeakerError::PeerExists(peer) => write!(f, "BGP peer {} is already configured", peer),
+            SpeakerError::UnknownPeer(peer) => write!(f, "BGP peer {} is not configured", peer),
+            SpeakerError::MissingRemoteAs(peer) => write!(f, "{}", MissingRemoteAs(*peer)),
+            SpeakerError::Md5(peer, kind) => write!(f, "can't set the TCP MD5 password of BGP peer {}: {}", peer, kind),
+            SpeakerError::Closed => write!(f, "BGP speaker is no longer running")
+        }
+    }
+}
+
+impl std::error::Error for SpeakerError {}
+
+impl From<TableClosed> for SpeakerError {
+    fn from(_: TableClosed) -> Self {
+        SpeakerError::Closed
+    }
+}
+
+impl From<MissingRemoteAs> for SpeakerError {
+    fn from(value: MissingRemoteAs) -> Self {
+        SpeakerError::MissingRemoteAs(value.0)
+    }
+}
+
+enum RibRequest {
+    // Along with the peer's activated families and its allowas-in count
+    AddPeer(PeerHandle, ExportPeer, Vec<(Afi, Safi)>, u8),
+    RemovePeer(IpAddr),
+    Originate(LocalRoutes),
+    WithdrawOriginated(Vec<Route>),
+    // Replaces the aggregates
+    Aggregates(Vec<Route>),
+    // Replaces (or with None removes) a peer's route map for the direction
+    Policy(IpAddr, Direction, Option<RouteMap>),
+    MaxPrefix(IpAddr, Option<MaxPrefixConfig>),
+    // Paths that already went through the reflector's cluster are dropped while it's set
+    Reflector(Option<Arc<RouteReflector>>),
+    // Puts the peer (or with None every eBGP peer) in or out of maintenance
+    Maintenance(Option<IpAddr>, bool),
+    // What the peer's Adj-RIB-Out holds, None for unknown peers
+    AdjRibOut(IpAddr, oneshot::Sender<Option<Vec<Nlri>>>),
+    // Replaces the ROAs paths are validated against, or changes them, along with our AS for locally
+    // originated paths
+    Roas(RoaTable, u16),
+    Vrps(VrpUpdate, u16),
+    // Installs the family's bestpaths in a Fib from now on
+    Fib(Afi, Box<dyn BestpathSubscriber>),
+    Shutdown
+}
+
+impl From<Redistributed> for RibRequest {
+    fn from(change: Redistributed) -> Self {
+        match change {
+            Redistributed::Originate(local) => RibRequest::Originate(local),
+            Redistributed::Withdraw(routes) => RibRequest::WithdrawOriginated(routes)
+        }
+    }
+}
+
+pub struct Speaker {
+    // Our BGP Identifier, it goes into every Open and decides connection collisions. Checked when the
+    // speaker is made, a RouterIdSelector can pick it beforehand.
+    router_id: Ipv4Addr,
+    local_as: u16,
+    // Cloned into every peer's task, the RIB task owns the receiver
+    events: IngestSender,
+    requests: UnboundedSender<RibRequest>,
+    // Queries run on the RIB task too
+    tables: TableHandle,
+    // Kept to hand out subscriptions, the RIB task sends the events
+    router_events: broadcast::Sender<RouterEvent>,
+    // Set if we reflect routes between our iBGP peers (RFC 4456)
+    reflector: Option<Arc<RouteReflector>>,
+    clock: Arc<dyn Clock>,
+    // Takes the connections of TCP peers, once listen has been called
+    listener: Option<PeerListener<BgpCodec>>,
+    peers: HashMap<IpAddr, SpeakerPeer>,
+    // What handles know about the peers, kept along with peers
+    shared_peers: Arc<RwLock<HashMap<IpAddr, SharedPeer>>>,
+    rib: JoinHandle<()>
+}
+
+struct SpeakerPeer {
+    handle: PeerHandle,
+    task: JoinHandle<Fsm>,
+    remote_as: u16,
+    // Where the peer's connections are accepted, see PeerListener::add_bgp_peer
+    local_address: IpAddr,
+    group: Option<String>
+}
+
+impl Speaker {
+    pub fn new(router_id: Ipv4Addr, local_as: u16) -> Result<Self, RouterIdError> {
+        Self::from_tables(router_id, local_as, BgpTable::new(), BgpTable::new())
+    }
+    pub fn from_selector(selector: &RouterIdSelector, local_as: u16) -> Result<Self, RouterIdError> {
+        // Same, with the BGP Identifier picked from our addresses unless one is configured
+        Self::new(selector.select()?, local_as)
+    }
+    pub fn with_decision_config(
+        router_id: Ipv4Addr,
+        local_as: u16,
+        config: DecisionConfig
+    ) -> Result<Self, RouterIdError> {
+        // Same, with the optional steps of the Decision Process set. They can't change once the speaker runs.
+        Self::from_tables(router_id, local_as, BgpTable::with_config(config.clone()), BgpTable::with_config(config))
+    }
+    pub(crate) fn from_tables(
+        router_id: Ipv4Addr,
+        local_as: u16,
+        v4: BgpTable<Ipv4Addr>,
+        v6: BgpTable<Ipv6Addr>
+    ) -> Result<Self, RouterIdError> {
+        // The tables may come preconfigured, e.g. with a DecisionConfig or ROAs
+        Self::with_clock(router_id, local_as, v4, v6, Arc::new(TokioClock))
+    }
+    pub(crate) fn with_clock(
+        router_id: Ipv4Addr,
+        local_as: u16,
+        mut v4: BgpTable<Ipv4Addr>,
+        mut v6: BgpTable<Ipv6Addr>,
+        clock: Arc<dyn Clock>
+    ) -> Result<Self, RouterIdError> {
+        // Every timer of the speaker, its peers' and the RIB's, runs on the clock
+        let router_id = check_router_id(router_id)?;
+        v4.set_clock(Arc::clone(&clock));
+        v6.set_clock(Arc::clone(&clock));
+        let (events, events_rx) = ingest_queue(INGEST_CAPACITY);
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let (tables, jobs) = TableHandle::new();
+        let (router_events, _) = broadcast::channel(ROUTER_EVENT_CAPACITY);
+        let rib = Rib {
+            v4: Family::new(v4),
+            v6: Family::new(v6),
+            policy: PolicyEngine::new(),
+            limiter: PrefixLimiter::new(),
+            maintenance: Maintenance::new(),
+            as_loop: AsLoopCheck::new(local_as),
+            router_id,
+            reflector: None,
+            router_events: router_events.clone(),
+            clock: Arc::clone(&clock),
+            peers: HashMap::new(),
+            aggregates: Vec::new()
+        };
+        Ok(Self {
+            router_id,
+            local_as,
+            events,
+            requests,
+            tables,
+            router_events,
+            reflector: None,
+            clock,
+            listener: None,
+            peers: HashMap::new(),
+            shared_peers: Arc::new(RwLock::new(HashMap::new())),
+            rib: tokio::spawn(rib.run(requests_rx, jobs, events_rx))
+        })
+    }
+    pub fn set_route_reflector(&mut self, reflector: Option<RouteReflector>) -> Result<(), SpeakerError> {
+        // Applies to iBGP peers added from now on, the RIB checks what it receives against it right away
+        self.reflector = reflector.map(Arc::new);
+        self.send(RibRequest::Reflector(self.reflector.clone()))
+    }
+    pub fn reflector(&self) -> Option<&RouteReflector> {
+        self.reflector.as_deref()
+    }
+    pub fn route_reflector(&self) -> RouteReflector {
+        // Reflector settings with our BGP Identifier as the CLUSTER_ID, the usual choice for a cluster
+        // with a single reflector. RFC 4456, Pg. 4
+        RouteReflector::new(self.router_id)
+    }
+    pub(crate) fn connections(&self) -> PeerConnections {
+        // For a peer's two candidate connections, collisions are resolved against our BGP Identifier
+        PeerConnections::new(self.router_id)
+    }
+    pub fn router_id(&self) -> Ipv4Addr {
+        self.router_id
+    }
+    pub async fn listen(&mut self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        // Accepts connections for the TCP peers added from now on, usually on port 179. Returns the address
+        // listened on, e.g. the port picked for port 0.
+        let listener = PeerListener::bind(addr).await?;
+        let local_addr = listener.local_addr();
+        self.listener = Some(listener);
+        Ok(local_addr)
+    }
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().map(PeerListener::local_addr)
+    }
+    pub fn add_tcp_peer(&mut self, peer: BgpPeer) -> Result<PeerHandle, SpeakerError> {
+        // add_peer with connections over TCP, see TcpConnector::for_peer
+        if self.peers.contains_key(&peer.peer_address) {
+            return Err(SpeakerError::PeerExists(peer.peer_address));
+        }
+        let codec = BgpCodec::new(peer.peer_address, self.local_as);
+        let connector = TcpConnector::for_peer(&peer, codec.clone());
+        let incoming = match &self.listener {
+            Some(listener) => Some(
+                listener
+                    .add_bgp_peer(&peer, codec)
+                    .map_err(|err| SpeakerError::Md5(peer.peer_address, err.kind()))?
+            ),
+            None => None
+        };
+        self.add_peer(peer, connector, incoming)
+    }
+    pub fn add_group_peer(
+        &mut self,
+        group: &PeerGroup,
+        peer_address: IpAddr,
+        remote_as: Option<u16>,
+        local_address: IpAddr
+    ) -> Result<PeerHandle, SpeakerError> {
+        // add_tcp_peer with a peer built from the group. The group's remote AS is used if none is given.
+        let peer = match remote_as {
+            Some(remote_as) => group.peer_with_as(peer_address, remote_as, local_address),
+            None => group.peer(peer_address, local_address)?
+        };
+        self.add_tcp_peer(peer)
+    }
+    pub fn local_as(&self) -> u16 {
+        self.local_as
+    }
+    pub fn peers(&self) -> impl Iterator<Item = &PeerHandle> {
+        self.peers.values().map(|peer| &peer.handle)
+    }
+    pub fn peer(&self, peer: IpAddr) -> Option<&PeerHandle> {
+        self.peers.get(&peer).map(|peer| &peer.handle)
+    }
+    pub fn remote_as(&self, peer: IpAddr) -> Option<u16> {
+        self.peers.get(&peer).map(|peer| peer.remote_as)
+    }
+    pub fn group(&self, peer: IpAddr) -> Option<&str> {
+        self.peers.get(&peer).and_then(|peer| peer.group.as_deref())
+    }
+    pub fn group_peers<'a>(&'a self, group: &'a str) -> impl Iterator<Item = &'a PeerHandle> + 'a {
+        // Every peer configured from the peer group, e.g. to reset them all
+        self.peers
+            .values()
+            .filter(move |peer| peer.group.as_deref() == Some(group))
+            .map(|peer| &peer.handle)
+    }
+    pub(crate) fn add_peer<K: Connector>(
+        &mut self,
+        peer: BgpPeer,
+        connector: K,
+        incoming: Option<UnboundedReceiver<K::Conn>>
+    ) -> Result<PeerHandle, SpeakerError> {
+        // Spawns the peer's task and starts the session. Connections the peer opens to us come through
+        // incoming, if given.
+        let peer_addr = peer.peer_address;
+        if self.peers.contains_key(&peer_addr) {
+            return Err(SpeakerError::PeerExists(peer_addr));
+        }
+        let export = peer
+            .export_settings()
+            .apply(ExportPeer::new(peer_addr, peer.remote_as, self.local_as, peer.local_address));
+        let export = match (&self.reflector, peer.remote_as == self.local_as) {
+            (Some(reflector), true) => export.route_reflector(Arc::clone(reflector)),
+            _ => export
+        };
+        // The peer sees the migration AS if there is one
+        let open = peer
+            .families()
+            .iter()
+            .fold(
+                OpenBuilder::new(BGP_VERSION, export.session_as(), peer.session().hold_time() as u16, u32::from(self.router_id)),
+                |open, (afi, safi)| open.capability(Capability::Multiprotocol(*afi, *safi))
+            )
+            .capability(Capability::RouteRefresh)
+            .capability(Capability::FourOctetAs(export.session_as() as u32))
+            .build();
+        let (import, export_map) = peer.policies();
+        for (direction, map) in [(Direction::Import, import), (Direction::Export, export_map)] {
+            if let Some(map) = map {
+                self.send(RibRequest::Policy(peer_addr, direction, Some(map.clone())))?;
+            }
+        }
+        let (remote_as, local_address, group) = (peer.remote_as, peer.local_address, peer.group.clone());
+        let (families, allowas_in) = (peer.families().to_vec(), peer.own_as_allowed());
+        // Only a single-hop eBGP peer has to use a NEXT_HOP on the subnet we share with it
+        let shared_subnet = peer.subnet().filter(|_| remote_as != self.local_as).cloned();
+        let fsm = Fsm::new(peer.into_session(), open).remote_as(remote_as as u32);
+        let (handle, requests) = PeerHandle::new(peer_addr);
+        let mut fsms = self.connections();
+        fsms.add(Connection::Outgoing, fsm);
+        let mut task = PeerTask::new(peer_addr, fsms, connector, Arc::clone(&self.clock), self.events.clone())
+            .check_next_hop(local_address, shared_subnet);
+        if let Some(incoming) = incoming {
+            task = task.incoming(incoming);
+        }
+        self.send(RibRequest::AddPeer(handle.clone(), export, families, allowas_in))?;
+        let task = task.spawn(requests);
+        // The task is brand new, it can't have exited yet
+        _ = handle.start();
+        let shared = SharedPeer { handle: handle.clone(), remote_as, group: group.clone() };
+        self.shared_peers.write().unwrap().insert(peer_addr, shared);
+        self.peers.insert(peer_addr, SpeakerPeer { handle: handle.clone(), task, remote_as, local_address, group });
+        Ok(handle)
+    }
+    pub async fn remove_peer(&mut self, peer: IpAddr) -> Result<PeerStats, SpeakerError> {
+        // Shuts the session down and waits for the peer's task, its routes are withdrawn from everyone else.
+        // Returns what the session counted up to the end.
+        let removed = self.peers.remove(&peer).ok_or(SpeakerError::UnknownPeer(peer))?;
+        self.shared_peers.write().unwrap().remove(&peer);
+        if let Some(listener) = &self.listener {
+            let local = Some(removed.local_address).filter(|local| !local.is_unspecified());
+            listener.remove_peer(peer, local);
+        }
+        _ = removed.handle.exit();
+        self.send(RibRequest::RemovePeer(peer))?;
+        removed.task.await.map(|fsm| fsm.stats().clone()).map_err(|_| SpeakerError::Closed)
+    }
+    pub fn set_group(&mut self, peer: IpAddr, group: Option<String>) -> Result<(), SpeakerError> {
+        // The peer group the peer counts as a member of, nothing about the peer itself changes
+        let speaker_peer = self.peers.get_mut(&peer).ok_or(SpeakerError::UnknownPeer(peer))?;
+        if let Some(shared) = self.shared_peers.write().unwrap().get_mut(&peer) {
+            shared.group = group.clone();
+        }
+        speaker_peer.group = group;
+        Ok(())
+    }
+    pub fn originate(&self, local: LocalRoutes) -> Result<(), SpeakerError> {
+        self.send(RibRequest::Originate(local))
+    }
+    pub fn load_roas(&self, roas: RoaTable) -> Result<(), SpeakerError> {
+        // Validates every path against the ROAs from now on, replacing any loaded before. How the validation
+        // states are used is up to the DecisionConfig's RpkiPolicy.
+        self.send(RibRequest::Roas(roas, self.local_as))
+    }
+    pub fn connect_rtr(&self, cache: SocketAddr) -> JoinHandle<()> {
+        // Keeps the ROAs in sync with an RPKI cache over RTR (RFC 8210) instead, for as long as the speaker
+        // runs. The connection is retried if it fails, abort the task to stop.
+        let (requests, local_as) = (self.requests.clone(), self.local_as);
+        tokio::spawn(rtr::sync(cache, move |update| requests.send(RibRequest::Vrps(update, local_as)).is_ok()))
+    }
+    pub fn attach_fib<F: Fib + 'static>(&self, afi: Afi, fib: F) -> Result<(), SpeakerError> {
+        // The family's destinations are installed in the Fib, starting with the ones already in the table,
+        // along with every path tied with the bestpath if multipath is on (see DecisionConfig::max_paths).
+        // Each family needs a Fib of its own.
+        self.send(RibRequest::Fib(afi, Box::new(FibDriver::new(fib))))
+    }
+    pub fn withdraw_originated(&self, routes: Vec<Route>) -> Result<(), SpeakerError> {
+        self.send(RibRequest::WithdrawOriginated(routes))
+    }
+    pub fn set_aggregates(&self, aggregates: Vec<Route>) -> Result<(), SpeakerError> {
+        // Replaces the aggregates. Each is originated once the table has a route more specific than it and
+        // withdrawn with the last of them, the aggregates dropped here are withdrawn.
+        self.send(RibRequest::Aggregates(aggregates))
+    }
+    pub fn redistribute(&self, change: Redistributed) -> Result<(), SpeakerError> {
+        // Applies a change from Redistribution::update() or exabgp::parse_update()
+        self.send(RibRequest::from(change))
+    }
+    pub async fn feed_exabgp<R: AsyncBufRead + Unpin>(&self, reader: R) -> Result<usize, SpeakerError> {
+        // Originates and withdraws what the ExaBGP JSON updates read from the input ask for, one per line,
+        // until it ends. Returns how many were applied, see exabgp.rs.
+        exabgp::feed(reader, self).await
+    }
+    #[cfg(all(feature = "netlink", target_os = "linux"))]
+    pub fn redistribute_kernel(
+        &self,
+        mut monitor: crate::kernel_fib::KernelRouteMonitor,
+        mut redistribution: crate::redistribute::Redistribution
+    ) -> std::thread::JoinHandle<io::Result<()>> {
+        // Redistributes the routes of the monitor's table, starting with the ones it has now. The monitor
+        // blocks, so it gets a thread of its own, which ends on the first change after the speaker is gone.
+        let requests = self.requests.clone();
+        std::thread::spawn(move || {
+            let mut events = monitor.dump()?;
+            loop {
+                for change in events.into_iter().filter_map(|event| redistribution.update(event)) {
+                    if requests.send(RibRequest::from(change)).is_err() {
+                        return Ok(());
+                    }
+                }
+                events = monitor.recv()?;
+            }
+        })
+    }
+    pub fn inject(&self, injection: RouteInjection) -> Result<(), SpeakerError> {
+        // Imports the routes as if the source had sent them, see comms::RouteInjection. Configured
+        // peers' routes only come from their sessions.
+        let source = injection.source();
+        if self.peers.contains_key(&source) {
+            return Err(SpeakerError::PeerExists(source));
+        }
+        injection
+            .payloads(self.local_as)
+            .into_iter()
+            .try_for_each(|payload| self.events.send(TableCommand::Routes(payload)))
+            .map_err(|_| SpeakerError::Closed)
+    }
+    pub fn clear_injected(&self, source: IpAddr) -> Result<(), SpeakerError> {
+        // Withdraws everything injected from the source, as its session going down would
+        if self.peers.contains_key(&source) {
+            return Err(SpeakerError::PeerExists(source));
+        }
+        self.events.send(TableCommand::Down(source, None)).map_err(|_| SpeakerError::Closed)
+    }
+    pub fn set_policy(&self, peer: IpAddr, direction: Direction, map: Option<RouteMap>) -> Result<(), SpeakerError> {
+        // Applies to routes from now on, a soft reset applies it to what was already exchanged
+        self.send(RibRequest::Policy(peer, direction, map))
+    }
+    pub fn set_max_prefix(&self, peer: IpAddr, config: Option<MaxPrefixConfig>) -> Result<(), SpeakerError> {
+        // Counts from the routes accepted from now on, None removes the limit
+        self.send(RibRequest::MaxPrefix(peer, config))
+    }
+    pub fn start_maintenance(&self, peer: Option<IpAddr>) -> Result<(), SpeakerError> {
+        // Drains traffic away from the eBGP peer, or every eBGP peer with None, before it's shut down. Its
+        // routes are sent again tagged with GRACEFUL_SHUTDOWN and the routes from it lose out on LOCAL_PREF.
+        self.send(RibRequest::Maintenance(peer, true))
+    }
+    pub fn stop_maintenance(&self, peer: Option<IpAddr>) -> Result<(), SpeakerError> {
+        self.send(RibRequest::Maintenance(peer, false))
+    }
+    pub fn enable_duplicate_detection(&self, window: Duration, suppress: bool) -> Result<(), SpeakerError> {
+        // Counts announcements a peer repeats unchanged within the window, and with suppress doesn't pass
+        // them on. Counting starts over when called again.
+        Ok(self.tables.run(Box::new(move |v4, v6| {
+            v4.enable_duplicate_detection(window, suppress);
+            v6.enable_duplicate_detection(window, suppress);
+        }))?)
+    }
+    pub async fn duplicates(&self, peer: IpAddr) -> Result<usize, SpeakerError> {
+        // Duplicate announcements received from the peer, of both families. Always 0 without duplicate
+        // detection enabled.
+        self.with_tables(move |v4, v6| {
+            [v4.duplicate_detector(), v6.duplicate_detector()]
+                .into_iter()
+                .flatten()
+                .map(|detector| detector.duplicates(peer))
+                .sum()
+        })
+        .await
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<RouterEvent> {
+        // Events from now on, see router_events.rs
+        self.router_events.subscribe()
+    }
+    pub(crate) async fn with_tables<R, F>(&self, f: F) -> Result<R, SpeakerError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BgpTable<Ipv4Addr>, &mut BgpTable<Ipv6Addr>) -> R + Send + 'static
+    {
+        // Runs the closure on the RIB task, e.g. for show commands
+        Ok(self.tables.with_tables(f).await?)
+    }
+    pub async fn advertised_routes(&self, peer: IpAddr) -> Result<Vec<Nlri>, SpeakerError> {
+        // The routes the peer has been sent with their PAs, after the export rules and its export
+        // policy. Empty unless the session is Established.
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(RibRequest::AdjRibOut(peer, reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| SpeakerError::Closed)?
+            .ok_or(SpeakerError::UnknownPeer(peer))
+    }
+    pub fn tables(&self) -> TableHandle {
+        // For querying the tables without the speaker, e.g. from another task. Every query below goes
+        // through it.
+        self.tables.clone()
+    }
+    pub async fn routes(&self, afi: Afi) -> Result<Vec<RouteView>, SpeakerError> {
+        Ok(self.tables.routes(afi).await?)
+    }
+    pub async fn route(&self, route: Route) -> Result<Option<RouteView>, SpeakerError> {
+        Ok(self.tables.route(route).await?)
+    }
+    pub async fn routes_from_peer(&self, peer: IpAddr) -> Result<Vec<RouteView>, SpeakerError> {
+        Ok(self.tables.routes_from_peer(peer).await?)
+    }
+    pub async fn routes_with_community(&self, community: u32) -> Result<Vec<RouteView>, SpeakerError> {
+        Ok(self.tables.routes_with_community(community).await?)
+    }
+    pub async fn looking_glass(&self, query: LgQuery, limit: usize) -> Result<Vec<LgRoute>, SpeakerError> {
+        Ok(self.tables.looking_glass(query, limit).await?)
+    }
+    pub async fn snapshot(&self, afi: Afi) -> Result<Vec<SnapshotRow>, SpeakerError> {
+        Ok(self.tables.snapshot(afi).await?)
+    }
+    pub async fn export_table(&self, afi: Afi, format: SnapshotFormat) -> Result<String, SpeakerError> {
+        Ok(self.tables.export(afi, format).await?)
+    }
+    pub async fn memory_stats(&self, afi: Afi) -> Result<MemoryStats, SpeakerError> {
+        Ok(self.tables.memory_stats(afi).await?)
+    }
+    pub async fn changes_since(&self, afi: Afi, version: usize) -> Result<TableDelta, SpeakerError> {
+        Ok(self.tables.changes_since(afi, version).await?)
+    }
+    pub async fn shutdown(mut self) {
+        // Takes every session down, then stops the RIB task
+        let peers: Vec<SpeakerPeer> = self.peers.drain().map(|(_, peer)| peer).collect();
+        for peer in peers {
+            _ = peer.handle.exit();
+            _ = peer.task.await;
+        }
+        drop(self.events);
+        _ = self.requests.send(RibRequest::Shutdown);
+        _ = self.rib.await;
+    }
+    pub fn handle(&self) -> SpeakerHandle {
+        SpeakerHandle {
+            router_id: self.router_id,
+            local_as: self.local_as,
+            requests: self.requests.clone(),
+            tables: self.tables.clone(),
+            peers: Arc::clone(&self.shared_peers)
+        }
+    }
+    fn send(&self, request: RibRequest) -> Result<(), SpeakerError> {
+        self.requests.send(request).map_err(|_| SpeakerError::Closed)
+    }
+}
+
+// A peer as a SpeakerHandle sees it
+#[derive(Clone)]
+struct SharedPeer {
+    handle: PeerHandle,
+    remote_as: u16,
+    group: Option<String>
+}
+
+// A Speaker for other tasks, e.g. the management API (see admin.rs). Handles can be cloned and sent anywhere,
+// they see the peers as the Speaker adds and removes them and can query the tables and change the originated
+// routes. Only the Speaker itself adds and removes peers. Once it has shut down every request fails with
+// SpeakerError::Closed.
+#[derive(Clone)]
+pub struct SpeakerHandle {
+    router_id: Ipv4Addr,
+    local_as: u16,
+    requests: UnboundedSender<RibRequest>,
+    tables: TableHandle,
+    peers: Arc<RwLock<HashMap<IpAddr, SharedPeer>>>
+}
+
+impl SpeakerHandle {
+    pub fn router_id(&self) -> Ipv4Addr {
+        self.router_id
+    }
+    pub fn local_as(&self) -> u16 {
+        self.local_as
+    }
+    pub fn peers(&self) -> Vec<PeerHandle> {
+        self.peers.read().unwrap().values().map(|peer| peer.handle.clone()).collect()
+    }
+    pub fn peer(&self, peer: IpAddr) -> Option<PeerHandle> {
+        self.peers.read().unwrap().get(&peer).map(|peer| peer.handle.clone())
+    }
+    pub fn remote_as(&self, peer: IpAddr) -> Option<u16> {
+        self.peers.read().unwrap().get(&peer).map(|peer| peer.remote_as)
+    }
+    pub fn group(&self, peer: IpAddr) -> Option<String> {
+        self.peers.read().unwrap().get(&peer).and_then(|peer| peer.group.clone())
+    }
+    pub fn originate(&self, local: LocalRoutes) -> Result<(), SpeakerError> {
+        self.send(RibRequest::Originate(local))
+    }
+    pub fn withdraw_originated(&self, routes: Vec<Route>) -> Result<(), SpeakerError> {
+        self.send(RibRequest::WithdrawOriginated(routes))
+    }
+    pub fn redistribute(&self, change: Redistributed) -> Result<(), SpeakerError> {
+        self.send(RibRequest::from(change))
+    }
+    pub async fn advertised_routes(&self, peer: IpAddr) -> Result<Vec<Nlri>, SpeakerError> {
+        // See Speaker::advertised_routes
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(RibRequest::AdjRibOut(peer, reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| SpeakerError::Closed)?
+            .ok_or(SpeakerError::UnknownPeer(peer))
+    }
+    pub fn tables(&self) -> TableHandle {
+        self.tables.clone()
+    }
+    pub async fn looking_glass(&self, query: LgQuery, limit: usize) -> Result<Vec<LgRoute>, SpeakerError> {
+        Ok(self.tables.looking_glass(query, limit).await?)
+    }
+    fn send(&self, request: RibRequest) -> Result<(), SpeakerError> {
+        self.requests.send(request).map_err(|_| SpeakerError::Closed)
+    }
+}
+
+struct RibPeer {
+    handle: PeerHandle,
+    export: ExportPeer,
+    // Address families configured on the peer, and the ones of them the peer advertised this session
+    activated: Vec<(Afi, Safi)>,
+    families: Vec<(Afi, Safi)>,
+    // Only Established peers are sent Updates
+    up: bool,
+    adj_rib_out: AdjRibOut
+}
+
+impl RibPeer {
+    fn exchanges<A: TableAfi>(&self) -> bool {
+        // Whether routes of the table's address family go to and come from the peer
+        self.families.contains(&(A::AFI, A::SAFI))
+    }
+}
+
+// The Rib's Family for the table's address family, lets code that's generic over the family get to its table
+trait RibFamily: TableAfi + Sized {
+    fn family(rib: &Rib) -> &Family<Self>;
+}
+
+impl RibFamily for Ipv4Addr {
+    fn family(rib: &Rib) -> &Family<Self> {
+        &rib.v4
+    }
+}
+
+impl RibFamily for Ipv6Addr {
+    fn family(rib: &Rib) -> &Family<Self> {
+        &rib.v6
+    }
+}
+
+// An address family's table along with what each peer sent for it, before policy
+struct Family<A: TableAfi> {
+    table: BgpTable<A>,
+    adj_rib_in: AdjRibIn
+}
+
+impl<A: TableAfi> Family<A> {
+    fn new(table: BgpTable<A>) -> Self {
+        Self { table, adj_rib_in: AdjRibIn::new() }
+    }
+    fn import(&mut self, payload: ReceivedRoutes, policy: &PolicyEngine, limiter: &mut PrefixLimiter, draining: bool) -> Import<A> {
+        // Walks what import policy lets through, counting it against the peer's maximum-prefix limit
+        let _span = debug_span!("import", peer = %payload.peer_addr(), afi = ?payload.afi(), safi = ?payload.safi()).entered();
+        self.adj_rib_in.update(&payload);
+        let payloads: Vec<ReceivedRoutes> = policy
+            .apply_import(payload)
+            .into_iter()
+            .map(|payload| graceful_shutdown::import(payload, draining))
+            .collect();
+        let accepted = payloads
+            .iter()
+            .map(|payload| payload.routes().map_or(0, |routes| routes.len()))
+            .sum();
+        let now = self.table.clock().now();
+        let limit = payloads
+            .iter()
+            .filter_map(|payload| limiter.update(payload, now))
+            .last();
+        let (removed, adv) = self.table.walk_batch(payloads);
+        Import { accepted, limit, removed, adv }
+    }
+    fn replay(&mut self, peer: IpAddr, policy: &PolicyEngine, draining: bool) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        self.adj_rib_in.replay(peer, &mut self.table, |route, pas| {
+            policy
+                .apply(peer, Direction::Import, route, pas)
+                .map(|pas| graceful_shutdown::on_receipt(&pas, draining).unwrap_or(pas))
+        })
+    }
+    fn release(&mut self, peer: IpAddr) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        self.adj_rib_in.remove_peer(peer);
+        self.table.release_peer(peer)
+    }
+}
+
+// What came of importing a payload
+struct Import<A> {
+    // Announced routes that made it through import policy
+    accepted: usize,
+    limit: Option<MaxPrefixEvent>,
+    removed: Vec<Route>,
+    adv: AdvertisedRoutes<A>
+}
+
+struct Rib {
+    v4: Family<Ipv4Addr>,
+    v6: Family<Ipv6Addr>,
+    policy: PolicyEngine,
+    limiter: PrefixLimiter,
+    maintenance: Maintenance,
+    // Drops paths with our AS in the AS_PATH before they get to import policy or the Adj-RIB-In
+    as_loop: AsLoopCheck,
+    router_id: Ipv4Addr,
+    reflector: Option<Arc<RouteReflector>>,
+    router_events: broadcast::Sender<RouterEvent>,
+    clock: Arc<dyn Clock>,
+    peers: HashMap<IpAddr, RibPeer>,
+    // Along with whether each is originated, see aggregate()
+    aggregates: Vec<(Route, bool)>
+}
+
+impl Rib {
+    async fn run(
+        mut self,
+        mut requests: UnboundedReceiver<RibRequest>,
+        mut jobs: UnboundedReceiver<TableJob>,
+        mut events: IngestReceiver
+    ) {
+        // Requests are handled first, so a peer is known before its events arrive. Queries come next, a
+        // query sent after a request sees what the request did. Advertisements held back by the MRAI go
+        // out once nothing else is waiting.
+        loop {
+            let next_flush = self.next_flush();
+            tokio::select! {
+                biased;
+                request = requests.recv() => match request {
+                    Some(RibRequest::Shutdown) | None => break,
+                    Some(request) => self.request(request)
+                },
+                Some(job) = jobs.recv() => job(&mut self.v4.table, &mut self.v6.table),
+                Some(event) = events.recv() => self.event(event),
+                _ = self.clock.sleep(next_flush.unwrap_or_default()), if next_flush.is_some() => self.flush_all()
+            }
+        }
+    }
+    fn request(&mut self, request: RibRequest) {
+        match request {
+            RibRequest::AddPeer(handle, mut export, activated, allowas_in) => {
+                let peer = handle.peer_addr();
+                self.as_loop.set_allowas_in(peer, allowas_in);
+                export.set_graceful_shutdown(self.maintenance.contains(peer));
+                let adj_rib_out = AdjRibOut::new(peer, AdjRibOut::default_mrai(&export.route_source()));
+                let rib_peer = RibPeer { handle, export, activated, families: Vec::new(), up: false, adj_rib_out };
+                self.peers.insert(peer, rib_peer);
+            },
+            RibRequest::RemovePeer(peer) => {
+                // The session goes with the peer, its Down event only comes once the peer is forgotten
+                if self.peers.remove(&peer).is_some_and(|rib_peer| rib_peer.up) {
+                    self.publish(RouterEvent::PeerDown { peer, notification: None });
+                }
+                self.policy.detach(peer, Direction::Import);
+                self.policy.detach(peer, Direction::Export);
+                self.limiter.unconfigure(peer);
+                self.limiter.clear(peer);
+                self.maintenance.forget(peer);
+                self.as_loop.set_allowas_in(peer, 0);
+            },
+            RibRequest::Maintenance(peer, on) => {
+                match on {
+                    true => self.maintenance.start(peer),
+                    false => self.maintenance.stop(peer)
+                }
+                let changed: Vec<IpAddr> = self
+                    .peers
+                    .iter_mut()
+                    .filter_map(|(addr, rib_peer)| {
+                        let changed = rib_peer.export.set_graceful_shutdown(self.maintenance.contains(*addr));
+                        (changed && rib_peer.up).then_some(*addr)
+                    })
+                    .collect();
+                // What was received from the peer gets its LOCAL_PREF again, then it's sent everything again
+                for peer in changed {
+                    self.replay(peer);
+                    self.send_table(peer);
+                }
+            },
+            RibRequest::Originate(local) => {
+                if let Some(local) = local.for_afi(Afi::Ipv4) {
+                    let adv = self.v4.table.originate(local);
+                    self.distribute(Vec::new(), adv);
+                }
+                if let Some(local) = local.for_afi(Afi::Ipv6) {
+                    let adv = self.v6.table.originate(local);
+                    self.distribute(Vec::new(), adv);
+                }
+            },
+            RibRequest::WithdrawOriginated(routes) => {
+                let (v4, v6): (Vec<Route>, Vec<Route>) = routes.into_iter().partition(|route| route.prefix().is_ipv4());
+                let (removed, adv) = self.v4.table.withdraw_originated(v4);
+                self.distribute(removed, adv);
+                let (removed, adv) = self.v6.table.withdraw_originated(v6);
+                self.distribute(removed, adv);
+            },
+            RibRequest::Aggregates(aggregates) => {
+                let withdrawn: Vec<Route> = self.aggregates
+                    .iter()
+                    .filter(|(route, originated)| *originated && !aggregates.contains(route))
+                    .map(|(route, _)| route.clone())
+                    .collect();
+                self.aggregates = aggregates
+                    .into_iter()
+                    .map(|route| {
+                        let originated = self.aggregates.contains(&(route.clone(), true));
+                        (route, originated)
+                    })
+                    .collect();
+                if !withdrawn.is_empty() {
+                    self.request(RibRequest::WithdrawOriginated(withdrawn));
+                }
+                self.aggregate();
+            },
+            RibRequest::Policy(peer, direction, map) => {
+                match map {
+                    Some(map) => self.policy.attach(peer, direction, map),
+                    None => _ = self.policy.detach(peer, direction)
+                }
+            },
+            RibRequest::MaxPrefix(peer, config) => match config {
+                Some(config) => self.limiter.configure(peer, config),
+                None => self.limiter.unconfigure(peer)
+            },
+            RibRequest::Reflector(reflector) => self.reflector = reflector,
+            RibRequest::AdjRibOut(peer, reply) => {
+                _ = reply.send(self.peers.get(&peer).map(|rib_peer| rib_peer.adj_rib_out.advertised()));
+            },
+            RibRequest::Roas(roas, local_as) => {
+                self.v4.table.load_roas(roas.clone(), local_as);
+                self.v6.table.load_roas(roas, local_as);
+                let (removed, adv) = self.v4.table.revalidate_all();
+                self.distribute(removed, adv);
+                let (removed, adv) = self.v6.table.revalidate_all();
+                self.distribute(removed, adv);
+            },
+            RibRequest::Vrps(update, local_as) => {
+                self.v4.table.set_local_as(local_as);
+                self.v6.table.set_local_as(local_as);
+                let (removed, adv) = self.v4.table.apply_vrp_update(&update);
+                self.distribute(removed, adv);
+                let (removed, adv) = self.v6.table.apply_vrp_update(&update);
+                self.distribute(removed, adv);
+            },
+            RibRequest::Fib(afi, driver) => match afi {
+                Afi::Ipv4 => self.v4.table.subscribe(driver),
+                Afi::Ipv6 => self.v6.table.subscribe(driver)
+            },
+            RibRequest::Shutdown => ()
+        }
+    }
+    fn event(&mut self, event: TableCommand) {
+        match event {
+            TableCommand::Up(peer, params) => {
+                if let Some(rib_peer) = self.peers.get_mut(&peer) {
+                    // Only the negotiated families we activated. Negotiation implies IPv4 unicast when an Open has no
+                    // Multiprotocol capabilities, which mustn't bring back a family we deactivated.
+                    rib_peer.families = params
+                        .capabilities()
+                        .afi_safis()
+                        .iter()
+                        .filter(|family| rib_peer.activated.contains(family))
+                        .copied()
+                        .collect();
+                    rib_peer.up = true;
+                    rib_peer.adj_rib_out.clear();
+                }
+                self.publish(RouterEvent::PeerUp { peer, remote_as: params.remote_as(), router_id: params.remote_id() });
+                self.send_table(peer);
+            },
+            TableCommand::Routes(payload) => self.routes(payload),
+            TableCommand::Down(peer, notification) => {
+                // Routes go even if the peer was removed in the meantime
+                if let Some(rib_peer) = self.peers.get_mut(&peer).filter(|rib_peer| rib_peer.up) {
+                    rib_peer.up = false;
+                    rib_peer.adj_rib_out.clear();
+                    self.publish(RouterEvent::PeerDown { peer, notification });
+                }
+                self.limiter.remove_peer(peer);
+                let (removed, adv) = self.v4.release(peer);
+                self.distribute(removed, adv);
+                let (removed, adv) = self.v6.release(peer);
+                self.distribute(removed, adv);
+            },
+            TableCommand::MarkStale(peer, afi_safis) => {
+                // The peer is restarting, its routes are kept for now
+                if let Some(rib_peer) = self.peers.get_mut(&peer).filter(|rib_peer| rib_peer.up) {
+                    rib_peer.up = false;
+                    rib_peer.adj_rib_out.clear();
+                    self.publish(RouterEvent::PeerDown { peer, notification: None });
+                }
+                for family in afi_safis {
+                    match family {
+                        (Ipv4Addr::AFI, Ipv4Addr::SAFI) => _ = self.v4.table.mark_stale(peer),
+                        (Ipv6Addr::AFI, Ipv6Addr::SAFI) => _ = self.v6.table.mark_stale(peer),
+                        // Nothing was kept for it
+                        _ => ()
+                    }
+                }
+            },
+            TableCommand::FlushStale(peer, afi, safi) => match (afi, safi) {
+                (Ipv4Addr::AFI, Ipv4Addr::SAFI) => {
+                    let (removed, adv) = self.v4.table.flush_stale(peer);
+                    self.distribute(removed, adv);
+                },
+                (Ipv6Addr::AFI, Ipv6Addr::SAFI) => {
+                    let (removed, adv) = self.v6.table.flush_stale(peer);
+                    self.distribute(removed, adv);
+                },
+                _ => ()
+            },
+            TableCommand::ReplayAdjRibIn(peer) => self.replay(peer),
+            TableCommand::ResendAdjRibOut(peer) => self.send_table(peer)
+        }
+    }
+    fn routes(&mut self, payload: ReceivedRoutes) {
+        let peer = payload.peer_addr();
+        let received = payload.routes().map_or(0, |routes| routes.len());
+        let draining = self.draining(peer);
+        // Each RIB takes one AFI/SAFI, routes for one we keep no RIB for, or that isn't active with the
+        // peer, are counted as rejected. Injected routes don't come from a peer and aren't checked.
+        let family = payload.family();
+        if let Some(rib_peer) = self.peers.get(&peer).filter(|rib_peer| !rib_peer.families.contains(&family)) {
+            warn!(%peer, afi = ?family.0, safi = ?family.1, "address family not active with the peer, routes dropped");
+            _ = rib_peer.handle.imported(0, received);
+            return;
+        }
+        let payload = self.loop_check(payload);
+        let (accepted, limit) = match family {
+            (Ipv4Addr::AFI, Ipv4Addr::SAFI) => {
+                let import = self.v4.import(payload, &self.policy, &mut self.limiter, draining);
+                self.distribute(import.removed, import.adv);
+                (import.accepted, import.limit)
+            },
+            (Ipv6Addr::AFI, Ipv6Addr::SAFI) => {
+                let import = self.v6.import(payload, &self.policy, &mut self.limiter, draining);
+                self.distribute(import.removed, import.adv);
+                (import.accepted, import.limit)
+            },
+            (afi, safi) => {
+                warn!(%peer, ?afi, ?safi, "no RIB for the address family, routes dropped");
+                (0, None)
+            }
+        };
+        if let Some(rib_peer) = self.peers.get(&peer) {
+            _ = rib_peer.handle.imported(accepted, received.saturating_sub(accepted));
+        }
+        if let Some(limit) = limit {
+            self.prefix_limit(peer, limit);
+        }
+    }
+    fn loop_check(&self, payload: ReceivedRoutes) -> ReceivedRoutes {
+        // Paths with our AS in the AS_PATH, or as a reflector paths we originated or reflected before, are
+        // withdrawn instead. RFC 4271, Pg. 76 and RFC 4456, Pg. 6
+        let payload = self.as_loop.check(payload);
+        match &self.reflector {
+            Some(reflector) if reflector.is_looped(payload.path_attrs_ref(), self.router_id) => treat_as_withdraw(&payload),
+            _ => payload
+        }
+    }
+    fn replay(&mut self, peer: IpAddr) {
+        // Runs what the peer sent through import policy again
+        let draining = self.draining(peer);
+        let (removed, adv) = self.v4.replay(peer, &self.policy, draining);
+        self.distribute(removed, adv);
+        let (removed, adv) = self.v6.replay(peer, &self.policy, draining);
+        self.distribute(removed, adv);
+    }
+    fn draining(&self, peer: IpAddr) -> bool {
+        self.peers.get(&peer).is_some_and(|rib_peer| rib_peer.export.graceful_shutdown())
+    }
+    fn prefix_limit(&self, peer: IpAddr, event: MaxPrefixEvent) {
+        let count = self.limiter.count(peer);
+        let limit = self.limiter.config(peer).map_or(0, |config| config.limit());
+        match event {
+            MaxPrefixEvent::Warning { count, limit } => warn!(%peer, count, limit, "approaching maximum prefixes"),
+            MaxPrefixEvent::Exceeded { count, limit } => {
+                warn!(%peer, count, limit, "maximum prefixes exceeded");
+                self.publish(RouterEvent::PrefixLimitExceeded { peer, count, limit });
+            },
+            MaxPrefixEvent::Teardown { restart_at, .. } => {
+                warn!(%peer, count, limit, "maximum prefixes exceeded, closing the session");
+                self.publish(RouterEvent::PrefixLimitExceeded { peer, count, limit });
+                let handle = match self.peers.get(&peer) {
+                    Some(rib_peer) => rib_peer.handle.clone(),
+                    None => return
+                };
+                _ = handle.cease(CeaseSubcode::MaxPrefixesReached);
+                // Without a restart timer the peer stays down until cleared
+                if let Some(restart_at) = restart_at {
+                    let clock = Arc::clone(&self.clock);
+                    tokio::spawn(async move {
+                        clock.sleep(restart_at.saturating_duration_since(clock.now())).await;
+                        _ = handle.start();
+                    });
+                }
+            }
+        }
+    }
+    fn publish(&self, event: RouterEvent) {
+        // Nobody subscribed is fine
+        _ = self.router_events.send(event);
+    }
+    fn send_table(&mut self, peer: IpAddr) {
+        // Sends the peer everything it should have, whether or not it already has it
+        let nlri = match self.peers.get(&peer) {
+            Some(rib_peer) if rib_peer.up => self.export_table(peer, rib_peer),
+            _ => return
+        };
+        if let Some(rib_peer) = self.peers.get_mut(&peer) {
+            rib_peer.adj_rib_out.replace(nlri);
+        }
+        self.flush(peer);
+    }
+    fn export_table(&self, peer: IpAddr, rib_peer: &RibPeer) -> Vec<Nlri> {
+        // Everything the peer should have, after the export rules and its export policy
+        let mut nlri = Vec::new();
+        if rib_peer.exchanges::<Ipv4Addr>() {
+            nlri.extend(self.v4.table.export_bestpaths(&rib_peer.export));
+        }
+        if rib_peer.exchanges::<Ipv6Addr>() {
+            nlri.extend(self.v6.table.export_bestpaths(&rib_peer.export));
+        }
+        self.policy.apply_export(peer, Vec::new(), nlri).1
+    }
+    fn distribute<A: RibFamily>(&mut self, removed: Vec<Route>, adv: AdvertisedRoutes<A>) {
+        // Queues the bestpath changes of a walk in the Adj-RIB-Out of every Established peer, then sends
+        // what the MRAI lets through
+        if removed.is_empty() && adv.is_empty() {
+            return;
+        }
+        let table = &A::family(self).table;
+        if self.router_events.receiver_count() > 0 {
+            self.publish(RouterEvent::BestPathChanged { afi: A::AFI, changed: adv.prefixes(), withdrawn: removed.clone() });
+            self.publish(RouterEvent::TableVersionBumped { afi: A::AFI, version: table.version() });
+        }
+        let tags = table.rpki_tags();
+        let exported: Vec<(IpAddr, Vec<Route>, Vec<Nlri>)> = self
+            .peers
+            .iter()
+            .filter(|(_, rib_peer)| rib_peer.up && rib_peer.exchanges::<A>())
+            .map(|(peer, rib_peer)| {
+                let (mut withdrawn, nlri) = adv.export(&rib_peer.export, tags);
+                withdrawn.extend_from_slice(&removed);
+                let (withdrawn, nlri) = self.policy.apply_export(*peer, withdrawn, nlri);
+                (*peer, withdrawn, nlri)
+            })
+            .collect();
+        for (peer, withdrawn, nlri) in exported {
+            if let Some(rib_peer) = self.peers.get_mut(&peer) {
+                rib_peer.adj_rib_out.queue(withdrawn, nlri);
+            }
+            self.flush(peer);
+        }
+        self.aggregate();
+    }
+    fn aggregate(&mut self) {
+        // Originates the aggregates that have a contributing route, a more specific one in the table, and
+        // withdraws the ones whose last contributing route is gone. Either distributes in turn, by then
+        // nothing is left to change.
+        let mut originated: Vec<Route> = Vec::new();
+        let mut withdrawn: Vec<Route> = Vec::new();
+        for (aggregate, active) in self.aggregates.iter_mut() {
+            let contributing = self.v4.table
+                .covered_routes(aggregate)
+                .into_iter()
+                .chain(self.v6.table.covered_routes(aggregate))
+                .any(|route| route.prefix_len() > aggregate.prefix_len());
+            match (contributing, *active) {
+                (true, false) => originated.push(aggregate.clone()),
+                (false, true) => withdrawn.push(aggregate.clone()),
+                _ => continue
+            }
+            *active = contributing;
+        }
+        if !originated.is_empty() {
+            self.request(RibRequest::Originate(LocalRoutes::new(originated)));
+        }
+        if !withdrawn.is_empty() {
+            self.request(RibRequest::WithdrawOriginated(withdrawn));
+        }
+    }
+    fn flush(&mut self, peer: IpAddr) {
+        // Sends what the peer's Adj-RIB-Out has queued. Withdrawals go right away, advertisements once the
+        // MRAI has expired. RFC 4271, Pg. 84
+        let now = self.clock.now();
+        let rib_peer = match self.peers.get_mut(&peer) {
+            Some(rib_peer) if rib_peer.up => rib_peer,
+            _ => return
+        };
+        let (withdrawn, nlri) = rib_peer.adj_rib_out.flush(now);
+        if withdrawn.is_empty() && nlri.is_empty() {
+            return;
+        }
+        // The peer's task may be on its way out, its Down event follows
+        _ = rib_peer.handle.advertise(build_updates(withdrawn, nlri));
+    }
+    fn flush_all(&mut self) {
+        let peers: Vec<IpAddr> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.flush(peer);
+        }
+    }
+    fn next_flush(&self) -> Option<Duration> {
+        // Time until the first MRAI with advertisements waiting on it expires
+        let now = self.clock.now();
+        self.peers
+            .values()
+            .filter(|rib_peer| rib_peer.up)
+            .filter_map(|rib_peer| rib_peer.adj_rib_out.next_flush())
+            .min()
+            .map(|at| at.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{future::{self, Future}, io, pin::Pin, sync::Mutex};
+    use tokio::io::AsyncWriteExt;
+    use crate::{
+        comms::{MockReceivedRoutesBuilder, RouteInjectionBuilder},
+        export::ExportOptions,
+        fib::MemoryFib,
+        fsm_ds::{Event, PeerSessionBuilder, SessionParams},
+        message_types::{Open, Update, UpdateBuilder},
+        rpki::{Roa, RpkiPolicy, RpkiState},
+        rtr::RtrPdu,
+        path_attrs::*,
+        peer::{Connection, Inbound, Outbound},
+        redistribute::{KernelRouteEvent, RedistributeAttrs, Redistribution, RouteKind},
+        table::{PathView, RouteSource},
+    };
+
+    // The test plays the peer on the other end of the channels
+    struct MockConn {
+        tx: UnboundedSender<Outbound>,
+        rx: UnboundedReceiver<Inbound>
+    }
+
+    impl Connection for MockConn {
+        fn send(&mut self, msg: Outbound) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+            let result = self.tx.send(msg).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+            Box::pin(future::ready(result))
+        }
+        fn recv(&mut self) -> Pin<Box<dyn Future<Output = Option<Inbound>> + Send + '_>> {
+            Box::pin(self.rx.recv())
+        }
+    }
+
+    struct MockConnector(Mutex<Option<MockConn>>);
+
+    impl Connector for MockConnector {
+        type Conn = MockConn;
+        fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<MockConn>> + Send>> {
+            let conn = self.0.lock().unwrap().take().ok_or(io::Error::from(io::ErrorKind::ConnectionRefused));
+            Box::pin(future::ready(conn))
+        }
+    }
+
+    // Brings a session with the peer up, returns what we send it and the way to send it things
+    async fn peer_up(speaker: &mut Speaker, addr: IpAddr, remote_as: u16) -> (UnboundedReceiver<Outbound>, UnboundedSender<Inbound>) {
+        let local = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254));
+        let peer = BgpPeer::new(addr, remote_as, local, PeerSessionBuilder::new().build());
+        let open = OpenBuilder::new(4, remote_as, 90, u32::from(Ipv4Addr::new(10, 0, 0, remote_as as u8))).build();
+        session_up(speaker, peer, open).await.0
+    }
+
+    // Same, with the peer's Open given, also returns the Open we sent
+    async fn session_up(speaker: &mut Speaker, peer: BgpPeer, open: Open) -> ((UnboundedReceiver<Outbound>, UnboundedSender<Inbound>), Open) {
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+        let (in_tx, in_rx) = mpsc::unbounded_channel();
+        let connector = MockConnector(Mutex::new(Some(MockConn { tx: out_tx, rx: in_rx })));
+        speaker.add_peer(peer, connector, None).unwrap();
+        let sent = match out_rx.recv().await {
+            Some(Outbound::Open(sent)) => sent,
+            other => panic!("expected an Open, got {:?}", other)
+        };
+        in_tx.send(Inbound::Event(Event::BGPOpen(open))).unwrap();
+        assert_eq!(out_rx.recv().await, Some(Outbound::Keepalive));
+        in_tx.send(Inbound::Event(Event::KeepAliveMsg)).unwrap();
+        ((out_rx, in_tx), sent)
+    }
+
+    async fn next_update(out_rx: &mut UnboundedReceiver<Outbound>) -> Update {
+        loop {
+            match out_rx.recv().await {
+                Some(Outbound::Update(update)) => return update,
+                Some(_) => continue,
+                None => panic!("expected an Update")
+            }
+        }
+    }
+
+    fn pas(asn: u16) -> Vec<PathAttr> {
+        vec![
+            PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap(),
+            PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(vec![asn])]).build().unwrap(),
+            PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(Ipv4Addr::new(192, 0, 2, asn as u8))).build().unwrap(),
+        ]
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_routes_between_peers() {
+        // The BGP Identifier goes into every Open, a speaker can't have an invalid one
+        let multicast = Ipv4Addr::new(224, 0, 0, 5);
+        assert_eq!(Speaker::new(multicast, 65000).err(), Some(RouterIdError::Invalid(multicast)));
+        assert_eq!(Speaker::from_selector(&RouterIdSelector::new(), 65000).err(), Some(RouterIdError::NoCandidates));
+        let selector = RouterIdSelector::new().loopbacks(vec![Ipv4Addr::LOCALHOST, Ipv4Addr::new(10, 0, 0, 1)]);
+        let mut speaker = Speaker::from_selector(&selector, 65000).unwrap();
+        assert_eq!(speaker.router_id(), Ipv4Addr::new(10, 0, 0, 1));
+        let peer_a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let peer_b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let (mut b_out, _b_in) = peer_up(&mut speaker, peer_b, 65002).await;
+        assert_eq!(speaker.add_peer(
+            BgpPeer::new(peer_a, 65001, peer_a, PeerSessionBuilder::new().build()),
+            MockConnector(Mutex::new(None)),
+            None
+        ).err(), Some(SpeakerError::PeerExists(peer_a)));
+
+        // A's route goes to B, its multicast route has no RIB to go to
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let multicast = MockReceivedRoutesBuilder::new(Some(vec![Route::new(24, IpAddr::V4(Ipv4Addr::new(233, 252, 0, 0)))]), None, pas(65001))
+            .peer_addr(peer_a)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+            .safi(Safi::Multicast)
+            .build();
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(65001))
+            .peer_addr(peer_a)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+            .build();
+        a_in.send(Inbound::Update(vec![multicast, payload])).unwrap();
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.nlri(), Some(&[route.clone()][..]));
+        let stats = speaker.peer(peer_a).unwrap().stats().await.unwrap();
+        assert_eq!((stats.prefixes_accepted(), stats.prefixes_rejected()), (1, 1));
+
+        // Originated routes go everywhere
+        let local = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        speaker.originate(LocalRoutes::new(vec![local.clone()])).unwrap();
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.nlri(), Some(&[local.clone()][..]));
+        assert_eq!(speaker.routes(Afi::Ipv4).await.unwrap().len(), 2);
+        let tables = speaker.tables();
+        assert_eq!(tokio::spawn(async move { tables.num_destinations(Afi::Ipv4).await }).await.unwrap(), Ok(2));
+        assert!(speaker.routes(Afi::Ipv6).await.unwrap().is_empty());
+        let view = speaker.route(route.clone()).await.unwrap().unwrap();
+        assert_eq!(view.bestpath().map(PathView::peer_addr), Some(peer_a));
+        let from_a = speaker.routes_from_peer(peer_a).await.unwrap();
+        assert_eq!(from_a.iter().map(RouteView::route).collect::<Vec<_>>(), vec![&route]);
+        let found = speaker.looking_glass(LgQuery::Peer(peer_a), 10).await.unwrap();
+        assert_eq!(found.iter().map(LgRoute::prefix).collect::<Vec<_>>(), vec!["203.0.113.0/24"]);
+        assert_eq!(speaker.looking_glass(LgQuery::OriginAs(65001), 10).await.unwrap().len(), 1);
+        let snapshot = speaker.snapshot(Afi::Ipv4).await.unwrap();
+        assert_eq!(snapshot.iter().map(SnapshotRow::prefix).collect::<Vec<_>>(), vec!["198.51.100.0/24", "203.0.113.0/24"]);
+        assert_eq!(snapshot[1].peer(), peer_a);
+        let csv = speaker.export_table(Afi::Ipv4, SnapshotFormat::Csv).await.unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        let stats = speaker.memory_stats(Afi::Ipv4).await.unwrap();
+        assert!(stats.total_bytes() > 0 && stats.dedup_ratio() == 1.0);
+        let delta = speaker.changes_since(Afi::Ipv4, 1).await.unwrap();
+        assert_eq!((delta.version(), delta.updated().len()), (2, 1));
+        assert_eq!(delta.updated()[0].routes(), std::slice::from_ref(&local));
+        let mut advertised: Vec<Route> = speaker
+            .advertised_routes(peer_b)
+            .await
+            .unwrap()
+            .iter()
+            .flat_map(|nlri| nlri.routes().to_vec())
+            .collect();
+        advertised.sort();
+        assert_eq!(advertised, vec![local.clone(), route.clone()]);
+        let unknown = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 9));
+        assert_eq!(speaker.advertised_routes(unknown).await.err(), Some(SpeakerError::UnknownPeer(unknown)));
+
+        // Handles see the peers come and go
+        let handle = speaker.handle();
+        assert_eq!((handle.remote_as(peer_a), handle.peers().len()), (Some(65001), 2));
+
+        // A goes away, so does its route. Its counters are handed back.
+        let stats = speaker.remove_peer(peer_a).await.unwrap();
+        assert!(handle.peer(peer_a).is_none());
+        assert_eq!(stats.prefixes_accepted(), 1);
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.withdrawn_routes(), Some(&[route][..]));
+        assert_eq!(speaker.remove_peer(peer_a).await.err(), Some(SpeakerError::UnknownPeer(peer_a)));
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_route_server() {
+        // B is a route server client, A's path reaches it untouched
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let local = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254));
+        let (peer_a, peer_b) = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let b = BgpPeer::new(peer_b, 65002, local, PeerSessionBuilder::new().build())
+            .export_options(ExportOptions { route_server_client: true, ..Default::default() });
+        let open = OpenBuilder::new(4, 65002, 90, u32::from(Ipv4Addr::new(10, 0, 0, 2))).build();
+        let ((mut b_out, _b_in), _) = session_up(&mut speaker, b, open).await;
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let mut attrs = pas(65001);
+        attrs.push(PathAttrBuilder::<Med>::new().metric(20).build().unwrap());
+        let next_hop = attrs.iter().find_map(|pa| pa.next_hop());
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, attrs).peer_addr(peer_a).build();
+        a_in.send(Inbound::Update(vec![payload])).unwrap();
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.nlri(), Some(&[route][..]));
+        let sent = update.path_attrs().unwrap();
+        assert_eq!(sent.iter().find_map(|pa| pa.as_path()), Some(vec![AsSegment::AsSequence(vec![65001])]));
+        assert_eq!(sent.iter().find_map(|pa| pa.next_hop()), next_hop);
+        assert_eq!(sent.iter().find_map(|pa| pa.med()), Some(20));
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_mrai() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let peer_a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let peer_b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let (mut b_out, _b_in) = peer_up(&mut speaker, peer_b, 65002).await;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let announce = |pas: Vec<PathAttr>| {
+            let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas)
+                .peer_addr(peer_a)
+                .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+                .build();
+            a_in.send(Inbound::Update(vec![payload])).unwrap();
+        };
+        announce(pas(65001));
+        assert_eq!(next_update(&mut b_out).await.nlri(), Some(&[route.clone()][..]));
+
+        // A new MED changes the bestpath, but isn't passed on to another AS, so B already has what it would be
+        // sent
+        let mut with_med = pas(65001);
+        with_med.push(PathAttrBuilder::<Med>::new().metric(50).build().unwrap());
+        announce(with_med);
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        while let Ok(msg) = b_out.try_recv() {
+            assert!(!matches!(msg, Outbound::Update(_)));
+        }
+
+        // The eBGP MRAI has expired, a longer AS_PATH goes out right away. Going back to the shorter one has to
+        // wait for the MRAI again.
+        let as_path = |pas: &[PathAttr]| pas.iter().find_map(|pa| pa.as_path());
+        let mut prepended = pas(65001);
+        prepended[1] = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001, 65010])])
+            .build()
+            .unwrap();
+        announce(prepended);
+        let update = next_update(&mut b_out).await;
+        let sent_at = tokio::time::Instant::now();
+        assert_eq!(as_path(update.path_attrs().unwrap()), Some(vec![AsSegment::AsSequence(vec![65000, 65001, 65010])]));
+        announce(pas(65001));
+        let update = next_update(&mut b_out).await;
+        assert!(tokio::time::Instant::now() - sent_at >= Duration::from_secs(30));
+        let expected = Some(vec![AsSegment::AsSequence(vec![65000, 65001])]);
+        assert_eq!(as_path(update.path_attrs().unwrap()), expected);
+
+        // What B was sent is what it's reported to have
+        let advertised = speaker.advertised_routes(peer_b).await.unwrap();
+        assert_eq!(advertised.len(), 1);
+        assert_eq!(advertised[0].routes(), &[route]);
+        assert_eq!(as_path(advertised[0].path_attrs()), expected);
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_address_families() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let local = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254));
+        let (peer_a, peer_b) = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+        let open = |remote_as: u16| OpenBuilder::new(4, remote_as, 90, u32::from(Ipv4Addr::new(10, 0, 0, remote_as as u8)))
+            .capability(Capability::Multiprotocol(Afi::Ipv4, Safi::Unicast))
+            .capability(Capability::Multiprotocol(Afi::Ipv6, Safi::Unicast))
+            .build();
+        let ((_a_out, a_in), _) = session_up(
+            &mut speaker,
+            BgpPeer::new(peer_a, 65001, local, PeerSessionBuilder::new().build()),
+            open(65001)
+        ).await;
+        // B has IPv6 unicast turned off, only IPv4 unicast is offered to it
+        let b = BgpPeer::new(peer_b, 65002, local, PeerSessionBuilder::new().build())
+            .deactivate(Afi::Ipv6, Safi::Unicast)
+            .activate(Afi::Ipv4, Safi::Unicast);
+        let ((mut b_out, b_in), sent) = session_up(&mut speaker, b, open(65002)).await;
+        assert_eq!(
+            sent.capabilities().into_iter().filter(|cap| matches!(cap, Capability::Multiprotocol(..))).collect::<Vec<_>>(),
+            vec![Capability::Multiprotocol(Afi::Ipv4, Safi::Unicast)]
+        );
+
+        // A's IPv6 route isn't sent to B, its IPv4 route is
+        let v6 = |last: u8| Route::new(48, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, last as u16, 0, 0, 0, 0, 0)));
+        let v4 = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let from_a = |routes: Vec<Route>, afi: Afi| MockReceivedRoutesBuilder::new(Some(routes), None, pas(65001))
+            .peer_addr(peer_a)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+            .afi(afi)
+            .build();
+        a_in.send(Inbound::Update(vec![from_a(vec![v6(1)], Afi::Ipv6), from_a(vec![v4.clone()], Afi::Ipv4)])).unwrap();
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.nlri(), Some(&[v4][..]));
+        assert!(update.mp_reach().is_none());
+
+        // Nor is B's IPv6 route taken
+        let from_b = MockReceivedRoutesBuilder::new(Some(vec![v6(2)]), None, pas(65002))
+            .peer_addr(peer_b)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 102))
+            .afi(Afi::Ipv6)
+            .build();
+        b_in.send(Inbound::Update(vec![from_b])).unwrap();
+        // Lets the peer's task and the RIB get to it
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let stats = speaker.peer(peer_b).unwrap().stats().await.unwrap();
+        assert_eq!((stats.prefixes_accepted(), stats.prefixes_rejected()), (0, 1));
+        let destinations = speaker.with_tables(|_, v6| v6.num_destinations()).await.unwrap();
+        assert_eq!(destinations, 1);
+        assert_eq!(speaker.advertised_routes(peer_b).await.unwrap().len(), 1);
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_as_loop() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let local = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254));
+        let (peer_a, peer_b) = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+        let open = |remote_as: u16| OpenBuilder::new(4, remote_as, 90, u32::from(Ipv4Addr::new(10, 0, 0, remote_as as u8))).build();
+        // A is a site of a customer whose other sites are behind us, so our AS is allowed once
+        let a = BgpPeer::new(peer_a, 65001, local, PeerSessionBuilder::new().build()).allowas_in(Some(1));
+        let ((_a_out, a_in), _) = session_up(&mut speaker, a, open(65001)).await;
+        let (mut b_out, b_in) = peer_up(&mut speaker, peer_b, 65002).await;
+        let through_us = |peer: IpAddr, ases: Vec<u16>, route: &Route| {
+            let mut attrs = pas(ases[0]);
+            attrs.retain(|pa| pa.as_path().is_none());
+            attrs.push(PathAttrBuilder::<AsPath>::new().as_segments(vec![AsSegment::AsSequence(ases)]).build().unwrap());
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, attrs)
+                .peer_addr(peer)
+                .peer_id(Ipv4Addr::new(10, 0, 0, 100))
+                .build()
+        };
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        a_in.send(Inbound::Update(vec![through_us(peer_a, vec![65001, 65000, 65003], &route)])).unwrap();
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.nlri(), Some(&[route.clone()][..]));
+
+        // B gets no exception, and twice is too many for A. A's path is gone rather than kept from before.
+        let other = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        b_in.send(Inbound::Update(vec![through_us(peer_b, vec![65002, 65000], &other)])).unwrap();
+        a_in.send(Inbound::Update(vec![through_us(peer_a, vec![65001, 65000, 65000], &route)])).unwrap();
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.withdrawn_routes(), Some(&[route][..]));
+        let stats = speaker.peer(peer_b).unwrap().stats().await.unwrap();
+        assert_eq!((stats.prefixes_accepted(), stats.prefixes_rejected()), (0, 1));
+        let destinations = speaker.with_tables(|v4, _| v4.num_destinations()).await.unwrap();
+        assert_eq!(destinations, 0);
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_invalid_next_hop() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let mut events = speaker.subscribe();
+        let peer_a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let subnet = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let peer = BgpPeer::new(peer_a, 65001, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254)), PeerSessionBuilder::new().build())
+            .shared_subnet(Some(subnet));
+        let open = OpenBuilder::new(4, 65001, 90, u32::from(Ipv4Addr::new(10, 0, 0, 101))).build();
+        let ((_a_out, a_in), _) = session_up(&mut speaker, peer, open).await;
+        let announce = |route: &Route, next_hop: Ipv4Addr| {
+            let mut pas = pas(65001);
+            pas[2] = PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(next_hop)).build().unwrap();
+            let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas)
+                .peer_addr(peer_a)
+                .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+                .build();
+            a_in.send(Inbound::Update(vec![payload])).unwrap();
+        };
+
+        // A NEXT_HOP on the shared subnet is taken
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        announce(&route, Ipv4Addr::new(192, 0, 2, 7));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(speaker.route(route).await.unwrap().is_some());
+
+        // One off it is an UPDATE Message Error, Invalid NEXT_HOP Attribute, and the session goes down
+        let off_subnet = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        announce(&off_subnet, Ipv4Addr::new(198, 51, 100, 1));
+        let notification = loop {
+            match events.recv().await.unwrap() {
+                RouterEvent::PeerDown { notification, .. } => break notification.unwrap(),
+                _ => continue
+            }
+        };
+        assert_eq!((notification.code, notification.subcode, notification.sent), (3, 8, true));
+        assert!(speaker.route(off_subnet).await.unwrap().is_none());
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_rpki() {
+        let config = DecisionConfig::new().rpki_policy(RpkiPolicy::PreferValid);
+        let mut speaker = Speaker::with_decision_config(Ipv4Addr::new(10, 0, 0, 1), 65000, config).unwrap();
+        let peer_a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let peer_b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let (_b_out, b_in) = peer_up(&mut speaker, peer_b, 65002).await;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        for (peer, asn, input) in [(peer_a, 65001, &a_in), (peer_b, 65002, &b_in)] {
+            let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(asn))
+                .peer_addr(peer)
+                .peer_id(Ipv4Addr::new(10, 0, 0, asn as u8))
+                .build();
+            input.send(Inbound::Update(vec![payload])).unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let best = |view: Option<RouteView>| {
+            view.and_then(|view| view.bestpath().map(|path| (path.peer_addr(), path.rpki_state())))
+        };
+        assert_eq!(best(speaker.route(route.clone()).await.unwrap()), Some((peer_a, RpkiState::NotFound)));
+
+        // Only B's AS may originate the route, its path is Valid and A's is Invalid
+        let roa = Roa::new(Route::new(16, IpAddr::V4(Ipv4Addr::new(203, 0, 0, 0))), 24, 65002);
+        speaker.load_roas(RoaTable::load(vec![roa])).unwrap();
+        assert_eq!(best(speaker.route(route.clone()).await.unwrap()), Some((peer_b, RpkiState::Valid)));
+        let view = speaker.route(route).await.unwrap().unwrap();
+        assert_eq!(view.paths()[1].rpki_state(), RpkiState::Invalid);
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_fib() {
+        let config = DecisionConfig::new().max_paths(2);
+        let mut speaker = Speaker::with_decision_config(Ipv4Addr::new(10, 0, 0, 1), 65000, config).unwrap();
+        let (peer_a, peer_b) = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let (_b_out, b_in) = peer_up(&mut speaker, peer_b, 65002).await;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let payload = |peer: IpAddr, asn: u16, announce: bool| {
+            let routes = Some(vec![route.clone()]);
+            let (routes, withdrawn) = match announce {
+                true => (routes, None),
+                false => (None, routes)
+            };
+            MockReceivedRoutesBuilder::new(routes, withdrawn, pas(asn))
+                .peer_addr(peer)
+                .peer_id(Ipv4Addr::new(10, 0, 0, asn as u8))
+                .build()
+        };
+        let next_hop = |asn: u16| pas(asn).iter().find_map(|pa| pa.next_hop()).unwrap();
+
+        // What's already in the table is installed as soon as the Fib is attached
+        a_in.send(Inbound::Update(vec![payload(peer_a, 65001, true)])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let fib = MemoryFib::new();
+        speaker.attach_fib(Afi::Ipv4, fib.clone()).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(fib.next_hops(&route), Some(vec![next_hop(65001)]));
+
+        // B's path ties with A's, the route is installed over both
+        b_in.send(Inbound::Update(vec![payload(peer_b, 65002, true)])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(fib.next_hops(&route), Some(vec![next_hop(65001), next_hop(65002)]));
+        a_in.send(Inbound::Update(vec![payload(peer_a, 65001, false)])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(fib.next_hops(&route), Some(vec![next_hop(65002)]));
+        b_in.send(Inbound::Update(vec![payload(peer_b, 65002, false)])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(fib.is_empty());
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn speaker_rtr() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let peer_a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(65001)).peer_addr(peer_a).build();
+        a_in.send(Inbound::Update(vec![payload])).unwrap();
+
+        // The cache only lets another AS originate the route
+        let listener = tokio::net::TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let cache = listener.local_addr().unwrap();
+        let cache_task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(rtr::recv_pdu(&mut stream).await, Ok(RtrPdu::ResetQuery));
+            let roa = Roa::new(Route::new(16, IpAddr::V4(Ipv4Addr::new(203, 0, 0, 0))), 24, 65002);
+            let pdus = [
+                RtrPdu::CacheResponse { session_id: 7 },
+                RtrPdu::Prefix { announce: true, roa },
+                RtrPdu::EndOfData { session_id: 7, serial: 1, refresh: 3600, retry: 600, expire: 7200 },
+            ];
+            for pdu in pdus {
+                stream.write_all(&pdu.encode()).await.unwrap();
+            }
+            // Stays connected until the test is done
+            _ = rtr::recv_pdu(&mut stream).await;
+        });
+        let rtr = speaker.connect_rtr(cache);
+        let mut state = None;
+        for _ in 0..50 {
+            state = speaker.route(route.clone()).await.unwrap().and_then(|view| view.bestpath().map(PathView::rpki_state));
+            if state == Some(RpkiState::Invalid) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(state, Some(RpkiState::Invalid));
+        rtr.abort();
+        _ = cache_task.await;
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_reflector_loop() {
+        let router_id = Ipv4Addr::new(10, 0, 0, 1);
+        let mut speaker = Speaker::new(router_id, 65000).unwrap();
+        let cluster_id = Ipv4Addr::new(10, 255, 0, 1);
+        speaker.set_route_reflector(Some(RouteReflector::new(cluster_id))).unwrap();
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let (_out, peer_in) = peer_up(&mut speaker, peer, 65000).await;
+        let from_peer = |last: u8, extra: Option<PathAttr>| {
+            let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, last)));
+            MockReceivedRoutesBuilder::new(Some(vec![route]), None, pas(65010).into_iter().chain(extra).collect())
+                .peer_addr(peer)
+                .peer_id(Ipv4Addr::new(10, 0, 0, 232))
+                .build()
+        };
+
+        // Paths we originated, or that went through our cluster already, don't come back in
+        let ours = PathAttrBuilder::<OriginatorId>::new().originator_id(router_id).build().unwrap();
+        let cluster = PathAttrBuilder::<ClusterList>::new()
+            .cluster_ids(vec![Ipv4Addr::new(10, 255, 0, 2), cluster_id])
+            .build()
+            .unwrap();
+        peer_in.send(Inbound::Update(vec![from_peer(0, None), from_peer(1, Some(ours)), from_peer(2, Some(cluster))])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let stats = speaker.peer(peer).unwrap().stats().await.unwrap();
+        assert_eq!((stats.prefixes_accepted(), stats.prefixes_rejected()), (1, 2));
+        let destinations = speaker.with_tables(|v4, _| v4.num_destinations()).await.unwrap();
+        assert_eq!(destinations, 1);
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_duplicates() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        speaker.enable_duplicate_detection(Duration::from_secs(60), true).unwrap();
+        let peer_a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let peer_b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let (mut b_out, _b_in) = peer_up(&mut speaker, peer_b, 65002).await;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let payload = |route: &Route| MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(65001))
+            .peer_addr(peer_a)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+            .build();
+
+        // The repeat is counted and not passed on, B's next Update is for the other route
+        let other = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        a_in.send(Inbound::Update(vec![payload(&route)])).unwrap();
+        a_in.send(Inbound::Update(vec![payload(&route)])).unwrap();
+        a_in.send(Inbound::Update(vec![payload(&other)])).unwrap();
+        assert_eq!(next_update(&mut b_out).await.nlri(), Some(&[route][..]));
+        assert_eq!(next_update(&mut b_out).await.nlri(), Some(&[other][..]));
+        assert_eq!(speaker.duplicates(peer_a).await.unwrap(), 1);
+        assert_eq!(speaker.duplicates(peer_b).await.unwrap(), 0);
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn speaker_tcp_peer() {
+        // The test dials in as a passive peer and sends it a route
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let addr = speaker.listen(SocketAddr::new(localhost, 0)).await.unwrap();
+        let group = PeerGroup::new("ixp").session(PeerSessionBuilder::new().passive());
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            speaker.add_group_peer(&group, localhost, None, unspecified).err(),
+            Some(SpeakerError::MissingRemoteAs(localhost))
+        );
+        speaker.add_group_peer(&group, localhost, Some(65001), unspecified).unwrap();
+        assert_eq!(speaker.group(localhost), Some("ixp"));
+        let connector = TcpConnector::new(localhost, BgpCodec::new(localhost, 65001)).port(addr.port());
+        let mut conn = connector.connect().await.unwrap();
+
+        let open = OpenBuilder::new(4, 65001, 90, u32::from(Ipv4Addr::new(10, 0, 0, 2))).build();
+        conn.send(Outbound::Open(open.clone())).await.unwrap();
+        let sent = match conn.recv().await {
+            Some(Inbound::Event(Event::BGPOpen(sent))) => sent,
+            _ => panic!("expected an Open")
+        };
+        assert_eq!(sent.bgp_id(), u32::from(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(matches!(conn.recv().await, Some(Inbound::Event(Event::KeepAliveMsg))));
+        conn.send(Outbound::Keepalive).await.unwrap();
+        conn.negotiated(&SessionParams::negotiate(&open, &sent));
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let update = UpdateBuilder::new().nlri(Nlri::new(std::slice::from_ref(&route), &pas(65001))).build().unwrap();
+        conn.send(Outbound::Update(update)).await.unwrap();
+        let mut paths = 0;
+        for _ in 0..50 {
+            let route = route.clone();
+            paths = speaker.with_tables(move |v4, _| v4.bestpaths(&route).len()).await.unwrap();
+            if paths == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(paths, 1);
+        let again = BgpPeer::new(localhost, 65001, localhost, PeerSessionBuilder::new().build());
+        assert_eq!(speaker.add_tcp_peer(again).err(), Some(SpeakerError::PeerExists(localhost)));
+        speaker.remove_peer(localhost).await.unwrap();
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_inject() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let (mut out, _peer_in) = peer_up(&mut speaker, peer, 65001).await;
+        let source = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100));
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let injection = RouteInjectionBuilder::new(source, Ipv4Addr::new(10, 0, 0, 100), 65100)
+            .announce(vec![route.clone()], pas(65100))
+            .build()
+            .unwrap();
+        speaker.inject(injection.clone()).unwrap();
+        let update = next_update(&mut out).await;
+        assert_eq!(update.nlri(), Some(&[route.clone()][..]));
+        let paths = speaker
+            .with_tables(move |v4: &mut BgpTable<Ipv4Addr>, _: &mut BgpTable<Ipv6Addr>| v4.bestpaths(&route).len())
+            .await
+            .unwrap();
+        assert_eq!(paths, 1);
+
+        // Sessions can't be impersonated
+        let configured = RouteInjectionBuilder::new(peer, Ipv4Addr::new(10, 0, 0, 233), 65001)
+            .withdraw(injection.routes().to_vec())
+            .build()
+            .unwrap();
+        assert_eq!(speaker.inject(configured), Err(SpeakerError::PeerExists(peer)));
+
+        speaker.clear_injected(source).unwrap();
+        let update = next_update(&mut out).await;
+        assert_eq!(update.withdrawn_routes(), Some(injection.routes()));
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_redistribute() {
+        let speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let mut redistribution = Redistribution::new().kind(RouteKind::Static, RedistributeAttrs::new().med(10));
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        let connected = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+        let events = [
+            KernelRouteEvent::Added(route.clone(), RouteKind::Static),
+            KernelRouteEvent::Added(connected, RouteKind::Connected)
+        ];
+        for change in events.into_iter().filter_map(|event| redistribution.update(event)) {
+            speaker.redistribute(change).unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let routes = speaker.routes(Afi::Ipv4).await.unwrap();
+        assert_eq!(routes.iter().map(RouteView::route).collect::<Vec<_>>(), vec![&route]);
+        let best = routes[0].bestpath().unwrap();
+        assert_eq!((best.route_source(), best.med()), (&RouteSource::Local, 10));
+
+        let change = redistribution.update(KernelRouteEvent::Removed(route, RouteKind::Static)).unwrap();
+        speaker.redistribute(change).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(speaker.routes(Afi::Ipv4).await.unwrap().is_empty());
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_aggregates() {
+        let speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let aggregate = Route::new(22, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        let v6_aggregate = Route::new(32, "2001:db8::".parse().unwrap());
+        let contributing = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 101, 0)));
+        let outside = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        speaker.set_aggregates(vec![aggregate.clone(), v6_aggregate.clone()]).unwrap();
+        speaker.originate(LocalRoutes::new(vec![outside.clone()])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // Nothing contributes yet
+        assert!(speaker.route(aggregate.clone()).await.unwrap().is_none());
+
+        speaker.originate(LocalRoutes::new(vec![contributing.clone()])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let routes = speaker.routes(Afi::Ipv4).await.unwrap();
+        let routes: Vec<&Route> = routes.iter().map(RouteView::route).collect();
+        assert_eq!(routes.len(), 3);
+        assert!(routes.contains(&&aggregate));
+        assert!(speaker.routes(Afi::Ipv6).await.unwrap().is_empty());
+
+        speaker.withdraw_originated(vec![contributing.clone()]).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let routes = speaker.routes(Afi::Ipv4).await.unwrap();
+        assert_eq!(routes.iter().map(RouteView::route).collect::<Vec<_>>(), vec![&outside]);
+
+        // An aggregate that's no longer wanted is withdrawn even with contributing routes
+        speaker.originate(LocalRoutes::new(vec![contributing])).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(speaker.route(aggregate.clone()).await.unwrap().is_some());
+        speaker.set_aggregates(vec![v6_aggregate]).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(speaker.route(aggregate).await.unwrap().is_none());
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_router_events() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let mut events = speaker.subscribe();
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let (mut out, peer_in) = peer_up(&mut speaker, peer, 65001).await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            RouterEvent::PeerUp { peer, remote_as: 65001, router_id: Ipv4Addr::new(10, 0, 0, 233) }
+        );
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(65001))
+            .peer_addr(peer)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+            .build();
+        peer_in.send(Inbound::Update(vec![payload])).unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            RouterEvent::BestPathChanged { afi: Afi::Ipv4, changed: vec![route.clone()], withdrawn: Vec::new() }
+        );
+        assert_eq!(events.recv().await.unwrap(), RouterEvent::TableVersionBumped { afi: Afi::Ipv4, version: 1 });
+
+        // One more route than the limit allows takes the session down
+        speaker.set_max_prefix(peer, Some(MaxPrefixConfig::new(1))).unwrap();
+        let more = Route::new(24, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![more]), None, pas(65001))
+            .peer_addr(peer)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+            .build();
+        peer_in.send(Inbound::Update(vec![payload])).unwrap();
+        let mut limited = false;
+        let notification = loop {
+            match events.recv().await.unwrap() {
+                RouterEvent::PrefixLimitExceeded { count, limit, .. } => {
+                    assert_eq!((count, limit), (2, 1));
+                    limited = true;
+                },
+                RouterEvent::PeerDown { notification, .. } => break notification.unwrap(),
+                _ => continue
+            }
+        };
+        assert!(limited);
+        assert_eq!((notification.code, notification.subcode, notification.sent), (6, 1, true));
+        loop {
+            match out.recv().await {
+                Some(Outbound::Notification(_)) => break,
+                Some(_) => continue,
+                None => panic!("expected a NOTIFICATION")
+            }
+        }
+        speaker.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speaker_graceful_shutdown() {
+        let mut speaker = Speaker::new(Ipv4Addr::new(10, 0, 0, 1), 65000).unwrap();
+        let peer_a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let peer_b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let (_a_out, a_in) = peer_up(&mut speaker, peer_a, 65001).await;
+        let (mut b_out, _b_in) = peer_up(&mut speaker, peer_b, 65002).await;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+        let payload = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(65001))
+            .peer_addr(peer_a)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 101))
+            .build();
+        a_in.send(Inbound::Update(vec![payload])).unwrap();
+        let communities = |update: &Update| update.path_attrs().unwrap().iter().find_map(|pa| pa.communities());
+        assert_eq!(communities(&next_update(&mut b_out).await), None);
+
+        // B is sent everything again, tagged
+        speaker.start_maintenance(Some(peer_b)).unwrap();
+        let update = next_update(&mut b_out).await;
+        assert_eq!(update.nlri(), Some(&[route.clone()][..]));
+        assert_eq!(communities(&update), Some(vec![GRACEFUL_SHUTDOWN]));
+
+        // What A sent loses out once A is in maintenance too
+        let local_pref = |route: Route| {
+            move |v4: &mut BgpTable<Ipv4Addr>, _: &mut BgpTable<Ipv6Addr>| v4.route_view(&route).unwrap().paths()[0].local_pref()
+        };
+        assert_ne!(speaker.with_tables(local_pref(route.clone())).await.unwrap(), Some(0));
+        speaker.start_maintenance(None).unwrap();
+        assert_eq!(speaker.with_tables(local_pref(route.clone())).await.unwrap(), Some(0));
+
+        // Anything B was sent in the meantime is still tagged
+        speaker.stop_maintenance(None).unwrap();
+        let mut untagged = false;
+        for _ in 0..3 {
+            if communities(&next_update(&mut b_out).await).is_none() {
+                untagged = true;
+                break;
+            }
+        }
+        assert!(untagged);
+        speaker.shutdown().await;
+    }
+}
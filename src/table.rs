@@ -1,25 +1,118 @@
 // Holds logic for the BGP RIBs and Decision Process
 
 use std::{
-    cmp::{self, Reverse},
-    collections::{BinaryHeap, HashMap},
+    cmp,
+    fmt::Debug,
+    collections::{hash_map::DefaultHasher, HashMap},
     hash::{Hash, Hasher},
     marker::PhantomData,
+    mem,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    rc::Rc
+    sync::Arc,
+    time::{Duration, Instant},
 };
 // Using hashbrown due to entry API
 use hashbrown::HashSet;
+use serde::Serialize;
+use smallvec::SmallVec;
+use tracing::{debug, debug_span};
 
-use crate::{message_types::{Nlri, Update, Open, Route},
+use crate::{message_types::{Afi, Nlri, Update, Open, Route, Safi},
             path_attrs::*,
-            comms::ReceivedRoutes,
+            comms::{ReceivedRoutes, WithdrawnRoutes},
+            export::{ExportPeer, PathSource},
+            policy::SetAction,
+            rpki::{origin_as, RoaTable, RpkiPolicy, RpkiState, RpkiTags},
+            rtr::VrpUpdate,
+            timers::{Clock, TokioClock},
+            trie::PrefixTrie,
         };
 
 type PrefixLen = u8;
+// The destinations map, hashbrown's default hasher is much cheaper than SipHash on the small keys
+type RibMap<K, V> = hashbrown::HashMap<K, V>;
+
+// ** MRAI DEFAULTS **
+// RFC 4271 Pg. 90
+const MRAI_EBGP_SECS: u64 = 30;
+const MRAI_IBGP_SECS: u64 = 5;
+
+// Size the PA table has to reach before growth alone triggers a full sweep of it
+const PA_SWEEP_MIN: usize = 1024;
+
+// Implemented by the address types a BgpTable can be keyed on, so that the table logic
+// only needs to be written once for both address families.
+pub(crate) trait TableAfi: Copy + Eq + Hash {
+    const AFI: Afi;
+    // Only unicast routes are kept, other SAFIs would get a table of their own
+    const SAFI: Safi = Safi::Unicast;
+    const MAX_LEN: u8;
+    // A destination (prefix and length) as the table's maps are keyed on it, packed as tightly as the
+    // family allows since hashing and storing the keys adds up over a full table
+    type Key: Copy + Eq + Hash + Debug + Send + Sync;
+    // Pulls the prefix out of a route if it belongs to this address family
+    fn from_route(route: &Route) -> Option<Self>;
+    fn to_ip(self) -> IpAddr;
+    // Address left aligned in a u128, used as the trie key
+    fn to_bits(self) -> u128;
+    fn key(self, len: PrefixLen) -> Self::Key;
+    fn from_key(key: Self::Key) -> (Self, PrefixLen);
+    fn route_key(route: &Route) -> Option<Self::Key> {
+        Self::from_route(route).map(|prefix| prefix.key(route.prefix_len()))
+    }
+    fn key_route(key: Self::Key) -> Route {
+        let (prefix, len) = Self::from_key(key);
+        Route::new(len, prefix.to_ip())
+    }
+}
+
+impl TableAfi for Ipv4Addr {
+    const AFI: Afi = Afi::Ipv4;
+    const MAX_LEN: u8 = 32;
+    fn from_route(route: &Route) -> Option<Self> {
+        route.prefix_v4()
+    }
+    fn to_ip(self) -> IpAddr {
+        IpAddr::V4(self)
+    }
+    fn to_bits(self) -> u128 {
+        (u32::from(self) as u128) << 96
+    }
+    // Address in the upper bits, length in the lowest octet
+    type Key = u64;
+    fn key(self, len: PrefixLen) -> u64 {
+        (u32::from(self) as u64) << 8 | len as u64
+    }
+    fn from_key(key: u64) -> (Self, PrefixLen) {
+        (Ipv4Addr::from((key >> 8) as u32), key as u8)
+    }
+}
+
+impl TableAfi for Ipv6Addr {
+    const AFI: Afi = Afi::Ipv6;
+    const MAX_LEN: u8 = 128;
+    fn from_route(route: &Route) -> Option<Self> {
+        route.prefix_v6()
+    }
+    fn to_ip(self) -> IpAddr {
+        IpAddr::V6(self)
+    }
+    fn to_bits(self) -> u128 {
+        u128::from(self)
+    }
+    type Key = (Ipv6Addr, PrefixLen);
+    fn key(self, len: PrefixLen) -> Self::Key {
+        (self, len)
+    }
+    fn from_key(key: Self::Key) -> (Self, PrefixLen) {
+        key
+    }
+}
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
-pub(crate) enum RouteSource {
+pub enum RouteSource {
+    // Locally originated ("network" statements)
+    Local,
     Ebgp,
     Ibgp
 }
@@ -27,16 +120,60 @@ pub(crate) enum RouteSource {
 impl From<&RouteSource> for u8 {
     fn from(value: &RouteSource) -> Self {
         match value {
-            RouteSource::Ebgp => 0,
-            RouteSource::Ibgp => 1
+            RouteSource::Local => 0,
+            RouteSource::Ebgp => 1,
+            RouteSource::Ibgp => 2
         }
     }
 }
 
+// Weight given to locally originated paths, same as IOS
+const LOCAL_WEIGHT: u32 = 32768;
+
+// Locally originated paths are installed as if they came from this pseudo-peer
+const LOCAL_PEER_ID: Ipv4Addr = Ipv4Addr::UNSPECIFIED;
+const LOCAL_PEER_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+// Optional steps of the Decision Process. Fixed for the lifetime of a table so every path
+// in it is compared the same way.
+#[derive(Clone, Debug, Default)]
+pub struct DecisionConfig {
+    // Prefer the oldest of otherwise equal eBGP paths before comparing router IDs. RFC 5004
+    prefer_oldest: bool,
+    // Whether origin validation states take part in the Decision Process
+    rpki_policy: RpkiPolicy,
+    // Maximum number of equal cost paths a destination is installed with, 0 or 1 disables multipath
+    max_paths: usize
+}
+
+impl DecisionConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn prefer_oldest(mut self, prefer_oldest: bool) -> Self {
+        self.prefer_oldest = prefer_oldest;
+        self
+    }
+    pub fn rpki_policy(mut self, rpki_policy: RpkiPolicy) -> Self {
+        self.rpki_policy = rpki_policy;
+        self
+    }
+    pub fn max_paths(mut self, max_paths: usize) -> Self {
+        self.max_paths = max_paths;
+        self
+    }
+}
+
 // This data structure is used to simplify comparisons between many candidate paths
 // to a destination as opposed to destructuring the raw path attribute data for each comparison.
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+// The installation time isn't part of the path's identity, so equality and hashing ignore it.
+#[derive(Clone, Debug)]
 struct DecisionProcessData {
+    // Locally significant, compared before anything else. Higher wins.
+    weight: u32,
+    rpki_state: RpkiState,
+    // Only compare the validation state if the table's policy asks for it
+    prefer_valid: bool,
     local_pref: Option<u32>,
     as_path_len: u8,
     last_as: u16,
@@ -45,15 +182,32 @@ struct DecisionProcessData {
     route_souce: RouteSource,
     igp_cost: u64,
     peer_id: Ipv4Addr,
-    peer_addr: IpAddr
+    peer_addr: IpAddr,
+    // When the path was installed, only tracked if the table prefers the oldest path
+    installed: Option<Instant>
+}
+
+impl PartialEq for DecisionProcessData {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+impl Eq for DecisionProcessData {}
+impl Hash for DecisionProcessData {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state);
+    }
 }
 
 impl DecisionProcessData {
     // Naive approach here for now for testing, will most likely have
     // a custom type that the table thread picks up that does much of this
     // function's work. 
-    pub fn new(data: &ReceivedRoutes) -> Self {
+    pub fn new(data: &ReceivedRoutes, config: &DecisionConfig, now: Instant) -> Self {
         Self {
+            weight: data.weight(),
+            rpki_state: data.rpki_state(),
+            prefer_valid: config.rpki_policy != RpkiPolicy::Ignore,
             local_pref: data.local_pref(),
             as_path_len: data.as_path_len(),
             last_as: data.last_as(),
@@ -62,68 +216,82 @@ impl DecisionProcessData {
             route_souce: data.route_source(),
             igp_cost: data.igp_cost(),
             peer_id: data.peer_id(),
-            peer_addr: data.peer_addr()
-        }
-    }
-}
-
-// Implementing PartialOrd (and Ord, implicitly) for this data structure will be critical in
-// allowing the best paths to easily be found and for feasible paths to always
-// be ordered (using min heaps per destination). This effectively implements the Decision Process.
-// Paths that evaluate to "less than" are better paths.
-impl PartialOrd for DecisionProcessData {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        // First check to see if local pref can be compared
-       let lp_ord = match (self.local_pref, other.local_pref) {
-            // If so, compare local pref and return Option
-            // with the result. Note that the order for cmp() is switched!
-            // We want to prefer higher local preference, but make that evaluate
-            // to "less than".
-            (Some(left), Some(right)) => { Some(right.cmp(&left)) },
-            (None, _) | (_, None) => { None }
-        };
-        // Define a closure that does the non local pref comparisons
-        let f = || {
-            let comp = self.as_path_len.cmp(&other.as_path_len) // Shortest AS path wins
-            .then(self.origin.cmp(&other.origin)); // Lowest origin wins
-
-            // Before comparing med, need to verify both paths have same last_as.
-            // lowest med wins.
-            let comp = if self.last_as == other.last_as {
-                comp.then(self.med.cmp(&other.med))
-            } else {
-                comp
-            };
-            // Continue comparions
-            let this_rs: u8 = (&self.route_souce).into();
-            let other_rs: u8 = (&other.route_souce).into();
-            comp.then(this_rs.cmp(&other_rs)) // lowest route source wins (based on From impl)
-            .then(self.igp_cost.cmp(&other.igp_cost)) // Lowest IGP cost wins
-            .then(self.peer_id.cmp(&other.peer_id)) // Lowest peer id wins
-            .then(self.peer_addr.cmp(&other.peer_addr)) // Lowest peer addr wins
-        };
-
-        // Now can check the lp ordering Option and continue the comparison if necessary
-        match lp_ord {
-            Some(ord) => {
-                // Return the comp value if LP was deciding factor, otherwise continue
-                // the comparisons through the closure
-                if ord != std::cmp::Ordering::Equal {
-                    return Some(ord);
-                }
-                Some(f())
-            }
-            None => { Some(f()) }
+            peer_addr: data.peer_addr(),
+            installed: config.prefer_oldest.then_some(now)
         }
     }
+    fn identity(&self) -> (u32, RpkiState, Option<u32>, u8, u16, u8, u32, &RouteSource, u64, Ipv4Addr, IpAddr) {
+        (
+            self.weight,
+            self.rpki_state,
+            self.local_pref,
+            self.as_path_len,
+            self.last_as,
+            self.origin,
+            self.med,
+            &self.route_souce,
+            self.igp_cost,
+            self.peer_id,
+            self.peer_addr
+        )
+    }
+}
+
+// The Decision Process (RFC 4271, Pg. 80). Paths can't be ranked two at a time: MED is only compared between
+// paths from the same neighbouring AS, so a pairwise ordering isn't transitive and the winner would depend on
+// the order the paths arrived in. Each step instead runs over all the remaining paths of the destination and
+// keeps only the ones that are best at it. Candidates are indexes into the destination's paths.
+fn keep_min<K: Ord>(candidates: &mut Vec<usize>, key: impl Fn(&usize) -> K) {
+    // Keeps the candidates with the lowest key
+    if let Some(min) = candidates.iter().map(&key).min() {
+        candidates.retain(|idx| key(idx) == min);
+    }
 }
 
-impl Ord for DecisionProcessData {
-    fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+fn multipath_candidates(paths: &[Arc<PathAttributeTableEntry>], mut candidates: Vec<usize>) -> Vec<usize> {
+    // Runs the Decision Process up to and including the IGP cost step. The paths left only differ by the
+    // tie breakers and are candidates for multipath.
+    let data = |idx: &usize| &paths[*idx].decision_data;
+
+    // Weight trumps everything, highest weight wins
+    keep_min(&mut candidates, |idx| cmp::Reverse(data(idx).weight));
+    // Then origin validation state, Valid < NotFound < Invalid
+    if candidates.iter().all(|idx| data(idx).prefer_valid) {
+        keep_min(&mut candidates, |idx| u8::from(&data(idx).rpki_state));
+    }
+    // Highest local pref wins. Paths without one can't be compared on it and stay.
+    if let Some(best_lp) = candidates.iter().filter_map(|idx| data(idx).local_pref).max() {
+        candidates.retain(|idx| data(idx).local_pref.unwrap_or(best_lp) == best_lp);
     }
+    keep_min(&mut candidates, |idx| data(idx).as_path_len); // Shortest AS path wins
+    keep_min(&mut candidates, |idx| data(idx).origin); // Lowest origin wins
+
+    // Lowest med wins, but a path is only beaten by paths with the same last_as
+    let before_med = candidates.clone();
+    candidates.retain(|idx| {
+        before_med
+        .iter()
+        .all(|other| data(other).last_as != data(idx).last_as || data(other).med >= data(idx).med)
+    });
+    // Lowest route source wins (based on From impl)
+    keep_min(&mut candidates, |idx| u8::from(&data(idx).route_souce));
+    keep_min(&mut candidates, |idx| data(idx).igp_cost); // Lowest IGP cost wins
+    candidates
 }
 
+fn tie_break(paths: &[Arc<PathAttributeTableEntry>], candidates: &mut [usize]) {
+    // Orders the multipath candidates best first. Oldest path wins, only when all of them are eBGP paths with
+    // a known installation time (RFC 5004), then lowest peer id, then lowest peer addr. Paths from the same
+    // peer keep their storage order.
+    let data = |idx: &usize| &paths[*idx].decision_data;
+    let by_age = candidates
+        .iter()
+        .all(|idx| data(idx).installed.is_some() && data(idx).route_souce == RouteSource::Ebgp);
+    candidates.sort_by_key(|idx| {
+        let data = data(idx);
+        (data.installed.filter(|_| by_age), data.peer_id, data.peer_addr, *idx)
+    });
+}
 
 // This is an entry in the Path Attribute Table. The goal is to have a data structure that contains
 // the raw Path Attribute data (for easy Update creation) in addition to a representation of the relevant
@@ -137,330 +305,2185 @@ pub(crate) struct PathAttributeTableEntry {
 }
 
 impl PathAttributeTableEntry {
-    pub fn new(decision_data: DecisionProcessData, mut raw_pas: Vec<PathAttr>) -> Self {
-        // For hashing purposes, we want the Path Attributes to be sorted. Choosing to sort
-        // by Path Attribute Type Code.
-        raw_pas.sort_by_cached_key(|pa| pa.attr_type_code());
+    pub fn new(decision_data: DecisionProcessData, raw_pas: Vec<PathAttr>) -> Self {
+        // For hashing purposes, we want the Path Attributes to be in canonical form
+        // (sorted by Path Attribute Type Code).
         Self {
             decision_data,
-            raw_path_attrs: raw_pas
+            raw_path_attrs: canonicalize_attrs(raw_pas)
         }
     }
-    pub fn get_pas(&self) -> Vec<PathAttr> {
-        self.raw_path_attrs.clone()
+    pub fn pas(&self) -> &[PathAttr] {
+        self.raw_path_attrs.as_slice()
     }
     pub fn peer_id(&self) -> Ipv4Addr {
         self.decision_data.peer_id
     }
-}
-
-impl PartialOrd for PathAttributeTableEntry {
-    // Ordering for the full data structure is unnecessary, will reuse the implementation
-    // for DecisionProcessData
-    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        self.decision_data.partial_cmp(&other.decision_data)
+    pub fn peer_addr(&self) -> IpAddr {
+        self.decision_data.peer_addr
     }
-}
-
-impl Ord for PathAttributeTableEntry {
-    fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+    pub fn rpki_state(&self) -> RpkiState {
+        self.decision_data.rpki_state
+    }
+    pub fn next_hop(&self) -> Option<IpAddr> {
+        self.raw_path_attrs.iter().find_map(|pa| pa.next_hop())
+    }
+    pub fn source(&self) -> PathSource {
+        // Peer the path was learned from, for the export rules
+        match self.decision_data.route_souce {
+            RouteSource::Local => PathSource::Local,
+            RouteSource::Ebgp => PathSource::Ebgp(self.decision_data.peer_addr),
+            RouteSource::Ibgp => PathSource::Ibgp(self.decision_data.peer_addr, self.decision_data.peer_id)
+        }
     }
 }
 
-// Want the Entry to be behind an Rc so that when no paths are pointing to it,
+// Want the Entry to be behind an Arc so that when no paths are pointing to it,
 // it can be cleaned out of the table.
+// Only entries that were just inserted or have had a path released can have gone stale, so those are
+// tracked and checked after each walk instead of sweeping the whole table. A full sweep still happens
+// on demand, or once the table has doubled since the last one, which catches anything released
+// without being tracked.
 struct PathAttributeTable {
-    table: HashSet<Rc<PathAttributeTableEntry>>
+    table: HashSet<Arc<PathAttributeTableEntry>>,
+    released: Vec<Arc<PathAttributeTableEntry>>,
+    // Size of the table after the last full sweep
+    swept_len: usize
 }
 impl PathAttributeTable {
     pub fn new() -> Self {
         Self {
-            table: HashSet::new()
+            table: HashSet::new(),
+            released: Vec::new(),
+            swept_len: 0
         }
     }
-    pub fn insert(&mut self, entry: PathAttributeTableEntry) -> &Rc<PathAttributeTableEntry> {
+    pub fn insert(&mut self, entry: PathAttributeTableEntry) -> &Arc<PathAttributeTableEntry> {
         // Checks to see if the entry exists in the table and inserts if necessary.
         // A reference to the entry is always returned.
-        self.table.get_or_insert(Rc::new(entry))
+        let entry = Arc::new(entry);
+        let new = Arc::as_ptr(&entry);
+        let stored = self.table.get_or_insert(entry);
+        // A new entry nothing ends up pointing to (e.g. from a withdrawal) is stale right away
+        if Arc::as_ptr(stored) == new {
+            self.released.push(Arc::clone(stored));
+        }
+        stored
+    }
+    pub fn release(&mut self, entry: Arc<PathAttributeTableEntry>) {
+        // Hands back a path that was dropped from the RIB, so its entry is checked at the next cleanup
+        self.released.push(entry);
+    }
+    pub fn remove_released(&mut self) {
+        // Drops the released entries nothing points to anymore (the table's ref and the released one
+        // are all that's left).
+        let mut released = mem::take(&mut self.released);
+        released.sort_by_key(Arc::as_ptr);
+        released.dedup_by(|a, b| Arc::ptr_eq(a, b));
+        for entry in released {
+            if Arc::strong_count(&entry) == 2 {
+                _ = self.table.remove(&entry);
+            }
+        }
+        if self.table.len() > (2 * self.swept_len).max(PA_SWEEP_MIN) {
+            self.remove_stale();
+        }
     }
     pub fn remove_stale(&mut self) {
-        // Checks to see if any stale entries in the table exist (aka. Rc strong counts are 1)
+        // Checks to see if any stale entries in the table exist (aka. Arc strong counts are 1)
         // and drops them.
-        self.table.retain(|rc| Rc::strong_count(rc) > 1);
+        self.released.clear();
+        self.table.retain(|rc| Arc::strong_count(rc) > 1);
+        self.swept_len = self.table.len();
     }
     pub fn len(&self) -> usize {
         self.table.len()
     }
 }
 
-// The BinaryHeap with reverse effectively makes it a min heap. Want the paths to be sorted based
-// on their Ordering. The best path evaluates to the "smallest" path based on Ordering.
+// Paths of a destination, kept sorted on the peer they were learned from, so a peer's path is found with a
+// binary search. Most destinations only have a handful of paths, which stay inline in the entry.
+type PathSet = SmallVec<[Arc<PathAttributeTableEntry>; 8]>;
+
 struct BgpTableEntry {
-    paths: BinaryHeap<Reverse<Rc<PathAttributeTableEntry>>>,
+    paths: PathSet,
+    // Index of the bestpath, the Decision Process is rerun over all the paths whenever the set changes
+    best: usize,
+    // Table version at which the bestpath last changed
+    version: usize,
 }
 impl BgpTableEntry {
-    fn new(pa_entry: &Rc<PathAttributeTableEntry>) -> Self {
+    fn new(pa_entry: &Arc<PathAttributeTableEntry>) -> Self {
         // No table entry can be created without an associated path! This API assumes
         // the ref to the PA Entry is coming from the Path Attribute table (has already been inserted there).
-        let mut new_path: BinaryHeap<Reverse<Rc<PathAttributeTableEntry>>> = BinaryHeap::new();
-        new_path.push(Reverse(Rc::clone(pa_entry)));
+        let mut new_path = PathSet::new();
+        new_path.push(Arc::clone(pa_entry));
 
         Self {
-            paths: new_path
+            paths: new_path,
+            best: 0,
+            version: 0
         }
     }
-    fn insert(&mut self, pa_entry: &Rc<PathAttributeTableEntry>) -> bool {
+    fn storage_key(pa_entry: &PathAttributeTableEntry) -> (Ipv4Addr, IpAddr) {
+        (pa_entry.peer_id(), pa_entry.peer_addr())
+    }
+    fn set_paths(&mut self, mut paths: Vec<Arc<PathAttributeTableEntry>>) {
+        // Replaces all the paths at once
+        paths.sort_by_key(|path| Self::storage_key(path));
+        self.paths = PathSet::from_vec(paths);
+        self.decide();
+    }
+    fn insert(&mut self, pa_entry: &Arc<PathAttributeTableEntry>) -> bool {
         // Inserts the ref to a table entry (presumably returned from the PathAttributeTable)
-        // into the local min. heap if it doesn't already exist (duplicate entry).
-        // Leverage deref coercion with is_in().
-        match self.is_in(pa_entry) {
-            true => false,
-            false => {
-                self.paths.push(Reverse(Rc::clone(pa_entry)));
+        // in order, if it doesn't already exist (duplicate entry).
+        match self.position(pa_entry) {
+            Ok(_) => false,
+            Err(idx) => {
+                self.paths.insert(idx, Arc::clone(pa_entry));
+                self.decide();
                 true
             }
         }
     }
+    fn position(&self, pa_entry: &PathAttributeTableEntry) -> Result<usize, usize> {
+        // Index of the path if it's in the set, otherwise where it would be inserted (after any other paths
+        // from the same peer, of which there is usually none).
+        let key = Self::storage_key(pa_entry);
+        let start = self.paths.partition_point(|exist| Self::storage_key(exist) < key);
+        let end = start + self.paths[start..].partition_point(|exist| Self::storage_key(exist) == key);
+        match self.paths[start..end].iter().position(|exist| exist.as_ref() == pa_entry) {
+            Some(idx) => Ok(start + idx),
+            None => Err(end)
+        }
+    }
     fn is_in(&self, pa_entry: &PathAttributeTableEntry) -> bool {
-        // Walks the heap to see if the ref already exists
-        match self
-            .paths
-            .iter()
-            .filter(|exist| exist.0.as_ref() == pa_entry)
-            .count() {
-                0 => false,
-                _ => true
-            }
+        self.position(pa_entry).is_ok()
     }
     fn is_empty(&self) -> bool {
         self.paths.is_empty()
     }
-    fn bestpath(&self) -> &Rc<PathAttributeTableEntry> {
-        // Returns the best path for this destination (aka top item in the heap)
-        &self
+    fn ranked(&self, candidates: Vec<usize>) -> Vec<usize> {
+        // The multipath candidates among the paths, best first
+        let mut candidates = multipath_candidates(&self.paths, candidates);
+        tie_break(&self.paths, &mut candidates);
+        candidates
+    }
+    fn decide(&mut self) {
+        self.best = self.ranked((0..self.paths.len()).collect()).first().copied().unwrap_or(0);
+    }
+    fn bestpath(&self) -> &Arc<PathAttributeTableEntry> {
+        // Returns the best path for this destination
+        self
         .paths
-        .peek()
+        .get(self.best)
         .expect("A table entry should not exist without a path!")
-        .0
-
     }
-    fn remove(&mut self, path: &PathAttributeTableEntry) {
-        // Removes a path from the BGP Table Entry as long as the peer IDs match. RFC 4271, Pg. 20.
-        self.paths.retain(|x| x.0.as_ref().peer_id() != path.peer_id());
+    fn sorted(&self) -> Vec<&Arc<PathAttributeTableEntry>> {
+        // Returns all the paths, best first. Each next path is the bestpath among the ones left.
+        let mut left: Vec<usize> = (0..self.paths.len()).collect();
+        let mut sorted = Vec::with_capacity(left.len());
+        while let Some(&next) = self.ranked(left.clone()).first() {
+            left.retain(|idx| *idx != next);
+            sorted.push(&self.paths[next]);
+        }
+        sorted
+    }
+    fn multipath(&self, max_paths: usize) -> Vec<Arc<PathAttributeTableEntry>> {
+        // Same as bestpaths(), owned so the set can be compared after the entry changes
+        match max_paths > 1 {
+            true => self.bestpaths(max_paths).into_iter().cloned().collect(),
+            false => vec![Arc::clone(self.bestpath())]
+        }
+    }
+    fn bestpaths(&self, max_paths: usize) -> Vec<&Arc<PathAttributeTableEntry>> {
+        // Returns up to max_paths paths that are tied with the bestpath through the IGP cost step,
+        // best first. The bestpath is always included.
+        self
+        .ranked((0..self.paths.len()).collect())
+        .into_iter()
+        .take(max_paths.max(1))
+        .map(|idx| &self.paths[idx])
+        .collect()
+    }
+    fn remove(&mut self, peer_id: Ipv4Addr, pa_table: &mut PathAttributeTable) {
+        // Removes the peer's path from the BGP Table Entry, paths only need to match on the peer.
+        // RFC 4271, Pg. 20. Removed paths are released to the PA table.
+        let start = self.paths.partition_point(|exist| exist.peer_id() < peer_id);
+        let end = start + self.paths[start..].partition_point(|exist| exist.peer_id() == peer_id);
+        if start == end {
+            return;
+        }
+        for path in self.paths.drain(start..end) {
+            pa_table.release(path);
+        }
+        self.decide();
     }
     fn len(&self) -> usize {
         self.paths.len()
     }
 }
 
+// Key used to group advertised routes by their Path Attributes. Wraps the interned PAT entry
+// so grouping never clones the PAs. Only the PAs (and the validation state, which can be tagged on export)
+// take part in equality and hashing since PAT entries learned from different peers can carry identical PAs
+// and should end up in the same Update. iBGP paths also keep their source, since whether (and how) they are
+// reflected depends on it.
+#[derive(Clone, Debug)]
+pub(crate) struct AdvertisedPas(Arc<PathAttributeTableEntry>);
+
+impl AdvertisedPas {
+    pub fn pas(&self) -> &[PathAttr] {
+        self.0.pas()
+    }
+    fn ibgp_source(&self) -> Option<PathSource> {
+        match self.0.source() {
+            source @ PathSource::Ibgp(..) => Some(source),
+            _ => None
+        }
+    }
+}
+impl PartialEq for AdvertisedPas {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+        || (self.0.pas() == other.0.pas()
+            && self.0.rpki_state() == other.0.rpki_state()
+            && self.ibgp_source() == other.ibgp_source())
+    }
+}
+impl Eq for AdvertisedPas {}
+impl Hash for AdvertisedPas {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.pas().hash(state);
+        self.0.rpki_state().hash(state);
+        self.ibgp_source().hash(state);
+    }
+}
+
 // Struct to house prefixes generated from a BGP Table walk
 // for future UPDATE message creation
-struct AdvertisedRoutes<T> {
+pub(crate) struct AdvertisedRoutes<T> {
     _marker: PhantomData<T>,
-    routes: HashMap<Vec<PathAttr>, Vec<Route>>
+    routes: HashMap<AdvertisedPas, Vec<Route>>
 }
 impl<T> AdvertisedRoutes<T> {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {_marker: PhantomData, routes: HashMap::new() }
     }
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.routes.len()
     }
-    fn routes(&self) -> &HashMap<Vec<PathAttr>, Vec<Route>> {
+    fn routes(&self) -> &HashMap<AdvertisedPas, Vec<Route>> {
         &self.routes
     }
-    fn is_empty(&self) -> bool {
+    pub fn is_empty(&self) -> bool {
         self.routes.is_empty()
     }
-}
-impl AdvertisedRoutes<Ipv4Addr> {
-    fn entry(&mut self, key: Vec<PathAttr>, prefix: Ipv4Addr, prefix_len: u8) {
-        // Abstracts away the machinery of the entry API.
-        // Adds or updates a given Key/Value combo. Using Vec<PathAttr> as a key should be fine since the PAs are sorted
-        // deterministically in the PAT Entry, which is where they're pulled from, unchanged.
-        let addr = IpAddr::V4(prefix);
+    pub fn prefixes(&self) -> Vec<Route> {
+        // Every route across the groups, sorted
+        let mut prefixes: Vec<Route> = self.routes.values().flatten().cloned().collect();
+        prefixes.sort();
+        prefixes
+    }
+    pub fn to_nlri(&self) -> Vec<Nlri> {
+        // Couples each group of routes with its PAs, one Nlri per Update message
+        // that needs to be generated. Groups too large for a single Update are split up front.
+        // This is the only place the raw PAs are copied out of the PA table.
         self.routes
-        .entry(key)
-        .and_modify(|v| v.push(Route::new(prefix_len, addr)))
-        .or_insert(vec![Route::new(prefix_len, addr)]);
+        .iter()
+        .flat_map(|(pas, routes)| Nlri::chunked(routes.as_slice(), pas.pas()))
+        .collect()
     }
-}
-// Will be generic over AFI (v4/v6)
-// TO-DO: Think about how aggregation can be implemented. Maybe add a suppressed field in BGP Table Entry?
-// Could potentially create a radix tree from all the destinations and use this to determine which should be suppressed?
-pub(crate) struct BgpTable<A> {
-    table: HashMap<(A, PrefixLen), BgpTableEntry>,
-    table_version: usize,
-    pa_table: PathAttributeTable,
-}
-impl<A> BgpTable<A> {
-    pub fn increment_version(&mut self) {
-        self.table_version += 1;
+    fn insert(&mut self, key: &Arc<PathAttributeTableEntry>, route: Route) {
+        // Abstracts away the machinery of the entry API.
+        // Adds or updates a given Key/Value combo. PAT entries are already canonicalized, so the same
+        // set of PAs always hashes the same regardless of which entry it came from.
+        self.routes
+        .entry(AdvertisedPas(Arc::clone(key)))
+        .or_default()
+        .push(route);
     }
-    
-    pub fn num_paths(&self) -> usize {
-        // Returns number of PATHs in the BGP table, not number of destinations
-        self.table
+    pub fn export(&self, peer: &ExportPeer, tags: Option<&RpkiTags>) -> (Vec<Route>, Vec<Nlri>) {
+        // Applies the peer's export rules to every group of routes. Returns the routes that can't be
+        // advertised to the peer (they need to be withdrawn in case the peer has an older bestpath) and the
+        // Nlri to advertise. Groups that end up with the same PAs after export are merged, then split so each
+        // fits in a single Update.
+        // If tags are given, each path also carries the community for its validation state.
+        let mut withdrawn: Vec<Route> = Vec::new();
+        let mut grouped: HashMap<Vec<PathAttr>, Vec<Route>> = HashMap::new();
+        self.routes
         .iter()
-        .map(|(_, entry)| entry.len())
-        .sum()
+        .for_each(|(pas, routes)| {
+            let exported = peer
+                .export(pas.pas(), &pas.0.source())
+                .map(|out| match tags {
+                    Some(tags) => {
+                        let mut out = out;
+                        SetAction::AddCommunities(vec![tags.community(pas.0.rpki_state())]).apply(&mut out);
+                        canonicalize_attrs(out)
+                    },
+                    None => out
+                });
+            match exported {
+                Some(out) => grouped.entry(out).or_default().extend_from_slice(routes),
+                None => withdrawn.extend_from_slice(routes)
+            }
+        });
+        let nlri = grouped
+            .iter()
+            .flat_map(|(pas, routes)| Nlri::chunked(routes.as_slice(), pas.as_slice()))
+            .collect();
+        (withdrawn, nlri)
     }
-    
-    pub fn num_destinations(&self) -> usize {
-        // Returns number of destinations in the BGP table
-        self.table.len()
+    pub fn extend(&mut self, other: AdvertisedRoutes<T>) {
+        // Merges the routes from another walk into this container.
+        other
+        .routes
+        .into_iter()
+        .for_each(|(pas, routes)| self.routes.entry(pas).or_default().extend(routes));
     }
-
-    pub fn num_pa_entries(&self) -> usize {
-        self.pa_table.len()
+}
+impl<A: TableAfi> AdvertisedRoutes<A> {
+    fn afi(&self) -> Afi {
+        A::AFI
     }
-
-}  
-impl BgpTable<Ipv4Addr> {
-    pub fn new() -> Self {
-        Self {
-            table: HashMap::new(),
-            table_version: 0,
-            pa_table: PathAttributeTable::new()
-        }
+    fn entry(&mut self, key: &Arc<PathAttributeTableEntry>, prefix: A, prefix_len: u8) {
+        self.insert(key, Route::new(prefix_len, prefix.to_ip()));
     }
-    
-    pub fn walk(&mut self, payload: ReceivedRoutes) -> (Vec<Route>, AdvertisedRoutes<Ipv4Addr>) {
-        // Inserts (and/or removes) paths received in an Update message to/from the BGP table.
-        // The function returns routes that can be withdrawn along with a container holding all
-        // the Nlri that would need to be advertised using different Update messages, based on changes
-        // to the BGP table. 
+}
 
-        let ddata = DecisionProcessData::new(&payload);
-        let mut adv_routes: AdvertisedRoutes<Ipv4Addr> = AdvertisedRoutes::new();
-        let mut removed_routes: Vec<Route> = Vec::new();
+// What to do with a path when reworking the paths already in the table
+enum PathChange {
+    Keep,
+    Replace(DecisionProcessData),
+    Remove
+}
 
+// Looks up the IGP cost to a next hop. When the table has a resolver, it takes precedence over the
+// cost supplied in ReceivedRoutes; next hops it can't resolve keep the supplied cost. Implemented for closures.
+pub(crate) trait IgpResolver: Send {
+    fn resolve(&self, next_hop: IpAddr) -> Option<u64>;
+}
 
-        // Pre-emptively update the PAT and get the ref necessary to update BGP
-        // table entries
-        let pat_entry = PathAttributeTableEntry::new(ddata, payload.path_attrs());
-        let pat_entry_ref = self.pa_table.insert(pat_entry);
-        
+impl<F: Fn(IpAddr) -> Option<u64> + Send> IgpResolver for F {
+    fn resolve(&self, next_hop: IpAddr) -> Option<u64> {
+        self(next_hop)
+    }
+}
 
-        // First check to see if there are any new routes to be added to table. If not, immediately check to
-        // see if any routes need to be withdrawn. These two operations are logically disjoint, the intersection of
-        // advertised and withdrawn routes from a given Update should be the empty set.
-        // RFC 4271 states that implementations should be able to catch cases where the intersection ISNT the empty set,
-        // which will occur before the data reaches this algorithm.
-        if let Some(new_paths) = payload.routes() {
-            new_paths
-            .iter()
-            .filter(|dest| dest.prefix_v4().is_some()) // only allow v4
-            .for_each(|dest| {
-                match self.table.get_mut(&(dest.prefix_v4().expect("Filter should only allow v4 routes"), dest.prefix_len())) {
-                    // If the BGP table entry exists, add path to it
-                    Some(bgp_table_entry) => {
-                        bgp_table_entry.insert(pat_entry_ref);
-                        // If the new entry is the bestpath, add it to
-                        // the container to be advertised. Entry API is amazing!
-                        if bgp_table_entry.bestpath() == pat_entry_ref {
-                            adv_routes.entry(pat_entry_ref.get_pas(), dest.prefix_v4().unwrap(), dest.prefix_len());
-                        }
-                    },
-                    // Otherwise, create a new entry and insert the ref. Add to container
-                    // to be advertised.
-                    None => {
-                        self.table.insert((dest.prefix_v4().unwrap(), dest.prefix_len()), BgpTableEntry::new(pat_entry_ref));
-                        adv_routes.entry(pat_entry_ref.get_pas(), dest.prefix_v4().unwrap(), dest.prefix_len());
+// View of the routing table (or ARP/ND cache) used to check whether a next hop can be reached.
+// Paths whose next hop can't be reached aren't eligible for bestpath. Implemented for closures.
+pub(crate) trait NextHopView: Send {
+    fn is_reachable(&self, next_hop: IpAddr) -> bool;
+}
 
-                    }
-                }
-            })
-        }
+impl<F: Fn(IpAddr) -> bool + Send> NextHopView for F {
+    fn is_reachable(&self, next_hop: IpAddr) -> bool {
+        self(next_hop)
+    }
+}
 
-        if let Some(del_paths) = payload.withdrawn_routes() {
-            del_paths
-            .iter()
-            .filter(|dest| dest.prefix_v4().is_some()) // Only allow v4
-            .for_each(|dest| {
-                match self.table.get_mut(&(dest.prefix_v4().expect("Filter should only allow v4 routes"), dest.prefix_len())) {
-                    // Check to see if destination is in table
-                    Some(bgp_table_entry) => {
-                        // Check to see if path to be removed is currently the bestpath. RFC 4271, Pg. 20
-                        // states that only need to match on peer.
-                        let was_best = if bgp_table_entry.bestpath().peer_id() == pat_entry_ref.peer_id() {
-                            true
-                        } else {false};
-                        // Remove the path
-                        bgp_table_entry.remove(pat_entry_ref);
-                        // If resulting BGP table entry is empty, remove from table and add destination
-                        // to routes to be withdrawn from peers.
-                        if bgp_table_entry.is_empty() {
-                           _ = self.table.remove(&(dest.prefix_v4().unwrap(), dest.prefix_len()));
-                           removed_routes.push(Route::new(dest.prefix_len(), IpAddr::V4(dest.prefix_v4().unwrap())))
-                        } else if was_best { // Otherwise, if new bestpath, add to adv routes container
-                            adv_routes.entry(bgp_table_entry.bestpath().get_pas(), dest.prefix_v4().unwrap(), dest.prefix_len());
-                        }
-                    },
-                    // Do nothing in None case
-                    None => {
-                        ();
-                    }
-                }
-            });
+fn nh_resolvable(view: Option<&dyn NextHopView>, path: &PathAttributeTableEntry) -> bool {
+    // Without a view (or a next hop, e.g. locally originated paths) every path is eligible
+    match (view, path.next_hop()) {
+        (Some(view), Some(next_hop)) => view.is_reachable(next_hop),
+        _ => true
+    }
+}
 
+fn unpark<A: TableAfi>(
+    unresolved: &mut HashMap<A::Key, Vec<Arc<PathAttributeTableEntry>>>,
+    key: A::Key,
+    peer_id: Ipv4Addr,
+    pa_table: &mut PathAttributeTable
+) {
+    // Drops whatever the peer had parked for the destination, releasing it to the PA table
+    if let Some(parked) = unresolved.get_mut(&key) {
+        while let Some(idx) = parked.iter().position(|exist| exist.peer_id() == peer_id) {
+            pa_table.release(parked.remove(idx));
         }
-        // Clean up the PA table
-        self.pa_table.remove_stale();
+        if parked.is_empty() {
+            _ = unresolved.remove(&key);
+        }
+    }
+}
 
-        // Increment the table version if the table changed (bestpaths changed and/or destinations removed.)
-        if !removed_routes.is_empty() || !adv_routes.is_empty() {
-            self.increment_version();
+// Results accumulated over one or more payloads walked into the table before it is swept.
+struct WalkPass<A> {
+    // Version the table moves to once the pass is finished
+    next_version: usize,
+    adv_routes: AdvertisedRoutes<A>,
+    removed_routes: Vec<Route>,
+    events: Vec<BestpathEvent>,
+    // Whether any destination was stamped with next_version
+    changed: bool
+}
+
+impl<A> WalkPass<A> {
+    fn new(next_version: usize) -> Self {
+        Self {
+            next_version,
+            adv_routes: AdvertisedRoutes::new(),
+            removed_routes: Vec::new(),
+            events: Vec::new(),
+            changed: false
         }
+    }
+}
 
-        (removed_routes, adv_routes)
+// Changes to a table since a given version, see BgpTable::changes_since()
+pub struct TableDelta {
+    // Current table version, the version to ask for changes since next time
+    version: usize,
+    withdrawn: Vec<Route>,
+    // Bestpaths that changed, grouped by their PAs
+    updated: Vec<Nlri>
+}
+
+impl TableDelta {
+    pub fn version(&self) -> usize {
+        self.version
+    }
+    pub fn withdrawn(&self) -> &[Route] {
+        self.withdrawn.as_slice()
+    }
+    pub fn updated(&self) -> &[Nlri] {
+        self.updated.as_slice()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.withdrawn.is_empty() && self.updated.is_empty()
     }
 }
-impl BgpTable<Ipv6Addr> {
-    pub fn new() -> Self {
+
+// Detects exact duplicate announcements (same peer, prefix and PAs) received within a configurable
+// window. Flappy upstreams tend to resend identical state, which would otherwise cause the same route
+// to be re-advertised downstream. Optionally, those re-advertisements can be suppressed.
+pub(crate) struct DuplicateDetector {
+    window: Duration,
+    suppress: bool,
+    // Hash of the PAs and when the prefix was last announced by the peer. Storing the hash
+    // instead of the PAT entry so that this doesn't keep PAT entries alive.
+    last_seen: HashMap<(IpAddr, Route), (u64, Instant)>,
+    // Number of duplicates received per peer
+    counters: HashMap<IpAddr, usize>,
+}
+impl DuplicateDetector {
+    pub fn new(window: Duration, suppress: bool) -> Self {
         Self {
-            table: HashMap::new(),
-            table_version: 0,
-            pa_table: PathAttributeTable::new()
+            window,
+            suppress,
+            last_seen: HashMap::new(),
+            counters: HashMap::new()
+        }
+    }
+    pub fn observe(&mut self, peer: IpAddr, route: &Route, pas_hash: u64, now: Instant) -> bool {
+        // Records the announcement and returns whether it duplicates one received within the window.
+        let key = (peer, route.clone());
+        let duplicate = match self.last_seen.get(&key) {
+            Some((hash, at)) => *hash == pas_hash && now.duration_since(*at) < self.window,
+            None => false
+        };
+        self.last_seen.insert(key, (pas_hash, now));
+        if duplicate {
+            *self.counters.entry(peer).or_insert(0) += 1;
         }
+        duplicate
+    }
+    pub fn forget(&mut self, peer: IpAddr, route: &Route) {
+        // Called on withdrawal so that a re-announcement isn't seen as a duplicate.
+        _ = self.last_seen.remove(&(peer, route.clone()));
+    }
+    pub fn prune(&mut self, now: Instant) {
+        // Drops announcements that have aged out of the window.
+        let window = self.window;
+        self.last_seen.retain(|_, (_, at)| now.duration_since(*at) < window);
+    }
+    pub fn duplicates(&self, peer: IpAddr) -> usize {
+        self.counters.get(&peer).copied().unwrap_or(0)
+    }
+    pub fn suppress(&self) -> bool {
+        self.suppress
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use rand::{seq::SliceRandom, Rng};
-    use crate::{comms::MockReceivedRoutesBuilder, message_types::Route};
+// Result of simulating the announcement of a locally originated route. Holds the current bestpath
+// for the destination (if any) and, per peer, the PAs it would be advertised with (None if it wouldn't be).
+pub(crate) struct AnnounceSimulation {
+    current_bestpath: Option<Vec<PathAttr>>,
+    exports: Vec<(IpAddr, Option<Vec<PathAttr>>)>
+}
+impl AnnounceSimulation {
+    pub fn current_bestpath(&self) -> Option<&[PathAttr]> {
+        self.current_bestpath.as_deref()
+    }
+    pub fn exports(&self) -> &[(IpAddr, Option<Vec<PathAttr>>)] {
+        self.exports.as_slice()
+    }
+}
 
-    use super::*;
+// Per peer view of exactly what each peer advertised, before any policy is applied (Adj-RIB-In,
+// RFC 4271 Pg. 9). Each route points to the payload it was received with (minus the routes), so
+// the peer's paths can be replayed into the Loc-RIB later (e.g. when inbound policy changes).
+pub(crate) struct AdjRibIn {
+    peers: HashMap<IpAddr, HashMap<Route, Arc<ReceivedRoutes>>>
+}
+impl AdjRibIn {
+    pub fn new() -> Self {
+        Self {
+            peers: HashMap::new()
+        }
+    }
+    pub fn update(&mut self, payload: &ReceivedRoutes) {
+        // Stores the routes announced in the payload and drops the withdrawn ones.
+        let rib = self.peers.entry(payload.peer_addr()).or_default();
+        if let Some(routes) = payload.routes() {
+            let template = Arc::new(payload.with_routes(payload.path_attrs(), None, None));
+            routes
+            .into_iter()
+            .for_each(|route| {
+                rib.insert(route, Arc::clone(&template));
+            });
+        }
+        if let Some(routes) = payload.withdrawn_routes() {
+            routes
+            .iter()
+            .for_each(|route| {
+                _ = rib.remove(route);
+            });
+        }
+    }
+    pub fn remove_peer(&mut self, peer: IpAddr) -> usize {
+        // Drops everything received from the peer (e.g. session went down), returning the number of routes removed.
+        self.peers
+        .remove(&peer)
+        .map(|rib| rib.len())
+        .unwrap_or(0)
+    }
+    pub fn num_routes(&self, peer: IpAddr) -> usize {
+        self.peers.get(&peer).map(|rib| rib.len()).unwrap_or(0)
+    }
+    pub fn dump(&self, peer: IpAddr) -> Vec<(&Route, &[PathAttr])> {
+        // Returns every route received from the peer along with the PAs it was received with, sorted by route.
+        let mut routes: Vec<(&Route, &[PathAttr])> = self
+            .peers
+            .get(&peer)
+            .iter()
+            .flat_map(|rib| rib.iter())
+            .map(|(route, template)| (route, template.path_attrs_ref()))
+            .collect();
+        routes.sort_by(|left, right| left.0.cmp(right.0));
+        routes
+    }
+    pub fn replay<A, F>(&self, peer: IpAddr, table: &mut BgpTable<A>, mut policy: F) -> (Vec<Route>, AdvertisedRoutes<A>)
+    where
+        A: TableAfi,
+        F: FnMut(&Route, &[PathAttr]) -> Option<Vec<PathAttr>>
+    {
+        // Runs every route received from the peer through the policy and walks the results into the table.
+        // The policy returns the PAs to install the route with, or None to reject it. Rejected routes are
+        // withdrawn from the table in case they were previously accepted. Returns the combined walk results.
+        let mut removed_routes: Vec<Route> = Vec::new();
+        let mut adv_routes: AdvertisedRoutes<A> = AdvertisedRoutes::new();
+        let rib = match self.peers.get(&peer) {
+            Some(rib) => rib,
+            None => return (removed_routes, adv_routes)
+        };
 
+        // Group the routes so that each set of (payload, PAs) only needs a single walk. Rejected routes
+        // are grouped under the PAs they were received with.
+        let mut groups: HashMap<(usize, Vec<PathAttr>), (Arc<ReceivedRoutes>, Vec<Route>, Vec<Route>)> = HashMap::new();
+        rib
+        .iter()
+        .for_each(|(route, template)| {
+            let key = Arc::as_ptr(template) as usize;
+            match policy(route, template.path_attrs_ref()) {
+                Some(pas) => groups
+                    .entry((key, canonicalize_attrs(pas)))
+                    .or_insert_with(|| (Arc::clone(template), Vec::new(), Vec::new()))
+                    .1
+                    .push(route.clone()),
+                None => groups
+                    .entry((key, template.path_attrs()))
+                    .or_insert_with(|| (Arc::clone(template), Vec::new(), Vec::new()))
+                    .2
+                    .push(route.clone())
+            }
+        });
 
-    // Setup Functions
-    
-    fn build_pa_entry(med_val: u32, origin: OriginValue) -> PathAttributeTableEntry {
-        let pa = PathAttrBuilder::<Med>::new().metric(med_val).build();
-        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin.clone()).build();
-        let mut raw_pas = vec![pa, pa2];
-        // Randomly shuffle the PA vector since it should be sorted deterministically by
-        // its generating function.
-        let mut rng = rand::thread_rng();
-        raw_pas.shuffle(&mut rng);
+        groups
+        .into_iter()
+        .for_each(|((_, pas), (template, accepted, rejected))| {
+            let accepted = match accepted.is_empty() {
+                true => None,
+                false => Some(accepted)
+            };
+            let rejected = match rejected.is_empty() {
+                true => None,
+                false => Some(rejected)
+            };
+            // Policy may have changed PAs used by the Decision Process
+            let mut payload = template.with_routes(pas, accepted, rejected);
+            payload.sync_decision_data();
+            let (removed, adv) = table.walk(payload);
+            removed_routes.extend(removed);
+            adv_routes.extend(adv);
+        });
 
-        let ddata = DecisionProcessData {
-            local_pref: Some(100),
-            as_path_len: 1,
-            last_as: 65000,
-            origin: origin.into(),
-            med: med_val,
-            route_souce: RouteSource::Ebgp,
-            igp_cost: 0,
-            peer_addr: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
-            peer_id: Ipv4Addr::new(192, 168, 1, 1)
-        };
-        PathAttributeTableEntry::new(ddata, raw_pas)
+        (removed_routes, adv_routes)
     }
+}
 
-    fn generate_routes_v4(num_routes: usize) -> Vec<Route> {
+// Per peer view of exactly what has been advertised to the peer (Adj-RIB-Out, RFC 4271 Pg. 9).
+// Changes are queued and only sent if they differ from what the peer already has. Advertisements
+// are paced by the MinRouteAdvertisementInterval (RFC 4271 Pg. 84), withdrawals are not.
+pub(crate) struct AdjRibOut {
+    peer_addr: IpAddr,
+    mrai: Duration,
+    // What the peer currently has from us
+    advertised: HashMap<Route, Vec<PathAttr>>,
+    // Changes waiting to be sent, None means withdraw
+    pending: HashMap<Route, Option<Vec<PathAttr>>>,
+    last_advertised: Option<Instant>
+}
+impl AdjRibOut {
+    pub fn new(peer_addr: IpAddr, mrai: Duration) -> Self {
+        Self {
+            peer_addr,
+            mrai,
+            advertised: HashMap::new(),
+            pending: HashMap::new(),
+            last_advertised: None
+        }
+    }
+    pub fn default_mrai(route_source: &RouteSource) -> Duration {
+        match route_source {
+            RouteSource::Ebgp => Duration::from_secs(MRAI_EBGP_SECS),
+            RouteSource::Ibgp | RouteSource::Local => Duration::from_secs(MRAI_IBGP_SECS)
+        }
+    }
+    pub fn peer_addr(&self) -> IpAddr {
+        self.peer_addr
+    }
+    pub fn mrai(&self) -> Duration {
+        self.mrai
+    }
+    pub fn advertise(&mut self, route: Route, pas: Vec<PathAttr>) {
+        // Queues the route to be advertised with the given PAs. If the peer already has
+        // exactly this, any pending change is dropped instead.
+        let pas = canonicalize_attrs(pas);
+        match self.advertised.get(&route) == Some(&pas) {
+            true => _ = self.pending.remove(&route),
+            false => _ = self.pending.insert(route, Some(pas))
+        }
+    }
+    pub fn withdraw(&mut self, route: Route) {
+        // Queues the route to be withdrawn. Nothing needs to be sent if the peer doesn't have it.
+        match self.advertised.contains_key(&route) {
+            true => _ = self.pending.insert(route, None),
+            false => _ = self.pending.remove(&route)
+        }
+    }
+    pub fn queue(&mut self, withdrawn: Vec<Route>, nlri: Vec<Nlri>) {
+        // Queues the changes of a walk, as exported to the peer
+        withdrawn
+        .into_iter()
+        .for_each(|route| self.withdraw(route));
+        nlri
+        .iter()
+        .for_each(|nlri| {
+            nlri
+            .routes()
+            .iter()
+            .for_each(|route| self.advertise(route.clone(), nlri.path_attrs().to_vec()))
+        });
+    }
+    pub fn replace(&mut self, nlri: Vec<Nlri>) {
+        // Queues everything the peer should have, e.g. when it asked for the table again (RFC 2918). All of it
+        // is sent even if the peer already has it, and what the peer has that isn't in it is withdrawn.
+        let mut stale: HashSet<Route> = self.advertised.keys().cloned().collect();
+        self.pending.clear();
+        nlri
+        .iter()
+        .for_each(|nlri| {
+            nlri
+            .routes()
+            .iter()
+            .for_each(|route| {
+                _ = stale.remove(route);
+                self.pending.insert(route.clone(), Some(canonicalize_attrs(nlri.path_attrs().to_vec())));
+            })
+        });
+        stale
+        .into_iter()
+        .for_each(|route| _ = self.pending.insert(route, None));
+    }
+    pub fn get(&self, route: &Route) -> Option<&[PathAttr]> {
+        // Returns the PAs the route was advertised to the peer with, if it was.
+        self.advertised.get(route).map(|pas| pas.as_slice())
+    }
+    pub fn num_advertised(&self) -> usize {
+        self.advertised.len()
+    }
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn advertised(&self) -> Vec<Nlri> {
+        // What the peer has from us, one Nlri per Update
+        let mut grouped: HashMap<&[PathAttr], Vec<Route>> = HashMap::new();
+        self.advertised
+        .iter()
+        .for_each(|(route, pas)| grouped.entry(pas.as_slice()).or_default().push(route.clone()));
+        grouped
+        .into_iter()
+        .flat_map(|(pas, mut routes)| {
+            routes.sort();
+            Nlri::chunked(routes.as_slice(), pas)
+        })
+        .collect()
+    }
+    pub fn next_flush(&self) -> Option<Instant> {
+        // When the advertisements held back by the MRAI can go out, if any are
+        self.last_advertised
+        .filter(|_| !self.pending.is_empty())
+        .map(|at| at + self.mrai)
+    }
+    pub fn mrai_expired(&self, now: Instant) -> bool {
+        match self.last_advertised {
+            Some(at) => now.duration_since(at) >= self.mrai,
+            None => true
+        }
+    }
+    pub fn flush(&mut self, now: Instant) -> (Vec<Route>, Vec<Nlri>) {
+        // Returns the routes to withdraw and the Nlri to advertise (one per Update), and records
+        // them as sent. Pending withdrawals are always returned, pending advertisements stay queued
+        // until the MRAI has expired.
+        let send_adv = self.mrai_expired(now);
+        let mut withdrawn: Vec<Route> = Vec::new();
+        let mut grouped: HashMap<Vec<PathAttr>, Vec<Route>> = HashMap::new();
+        let pending: Vec<(Route, Option<Vec<PathAttr>>)> = self.pending.drain().collect();
+
+        pending
+        .into_iter()
+        .for_each(|(route, change)| {
+            match change {
+                None => {
+                    _ = self.advertised.remove(&route);
+                    withdrawn.push(route);
+                },
+                Some(pas) if send_adv => {
+                    grouped.entry(pas.clone()).or_default().push(route.clone());
+                    self.advertised.insert(route, pas);
+                },
+                Some(pas) => _ = self.pending.insert(route, Some(pas))
+            }
+        });
+        if !grouped.is_empty() {
+            self.last_advertised = Some(now);
+        }
+        let nlri = grouped
+            .iter()
+            .flat_map(|(pas, routes)| Nlri::chunked(routes.as_slice(), pas.as_slice()))
+            .collect();
+
+        (withdrawn, nlri)
+    }
+    pub fn clear(&mut self) {
+        // Peer went down, it no longer has anything from us.
+        self.advertised.clear();
+        self.pending.clear();
+        self.last_advertised = None;
+    }
+}
+
+// Read-only view of a single path in the table, used by the query API
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathView {
+    weight: u32,
+    rpki_state: RpkiState,
+    peer_id: Ipv4Addr,
+    peer_addr: IpAddr,
+    local_pref: Option<u32>,
+    med: u32,
+    as_path_len: u8,
+    origin: u8,
+    route_source: RouteSource,
+    best: bool,
+    pas: Vec<PathAttr>
+}
+
+impl PathView {
+    fn new(entry: &PathAttributeTableEntry, best: bool) -> Self {
+        let ddata = &entry.decision_data;
+        Self {
+            weight: ddata.weight,
+            rpki_state: ddata.rpki_state,
+            peer_id: ddata.peer_id,
+            peer_addr: ddata.peer_addr,
+            local_pref: ddata.local_pref,
+            med: ddata.med,
+            as_path_len: ddata.as_path_len,
+            origin: ddata.origin,
+            route_source: ddata.route_souce.clone(),
+            best,
+            pas: entry.pas().to_vec()
+        }
+    }
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+    pub fn rpki_state(&self) -> RpkiState {
+        self.rpki_state
+    }
+    pub fn peer_id(&self) -> Ipv4Addr {
+        self.peer_id
+    }
+    pub fn peer_addr(&self) -> IpAddr {
+        self.peer_addr
+    }
+    pub fn local_pref(&self) -> Option<u32> {
+        self.local_pref
+    }
+    pub fn med(&self) -> u32 {
+        self.med
+    }
+    pub fn as_path_len(&self) -> u8 {
+        self.as_path_len
+    }
+    pub fn origin(&self) -> u8 {
+        self.origin
+    }
+    pub fn route_source(&self) -> &RouteSource {
+        &self.route_source
+    }
+    pub fn is_best(&self) -> bool {
+        self.best
+    }
+    pub fn pas(&self) -> &[PathAttr] {
+        self.pas.as_slice()
+    }
+    pub fn next_hop(&self) -> Option<IpAddr> {
+        self.pas.iter().find_map(|pa| pa.next_hop())
+    }
+    pub fn communities(&self) -> Vec<u32> {
+        self.pas.iter().find_map(|pa| pa.communities()).unwrap_or_default()
+    }
+}
+
+// Read-only view of a destination and its candidate paths, best first
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteView {
+    route: Route,
+    paths: Vec<PathView>
+}
+
+impl RouteView {
+    fn new(route: Route, entry: &BgpTableEntry) -> Self {
+        let paths = entry
+            .sorted()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, path)| PathView::new(path, idx == 0))
+            .collect();
+        Self {
+            route,
+            paths
+        }
+    }
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+    pub fn paths(&self) -> &[PathView] {
+        self.paths.as_slice()
+    }
+    pub fn bestpath(&self) -> Option<&PathView> {
+        self.paths.iter().find(|path| path.is_best())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Json,
+    Csv
+}
+
+// Approximate memory used by a table, see BgpTable::memory_stats(). Sizes are estimates based on
+// element sizes and container capacities, allocator overhead isn't included.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MemoryStats {
+    // Destination map, keys and entries
+    prefix_map_bytes: usize,
+    // Paths of destinations with too many to keep inline in the entry
+    path_heap_bytes: usize,
+    // Interned PA entries, including the raw PA values
+    pa_table_bytes: usize,
+    // Trie index over the destinations
+    index_bytes: usize,
+    num_paths: usize,
+    num_pa_entries: usize
+}
+
+impl MemoryStats {
+    pub fn prefix_map_bytes(&self) -> usize {
+        self.prefix_map_bytes
+    }
+    pub fn path_heap_bytes(&self) -> usize {
+        self.path_heap_bytes
+    }
+    pub fn pa_table_bytes(&self) -> usize {
+        self.pa_table_bytes
+    }
+    pub fn index_bytes(&self) -> usize {
+        self.index_bytes
+    }
+    pub fn total_bytes(&self) -> usize {
+        self.prefix_map_bytes + self.path_heap_bytes + self.pa_table_bytes + self.index_bytes
+    }
+    pub fn dedup_ratio(&self) -> f64 {
+        // Paths per PA entry, i.e. how many paths share each set of PAs on average
+        match self.num_pa_entries {
+            0 => 0.0,
+            entries => self.num_paths as f64 / entries as f64
+        }
+    }
+}
+
+// One row of a table snapshot, describing a destination and its bestpath
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SnapshotRow {
+    prefix: String,
+    peer: IpAddr,
+    next_hop: Option<IpAddr>,
+    local_pref: Option<u32>,
+    med: u32,
+    origin: u8,
+    as_path: String,
+    communities: Vec<u32>,
+    alternate_paths: usize
+}
+
+impl SnapshotRow {
+    const CSV_HEADER: &'static str = "prefix,peer,next_hop,local_pref,med,origin,as_path,communities,alternate_paths";
+
+    fn new(view: &RouteView) -> Option<Self> {
+        let best = view.bestpath()?;
+        let as_path = best
+            .pas()
+            .iter()
+            .find_map(|pa| pa.as_path())
+            .unwrap_or_default()
+            .iter()
+            .map(|seg| seg.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        Some(Self {
+            prefix: view.route().to_string(),
+            peer: best.peer_addr(),
+            next_hop: best.next_hop(),
+            local_pref: best.local_pref(),
+            med: best.med(),
+            origin: best.origin(),
+            as_path,
+            communities: best.communities(),
+            alternate_paths: view.paths().len() - 1
+        })
+    }
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+    pub fn peer(&self) -> IpAddr {
+        self.peer
+    }
+    pub fn next_hop(&self) -> Option<IpAddr> {
+        self.next_hop
+    }
+    pub fn local_pref(&self) -> Option<u32> {
+        self.local_pref
+    }
+    pub fn med(&self) -> u32 {
+        self.med
+    }
+    pub fn origin(&self) -> u8 {
+        self.origin
+    }
+    pub fn as_path(&self) -> &str {
+        &self.as_path
+    }
+    pub fn communities(&self) -> &[u32] {
+        self.communities.as_slice()
+    }
+    pub fn alternate_paths(&self) -> usize {
+        self.alternate_paths
+    }
+    fn to_csv(&self) -> String {
+        // None of the fields can contain a comma, so no quoting is needed
+        let opt = |val: Option<String>| val.unwrap_or_default();
+        let communities = self.communities
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        format!(
+            "{},{},{},{},{},{},{},{},{}",
+            self.prefix,
+            self.peer,
+            opt(self.next_hop.map(|nh| nh.to_string())),
+            opt(self.local_pref.map(|lp| lp.to_string())),
+            self.med,
+            self.origin,
+            self.as_path,
+            communities,
+            self.alternate_paths
+        )
+    }
+}
+
+// Routes to originate locally ("network" statements) along with the attributes to originate them with.
+// Paths get ORIGIN (IGP unless changed), an empty AS_PATH and optionally a MED and COMMUNITIES.
+#[derive(Clone, Debug)]
+pub struct LocalRoutes {
+    routes: Vec<Route>,
+    origin: OriginValue,
+    med: Option<u32>,
+    communities: Vec<u32>
+}
+
+impl LocalRoutes {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self {
+            routes,
+            origin: OriginValue::Igp,
+            med: None,
+            communities: Vec::new()
+        }
+    }
+    pub fn origin(mut self, origin: OriginValue) -> Self {
+        self.origin = origin;
+        self
+    }
+    pub fn med(mut self, med: u32) -> Self {
+        self.med = Some(med);
+        self
+    }
+    pub fn communities(mut self, communities: Vec<u32>) -> Self {
+        self.communities = communities;
+        self
+    }
+    pub fn for_afi(&self, afi: Afi) -> Option<LocalRoutes> {
+        // The routes of the address family only, for the table of that family
+        let routes: Vec<Route> = self.routes
+            .iter()
+            .filter(|route| matches!((afi, route.prefix()), (Afi::Ipv4, IpAddr::V4(_)) | (Afi::Ipv6, IpAddr::V6(_))))
+            .cloned()
+            .collect();
+        match routes.is_empty() {
+            true => None,
+            false => Some(Self { routes, ..self.clone() })
+        }
+    }
+    pub fn path_attrs(&self) -> Vec<PathAttr> {
+        let mut pas = vec![
+            PathAttrBuilder::<Origin>::new().origin(self.origin.clone()).build().expect("ORIGIN value was supplied"),
+            PathAttrBuilder::<AsPath>::new().as_segments(Vec::new()).build().expect("Empty AS_PATH is valid"),
+        ];
+        if let Some(med) = self.med {
+            pas.push(PathAttrBuilder::<Med>::new().metric(med).build().expect("MED value was supplied"));
+        }
+        if !self.communities.is_empty() {
+            pas.push(
+                PathAttrBuilder::<Communities>::new()
+                .communities(self.communities.clone())
+                .build()
+                .expect("Communities were supplied")
+            );
+        }
+        pas
+    }
+    fn to_received(&self, afi: Afi, routes: Option<Vec<Route>>, withdrawn_routes: Option<Vec<Route>>) -> ReceivedRoutes {
+        let mut payload = ReceivedRoutes::new(
+            LOCAL_PEER_ID,
+            LOCAL_PEER_ADDR,
+            0,
+            None,
+            0,
+            self.origin.clone(),
+            self.med.unwrap_or(0),
+            RouteSource::Local,
+            0,
+            self.path_attrs(),
+            routes,
+            withdrawn_routes,
+            afi,
+            Safi::Unicast
+        );
+        payload.set_weight(LOCAL_WEIGHT);
+        payload
+    }
+}
+
+// Emitted by a BgpTable walk whenever the bestpath of a destination changes, or with multipath, the set of
+// paths it's installed with. The set holds new and any paths tied with it, best first.
+#[derive(Clone, Debug)]
+pub(crate) enum BestpathEvent {
+    // New destination
+    Added { route: Route, new: Arc<PathAttributeTableEntry>, multipath: Vec<Arc<PathAttributeTableEntry>> },
+    Changed {
+        route: Route,
+        old: Arc<PathAttributeTableEntry>,
+        new: Arc<PathAttributeTableEntry>,
+        multipath: Vec<Arc<PathAttributeTableEntry>>
+    },
+    // Last path to the destination was removed
+    Withdrawn { route: Route, old: Arc<PathAttributeTableEntry> }
+}
+
+impl BestpathEvent {
+    pub fn route(&self) -> &Route {
+        match self {
+            BestpathEvent::Added { route, .. } => route,
+            BestpathEvent::Changed { route, .. } => route,
+            BestpathEvent::Withdrawn { route, .. } => route
+        }
+    }
+}
+
+// Anything that wants to hear about bestpath changes. Implemented for closures. Subscribers move
+// with the table, so they must be Send.
+pub(crate) trait BestpathSubscriber: Send {
+    fn notify(&mut self, event: &BestpathEvent);
+}
+
+impl<F: FnMut(&BestpathEvent) + Send> BestpathSubscriber for F {
+    fn notify(&mut self, event: &BestpathEvent) {
+        self(event)
+    }
+}
+
+// Generic over AFI (v4/v6), see TableAfi.
+// TO-DO: Think about how aggregation can be implemented. Maybe add a suppressed field in BGP Table Entry?
+// Could potentially create a radix tree from all the destinations and use this to determine which should be suppressed?
+pub(crate) struct BgpTable<A: TableAfi> {
+    table: RibMap<A::Key, BgpTableEntry>,
+    // Index over the table keys for longest prefix match and covering/covered queries
+    index: PrefixTrie<A::Key>,
+    table_version: usize,
+    // Version at which each destination was removed, so changes_since() can report withdrawals
+    withdrawn_versions: HashMap<A::Key, usize>,
+    pa_table: PathAttributeTable,
+    dup_detector: Option<DuplicateDetector>,
+    // Maximum number of equal cost paths returned by bestpaths(), 1 disables multipath
+    max_paths: usize,
+    subscribers: Vec<Box<dyn BestpathSubscriber>>,
+    config: DecisionConfig,
+    // Validated ROAs, paths are only validated if these are loaded
+    roas: Option<RoaTable>,
+    // Our AS, the origin of locally originated paths during validation
+    local_as: u16,
+    // Communities exported paths are tagged with based on their validation state
+    rpki_tags: Option<RpkiTags>,
+    igp_resolver: Option<Box<dyn IgpResolver>>,
+    nh_view: Option<Box<dyn NextHopView>>,
+    // Paths whose next hop is unreachable, kept out of the Decision Process until it is reachable again
+    unresolved: HashMap<A::Key, Vec<Arc<PathAttributeTableEntry>>>,
+    // Routes retained from peers that restarted gracefully (RFC 4724), along with the peer's BGP Identifier.
+    // A route is no longer stale once the peer announces or withdraws it again.
+    stale: HashMap<IpAddr, (Ipv4Addr, HashSet<Route>)>,
+    // Time for duplicate detection and prefer-oldest
+    clock: Arc<dyn Clock>,
+}
+impl<A: TableAfi> BgpTable<A> {
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn subscribe(&mut self, mut subscriber: Box<dyn BestpathSubscriber>) {
+        // The subscriber first hears about every destination already in the table
+        self.table
+        .iter()
+        .map(|(key, entry)| BestpathEvent::Added {
+            route: A::key_route(*key),
+            new: Arc::clone(entry.bestpath()),
+            multipath: entry.multipath(self.max_paths)
+        })
+        .for_each(|event| subscriber.notify(&event));
+        self.subscribers.push(subscriber);
+    }
+
+    fn publish(&mut self, events: Vec<BestpathEvent>) {
+        events
+        .iter()
+        .for_each(|event| self.subscribers.iter_mut().for_each(|sub| sub.notify(event)));
+    }
+
+    pub fn set_max_paths(&mut self, max_paths: usize) {
+        self.max_paths = max_paths.max(1);
+    }
+
+    pub fn max_paths(&self) -> usize {
+        self.max_paths
+    }
+
+    pub fn enable_duplicate_detection(&mut self, window: Duration, suppress: bool) {
+        self.dup_detector = Some(DuplicateDetector::new(window, suppress));
+    }
+
+    pub fn duplicate_detector(&self) -> Option<&DuplicateDetector> {
+        self.dup_detector.as_ref()
+    }
+
+    pub fn duplicate_detector_mut(&mut self) -> Option<&mut DuplicateDetector> {
+        self.dup_detector.as_mut()
+    }
+
+    pub fn increment_version(&mut self) {
+        self.table_version += 1;
+    }
+
+    pub fn version(&self) -> usize {
+        self.table_version
+    }
+    
+    pub fn num_paths(&self) -> usize {
+        // Returns number of PATHs in the BGP table, not number of destinations
+        self.table
+        .iter()
+        .map(|(_, entry)| entry.len())
+        .sum()
+    }
+    
+    pub fn num_destinations(&self) -> usize {
+        // Returns number of destinations in the BGP table
+        self.table.len()
+    }
+
+    pub fn num_pa_entries(&self) -> usize {
+        self.pa_table.len()
+    }
+
+    pub fn sweep_pa_table(&mut self) {
+        // Drops every PA entry no path points to. Walks only check the entries they touched.
+        self.pa_table.remove_stale();
+    }
+
+    pub fn num_unresolved(&self) -> usize {
+        // Returns number of paths parked because their next hop is unreachable
+        self.unresolved
+        .values()
+        .map(|paths| paths.len())
+        .sum()
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        // Walks the table to estimate where memory goes. This is O(n), not meant to be called per walk.
+        let prefix_map_bytes = self.table.capacity()
+            * (mem::size_of::<A::Key>() + mem::size_of::<BgpTableEntry>());
+        let path_heap_bytes = self.table
+            .values()
+            // Inline paths are already counted with the entry
+            .filter(|entry| entry.paths.spilled())
+            .map(|entry| entry.paths.capacity() * mem::size_of::<Arc<PathAttributeTableEntry>>())
+            .sum();
+        let pa_table_bytes = self.pa_table.table.capacity() * mem::size_of::<Arc<PathAttributeTableEntry>>()
+            + self.pa_table.table
+                .iter()
+                .map(|entry| {
+                    // Arc header (strong and weak counts) plus the entry and its PAs
+                    2 * mem::size_of::<usize>()
+                    + mem::size_of::<PathAttributeTableEntry>()
+                    + entry.raw_path_attrs.capacity() * mem::size_of::<PathAttr>()
+                    + entry.raw_path_attrs.iter().map(|pa| pa.attr_value().len()).sum::<usize>()
+                })
+                .sum::<usize>();
+        MemoryStats {
+            prefix_map_bytes,
+            path_heap_bytes,
+            pa_table_bytes,
+            index_bytes: self.index.memory_bytes(),
+            num_paths: self.num_paths(),
+            num_pa_entries: self.num_pa_entries()
+        }
+    }
+
+    pub fn load_roas(&mut self, roas: RoaTable, local_as: u16) {
+        // Replaces the ROA set used to validate paths. Paths already in the table keep the
+        // state they were installed with until they are re-validated, see revalidate().
+        self.roas = Some(roas);
+        self.local_as = local_as;
+    }
+
+    pub fn clear_roas(&mut self) {
+        self.roas = None;
+    }
+
+    pub fn roas(&self) -> Option<&RoaTable> {
+        self.roas.as_ref()
+    }
+
+    pub fn rpki_tags(&self) -> Option<&RpkiTags> {
+        self.rpki_tags.as_ref()
+    }
+    pub fn set_rpki_tags(&mut self, tags: Option<RpkiTags>) {
+        self.rpki_tags = tags;
+    }
+
+    pub fn set_local_as(&mut self, local_as: u16) {
+        self.local_as = local_as;
+    }
+
+}  
+impl<A: TableAfi> BgpTable<A> {
+    pub fn new() -> Self {
+        Self {
+            table: RibMap::new(),
+            index: PrefixTrie::new(),
+            table_version: 0,
+            withdrawn_versions: HashMap::new(),
+            pa_table: PathAttributeTable::new(),
+            dup_detector: None,
+            max_paths: 1,
+            subscribers: Vec::new(),
+            config: DecisionConfig::default(),
+            roas: None,
+            local_as: 0,
+            rpki_tags: None,
+            igp_resolver: None,
+            nh_view: None,
+            unresolved: HashMap::new(),
+            stale: HashMap::new(),
+            clock: Arc::new(TokioClock)
+        }
+    }
+
+    pub fn with_config(config: DecisionConfig) -> Self {
+        Self {
+            max_paths: config.max_paths.max(1),
+            config,
+            ..Self::new()
+        }
+    }
+    
+    pub fn walk(&mut self, payload: ReceivedRoutes) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Inserts (and/or removes) paths received in an Update message to/from the BGP table.
+        // The function returns routes that can be withdrawn along with a container holding all
+        // the Nlri that would need to be advertised using different Update messages, based on changes
+        // to the BGP table.
+        let _span = debug_span!("table_walk", peer = %payload.peer_addr()).entered();
+        let mut pass = WalkPass::new(self.table_version + 1);
+        self.walk_payload(payload, &mut pass);
+        self.finish_walk(pass)
+    }
+
+    pub fn walk_withdrawn(&mut self, withdrawn: WithdrawnRoutes) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Removes the peer's paths to the withdrawn routes, same results as walk()
+        let _span = debug_span!("table_walk", peer = %withdrawn.peer_addr()).entered();
+        let mut pass = WalkPass::new(self.table_version + 1);
+        self.walk_withdrawal(&withdrawn, &mut pass);
+        self.finish_walk(pass)
+    }
+
+    pub fn walk_batch(&mut self, payloads: Vec<ReceivedRoutes>) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Walks a burst of payloads (e.g. a peer sending its full table) in a single pass. Only the net
+        // change per destination over the whole batch is returned: a route announced then withdrawn within
+        // the batch is never advertised, and a destination whose bestpath ends up where it started isn't
+        // either. The PA table is only swept and the table version only bumped once, at the end.
+        let _span = debug_span!("table_walk", payloads = payloads.len()).entered();
+        let mut pass = WalkPass::new(self.table_version + 1);
+        // Bestpath of every destination the batch touches, as it was before the batch
+        let mut before: HashMap<A::Key, Option<Arc<PathAttributeTableEntry>>> = HashMap::new();
+        for payload in payloads {
+            payload
+            .routes()
+            .into_iter()
+            .chain(payload.withdrawn_routes())
+            .flatten()
+            .filter_map(|dest| A::from_route(&dest).map(|prefix| prefix.key(dest.prefix_len())))
+            .for_each(|key| {
+                _ = before
+                    .entry(key)
+                    .or_insert_with(|| self.table.get(&key).map(|entry| Arc::clone(entry.bestpath())));
+            });
+            self.walk_payload(payload, &mut pass);
+        }
+
+        // Replace the per-message results with the net changes
+        pass.adv_routes = AdvertisedRoutes::new();
+        pass.removed_routes.clear();
+        for (key, old_best) in before {
+            match (self.table.get(&key), old_best) {
+                (Some(entry), Some(old_best)) if Arc::ptr_eq(&old_best, entry.bestpath()) => (),
+                (Some(entry), _) => pass.adv_routes.insert(entry.bestpath(), A::key_route(key)),
+                (None, Some(_)) => pass.removed_routes.push(A::key_route(key)),
+                (None, None) => ()
+            }
+        }
+        self.finish_walk(pass)
+    }
+
+    fn walk_payload(&mut self, payload: ReceivedRoutes, pass: &mut WalkPass<A>) {
+        // Validates the payload's routes against the loaded ROAs (if any) before walking them into the table.
+        // The validation state is part of the path, so the payload is split into one walk per state.
+        // Invalid routes are treated as withdrawn if the policy drops them.
+        let (roas, routes) = match (self.roas.as_ref(), payload.routes()) {
+            (Some(roas), Some(routes)) => (roas, routes),
+            _ => return self.walk_validated(payload, pass)
+        };
+        let origin = origin_as(payload.path_attrs_ref(), self.local_as);
+        let drop_invalid = self.config.rpki_policy == RpkiPolicy::DropInvalid;
+        let mut withdrawn = payload.withdrawn_routes().unwrap_or_default();
+        let mut by_state: HashMap<RpkiState, Vec<Route>> = HashMap::new();
+        for route in routes {
+            match roas.validate(&route, origin) {
+                RpkiState::Invalid if drop_invalid => withdrawn.push(route),
+                state => by_state.entry(state).or_default().push(route)
+            }
+        }
+        let mut payloads: Vec<ReceivedRoutes> = by_state
+            .into_iter()
+            .map(|(state, routes)| {
+                let mut validated = payload.with_routes(payload.path_attrs(), Some(routes), None);
+                validated.set_rpki_state(state);
+                validated
+            })
+            .collect();
+        if !withdrawn.is_empty() {
+            payloads.push(payload.with_routes(payload.path_attrs(), None, Some(withdrawn)));
+        }
+        payloads.into_iter().for_each(|validated| self.walk_validated(validated, pass));
+    }
+
+    fn walk_validated(&mut self, payload: ReceivedRoutes, pass: &mut WalkPass<A>) {
+        // Walks a single payload into the table, accumulating the results in the pass.
+
+        // Payloads for other address families belong to a different table.
+        if payload.family() != (A::AFI, A::SAFI) {
+            return;
+        }
+        // The withdrawals go first, so a prefix that's also announced ends up announced (RFC 4271, Section 4.3)
+        if let Some(withdrawn) = payload.withdrawn() {
+            self.walk_withdrawal(&withdrawn, pass);
+        }
+        // Nothing to intern for a pure withdrawal
+        let new_paths = match payload.routes() {
+            Some(routes) => routes,
+            None => return
+        };
+
+        let now = self.clock.now();
+        let mut ddata = DecisionProcessData::new(&payload, &self.config, now);
+        if let Some(cost) = self.igp_resolver.as_ref().zip(payload.next_hop()).and_then(|(r, nh)| r.resolve(nh)) {
+            ddata.igp_cost = cost;
+        }
+        // Destinations whose bestpath changes are stamped with the version this walk produces
+        let next_version = pass.next_version;
+        let WalkPass { adv_routes, events, changed, .. } = pass;
+        // Bestpath changes are only tracked if someone is listening
+        let publish = !self.subscribers.is_empty();
+
+        // Pre-emptively update the PAT and get the ref necessary to update BGP
+        // table entries
+        let pat_entry = PathAttributeTableEntry::new(ddata, payload.path_attrs());
+        // Owned, the PA table is still needed to release the paths this one replaces
+        let pat_entry_ref = &Arc::clone(self.pa_table.insert(pat_entry));
+        // Paths whose next hop can't be reached are parked instead of installed, see next_hops_changed()
+        let resolvable = nh_resolvable(self.nh_view.as_deref(), pat_entry_ref);
+        
+        // Needed for duplicate detection
+        let peer_addr = payload.peer_addr();
+        let peer_id = payload.peer_id();
+        // Anything the peer sends again is fresh
+        if let Some((_, stale)) = self.stale.get_mut(&peer_addr) {
+            new_paths.iter().for_each(|route| _ = stale.remove(route));
+        }
+        let pas_hash = {
+            let mut hasher = DefaultHasher::new();
+            pat_entry_ref.pas().hash(&mut hasher);
+            hasher.finish()
+        };
+
+        if resolvable {
+            new_paths
+            .iter()
+            .filter_map(|dest| A::from_route(dest).map(|prefix| (dest, prefix))) // only allow this AFI
+            .for_each(|(dest, prefix)| {
+                // Exact duplicates don't change the table, so their re-advertisement can be suppressed
+                let suppressed = match self.dup_detector.as_mut() {
+                    Some(detector) => detector.observe(peer_addr, dest, pas_hash, now) && detector.suppress(),
+                    None => false
+                };
+                // The new path replaces whatever the peer had parked for the destination
+                unpark::<A>(&mut self.unresolved, prefix.key(dest.prefix_len()), peer_id, &mut self.pa_table);
+                match self.table.get_mut(&prefix.key(dest.prefix_len())) {
+                    // If the BGP table entry exists, add path to it
+                    Some(bgp_table_entry) => {
+                        let old_best = Arc::clone(bgp_table_entry.bestpath());
+                        let old_multipath = bgp_table_entry.multipath(self.max_paths);
+                        // Implicit withdraw: a path from the same peer is replaced, not added alongside.
+                        // RFC 4271, Pg. 20
+                        bgp_table_entry.remove(peer_id, &mut self.pa_table);
+                        bgp_table_entry.insert(pat_entry_ref);
+                        // If the new entry is the bestpath (or replacing the peer's old path moved the bestpath
+                        // elsewhere), add it to the container to be advertised. Entry API is amazing!
+                        let best_changed = !Arc::ptr_eq(&old_best, bgp_table_entry.bestpath());
+                        if (bgp_table_entry.bestpath() == pat_entry_ref && !suppressed) || best_changed {
+                            adv_routes.entry(bgp_table_entry.bestpath(), prefix, dest.prefix_len());
+                        }
+                        if !Arc::ptr_eq(&old_best, bgp_table_entry.bestpath()) {
+                            bgp_table_entry.version = next_version;
+                            *changed = true;
+                        }
+                        let multipath = bgp_table_entry.multipath(self.max_paths);
+                        if publish && (best_changed || multipath != old_multipath) {
+                            events.push(BestpathEvent::Changed {
+                                route: dest.clone(),
+                                old: old_best,
+                                new: Arc::clone(bgp_table_entry.bestpath()),
+                                multipath
+                            });
+                        }
+                    },
+                    // Otherwise, create a new entry and insert the ref. Add to container
+                    // to be advertised.
+                    None => {
+                        let mut entry = BgpTableEntry::new(pat_entry_ref);
+                        entry.version = next_version;
+                        *changed = true;
+                        self.table.insert(prefix.key(dest.prefix_len()), entry);
+                        _ = self.withdrawn_versions.remove(&prefix.key(dest.prefix_len()));
+                        self.index.insert(prefix.to_bits(), dest.prefix_len(), prefix.key(dest.prefix_len()));
+                        adv_routes.entry(pat_entry_ref, prefix, dest.prefix_len());
+                        if publish {
+                            events.push(BestpathEvent::Added {
+                                route: dest.clone(),
+                                new: Arc::clone(pat_entry_ref),
+                                multipath: vec![Arc::clone(pat_entry_ref)]
+                            });
+                        }
+                    }
+                }
+            })
+        }
+
+        // An announcement with an unreachable next hop still replaces the peer's current path, so those
+        // routes are withdrawn before being parked.
+        let parked_paths = match resolvable {
+            true => Vec::new(),
+            false => new_paths
+        };
+        self.remove_paths(peer_addr, peer_id, &parked_paths, pass);
+
+        parked_paths
+        .iter()
+        .filter_map(|dest| A::from_route(dest).map(|prefix| (dest, prefix))) // only allow this AFI
+        .for_each(|(dest, prefix)| {
+            let parked = self.unresolved.entry(prefix.key(dest.prefix_len())).or_default();
+            if !parked.iter().any(|path| path == pat_entry_ref) {
+                parked.push(Arc::clone(pat_entry_ref));
+            }
+        });
+    }
+
+    fn walk_withdrawal(&mut self, withdrawn: &WithdrawnRoutes, pass: &mut WalkPass<A>) {
+        // Removes the peer's paths to the withdrawn destinations, the PA table is only told what's released
+        if withdrawn.family() != (A::AFI, A::SAFI) {
+            return;
+        }
+        if let Some((_, stale)) = self.stale.get_mut(&withdrawn.peer_addr()) {
+            withdrawn.routes().iter().for_each(|route| _ = stale.remove(route));
+        }
+        self.remove_paths(withdrawn.peer_addr(), withdrawn.peer_id(), withdrawn.routes(), pass);
+    }
+
+    fn remove_paths(&mut self, peer_addr: IpAddr, peer_id: Ipv4Addr, routes: &[Route], pass: &mut WalkPass<A>) {
+        // Removes the peer's paths (parked or installed) to the destinations, accumulating the results in the pass
+        let next_version = pass.next_version;
+        let WalkPass { adv_routes, removed_routes, events, changed, .. } = pass;
+        let publish = !self.subscribers.is_empty();
+        routes
+        .iter()
+        .filter_map(|dest| A::from_route(dest).map(|prefix| (dest, prefix))) // Only allow this AFI
+        .for_each(|(dest, prefix)| {
+            if let Some(detector) = self.dup_detector.as_mut() {
+                detector.forget(peer_addr, dest);
+            }
+            unpark::<A>(&mut self.unresolved, prefix.key(dest.prefix_len()), peer_id, &mut self.pa_table);
+            // Nothing to do unless the destination is in the table
+            if let Some(bgp_table_entry) = self.table.get_mut(&prefix.key(dest.prefix_len())) {
+                // Check to see if path to be removed is currently the bestpath. RFC 4271, Pg. 20
+                // states that only need to match on peer.
+                let was_best = bgp_table_entry.bestpath().peer_id() == peer_id;
+                let old_best = Arc::clone(bgp_table_entry.bestpath());
+                let old_multipath = bgp_table_entry.multipath(self.max_paths);
+                // Remove the path
+                bgp_table_entry.remove(peer_id, &mut self.pa_table);
+                // If resulting BGP table entry is empty, remove from table and add destination
+                // to routes to be withdrawn from peers.
+                if bgp_table_entry.is_empty() {
+                    _ = self.table.remove(&prefix.key(dest.prefix_len()));
+                    _ = self.index.remove(prefix.to_bits(), dest.prefix_len());
+                    _ = self.withdrawn_versions.insert(prefix.key(dest.prefix_len()), next_version);
+                    *changed = true;
+                    removed_routes.push(Route::new(dest.prefix_len(), prefix.to_ip()));
+                    if publish {
+                        events.push(BestpathEvent::Withdrawn { route: dest.clone(), old: old_best });
+                    }
+                } else {
+                    // Otherwise, if new bestpath, add to adv routes container
+                    if was_best {
+                        bgp_table_entry.version = next_version;
+                        *changed = true;
+                        adv_routes.entry(bgp_table_entry.bestpath(), prefix, dest.prefix_len());
+                    }
+                    let multipath = bgp_table_entry.multipath(self.max_paths);
+                    if publish && (was_best || multipath != old_multipath) {
+                        events.push(BestpathEvent::Changed {
+                            route: dest.clone(),
+                            old: old_best,
+                            new: Arc::clone(bgp_table_entry.bestpath()),
+                            multipath
+                        });
+                    }
+                }
+            }
+        });
+    }
+
+    fn finish_walk(&mut self, pass: WalkPass<A>) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Events hold refs to PAT entries, so publish (and drop) them before cleaning up the PA table.
+        self.publish(pass.events);
+        self.pa_table.remove_released();
+
+        // Increment the table version if the table changed (bestpaths changed and/or destinations removed.)
+        if pass.changed || !pass.removed_routes.is_empty() || !pass.adv_routes.is_empty() {
+            self.increment_version();
+        }
+        debug!(
+            version = self.table_version,
+            withdrawn = pass.removed_routes.len(),
+            advertised_groups = pass.adv_routes.len(),
+            "walk done"
+        );
+
+        (pass.removed_routes, pass.adv_routes)
+    }
+
+    pub fn apply_vrp_update(&mut self, update: &VrpUpdate) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Applies VRP changes (e.g. from an RTR session) to the loaded ROAs and re-validates every
+        // destination the changed ROAs cover. A reset re-validates the whole table.
+        update.apply(self.roas.get_or_insert_with(RoaTable::new));
+        let mut affected: Vec<Route> = match update.is_reset() {
+            true => self.table.keys().map(|key| A::key_route(*key)).collect(),
+            false => update
+                .announced()
+                .iter()
+                .chain(update.withdrawn().iter())
+                .flat_map(|roa| self.covered_routes(roa.prefix()))
+                .collect()
+        };
+        affected.sort();
+        affected.dedup();
+        self.revalidate(&affected)
+    }
+
+    pub fn revalidate_all(&mut self) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Re-validates every destination, e.g. once a new set of ROAs was loaded
+        let routes: Vec<Route> = self.table.keys().map(|key| A::key_route(*key)).collect();
+        self.revalidate(&routes)
+    }
+
+    pub fn revalidate(&mut self, routes: &[Route]) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Re-computes the validation state of every path to the given destinations. Paths whose state
+        // changed are re-installed with the new state, which can change the bestpath. Invalid paths are
+        // removed if the policy drops them; they only come back once they are received (or replayed) again.
+        let drop_invalid = self.config.rpki_policy == RpkiPolicy::DropInvalid;
+        let roas = self.roas.take();
+        let local_as = self.local_as;
+        let out = self.rework_paths(routes, |route, path| {
+            let state = match roas.as_ref() {
+                Some(roas) => roas.validate(route, origin_as(path.pas(), local_as)),
+                None => RpkiState::NotFound
+            };
+            match (state == path.rpki_state(), state == RpkiState::Invalid && drop_invalid) {
+                (true, _) => PathChange::Keep,
+                (false, true) => PathChange::Remove,
+                (false, false) => {
+                    let mut decision_data = path.decision_data.clone();
+                    decision_data.rpki_state = state;
+                    PathChange::Replace(decision_data)
+                }
+            }
+        });
+        self.roas = roas;
+        out
+    }
+
+    pub fn set_igp_resolver(&mut self, resolver: Option<Box<dyn IgpResolver>>) {
+        // Paths walked in from now on get their IGP cost from the resolver, see refresh_igp_costs()
+        // for paths already in the table.
+        self.igp_resolver = resolver;
+    }
+
+    pub fn refresh_igp_costs(&mut self, next_hops: &[IpAddr]) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Re-resolves the IGP cost of every path using one of the given next hops, e.g. after the IGP
+        // signalled a metric change. An empty slice refreshes every path in the table.
+        let resolver = match self.igp_resolver.take() {
+            Some(resolver) => resolver,
+            None => return (Vec::new(), AdvertisedRoutes::new())
+        };
+        let routes: Vec<Route> = self.table
+            .iter()
+            .filter(|(_, entry)| entry.paths.iter().any(|path| {
+                path.next_hop().is_some_and(|nh| next_hops.is_empty() || next_hops.contains(&nh))
+            }))
+            .map(|(key, _)| A::key_route(*key))
+            .collect();
+        let out = self.rework_paths(&routes, |_, path| {
+            let cost = path
+                .next_hop()
+                .filter(|nh| next_hops.is_empty() || next_hops.contains(nh))
+                .and_then(|nh| resolver.resolve(nh));
+            match cost {
+                Some(cost) if cost != path.decision_data.igp_cost => {
+                    let mut decision_data = path.decision_data.clone();
+                    decision_data.igp_cost = cost;
+                    PathChange::Replace(decision_data)
+                },
+                _ => PathChange::Keep
+            }
+        });
+        self.igp_resolver = Some(resolver);
+        out
+    }
+
+    pub fn set_next_hop_view(&mut self, view: Option<Box<dyn NextHopView>>) {
+        // Paths walked in from now on are checked against the view. Call next_hops_changed() with an
+        // empty slice to re-check the paths already in the table.
+        self.nh_view = view;
+    }
+
+    pub fn next_hops_changed(&mut self, next_hops: &[IpAddr]) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Re-runs the Decision Process for every destination with a path (installed or parked) using one
+        // of the given next hops, e.g. after the routing table or ARP cache signalled a change. Paths whose
+        // next hop became unreachable are parked and parked paths whose next hop is reachable again are
+        // re-installed. An empty slice re-checks every path in the table.
+        let uses_next_hop = |path: &Arc<PathAttributeTableEntry>| {
+            path.next_hop().is_some_and(|nh| next_hops.is_empty() || next_hops.contains(&nh))
+        };
+        let mut routes: Vec<Route> = self.table
+            .iter()
+            .filter(|(_, entry)| entry.paths.iter().any(&uses_next_hop))
+            .map(|(key, _)| key)
+            .chain(self.unresolved
+                .iter()
+                .filter(|(_, paths)| paths.iter().any(uses_next_hop))
+                .map(|(key, _)| key)
+            )
+            .map(|key| A::key_route(*key))
+            .collect();
+        routes.sort();
+        routes.dedup();
+        // Reachability is always re-checked while reworking, so nothing else needs to change
+        self.rework_paths(&routes, |_, _| PathChange::Keep)
+    }
+
+    fn rework_paths<F>(&mut self, routes: &[Route], mut f: F) -> (Vec<Route>, AdvertisedRoutes<A>)
+    where
+        F: FnMut(&Route, &PathAttributeTableEntry) -> PathChange
+    {
+        // Runs every path to the given destinations through f, re-installing paths whose decision data
+        // changed and removing the ones f drops. Next hop reachability is re-checked along the way, so
+        // parked paths can come back and installed ones can be parked. Bestpath changes are handled
+        // the same way as in a walk.
+        let mut adv_routes: AdvertisedRoutes<A> = AdvertisedRoutes::new();
+        let mut removed_routes: Vec<Route> = Vec::new();
+        let publish = !self.subscribers.is_empty();
+        let mut events: Vec<BestpathEvent> = Vec::new();
+        let next_version = self.table_version + 1;
+
+        for route in routes {
+            let prefix = match A::from_route(route) {
+                Some(prefix) => prefix,
+                None => continue
+            };
+            let key = prefix.key(route.prefix_len());
+            let view = self.nh_view.as_deref();
+            let old_best = self.table.get(&key).map(|entry| Arc::clone(entry.bestpath()));
+            let old_multipath = self.table.get(&key).map(|entry| entry.multipath(self.max_paths)).unwrap_or_default();
+            let installed: Vec<Arc<PathAttributeTableEntry>> = self.table
+                .get(&key)
+                .map(|entry| entry.paths.to_vec())
+                .unwrap_or_default();
+            let mut paths: Vec<Arc<PathAttributeTableEntry>> = Vec::new();
+            let mut parked: Vec<Arc<PathAttributeTableEntry>> = Vec::new();
+            for path in installed.into_iter().chain(self.unresolved.remove(&key).unwrap_or_default()) {
+                if !nh_resolvable(view, &path) {
+                    parked.push(path);
+                    continue;
+                }
+                match f(route, &path) {
+                    PathChange::Keep => paths.push(path),
+                    PathChange::Remove => self.pa_table.release(path),
+                    PathChange::Replace(decision_data) => {
+                        let new_path = self.pa_table.insert(PathAttributeTableEntry {
+                            decision_data,
+                            raw_path_attrs: path.raw_path_attrs.clone()
+                        });
+                        paths.push(Arc::clone(new_path));
+                        self.pa_table.release(path);
+                    }
+                }
+            }
+            if !parked.is_empty() {
+                self.unresolved.insert(key, parked);
+            }
+            match (paths.is_empty(), self.table.contains_key(&key)) {
+                (true, true) => {
+                    _ = self.table.remove(&key);
+                    _ = self.index.remove(prefix.to_bits(), route.prefix_len());
+                    _ = self.withdrawn_versions.insert(key, next_version);
+                },
+                (false, false) => {
+                    self.table.insert(key, BgpTableEntry::new(&paths[0]));
+                    self.index.insert(prefix.to_bits(), route.prefix_len(), key);
+                    _ = self.withdrawn_versions.remove(&key);
+                },
+                _ => ()
+            }
+            let entry = match self.table.get_mut(&key) {
+                Some(entry) => entry,
+                None => {
+                    if let Some(old) = old_best {
+                        removed_routes.push(route.clone());
+                        if publish {
+                            events.push(BestpathEvent::Withdrawn { route: route.clone(), old });
+                        }
+                    }
+                    continue;
+                }
+            };
+            entry.set_paths(paths);
+            let best_changed = !old_best.as_ref().is_some_and(|old| Arc::ptr_eq(old, entry.bestpath()));
+            if best_changed {
+                entry.version = next_version;
+                adv_routes.entry(entry.bestpath(), prefix, route.prefix_len());
+            }
+            let multipath = entry.multipath(self.max_paths);
+            if publish && (best_changed || multipath != old_multipath) {
+                let new = Arc::clone(entry.bestpath());
+                events.push(match old_best {
+                    Some(old) => BestpathEvent::Changed { route: route.clone(), old, new, multipath },
+                    None => BestpathEvent::Added { route: route.clone(), new, multipath }
+                });
+            }
+        }
+        self.publish(events);
+        self.pa_table.remove_released();
+        if !removed_routes.is_empty() || !adv_routes.is_empty() {
+            self.increment_version();
+        }
+        (removed_routes, adv_routes)
+    }
+
+    pub fn originate(&mut self, local: LocalRoutes) -> AdvertisedRoutes<A> {
+        // Installs locally originated routes. Routes already originated are replaced, in the same walk
+        // like any peer's implicit withdraw.
+        self.walk(local.to_received(A::AFI, Some(local.routes.clone()), None)).1
+    }
+
+    pub fn withdraw_originated(&mut self, routes: Vec<Route>) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Removes locally originated routes, learned paths to the same destinations are untouched.
+        self.walk(LocalRoutes::new(Vec::new()).to_received(A::AFI, None, Some(routes)))
+    }
+
+    pub fn walk_export(&mut self, payload: ReceivedRoutes, peers: &[ExportPeer]) -> Vec<(IpAddr, Vec<Route>, Vec<Nlri>)> {
+        // Walks the payload into the table, then runs the bestpath changes through each peer's
+        // export rules. Returns, per peer, the routes to withdraw and the Nlri to advertise.
+        let (removed, adv_routes) = self.walk(payload);
+        peers
+        .iter()
+        .map(|peer| {
+            let (mut withdrawn, nlri) = adv_routes.export(peer, self.rpki_tags.as_ref());
+            withdrawn.extend_from_slice(removed.as_slice());
+            (peer.peer_addr(), withdrawn, nlri)
+        })
+        .collect()
+    }
+
+    pub fn export_bestpaths(&self, peer: &ExportPeer) -> Vec<Nlri> {
+        // Produces the full set of Nlri to send to a peer from the current bestpaths, e.g. when
+        // a session first comes up.
+        let mut adv_routes: AdvertisedRoutes<A> = AdvertisedRoutes::new();
+        self.table
+        .iter()
+        .for_each(|(key, entry)| adv_routes.insert(entry.bestpath(), A::key_route(*key)));
+        adv_routes.export(peer, self.rpki_tags.as_ref()).1
+    }
+
+    pub fn changes_since(&self, version: usize) -> TableDelta {
+        // Returns every destination whose bestpath changed (or that was removed) after the given version.
+        // Feeding the delta to an Adj-RIB-Out brings a peer that was synced at that version up to date.
+        let mut updated: AdvertisedRoutes<A> = AdvertisedRoutes::new();
+        self.table
+        .iter()
+        .filter(|(_, entry)| entry.version > version)
+        .for_each(|(key, entry)| updated.insert(entry.bestpath(), A::key_route(*key)));
+        let withdrawn = self.withdrawn_versions
+            .iter()
+            .filter(|(_, removed)| **removed > version)
+            .map(|(key, _)| A::key_route(*key))
+            .collect();
+        TableDelta {
+            version: self.table_version,
+            withdrawn,
+            updated: updated.to_nlri()
+        }
+    }
+
+    pub fn destination_version(&self, route: &Route) -> Option<usize> {
+        // Version at which the destination's bestpath last changed
+        let prefix = A::from_route(route)?;
+        self.table.get(&prefix.key(route.prefix_len())).map(|entry| entry.version)
+    }
+
+    pub fn prune_withdrawn(&mut self, version: usize) {
+        // Forgets withdrawals at or before the version, once every consumer has caught up past it.
+        self.withdrawn_versions.retain(|_, removed| *removed > version);
+    }
+
+    pub fn routes(&self) -> Vec<RouteView> {
+        // Every destination in the table with all of its paths, sorted by prefix.
+        self.filter_routes(|_| true)
+    }
+
+    pub fn route_view(&self, route: &Route) -> Option<RouteView> {
+        let prefix = A::from_route(route)?;
+        self.table
+        .get(&prefix.key(route.prefix_len()))
+        .map(|entry| RouteView::new(route.clone(), entry))
+    }
+
+    pub fn filter_routes<F: Fn(&PathView) -> bool>(&self, filter: F) -> Vec<RouteView> {
+        // Returns the destinations with at least one path passing the filter, keeping only those paths.
+        // The bestpath flag still reflects the full set of paths.
+        let mut views: Vec<RouteView> = self.table
+            .iter()
+            .map(|(key, entry)| RouteView::new(A::key_route(*key), entry))
+            .filter_map(|mut view| {
+                view.paths.retain(|path| filter(path));
+                match view.paths.is_empty() {
+                    true => None,
+                    false => Some(view)
+                }
+            })
+            .collect();
+        views.sort_by_key(|view| (view.route.prefix(), view.route.prefix_len()));
+        views
+    }
+
+    pub fn snapshot(&self) -> Vec<SnapshotRow> {
+        // Bestpath of every destination, sorted by prefix so two snapshots can be diffed.
+        self.routes()
+        .iter()
+        .filter_map(SnapshotRow::new)
+        .collect()
+    }
+
+    pub fn export(&self, format: SnapshotFormat) -> String {
+        // Serializes a snapshot of the table for offline analysis.
+        let rows = self.snapshot();
+        match format {
+            SnapshotFormat::Json => serde_json::to_string_pretty(&rows).expect("Snapshot rows are always serializable"),
+            SnapshotFormat::Csv => {
+                let mut out = String::from(SnapshotRow::CSV_HEADER);
+                rows
+                .iter()
+                .for_each(|row| {
+                    out.push('\n');
+                    out.push_str(&row.to_csv());
+                });
+                out.push('\n');
+                out
+            }
+        }
+    }
+
+    pub fn routes_from_peer(&self, peer: IpAddr) -> Vec<RouteView> {
+        self.filter_routes(|path| path.peer_addr() == peer)
+    }
+
+    pub fn mark_stale(&mut self, peer: IpAddr) -> usize {
+        // Keeps the peer's paths in the table when its session drops with Graceful Restart negotiated.
+        // Returns the number of routes that are now stale.
+        let views = self.routes_from_peer(peer);
+        let peer_id = match views.first().and_then(|view| view.paths().first()) {
+            Some(path) => path.peer_id(),
+            None => return self.stale_count(peer)
+        };
+        let (_, stale) = self.stale.entry(peer).or_insert_with(|| (peer_id, HashSet::new()));
+        stale.extend(views.into_iter().map(|view| view.route().clone()));
+        stale.len()
+    }
+
+    pub fn stale_count(&self, peer: IpAddr) -> usize {
+        self.stale.get(&peer).map(|(_, stale)| stale.len()).unwrap_or(0)
+    }
+
+    pub fn flush_stale(&mut self, peer: IpAddr) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Removes the peer's paths that weren't refreshed since they were marked stale, either because
+        // the peer sent End-of-RIB or because it didn't come back in time.
+        match self.stale.remove(&peer) {
+            Some((peer_id, stale)) => self.withdraw_peer_routes(peer, peer_id, stale.into_iter().collect()),
+            None => (Vec::new(), AdvertisedRoutes::new())
+        }
+    }
+
+    pub fn release_peer(&mut self, peer: IpAddr) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        // Removes every path learned from the peer, stale or not, once its session is gone for good.
+        self.stale.remove(&peer);
+        let views = self.routes_from_peer(peer);
+        let peer_id = match views.first().and_then(|view| view.paths().first()) {
+            Some(path) => path.peer_id(),
+            None => return (Vec::new(), AdvertisedRoutes::new())
+        };
+        let routes = views.into_iter().map(|view| view.route().clone()).collect();
+        self.withdraw_peer_routes(peer, peer_id, routes)
+    }
+
+    fn withdraw_peer_routes(&mut self, peer: IpAddr, peer_id: Ipv4Addr, routes: Vec<Route>) -> (Vec<Route>, AdvertisedRoutes<A>) {
+        self.walk_withdrawn(WithdrawnRoutes::new(peer_id, peer, routes, A::AFI, Safi::Unicast))
+    }
+
+    pub fn routes_with_community(&self, community: u32) -> Vec<RouteView> {
+        self.filter_routes(|path| path.communities().contains(&community))
+    }
+
+    pub fn lookup_lpm(&self, addr: A) -> Option<(Route, &PathAttributeTableEntry)> {
+        // Returns the most specific destination covering the address along with its bestpath.
+        self.index
+        .longest_match(addr.to_bits(), A::MAX_LEN)
+        .and_then(|(_, key)| self.table.get(key).map(|entry| (A::key_route(*key), entry.bestpath().as_ref())))
+    }
+
+    pub fn covered_routes(&self, route: &Route) -> Vec<Route> {
+        // Returns every destination in the table covered by the route (including the route itself).
+        match A::from_route(route) {
+            Some(prefix) => self.index
+                .covered(prefix.to_bits(), route.prefix_len())
+                .into_iter()
+                .map(|(_, _, key)| A::key_route(*key))
+                .collect(),
+            None => Vec::new()
+        }
+    }
+
+    pub fn covering_routes(&self, route: &Route) -> Vec<Route> {
+        // Returns every destination in the table that covers the route (including the route itself),
+        // least specific first.
+        match A::from_route(route) {
+            Some(prefix) => self.index
+                .covering(prefix.to_bits(), route.prefix_len())
+                .into_iter()
+                .map(|(_, key)| A::key_route(*key))
+                .collect(),
+            None => Vec::new()
+        }
+    }
+
+    pub fn bestpaths(&self, route: &Route) -> Vec<&PathAttributeTableEntry> {
+        // Returns the multipath set for the destination (up to max_paths paths tied through the
+        // IGP cost step), best first. Empty if the destination isn't in the table.
+        match A::from_route(route).and_then(|prefix| self.table.get(&prefix.key(route.prefix_len()))) {
+            Some(entry) => entry
+                .bestpaths(self.max_paths)
+                .into_iter()
+                .map(|path| path.as_ref())
+                .collect(),
+            None => Vec::new()
+        }
+    }
+
+    pub fn simulate_announce(&self, route: &Route, pas: Vec<PathAttr>, peers: &[ExportPeer]) -> AnnounceSimulation {
+        // Reports what each peer would be sent if the route were locally originated with the given PAs.
+        // Locally originated paths are always preferred, so the route would become the bestpath and
+        // only export policy decides what is sent. The table is only read, never modified.
+        let pas = canonicalize_attrs(pas);
+        let current_bestpath = A::from_route(route)
+            .and_then(|prefix| self.table.get(&prefix.key(route.prefix_len())))
+            .map(|entry| entry.bestpath().pas().to_vec());
+        let exports = peers
+            .iter()
+            .map(|peer| (peer.peer_addr(), peer.export(&pas, &PathSource::Local)))
+            .collect();
+
+        AnnounceSimulation {
+            current_bestpath,
+            exports
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use rand::{seq::SliceRandom, Rng};
+    use crate::{comms::MockReceivedRoutesBuilder, full_table::FullTableGenerator, message_types::{Route, MAX_MESSAGE_LEN}};
+
+    use super::*;
+
+
+    // Setup Functions
+    
+    fn prefers(best: &DecisionProcessData, candidate: &DecisionProcessData) -> bool {
+        // Runs the Decision Process over the two paths, whichever arrived first
+        let pick = |first: &DecisionProcessData, second: &DecisionProcessData| {
+            let paths = [first, second]
+                .map(|data| Arc::new(PathAttributeTableEntry::new(data.clone(), Vec::new())));
+            let mut candidates = multipath_candidates(&paths, vec![0, 1]);
+            tie_break(&paths, &mut candidates);
+            candidates[0]
+        };
+        pick(best, candidate) == 0 && pick(candidate, best) == 1
+    }
+    fn build_pa_entry(med_val: u32, origin: OriginValue) -> PathAttributeTableEntry {
+        let pa = PathAttrBuilder::<Med>::new().metric(med_val).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin.clone()).build().unwrap();
+        let mut raw_pas = vec![pa, pa2];
+        // Randomly shuffle the PA vector since it should be sorted deterministically by
+        // its generating function.
+        let mut rng = rand::thread_rng();
+        raw_pas.shuffle(&mut rng);
+
+        let ddata = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
+            local_pref: Some(100),
+            as_path_len: 1,
+            last_as: 65000,
+            origin: origin.into(),
+            med: med_val,
+            route_souce: RouteSource::Ebgp,
+            igp_cost: 0,
+            peer_addr: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+            peer_id: Ipv4Addr::new(192, 168, 1, 1)
+        };
+        PathAttributeTableEntry::new(ddata, raw_pas)
+    }
+
+    fn generate_routes_v6(num_routes: usize) -> Vec<Route> {
+        let mut rng = rand::thread_rng();
+        let c = |_| {
+                let addr = Ipv6Addr::new(0x2001,
+                             rng.gen_range(0..=0xffff),
+                             rng.gen_range(0..=0xffff),
+                             rng.gen_range(0..=0xffff),
+                             0, 0, 0, 0);
+                Route::new(rng.gen_range(16..=64), IpAddr::V6(addr))
+        };
+        (1..=num_routes).map(c).collect()
+    }
+
+    fn generate_routes_v4(num_routes: usize) -> Vec<Route> {
         let mut rng = rand::thread_rng();
         let c = |_| {
                 let addr = Ipv4Addr::new(rng.gen_range(1..=223),
@@ -476,6 +2499,10 @@ mod tests {
     fn decision_data_cmp_lp() {
         let ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 0,
@@ -487,6 +2514,10 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(100),
             as_path_len: 0,
             last_as: 0,
@@ -498,12 +2529,16 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_as_path_len() {
         let ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 5,
             last_as: 0,
@@ -515,6 +2550,10 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 10,
             last_as: 0,
@@ -526,12 +2565,16 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_origin() {
         let ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 0,
@@ -543,6 +2586,10 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: None,
             as_path_len: 0,
             last_as: 0,
@@ -554,12 +2601,16 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_med() {
         let ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -571,6 +2622,10 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -582,12 +2637,16 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_rte_src() {
         let ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -599,6 +2658,10 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -610,12 +2673,16 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_igp_cost() {
         let ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -627,6 +2694,10 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -638,13 +2709,17 @@ mod tests {
             peer_addr: IpAddr::V4(ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_peer_id() {
         let best_ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let cand_ip_addr = Ipv4Addr::new(192, 168, 2, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -656,6 +2731,10 @@ mod tests {
             peer_addr: IpAddr::V4(cand_ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -667,13 +2746,17 @@ mod tests {
             peer_addr: IpAddr::V4(cand_ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_peer_addr_v4() {
         let best_ip_addr = Ipv4Addr::new(192, 168, 1, 1);
         let cand_ip_addr = Ipv4Addr::new(192, 168, 2, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -685,6 +2768,10 @@ mod tests {
             peer_addr: IpAddr::V4(best_ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -696,7 +2783,7 @@ mod tests {
             peer_addr: IpAddr::V4(cand_ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
     #[test]
     fn decision_data_cmp_peer_addr_v6() {
@@ -704,6 +2791,10 @@ mod tests {
         let cand_ip_addr = Ipv6Addr::new(0, 0, 0, 0, 0x01, 0xffff, 0xffff, 0xffff);
         let peer_id = Ipv4Addr::new(192, 168, 1, 1);
         let best = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -715,6 +2806,10 @@ mod tests {
             peer_addr: IpAddr::V6(best_ip_addr.clone())
         };
         let candidate = DecisionProcessData {
+            weight: 0,
+            rpki_state: RpkiState::NotFound,
+            prefer_valid: false,
+            installed: None,
             local_pref: Some(1000),
             as_path_len: 0,
             last_as: 65000,
@@ -726,7 +2821,7 @@ mod tests {
             peer_addr: IpAddr::V6(cand_ip_addr.clone())
         };
 
-        assert!(candidate > best);
+        assert!(prefers(&best, &candidate));
     }
 
 
@@ -747,12 +2842,42 @@ mod tests {
 
         // Add entry to table then clone to increase strong count
         let rc_ref = pa_table.insert(pa_entry);
-        let _cloned = Rc::clone(rc_ref);
+        let _cloned = Arc::clone(rc_ref);
 
         // Run remove stale; nothing should get removed since strong counts should be two
         pa_table.remove_stale();
         assert_eq!(pa_table.len(), 1);
     }
+    #[test]
+    fn test_pat_remove_released() {
+        let mut pa_table = PathAttributeTable::new();
+        let held = Arc::clone(pa_table.insert(build_pa_entry(1000, OriginValue::Igp)));
+        // Nothing points to this one, it's dropped at the first cleanup
+        pa_table.insert(build_pa_entry(900, OriginValue::Igp));
+        // Inserting an existing entry doesn't mark it again
+        pa_table.insert(build_pa_entry(1000, OriginValue::Igp));
+        pa_table.remove_released();
+        assert_eq!(pa_table.len(), 1);
+
+        // Released twice over, checked once
+        pa_table.release(Arc::clone(&held));
+        pa_table.release(held);
+        pa_table.remove_released();
+        assert_eq!(pa_table.len(), 0);
+
+        // Entries that went stale untracked wait for a sweep
+        let held = Arc::clone(pa_table.insert(build_pa_entry(800, OriginValue::Igp)));
+        pa_table.remove_released();
+        drop(held);
+        pa_table.remove_released();
+        assert_eq!(pa_table.len(), 1);
+        // which growing past the minimum brings on
+        let held: Vec<_> = (0..PA_SWEEP_MIN as u32)
+            .map(|med| Arc::clone(pa_table.insert(build_pa_entry(med, OriginValue::Egp))))
+            .collect();
+        pa_table.remove_released();
+        assert_eq!(pa_table.len(), held.len());
+    }
 
     // BGP Table Entry Tests
     #[test]
@@ -793,10 +2918,64 @@ mod tests {
         let mut bgp_entry = BgpTableEntry::new(pa_table.insert(pa_entry));
         bgp_entry.insert(pa_table.insert(best_pa_entry));
 
-        // Check to make sure best path is the one with lower med
-        let best_rc = Rc::new(best_pa_entry_c);
-        assert_eq!(bgp_entry.paths.len(), 2);
-        assert_eq!(bgp_entry.bestpath(), &best_rc)
+        // Check to make sure best path is the one with lower med
+        let best_rc = Arc::new(best_pa_entry_c);
+        assert_eq!(bgp_entry.paths.len(), 2);
+        assert_eq!(bgp_entry.bestpath(), &best_rc)
+    }
+    #[test]
+    fn bgp_entry_sorted() {
+        let mut pa_table = PathAttributeTable::new();
+        let mut meds: Vec<u32> = (0..12).collect();
+        meds.shuffle(&mut rand::thread_rng());
+        let mut bgp_entry = BgpTableEntry::new(pa_table.insert(build_pa_entry(meds[0], OriginValue::Igp)));
+        for med in &meds[1..] {
+            assert!(bgp_entry.insert(pa_table.insert(build_pa_entry(*med, OriginValue::Igp))));
+        }
+        // Duplicates are turned away, past the inline capacity too
+        assert!(!bgp_entry.insert(pa_table.insert(build_pa_entry(11, OriginValue::Igp))));
+        assert!(bgp_entry.paths.spilled());
+        let sorted: Vec<u32> = bgp_entry.sorted().iter().map(|path| path.decision_data.med).collect();
+        assert_eq!(sorted, (0..12).collect::<Vec<u32>>());
+        assert_eq!(bgp_entry.bestpath().decision_data.med, 0);
+        assert!(bgp_entry.is_in(&build_pa_entry(7, OriginValue::Igp)));
+        assert!(!bgp_entry.is_in(&build_pa_entry(7, OriginValue::Egp)));
+    }
+    #[test]
+    fn bgp_entry_med_cycle() {
+        // MED only counts within a neighbouring AS, so compared two at a time the paths would beat each other in a
+        // circle: a over b on MED, b over c and c over a on the peer ID. Run over the whole set, MED leaves the
+        // best path of each AS (a and d) and the peer ID picks d, whatever order the paths arrived in.
+        let path = |last_as: u16, med: u32, peer_id: u8| {
+            let mut entry = build_pa_entry(med, OriginValue::Igp);
+            entry.decision_data.last_as = last_as;
+            entry.decision_data.peer_id = Ipv4Addr::new(10, 0, 0, peer_id);
+            entry
+        };
+        let paths = [path(65001, 5, 6), path(65001, 8, 1), path(65002, 5, 5), path(65002, 4, 3)];
+
+        let mut orders: Vec<Vec<usize>> = (0..paths.len())
+            .map(|first| (0..paths.len()).map(|idx| (first + idx) % paths.len()).collect())
+            .collect();
+        orders.extend(orders.clone().into_iter().map(|order| order.into_iter().rev().collect()));
+        for order in orders {
+            let mut pa_table = PathAttributeTable::new();
+            let mut bgp_entry = BgpTableEntry::new(pa_table.insert(paths[order[0]].clone()));
+            for idx in &order[1..] {
+                assert!(bgp_entry.insert(pa_table.insert(paths[*idx].clone())));
+            }
+            for entry in &paths {
+                assert!(bgp_entry.is_in(entry));
+                assert!(!bgp_entry.insert(pa_table.insert(entry.clone())));
+            }
+            assert_eq!(bgp_entry.len(), paths.len());
+            assert_eq!(bgp_entry.bestpath().as_ref(), &paths[3]);
+            assert_eq!(bgp_entry.sorted()[1].as_ref(), &paths[2]);
+
+            // The best left after d goes is still picked from the whole set
+            bgp_entry.remove(Ipv4Addr::new(10, 0, 0, 3), &mut pa_table);
+            assert_eq!(bgp_entry.bestpath().as_ref(), &paths[2]);
+        }
     }
 
 
@@ -810,8 +2989,8 @@ mod tests {
         // Need to sort and dedup vec to know exact number of destinations
         routes.sort();
         routes.dedup();
-        let pa = PathAttrBuilder::<Med>::new().metric(med).build();
-        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build();
+        let pa = PathAttrBuilder::<Med>::new().metric(med).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build().unwrap();
         let pas = vec![pa, pa2];
         
         // Create the payload
@@ -837,8 +3016,8 @@ mod tests {
         // Need to sort and dedup vec to know exact number of destinations
         routes.sort();
         routes.dedup();
-        let pa = PathAttrBuilder::<Med>::new().metric(med).build();
-        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build();
+        let pa = PathAttrBuilder::<Med>::new().metric(med).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build().unwrap();
         let pas = vec![pa, pa2];
         let peer1_id = Ipv4Addr::new(10, 2, 2, 1);
 
@@ -868,8 +3047,8 @@ mod tests {
         // Need to sort and dedup vec to know exact number of destinations
         routes.sort();
         routes.dedup();
-        let pa = PathAttrBuilder::<Med>::new().metric(med).build();
-        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build();
+        let pa = PathAttrBuilder::<Med>::new().metric(med).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build().unwrap();
         let pas = vec![pa, pa2];
 
         // Generate two different rx routes messages with the same information other than a different peer
@@ -905,8 +3084,8 @@ mod tests {
         // Need to sort and dedup vec to know exact number of destinations
         routes.sort();
         routes.dedup();
-        let pa = PathAttrBuilder::<Med>::new().metric(med).build();
-        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build();
+        let pa = PathAttrBuilder::<Med>::new().metric(med).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build().unwrap();
         let pas = vec![pa, pa2];
         let peer1_id = Ipv4Addr::new(10, 2, 2, 1);
 
@@ -950,8 +3129,8 @@ mod tests {
         // Need to sort and dedup vec to know exact number of destinations
         routes.sort();
         routes.dedup();
-        let pa = PathAttrBuilder::<Med>::new().metric(med).build();
-        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build();
+        let pa = PathAttrBuilder::<Med>::new().metric(med).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(origin).build().unwrap();
         let pas = vec![pa, pa2];
 
         // Generate payload and table and add routes to the table
@@ -961,10 +3140,1096 @@ mod tests {
 
         assert_eq!(adv_routes.len(), 1);
         for (k, v) in adv_routes.routes().iter() {
-            assert_eq!(k[0].attr_type_code(), 1); // Checking vec sorting
-            assert_eq!(k[1].attr_type_code(), 4); // Checking vec sorting
+            assert_eq!(k.pas()[0].attr_type_code(), 1); // Checking vec sorting
+            assert_eq!(k.pas()[1].attr_type_code(), 4); // Checking vec sorting
             assert_eq!(v.len(), routes.len());
         }
 
     }
-}
\ No newline at end of file
+    #[test]
+    fn adv_routes_v6_entry() {
+        let pa_entry = Arc::new(build_pa_entry(10, OriginValue::Igp));
+        let mut adv_routes: AdvertisedRoutes<Ipv6Addr> = AdvertisedRoutes::new();
+        adv_routes.entry(&pa_entry, Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32);
+        adv_routes.entry(&pa_entry, Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0), 48);
+
+        assert_eq!(adv_routes.afi(), Afi::Ipv6);
+        assert_eq!(adv_routes.len(), 1);
+        let nlri = adv_routes.to_nlri();
+        assert_eq!(nlri.len(), 1);
+    }
+    #[test]
+    fn bgp_table_walk_mixed_family() {
+        // Build a payload carrying both v4 and v6 routes, split it by AFI and make sure
+        // only the v4 payload is installed into the v4 table.
+        let mut routes = generate_routes_v4(100);
+        routes.sort();
+        routes.dedup();
+        let num_v4 = routes.len();
+        routes.extend(generate_routes_v6(100));
+        let pa = PathAttrBuilder::<Med>::new().metric(1000).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let pas = vec![pa, pa2];
+
+        let rxr = MockReceivedRoutesBuilder::new(Some(routes), None, pas).build();
+        let payloads = rxr.split_by_afi();
+        assert_eq!(payloads.len(), 2);
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        for payload in payloads {
+            let afi = payload.afi();
+            let (_, adv_routes) = table.walk(payload);
+            match afi {
+                Afi::Ipv4 => assert_eq!(adv_routes.len(), 1),
+                Afi::Ipv6 => assert!(adv_routes.is_empty()),
+            }
+        }
+        assert_eq!(table.num_destinations(), num_v4);
+        assert_eq!(table.num_pa_entries(), 1);
+    }
+    #[test]
+    fn bgp_table_simulate_announce() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        let pa = PathAttrBuilder::<Med>::new().metric(1000).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let rxr = MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![pa, pa2]).build();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(rxr);
+        let version = table.table_version;
+
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ebgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 65001, 65000, local_addr);
+        let ibgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)), 65000, 65000, local_addr);
+        let local_pas = vec![PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap()];
+        let sim = table.simulate_announce(&route, local_pas, &[ebgp, ibgp]);
+
+        // Existing bestpath is reported and both peers would receive the route
+        assert_eq!(sim.current_bestpath().unwrap().len(), 2);
+        assert_eq!(sim.exports().len(), 2);
+        let ebgp_pas = sim.exports()[0].1.as_ref().unwrap();
+        assert!(ebgp_pas.iter().any(|pa| pa.attr_type_code() == AS_PATH));
+        assert!(!ebgp_pas.iter().any(|pa| pa.attr_type_code() == LOCAL_PREF));
+        let ibgp_pas = sim.exports()[1].1.as_ref().unwrap();
+        assert!(ibgp_pas.iter().any(|pa| pa.attr_type_code() == LOCAL_PREF));
+
+        // Table is untouched
+        assert_eq!(table.table_version, version);
+        assert_eq!(table.num_paths(), 1);
+        assert_eq!(table.num_pa_entries(), 1);
+    }
+    #[test]
+    fn adv_routes_group_by_pas() {
+        // Two PAT entries with identical PAs but different decision data (different peers)
+        // should be grouped under the same key.
+        let pa_entry = build_pa_entry(10, OriginValue::Igp);
+        let mut other = pa_entry.clone();
+        other.decision_data.peer_id = Ipv4Addr::new(10, 10, 10, 10);
+        assert_ne!(pa_entry, other);
+
+        let mut adv_routes: AdvertisedRoutes<Ipv4Addr> = AdvertisedRoutes::new();
+        adv_routes.entry(&Arc::new(pa_entry), Ipv4Addr::new(192, 168, 1, 0), 24);
+        adv_routes.entry(&Arc::new(other), Ipv4Addr::new(192, 168, 2, 0), 24);
+        assert_eq!(adv_routes.len(), 1);
+        assert_eq!(adv_routes.routes().values().next().unwrap().len(), 2);
+    }
+    #[test]
+    fn adv_routes_fit_update() {
+        // A full table's worth of routes with the same PAs can't go in one Update
+        let pa_entry = Arc::new(build_pa_entry(10, OriginValue::Igp));
+        let mut adv_routes: AdvertisedRoutes<Ipv4Addr> = AdvertisedRoutes::new();
+        generate_routes_v4(5000)
+        .into_iter()
+        .for_each(|route| adv_routes.insert(&pa_entry, route));
+        let nlri = adv_routes.to_nlri();
+        assert!(nlri.len() > 1);
+        assert!(nlri.iter().all(|group| group.update_len() <= MAX_MESSAGE_LEN));
+        assert_eq!(nlri.iter().map(|group| group.routes().len()).sum::<usize>(), 5000);
+    }
+    #[test]
+    fn bgp_table_duplicate_detection() {
+        let mut routes = generate_routes_v4(100);
+        routes.sort();
+        routes.dedup();
+        let pa = PathAttrBuilder::<Med>::new().metric(1000).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let pas = vec![pa, pa2];
+        let peer_addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        // Without suppression the duplicates are counted but still advertised
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        table.enable_duplicate_detection(Duration::from_secs(60), false);
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, pas.clone()).build());
+        assert_eq!(table.duplicate_detector().unwrap().duplicates(peer_addr), 0);
+        let (_, adv_routes) = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, pas.clone()).build());
+        assert_eq!(table.duplicate_detector().unwrap().duplicates(peer_addr), routes.len());
+        assert_eq!(adv_routes.len(), 1);
+
+        // With suppression the second announcement generates nothing
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        table.enable_duplicate_detection(Duration::from_secs(60), true);
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, pas.clone()).build());
+        let (_, adv_routes) = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, pas.clone()).build());
+        assert!(adv_routes.is_empty());
+
+        // A withdraw followed by a re-announcement isn't a duplicate
+        _ = table.walk(MockReceivedRoutesBuilder::new(None, Some(routes.clone()), pas.clone()).build());
+        let (_, adv_routes) = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, pas.clone()).build());
+        assert_eq!(adv_routes.len(), 1);
+        assert_eq!(table.duplicate_detector().unwrap().duplicates(peer_addr), routes.len());
+    }
+
+    #[test]
+    fn duplicate_detector_window() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut detector = DuplicateDetector::new(Duration::from_secs(10), false);
+        let start = Instant::now();
+
+        assert!(!detector.observe(peer, &route, 1, start));
+        assert!(detector.observe(peer, &route, 1, start + Duration::from_secs(5)));
+        // Different PAs
+        assert!(!detector.observe(peer, &route, 2, start + Duration::from_secs(6)));
+        // Outside the window
+        assert!(!detector.observe(peer, &route, 2, start + Duration::from_secs(20)));
+        assert_eq!(detector.duplicates(peer), 1);
+
+        detector.prune(start + Duration::from_secs(60));
+        assert!(detector.last_seen.is_empty());
+    }
+    #[test]
+    fn bgp_table_v6_walk_add_remove() {
+        let mut routes = generate_routes_v6(10000);
+        routes.sort();
+        routes.dedup();
+        let pa = PathAttrBuilder::<Med>::new().metric(1000).build().unwrap();
+        let pa2 = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let pas = vec![pa, pa2];
+        let peer1_id = Ipv4Addr::new(10, 2, 2, 1);
+
+        let rxr1_adv = MockReceivedRoutesBuilder::new(Some(routes.clone()), None, pas.clone()).afi(Afi::Ipv6).build();
+        let rxr1_withdrawn = MockReceivedRoutesBuilder::new(None, Some(routes.clone()), pas.clone()).afi(Afi::Ipv6).build();
+        let rxr2_adv = MockReceivedRoutesBuilder::new(Some(routes.clone()), None, pas.clone())
+            .afi(Afi::Ipv6)
+            .peer_id(peer1_id)
+            .med(10)
+            .build();
+
+        let mut table = BgpTable::<Ipv6Addr>::new();
+        let (_, adv_routes) = table.walk(rxr1_adv);
+        assert_eq!(adv_routes.afi(), Afi::Ipv6);
+        assert_eq!(adv_routes.routes().values().next().unwrap().len(), routes.len());
+
+        // Second peer has a lower MED so it becomes the bestpath everywhere
+        let (_, adv_routes) = table.walk(rxr2_adv);
+        assert_eq!(table.num_destinations(), routes.len());
+        assert_eq!(table.num_paths(), 2 * routes.len());
+        assert_eq!(adv_routes.routes().values().next().unwrap().len(), routes.len());
+
+        // Withdrawing the first peer's paths doesn't change any bestpaths
+        let (removed, adv_routes) = table.walk(rxr1_withdrawn);
+        assert!(removed.is_empty());
+        assert!(adv_routes.is_empty());
+        assert_eq!(table.num_paths(), routes.len());
+    }
+
+    #[test]
+    fn bgp_table_v6_ignores_v4() {
+        let routes = generate_routes_v4(100);
+        let pa = PathAttrBuilder::<Med>::new().metric(1000).build().unwrap();
+        let mut table = BgpTable::<Ipv6Addr>::new();
+
+        // Tagged v4 payloads are skipped entirely, v4 routes in a v6 tagged payload are filtered
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa.clone()]).build());
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes), None, vec![pa]).afi(Afi::Ipv6).build());
+        assert_eq!(table.num_destinations(), 0);
+    }
+
+    #[test]
+    fn bgp_table_entry_bestpaths() {
+        let mut best = build_pa_entry(10, OriginValue::Igp);
+        let mut tied = build_pa_entry(10, OriginValue::Igp);
+        tied.decision_data.peer_id = Ipv4Addr::new(192, 168, 1, 2);
+        let mut worse = build_pa_entry(10, OriginValue::Igp);
+        worse.decision_data.peer_id = Ipv4Addr::new(192, 168, 1, 0);
+        worse.decision_data.igp_cost = 10;
+        best.decision_data.peer_id = Ipv4Addr::new(192, 168, 1, 1);
+
+        let best = Arc::new(best);
+        let tied = Arc::new(tied);
+        let worse = Arc::new(worse);
+        let mut entry = BgpTableEntry::new(&worse);
+        entry.insert(&tied);
+        entry.insert(&best);
+
+        // Worse path has a higher IGP cost so it is never part of the set
+        assert_eq!(entry.bestpaths(1), vec![&best]);
+        assert_eq!(entry.bestpaths(4), vec![&best, &tied]);
+        assert_eq!(entry.bestpaths(0), vec![&best]);
+    }
+
+    #[test]
+    fn bgp_table_multipath() {
+        let routes = generate_routes_v4(100);
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let peer2 = Ipv4Addr::new(10, 2, 2, 2);
+        let peer3 = Ipv4Addr::new(10, 3, 3, 3);
+        let rxr1 = MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa.clone()]).build();
+        let rxr2 = MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa.clone()])
+            .peer_id(peer2)
+            .peer_addr(IpAddr::V4(peer2))
+            .build();
+        let rxr3 = MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa])
+            .peer_id(peer3)
+            .peer_addr(IpAddr::V4(peer3))
+            .igp_cost(5000)
+            .build();
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(rxr1);
+        _ = table.walk(rxr2);
+        _ = table.walk(rxr3);
+
+        assert_eq!(table.bestpaths(&routes[0]).len(), 1);
+        table.set_max_paths(8);
+        let paths = table.bestpaths(&routes[0]);
+        assert_eq!(paths.len(), 2);
+        // Lowest peer id is still the bestpath
+        assert_eq!(paths[0].peer_id(), peer2);
+        assert_eq!(paths[1].peer_id(), Ipv4Addr::new(192, 168, 1, 1));
+        assert!(table.bestpaths(&Route::new(32, IpAddr::V4(Ipv4Addr::new(224, 0, 0, 1)))).is_empty());
+    }
+
+    #[test]
+    fn adj_rib_in_update_and_dump() {
+        let routes = vec![
+            Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0))),
+            Route::new(16, IpAddr::V4(Ipv4Addr::new(10, 2, 0, 0))),
+        ];
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut rib = AdjRibIn::new();
+        rib.update(&MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa.clone()]).build());
+        assert_eq!(rib.num_routes(peer), 2);
+        let dump = rib.dump(peer);
+        assert!(dump.contains(&(&routes[0], [pa.clone()].as_slice())));
+
+        rib.update(&MockReceivedRoutesBuilder::new(None, Some(vec![routes[0].clone()]), Vec::new()).build());
+        assert_eq!(rib.num_routes(peer), 1);
+        assert_eq!(rib.dump(peer)[0].0, &routes[1]);
+        assert_eq!(rib.remove_peer(peer), 1);
+        assert!(rib.dump(peer).is_empty());
+    }
+
+    #[test]
+    fn adj_rib_in_replay() {
+        let routes = generate_routes_v4(1000);
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let payload = MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa]).build();
+        let peer = payload.peer_addr();
+        let mut rib = AdjRibIn::new();
+        rib.update(&payload);
+        let num_routes = rib.num_routes(peer);
+
+        // Accept everything
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        let (_, adv) = rib.replay(peer, &mut table, |_, pas| Some(pas.to_vec()));
+        assert_eq!(table.num_destinations(), num_routes);
+        assert_eq!(adv.routes().values().map(|r| r.len()).sum::<usize>(), num_routes);
+
+        // Policy change rejects everything, which removes the routes from the table
+        let (removed, _) = rib.replay(peer, &mut table, |_, _| None);
+        assert_eq!(removed.len(), num_routes);
+        assert_eq!(table.num_destinations(), 0);
+        assert_eq!(rib.num_routes(peer), num_routes);
+    }
+
+    #[test]
+    fn adj_rib_out_suppress_identical() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let pa = PathAttrBuilder::<Med>::new().metric(10).build().unwrap();
+        let mut rib = AdjRibOut::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), Duration::ZERO);
+        let now = Instant::now();
+
+        rib.advertise(route.clone(), vec![pa.clone()]);
+        let (withdrawn, nlri) = rib.flush(now);
+        assert!(withdrawn.is_empty());
+        assert_eq!(nlri.len(), 1);
+        assert_eq!(nlri[0].routes(), &[route.clone()]);
+        assert_eq!(rib.get(&route), Some([pa.clone()].as_slice()));
+
+        // Same state again is a no-op
+        rib.advertise(route.clone(), vec![pa]);
+        assert_eq!(rib.num_pending(), 0);
+
+        // Withdrawing something never advertised is a no-op
+        rib.withdraw(Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 2, 2, 0))));
+        assert_eq!(rib.num_pending(), 0);
+
+        rib.withdraw(route.clone());
+        let (withdrawn, nlri) = rib.flush(now);
+        assert_eq!(withdrawn, vec![route]);
+        assert!(nlri.is_empty());
+        assert_eq!(rib.num_advertised(), 0);
+    }
+
+    #[test]
+    fn adj_rib_out_mrai() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let route2 = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 2, 2, 0)));
+        let pa = PathAttrBuilder::<Med>::new().metric(10).build().unwrap();
+        let pa2 = PathAttrBuilder::<Med>::new().metric(20).build().unwrap();
+        let mrai = AdjRibOut::default_mrai(&RouteSource::Ebgp);
+        let mut rib = AdjRibOut::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), mrai);
+        let now = Instant::now();
+
+        rib.advertise(route.clone(), vec![pa.clone()]);
+        rib.advertise(route2.clone(), vec![pa]);
+        assert_eq!(rib.flush(now).1.len(), 1);
+
+        // Within the MRAI, advertisements are held but withdrawals go out
+        rib.advertise(route.clone(), vec![pa2]);
+        rib.withdraw(route2.clone());
+        let (withdrawn, nlri) = rib.flush(now + Duration::from_secs(1));
+        assert_eq!(withdrawn, vec![route2]);
+        assert!(nlri.is_empty());
+        assert_eq!(rib.num_pending(), 1);
+        assert_eq!(rib.next_flush(), Some(now + mrai));
+
+        let (_, nlri) = rib.flush(now + mrai);
+        assert_eq!(nlri.len(), 1);
+        assert_eq!(rib.num_pending(), 0);
+        assert_eq!(rib.next_flush(), None);
+    }
+
+    #[test]
+    fn adj_rib_out_queue_walk() {
+        let routes = generate_routes_v4(100);
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        let (removed, adv) = table.walk(MockReceivedRoutesBuilder::new(Some(routes), None, vec![pa]).build());
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ebgp = ExportPeer::new(peer, 65002, 65000, IpAddr::V4(Ipv4Addr::new(10, 9, 9, 9)));
+        let (mut withdrawn, nlri) = adv.export(&ebgp, table.rpki_tags());
+        withdrawn.extend(removed);
+        let mut rib = AdjRibOut::new(peer, Duration::ZERO);
+        rib.queue(withdrawn, nlri);
+        assert_eq!(rib.num_pending(), table.num_destinations());
+        _ = rib.flush(Instant::now());
+        assert_eq!(rib.num_advertised(), table.num_destinations());
+        let advertised: usize = rib.advertised().iter().map(|nlri| nlri.routes().len()).sum();
+        assert_eq!(advertised, table.num_destinations());
+
+        // A full resend goes out even though nothing changed, and withdraws what's no longer exported
+        let kept = rib.advertised()[0].routes()[0].clone();
+        let pas = rib.get(&kept).unwrap().to_vec();
+        rib.replace(Nlri::chunked(std::slice::from_ref(&kept), &pas));
+        let (withdrawn, nlri) = rib.flush(Instant::now());
+        assert_eq!(withdrawn.len(), table.num_destinations() - 1);
+        assert_eq!(nlri.len(), 1);
+        assert_eq!(nlri[0].routes(), &[kept]);
+        assert_eq!(rib.num_advertised(), 1);
+    }
+
+    #[test]
+    fn bgp_table_walk_export() {
+        let routes = generate_routes_v4(100);
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let lp = PathAttrBuilder::<LocalPref>::new().local_pref(200).build().unwrap();
+        let nh = PathAttrBuilder::<NextHop>::new()
+            .next_hop(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+            .build()
+            .unwrap();
+        let payload = MockReceivedRoutesBuilder::new(Some(routes), None, vec![origin, lp, nh]).build();
+        let source = payload.peer_addr();
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 9, 9, 9));
+        let ebgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 2, 2, 2)), 65002, 65000, local_addr);
+        let ibgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 3, 3, 3)), 65000, 65000, local_addr);
+        let back = ExportPeer::new(source, 65001, 65000, local_addr);
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        let out = table.walk_export(payload, &[ebgp.clone(), ibgp, back]);
+        let num_dest = table.num_destinations();
+
+        // eBGP peer gets LOCAL_PREF stripped and NEXT_HOP rewritten
+        let (_, withdrawn, nlri) = &out[0];
+        assert!(withdrawn.is_empty());
+        assert_eq!(nlri.len(), 1);
+        assert!(nlri[0].path_attrs().iter().all(|pa| pa.attr_type_code() != LOCAL_PREF));
+        assert_eq!(nlri[0].path_attrs().iter().find_map(|pa| pa.next_hop()), Some(local_addr));
+        // iBGP peer keeps the LOCAL_PREF
+        assert!(out[1].2[0].path_attrs().iter().any(|pa| pa.attr_type_code() == LOCAL_PREF));
+        // Nothing goes back to the source
+        assert!(out[2].2.is_empty());
+        assert_eq!(out[2].1.len(), num_dest);
+
+        let full = table.export_bestpaths(&ebgp);
+        assert_eq!(full.iter().map(|n| n.routes().len()).sum::<usize>(), num_dest);
+    }
+
+    #[test]
+    fn bgp_table_lpm() {
+        let routes = vec![
+            Route::new(8, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0))),
+            Route::new(16, IpAddr::V4(Ipv4Addr::new(10, 1, 0, 0))),
+            Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0))),
+        ];
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa.clone()]).build());
+
+        assert_eq!(table.lookup_lpm(Ipv4Addr::new(10, 1, 1, 1)).unwrap().0, routes[2]);
+        assert_eq!(table.lookup_lpm(Ipv4Addr::new(10, 1, 2, 1)).unwrap().0, routes[1]);
+        assert!(table.lookup_lpm(Ipv4Addr::new(11, 1, 1, 1)).is_none());
+        assert_eq!(table.covered_routes(&routes[0]), routes);
+        assert_eq!(table.covering_routes(&routes[2]), routes);
+
+        // Withdrawn destinations drop out of the index
+        _ = table.walk(MockReceivedRoutesBuilder::new(None, Some(vec![routes[2].clone()]), vec![pa]).build());
+        assert_eq!(table.lookup_lpm(Ipv4Addr::new(10, 1, 1, 1)).unwrap().0, routes[1]);
+        assert_eq!(table.covered_routes(&routes[0]).len(), 2);
+    }
+
+    #[test]
+    fn bgp_table_bestpath_events() {
+        use std::sync::Mutex;
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let better_peer = Ipv4Addr::new(10, 0, 0, 2);
+        let events: Arc<Mutex<Vec<BestpathEvent>>> = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        table.subscribe(Box::new(move |event: &BestpathEvent| sink.lock().unwrap().push(event.clone())));
+
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![pa.clone()]).build());
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![pa.clone()])
+            .peer_id(better_peer)
+            .build()
+        );
+        // A worse path doesn't change the bestpath
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![pa.clone()])
+            .peer_id(Ipv4Addr::new(192, 168, 1, 2))
+            .build()
+        );
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(None, Some(vec![route.clone()]), vec![pa.clone()])
+            .peer_id(better_peer)
+            .build()
+        );
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], BestpathEvent::Added { new, .. } if new.peer_id() == Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(matches!(&events[1], BestpathEvent::Changed { new, .. } if new.peer_id() == better_peer));
+        assert!(matches!(&events[2], BestpathEvent::Changed { old, .. } if old.peer_id() == better_peer));
+        assert!(events.iter().all(|event| event.route() == &route));
+    }
+
+    #[test]
+    fn bgp_table_route_views() {
+        let routes = vec![
+            Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0))),
+            Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 2, 0))),
+        ];
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let comms = PathAttrBuilder::<Communities>::new().communities(vec![100]).build().unwrap();
+        let peer2 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![origin.clone()]).build());
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![routes[1].clone()]), None, vec![origin, comms])
+            .peer_addr(peer2)
+            .peer_id(Ipv4Addr::new(10, 0, 0, 2))
+            .build()
+        );
+
+        let views = table.routes();
+        assert_eq!(views.len(), 2);
+        let view = table.route_view(&routes[1]).unwrap();
+        assert_eq!(view.paths().len(), 2);
+        assert_eq!(view.bestpath().unwrap().peer_addr(), peer2);
+        assert!(!view.paths()[1].is_best());
+        assert!(table.route_view(&Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 9, 9, 0)))).is_none());
+
+        let from_peer = table.routes_from_peer(peer2);
+        assert_eq!(from_peer.len(), 1);
+        assert_eq!(from_peer[0].paths().len(), 1);
+        assert_eq!(table.routes_with_community(100).len(), 1);
+        assert!(table.routes_with_community(200).is_empty());
+    }
+
+    #[test]
+    fn bgp_table_export_snapshot() {
+        let routes = vec![
+            Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0))),
+            Route::new(16, IpAddr::V4(Ipv4Addr::new(10, 2, 0, 0))),
+        ];
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let aspath = PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![65001, 65002])])
+            .build()
+            .unwrap();
+        let nh = PathAttrBuilder::<NextHop>::new()
+            .next_hop(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+            .build()
+            .unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![origin.clone(), aspath, nh]).build());
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![routes[0].clone()]), None, vec![origin])
+            .peer_id(Ipv4Addr::new(192, 168, 1, 2))
+            .build()
+        );
+
+        let csv = table.export(SnapshotFormat::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], SnapshotRow::CSV_HEADER);
+        assert_eq!(lines[1], "10.1.1.0/24,10.0.0.1,10.0.0.1,100,1000,0,65001 65002,,1");
+
+        let json: serde_json::Value = serde_json::from_str(&table.export(SnapshotFormat::Json)).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[1]["prefix"], "10.2.0.0/16");
+        assert_eq!(json[1]["alternate_paths"], 0);
+    }
+
+    #[test]
+    fn bgp_table_originate() {
+        use std::sync::Mutex;
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin]).build());
+
+        // Local route is preferred over the learned one
+        let adv = table.originate(LocalRoutes::new(vec![route.clone()]).med(10).communities(vec![100]));
+        assert_eq!(adv.len(), 1);
+        let view = table.route_view(&route).unwrap();
+        assert_eq!(view.paths().len(), 2);
+        let best = view.bestpath().unwrap();
+        assert_eq!(best.route_source(), &RouteSource::Local);
+        assert_eq!(best.communities(), vec![100]);
+        assert_eq!(best.pas().iter().find_map(|pa| pa.as_path()), Some(Vec::new()));
+
+        // Re-originating with new attributes replaces the local path, as a single bestpath change
+        let events: Arc<Mutex<Vec<BestpathEvent>>> = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        table.subscribe(Box::new(move |event: &BestpathEvent| sink.lock().unwrap().push(event.clone())));
+        let version = table.version();
+        let adv = table.originate(LocalRoutes::new(vec![route.clone()]).med(20));
+        assert_eq!(adv.len(), 1);
+        assert_eq!(table.version(), version + 1);
+        {
+            // The subscriber is told about the route first
+            let events = events.lock().unwrap();
+            assert_eq!(events.len(), 2);
+            assert!(matches!(events[0], BestpathEvent::Added { .. }));
+            assert!(matches!(events[1], BestpathEvent::Changed { .. }));
+        }
+        let view = table.route_view(&route).unwrap();
+        assert_eq!(view.paths().len(), 2);
+        assert_eq!(view.bestpath().unwrap().med(), 20);
+
+        // Locally originated paths go out with next-hop-self and our AS to eBGP peers
+        let local_addr = IpAddr::V4(Ipv4Addr::new(10, 9, 9, 9));
+        let ebgp = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 65002, 65000, local_addr);
+        let nlri = table.export_bestpaths(&ebgp);
+        assert_eq!(nlri.len(), 1);
+        assert_eq!(nlri[0].path_attrs().iter().find_map(|pa| pa.next_hop()), Some(local_addr));
+
+        let (removed, _) = table.withdraw_originated(vec![route.clone()]);
+        assert!(removed.is_empty());
+        assert_eq!(table.route_view(&route).unwrap().paths().len(), 1);
+    }
+
+    #[test]
+    fn decision_process_weight() {
+        // Higher weight wins over a higher local pref
+        let mut best = build_pa_entry(10, OriginValue::Igp).decision_data;
+        let mut candidate = best.clone();
+        best.weight = 100;
+        candidate.local_pref = Some(1000);
+        assert!(prefers(&best, &candidate));
+
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin.clone()]).weight(10).build());
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin])
+            .peer_id(Ipv4Addr::new(1, 1, 1, 1))
+            .local_pref(500)
+            .build()
+        );
+        assert_eq!(table.route_view(&route).unwrap().bestpath().unwrap().weight(), 10);
+    }
+
+    #[test]
+    fn decision_process_oldest_path() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let older = || MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin.clone()])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 9))
+            .build();
+        // Would win on router ID
+        let newer = || MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin.clone()])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 1))
+            .build();
+
+        let mut table = BgpTable::<Ipv4Addr>::with_config(DecisionConfig::new().prefer_oldest(true));
+        _ = table.walk(older());
+        std::thread::sleep(Duration::from_millis(2));
+        _ = table.walk(newer());
+        assert_eq!(table.route_view(&route).unwrap().bestpath().unwrap().peer_id(), Ipv4Addr::new(10, 0, 0, 9));
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(older());
+        _ = table.walk(newer());
+        assert_eq!(table.route_view(&route).unwrap().bestpath().unwrap().peer_id(), Ipv4Addr::new(10, 0, 0, 1));
+    }
+
+    #[test]
+    fn bgp_table_rpki_validation() {
+        use crate::rpki::Roa;
+        let valid = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let invalid = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 2, 1, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let as_path = |asn: u16| PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![asn])])
+            .build()
+            .unwrap();
+        let roas = || RoaTable::load(vec![
+            Roa::new(Route::new(16, IpAddr::V4(Ipv4Addr::new(10, 1, 0, 0))), 24, 65001),
+            Roa::new(Route::new(16, IpAddr::V4(Ipv4Addr::new(10, 2, 0, 0))), 24, 65009),
+        ]);
+        // Would win on router ID
+        let from_65001 = || MockReceivedRoutesBuilder::new(Some(vec![valid.clone(), invalid.clone()]), None, vec![origin.clone(), as_path(65001)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 9))
+            .build();
+        let from_65002 = || MockReceivedRoutesBuilder::new(Some(vec![valid.clone()]), None, vec![origin.clone(), as_path(65002)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 1))
+            .peer_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+            .build();
+
+        let mut table = BgpTable::<Ipv4Addr>::with_config(DecisionConfig::new().rpki_policy(RpkiPolicy::PreferValid));
+        table.load_roas(roas(), 65000);
+        _ = table.walk(from_65001());
+        _ = table.walk(from_65002());
+        let view = table.route_view(&valid).unwrap();
+        assert_eq!(view.bestpath().unwrap().rpki_state(), RpkiState::Valid);
+        assert_eq!(view.paths()[1].rpki_state(), RpkiState::Invalid);
+        assert_eq!(table.route_view(&invalid).unwrap().bestpath().unwrap().rpki_state(), RpkiState::Invalid);
+
+        // Exported paths are tagged with their state
+        table.set_rpki_tags(Some(RpkiTags::new(100, 200, 300)));
+        let peer = ExportPeer::new(IpAddr::V4(Ipv4Addr::new(10, 9, 9, 9)), 65000, 65000, IpAddr::V4(Ipv4Addr::new(10, 9, 9, 1)));
+        let mut tags: Vec<Vec<u32>> = table
+            .export_bestpaths(&peer)
+            .iter()
+            .map(|nlri| nlri.path_attrs().iter().find_map(|pa| pa.communities()).unwrap())
+            .collect();
+        tags.sort();
+        assert_eq!(tags, vec![vec![100], vec![300]]);
+
+        // Invalid routes never make it into the table
+        let mut table = BgpTable::<Ipv4Addr>::with_config(DecisionConfig::new().rpki_policy(RpkiPolicy::DropInvalid));
+        table.load_roas(roas(), 65000);
+        _ = table.walk(from_65001());
+        assert!(table.route_view(&invalid).is_none());
+        assert_eq!(table.route_view(&valid).unwrap().paths().len(), 1);
+
+        // Without a policy the state is recorded but router ID decides
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        table.load_roas(roas(), 65000);
+        _ = table.walk(from_65001());
+        _ = table.walk(from_65002());
+        let best = table.route_view(&valid).unwrap().bestpath().unwrap().clone();
+        assert_eq!(best.peer_id(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(best.rpki_state(), RpkiState::Invalid);
+    }
+
+    #[test]
+    fn bgp_table_apply_vrp_update() {
+        use crate::{rpki::Roa, rtr::{RtrAction, RtrClient, RtrPdu}};
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let as_path = |asn: u16| PathAttrBuilder::<AsPath>::new()
+            .as_segments(vec![AsSegment::AsSequence(vec![asn])])
+            .build()
+            .unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::with_config(DecisionConfig::new().rpki_policy(RpkiPolicy::PreferValid));
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin.clone(), as_path(65001)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 9))
+            .build()
+        );
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin, as_path(65002)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 1))
+            .peer_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+            .build()
+        );
+        assert_eq!(table.route_view(&route).unwrap().bestpath().unwrap().peer_id(), Ipv4Addr::new(10, 0, 0, 1));
+
+        // VRPs arrive over RTR authorizing 65001 only
+        let now = Instant::now();
+        let mut client = RtrClient::new();
+        _ = client.query();
+        _ = client.handle(RtrPdu::CacheResponse { session_id: 1 }, now);
+        let roa = Roa::new(Route::new(16, IpAddr::V4(Ipv4Addr::new(10, 1, 0, 0))), 24, 65001);
+        _ = client.handle(RtrPdu::Prefix { announce: true, roa }, now);
+        let update = match client.handle(RtrPdu::EndOfData { session_id: 1, serial: 1, refresh: 1, retry: 1, expire: 1 }, now) {
+            Ok(RtrAction::Update(update)) => update,
+            other => panic!("expected an update, got {:?}", other)
+        };
+        let (removed, adv) = table.apply_vrp_update(&update);
+        assert!(removed.is_empty());
+        assert_eq!(adv.len(), 1);
+        let view = table.route_view(&route).unwrap();
+        assert_eq!(view.bestpath().unwrap().peer_id(), Ipv4Addr::new(10, 0, 0, 9));
+        assert_eq!(view.bestpath().unwrap().rpki_state(), RpkiState::Valid);
+        assert_eq!(view.paths()[1].rpki_state(), RpkiState::Invalid);
+
+        // Nothing changes if the update doesn't touch the destination
+        let (removed, adv) = table.apply_vrp_update(&VrpUpdate::default());
+        assert!(removed.is_empty() && adv.is_empty());
+    }
+
+    #[test]
+    fn bgp_table_changes_since() {
+        let routes = generate_routes_v4(10);
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![origin.clone()]).build());
+        let synced = table.version();
+        assert_eq!(synced, 1);
+        assert_eq!(table.destination_version(&routes[0]), Some(1));
+
+        // A better path for one destination and a withdrawal for another
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![routes[0].clone()]), None, vec![origin.clone()])
+            .peer_id(Ipv4Addr::new(1, 1, 1, 1))
+            .peer_addr(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)))
+            .build()
+        );
+        _ = table.walk(MockReceivedRoutesBuilder::new(None, Some(vec![routes[1].clone()]), vec![origin.clone()]).build());
+        assert_eq!(table.version(), 3);
+        assert_eq!(table.destination_version(&routes[0]), Some(2));
+        assert_eq!(table.destination_version(&routes[2]), Some(1));
+
+        let delta = table.changes_since(synced);
+        assert_eq!(delta.version(), 3);
+        assert_eq!(delta.withdrawn(), &[routes[1].clone()]);
+        let updated: Vec<&Route> = delta.updated().iter().flat_map(|nlri| nlri.routes()).collect();
+        assert_eq!(updated, vec![&routes[0]]);
+        assert!(table.changes_since(3).is_empty());
+        assert_eq!(table.changes_since(0).updated().iter().map(|nlri| nlri.routes().len()).sum::<usize>(), 9);
+
+        // Once consumers are past the withdrawal it can be forgotten
+        table.prune_withdrawn(3);
+        assert!(table.changes_since(synced).withdrawn().is_empty());
+    }
+
+    #[test]
+    fn bgp_table_walk_batch() {
+        let routes = generate_routes_v4(100);
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let med = PathAttrBuilder::<Med>::new().metric(10).build().unwrap();
+        let batch = vec![
+            MockReceivedRoutesBuilder::new(Some(routes[..50].to_vec()), None, vec![origin.clone()]).build(),
+            MockReceivedRoutesBuilder::new(Some(routes[50..].to_vec()), None, vec![origin.clone(), med]).build(),
+            // Withdrawn before the batch is over, so never advertised
+            MockReceivedRoutesBuilder::new(None, Some(routes[..10].to_vec()), vec![origin.clone()]).build(),
+        ];
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        let (removed, adv) = table.walk_batch(batch);
+        assert!(removed.is_empty());
+        assert_eq!(adv.len(), 2);
+        assert_eq!(adv.routes().values().flatten().count(), 90);
+        assert_eq!(table.num_destinations(), 90);
+        assert_eq!(table.num_pa_entries(), 2);
+        assert_eq!(table.version(), 1);
+
+        // Withdrawn then re-announced with the same PAs nets out to nothing
+        let batch = vec![
+            MockReceivedRoutesBuilder::new(None, Some(routes[10..20].to_vec()), vec![origin.clone()]).build(),
+            MockReceivedRoutesBuilder::new(Some(routes[10..20].to_vec()), None, vec![origin.clone()]).build(),
+            MockReceivedRoutesBuilder::new(None, Some(routes[20..30].to_vec()), vec![origin]).build(),
+        ];
+        let (removed, adv) = table.walk_batch(batch);
+        assert_eq!(removed.len(), 10);
+        assert!(adv.is_empty());
+        assert_eq!(table.num_destinations(), 80);
+    }
+
+    #[test]
+    fn table_keys() {
+        for (addr, len) in [(Ipv4Addr::UNSPECIFIED, 0), (Ipv4Addr::new(192, 0, 2, 0), 24), (Ipv4Addr::BROADCAST, 32)] {
+            assert_eq!(Ipv4Addr::from_key(addr.key(len)), (addr, len));
+        }
+        assert_ne!(Ipv4Addr::new(10, 0, 0, 0).key(8), Ipv4Addr::new(10, 0, 0, 0).key(16));
+        let route = Route::new(48, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0)));
+        assert_eq!(Ipv6Addr::key_route(Ipv6Addr::route_key(&route).unwrap()), route);
+        assert_eq!(Ipv4Addr::route_key(&route), None);
+    }
+
+    #[test]
+    fn bgp_table_full_table() {
+        // A full v4 table from one peer, walked in, looked up and withdrawn again
+        let full = FullTableGenerator::new().prefixes_v4(1_000_000).prefixes_v6(0).seed(1880).generate();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        let peer_addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let payloads = full.received_routes(Ipv4Addr::new(10, 0, 0, 1), peer_addr);
+        // Short AS_PATHs without MED or communities can come out the same
+        let num_sets = full.groups().iter().map(|(pas, _)| pas).collect::<HashSet<_>>().len();
+        payloads.into_iter().for_each(|payload| _ = table.walk(payload));
+        assert_eq!(table.num_destinations(), 1_000_000);
+        assert_eq!(table.num_pa_entries(), num_sets);
+        assert!(full.routes().all(|route| table.bestpaths(route).len() == 1));
+
+        let withdrawn: Vec<Route> = full.routes().cloned().collect();
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let (removed, _) = table.walk(
+            MockReceivedRoutesBuilder::new(None, Some(withdrawn), vec![pa])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 1))
+            .peer_addr(peer_addr)
+            .build()
+        );
+        assert_eq!(removed.len(), 1_000_000);
+        assert_eq!((table.num_destinations(), table.num_pa_entries()), (0, 0));
+    }
+
+    #[test]
+    fn bgp_table_memory_stats() {
+        let routes = generate_routes_v4(1000);
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        assert_eq!(table.memory_stats().dedup_ratio(), 0.0);
+
+        _ = table.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa.clone()]).build());
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(routes), None, vec![pa])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 2))
+            .build()
+        );
+        let stats = table.memory_stats();
+        // 2000 paths sharing 2 PA entries
+        assert_eq!(stats.dedup_ratio(), 1000.0);
+        assert!(stats.prefix_map_bytes() >= 1000 * mem::size_of::<BgpTableEntry>());
+        // Two paths per destination fit inline in the entries
+        assert_eq!(stats.path_heap_bytes(), 0);
+        assert!(stats.pa_table_bytes() > 0 && stats.index_bytes() > 0);
+        assert_eq!(
+            stats.total_bytes(),
+            stats.prefix_map_bytes() + stats.path_heap_bytes() + stats.pa_table_bytes() + stats.index_bytes()
+        );
+    }
+
+    #[test]
+    fn bgp_table_igp_resolver() {
+        use std::sync::Mutex;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let nh = |addr: Ipv4Addr| PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(addr)).build().unwrap();
+        let nh1 = Ipv4Addr::new(172, 16, 0, 1);
+        let nh2 = Ipv4Addr::new(172, 16, 0, 2);
+        let metrics: Arc<Mutex<HashMap<IpAddr, u64>>> = Arc::new(Mutex::new(HashMap::from([
+            (IpAddr::V4(nh1), 10),
+            (IpAddr::V4(nh2), 20),
+        ])));
+        let igp = Arc::clone(&metrics);
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        table.set_igp_resolver(Some(Box::new(move |nh: IpAddr| igp.lock().unwrap().get(&nh).copied())));
+        // Supplied costs are ignored in favor of the resolver
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin.clone(), nh(nh1)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 9))
+            .igp_cost(5000)
+            .build()
+        );
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin, nh(nh2)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 1))
+            .peer_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+            .igp_cost(1)
+            .build()
+        );
+        assert_eq!(table.bestpaths(&route)[0].next_hop(), Some(IpAddr::V4(nh1)));
+
+        // The IGP metric to nh1 goes up
+        metrics.lock().unwrap().insert(IpAddr::V4(nh1), 30);
+        let (removed, adv) = table.refresh_igp_costs(&[IpAddr::V4(nh1)]);
+        assert!(removed.is_empty());
+        assert_eq!(adv.len(), 1);
+        assert_eq!(table.bestpaths(&route)[0].next_hop(), Some(IpAddr::V4(nh2)));
+        // Nothing left to change
+        assert!(table.refresh_igp_costs(&[]).1.is_empty());
+    }
+
+    #[test]
+    fn bgp_table_next_hop_tracking() {
+        use std::sync::Mutex;
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let nh = |addr: Ipv4Addr| PathAttrBuilder::<NextHop>::new().next_hop(IpAddr::V4(addr)).build().unwrap();
+        let nh1 = Ipv4Addr::new(172, 16, 0, 1);
+        let nh2 = Ipv4Addr::new(172, 16, 0, 2);
+        let reachable: Arc<Mutex<Vec<IpAddr>>> = Arc::new(Mutex::new(vec![IpAddr::V4(nh2)]));
+        let rib = Arc::clone(&reachable);
+
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        table.set_next_hop_view(Some(Box::new(move |nh: IpAddr| rib.lock().unwrap().contains(&nh))));
+        // The better path (lower peer ID) is parked since its next hop is unreachable
+        let (_, adv) = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin.clone(), nh(nh1)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+        );
+        assert!(adv.is_empty());
+        assert_eq!(table.num_unresolved(), 1);
+        assert_eq!(table.num_destinations(), 0);
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, vec![origin.clone(), nh(nh2)])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 9))
+            .peer_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9)))
+            .build()
+        );
+        assert_eq!(table.bestpaths(&route)[0].next_hop(), Some(IpAddr::V4(nh2)));
+
+        // nh1 becomes reachable, its path takes over
+        reachable.lock().unwrap().push(IpAddr::V4(nh1));
+        let (removed, adv) = table.next_hops_changed(&[IpAddr::V4(nh1)]);
+        assert!(removed.is_empty());
+        assert_eq!(adv.len(), 1);
+        assert_eq!(table.num_unresolved(), 0);
+        assert_eq!(table.bestpaths(&route)[0].next_hop(), Some(IpAddr::V4(nh1)));
+
+        // Both become unreachable, the destination is withdrawn
+        reachable.lock().unwrap().clear();
+        let (removed, adv) = table.next_hops_changed(&[]);
+        assert_eq!(removed, vec![route.clone()]);
+        assert!(adv.is_empty());
+        assert_eq!(table.num_unresolved(), 2);
+
+        // Withdrawals also remove parked paths
+        _ = table.walk(
+            MockReceivedRoutesBuilder::new(None, Some(vec![route.clone()]), vec![origin])
+            .peer_id(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+        );
+        assert_eq!(table.num_unresolved(), 1);
+    }
+
+    #[test]
+    fn bgp_table_implicit_withdraw() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let pas = |med: u32| vec![
+            PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap(),
+            PathAttrBuilder::<Med>::new().metric(med).build().unwrap()
+        ];
+        let from = |peer: u8, med: u32| {
+            MockReceivedRoutesBuilder::new(Some(vec![route.clone()]), None, pas(med))
+            .peer_id(Ipv4Addr::new(1, 1, 1, peer))
+            .peer_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, peer)))
+            .med(med)
+            .build()
+        };
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(from(1, 10));
+        _ = table.walk(from(2, 20));
+        assert_eq!(table.bestpaths(&route)[0].peer_id(), Ipv4Addr::new(1, 1, 1, 1));
+
+        // Peer 1 replaces its path with a worse one, the old path is gone and peer 2's path takes over
+        let (removed, adv) = table.walk(from(1, 30));
+        assert!(removed.is_empty());
+        assert_eq!(table.num_paths(), 2);
+        assert_eq!(table.bestpaths(&route)[0].peer_id(), Ipv4Addr::new(1, 1, 1, 2));
+        let nlri = adv.to_nlri();
+        assert_eq!(nlri.len(), 1);
+        assert_eq!(nlri[0].path_attrs().iter().find_map(|pa| pa.med()), Some(20));
+        assert_eq!(table.num_pa_entries(), 2);
+    }
+
+    #[test]
+    fn bgp_table_walk_withdrawn() {
+        let route = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 1, 0)));
+        let other = Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, 2, 0)));
+        let peer = |n: u8| (Ipv4Addr::new(1, 1, 1, n), IpAddr::V4(Ipv4Addr::new(10, 0, 0, n)));
+        let from = |n: u8, routes: Option<Vec<Route>>, withdrawn: Option<Vec<Route>>, med: u32| {
+            let pas = vec![
+                PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap(),
+                PathAttrBuilder::<Med>::new().metric(med).build().unwrap()
+            ];
+            MockReceivedRoutesBuilder::new(routes, withdrawn, pas)
+            .peer_id(peer(n).0)
+            .peer_addr(peer(n).1)
+            .med(med)
+            .build()
+        };
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(from(1, Some(vec![route.clone(), other.clone()]), None, 10));
+        _ = table.walk(from(2, Some(vec![route.clone()]), None, 20));
+
+        // Peer 1's path goes, peer 2's takes over and peer 1's PAs are still in use for the other route
+        let (peer_id, peer_addr) = peer(1);
+        let (removed, adv) = table.walk_withdrawn(WithdrawnRoutes::new(peer_id, peer_addr, vec![route.clone()], Afi::Ipv4, Safi::Unicast));
+        assert!(removed.is_empty());
+        assert_eq!(table.bestpaths(&route)[0].peer_id(), peer(2).0);
+        assert_eq!(adv.to_nlri()[0].path_attrs().iter().find_map(|pa| pa.med()), Some(20));
+        assert_eq!(table.num_pa_entries(), 2);
+        let (removed, _) = table.walk_withdrawn(WithdrawnRoutes::new(peer_id, peer_addr, vec![other.clone()], Afi::Ipv4, Safi::Unicast));
+        assert_eq!(removed, vec![other.clone()]);
+        assert_eq!(table.num_pa_entries(), 1);
+
+        // A payload's withdrawals come out on their own, and go before its announcements
+        let both = from(1, Some(vec![other.clone()]), Some(vec![other.clone(), route.clone()]), 10);
+        let withdrawn = both.withdrawn().unwrap();
+        assert_eq!(withdrawn.peer_addr(), peer_addr);
+        assert_eq!(withdrawn.routes(), &[other.clone(), route.clone()]);
+        assert!(from(1, Some(vec![other.clone()]), None, 10).withdrawn().is_none());
+        _ = table.walk(both);
+        assert_eq!(table.bestpaths(&other)[0].peer_id(), peer_id);
+        assert_eq!(table.bestpaths(&route)[0].peer_id(), peer(2).0);
+
+        // Multicast withdrawals belong to another RIB, the unicast path stays
+        let (removed, adv) = table.walk_withdrawn(WithdrawnRoutes::new(peer_id, peer_addr, vec![other.clone()], Afi::Ipv4, Safi::Multicast));
+        assert!(removed.is_empty() && adv.is_empty());
+        assert_eq!(table.bestpaths(&other)[0].peer_id(), peer_id);
+    }
+
+    #[test]
+    fn bgp_table_graceful_restart_stale() {
+        let routes: Vec<Route> = (1..=3).map(|i| Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 1, i, 0)))).collect();
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let from_peer = |routes: Vec<Route>| {
+            let origin = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+            MockReceivedRoutesBuilder::new(Some(routes), None, vec![origin])
+            .peer_id(Ipv4Addr::new(1, 1, 1, 1))
+            .peer_addr(peer)
+            .build()
+        };
+        let mut table = BgpTable::<Ipv4Addr>::new();
+        _ = table.walk(from_peer(routes.clone()));
+
+        // The session dropped, the paths stay until the peer has had a chance to resend them
+        assert_eq!(table.mark_stale(peer), 3);
+        assert_eq!(table.num_paths(), 3);
+        _ = table.walk(from_peer(vec![routes[0].clone()]));
+        assert_eq!(table.stale_count(peer), 2);
+
+        // End-of-RIB: whatever wasn't refreshed is withdrawn
+        let (mut removed, _) = table.flush_stale(peer);
+        removed.sort();
+        assert_eq!(removed, routes[1..].to_vec());
+        assert_eq!(table.num_paths(), 1);
+        assert_eq!(table.stale_count(peer), 0);
+        assert!(table.flush_stale(peer).0.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+// Handle to the tables of a running Speaker. The RIB task is their single owner; everything else (the admin
+// API, exporters, telemetry) queries them by sending jobs through a cloneable handle, which can be moved into
+// other tasks and threads without borrowing the Speaker. Jobs run on the RIB task in the order they were
+// sent, between the walks it does for the peers, so every answer is a consistent view of the table.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use crate::{
+    looking_glass::{self, LgQuery, LgRoute},
+    message_types::{Afi, Route},
+    table::{BgpTable, MemoryStats, RouteView, SnapshotFormat, SnapshotRow, TableDelta},
+};
+
+pub(crate) type TableJob = Box<dyn FnOnce(&mut BgpTable<Ipv4Addr>, &mut BgpTable<Ipv6Addr>) + Send>;
+
+// The RIB task has exited, so the request couldn't be processed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableClosed;
+
+impl fmt::Display for TableClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BGP table task is no longer running")
+    }
+}
+
+impl std::error::Error for TableClosed {}
+
+#[derive(Clone)]
+pub struct TableHandle {
+    tx: UnboundedSender<TableJob>
+}
+
+impl TableHandle {
+    pub(crate) fn new() -> (Self, UnboundedReceiver<TableJob>) {
+        // Whoever owns the tables runs the jobs from the receiver
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { tx }, rx)
+    }
+    pub(crate) fn run(&self, job: TableJob) -> Result<(), TableClosed> {
+        // Queues the job without waiting for it
+        self.tx.send(job).map_err(|_| TableClosed)
+    }
+    pub(crate) async fn with_tables<R, F>(&self, f: F) -> Result<R, TableClosed>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BgpTable<Ipv4Addr>, &mut BgpTable<Ipv6Addr>) -> R + Send + 'static
+    {
+        // Runs the closure on the tables and returns its result
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.run(Box::new(move |v4, v6| _ = reply_tx.send(f(v4, v6))))?;
+        reply_rx.await.map_err(|_| TableClosed)
+    }
+    pub async fn routes(&self, afi: Afi) -> Result<Vec<RouteView>, TableClosed> {
+        // Every destination of the family's Loc-RIB with all of its paths, sorted by prefix ("show ip bgp")
+        self.with_tables(move |v4, v6| match afi {
+            Afi::Ipv4 => v4.routes(),
+            Afi::Ipv6 => v6.routes()
+        })
+        .await
+    }
+    pub async fn route(&self, route: Route) -> Result<Option<RouteView>, TableClosed> {
+        // Every path to the prefix, best first. None if nothing was received for it.
+        self.with_tables(move |v4, v6| match route.prefix() {
+            IpAddr::V4(_) => v4.route_view(&route),
+            IpAddr::V6(_) => v6.route_view(&route)
+        })
+        .await
+    }
+    pub async fn routes_from_peer(&self, peer: IpAddr) -> Result<Vec<RouteView>, TableClosed> {
+        // The destinations the peer has a path to, of both families, keeping only the peer's paths
+        self.with_tables(move |v4, v6| {
+            let mut views = v4.routes_from_peer(peer);
+            views.extend(v6.routes_from_peer(peer));
+            views
+        })
+        .await
+    }
+    pub async fn routes_with_community(&self, community: u32) -> Result<Vec<RouteView>, TableClosed> {
+        // Same for the paths carrying the community
+        self.with_tables(move |v4, v6| {
+            let mut views = v4.routes_with_community(community);
+            views.extend(v6.routes_with_community(community));
+            views
+        })
+        .await
+    }
+    pub async fn looking_glass(&self, query: LgQuery, limit: usize) -> Result<Vec<LgRoute>, TableClosed> {
+        // Answers the query over both families, IPv4 first, with at most limit routes. A prefix is only
+        // looked up in its own family's table.
+        self.with_tables(move |v4, v6| match &query {
+            LgQuery::Prefix(route) if route.prefix().is_ipv6() => looking_glass::query(v6, &query, limit),
+            LgQuery::Prefix(_) => looking_glass::query(v4, &query, limit),
+            _ => {
+                let mut found = looking_glass::query(v4, &query, limit);
+                found.extend(looking_glass::query(v6, &query, limit - found.len()));
+                found
+            }
+        })
+        .await
+    }
+    pub async fn snapshot(&self, afi: Afi) -> Result<Vec<SnapshotRow>, TableClosed> {
+        // The bestpath of every destination of the family, sorted by prefix so two snapshots can be diffed
+        self.with_tables(move |v4, v6| match afi {
+            Afi::Ipv4 => v4.snapshot(),
+            Afi::Ipv6 => v6.snapshot()
+        })
+        .await
+    }
+    pub async fn export(&self, afi: Afi, format: SnapshotFormat) -> Result<String, TableClosed> {
+        // Same, serialized for offline analysis
+        self.with_tables(move |v4, v6| match afi {
+            Afi::Ipv4 => v4.export(format),
+            Afi::Ipv6 => v6.export(format)
+        })
+        .await
+    }
+    pub async fn memory_stats(&self, afi: Afi) -> Result<MemoryStats, TableClosed> {
+        // Where the family's table spends its memory. Walks the whole table, not meant to be polled often.
+        self.with_tables(move |v4, v6| match afi {
+            Afi::Ipv4 => v4.memory_stats(),
+            Afi::Ipv6 => v6.memory_stats()
+        })
+        .await
+    }
+    pub async fn changes_since(&self, afi: Afi, version: usize) -> Result<TableDelta, TableClosed> {
+        // What changed in the family's table after the version, e.g. for telemetry export. Start from 0,
+        // then ask again from the version the delta came with.
+        self.with_tables(move |v4, v6| match afi {
+            Afi::Ipv4 => v4.changes_since(version),
+            Afi::Ipv6 => v6.changes_since(version)
+        })
+        .await
+    }
+    pub async fn num_destinations(&self, afi: Afi) -> Result<usize, TableClosed> {
+        self.with_tables(move |v4, v6| match afi {
+            Afi::Ipv4 => v4.num_destinations(),
+            Afi::Ipv6 => v6.num_destinations()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comms::MockReceivedRoutesBuilder, path_attrs::*, table::AdvertisedRoutes};
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn table_is_send() {
+        assert_send::<BgpTable<Ipv4Addr>>();
+        assert_send::<AdvertisedRoutes<Ipv4Addr>>();
+        assert_send::<TableHandle>();
+    }
+
+    #[tokio::test]
+    async fn table_handle_queries() {
+        let routes: Vec<Route> = (0..10)
+            .map(|octet| Route::new(24, IpAddr::V4(Ipv4Addr::new(10, 0, octet, 0))))
+            .collect();
+        let pa = PathAttrBuilder::<Origin>::new().origin(OriginValue::Igp).build().unwrap();
+        let mut v4 = BgpTable::<Ipv4Addr>::new();
+        _ = v4.walk(MockReceivedRoutesBuilder::new(Some(routes.clone()), None, vec![pa]).build());
+        let (handle, mut jobs) = TableHandle::new();
+        let owner = tokio::spawn(async move {
+            let mut v6 = BgpTable::<Ipv6Addr>::new();
+            while let Some(job) = jobs.recv().await {
+                job(&mut v4, &mut v6);
+            }
+        });
+
+        // Queries can come from any task
+        let querier = handle.clone();
+        let views = tokio::spawn(async move { querier.routes(Afi::Ipv4).await }).await.unwrap().unwrap();
+        assert_eq!(views.iter().map(RouteView::route).collect::<Vec<_>>(), routes.iter().collect::<Vec<_>>());
+        assert_eq!(handle.route(routes[3].clone()).await.unwrap().map(|view| view.paths().len()), Some(1));
+        assert_eq!(handle.num_destinations(Afi::Ipv6).await, Ok(0));
+        assert_eq!(handle.changes_since(Afi::Ipv4, 0).await.unwrap().version(), 1);
+
+        owner.abort();
+        _ = owner.await;
+        assert_eq!(handle.num_destinations(Afi::Ipv4).await, Err(TableClosed));
+    }
+}
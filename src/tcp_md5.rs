@@ -0,0 +1,116 @@
+// TCP MD5 signatures (RFC 2385). The kernel signs and checks every segment to and from the peer
+// once the peer's key is set on the socket: on the connecting socket before connect for outgoing
+// connections, on the listening socket for incoming ones. Only Linux has TCP_MD5SIG in this form,
+// elsewhere setting a key fails with ErrorKind::Unsupported so the peer can be reported rather than
+// brought up unsigned.
+
+use std::{io, net::IpAddr};
+
+#[cfg(target_os = "linux")]
+use std::{mem, net::SocketAddr, os::fd::AsRawFd};
+
+// Longest key the kernel takes
+pub(crate) const MAX_KEY_LEN: usize = 80;
+
+// struct tcp_md5sig from linux/tcp.h
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct TcpMd5Sig {
+    addr: libc::sockaddr_storage,
+    flags: u8,
+    prefixlen: u8,
+    keylen: u16,
+    ifindex: libc::c_int,
+    key: [u8; MAX_KEY_LEN]
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_key<S: AsRawFd>(socket: &S, peer: IpAddr, ipv6_socket: bool, key: &[u8]) -> io::Result<()> {
+    // Sets the key for segments to and from the peer, an empty key removes it.
+    // IPv4 peers of IPv6 sockets (dual stack listeners) are keyed by their mapped address.
+    if key.len() > MAX_KEY_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "TCP MD5 key is longer than 80 bytes"));
+    }
+    let peer = match (peer, ipv6_socket) {
+        (IpAddr::V4(addr), true) => IpAddr::V6(addr.to_ipv6_mapped()),
+        (peer, _) => peer
+    };
+    // Safe, all zeroes is valid for every field
+    let mut sig: TcpMd5Sig = unsafe { mem::zeroed() };
+    write_sockaddr(&mut sig.addr, SocketAddr::new(peer, 0));
+    sig.keylen = key.len() as u16;
+    sig.key[..key.len()].copy_from_slice(key);
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MD5SIG,
+            &sig as *const TcpMd5Sig as *const libc::c_void,
+            mem::size_of::<TcpMd5Sig>() as libc::socklen_t
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(unsupported_or(io::Error::last_os_error()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_key<S>(_socket: &S, _peer: IpAddr, _ipv6_socket: bool, _key: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "TCP MD5 signatures are not supported on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn unsupported_or(err: io::Error) -> io::Error {
+    // Kernels built without CONFIG_TCP_MD5SIG don't know the option
+    match err.raw_os_error() {
+        Some(libc::ENOPROTOOPT) => io::Error::new(io::ErrorKind::Unsupported, "TCP MD5 signatures are not supported by the kernel"),
+        _ => err
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn write_sockaddr(storage: &mut libc::sockaddr_storage, addr: SocketAddr) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) },
+                sin_zero: [0; 8]
+            };
+            // Safe, sockaddr_storage is large and aligned enough for any sockaddr
+            unsafe { (storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in).write(sin) }
+        },
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: 0,
+                sin6_addr: libc::in6_addr { s6_addr: addr.ip().octets() },
+                sin6_scope_id: 0
+            };
+            unsafe { (storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6).write(sin6) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpSocket;
+
+    #[tokio::test]
+    async fn tcp_md5_set_key() {
+        let socket = TcpSocket::new_v4().unwrap();
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let err = set_key(&socket, peer, false, &[b'k'; MAX_KEY_LEN + 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match set_key(&socket, peer, false, b"secret") {
+            Ok(()) => set_key(&socket, peer, false, b"").unwrap(),
+            // Not every platform (or kernel) has it, but it has to say so
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::Unsupported)
+        }
+    }
+}
@@ -0,0 +1,313 @@
+// Runs the session timers for the FSM. The FSM only asks for timers to be started and stopped (see
+// fsm::Action), this turns those requests into tasks that sleep on a Clock and report back when they
+// expire. Expiries are delivered on a channel so they can be fed to the FSM alongside the other events.
+// Every start bumps the timer's generation, an expiry from a timer that was restarted or stopped in
+// the meantime is recognized as stale and dropped instead of reaching the FSM.
+// The Clock is also where the RIB gets the time from (duplicate detection, prefer-oldest, maximum-prefix
+// restarts), so a Speaker given a ManualClock only sees time pass when the test or simulation says so.
+
+use std::{
+    future::{self, Future},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    fsm::{Action, Timer},
+    fsm_ds::Event,
+};
+
+// Source of time for the timers, so tests and simulations can drive time themselves
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        C::now(self)
+    }
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        C::sleep(self, duration)
+    }
+}
+
+// Tokio's time, which a paused runtime (start_paused) already lets tests skip through
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// Time that only moves when advance() is called. Sleepers wake once the clock has been advanced past
+// their deadline, in deadline order. Clones share the time.
+#[derive(Clone, Debug)]
+pub(crate) struct ManualClock {
+    time: Arc<Mutex<ManualTime>>
+}
+
+#[derive(Debug)]
+struct ManualTime {
+    now: Instant,
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            time: Arc::new(Mutex::new(ManualTime { now: Instant::now(), sleepers: Vec::new() }))
+        }
+    }
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.now += duration;
+        let now = time.now;
+        let (mut due, waiting): (Vec<_>, Vec<_>) = time.sleepers.drain(..).partition(|(deadline, _)| *deadline <= now);
+        time.sleepers = waiting;
+        due.sort_by_key(|(deadline, _)| *deadline);
+        // Sleepers that were cancelled have dropped their receiver
+        due.into_iter().for_each(|(_, wake)| _ = wake.send(()));
+    }
+    pub fn sleepers(&self) -> usize {
+        // How many sleeps are waiting on the clock, cancelled ones included until their deadline
+        self.time.lock().unwrap().sleepers.len()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.time.lock().unwrap().now
+    }
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        if duration.is_zero() {
+            return Box::pin(future::ready(()));
+        }
+        let (wake, woken) = oneshot::channel();
+        let mut time = self.time.lock().unwrap();
+        let deadline = time.now + duration;
+        time.sleepers.push((deadline, wake));
+        Box::pin(async move {
+            // A dropped clock never wakes anyone
+            if woken.await.is_err() {
+                future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TimerExpired {
+    timer: Timer,
+    generation: u64
+}
+
+#[derive(Default)]
+struct RunningTimer {
+    generation: u64,
+    task: Option<JoinHandle<()>>
+}
+
+pub(crate) struct SessionTimers<C> {
+    clock: Arc<C>,
+    tx: UnboundedSender<TimerExpired>,
+    connect_retry: RunningTimer,
+    hold: RunningTimer,
+    keepalive: RunningTimer,
+    delay_open: RunningTimer,
+    idle_hold: RunningTimer,
+    stale: RunningTimer
+}
+
+impl<C: Clock + 'static> SessionTimers<C> {
+    pub fn new(clock: C) -> (Self, UnboundedReceiver<TimerExpired>) {
+        // Must be called within a tokio runtime. The receiver yields the expiries to pass to accept().
+        Self::on(Arc::new(clock))
+    }
+    pub fn share(&self) -> (Self, UnboundedReceiver<TimerExpired>) {
+        // Another set of timers on the same clock, e.g. for a second connection to the peer
+        Self::on(Arc::clone(&self.clock))
+    }
+    fn on(clock: Arc<C>) -> (Self, UnboundedReceiver<TimerExpired>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let timers = Self {
+            clock,
+            tx,
+            connect_retry: RunningTimer::default(),
+            hold: RunningTimer::default(),
+            keepalive: RunningTimer::default(),
+            delay_open: RunningTimer::default(),
+            idle_hold: RunningTimer::default(),
+            stale: RunningTimer::default()
+        };
+        (timers, rx)
+    }
+    pub fn apply(&mut self, actions: &[Action]) {
+        // Carries out the timer actions, anything else is left to the caller
+        for action in actions {
+            match action {
+                Action::StartTimer(timer, secs) => self.start(*timer, Duration::from_secs(*secs as u64)),
+                Action::StopTimer(timer) => self.stop(*timer),
+                _ => ()
+            }
+        }
+    }
+    pub fn start(&mut self, timer: Timer, duration: Duration) {
+        self.stop(timer);
+        let clock = Arc::clone(&self.clock);
+        let tx = self.tx.clone();
+        let running = self.running_mut(timer);
+        let generation = running.generation;
+        running.task = Some(tokio::spawn(async move {
+            clock.sleep(duration).await;
+            // The receiver being gone just means the session is being torn down
+            _ = tx.send(TimerExpired { timer, generation });
+        }));
+    }
+    pub fn stop(&mut self, timer: Timer) {
+        let running = self.running_mut(timer);
+        running.generation += 1;
+        if let Some(task) = running.task.take() {
+            task.abort();
+        }
+    }
+    pub fn stop_all(&mut self) {
+        for timer in [Timer::ConnectRetry, Timer::Hold, Timer::Keepalive, Timer::DelayOpen, Timer::IdleHold, Timer::Stale] {
+            self.stop(timer);
+        }
+    }
+    pub fn is_running(&self, timer: Timer) -> bool {
+        self.running(timer).task.as_ref().is_some_and(|task| !task.is_finished())
+    }
+    pub fn accept(&mut self, expired: TimerExpired) -> Option<Event> {
+        // Turns an expiry into the FSM event, or None if the timer was restarted or stopped since
+        let running = self.running_mut(expired.timer);
+        if running.generation != expired.generation {
+            return None;
+        }
+        running.task = None;
+        Some(match expired.timer {
+            Timer::ConnectRetry => Event::ConnectRetryTimerExpires,
+            Timer::Hold => Event::HoldTimerExpires,
+            Timer::Keepalive => Event::KeepaliveTimerExpires,
+            Timer::DelayOpen => Event::DelayOpenTimerExpires,
+            Timer::IdleHold => Event::IdleHoldTimerExpires,
+            Timer::Stale => Event::StaleTimerExpires
+        })
+    }
+    fn running(&self, timer: Timer) -> &RunningTimer {
+        match timer {
+            Timer::ConnectRetry => &self.connect_retry,
+            Timer::Hold => &self.hold,
+            Timer::Keepalive => &self.keepalive,
+            Timer::DelayOpen => &self.delay_open,
+            Timer::IdleHold => &self.idle_hold,
+            Timer::Stale => &self.stale
+        }
+    }
+    fn running_mut(&mut self, timer: Timer) -> &mut RunningTimer {
+        match timer {
+            Timer::ConnectRetry => &mut self.connect_retry,
+            Timer::Hold => &mut self.hold,
+            Timer::Keepalive => &mut self.keepalive,
+            Timer::DelayOpen => &mut self.delay_open,
+            Timer::IdleHold => &mut self.idle_hold,
+            Timer::Stale => &mut self.stale
+        }
+    }
+}
+
+impl<C> Drop for SessionTimers<C> {
+    fn drop(&mut self) {
+        for running in [&mut self.connect_retry, &mut self.hold, &mut self.keepalive, &mut self.delay_open, &mut self.idle_hold, &mut self.stale] {
+            if let Some(task) = running.task.take() {
+                task.abort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn session_timers_fire() {
+        let (mut timers, mut rx) = SessionTimers::new(TokioClock);
+        timers.apply(&[
+            Action::StartTimer(Timer::Keepalive, 30),
+            Action::StartTimer(Timer::Hold, 90),
+            Action::SendKeepalive
+        ]);
+        assert!(timers.is_running(Timer::Hold));
+
+        let expired = rx.recv().await.unwrap();
+        assert!(matches!(timers.accept(expired), Some(Event::KeepaliveTimerExpires)));
+        assert!(!timers.is_running(Timer::Keepalive));
+        let expired = rx.recv().await.unwrap();
+        assert!(matches!(timers.accept(expired), Some(Event::HoldTimerExpires)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_timers_stale() {
+        let (mut timers, mut rx) = SessionTimers::new(TokioClock);
+        timers.start(Timer::Hold, Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        tokio::task::yield_now().await;
+        // The hold timer already fired, but was restarted before the expiry was handled
+        timers.start(Timer::Hold, Duration::from_secs(90));
+        let expired = rx.recv().await.unwrap();
+        assert!(timers.accept(expired).is_none());
+
+        timers.apply(&[Action::StopTimer(Timer::Hold)]);
+        assert!(!timers.is_running(Timer::Hold));
+        timers.start(Timer::ConnectRetry, Duration::from_secs(120));
+        let expired = rx.recv().await.unwrap();
+        assert!(matches!(timers.accept(expired), Some(Event::ConnectRetryTimerExpires)));
+    }
+
+    #[tokio::test]
+    async fn session_timers_manual_clock() {
+        // Nothing fires until the clock is moved, however long the test takes
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let (mut timers, mut rx) = SessionTimers::new(clock.clone());
+        timers.start(Timer::Keepalive, Duration::from_secs(30));
+        timers.start(Timer::Hold, Duration::from_secs(90));
+        tokio::task::yield_now().await;
+        assert_eq!(clock.sleepers(), 2);
+
+        clock.advance(Duration::from_secs(29));
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+        clock.advance(Duration::from_secs(1));
+        let expired = rx.recv().await.unwrap();
+        assert!(matches!(timers.accept(expired), Some(Event::KeepaliveTimerExpires)));
+        assert_eq!(clock.now() - start, Duration::from_secs(30));
+
+        clock.advance(Duration::from_secs(60));
+        let expired = rx.recv().await.unwrap();
+        assert!(matches!(timers.accept(expired), Some(Event::HoldTimerExpires)));
+        assert_eq!(clock.sleepers(), 0);
+    }
+}
@@ -0,0 +1,691 @@
+// TCP transport for peers. TcpConnector opens the session's outgoing connection, optionally from a
+// given source address and backing off between failed attempts, and PeerListener accepts incoming
+// connections on port 179 and hands each one to the task of the peer it came from. A peer with a local
+// address (e.g. a loopback for iBGP) connects from it, and only takes connections made to it.
+// Framing (the 19 byte header) is done here, turning message bodies into messages and back is the
+// Codec's job. Reads are buffered so a recv cancelled by the peer's task never loses part of a message.
+// Bodies are handed to the Codec as Bytes split off the receive buffer, so what it decodes can keep
+// slices of them instead of copies (see msg_decoder).
+// Peers with an MD5 password get the key set on both the connecting and the listening socket.
+// Messages go out with vectored writes, headers and bodies straight from where they were encoded, and each
+// body is handed back to the Codec once it's written so its buffer can be reused. Batches (see
+// Connection::send_batch) only have BATCH_LEN bytes encoded ahead of the socket, the rest waits until the
+// peer has taken those, so a slow peer holds back the flush rather than memory filling.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io::{self, IoSlice},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    errors::{MsgHeaderErrSubcode, NotifErrorCode},
+    fsm_ds::{BgpPeer, Event, SessionParams},
+    message_types::{HEADER_LEN, MARKER, MAX_MESSAGE_LEN},
+    peer::{Connection, Connector, Inbound, Outbound},
+    tcp_md5,
+};
+
+pub(crate) const BGP_PORT: u16 = 179;
+const MARKER_LEN: usize = MARKER.len();
+// How much of a batch is encoded ahead of the socket, and how many buffers go in one write (two for
+// each message)
+const BATCH_LEN: usize = 64 * 1024;
+const MAX_IOVECS: usize = 64;
+
+// Turns messages into bodies and back. Each connection gets its own clone, so a codec can keep
+// per-connection state (e.g. the negotiated capabilities).
+pub(crate) trait Codec: Clone + Send + 'static {
+    // The message type and body, the header is added by the connection
+    fn encode(&mut self, msg: Outbound) -> (u8, BytesMut);
+    // Takes back a body once the connection has written it
+    fn recycle(&mut self, _body: BytesMut) {}
+    // None for messages that are ignored, e.g. a ROUTE-REFRESH for a family we don't know
+    fn decode(&mut self, msg_type: u8, body: Bytes) -> Option<Inbound>;
+    // What the session negotiated, see Connection::negotiated
+    fn negotiated(&mut self, params: &SessionParams);
+}
+
+// How long to wait before another connection attempt, doubling with each consecutive failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ConnectBackoff {
+    initial: Duration,
+    max: Duration
+}
+
+impl ConnectBackoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+    pub fn none() -> Self {
+        Self::new(Duration::ZERO, Duration::ZERO)
+    }
+    pub fn delay(&self, failures: u32) -> Duration {
+        // The first attempt goes out straight away
+        match failures {
+            0 => Duration::ZERO,
+            n => self.initial
+                .saturating_mul(2u32.saturating_pow(n - 1))
+                .min(self.max)
+        }
+    }
+}
+
+// Whether received headers have to start with the marker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MarkerCheck {
+    // Anything else is a Connection Not Synchronized error. RFC 4271, Pg. 21
+    #[default]
+    Strict,
+    // The marker is skipped, for interop testing against implementations that get it wrong
+    Lenient
+}
+
+pub(crate) struct TcpConnection<D> {
+    stream: TcpStream,
+    codec: D,
+    // Whatever has been read but isn't a whole message yet
+    buf: BytesMut,
+    marker_check: MarkerCheck
+}
+
+impl<D: Codec> TcpConnection<D> {
+    pub fn new(stream: TcpStream, codec: D) -> Self {
+        Self { stream, codec, buf: BytesMut::with_capacity(MAX_MESSAGE_LEN), marker_check: MarkerCheck::default() }
+    }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+    pub fn set_marker_check(&mut self, marker_check: MarkerCheck) {
+        // TcpConnector and PeerListener set the peer's on the connections they hand over
+        self.marker_check = marker_check;
+    }
+    fn next_message(&mut self) -> Option<Inbound> {
+        // The next message the codec doesn't ignore if it has been read in full, header errors as soon as
+        // the header is in
+        loop {
+            if self.buf.len() < HEADER_LEN {
+                return None;
+            }
+            if self.marker_check == MarkerCheck::Strict && self.buf[..MARKER_LEN] != MARKER {
+                return Some(self.header_err(MsgHeaderErrSubcode::ConnNotSynced));
+            }
+            let len = u16::from_be_bytes([self.buf[MARKER_LEN], self.buf[MARKER_LEN + 1]]) as usize;
+            if !(HEADER_LEN..=MAX_MESSAGE_LEN).contains(&len) {
+                return Some(self.header_err(MsgHeaderErrSubcode::BadMsgLen));
+            }
+            if self.buf.len() < len {
+                return None;
+            }
+            let mut msg = self.buf.split_to(len);
+            let body = msg.split_off(HEADER_LEN).freeze();
+            trace!(msg_type = msg[HEADER_LEN - 1], len, "decoding");
+            if let Some(inbound) = self.codec.decode(msg[HEADER_LEN - 1], body) {
+                return Some(inbound);
+            }
+        }
+    }
+    fn header_err(&mut self, subcode: MsgHeaderErrSubcode) -> Inbound {
+        // There's no finding the next message after a bad header, the FSM drops the connection
+        debug!(?subcode, "bad message header");
+        self.buf.clear();
+        Inbound::Event(Event::BGPHeaderErr(NotifErrorCode::MessageHeaderError(subcode)))
+    }
+}
+
+impl<D: Codec> Connection for TcpConnection<D> {
+    fn send(&mut self, msg: Outbound) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        let (msg_type, body) = self.codec.encode(msg);
+        trace!(msg_type, len = HEADER_LEN + body.len(), "encoded");
+        Box::pin(async move {
+            let mut pending = VecDeque::from([(header(msg_type, body.len())?, body)]);
+            write_messages(&mut self.stream, &mut pending, |body| self.codec.recycle(body)).await
+        })
+    }
+    fn send_batch(&mut self, msgs: Vec<Outbound>) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let mut msgs = msgs.into_iter();
+            let mut pending = VecDeque::with_capacity(MAX_IOVECS / 2);
+            loop {
+                // The next batch is encoded once the socket has taken the last one
+                let mut len = 0;
+                while len < BATCH_LEN && pending.len() < MAX_IOVECS / 2 {
+                    let (msg_type, body) = match msgs.next() {
+                        Some(msg) => self.codec.encode(msg),
+                        None => break
+                    };
+                    trace!(msg_type, len = HEADER_LEN + body.len(), "encoded");
+                    len += HEADER_LEN + body.len();
+                    pending.push_back((header(msg_type, body.len())?, body));
+                }
+                if pending.is_empty() {
+                    return Ok(());
+                }
+                write_messages(&mut self.stream, &mut pending, |body| self.codec.recycle(body)).await?;
+            }
+        })
+    }
+    fn recv(&mut self) -> Pin<Box<dyn Future<Output = Option<Inbound>> + Send + '_>> {
+        Box::pin(async move {
+            loop {
+                if let Some(inbound) = self.next_message() {
+                    return Some(inbound);
+                }
+                // read_buf is cancel safe, anything read before the task moved on stays in the buffer
+                match self.stream.read_buf(&mut self.buf).await {
+                    Ok(0) | Err(_) => return None,
+                    Ok(_) => ()
+                }
+            }
+        })
+    }
+    fn negotiated(&mut self, params: &SessionParams) {
+        self.codec.negotiated(params);
+    }
+}
+
+fn header(msg_type: u8, body_len: usize) -> io::Result<[u8; HEADER_LEN]> {
+    // Marker, length and type, what goes in front of the body
+    let len = HEADER_LEN + body_len;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "BGP message exceeds the maximum length"));
+    }
+    let mut header = [0; HEADER_LEN];
+    header[..MARKER_LEN].copy_from_slice(&MARKER);
+    header[MARKER_LEN..MARKER_LEN + 2].copy_from_slice(&(len as u16).to_be_bytes());
+    header[HEADER_LEN - 1] = msg_type;
+    Ok(header)
+}
+
+async fn write_messages<W, F>(
+    writer: &mut W,
+    msgs: &mut VecDeque<([u8; HEADER_LEN], BytesMut)>,
+    mut written: F
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    F: FnMut(BytesMut)
+{
+    // Writes all of the messages, as many at a time as the writer takes, and hands each body to written
+    // once it's out. Waiting on the writer is the backpressure, nothing more is queued until it's done.
+    // How far into the first message the writer has got
+    let mut offset = 0;
+    while !msgs.is_empty() {
+        let mut skip = offset;
+        let slices: Vec<IoSlice> = msgs
+            .iter()
+            .flat_map(|(header, body)| [&header[..], &body[..]])
+            .filter_map(|part| {
+                let start = skip.min(part.len());
+                skip -= start;
+                (start < part.len()).then(|| IoSlice::new(&part[start..]))
+            })
+            .collect();
+        offset += match writer.write_vectored(&slices).await? {
+            0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            n => n
+        };
+        while let Some((_, body)) = msgs.front() {
+            let len = HEADER_LEN + body.len();
+            if offset < len {
+                break;
+            }
+            offset -= len;
+            if let Some((_, body)) = msgs.pop_front() {
+                written(body);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) struct TcpConnector<D> {
+    peer: SocketAddr,
+    source: Option<IpAddr>,
+    backoff: ConnectBackoff,
+    md5_password: Option<String>,
+    marker_check: MarkerCheck,
+    // Consecutive failed attempts, counted by the connect futures themselves
+    failures: Arc<AtomicU32>,
+    codec: D
+}
+
+impl<D: Codec> TcpConnector<D> {
+    pub fn new(peer: IpAddr, codec: D) -> Self {
+        Self {
+            peer: SocketAddr::new(peer, BGP_PORT),
+            source: None,
+            backoff: ConnectBackoff::none(),
+            md5_password: None,
+            marker_check: MarkerCheck::default(),
+            failures: Arc::new(AtomicU32::new(0)),
+            codec
+        }
+    }
+    pub fn for_peer(peer: &BgpPeer, codec: D) -> Self {
+        // Connects from the peer's local address, unless it's left unspecified, with the peer's MD5 password
+        // and marker check
+        let mut connector = Self::new(peer.peer_address, codec).marker_check(peer.marker_mode());
+        connector.md5_password = peer.password().map(str::to_string);
+        match peer.local_address.is_unspecified() {
+            true => connector,
+            false => connector.source(peer.local_address)
+        }
+    }
+    pub fn port(mut self, port: u16) -> Self {
+        self.peer.set_port(port);
+        self
+    }
+    pub fn source(mut self, source: IpAddr) -> Self {
+        // The address the connection comes from, what the peer has configured for us
+        self.source = Some(source);
+        self
+    }
+    pub fn backoff(mut self, backoff: ConnectBackoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+    pub fn md5_password(mut self, password: &str) -> Self {
+        self.md5_password = Some(password.to_string());
+        self
+    }
+    pub fn marker_check(mut self, marker_check: MarkerCheck) -> Self {
+        self.marker_check = marker_check;
+        self
+    }
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+impl<D: Codec> Connector for TcpConnector<D> {
+    type Conn = TcpConnection<D>;
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<TcpConnection<D>>> + Send>> {
+        let (peer, source, codec, marker_check) = (self.peer, self.source, self.codec.clone(), self.marker_check);
+        let md5_password = self.md5_password.clone();
+        let failures = self.failures.clone();
+        let delay = self.backoff.delay(failures.load(Ordering::Relaxed));
+        Box::pin(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            match open_stream(peer, source, md5_password.as_deref()).await {
+                Ok(stream) => {
+                    failures.store(0, Ordering::Relaxed);
+                    let mut conn = TcpConnection::new(stream, codec);
+                    conn.set_marker_check(marker_check);
+                    Ok(conn)
+                },
+                Err(err) => {
+                    failures.fetch_add(1, Ordering::Relaxed);
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+async fn open_stream(peer: SocketAddr, source: Option<IpAddr>, md5_password: Option<&str>) -> io::Result<TcpStream> {
+    let socket = match peer {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?
+    };
+    if let Some(source) = source {
+        socket.bind(SocketAddr::new(source, 0))?;
+    }
+    if let Some(password) = md5_password {
+        tcp_md5::set_key(&socket, peer.ip(), peer.is_ipv6(), password.as_bytes())?;
+    }
+    socket.connect(peer).await
+}
+
+// Configured peers by their address and the local address their connections have to be made to (None
+// for any), with where to hand their connections, the codec to use and how to check their markers
+type ListenerPeers<D> = Arc<Mutex<HashMap<(IpAddr, Option<IpAddr>), (UnboundedSender<TcpConnection<D>>, D, MarkerCheck)>>>;
+
+// Accepts connections on behalf of every configured peer. Connections from anyone else are closed
+// straight away. The accept loop runs in its own task until the listener is dropped.
+pub(crate) struct PeerListener<D> {
+    // Shared with the accept loop, MD5 keys are set on it as peers come and go
+    listener: Arc<TcpListener>,
+    local_addr: SocketAddr,
+    peers: ListenerPeers<D>,
+    task: JoinHandle<()>
+}
+
+impl<D: Codec> PeerListener<D> {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        // Must be called within a tokio runtime
+        let listener = Arc::new(TcpListener::bind(addr).await?);
+        let local_addr = listener.local_addr()?;
+        let peers: ListenerPeers<D> = Arc::new(Mutex::new(HashMap::new()));
+        let task = tokio::spawn(accept_loop(listener.clone(), peers.clone()));
+        Ok(Self { listener, local_addr, peers, task })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    pub fn add_peer(&self, peer: IpAddr, local: Option<IpAddr>, codec: D) -> UnboundedReceiver<TcpConnection<D>> {
+        // The receiver goes to the peer's task, replaces whatever was there for the peer and local address.
+        // With a local address, connections from the peer to any other address of ours are closed.
+        self.insert(peer, local, codec, MarkerCheck::default())
+    }
+    pub fn add_bgp_peer(&self, peer: &BgpPeer, codec: D) -> io::Result<UnboundedReceiver<TcpConnection<D>>> {
+        // Matched on the peer's address and its local address, unless that's left unspecified. Markers are
+        // checked as the peer is configured to. A peer with an MD5 password fails to be added if the key
+        // can't be set, e.g. where MD5 isn't supported.
+        if let Some(password) = peer.password() {
+            self.md5_password(peer.peer_address, Some(password))?;
+        }
+        let local = Some(peer.local_address).filter(|local| !local.is_unspecified());
+        Ok(self.insert(peer.peer_address, local, codec, peer.marker_mode()))
+    }
+    fn insert(
+        &self,
+        peer: IpAddr,
+        local: Option<IpAddr>,
+        codec: D,
+        marker_check: MarkerCheck
+    ) -> UnboundedReceiver<TcpConnection<D>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.peers.lock().unwrap().insert(listener_key(peer, local), (tx, codec, marker_check));
+        rx
+    }
+    pub fn remove_peer(&self, peer: IpAddr, local: Option<IpAddr>) {
+        // Along with the peer's MD5 key, if it had one and no other local address has the peer
+        let mut peers = self.peers.lock().unwrap();
+        peers.remove(&listener_key(peer, local));
+        let canonical = peer.to_canonical();
+        if peers.keys().all(|(other, _)| *other != canonical) {
+            _ = self.md5_password(peer, None);
+        }
+    }
+    pub fn md5_password(&self, peer: IpAddr, password: Option<&str>) -> io::Result<()> {
+        // Connections from the peer are only accepted if signed with the password, None stops checking
+        let key = password.map(str::as_bytes).unwrap_or_default();
+        tcp_md5::set_key(self.listener.as_ref(), peer.to_canonical(), self.local_addr.is_ipv6(), key)
+    }
+}
+
+impl<D> Drop for PeerListener<D> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn listener_key(peer: IpAddr, local: Option<IpAddr>) -> (IpAddr, Option<IpAddr>) {
+    // IPv4 peers show up as mapped addresses on dual stack listeners
+    (peer.to_canonical(), local.map(|local| local.to_canonical()))
+}
+
+async fn accept_loop<D: Codec>(listener: Arc<TcpListener>, peers: ListenerPeers<D>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Errors here are about the accepted socket (e.g. reset before accept), not the listener
+            Err(_) => continue
+        };
+        let local = match stream.local_addr() {
+            Ok(local) => local.ip(),
+            Err(_) => continue
+        };
+        // A peer configured with the address the connection was made to comes first
+        let mut peers = peers.lock().unwrap();
+        let key = [listener_key(addr.ip(), Some(local)), listener_key(addr.ip(), None)]
+            .into_iter()
+            .find(|key| peers.contains_key(key));
+        match key {
+            Some(key) => {
+                let (tx, codec, marker_check) = &peers[&key];
+                let mut conn = TcpConnection::new(stream, codec.clone());
+                conn.set_marker_check(*marker_check);
+                // The peer's task is gone, so is the peer
+                if tx.send(conn).is_err() {
+                    peers.remove(&key);
+                }
+            },
+            None => debug!(peer = %addr, %local, "closing connection from an unknown peer")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::{
+        fsm_ds::PeerSessionBuilder,
+        message_types::{Afi, MessageType, Safi},
+    };
+
+    // Bodies go through as a keepalive carrying nothing and an update carrying nothing
+    #[derive(Clone)]
+    struct TestCodec;
+
+    impl Codec for TestCodec {
+        fn encode(&mut self, msg: Outbound) -> (u8, BytesMut) {
+            match msg {
+                Outbound::Keepalive => (4, BytesMut::new()),
+                _ => (2, BytesMut::zeroed(4))
+            }
+        }
+        fn decode(&mut self, msg_type: u8, body: Bytes) -> Option<Inbound> {
+            match (msg_type, body.len()) {
+                (4, 0) => Some(Inbound::Event(Event::KeepAliveMsg)),
+                _ => Some(Inbound::Update(Vec::new()))
+            }
+        }
+        fn negotiated(&mut self, _params: &SessionParams) {}
+    }
+
+    fn localhost() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
+
+    #[test]
+    fn connect_backoff_delay() {
+        let backoff = ConnectBackoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        let delays: Vec<u64> = (0..8).map(|failures| backoff.delay(failures).as_secs()).collect();
+        assert_eq!(delays, vec![0, 1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(30));
+        assert_eq!(ConnectBackoff::none().delay(5), Duration::ZERO);
+    }
+
+    #[test]
+    fn frame_header() {
+        let header = header(4, 0).unwrap();
+        assert!(header[..MARKER_LEN].iter().all(|byte| *byte == 0xFF));
+        assert_eq!(&header[MARKER_LEN..], &[0, 19, 4]);
+        assert!(super::header(2, MAX_MESSAGE_LEN).is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_connection_roundtrip() {
+        let listener = PeerListener::bind(SocketAddr::new(localhost(), 0)).await.unwrap();
+        let mut incoming = listener.add_peer(localhost(), None, TestCodec);
+        let connector = TcpConnector::new(localhost(), TestCodec)
+            .port(listener.local_addr().port())
+            .source(localhost());
+
+        let mut outgoing = connector.connect().await.unwrap();
+        let mut accepted = incoming.recv().await.unwrap();
+        assert_eq!(accepted.peer_addr().unwrap().ip(), localhost());
+        outgoing.send(Outbound::Keepalive).await.unwrap();
+        outgoing.send(Outbound::RouteRefresh(Afi::Ipv4, Safi::Unicast)).await.unwrap();
+        assert!(matches!(accepted.recv().await, Some(Inbound::Event(Event::KeepAliveMsg))));
+        assert!(matches!(accepted.recv().await, Some(Inbound::Update(_))));
+
+        // Garbage where the marker should be
+        accepted.stream.write_all(&[0; HEADER_LEN]).await.unwrap();
+        assert!(matches!(
+            outgoing.recv().await,
+            Some(Inbound::Event(Event::BGPHeaderErr(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::ConnNotSynced))))
+        ));
+        drop(accepted);
+        assert!(outgoing.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn tcp_connection_marker_check() {
+        // The peer is set to be lenient, so are the connections made to it and accepted from it
+        let lenient = BgpPeer::new(localhost(), 65001, IpAddr::V4(Ipv4Addr::UNSPECIFIED), PeerSessionBuilder::new().build())
+            .marker_check(MarkerCheck::Lenient);
+        let listener = PeerListener::bind(SocketAddr::new(localhost(), 0)).await.unwrap();
+        let mut incoming = listener.add_peer(localhost(), None, TestCodec);
+        let connector = TcpConnector::for_peer(&lenient, TestCodec).port(listener.local_addr().port());
+        let mut outgoing = connector.connect().await.unwrap();
+        let mut accepted = incoming.recv().await.unwrap();
+
+        // A KEEPALIVE (type 4) with a zeroed marker only gets through the lenient side
+        let mut keepalive = [0; HEADER_LEN];
+        keepalive[MARKER_LEN..].copy_from_slice(&[0, 19, MessageType::KeepAlive.into()]);
+        accepted.stream.write_all(&keepalive).await.unwrap();
+        assert!(matches!(outgoing.recv().await, Some(Inbound::Event(Event::KeepAliveMsg))));
+        outgoing.stream.write_all(&keepalive).await.unwrap();
+        assert!(matches!(
+            accepted.recv().await,
+            Some(Inbound::Event(Event::BGPHeaderErr(NotifErrorCode::MessageHeaderError(MsgHeaderErrSubcode::ConnNotSynced))))
+        ));
+
+        let mut incoming = listener.add_bgp_peer(&lenient, TestCodec).unwrap();
+        let mut outgoing = connector.connect().await.unwrap();
+        let mut accepted = incoming.recv().await.unwrap();
+        outgoing.stream.write_all(&keepalive).await.unwrap();
+        assert!(matches!(accepted.recv().await, Some(Inbound::Event(Event::KeepAliveMsg))));
+    }
+
+    #[tokio::test]
+    async fn write_messages_partial() {
+        // A pipe that only takes a few bytes at a time
+        let (mut writer, mut reader) = tokio::io::duplex(7);
+        let msgs: Vec<([u8; HEADER_LEN], BytesMut)> = (0..10u8)
+            .map(|n| (header(n, n as usize).unwrap(), BytesMut::from(&vec![n; n as usize][..])))
+            .collect();
+        let expected: Vec<u8> = msgs.iter().flat_map(|(header, body)| header.iter().chain(body.iter()).copied()).collect();
+        let read = tokio::spawn(async move {
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).await.unwrap();
+            read
+        });
+        let mut pending: VecDeque<([u8; HEADER_LEN], BytesMut)> = msgs.into_iter().collect();
+        let mut bodies = Vec::new();
+        write_messages(&mut writer, &mut pending, |body| bodies.push(body)).await.unwrap();
+        assert!(pending.is_empty());
+        drop(writer);
+        assert_eq!(read.await.unwrap(), expected);
+        // Every body comes back once it's been written, in order
+        assert_eq!(bodies.iter().map(BytesMut::len).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn tcp_connection_send_batch() {
+        let listener = PeerListener::bind(SocketAddr::new(localhost(), 0)).await.unwrap();
+        let mut incoming = listener.add_peer(localhost(), None, TestCodec);
+        let connector = TcpConnector::new(localhost(), TestCodec).port(listener.local_addr().port());
+        let mut outgoing = connector.connect().await.unwrap();
+        let mut accepted = incoming.recv().await.unwrap();
+
+        // More than one batch's worth, alternating the two kinds of message
+        let count = BATCH_LEN / HEADER_LEN + 100;
+        let msgs: Vec<Outbound> = (0..count)
+            .map(|n| match n % 2 {
+                0 => Outbound::Keepalive,
+                _ => Outbound::RouteRefresh(Afi::Ipv4, Safi::Unicast)
+            })
+            .collect();
+        let send = tokio::spawn(async move {
+            outgoing.send_batch(msgs).await.unwrap();
+            outgoing
+        });
+        for n in 0..count {
+            match (n % 2, accepted.recv().await) {
+                (0, Some(Inbound::Event(Event::KeepAliveMsg))) | (1, Some(Inbound::Update(_))) => (),
+                (_, inbound) => panic!("unexpected message {} {:?}", n, inbound.is_some())
+            }
+        }
+        send.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn peer_listener_unknown_peer() {
+        // Nobody configured for the address, the connection is closed on accept
+        let listener = PeerListener::<TestCodec>::bind(SocketAddr::new(localhost(), 0)).await.unwrap();
+        let other = listener.add_peer(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), None, TestCodec);
+        let mut conn = TcpConnector::new(localhost(), TestCodec)
+            .port(listener.local_addr().port())
+            .connect()
+            .await
+            .unwrap();
+        assert!(conn.recv().await.is_none());
+        assert!(other.is_empty());
+    }
+
+    #[tokio::test]
+    async fn peer_listener_local_address() {
+        // 127.0.0.0/8 is all loopback, the peer has to connect to .2 and does so from .3
+        let addr = |last: u8| IpAddr::V4(Ipv4Addr::new(127, 0, 0, last));
+        let listener = PeerListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await.unwrap();
+        let port = listener.local_addr().port();
+        let peer = BgpPeer::new(addr(3), 65001, addr(2), PeerSessionBuilder::new().build());
+        let mut incoming = listener.add_bgp_peer(&peer, TestCodec).unwrap();
+
+        let mut wrong_local = TcpConnector::new(addr(1), TestCodec).port(port).source(addr(3)).connect().await.unwrap();
+        assert!(wrong_local.recv().await.is_none());
+        assert!(incoming.is_empty());
+
+        // From our side the peer is at .2 and we are at .3
+        let us = BgpPeer::new(addr(2), 65000, addr(3), PeerSessionBuilder::new().build());
+        let _outgoing = TcpConnector::for_peer(&us, TestCodec).port(port).connect().await.unwrap();
+        let accepted = incoming.recv().await.unwrap();
+        assert_eq!(accepted.peer_addr().unwrap().ip(), addr(3));
+        listener.remove_peer(addr(3), Some(addr(2)));
+        assert!(listener.peers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tcp_connector_failures() {
+        // Nothing listens on the port anymore, so failures pile up
+        let port = TcpListener::bind(SocketAddr::new(localhost(), 0)).await.unwrap().local_addr().unwrap().port();
+        let connector = TcpConnector::new(localhost(), TestCodec).port(port);
+        assert!(connector.connect().await.is_err());
+        assert!(connector.connect().await.is_err());
+        assert_eq!(connector.failures(), 2);
+    }
+
+    #[tokio::test]
+    async fn tcp_connection_md5() {
+        // The peer's password is set on the listener when it's added, and used by its connector
+        let peer = BgpPeer::new(localhost(), 65001, IpAddr::V4(Ipv4Addr::UNSPECIFIED), PeerSessionBuilder::new().build())
+            .md5_password(Some("secret".to_string()));
+        let listener = PeerListener::bind(SocketAddr::new(localhost(), 0)).await.unwrap();
+        let mut incoming = match listener.add_bgp_peer(&peer, TestCodec) {
+            Ok(incoming) => incoming,
+            Err(err) => {
+                // Nothing more to check where MD5 isn't supported
+                assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+                return;
+            }
+        };
+        let connector = TcpConnector::for_peer(&peer, TestCodec).port(listener.local_addr().port());
+        let mut outgoing = connector.connect().await.unwrap();
+        let mut accepted = incoming.recv().await.unwrap();
+        outgoing.send(Outbound::Keepalive).await.unwrap();
+        assert!(matches!(accepted.recv().await, Some(Inbound::Event(Event::KeepAliveMsg))));
+    }
+}
@@ -0,0 +1,230 @@
+// Binary (radix 2) trie keyed on prefixes. Used as an index alongside the BGP table so that
+// longest prefix match and covering/covered prefix queries don't need to scan every destination.
+// Prefixes are stored left aligned in a u128 so the same trie works for v4 and v6.
+
+use std::net::IpAddr;
+
+const MAX_DEPTH: u8 = 128;
+
+#[derive(Clone, Debug)]
+struct TrieNode<V> {
+    value: Option<V>,
+    children: [Option<Box<TrieNode<V>>>; 2]
+}
+impl<V> TrieNode<V> {
+    fn new() -> Self {
+        Self {
+            value: None,
+            children: [None, None]
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.iter().all(|child| child.is_none())
+    }
+    fn num_nodes(&self) -> usize {
+        1 + self.children.iter().flatten().map(|child| child.num_nodes()).sum::<usize>()
+    }
+    fn remove(&mut self, bits: u128, len: u8, depth: u8) -> Option<V> {
+        // Removes the value and prunes any nodes left empty on the way back up.
+        if depth == len {
+            return self.value.take();
+        }
+        let bit = bit_at(bits, depth);
+        let child = self.children[bit].as_mut()?;
+        let removed = child.remove(bits, len, depth + 1);
+        if child.is_empty() {
+            self.children[bit] = None;
+        }
+        removed
+    }
+    fn collect<'a>(&'a self, bits: u128, depth: u8, out: &mut Vec<(u128, u8, &'a V)>) {
+        // Depth first walk of the subtree, shorter prefixes first.
+        if let Some(value) = self.value.as_ref() {
+            out.push((bits, depth, value));
+        }
+        self.children
+        .iter()
+        .enumerate()
+        .filter_map(|(bit, child)| child.as_ref().map(|child| (bit, child)))
+        .for_each(|(bit, child)| {
+            let child_bits = bits | ((bit as u128) << (MAX_DEPTH - 1 - depth));
+            child.collect(child_bits, depth + 1, out);
+        });
+    }
+}
+
+fn bit_at(bits: u128, depth: u8) -> usize {
+    ((bits >> (MAX_DEPTH - 1 - depth)) & 1) as usize
+}
+
+pub(crate) fn mask(bits: u128, len: u8) -> u128 {
+    // Zeroes out everything past the prefix length
+    bits & u128::MAX.checked_shl((MAX_DEPTH - len.min(MAX_DEPTH)) as u32).unwrap_or(0)
+}
+
+pub(crate) fn ip_bits(addr: IpAddr) -> u128 {
+    // Left aligns the address so v4 and v6 prefixes are keyed the same way
+    match addr {
+        IpAddr::V4(addr) => (u32::from(addr) as u128) << 96,
+        IpAddr::V6(addr) => u128::from(addr)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PrefixTrie<V> {
+    root: TrieNode<V>,
+    len: usize
+}
+impl<V> PrefixTrie<V> {
+    pub fn new() -> Self {
+        Self {
+            root: TrieNode::new(),
+            len: 0
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn insert(&mut self, bits: u128, len: u8, value: V) -> Option<V> {
+        // Inserts the value at the prefix, returning the previous value if there was one.
+        let len = len.min(MAX_DEPTH);
+        let mut node = &mut self.root;
+        for depth in 0..len {
+            node = node.children[bit_at(bits, depth)].get_or_insert_with(|| Box::new(TrieNode::new()));
+        }
+        let old = node.value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+    pub fn remove(&mut self, bits: u128, len: u8) -> Option<V> {
+        let removed = self.root.remove(bits, len.min(MAX_DEPTH), 0);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+    pub fn get(&self, bits: u128, len: u8) -> Option<&V> {
+        self.node(bits, len.min(MAX_DEPTH))?.value.as_ref()
+    }
+    pub fn get_mut(&mut self, bits: u128, len: u8) -> Option<&mut V> {
+        let mut node = &mut self.root;
+        for depth in 0..len.min(MAX_DEPTH) {
+            node = node.children[bit_at(bits, depth)].as_mut()?;
+        }
+        node.value.as_mut()
+    }
+    pub fn longest_match(&self, bits: u128, max_len: u8) -> Option<(u8, &V)> {
+        // Returns the most specific prefix (no longer than max_len) that covers the bits.
+        self.covering(bits, max_len).pop()
+    }
+    pub fn covering(&self, bits: u128, len: u8) -> Vec<(u8, &V)> {
+        // Returns every prefix that covers the given prefix (including itself), least specific first.
+        let mut out: Vec<(u8, &V)> = Vec::new();
+        let mut node = &self.root;
+        let mut depth: u8 = 0;
+        loop {
+            if let Some(value) = node.value.as_ref() {
+                out.push((depth, value));
+            }
+            if depth >= len.min(MAX_DEPTH) {
+                break;
+            }
+            match node.children[bit_at(bits, depth)].as_ref() {
+                Some(child) => node = child,
+                None => break
+            }
+            depth += 1;
+        }
+        out
+    }
+    pub fn covered(&self, bits: u128, len: u8) -> Vec<(u128, u8, &V)> {
+        // Returns every prefix covered by the given prefix (including itself), shorter prefixes first.
+        let len = len.min(MAX_DEPTH);
+        let mut out: Vec<(u128, u8, &V)> = Vec::new();
+        if let Some(node) = self.node(bits, len) {
+            node.collect(mask(bits, len), len, &mut out);
+        }
+        out
+    }
+    pub fn iter(&self) -> Vec<(u128, u8, &V)> {
+        self.covered(0, 0)
+    }
+    pub fn memory_bytes(&self) -> usize {
+        // Approximate heap usage, values are counted inline so anything they own isn't included.
+        self.root.num_nodes() * std::mem::size_of::<TrieNode<V>>()
+    }
+    fn node(&self, bits: u128, len: u8) -> Option<&TrieNode<V>> {
+        let mut node = &self.root;
+        for depth in 0..len {
+            node = node.children[bit_at(bits, depth)].as_ref()?;
+        }
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> u128 {
+        (u32::from_be_bytes([a, b, c, d]) as u128) << 96
+    }
+
+    #[test]
+    fn trie_insert_remove() {
+        let mut trie: PrefixTrie<u32> = PrefixTrie::new();
+        assert_eq!(trie.insert(v4(10, 0, 0, 0), 8, 1), None);
+        assert_eq!(trie.insert(v4(10, 1, 0, 0), 16, 2), None);
+        assert_eq!(trie.insert(v4(10, 0, 0, 0), 8, 3), Some(1));
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.get(v4(10, 1, 0, 0), 16), Some(&2));
+        assert_eq!(trie.get(v4(10, 1, 0, 0), 17), None);
+
+        assert_eq!(trie.remove(v4(10, 1, 0, 0), 16), Some(2));
+        assert_eq!(trie.remove(v4(10, 1, 0, 0), 16), None);
+        assert_eq!(trie.remove(v4(10, 0, 0, 0), 8), Some(3));
+        assert!(trie.is_empty());
+        // Everything was pruned
+        assert!(trie.root.is_empty());
+    }
+
+    #[test]
+    fn trie_longest_match() {
+        let mut trie: PrefixTrie<u32> = PrefixTrie::new();
+        trie.insert(0, 0, 0);
+        trie.insert(v4(10, 0, 0, 0), 8, 8);
+        trie.insert(v4(10, 1, 0, 0), 16, 16);
+        trie.insert(v4(10, 1, 1, 0), 24, 24);
+
+        assert_eq!(trie.longest_match(v4(10, 1, 1, 1), 32), Some((24, &24)));
+        assert_eq!(trie.longest_match(v4(10, 1, 2, 1), 32), Some((16, &16)));
+        assert_eq!(trie.longest_match(v4(10, 2, 2, 1), 32), Some((8, &8)));
+        assert_eq!(trie.longest_match(v4(192, 168, 1, 1), 32), Some((0, &0)));
+        assert_eq!(trie.covering(v4(10, 1, 1, 0), 24).len(), 4);
+    }
+
+    #[test]
+    fn trie_covered() {
+        let mut trie: PrefixTrie<u32> = PrefixTrie::new();
+        trie.insert(v4(10, 0, 0, 0), 8, 8);
+        trie.insert(v4(10, 1, 0, 0), 16, 16);
+        trie.insert(v4(10, 1, 1, 0), 24, 24);
+        trie.insert(v4(11, 0, 0, 0), 8, 11);
+
+        let covered: Vec<(u128, u8)> = trie
+            .covered(v4(10, 0, 0, 0), 8)
+            .into_iter()
+            .map(|(bits, len, _)| (bits, len))
+            .collect();
+        assert_eq!(covered, vec![(v4(10, 0, 0, 0), 8), (v4(10, 1, 0, 0), 16), (v4(10, 1, 1, 0), 24)]);
+        assert!(trie.covered(v4(12, 0, 0, 0), 8).is_empty());
+        assert_eq!(trie.iter().len(), 4);
+        // Root, 10/8 and 11/8 share 7 nodes, then 8 more for /16 and 8 more for /24
+        assert_eq!(trie.root.num_nodes(), 1 + 7 + 1 + 1 + 8 + 8);
+    }
+}
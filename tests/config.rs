@@ -0,0 +1,101 @@
+// Driving a speaker from a config file through the crate's public API, the way a routing daemon would.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use bgp4::{Afi, ConfigChanges, ConfigError, ConfiguredSpeaker, HostBits, Route, RouteView, RouterConfig};
+
+const CONFIG: &str = r#"
+    router_id = "192.0.2.254"
+    local_as = 65000
+    listen = "127.0.0.1:0"
+    networks = ["198.51.100.0/24"]
+    aggregates = ["198.51.100.0/22", "203.0.112.0/22"]
+
+    [peer_groups.ixp]
+    timers = { hold = 30 }
+    afi_safis = ["ipv4-unicast"]
+
+    [[peers]]
+    address = "127.0.0.1"
+    remote_as = 65001
+    local_address = "127.0.0.1"
+    group = "ixp"
+    passive = true
+"#;
+
+fn route(prefix: &str) -> Route {
+    Route::parse(prefix, HostBits::Normalize).unwrap()
+}
+
+#[tokio::test]
+async fn config_public_api() {
+    let config = RouterConfig::from_toml(CONFIG).unwrap();
+    let speaker = ConfiguredSpeaker::start_tcp(config, Vec::new()).await.unwrap();
+    assert_eq!(speaker.speaker().router_id(), Ipv4Addr::new(192, 0, 2, 254));
+    assert!(speaker.speaker().listen_addr().is_some());
+    let peer = speaker.speaker().peer(IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap();
+    assert_eq!(peer.peer_addr(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    // Only the aggregate with a contributing route is originated
+    let mut routes = Vec::new();
+    for _ in 0..50 {
+        routes = speaker.speaker().routes(Afi::Ipv4).await.unwrap();
+        if routes.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut routes: Vec<Route> = routes.iter().map(RouteView::route).cloned().collect();
+    routes.sort_by_key(|route| (route.prefix_len(), route.prefix()));
+    assert_eq!(routes, vec![route("198.51.100.0/22"), route("198.51.100.0/24")]);
+
+    // Checked before anything starts
+    let config = RouterConfig::from_toml(&CONFIG.replace("group = \"ixp\"", "group = \"rr\"")).unwrap();
+    assert_eq!(
+        ConfiguredSpeaker::start_tcp(config, Vec::new()).await.err(),
+        Some(ConfigError::UnknownGroup(String::from("rr")))
+    );
+    speaker.shutdown().await;
+}
+
+#[tokio::test]
+async fn config_reload_public_api() {
+    let mut speaker = ConfiguredSpeaker::start_tcp(RouterConfig::from_toml(CONFIG).unwrap(), Vec::new()).await.unwrap();
+    let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let added = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    let new = CONFIG
+        .replace("timers = { hold = 30 }", "timers = { hold = 90 }")
+        .replace("\"198.51.100.0/22\", ", "")
+        .replace("networks = [\"198.51.100.0/24\"]", "networks = [\"198.51.100.0/24\", \"203.0.113.0/24\"]")
+        + "[[peers]]\naddress = \"127.0.0.2\"\nremote_as = 65002\nlocal_address = \"127.0.0.1\"\npassive = true";
+    let changes = speaker.apply_config(RouterConfig::from_toml(&new).unwrap()).await.unwrap();
+    assert_eq!(changes, ConfigChanges {
+        added: vec![added],
+        reset: vec![peer],
+        originated: vec![route("203.0.113.0/24")],
+        ..Default::default()
+    });
+    assert_eq!(speaker.config().networks.len(), 2);
+    assert!(speaker.speaker().peer(added).is_some());
+
+    // The dropped aggregate goes, the other one has a contributing route now
+    let mut routes = Vec::new();
+    for _ in 0..50 {
+        routes = speaker.speaker().routes(Afi::Ipv4).await.unwrap();
+        if routes.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut routes: Vec<Route> = routes.iter().map(RouteView::route).cloned().collect();
+    routes.sort_by_key(|route| (route.prefix_len(), route.prefix()));
+    assert_eq!(routes, vec![route("203.0.112.0/22"), route("198.51.100.0/24"), route("203.0.113.0/24")]);
+
+    let mut moved = speaker.config().clone();
+    moved.local_as = 65010;
+    assert_eq!(speaker.apply_config(moved).await, Err(ConfigError::RestartRequired));
+    speaker.shutdown().await;
+}